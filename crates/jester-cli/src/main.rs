@@ -1,11 +1,34 @@
-use std::{fs, path::PathBuf, sync::OnceLock};
+use std::{
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
 use clap::{Parser, Subcommand};
-use jester_core::{config::Config, proxy::Proxy};
-use jester_plugin_sdk::PluginManifest;
-use regex::Regex;
-use tracing_subscriber::{fmt, EnvFilter};
+use http::{Method, Request};
+use http_body_util::{BodyExt, Full};
+use hyper_util::rt::TokioIo;
+use jester_core::{
+    admin::LogControl,
+    config::{self, interpolate, overlay, Config, ConfigSource},
+    proxy::{BindOptions, BindPolicy, Proxy, DEBUG_REQUEST_DIRECTIVE},
+};
+use jester_plugin_sdk::{compatible_sdk_versions, PluginManifest, SDK_VERSION};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tracing_subscriber::{fmt, prelude::*, reload, EnvFilter, Registry};
+
+use crate::snapshot::Snapshot;
+
+#[cfg(windows)]
+mod service;
+mod snapshot;
 
 #[derive(Parser, Debug)]
 #[command(name = "jester", author, version, about = "Programmable reverse proxy")]
@@ -29,6 +52,35 @@ enum Commands {
             default_value = "examples/config/minimal.jester.toml"
         )]
         config: PathBuf,
+        /// Overlay merged onto the config, as a path or a name such as `prod`
+        /// for `overlays/prod.toml` beside it; repeat to apply several in order.
+        #[arg(long, value_name = "FILE")]
+        overlay: Vec<String>,
+        /// Reject unknown keys in the config instead of ignoring them, as
+        /// `[meta] strict = true` does.
+        #[arg(long)]
+        strict: bool,
+        /// Refuse to start unless every listener binds (default).
+        #[arg(long, conflicts_with = "best_effort")]
+        fail_fast: bool,
+        /// Start with the listeners that bound, logging the ones that failed.
+        #[arg(long)]
+        best_effort: bool,
+        /// Retries for listeners whose address is still in use.
+        #[arg(long, value_name = "N", default_value_t = 3)]
+        bind_retries: u32,
+        /// Boot from the last-known-good snapshot when the config file fails
+        /// to load or validate.
+        #[arg(long)]
+        fallback_last_good: bool,
+        /// Where the last-known-good snapshot is kept [default: <config>.last-good].
+        #[arg(long, value_name = "FILE")]
+        last_good: Option<PathBuf>,
+        /// Run under the Windows service control manager as the service
+        /// `NAME` [default: jester].
+        #[cfg(windows)]
+        #[arg(long, value_name = "NAME", num_args = 0..=1, default_missing_value = "jester")]
+        service: Option<String>,
     },
     /// Interact with configuration files (validate, sample output, etc.)
     Config {
@@ -45,6 +97,46 @@ enum Commands {
         #[arg(long, value_name = "ROUTE")]
         route: String,
     },
+    /// Show or change the log filter of a running proxy through its admin API.
+    Loglevel {
+        /// New filter directives, e.g. `info,jester_core::proxy=trace`; omit to
+        /// print the current filter.
+        directives: Option<String>,
+        /// Restore the filter the proxy started with.
+        #[arg(long, conflicts_with = "directives")]
+        reset: bool,
+        /// Address of the proxy's admin API (`[admin] listen`).
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:9900")]
+        admin: String,
+    },
+    /// Operate on a running proxy through its admin socket (`[admin] socket`).
+    Ctl {
+        #[arg(long, value_name = "PATH", default_value = "/run/jester/admin.sock")]
+        socket: PathBuf,
+        #[command(subcommand)]
+        command: CtlCommands,
+    },
+    /// Print a URL signed for routes with the `signed-url` filter.
+    SignUrl {
+        /// Path and query to sign, e.g. `/downloads/report.pdf`.
+        path: String,
+        /// The filter's `secret`.
+        #[arg(
+            long,
+            conflicts_with = "secret_env",
+            required_unless_present = "secret_env"
+        )]
+        secret: Option<String>,
+        /// Environment variable holding the secret, as in `secret_env`.
+        #[arg(long, value_name = "VAR")]
+        secret_env: Option<String>,
+        /// Seconds from now until the link expires.
+        #[arg(long, value_name = "SECS", default_value_t = 3600)]
+        expires_in: u64,
+        /// Only accept the link from this client address (`bind_client_ip`).
+        #[arg(long, value_name = "IP")]
+        client_ip: Option<IpAddr>,
+    },
     /// Dump the resolved configuration as JSON.
     Diag {
         #[arg(
@@ -54,6 +146,18 @@ enum Commands {
             default_value = "examples/config/minimal.jester.toml"
         )]
         config: PathBuf,
+        /// Overlay merged onto the config, as a path or a name such as `prod`
+        /// for `overlays/prod.toml` beside it; repeat to apply several in order.
+        #[arg(long, value_name = "FILE")]
+        overlay: Vec<String>,
+        /// Reject unknown keys in the config instead of ignoring them, as
+        /// `[meta] strict = true` does.
+        #[arg(long)]
+        strict: bool,
+        /// List the `${VAR}` placeholders of the config and its overlays, and
+        /// whether each variable is set, instead of the configuration.
+        #[arg(long)]
+        variables: bool,
     },
 }
 
@@ -63,16 +167,71 @@ enum ConfigCommands {
     Validate {
         #[arg(value_name = "FILE")]
         config: PathBuf,
+        /// Overlay merged onto the config, as a path or a name such as `prod`
+        /// for `overlays/prod.toml` beside it; repeat to apply several in order.
+        #[arg(long, value_name = "FILE")]
+        overlay: Vec<String>,
+        /// Reject unknown keys in the config instead of ignoring them, as
+        /// `[meta] strict = true` does.
+        #[arg(long)]
+        strict: bool,
     },
-    /// Performs semantic linting (not yet implemented, returns TODO).
+    /// Validates the file and reports likely mistakes (e.g. unreachable routes).
     Lint {
         #[arg(value_name = "FILE")]
         config: PathBuf,
+        /// Overlay merged onto the config, as a path or a name such as `prod`
+        /// for `overlays/prod.toml` beside it; repeat to apply several in order.
+        #[arg(long, value_name = "FILE")]
+        overlay: Vec<String>,
+        /// Reject unknown keys in the config instead of ignoring them, as
+        /// `[meta] strict = true` does.
+        #[arg(long)]
+        strict: bool,
     },
     /// Prints the bundled minimal example configuration.
     Example,
 }
 
+#[derive(Subcommand, Debug)]
+enum CtlCommands {
+    /// Prints the live route table with in-flight request counts.
+    Routes,
+    /// Prints in-flight requests and open connections.
+    Stats,
+    /// Prints the startup report: listeners, certificates, plugins, and
+    /// degraded subsystems.
+    Startup,
+    /// Re-reads the config file the proxy was started with and applies its
+    /// routes and filters.
+    Reload,
+    /// Stops accepting connections and exits once in-flight requests finish.
+    Drain,
+    /// Samples requests for a while and prints where their filters, routes,
+    /// and upstream calls spent time, as folded stacks for flamegraph tools.
+    /// Builds with the `profiling` feature also take CPU and heap profiles.
+    Profile {
+        /// What to profile: `requests`, `cpu`, or `heap`.
+        #[arg(long, default_value = "requests")]
+        kind: String,
+        /// Print an SVG flamegraph instead of folded stacks.
+        #[arg(long)]
+        svg: bool,
+        /// How long to sample for.
+        #[arg(long, default_value_t = 30)]
+        seconds: u64,
+        /// Share of requests to sample, from 0 (exclusive) to 1.
+        #[arg(long, default_value_t = 1.0)]
+        rate: f64,
+        /// Only print stacks through this route.
+        #[arg(long)]
+        route: Option<String>,
+        /// Native stack samples per second for `--kind cpu`.
+        #[arg(long, default_value_t = 99)]
+        frequency: u32,
+    },
+}
+
 #[derive(Subcommand, Debug)]
 enum PluginCommands {
     /// Lists discovered plugins (currently stubbed).
@@ -82,44 +241,322 @@ enum PluginCommands {
     },
 }
 
+/// Records allocations while `POST /profile?kind=heap` runs.
+#[cfg(feature = "profiling")]
+#[global_allocator]
+static ALLOC: jester_core::HeapSampler = jester_core::HeapSampler;
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
-    init_tracing(&cli.log_level)?;
+    let log_control = init_tracing(&cli.log_level)?;
     match cli.command {
-        Commands::Run { config } => handle_run(config).await,
+        Commands::Run {
+            config,
+            overlay,
+            strict,
+            fail_fast: _,
+            best_effort,
+            bind_retries,
+            fallback_last_good,
+            last_good,
+            #[cfg(windows)]
+            service,
+        } => {
+            let policy = if best_effort {
+                BindPolicy::BestEffort
+            } else {
+                BindPolicy::FailFast
+            };
+            let bind = BindOptions {
+                policy,
+                retries: bind_retries,
+                ..BindOptions::default()
+            };
+            let snapshot = Snapshot::for_config(&config, last_good);
+            let proxy = build_proxy(
+                config,
+                overlay,
+                strict,
+                bind,
+                log_control,
+                snapshot,
+                fallback_last_good,
+            )
+            .await?;
+            #[cfg(windows)]
+            if let Some(name) = service {
+                return service::run(&name, || proxy.start()).await;
+            }
+            proxy.run().await
+        }
         Commands::Config { command } => handle_config(command),
         Commands::Plugins { command } => handle_plugins(command),
         Commands::Tap { route } => handle_tap(route),
-        Commands::Diag { config } => handle_diag(config),
+        Commands::Loglevel {
+            directives,
+            reset,
+            admin,
+        } => handle_loglevel(&admin, directives, reset).await,
+        Commands::Ctl { socket, command } => handle_ctl(&socket, command).await,
+        Commands::SignUrl {
+            path,
+            secret,
+            secret_env,
+            expires_in,
+            client_ip,
+        } => handle_sign_url(&path, secret, secret_env, expires_in, client_ip),
+        Commands::Diag {
+            config,
+            overlay,
+            strict,
+            variables: false,
+        } => handle_diag(config, &overlay, strict),
+        Commands::Diag {
+            config,
+            overlay,
+            variables: true,
+            ..
+        } => handle_diag_variables(&config, &overlay),
+    }
+}
+
+fn init_tracing(level: &str) -> Result<Arc<ReloadableFilter>> {
+    let filter = env_filter(level).unwrap_or_else(|_| env_filter("info").expect("valid filter"));
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .try_init()
+        .ok();
+    Ok(Arc::new(ReloadableFilter(handle)))
+}
+
+/// Exposes the subscriber's `EnvFilter` to the admin API.
+struct ReloadableFilter(reload::Handle<EnvFilter, Registry>);
+
+impl LogControl for ReloadableFilter {
+    fn directives(&self) -> String {
+        self.0
+            .with_current(|filter| filter.to_string())
+            .unwrap_or_default()
+    }
+
+    fn set_directives(&self, directives: &str) -> Result<()> {
+        let filter =
+            env_filter(directives).with_context(|| format!("invalid log filter `{directives}`"))?;
+        self.0.reload(filter).context("failed to swap log filter")
+    }
+}
+
+/// Builds a filter from `directives`, keeping trace logging for debug requests.
+fn env_filter(directives: &str) -> Result<EnvFilter> {
+    let filter = if directives.contains(DEBUG_REQUEST_DIRECTIVE) {
+        EnvFilter::try_new(directives)?
+    } else {
+        EnvFilter::try_new(format!("{directives},{DEBUG_REQUEST_DIRECTIVE}"))?
+    };
+    Ok(filter)
+}
+
+async fn build_proxy(
+    config_path: PathBuf,
+    overlays: Vec<String>,
+    strict: bool,
+    bind: BindOptions,
+    log_control: Arc<ReloadableFilter>,
+    snapshot: Snapshot,
+    fallback_last_good: bool,
+) -> Result<Proxy> {
+    let (mut config, source) = match load_checked_config(&config_path, &overlays, strict) {
+        Ok((config, source)) => (config, Some(source)),
+        Err(err) if fallback_last_good => {
+            let (config, saved) = snapshot
+                .load()
+                .with_context(|| format!("{err:#}; no usable last-known-good snapshot"))?;
+            tracing::warn!(
+                error = format!("{err:#}"),
+                snapshot = %snapshot.path().display(),
+                snapshot_age_secs = saved.elapsed().unwrap_or_default().as_secs(),
+                "CONFIG {} IS BROKEN: running from the last-known-good snapshot until it is fixed and reloaded",
+                config_path.display()
+            );
+            (config, None)
+        }
+        Err(err) => return Err(err),
+    };
+    if let Some(acme) = &config.acme {
+        jester_core::acme::provision(acme, config.cluster.as_ref()).await?;
+    }
+    if let Some(hardening) = config.hardening.as_mut().filter(|h| h.landlock) {
+        // Reloads read the config and its overlays and rewrite the snapshot.
+        hardening.read_paths.push(parent_dir(&config_path));
+        hardening.write_paths.push(parent_dir(snapshot.path()));
+    }
+    let snapshot = Arc::new(snapshot);
+    let loader_snapshot = snapshot.clone();
+    let mut builder = Proxy::builder();
+    if source.is_none() {
+        builder = builder.degraded(
+            "config",
+            format!(
+                "{} is broken; serving the last-known-good snapshot",
+                config_path.display()
+            ),
+        );
+    }
+    let proxy = builder
+        .config(config)
+        .bind_options(bind)
+        .log_control(log_control)
+        .config_loader(Arc::new(move || {
+            let (config, source) = load_checked_config(&config_path, &overlays, strict)?;
+            save_snapshot(&loader_snapshot, &source);
+            Ok(config)
+        }))
+        .build()?;
+    if let Some(source) = source {
+        save_snapshot(&snapshot, &source);
+    }
+    Ok(proxy)
+}
+
+fn save_snapshot(snapshot: &Snapshot, source: &str) {
+    if let Err(err) = snapshot.save(source) {
+        tracing::warn!(
+            error = format!("{err:#}"),
+            "failed to save last-known-good config"
+        );
     }
 }
 
-fn init_tracing(level: &str) -> Result<()> {
-    let filter = EnvFilter::try_new(level).unwrap_or_else(|_| EnvFilter::new("info"));
-    fmt().with_env_filter(filter).try_init().ok();
+fn parent_dir(path: &Path) -> String {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.display().to_string(),
+        _ => ".".into(),
+    }
+}
+
+async fn handle_loglevel(admin: &str, directives: Option<String>, reset: bool) -> Result<()> {
+    let (method, body) = match (directives, reset) {
+        (_, true) => (Method::DELETE, String::new()),
+        (Some(directives), false) => (Method::PUT, directives),
+        (None, false) => (Method::GET, String::new()),
+    };
+    let response = admin_request(admin, method, "/log-level", body).await?;
+    println!("{response}");
     Ok(())
 }
 
-async fn handle_run(config_path: PathBuf) -> Result<()> {
-    let config = load_config(&config_path)?;
-    let proxy = Proxy::new(config)?;
-    proxy.run().await
+#[cfg(unix)]
+async fn handle_ctl(socket: &Path, command: CtlCommands) -> Result<()> {
+    let (method, path) = match command {
+        CtlCommands::Routes => (Method::GET, "/routes".to_string()),
+        CtlCommands::Stats => (Method::GET, "/stats".to_string()),
+        CtlCommands::Startup => (Method::GET, "/startup".to_string()),
+        CtlCommands::Reload => (Method::POST, "/reload".to_string()),
+        CtlCommands::Drain => (Method::POST, "/drain".to_string()),
+        CtlCommands::Profile {
+            kind,
+            svg,
+            seconds,
+            rate,
+            route,
+            frequency,
+        } => {
+            let mut path = format!("/profile?kind={kind}&seconds={seconds}");
+            match kind.as_str() {
+                "requests" => path.push_str(&format!("&rate={rate}")),
+                "cpu" => path.push_str(&format!("&frequency={frequency}")),
+                _ => {}
+            }
+            if let Some(route) = route {
+                path.push_str(&format!("&route={route}"));
+            }
+            if svg {
+                path.push_str("&format=svg");
+            }
+            (Method::POST, path)
+        }
+    };
+    let stream = tokio::net::UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("failed to connect to admin socket {}", socket.display()))?;
+    let response = send_admin_request(stream, "localhost", method, &path, String::new()).await?;
+    match serde_json::from_str::<serde_json::Value>(&response) {
+        Ok(json) => println!("{}", serde_json::to_string_pretty(&json)?),
+        Err(_) => println!("{response}"),
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn handle_ctl(_socket: &Path, _command: CtlCommands) -> Result<()> {
+    bail!("jester ctl requires a unix platform")
+}
+
+/// Sends one request to a proxy's admin API and returns the response body.
+async fn admin_request(addr: &str, method: Method, path: &str, body: String) -> Result<String> {
+    let tcp = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("failed to connect to admin API at {addr}"))?;
+    send_admin_request(tcp, addr, method, path, body).await
+}
+
+async fn send_admin_request<IO>(
+    io: IO,
+    host: &str,
+    method: Method,
+    path: &str,
+    body: String,
+) -> Result<String>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(io)).await?;
+    tokio::spawn(connection);
+    let request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(http::header::HOST, host)
+        .body(Full::new(Bytes::from(body)))?;
+    let response = sender.send_request(request).await?;
+    let status = response.status();
+    let body = response.into_body().collect().await?.to_bytes();
+    let body = String::from_utf8_lossy(&body).trim().to_string();
+    if !status.is_success() {
+        bail!("admin API returned {status}: {body}");
+    }
+    Ok(body)
 }
 
 fn handle_config(command: ConfigCommands) -> Result<()> {
     match command {
-        ConfigCommands::Validate { config } => {
-            let cfg = load_config(&config)?;
-            cfg.validate()?;
+        ConfigCommands::Validate {
+            config,
+            overlay,
+            strict,
+        } => {
+            load_checked_config(&config, &overlay, strict)?;
             println!("configuration OK: {}", config.display());
         }
-        ConfigCommands::Lint { config } => {
-            let cfg = load_config(&config)?;
+        ConfigCommands::Lint {
+            config,
+            overlay,
+            strict,
+        } => {
+            let (cfg, source) = read_config(&config, &overlay, strict)?;
             if let Err(err) = cfg.validate() {
+                let err = config::annotate(err, &source, !overlay.is_empty());
                 println!("lint failed: {err}");
             } else {
-                println!("lint pass: no additional issues detected (future release will add more checks)");
+                let warnings = cfg.lint();
+                if warnings.is_empty() {
+                    println!("lint pass: no issues detected");
+                }
+                for warning in warnings {
+                    println!("warning: {warning}");
+                }
             }
         }
         ConfigCommands::Example => {
@@ -132,24 +569,55 @@ fn handle_config(command: ConfigCommands) -> Result<()> {
     Ok(())
 }
 
+fn handle_sign_url(
+    path: &str,
+    secret: Option<String>,
+    secret_env: Option<String>,
+    expires_in: u64,
+    client_ip: Option<IpAddr>,
+) -> Result<()> {
+    let secret = match (secret, secret_env) {
+        (Some(secret), _) => secret,
+        (None, Some(name)) => std::env::var(&name)
+            .with_context(|| format!("environment variable {name} is not set"))?,
+        (None, None) => bail!("pass --secret or --secret-env"),
+    };
+    if !path.starts_with('/') {
+        bail!("path must start with `/`, got `{path}`");
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let url = jester_core::builtins::sign_url(secret.as_bytes(), path, now + expires_in, client_ip);
+    println!("{url}");
+    Ok(())
+}
+
 fn handle_plugins(command: PluginCommands) -> Result<()> {
     match command {
         PluginCommands::List { dir } => {
             let manifests = discover_plugins(&dir)?;
+            println!(
+                "host SDK {}; loads plugins built with SDK {}",
+                SDK_VERSION,
+                compatible_sdk_versions()
+            );
             if manifests.is_empty() {
                 println!("no plugin manifests found under {}", dir.display());
             } else {
                 for manifest in manifests {
                     println!(
-                        "- {} v{}{}",
+                        "- {} v{} (SDK {}){}",
                         manifest.name,
                         manifest.version,
+                        manifest.sdk_version.as_deref().unwrap_or("unknown"),
                         manifest
                             .description
                             .as_ref()
                             .map(|d| format!(" — {d}"))
                             .unwrap_or_default()
                     );
+                    if let Err(err) = manifest.check_sdk() {
+                        println!("  incompatible: {err}");
+                    }
                 }
             }
         }
@@ -166,31 +634,94 @@ fn handle_tap(route: String) -> Result<()> {
     Ok(())
 }
 
-fn handle_diag(path: PathBuf) -> Result<()> {
-    let cfg = load_config(&path)?;
+fn handle_diag(path: PathBuf, overlays: &[String], strict: bool) -> Result<()> {
+    let cfg = load_config(&path, overlays, strict)?;
     let json = serde_json::to_string_pretty(&cfg)?;
     println!("{json}");
     Ok(())
 }
 
-fn load_config(path: &PathBuf) -> Result<Config> {
-    let raw = fs::read_to_string(path)
-        .with_context(|| format!("failed to read config file {}", path.display()))?;
-    let expanded = interpolate_env(&raw)?;
-    let cfg = toml::from_str::<Config>(&expanded)
-        .with_context(|| format!("failed to parse {}", path.display()))?;
-    Ok(cfg)
+/// Prints each placeholder as `file:line  ${NAME}  status`. Values are left
+/// out, since variables often hold secrets.
+fn handle_diag_variables(path: &Path, overlays: &[String]) -> Result<()> {
+    let files = std::iter::once(path.to_path_buf())
+        .chain(overlays.iter().map(|spec| overlay::resolve(path, spec)));
+    for file in files {
+        let raw = fs::read_to_string(&file)
+            .with_context(|| format!("failed to read config file {}", file.display()))?;
+        for placeholder in interpolate::placeholders(&raw) {
+            let value = std::env::var_os(placeholder.name);
+            // Mirrors expansion: empty is a value unless the variable is required.
+            let status = match (value, placeholder.fallback) {
+                (Some(value), interpolate::Fallback::Required(_)) if value.is_empty() => {
+                    "empty; required".to_string()
+                }
+                (Some(_), _) => "set".to_string(),
+                (None, interpolate::Fallback::Empty) => "unset; empty".to_string(),
+                (None, interpolate::Fallback::Default(default)) => {
+                    format!("unset; default `{default}`")
+                }
+                (None, interpolate::Fallback::Required(_)) => "unset; required".to_string(),
+            };
+            println!(
+                "{}:{}\t${{{}}}\t{status}",
+                file.display(),
+                placeholder.line,
+                placeholder.name
+            );
+        }
+    }
+    Ok(())
+}
+
+fn load_config(path: &Path, overlays: &[String], strict: bool) -> Result<Config> {
+    read_config(path, overlays, strict).map(|(cfg, _)| cfg)
+}
+
+/// Loads and validates a config, also returning its interpolated source.
+fn load_checked_config(path: &Path, overlays: &[String], strict: bool) -> Result<(Config, String)> {
+    with_sources(path, overlays, |base, overlays| {
+        config::load_checked(base, overlays, strict)
+    })
+}
+
+/// Reads `path` with each of `overlays` merged on in turn, as
+/// [`config::load`] does. With overlays the source returned is the merged
+/// TOML.
+fn read_config(path: &Path, overlays: &[String], strict: bool) -> Result<(Config, String)> {
+    with_sources(path, overlays, |base, overlays| {
+        config::load(base, overlays, strict)
+    })
 }
 
-fn interpolate_env(input: &str) -> Result<String> {
-    static RE: OnceLock<Regex> = OnceLock::new();
-    let regex = RE.get_or_init(|| Regex::new(r"\$\{([A-Z0-9_]+)(?::([^}]+))?\}").unwrap());
-    let result = regex.replace_all(input, |caps: &regex::Captures| {
-        let key = &caps[1];
-        let default = caps.get(2).map(|m| m.as_str()).unwrap_or("");
-        std::env::var(key).unwrap_or_else(|_| default.to_string())
-    });
-    Ok(result.into_owned())
+/// Reads the config at `path` and the overlay files `overlays` name, and
+/// hands them to `load`.
+fn with_sources<T>(
+    path: &Path,
+    overlays: &[String],
+    load: impl FnOnce(&ConfigSource, &[ConfigSource]) -> Result<T>,
+) -> Result<T> {
+    let read = |path: &Path| {
+        fs::read_to_string(path)
+            .map(|text| (path.display().to_string(), text))
+            .with_context(|| format!("failed to read config file {}", path.display()))
+    };
+    let base = read(path)?;
+    let files = overlays
+        .iter()
+        .map(|spec| read(&overlay::resolve(path, spec)))
+        .collect::<Result<Vec<_>>>()?;
+    let overlays = files
+        .iter()
+        .map(|(name, text)| ConfigSource { name, text })
+        .collect::<Vec<_>>();
+    load(
+        &ConfigSource {
+            name: &base.0,
+            text: &base.1,
+        },
+        &overlays,
+    )
 }
 
 fn discover_plugins(dir: &PathBuf) -> Result<Vec<PluginManifest>> {
@@ -207,6 +738,9 @@ fn discover_plugins(dir: &PathBuf) -> Result<Vec<PluginManifest>> {
         let data = fs::read_to_string(&path)?;
         let manifest: PluginManifest = serde_json::from_str(&data)
             .with_context(|| format!("failed to parse manifest {}", path.display()))?;
+        manifest
+            .validate()
+            .with_context(|| format!("invalid manifest {}", path.display()))?;
         manifests.push(manifest);
     }
     Ok(manifests)
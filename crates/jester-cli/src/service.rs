@@ -0,0 +1,256 @@
+//! Running as a Windows service, for `jester run --service`.
+//!
+//! The service control manager (SCM) calls back into the process on a thread
+//! of its own: [`run`] starts the dispatcher on a blocking thread, waits for
+//! the SCM to start the service, then serves until a stop. Stop and shutdown
+//! controls drain the proxy; `paramchange` (`sc.exe control jester
+//! paramchange`) reloads its config.
+
+use std::{
+    ffi::c_void,
+    future::Future,
+    ptr,
+    sync::{mpsc as std_mpsc, Mutex, OnceLock},
+};
+
+use anyhow::{bail, Context, Result};
+use jester_core::proxy::ProxyHandle;
+use tokio::sync::{mpsc, oneshot};
+
+const SERVICE_WIN32_OWN_PROCESS: u32 = 0x10;
+
+const SERVICE_STOPPED: u32 = 1;
+const SERVICE_START_PENDING: u32 = 2;
+const SERVICE_STOP_PENDING: u32 = 3;
+const SERVICE_RUNNING: u32 = 4;
+
+const SERVICE_ACCEPT_STOP: u32 = 0x1;
+const SERVICE_ACCEPT_SHUTDOWN: u32 = 0x4;
+const SERVICE_ACCEPT_PARAMCHANGE: u32 = 0x8;
+
+const SERVICE_CONTROL_STOP: u32 = 1;
+const SERVICE_CONTROL_INTERROGATE: u32 = 4;
+const SERVICE_CONTROL_SHUTDOWN: u32 = 5;
+const SERVICE_CONTROL_PARAMCHANGE: u32 = 6;
+
+const NO_ERROR: u32 = 0;
+const ERROR_CALL_NOT_IMPLEMENTED: u32 = 120;
+const ERROR_SERVICE_SPECIFIC_ERROR: u32 = 1066;
+const ERROR_FAILED_SERVICE_CONTROLLER_CONNECT: i32 = 1063;
+
+/// How long the SCM should wait between status updates while starting or
+/// draining, in milliseconds.
+const WAIT_HINT_MS: u32 = 30_000;
+
+type ServiceMain = unsafe extern "system" fn(u32, *mut *mut u16);
+type HandlerEx = unsafe extern "system" fn(u32, u32, *mut c_void, *mut c_void) -> u32;
+
+#[repr(C)]
+struct ServiceTableEntryW {
+    name: *mut u16,
+    main: Option<ServiceMain>,
+}
+
+#[repr(C)]
+struct ServiceStatus {
+    service_type: u32,
+    current_state: u32,
+    controls_accepted: u32,
+    win32_exit_code: u32,
+    service_specific_exit_code: u32,
+    check_point: u32,
+    wait_hint: u32,
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn StartServiceCtrlDispatcherW(table: *const ServiceTableEntryW) -> i32;
+    fn RegisterServiceCtrlHandlerExW(
+        name: *const u16,
+        handler: HandlerEx,
+        context: *mut c_void,
+    ) -> isize;
+    fn SetServiceStatus(handle: isize, status: *const ServiceStatus) -> i32;
+}
+
+/// A control the SCM sent, as the proxy acts on it.
+enum Control {
+    Stop,
+    Reload,
+}
+
+/// State shared with the callbacks the SCM invokes, which take no closure.
+struct Dispatch {
+    name: Vec<u16>,
+    started: Mutex<Option<oneshot::Sender<Result<isize>>>>,
+    controls: mpsc::UnboundedSender<Control>,
+    /// Signalled once the service has reported itself stopped, letting
+    /// `service_main` return to the dispatcher.
+    stopped: Mutex<Option<std_mpsc::Receiver<()>>>,
+}
+
+static DISPATCH: OnceLock<Dispatch> = OnceLock::new();
+
+/// Runs as the service `name`: `start` binds and starts the proxy, which is
+/// then stopped and reloaded as the SCM asks.
+pub async fn run<F, Fut>(name: &str, start: F) -> Result<()>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<ProxyHandle>>,
+{
+    let (started_tx, started_rx) = oneshot::channel();
+    let (controls_tx, mut controls) = mpsc::unbounded_channel();
+    let (stopped_tx, stopped_rx) = std_mpsc::channel();
+    let dispatch = Dispatch {
+        name: name.encode_utf16().chain([0]).collect(),
+        started: Mutex::new(Some(started_tx)),
+        controls: controls_tx,
+        stopped: Mutex::new(Some(stopped_rx)),
+    };
+    if DISPATCH.set(dispatch).is_err() {
+        bail!("the service dispatcher is already running");
+    }
+    let dispatcher = tokio::task::spawn_blocking(|| {
+        let dispatch = DISPATCH.get().expect("dispatch state is set");
+        let table = [
+            ServiceTableEntryW {
+                name: dispatch.name.as_ptr() as *mut u16,
+                main: Some(service_main as ServiceMain),
+            },
+            ServiceTableEntryW {
+                name: ptr::null_mut(),
+                main: None,
+            },
+        ];
+        // SAFETY: the table is null-terminated and outlives the call, which
+        // returns once every service in it has stopped.
+        if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(ERROR_FAILED_SERVICE_CONTROLLER_CONNECT) {
+                bail!("--service only works when started by the service control manager");
+            }
+            return Err(err).context("failed to start the service dispatcher");
+        }
+        Ok(())
+    });
+    let status = match started_rx.await {
+        Ok(status) => status?,
+        // The dispatcher gave up before the SCM started the service.
+        Err(_) => {
+            dispatcher.await.context("service dispatcher panicked")??;
+            bail!("the service control manager never started the service");
+        }
+    };
+
+    report(status, SERVICE_START_PENDING, 0, 1);
+    let handle = match start().await {
+        Ok(handle) => handle,
+        Err(err) => {
+            report(status, SERVICE_STOPPED, ERROR_SERVICE_SPECIFIC_ERROR, 0);
+            stopped_tx.send(()).ok();
+            dispatcher.await.ok();
+            return Err(err);
+        }
+    };
+    report(status, SERVICE_RUNNING, 0, 0);
+    tracing::info!(service = name, "running as a Windows service");
+
+    while let Some(control) = controls.recv().await {
+        match control {
+            Control::Reload => {
+                if let Err(err) = handle.reload() {
+                    tracing::warn!(error = format!("{err:#}"), "service reload failed");
+                }
+            }
+            Control::Stop => break,
+        }
+    }
+    tracing::info!(service = name, "service stopping; draining");
+    report(status, SERVICE_STOP_PENDING, 0, 1);
+    let result = handle.shutdown().await;
+    let exit_code = if result.is_ok() {
+        NO_ERROR
+    } else {
+        ERROR_SERVICE_SPECIFIC_ERROR
+    };
+    report(status, SERVICE_STOPPED, exit_code, 0);
+    stopped_tx.send(()).ok();
+    dispatcher.await.context("service dispatcher panicked")??;
+    result
+}
+
+/// Called by the dispatcher on a thread of its own when the SCM starts the
+/// service. Returns once the service has stopped.
+extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+    let dispatch = DISPATCH.get().expect("dispatch state is set");
+    // SAFETY: the name is null-terminated and `control_handler` lives as
+    // long as the process.
+    let status = unsafe {
+        RegisterServiceCtrlHandlerExW(dispatch.name.as_ptr(), control_handler, ptr::null_mut())
+    };
+    let started = if status == 0 {
+        Err(std::io::Error::last_os_error())
+            .context("failed to register the service control handler")
+    } else {
+        Ok(status)
+    };
+    let registered = started.is_ok();
+    if let Some(tx) = dispatch.started.lock().unwrap().take() {
+        tx.send(started).ok();
+    }
+    let stopped = dispatch.stopped.lock().unwrap().take();
+    if let (true, Some(stopped)) = (registered, stopped) {
+        stopped.recv().ok();
+    }
+}
+
+/// Called by the SCM for each control sent to the service.
+extern "system" fn control_handler(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut c_void,
+    _context: *mut c_void,
+) -> u32 {
+    let Some(dispatch) = DISPATCH.get() else {
+        return ERROR_CALL_NOT_IMPLEMENTED;
+    };
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            dispatch.controls.send(Control::Stop).ok();
+            NO_ERROR
+        }
+        SERVICE_CONTROL_PARAMCHANGE => {
+            dispatch.controls.send(Control::Reload).ok();
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+/// Tells the SCM the service is in `state`.
+fn report(handle: isize, state: u32, exit_code: u32, check_point: u32) {
+    let pending = matches!(state, SERVICE_START_PENDING | SERVICE_STOP_PENDING);
+    let status = ServiceStatus {
+        service_type: SERVICE_WIN32_OWN_PROCESS,
+        current_state: state,
+        controls_accepted: if state == SERVICE_RUNNING {
+            SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN | SERVICE_ACCEPT_PARAMCHANGE
+        } else {
+            0
+        },
+        win32_exit_code: exit_code,
+        service_specific_exit_code: u32::from(exit_code == ERROR_SERVICE_SPECIFIC_ERROR),
+        check_point,
+        wait_hint: if pending { WAIT_HINT_MS } else { 0 },
+    };
+    // SAFETY: `handle` came from RegisterServiceCtrlHandlerExW and the status
+    // is fully initialized.
+    if unsafe { SetServiceStatus(handle, &status) } == 0 {
+        tracing::warn!(
+            error = %std::io::Error::last_os_error(),
+            state,
+            "failed to report service status"
+        );
+    }
+}
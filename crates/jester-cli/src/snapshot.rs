@@ -0,0 +1,107 @@
+//! Last-known-good copies of the configuration, for `run --fallback-last-good`.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use jester_core::config::Config;
+
+/// The last configuration (after `${VAR}` interpolation) that validated; kept
+/// at `<config>.last-good` unless another path is given.
+#[derive(Debug)]
+pub struct Snapshot {
+    path: PathBuf,
+}
+
+impl Snapshot {
+    pub fn for_config(config: &Path, path: Option<PathBuf>) -> Self {
+        let path = path.unwrap_or_else(|| {
+            let mut name = config.as_os_str().to_owned();
+            name.push(".last-good");
+            PathBuf::from(name)
+        });
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Atomically replaces the snapshot. Interpolated secrets end up in it, so
+    /// it is readable by the owner only.
+    pub fn save(&self, source: &str) -> Result<()> {
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(&tmp)
+            .with_context(|| format!("failed to write {}", tmp.display()))?;
+        file.write_all(source.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("failed to replace {}", self.path.display()))
+    }
+
+    /// Reads and validates the snapshot, returning it with its save time.
+    pub fn load(&self) -> Result<(Config, SystemTime)> {
+        let source = fs::read_to_string(&self.path)
+            .with_context(|| format!("failed to read snapshot {}", self.path.display()))?;
+        let saved = fs::metadata(&self.path)?.modified()?;
+        let config = toml::from_str::<Config>(&source)
+            .with_context(|| format!("failed to parse snapshot {}", self.path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("snapshot {} is invalid", self.path.display()))?;
+        Ok((config, saved))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[[listeners]]
+name = "edge"
+bind = ":8443"
+tls = { cert = "cert.pem", key = "key.pem" }
+
+[[routes]]
+name = "app"
+matchers = { hosts = ["example.com"] }
+upstream = { strategy = "single", target = "http://127.0.0.1:8080" }
+"#;
+
+    #[test]
+    fn saved_snapshots_load_back() {
+        let config = std::env::temp_dir().join(format!("jester-{}.toml", std::process::id()));
+        let snapshot = Snapshot::for_config(&config, None);
+        assert!(snapshot
+            .path()
+            .to_string_lossy()
+            .ends_with(".toml.last-good"));
+        assert!(snapshot.load().is_err());
+
+        snapshot.save(CONFIG).unwrap();
+        let (loaded, _) = snapshot.load().unwrap();
+        assert_eq!(loaded.routes[0].name, "app");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(snapshot.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_file(snapshot.path()).unwrap();
+    }
+}
@@ -0,0 +1,825 @@
+//! Certificates from an ACME CA (RFC 8555) using DNS-01 challenges, issued at
+//! startup when missing and renewed in the background before they expire.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL, Engine};
+use bytes::Bytes;
+use http::{header, response::Parts, Method, Request};
+use http_body_util::{BodyExt, Limited};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::{
+    sync::{watch, OnceCell, Semaphore},
+    task::JoinSet,
+};
+
+use crate::{
+    client::{HttpClient, UpstreamClients},
+    cluster,
+    config::{Acme, AcmeCertificate, Cluster, UpstreamTls},
+    election,
+    plugin::full_body,
+    stats::RuntimeStats,
+    tls::CertInfo,
+};
+
+mod dns;
+
+use dns::{DnsUpdater, TxtRecord};
+
+/// Largest response body read from the CA or a DNS API.
+const MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Authorization and order status checks before giving up on an order.
+const POLL_ATTEMPTS: u32 = 60;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Longest an instance that is not the leader goes without looking for
+/// certificates the leader renewed.
+const FOLLOWER_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// How often an instance waiting at startup for the leader's certificates
+/// looks for them.
+const PROVISION_WAIT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Issues every configured certificate whose files are missing, so listeners
+/// can load them. Call before building the proxy; renewals of existing
+/// certificates happen later in the background.
+///
+/// With a `cluster` leader election, only the instance that takes the lease
+/// issues them. The others wait for its files to appear on shared storage,
+/// and take over if the lease comes free first.
+pub async fn provision(acme: &Acme, cluster: Option<&Cluster>) -> Result<()> {
+    acme.validate()?;
+    let manager = Arc::new(Manager::new(acme)?);
+    let Some((election, cluster)) =
+        cluster.and_then(|cluster| Some((cluster.election.as_ref()?, cluster)))
+    else {
+        return manager.issue_missing().await;
+    };
+    let node = cluster::node_name(cluster);
+    let mut waiting = false;
+    while !missing(acme).is_empty() {
+        match election::while_leading(election, &node, manager.issue_missing()).await {
+            Ok(Some(issued)) => return issued,
+            Ok(None) => {}
+            Err(err) => {
+                tracing::warn!(
+                    error = format!("{err:#}"),
+                    "leader election failed; waiting for ACME certificates"
+                )
+            }
+        }
+        if !std::mem::replace(&mut waiting, true) {
+            tracing::info!(
+                certificates = ?missing(acme).iter().map(|certificate| &certificate.domains).collect::<Vec<_>>(),
+                "waiting for the cluster leader to issue ACME certificates"
+            );
+        }
+        tokio::time::sleep(PROVISION_WAIT_INTERVAL).await;
+    }
+    Ok(())
+}
+
+/// Certificates whose certificate or key file does not exist.
+fn missing(acme: &Acme) -> Vec<AcmeCertificate> {
+    acme.certificates
+        .iter()
+        .filter(|certificate| {
+            !Path::new(&certificate.cert).exists() || !Path::new(&certificate.key).exists()
+        })
+        .cloned()
+        .collect()
+}
+
+/// Checks certificates every `check_interval_secs` and renews those close to
+/// expiry, calling `renewed` with each one written to disk.
+///
+/// With a `leader` election only the leader renews. The others check every
+/// few minutes and reload certificates whose files the leader replaced on
+/// shared storage.
+pub(crate) async fn renew_loop<F>(
+    acme: Acme,
+    renewed: F,
+    mut leader: Option<watch::Receiver<bool>>,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    F: Fn(&AcmeCertificate),
+{
+    let manager = match Manager::new(&acme) {
+        Ok(manager) => Arc::new(manager),
+        Err(err) => {
+            tracing::error!(error = %err, "ACME renewals disabled");
+            return;
+        }
+    };
+    let renew_before = Duration::from_secs(acme.renew_before_days * 24 * 60 * 60);
+    // Expiry of each certificate file when last checked.
+    let mut seen = HashMap::new();
+    loop {
+        let leading = leader
+            .as_mut()
+            .is_none_or(|leader| *leader.borrow_and_update());
+        let mut due = Vec::new();
+        for certificate in &acme.certificates {
+            match expires_at(&certificate.cert) {
+                Ok(expiry) => {
+                    metrics::gauge!(
+                        "jester_acme_certificate_expiry_timestamp_seconds",
+                        "certificate" => certificate.domains[0].clone()
+                    )
+                    .set(expiry as f64);
+                    let previous = seen.insert(certificate.cert.clone(), expiry);
+                    if !leading && previous.is_some_and(|previous| previous != expiry) {
+                        tracing::info!(
+                            domains = ?certificate.domains,
+                            "reloading certificate renewed by the leader"
+                        );
+                        renewed(certificate);
+                    }
+                    if expiry < unix_now() + renew_before.as_secs() {
+                        due.push(certificate.clone());
+                    }
+                }
+                Err(err) => {
+                    if leading {
+                        tracing::warn!(cert = certificate.cert, error = %err, "cannot read certificate expiry; renewing");
+                    }
+                    due.push(certificate.clone());
+                }
+            }
+        }
+        let mut check_interval = Duration::from_secs(acme.check_interval_secs);
+        if leading {
+            for (certificate, result) in manager.issue_all(due).await {
+                match result {
+                    Ok(()) => {
+                        if let Ok(expiry) = expires_at(&certificate.cert) {
+                            seen.insert(certificate.cert.clone(), expiry);
+                        }
+                        renewed(&certificate)
+                    }
+                    Err(err) => tracing::warn!(
+                        domains = ?certificate.domains,
+                        error = format!("{err:#}"),
+                        "ACME renewal failed; retrying at the next check"
+                    ),
+                }
+            }
+        } else {
+            check_interval = check_interval.min(FOLLOWER_CHECK_INTERVAL);
+        }
+        let promoted = async {
+            match leader.as_mut() {
+                Some(leader) if !leading => {
+                    if leader.wait_for(|leading| *leading).await.is_err() {
+                        std::future::pending::<()>().await;
+                    }
+                }
+                _ => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(check_interval) => {}
+            _ = promoted => {}
+            _ = shutdown.wait_for(|stop| *stop) => return,
+        }
+    }
+}
+
+/// Orders certificates, at most `max_concurrent_renewals` at a time.
+struct Manager {
+    acme: Acme,
+    http: HttpClient,
+    dns: DnsUpdater,
+    account: OnceCell<Arc<AcmeClient>>,
+    permits: Arc<Semaphore>,
+}
+
+impl Manager {
+    fn new(acme: &Acme) -> Result<Self> {
+        let http = UpstreamClients::new(RuntimeStats::default()).get(&UpstreamTls::default())?;
+        Ok(Self {
+            dns: DnsUpdater::new(acme, http.clone())?,
+            acme: acme.clone(),
+            http,
+            account: OnceCell::new(),
+            permits: Arc::new(Semaphore::new(acme.max_concurrent_renewals)),
+        })
+    }
+
+    /// Issues the certificates whose files are missing, failing if any of
+    /// them could not be obtained.
+    async fn issue_missing(self: &Arc<Self>) -> Result<()> {
+        let failures = self
+            .issue_all(missing(&self.acme))
+            .await
+            .into_iter()
+            .filter_map(|(certificate, result)| {
+                result
+                    .err()
+                    .map(|err| format!("{:?}: {err:#}", certificate.domains))
+            })
+            .collect::<Vec<_>>();
+        if !failures.is_empty() {
+            bail!(
+                "failed to obtain {} ACME certificates:\n  - {}",
+                failures.len(),
+                failures.join("\n  - ")
+            );
+        }
+        Ok(())
+    }
+
+    async fn issue_all(
+        self: &Arc<Self>,
+        certificates: Vec<AcmeCertificate>,
+    ) -> Vec<(AcmeCertificate, Result<()>)> {
+        let mut orders = JoinSet::new();
+        for certificate in certificates {
+            let manager = self.clone();
+            orders.spawn(async move {
+                let _permit = manager.permits.clone().acquire_owned().await;
+                let result = manager.issue(&certificate).await;
+                let outcome = if result.is_ok() { "success" } else { "error" };
+                metrics::counter!("jester_acme_orders_total", "outcome" => outcome).increment(1);
+                (certificate, result)
+            });
+        }
+        let mut results = Vec::new();
+        while let Some(joined) = orders.join_next().await {
+            match joined {
+                Ok(result) => results.push(result),
+                Err(err) => tracing::error!(error = %err, "ACME order task aborted"),
+            }
+        }
+        results
+    }
+
+    async fn issue(&self, certificate: &AcmeCertificate) -> Result<()> {
+        tracing::info!(domains = ?certificate.domains, "ordering ACME certificate");
+        let client = self
+            .account
+            .get_or_try_init(|| async {
+                AcmeClient::connect(&self.acme, self.http.clone())
+                    .await
+                    .map(Arc::new)
+            })
+            .await?;
+        let (key_pem, chain_pem) = client.order(certificate, &self.dns).await?;
+        write_private(Path::new(&certificate.key), key_pem.as_bytes())?;
+        write_atomic(Path::new(&certificate.cert), chain_pem.as_bytes(), None)?;
+        tracing::info!(
+            domains = ?certificate.domains,
+            cert = certificate.cert,
+            "ACME certificate issued"
+        );
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+    error: Option<Value>,
+}
+
+/// A registered ACME account: the directory, its key, and the account URL
+/// that signs every request.
+struct AcmeClient {
+    http: HttpClient,
+    directory: Directory,
+    key: AccountKey,
+    kid: String,
+    nonce: Mutex<Option<String>>,
+}
+
+struct AcmeResponse {
+    location: Option<String>,
+    body: Bytes,
+}
+
+impl AcmeClient {
+    async fn connect(acme: &Acme, http: HttpClient) -> Result<Self> {
+        let request = Request::get(&acme.directory).body(full_body(Bytes::new()))?;
+        let (parts, body) = send(&http, request).await?;
+        if !parts.status.is_success() {
+            bail!("ACME directory returned {}", parts.status);
+        }
+        let directory: Directory =
+            serde_json::from_slice(&body).context("invalid ACME directory")?;
+        let key = AccountKey::load_or_create(&Path::new(&acme.state_dir).join("account.key"))?;
+        let mut client = Self {
+            http,
+            directory,
+            key,
+            kid: String::new(),
+            nonce: Mutex::default(),
+        };
+        let url = client.directory.new_account.clone();
+        let payload = json!({ "termsOfServiceAgreed": true, "contact": acme.contact });
+        let response = client.post(&url, Some(&payload)).await?;
+        client.kid = response
+            .location
+            .context("ACME account response has no Location")?;
+        tracing::debug!(account = client.kid, "ACME account ready");
+        Ok(client)
+    }
+
+    /// Runs one order to completion, returning the new key and certificate
+    /// chain as PEM.
+    async fn order(
+        &self,
+        certificate: &AcmeCertificate,
+        dns: &DnsUpdater,
+    ) -> Result<(String, String)> {
+        let identifiers = certificate
+            .domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect::<Vec<_>>();
+        let response = self
+            .post(
+                &self.directory.new_order,
+                Some(&json!({ "identifiers": identifiers })),
+            )
+            .await?;
+        let order_url = response.location.context("ACME order has no Location")?;
+        let order: Order = serde_json::from_slice(&response.body)?;
+
+        let mut pending = Vec::new();
+        for url in &order.authorizations {
+            let authorization: Authorization = self.fetch(url).await?;
+            if authorization.status == "valid" {
+                continue;
+            }
+            let challenge = authorization
+                .challenges
+                .into_iter()
+                .find(|challenge| challenge.kind == "dns-01")
+                .with_context(|| {
+                    format!(
+                        "CA offers no dns-01 challenge for {}",
+                        authorization.identifier.value
+                    )
+                })?;
+            pending.push((url.clone(), authorization.identifier.value, challenge));
+        }
+        // `example.com` and `*.example.com` share one record name.
+        let mut records = BTreeMap::<String, TxtRecord>::new();
+        for (_, domain, challenge) in &pending {
+            let name = format!("_acme-challenge.{domain}");
+            records
+                .entry(name.clone())
+                .or_insert_with(|| TxtRecord::new(name))
+                .values
+                .push(self.key.dns_value(&challenge.token));
+        }
+        let mut records = records.into_values().collect::<Vec<_>>();
+        let validated = async {
+            dns.present(&mut records).await?;
+            dns.wait_for_propagation(&records).await?;
+            for (url, domain, challenge) in &pending {
+                self.post(&challenge.url, Some(&json!({}))).await?;
+                self.poll_authorization(url, domain).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        if let Err(err) = dns.cleanup(&records).await {
+            tracing::warn!(
+                error = format!("{err:#}"),
+                "failed to remove ACME challenge records"
+            );
+        }
+        validated?;
+
+        let key = rcgen::KeyPair::generate()?;
+        let mut params = rcgen::CertificateParams::new(certificate.domains.clone())?;
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let csr = params.serialize_request(&key)?;
+        self.post(
+            &order.finalize,
+            Some(&json!({ "csr": BASE64URL.encode(csr.der()) })),
+        )
+        .await?;
+        let mut order = order;
+        for _ in 0..POLL_ATTEMPTS {
+            order = self.fetch(&order_url).await?;
+            match order.status.as_str() {
+                "valid" => break,
+                "invalid" => bail!("ACME order failed: {}", problem(order.error.as_ref())),
+                _ => tokio::time::sleep(POLL_INTERVAL).await,
+            }
+        }
+        let url = order
+            .certificate
+            .context("ACME order did not complete in time")?;
+        let chain = self.post(&url, None).await?.body;
+        let chain = String::from_utf8(chain.to_vec()).context("certificate is not PEM")?;
+        Ok((key.serialize_pem(), chain))
+    }
+
+    async fn poll_authorization(&self, url: &str, domain: &str) -> Result<()> {
+        for _ in 0..POLL_ATTEMPTS {
+            let authorization: Authorization = self.fetch(url).await?;
+            match authorization.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" | "processing" => tokio::time::sleep(POLL_INTERVAL).await,
+                status => {
+                    let error = authorization
+                        .challenges
+                        .iter()
+                        .find_map(|challenge| challenge.error.as_ref());
+                    bail!("authorization for {domain} is {status}: {}", problem(error));
+                }
+            }
+        }
+        bail!("authorization for {domain} did not complete in time")
+    }
+
+    /// POST-as-GET of an ACME resource.
+    async fn fetch<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T> {
+        let response = self.post(url, None).await?;
+        serde_json::from_slice(&response.body)
+            .with_context(|| format!("invalid response from {url}"))
+    }
+
+    /// Sends a signed request, retrying once when the CA rejects the nonce.
+    async fn post(&self, url: &str, payload: Option<&Value>) -> Result<AcmeResponse> {
+        let mut retried = false;
+        loop {
+            let nonce = self.nonce().await?;
+            let body = self.key.sign(url, &nonce, &self.kid, payload)?;
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header(header::CONTENT_TYPE, "application/jose+json")
+                .body(full_body(body))?;
+            let (parts, body) = send(&self.http, request).await?;
+            self.keep_nonce(&parts);
+            if parts.status.is_success() {
+                return Ok(AcmeResponse {
+                    location: header_value(&parts, header::LOCATION),
+                    body,
+                });
+            }
+            let error: Option<Value> = serde_json::from_slice(&body).ok();
+            let bad_nonce = error
+                .as_ref()
+                .and_then(|error| error["type"].as_str())
+                .is_some_and(|kind| kind.ends_with(":badNonce"));
+            if bad_nonce && !retried {
+                retried = true;
+                continue;
+            }
+            bail!(
+                "{url} returned {}: {}",
+                parts.status,
+                problem(error.as_ref())
+            );
+        }
+    }
+
+    async fn nonce(&self) -> Result<String> {
+        if let Some(nonce) = self.nonce.lock().unwrap_or_else(|p| p.into_inner()).take() {
+            return Ok(nonce);
+        }
+        let request = Request::head(&self.directory.new_nonce).body(full_body(Bytes::new()))?;
+        let (parts, _) = send(&self.http, request).await?;
+        header_value(&parts, "replay-nonce").context("CA returned no Replay-Nonce")
+    }
+
+    fn keep_nonce(&self, parts: &Parts) {
+        if let Some(nonce) = header_value(parts, "replay-nonce") {
+            *self.nonce.lock().unwrap_or_else(|p| p.into_inner()) = Some(nonce);
+        }
+    }
+}
+
+fn header_value(parts: &Parts, name: impl header::AsHeaderName) -> Option<String> {
+    parts
+        .headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// `detail` of an RFC 7807 problem document, falling back to its type.
+fn problem(error: Option<&Value>) -> String {
+    error
+        .and_then(|error| error["detail"].as_str().or(error["type"].as_str()))
+        .unwrap_or("no details")
+        .to_string()
+}
+
+/// The ECDSA P-256 key identifying the ACME account.
+struct AccountKey {
+    pair: EcdsaKeyPair,
+    rng: SystemRandom,
+}
+
+impl AccountKey {
+    fn load_or_create(path: &Path) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let pkcs8 = match std::fs::read(path) {
+            Ok(pem) => {
+                let mut reader = std::io::Cursor::new(pem);
+                match rustls_pemfile::read_one(&mut reader) {
+                    Ok(Some(rustls_pemfile::Item::PKCS8Key(key))) => key,
+                    _ => bail!("{} is not a PKCS#8 PEM key", path.display()),
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| anyhow!("failed to generate ACME account key"))?;
+                let pem = pem_encode("PRIVATE KEY", pkcs8.as_ref());
+                write_private(path, pem.as_bytes())?;
+                tracing::info!(path = %path.display(), "created ACME account key");
+                pkcs8.as_ref().to_vec()
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|err| anyhow!("invalid ACME account key {}: {err}", path.display()))?;
+        Ok(Self { pair, rng })
+    }
+
+    /// Public key as a JWK with members in the order RFC 7638 hashes them.
+    fn jwk(&self) -> String {
+        let point = self.pair.public_key().as_ref();
+        format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            BASE64URL.encode(&point[1..33]),
+            BASE64URL.encode(&point[33..65])
+        )
+    }
+
+    /// TXT record value proving control for a challenge `token`.
+    fn dns_value(&self, token: &str) -> String {
+        let thumbprint = BASE64URL.encode(Sha256::digest(self.jwk()));
+        BASE64URL.encode(Sha256::digest(format!("{token}.{thumbprint}")))
+    }
+
+    /// Flattened JWS for `url`; `kid` empty means the key itself is embedded,
+    /// as account creation requires.
+    fn sign(&self, url: &str, nonce: &str, kid: &str, payload: Option<&Value>) -> Result<Vec<u8>> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        if kid.is_empty() {
+            protected["jwk"] = serde_json::from_str(&self.jwk())?;
+        } else {
+            protected["kid"] = kid.into();
+        }
+        let protected = BASE64URL.encode(protected.to_string());
+        let payload = match payload {
+            Some(payload) => BASE64URL.encode(payload.to_string()),
+            None => String::new(),
+        };
+        let signature = self
+            .pair
+            .sign(&self.rng, format!("{protected}.{payload}").as_bytes())
+            .map_err(|_| anyhow!("failed to sign ACME request"))?;
+        Ok(serde_json::to_vec(&json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": BASE64URL.encode(signature.as_ref()),
+        }))?)
+    }
+}
+
+/// Sends `request` and buffers the response body.
+async fn send(
+    client: &HttpClient,
+    request: Request<crate::plugin::ProxyBody>,
+) -> Result<(Parts, Bytes)> {
+    let uri = request.uri().clone();
+    let exchange = async {
+        let response = client.request(request).await?;
+        let (parts, body) = response.into_parts();
+        let body = Limited::new(body, MAX_RESPONSE_BYTES)
+            .collect()
+            .await
+            .map_err(|err| anyhow!(err))?
+            .to_bytes();
+        Ok::<_, anyhow::Error>((parts, body))
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .with_context(|| format!("request to {uri} timed out"))?
+        .with_context(|| format!("request to {uri} failed"))
+}
+
+fn pem_encode(label: &str, der: &[u8]) -> String {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).expect("base64 is ascii"));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+    pem
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    write_atomic(path, contents, Some(0o600))
+}
+
+/// Replaces `path` in one step so a listener never reads half a file.
+fn write_atomic(path: &Path, contents: &[u8], mode: Option<u32>) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    let mut tmp = PathBuf::from(path);
+    tmp.as_mut_os_string().push(".tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    std::io::Write::write_all(
+        &mut options
+            .open(&tmp)
+            .with_context(|| format!("failed to write {}", tmp.display()))?,
+        contents,
+    )?;
+    std::fs::rename(&tmp, path).with_context(|| format!("failed to replace {}", path.display()))
+}
+
+/// `notAfter` of the first certificate in a PEM file, in seconds since the epoch.
+fn expires_at(path: &str) -> Result<u64> {
+    let pem = std::fs::read(path).with_context(|| format!("failed to read {path}"))?;
+    let certs = rustls_pemfile::certs(&mut std::io::Cursor::new(pem))
+        .map_err(|_| anyhow!("invalid certificate data in {path}"))?;
+    let leaf = certs
+        .first()
+        .with_context(|| format!("no certificate in {path}"))?;
+    CertInfo::parse(leaf)
+        .map(|info| info.not_after)
+        .with_context(|| format!("cannot parse certificate validity in {path}"))
+}
+
+/// UTC `(year, month, day, hour, minute, second)` of a Unix timestamp.
+pub(crate) fn civil_from_unix(secs: u64) -> (i64, u32, u32, u32, u32, u32) {
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    let time = secs % 86_400;
+    (
+        year,
+        month,
+        day,
+        (time / 3600) as u32,
+        (time / 60 % 60) as u32,
+        (time % 60) as u32,
+    )
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_unix_time_to_civil_dates() {
+        assert_eq!(civil_from_unix(1_440_938_160), (2015, 8, 30, 12, 36, 0));
+        assert_eq!(civil_from_unix(951_782_400), (2000, 2, 29, 0, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn followers_wait_for_the_leaders_certificates() {
+        let dir = std::env::temp_dir().join(format!("jester-acme-wait-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let acme: Acme = toml::from_str(&format!(
+            r#"
+            state_dir = "{dir}"
+            dns = {{ provider = "cloudflare", api_token = "t", zone_id = "z" }}
+            certificates = [{{ domains = ["example.com"], cert = "{cert}", key = "{key}" }}]
+            "#,
+            dir = dir.display(),
+            cert = cert.display(),
+            key = key.display(),
+        ))
+        .unwrap();
+        // Nothing answers on the lease backend, so this instance cannot lead.
+        let cluster: Cluster = toml::from_str(
+            r#"
+            bind = "127.0.0.1:0"
+            node = "b"
+            election = { backend = "redis", address = "127.0.0.1:1" }
+            "#,
+        )
+        .unwrap();
+        let leader = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            std::fs::write(&key, "key").unwrap();
+            std::fs::write(&cert, "cert").unwrap();
+        };
+        let (provisioned, ()) = tokio::join!(provision(&acme, Some(&cluster)), leader);
+        provisioned.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn account_keys_sign_jws_and_derive_challenge_values() {
+        let dir = std::env::temp_dir().join(format!("jester-acme-{}", std::process::id()));
+        let path = dir.join("account.key");
+        let key = AccountKey::load_or_create(&path).unwrap();
+        let reloaded = AccountKey::load_or_create(&path).unwrap();
+        assert_eq!(key.jwk(), reloaded.jwk());
+        assert_eq!(key.dns_value("token"), reloaded.dns_value("token"));
+        assert_eq!(key.dns_value("token").len(), 43);
+
+        let jws = key
+            .sign("https://ca/new-acct", "n0nce", "", Some(&json!({})))
+            .unwrap();
+        let jws: Value = serde_json::from_slice(&jws).unwrap();
+        let protected: Value = serde_json::from_slice(
+            &BASE64URL
+                .decode(jws["protected"].as_str().unwrap())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(protected["alg"], "ES256");
+        assert_eq!(protected["jwk"]["crv"], "P-256");
+        assert!(protected.get("kid").is_none());
+        let signature = BASE64URL
+            .decode(jws["signature"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(signature.len(), 64);
+        let signed = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        ring::signature::UnparsedPublicKey::new(
+            &ring::signature::ECDSA_P256_SHA256_FIXED,
+            key.pair.public_key().as_ref(),
+        )
+        .verify(signed.as_bytes(), &signature)
+        .unwrap();
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,557 @@
+//! Publishing `_acme-challenge` TXT records through a DNS provider, and waiting
+//! until resolvers serve them.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use http::{header, Method, Request};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Sha256, Sha512};
+
+use super::{send, unix_now};
+use crate::{
+    client::HttpClient,
+    config::{parse_resolver, Acme, DnsProvider, TsigAlgorithm},
+    dns::{encode_name, exchange, header, rand_id, rcode_name, skip_name, CLASS_IN},
+    plugin::full_body,
+    sigv4::{self, amz_timestamps, SigV4},
+};
+
+const CHALLENGE_TTL: u32 = 60;
+const CLOUDFLARE_API: &str = "https://api.cloudflare.com/client/v4";
+const ROUTE53_API: &str = "https://route53.amazonaws.com";
+
+const TYPE_SOA: u16 = 6;
+const TYPE_TXT: u16 = 16;
+const TYPE_TSIG: u16 = 250;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+
+/// The TXT values published under one name.
+pub(super) struct TxtRecord {
+    pub(super) name: String,
+    pub(super) values: Vec<String>,
+    /// Provider record IDs, for APIs that delete by ID.
+    ids: Vec<String>,
+}
+
+impl TxtRecord {
+    pub(super) fn new(name: String) -> Self {
+        Self {
+            name,
+            values: Vec::new(),
+            ids: Vec::new(),
+        }
+    }
+}
+
+enum Provider {
+    Cloudflare {
+        /// API base URL.
+        api: String,
+        token: String,
+        zone_id: String,
+    },
+    Route53 {
+        /// API base URL.
+        api: String,
+        access_key_id: String,
+        secret_access_key: String,
+        hosted_zone_id: String,
+    },
+    Rfc2136 {
+        server: SocketAddr,
+        zone: String,
+        key: TsigKey,
+    },
+}
+
+pub(super) struct DnsUpdater {
+    http: HttpClient,
+    provider: Provider,
+    resolvers: Vec<SocketAddr>,
+    timeout: Duration,
+    interval: Duration,
+}
+
+impl DnsUpdater {
+    pub(super) fn new(acme: &Acme, http: HttpClient) -> Result<Self> {
+        let provider = match &acme.dns {
+            DnsProvider::Cloudflare {
+                api_token,
+                zone_id,
+                endpoint,
+            } => Provider::Cloudflare {
+                api: api_base(endpoint.as_deref(), CLOUDFLARE_API),
+                token: api_token.clone(),
+                zone_id: zone_id.clone(),
+            },
+            DnsProvider::Route53 {
+                access_key_id,
+                secret_access_key,
+                hosted_zone_id,
+                endpoint,
+            } => Provider::Route53 {
+                api: api_base(endpoint.as_deref(), ROUTE53_API),
+                access_key_id: access_key_id.clone(),
+                secret_access_key: secret_access_key.clone(),
+                hosted_zone_id: hosted_zone_id
+                    .trim_start_matches("/hostedzone/")
+                    .to_string(),
+            },
+            DnsProvider::Rfc2136 {
+                server,
+                zone,
+                tsig_key,
+                tsig_secret,
+                tsig_algorithm,
+            } => Provider::Rfc2136 {
+                server: parse_resolver(server)?,
+                zone: zone.clone(),
+                key: TsigKey {
+                    name: tsig_key.trim_end_matches('.').to_ascii_lowercase(),
+                    algorithm: *tsig_algorithm,
+                    secret: BASE64
+                        .decode(tsig_secret.trim())
+                        .context("rfc2136 tsig_secret must be base64")?,
+                },
+            },
+        };
+        let resolvers = match (&provider, acme.propagation.resolvers.is_empty()) {
+            (Provider::Rfc2136 { server, .. }, true) => vec![*server],
+            (_, true) => vec![
+                SocketAddr::from((Ipv4Addr::new(1, 1, 1, 1), 53)),
+                SocketAddr::from((Ipv4Addr::new(8, 8, 8, 8), 53)),
+            ],
+            (_, false) => acme
+                .propagation
+                .resolvers
+                .iter()
+                .map(|resolver| parse_resolver(resolver))
+                .collect::<Result<_>>()?,
+        };
+        Ok(Self {
+            http,
+            provider,
+            resolvers,
+            timeout: Duration::from_secs(acme.propagation.timeout_secs),
+            interval: Duration::from_secs(acme.propagation.interval_secs),
+        })
+    }
+
+    pub(super) async fn present(&self, records: &mut [TxtRecord]) -> Result<()> {
+        for record in records {
+            match &self.provider {
+                Provider::Cloudflare {
+                    api,
+                    token,
+                    zone_id,
+                } => {
+                    for value in &record.values {
+                        let id = self
+                            .cloudflare_create(api, token, zone_id, &record.name, value)
+                            .await?;
+                        record.ids.push(id);
+                    }
+                }
+                Provider::Route53 { .. } => self.route53_change("UPSERT", record).await?,
+                Provider::Rfc2136 { server, zone, key } => {
+                    let message = update_message(zone, record, true)?;
+                    dns_update(*server, key, message).await?;
+                }
+            }
+            tracing::debug!(name = record.name, "published ACME challenge record");
+        }
+        Ok(())
+    }
+
+    pub(super) async fn cleanup(&self, records: &[TxtRecord]) -> Result<()> {
+        for record in records {
+            match &self.provider {
+                Provider::Cloudflare {
+                    api,
+                    token,
+                    zone_id,
+                } => {
+                    for id in &record.ids {
+                        let request = Request::builder()
+                            .method(Method::DELETE)
+                            .uri(format!("{api}/zones/{zone_id}/dns_records/{id}"))
+                            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+                            .body(full_body(Bytes::new()))?;
+                        cloudflare_result(&self.http, request).await?;
+                    }
+                }
+                Provider::Route53 { .. } => self.route53_change("DELETE", record).await?,
+                Provider::Rfc2136 { server, zone, key } => {
+                    let message = update_message(zone, record, false)?;
+                    dns_update(*server, key, message).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Polls every resolver until it serves all values of every record.
+    pub(super) async fn wait_for_propagation(&self, records: &[TxtRecord]) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        loop {
+            let mut missing = None;
+            'check: for resolver in &self.resolvers {
+                for record in records {
+                    let served = query_txt(*resolver, &record.name)
+                        .await
+                        .unwrap_or_else(|err| {
+                            tracing::debug!(%resolver, error = %err, "TXT lookup failed");
+                            Vec::new()
+                        });
+                    if !record.values.iter().all(|value| served.contains(value)) {
+                        missing = Some((*resolver, record.name.as_str()));
+                        break 'check;
+                    }
+                }
+            }
+            let Some((resolver, name)) = missing else {
+                return Ok(());
+            };
+            if tokio::time::Instant::now() + self.interval > deadline {
+                bail!(
+                    "TXT record {name} not visible at {resolver} after {}s",
+                    self.timeout.as_secs()
+                );
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    async fn cloudflare_create(
+        &self,
+        api: &str,
+        token: &str,
+        zone_id: &str,
+        name: &str,
+        value: &str,
+    ) -> Result<String> {
+        let body = json!({ "type": "TXT", "name": name, "content": value, "ttl": CHALLENGE_TTL });
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{api}/zones/{zone_id}/dns_records"))
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(full_body(body.to_string()))?;
+        let result = cloudflare_result(&self.http, request).await?;
+        result["id"]
+            .as_str()
+            .map(str::to_string)
+            .context("Cloudflare returned no record id")
+    }
+
+    async fn route53_change(&self, action: &str, record: &TxtRecord) -> Result<()> {
+        let Provider::Route53 {
+            api,
+            access_key_id,
+            secret_access_key,
+            hosted_zone_id,
+        } = &self.provider
+        else {
+            unreachable!("route53_change called for another provider");
+        };
+        let values = record
+            .values
+            .iter()
+            .map(|value| format!("<ResourceRecord><Value>\"{value}\"</Value></ResourceRecord>"))
+            .collect::<String>();
+        let body = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><ChangeResourceRecordSetsRequest xmlns="https://route53.amazonaws.com/doc/2013-04-01/"><ChangeBatch><Changes><Change><Action>{action}</Action><ResourceRecordSet><Name>{}</Name><Type>TXT</Type><TTL>{CHALLENGE_TTL}</TTL><ResourceRecords>{values}</ResourceRecords></ResourceRecordSet></Change></Changes></ChangeBatch></ChangeResourceRecordSetsRequest>"#,
+            record.name
+        );
+        let path = format!("/2013-04-01/hostedzone/{hosted_zone_id}/rrset/");
+        let uri: http::Uri = format!("{api}{path}").parse()?;
+        let host = uri.authority().context("Route 53 endpoint has no host")?;
+        let (_, amz_date) = amz_timestamps(unix_now());
+        let signer = SigV4 {
+            access_key_id,
+            secret_access_key,
+            region: "us-east-1",
+            service: "route53",
+        };
+        let headers = [
+            ("content-type", "application/xml"),
+            ("host", host.as_str()),
+            ("x-amz-date", amz_date.as_str()),
+        ];
+        let authorization = signer.authorization(
+            "POST",
+            uri.path(),
+            "",
+            &headers,
+            &sigv4::payload_hash(body.as_bytes()),
+        );
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/xml")
+            .header("x-amz-date", &amz_date)
+            .header(header::AUTHORIZATION, authorization)
+            .body(full_body(body))?;
+        let (parts, body) = send(&self.http, request).await?;
+        if !parts.status.is_success() {
+            bail!(
+                "Route 53 returned {}: {}",
+                parts.status,
+                String::from_utf8_lossy(&body)
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The configured API base URL, or the provider's own.
+fn api_base(endpoint: Option<&str>, default: &str) -> String {
+    endpoint
+        .unwrap_or(default)
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Sends a Cloudflare API request and returns its `result`.
+async fn cloudflare_result(
+    http: &HttpClient,
+    request: Request<crate::plugin::ProxyBody>,
+) -> Result<serde_json::Value> {
+    #[derive(Deserialize)]
+    struct Envelope {
+        success: bool,
+        #[serde(default)]
+        errors: Vec<serde_json::Value>,
+        #[serde(default)]
+        result: serde_json::Value,
+    }
+
+    let (parts, body) = send(http, request).await?;
+    let envelope: Envelope = serde_json::from_slice(&body)
+        .with_context(|| format!("unexpected Cloudflare response ({})", parts.status))?;
+    if !envelope.success {
+        bail!(
+            "Cloudflare returned {}: {}",
+            parts.status,
+            serde_json::Value::from(envelope.errors)
+        );
+    }
+    Ok(envelope.result)
+}
+
+/// Key signing RFC 2136 updates (RFC 8945).
+struct TsigKey {
+    name: String,
+    algorithm: TsigAlgorithm,
+    secret: Vec<u8>,
+}
+
+impl TsigKey {
+    const FUDGE: u16 = 300;
+
+    fn algorithm_name(&self) -> &'static str {
+        match self.algorithm {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm {
+            TsigAlgorithm::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+                    .expect("HMAC accepts any key length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            TsigAlgorithm::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(&self.secret)
+                    .expect("HMAC accepts any key length");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Appends a TSIG record signed at `time` to a complete message.
+    fn sign(&self, message: &mut Vec<u8>, time: u64) -> Result<()> {
+        let time = &time.to_be_bytes()[2..];
+        let mut signed = message.clone();
+        encode_name(&mut signed, &self.name)?;
+        signed.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        signed.extend_from_slice(&0u32.to_be_bytes());
+        encode_name(&mut signed, self.algorithm_name())?;
+        signed.extend_from_slice(time);
+        signed.extend_from_slice(&Self::FUDGE.to_be_bytes());
+        signed.extend_from_slice(&[0, 0, 0, 0]); // error, other length
+        let mac = self.mac(&signed);
+
+        let mut rdata = Vec::new();
+        encode_name(&mut rdata, self.algorithm_name())?;
+        rdata.extend_from_slice(time);
+        rdata.extend_from_slice(&Self::FUDGE.to_be_bytes());
+        rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(&mac);
+        rdata.extend_from_slice(&message[..2]); // original id
+        rdata.extend_from_slice(&[0, 0, 0, 0]);
+        encode_name(message, &self.name)?;
+        message.extend_from_slice(&TYPE_TSIG.to_be_bytes());
+        message.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        message.extend_from_slice(&0u32.to_be_bytes());
+        message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        message.extend_from_slice(&rdata);
+        let additional = u16::from_be_bytes([message[10], message[11]]) + 1;
+        message[10..12].copy_from_slice(&additional.to_be_bytes());
+        Ok(())
+    }
+}
+
+/// RFC 2136 update adding `record`'s values, or deleting exactly those values.
+fn update_message(zone: &str, record: &TxtRecord, add: bool) -> Result<Vec<u8>> {
+    let mut message = header(rand_id(), 5 << 11, [1, 0, record.values.len() as u16, 0]);
+    encode_name(&mut message, zone)?;
+    message.extend_from_slice(&TYPE_SOA.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    for value in &record.values {
+        let (class, ttl) = if add {
+            (CLASS_IN, CHALLENGE_TTL)
+        } else {
+            (CLASS_NONE, 0)
+        };
+        encode_name(&mut message, &record.name)?;
+        message.extend_from_slice(&TYPE_TXT.to_be_bytes());
+        message.extend_from_slice(&class.to_be_bytes());
+        message.extend_from_slice(&ttl.to_be_bytes());
+        let value = value.as_bytes();
+        if value.len() > 255 {
+            bail!("TXT value for {} is too long", record.name);
+        }
+        message.extend_from_slice(&(value.len() as u16 + 1).to_be_bytes());
+        message.push(value.len() as u8);
+        message.extend_from_slice(value);
+    }
+    Ok(message)
+}
+
+async fn dns_update(server: SocketAddr, key: &TsigKey, mut message: Vec<u8>) -> Result<()> {
+    key.sign(&mut message, unix_now())?;
+    let response = exchange(server, &message).await?;
+    match response[3] & 0x0f {
+        0 => Ok(()),
+        rcode => bail!("{server} rejected the DNS update: {}", rcode_name(rcode)),
+    }
+}
+
+/// TXT values served for `name` by `resolver`.
+async fn query_txt(resolver: SocketAddr, name: &str) -> Result<Vec<String>> {
+    let mut query = header(rand_id(), 0x0100, [1, 0, 0, 0]);
+    encode_name(&mut query, name)?;
+    query.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    let response = exchange(resolver, &query).await?;
+    parse_txt_answers(&response)
+}
+
+fn parse_txt_answers(message: &[u8]) -> Result<Vec<String>> {
+    let malformed = || anyhow::anyhow!("malformed DNS response");
+    let u16_at = |at: usize| -> Result<u16> {
+        Ok(u16::from_be_bytes(
+            message.get(at..at + 2).ok_or_else(malformed)?.try_into()?,
+        ))
+    };
+    match message.get(3).ok_or_else(malformed)? & 0x0f {
+        0 => {}
+        3 => return Ok(Vec::new()),
+        rcode => bail!("DNS query failed: {}", rcode_name(rcode)),
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(message, at).ok_or_else(malformed)? + 4;
+    }
+    let mut values = Vec::new();
+    for _ in 0..answers {
+        at = skip_name(message, at).ok_or_else(malformed)?;
+        let kind = u16_at(at)?;
+        let len = usize::from(u16_at(at + 8)?);
+        let rdata = message.get(at + 10..at + 10 + len).ok_or_else(malformed)?;
+        at += 10 + len;
+        if kind != TYPE_TXT {
+            continue;
+        }
+        let mut value = Vec::new();
+        let mut rest = rdata;
+        while let Some((&len, tail)) = rest.split_first() {
+            let (chunk, tail) = tail
+                .split_at_checked(usize::from(len))
+                .ok_or_else(malformed)?;
+            value.extend_from_slice(chunk);
+            rest = tail;
+        }
+        values.push(String::from_utf8_lossy(&value).into_owned());
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_txt_answers_with_compressed_names() {
+        let mut response = header(7, 0x8180, [1, 2, 0, 0]);
+        encode_name(&mut response, "_acme-challenge.example.com").unwrap();
+        response.extend_from_slice(&[0, 16, 0, 1]);
+        // Two TXT answers pointing back at the question name; the second is
+        // split into two character strings.
+        response.extend_from_slice(&[0xc0, 12, 0, 16, 0, 1, 0, 0, 0, 60, 0, 4, 3]);
+        response.extend_from_slice(b"abc");
+        response.extend_from_slice(&[0xc0, 12, 0, 16, 0, 1, 0, 0, 0, 60, 0, 6, 2]);
+        response.extend_from_slice(b"de");
+        response.push(2);
+        response.extend_from_slice(b"fg");
+        assert_eq!(parse_txt_answers(&response).unwrap(), ["abc", "defg"]);
+
+        let nxdomain = header(7, 0x8183, [0, 0, 0, 0]);
+        assert!(parse_txt_answers(&nxdomain).unwrap().is_empty());
+        assert!(parse_txt_answers(&response[..response.len() - 3]).is_err());
+    }
+
+    #[test]
+    fn builds_tsig_signed_updates() {
+        let mut record = TxtRecord::new("_acme-challenge.example.com".into());
+        record.values = vec!["value-one".into(), "value-two".into()];
+        let key = TsigKey {
+            name: "jester".into(),
+            algorithm: TsigAlgorithm::HmacSha256,
+            secret: b"secret".to_vec(),
+        };
+        let unsigned = update_message("example.com", &record, true).unwrap();
+        assert_eq!(u16::from_be_bytes([unsigned[2], unsigned[3]]), 0x2800);
+        assert_eq!(&unsigned[4..12], &[0, 1, 0, 0, 0, 2, 0, 0]);
+
+        let mut signed = unsigned.clone();
+        key.sign(&mut signed, 1_700_000_000).unwrap();
+        assert_eq!(&signed[10..12], &[0, 1]);
+        let tsig = &signed[unsigned.len()..];
+        assert!(tsig.starts_with(b"\x06jester\x00\x00\xfa\x00\xff"));
+        // The MAC follows the algorithm name, time, fudge, and MAC size.
+        let rdata = &tsig[18..];
+        let mac_at = b"\x0bhmac-sha256\x00".len() + 6 + 2 + 2;
+        assert_eq!(&rdata[mac_at - 2..mac_at], &[0, 32]);
+        assert_eq!(&rdata[mac_at + 32..mac_at + 34], &unsigned[..2]);
+
+        let deleted = update_message("example.com", &record, false).unwrap();
+        assert!(deleted.windows(4).any(|window| window == [0, 16, 0, 254]));
+    }
+}
@@ -0,0 +1,789 @@
+//! Plain-HTTP admin API served on `admin.listen` and/or the `admin.socket`
+//! unix socket used by `jester ctl`.
+//!
+//! Endpoints:
+//! - `POST /config/validate?strict=true`: checks a candidate config (TOML, JSON
+//!   when sent with `content-type: application/json`, or a `multipart/form-data`
+//!   `config` part with `overlay` parts) against this build, loading it as
+//!   `jester run` would, and returns structured diagnostics.
+//! - `GET`/`PUT`/`DELETE /log-level`: reads, replaces, or resets the log filter
+//!   through the [`LogControl`] the embedder registered.
+//! - `GET /routes`, `GET /stats`: the live route table and in-flight counters.
+//! - `GET /metrics`: request latency histograms in OpenMetrics format, with
+//!   trace IDs as exemplars.
+//! - `GET /startup`: the [`StartupReport`](crate::startup::StartupReport)
+//!   logged when listeners were bound.
+//! - `POST /reload`: reloads routes and filters through the registered
+//!   [`ConfigLoader`](crate::proxy::ConfigLoader).
+//! - `POST /drain`: stops accepting connections, as on a shutdown signal.
+//! - `POST /profile?seconds=30&rate=1&route=app`: samples requests for a while
+//!   and returns where their filters, routes, and upstream calls spent time, as
+//!   folded stacks. With the `profiling` feature, `kind=cpu` and `kind=heap`
+//!   profile native stacks and allocations instead, and `format=svg` returns a
+//!   flamegraph.
+
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use http::{header, HeaderValue, Method, StatusCode};
+use http_body_util::{BodyExt, Limited};
+use hyper::{body::Incoming, server::conn::http1, service::service_fn, Request};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::watch,
+};
+
+use crate::{
+    config::{self, Config, ConfigSource, Matchers, Upstream},
+    filter::FilterRegistry,
+    plugin::{full_body, text_response, HttpResponse},
+    profile,
+    proxy::{Proxy, ProxyControl, ACCEPT_ERROR_BACKOFF},
+};
+
+/// Largest request body the admin API will read.
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Runtime access to the process's log filter.
+///
+/// jester does not install a tracing subscriber itself; the binary that does
+/// implements this (e.g. over `tracing_subscriber::reload`) and registers it with
+/// [`ProxyBuilder::log_control`](crate::proxy::ProxyBuilder::log_control).
+pub trait LogControl: Send + Sync + 'static {
+    /// Current filter directives, e.g. `info,jester_core::proxy=trace`.
+    fn directives(&self) -> String;
+    /// Replaces the filter; invalid directives leave the current one in place.
+    fn set_directives(&self, directives: &str) -> Result<()>;
+}
+
+/// What admin handlers need from the running proxy.
+pub(crate) struct AdminState {
+    pub(crate) control: Arc<ProxyControl>,
+    pub(crate) log_control: Option<LogHandle>,
+}
+
+/// Registered [`LogControl`] plus the directives it started with, for resets.
+pub(crate) struct LogHandle {
+    control: Arc<dyn LogControl>,
+    initial: String,
+}
+
+impl LogHandle {
+    pub(crate) fn new(control: Arc<dyn LogControl>) -> Self {
+        let initial = control.directives();
+        Self { control, initial }
+    }
+}
+
+/// A bound admin endpoint.
+pub(crate) enum AdminListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+impl AdminListener {
+    /// Binds the unix control socket, replacing a stale socket file left by a
+    /// previous run, and restricts it to the owning user.
+    #[cfg(unix)]
+    pub(crate) fn bind_unix(path: &str) -> Result<Self> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let path = PathBuf::from(path);
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            if !metadata.file_type().is_socket() {
+                anyhow::bail!("{} exists and is not a socket", path.display());
+            }
+            std::fs::remove_file(&path)
+                .with_context(|| format!("failed to remove stale socket {}", path.display()))?;
+        }
+        let listener = tokio::net::UnixListener::bind(&path)
+            .with_context(|| format!("failed to bind admin socket {}", path.display()))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(Self::Unix(listener, path))
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn bind_unix(_path: &str) -> Result<Self> {
+        anyhow::bail!("admin.socket requires a unix platform")
+    }
+
+    /// Accepts one connection and serves it in the background.
+    async fn accept(&self, state: &Arc<AdminState>) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(tcp) => {
+                let (stream, peer) = tcp.accept().await?;
+                spawn_connection(stream, peer.to_string(), state.clone());
+            }
+            #[cfg(unix)]
+            Self::Unix(unix, path) => {
+                let (stream, _) = unix.accept().await?;
+                spawn_connection(stream, path.display().to_string(), state.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) async fn serve_admin(
+    listener: AdminListener,
+    state: Arc<AdminState>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    loop {
+        tokio::select! {
+            biased;
+            _ = shutdown.changed() => {
+                tracing::info!("admin API shutting down");
+                break;
+            }
+            accepted = listener.accept(&state) => {
+                if let Err(err) = accepted {
+                    // Typically fd exhaustion; back off instead of spinning.
+                    tracing::warn!(error = %err, "admin accept failed");
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                }
+            }
+        }
+    }
+    #[cfg(unix)]
+    if let AdminListener::Unix(_, path) = &listener {
+        std::fs::remove_file(path).ok();
+    }
+    Ok(())
+}
+
+fn spawn_connection<IO>(io: IO, peer: String, state: Arc<AdminState>)
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let service = service_fn(move |req| {
+            let state = state.clone();
+            async move { Ok::<_, hyper::Error>(handle(&state, req).await) }
+        });
+        if let Err(err) = http1::Builder::new()
+            .serve_connection(TokioIo::new(io), service)
+            .await
+        {
+            tracing::warn!(peer, error = %err, "admin connection closed with error");
+        }
+    });
+}
+
+async fn handle(state: &AdminState, req: Request<Incoming>) -> HttpResponse {
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/config/validate") => validate(state, req).await,
+        (_, "/config/validate") => text_response(StatusCode::METHOD_NOT_ALLOWED, "use POST"),
+        (_, "/log-level") => log_level(state, req).await,
+        (&Method::GET, "/routes") => json_response(&routes(&state.control)),
+        (&Method::GET, "/stats") => json_response(&stats(&state.control)),
+        (&Method::GET, "/metrics") => metrics(&state.control),
+        (&Method::GET, "/startup") => match state.control.startup() {
+            Some(report) => json_response(report),
+            None => text_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "listeners are not bound yet",
+            ),
+        },
+        (&Method::GET, "/cluster") => match state.control.gossip() {
+            Some(gossip) => json_response(&gossip.snapshot()),
+            None => text_response(StatusCode::NOT_FOUND, "cluster mode is not enabled"),
+        },
+        (&Method::POST, "/reload") if !state.control.can_reload() => text_response(
+            StatusCode::NOT_IMPLEMENTED,
+            "reload is not available: no config loader registered",
+        ),
+        (&Method::POST, "/reload") => match state.control.reload() {
+            Ok(outcome) => json_response(&outcome),
+            Err(err) => text_response(StatusCode::CONFLICT, format!("reload failed: {err:#}")),
+        },
+        (&Method::POST, "/drain") => {
+            state.control.drain();
+            text_response(StatusCode::ACCEPTED, "draining")
+        }
+        (&Method::POST, "/profile") => profile(&state.control, req.uri().query()).await,
+        (
+            _,
+            "/routes" | "/stats" | "/metrics" | "/startup" | "/cluster" | "/reload" | "/drain"
+            | "/profile",
+        ) => text_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        _ => text_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// One entry of `GET /routes`, in match order.
+#[derive(Debug, Serialize)]
+struct RouteEntry {
+    name: String,
+    matchers: Matchers,
+    upstream: Upstream,
+    inflight: usize,
+}
+
+fn routes(control: &ProxyControl) -> Vec<RouteEntry> {
+    let stats = control.stats();
+    control
+        .config()
+        .routes
+        .into_iter()
+        .map(|route| RouteEntry {
+            inflight: stats.route_inflight(&route.name),
+            name: route.name,
+            matchers: route.matchers,
+            upstream: route.upstream,
+        })
+        .collect()
+}
+
+/// `GET /stats`: current in-flight and open-connection counts.
+#[derive(Debug, Serialize)]
+struct StatsSnapshot {
+    routes: HashMap<String, usize>,
+    targets: HashMap<String, usize>,
+    listeners: HashMap<String, usize>,
+}
+
+fn stats(control: &ProxyControl) -> StatsSnapshot {
+    let stats = control.stats();
+    StatsSnapshot {
+        routes: stats.routes(),
+        targets: stats.targets(),
+        listeners: stats.listeners(),
+    }
+}
+
+/// `GET /metrics`: request latency histograms with trace ID exemplars, which
+/// only the OpenMetrics format can carry.
+fn metrics(control: &ProxyControl) -> HttpResponse {
+    let mut response = text_response(StatusCode::OK, control.stats().encode_latency());
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/openmetrics-text; version=1.0.0; charset=utf-8"),
+    );
+    response
+}
+
+/// `POST /profile`: profiles for `seconds`, then returns folded stacks, or a
+/// flamegraph with `format=svg`. `kind=requests`, the default, samples `rate`
+/// of requests and keeps stacks through `route` when given; `kind=cpu`
+/// samples native stacks `frequency` times a second, and `kind=heap` samples
+/// allocations.
+async fn profile(control: &ProxyControl, query: Option<&str>) -> HttpResponse {
+    let mut kind = "requests";
+    let mut format = "folded";
+    let mut seconds = 30;
+    let mut rate = 1.0;
+    let mut route = None;
+    let mut frequency = 99;
+    for pair in query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+    {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let parsed = match key {
+            "kind" => matches!(value, "requests" | "cpu" | "heap")
+                .then(|| kind = value)
+                .is_some(),
+            "format" => matches!(value, "folded" | "svg")
+                .then(|| format = value)
+                .is_some(),
+            "seconds" => value.parse().map(|value| seconds = value).is_ok(),
+            "rate" => value.parse().map(|value| rate = value).is_ok(),
+            "route" => {
+                route = Some(value);
+                true
+            }
+            "frequency" => value
+                .parse()
+                .ok()
+                .filter(|value| (1..=1000).contains(value))
+                .map(|value| frequency = value)
+                .is_some(),
+            _ => {
+                return text_response(
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "unknown parameter `{key}`; expected kind, seconds, rate, route, frequency, or format"
+                    ),
+                )
+            }
+        };
+        if !parsed {
+            return text_response(
+                StatusCode::BAD_REQUEST,
+                format!("invalid value `{value}` for `{key}`"),
+            );
+        }
+    }
+    if (kind != "requests" || format == "svg") && !cfg!(feature = "profiling") {
+        return without_profiling();
+    }
+    let duration = Duration::from_secs(seconds);
+    let (folded, sampled) = match kind {
+        "requests" => {
+            let session = match control.profiler().start(rate, duration) {
+                Ok(session) => session,
+                Err(err) if control.profiler().running() => {
+                    return text_response(StatusCode::CONFLICT, err.to_string())
+                }
+                Err(err) => return text_response(StatusCode::BAD_REQUEST, err.to_string()),
+            };
+            tracing::info!(seconds, rate, "profiling started through admin API");
+            session.finished().await;
+            (session.folded(route), Some(session.sampled()))
+        }
+        _ => match native_profile(kind, duration, frequency).await {
+            Ok(folded) => (folded, None),
+            Err(response) => return response,
+        },
+    };
+    let mut response = match format {
+        "svg" => flamegraph(kind, &folded),
+        _ => text_response(StatusCode::OK, folded),
+    };
+    if let Some(sampled) = sampled {
+        response
+            .headers_mut()
+            .insert(profile::SAMPLED_HEADER, HeaderValue::from(sampled));
+    }
+    response
+}
+
+#[cfg(feature = "profiling")]
+async fn native_profile(
+    kind: &str,
+    duration: Duration,
+    frequency: i32,
+) -> Result<String, HttpResponse> {
+    if let Err(err) = profile::check_duration(duration) {
+        return Err(text_response(StatusCode::BAD_REQUEST, err.to_string()));
+    }
+    if !profile::native::available(kind) {
+        let reason = match kind {
+            "cpu" => "cpu profiles are only available on Unix",
+            _ => "heap profiles need `jester_core::HeapSampler` as the global allocator",
+        };
+        return Err(text_response(StatusCode::NOT_IMPLEMENTED, reason));
+    }
+    tracing::info!(
+        kind,
+        seconds = duration.as_secs(),
+        "profiling started through admin API"
+    );
+    let profiled = match kind {
+        "cpu" => profile::native::cpu(duration, frequency).await,
+        _ => profile::native::heap(duration).await,
+    };
+    profiled.map_err(|err| match profile::native::running(kind) {
+        true => text_response(StatusCode::CONFLICT, err.to_string()),
+        false => text_response(StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")),
+    })
+}
+
+#[cfg(not(feature = "profiling"))]
+async fn native_profile(
+    _kind: &str,
+    _duration: Duration,
+    _frequency: i32,
+) -> Result<String, HttpResponse> {
+    Err(without_profiling())
+}
+
+#[cfg(feature = "profiling")]
+fn flamegraph(kind: &str, folded: &str) -> HttpResponse {
+    let count_name = match kind {
+        "requests" => "us",
+        "cpu" => "samples",
+        _ => "bytes",
+    };
+    let svg =
+        match profile::native::flamegraph(folded, &format!("jester {kind} profile"), count_name) {
+            Ok(svg) => svg,
+            Err(err) => return text_response(StatusCode::UNPROCESSABLE_ENTITY, err.to_string()),
+        };
+    let mut response = HttpResponse::new(full_body(svg));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("image/svg+xml"),
+    );
+    response
+}
+
+#[cfg(not(feature = "profiling"))]
+fn flamegraph(_kind: &str, _folded: &str) -> HttpResponse {
+    without_profiling()
+}
+
+fn without_profiling() -> HttpResponse {
+    text_response(
+        StatusCode::NOT_IMPLEMENTED,
+        "jester was built without the `profiling` feature",
+    )
+}
+
+/// Result of `POST /config/validate`.
+#[derive(Debug, Serialize)]
+struct Validation {
+    valid: bool,
+    /// Version of the running jester the config was checked against.
+    version: &'static str,
+    diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Debug, Serialize)]
+struct Diagnostic {
+    severity: Severity,
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Error,
+    Warning,
+}
+
+async fn validate(state: &AdminState, req: Request<Incoming>) -> HttpResponse {
+    let mut strict = false;
+    for pair in req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+    {
+        match pair.split_once('=').unwrap_or((pair, "")) {
+            ("strict", "" | "true" | "1") => strict = true,
+            ("strict", "false" | "0") => strict = false,
+            (key, value) => {
+                return text_response(
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "invalid parameter `{key}={value}`; expected strict=true or strict=false"
+                    ),
+                )
+            }
+        }
+    }
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let body = match read_body(req).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    json_response(&check_config(
+        state.control.registry(),
+        &content_type,
+        &body,
+        strict,
+    ))
+}
+
+#[derive(Debug, Serialize)]
+struct LogLevel {
+    directives: String,
+}
+
+async fn log_level(state: &AdminState, req: Request<Incoming>) -> HttpResponse {
+    let Some(log) = &state.log_control else {
+        return text_response(
+            StatusCode::NOT_IMPLEMENTED,
+            "runtime log control is not available in this process",
+        );
+    };
+    let directives = match *req.method() {
+        Method::GET => None,
+        Method::PUT => match read_body(req).await {
+            Ok(body) => Some(String::from_utf8_lossy(&body).trim().to_string()),
+            Err(response) => return response,
+        },
+        Method::DELETE => Some(log.initial.clone()),
+        _ => return text_response(StatusCode::METHOD_NOT_ALLOWED, "use GET, PUT, or DELETE"),
+    };
+    if let Some(directives) = directives {
+        if let Err(err) = log.control.set_directives(&directives) {
+            return text_response(StatusCode::BAD_REQUEST, format!("{err:#}"));
+        }
+        tracing::warn!(directives, "log filter changed through admin API");
+    }
+    json_response(&LogLevel {
+        directives: log.control.directives(),
+    })
+}
+
+async fn read_body(req: Request<Incoming>) -> Result<Bytes, HttpResponse> {
+    match Limited::new(req.into_body(), MAX_BODY_BYTES)
+        .collect()
+        .await
+    {
+        Ok(body) => Ok(body.to_bytes()),
+        Err(err) => Err(text_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("failed to read request body: {err}"),
+        )),
+    }
+}
+
+fn json_response(value: &impl Serialize) -> HttpResponse {
+    let mut response = HttpResponse::new(full_body(
+        serde_json::to_vec_pretty(value).expect("admin responses serialize"),
+    ));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+fn check_config(
+    registry: &FilterRegistry,
+    content_type: &str,
+    body: &[u8],
+    strict: bool,
+) -> Validation {
+    let built = candidate(content_type, body)
+        .and_then(|documents| parse_and_build(registry, &documents, strict));
+    let diagnostics = match built {
+        Ok(config) => config
+            .lint()
+            .into_iter()
+            .map(|message| Diagnostic {
+                severity: Severity::Warning,
+                message,
+            })
+            .collect(),
+        Err(err) => vec![Diagnostic {
+            severity: Severity::Error,
+            message: format!("{err:#}"),
+        }],
+    };
+    Validation {
+        valid: !diagnostics
+            .iter()
+            .any(|diagnostic| matches!(diagnostic.severity, Severity::Error)),
+        version: crate::version(),
+        diagnostics,
+    }
+}
+
+/// The documents of a candidate as `(name, TOML)`: the config, then the
+/// overlays to merge onto it.
+fn candidate(content_type: &str, body: &[u8]) -> Result<Vec<(String, String)>> {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    if media_type.eq_ignore_ascii_case("application/json") {
+        let mut json: serde_json::Value =
+            serde_json::from_slice(body).context("failed to parse config as JSON")?;
+        drop_nulls(&mut json);
+        let table: toml::Table =
+            serde_json::from_value(json).context("failed to parse config as JSON")?;
+        return Ok(vec![("candidate config".into(), toml::to_string(&table)?)]);
+    }
+    if media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return form_documents(content_type, body);
+    }
+    let text = std::str::from_utf8(body).context("config is not valid UTF-8")?;
+    Ok(vec![("candidate config".into(), text.to_string())])
+}
+
+/// Removes `null` members, which leave a setting unset; TOML has no null.
+fn drop_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(members) => {
+            members.retain(|_, member| !member.is_null());
+            members.values_mut().for_each(drop_nulls);
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(drop_nulls),
+        _ => {}
+    }
+}
+
+/// The `config` part and the `overlay` parts, in order, of a
+/// `multipart/form-data` body, named by their file names.
+fn form_documents(content_type: &str, body: &[u8]) -> Result<Vec<(String, String)>> {
+    let find = |haystack: &[u8], needle: &[u8]| {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+    };
+    let boundary = content_type
+        .split(';')
+        .skip(1)
+        .find_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            key.trim()
+                .eq_ignore_ascii_case("boundary")
+                .then(|| value.trim().trim_matches('"'))
+        })
+        .context("multipart body without a boundary")?;
+    // Every delimiter but the first follows a line break; add one before it.
+    let delimiter = format!("\r\n--{boundary}");
+    let body = [b"\r\n".as_slice(), body].concat();
+    let mut rest =
+        &body[find(&body, delimiter.as_bytes()).context("multipart body has no parts")?..];
+    let mut config = None;
+    let mut overlays = Vec::new();
+    loop {
+        rest = &rest[delimiter.len()..];
+        if rest.starts_with(b"--") {
+            break;
+        }
+        let end = find(rest, delimiter.as_bytes()).context("unterminated multipart body")?;
+        let part = &rest[..end];
+        rest = &rest[end..];
+        // The end of the delimiter line, the headers, and a blank line.
+        let headers_start = find(part, b"\r\n").context("malformed multipart part")? + 2;
+        let headers_end = find(part, b"\r\n\r\n")
+            .context("malformed multipart part")?
+            .max(headers_start);
+        let headers = String::from_utf8_lossy(&part[headers_start..headers_end]);
+        let disposition = headers
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim()
+                    .eq_ignore_ascii_case("content-disposition")
+                    .then_some(value)
+            })
+            .context("multipart part without Content-Disposition")?;
+        let param = |key: &str| {
+            disposition.split(';').skip(1).find_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                (name.trim() == key).then(|| value.trim().trim_matches('"').to_string())
+            })
+        };
+        let text = std::str::from_utf8(&part[headers_end + 4..])
+            .context("config is not valid UTF-8")?
+            .to_string();
+        match param("name").as_deref() {
+            Some("config") if config.is_none() => {
+                config = Some((param("filename").unwrap_or("candidate config".into()), text))
+            }
+            Some("config") => bail!("multipart body has more than one `config` part"),
+            Some("overlay") => {
+                let name =
+                    param("filename").unwrap_or_else(|| format!("overlay {}", overlays.len() + 1));
+                overlays.push((name, text));
+            }
+            name => bail!("unexpected multipart part {name:?}; expected `config` and `overlay`"),
+        }
+    }
+    let mut documents = vec![config.context("multipart body has no `config` part")?];
+    documents.extend(overlays);
+    Ok(documents)
+}
+
+/// Loads the candidate as `jester run` would, with `${VAR}` placeholders
+/// expanded from this process's environment and the overlays merged on, then
+/// runs the same build a real start would, minus binding, so unknown filters,
+/// bad filter config, and unreadable certificates all surface.
+fn parse_and_build(
+    registry: &FilterRegistry,
+    documents: &[(String, String)],
+    strict: bool,
+) -> Result<Config> {
+    let mut sources = documents
+        .iter()
+        .map(|(name, text)| ConfigSource { name, text });
+    let base = sources.next().context("no config to validate")?;
+    let (config, _) = config::load_checked(&base, &sources.collect::<Vec<_>>(), strict)?;
+    Proxy::builder()
+        .registry(registry.clone())
+        .config(config.clone())
+        .build()?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_parse_and_validation_errors() {
+        let result = check_config(&FilterRegistry::default(), "", b"listeners = 3", false);
+        assert!(!result.valid);
+        assert!(result.diagnostics[0].message.contains("TOML"));
+
+        let config = br#"
+            [admin]
+            listen = "0.0.0.0:9900"
+
+            [[routes]]
+            name = "app"
+            matchers = { hosts = ["example.com"] }
+            upstream = { strategy = "single", target = "http://127.0.0.1:8080" }
+            filters = [{ type = "builtin", name = "no-such-filter" }]
+        "#;
+        let result = check_config(&FilterRegistry::default(), "", config, false);
+        assert!(!result.valid);
+        assert_eq!(result.diagnostics.len(), 1);
+        assert!(result.diagnostics[0].message.contains("listener"));
+    }
+
+    #[test]
+    fn loads_candidates_as_jester_run_does() {
+        let registry = FilterRegistry::default();
+        let required = br#"
+            [admin]
+            listen = "${JESTER_TEST_UNSET_ADMIN_LISTEN:?set the admin address}"
+        "#;
+        let result = check_config(&registry, "", required, false);
+        assert!(result.diagnostics[0]
+            .message
+            .contains("set the admin address"));
+
+        let typo = br#"
+            [[routes]]
+            name = "app"
+            matchers = { path_prefx = "/api" }
+            upstream = { strategy = "single", target = "http://127.0.0.1:8080" }
+        "#;
+        let lenient = check_config(&registry, "", typo, false);
+        assert!(!lenient.diagnostics[0].message.contains("path_prefx"));
+        let strict = check_config(&registry, "", typo, true);
+        assert!(
+            strict.diagnostics[0].message.contains("path_prefx"),
+            "{:?}",
+            strict.diagnostics
+        );
+    }
+
+    #[test]
+    fn splits_multipart_candidates_into_config_and_overlays() {
+        let body = b"preamble\r\n\
+            --xyz\r\n\
+            Content-Disposition: form-data; name=\"config\"; filename=\"base.toml\"\r\n\
+            Content-Type: application/toml\r\n\
+            \r\n\
+            [admin]\r\n\
+            --xyz\r\n\
+            Content-Disposition: form-data; name=\"overlay\"\r\n\
+            \r\n\
+            meta = {}\r\n\
+            --xyz--\r\n";
+        let documents = form_documents("multipart/form-data; boundary=xyz", body).unwrap();
+        assert_eq!(
+            documents,
+            [
+                ("base.toml".to_string(), "[admin]".to_string()),
+                ("overlay 1".to_string(), "meta = {}".to_string()),
+            ]
+        );
+
+        let overlay_only = b"--xyz\r\n\
+            Content-Disposition: form-data; name=\"overlay\"\r\n\
+            \r\n\
+            meta = {}\r\n\
+            --xyz--";
+        assert!(form_documents("multipart/form-data; boundary=\"xyz\"", overlay_only).is_err());
+    }
+}
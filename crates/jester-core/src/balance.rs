@@ -0,0 +1,748 @@
+//! Target selection for `round_robin`, `hash`, `srv`, and `consul` upstreams.
+//!
+//! Round robin is smooth weighted round robin: over any `total weight`
+//! requests each target gets its weight's share, interleaved rather than in
+//! bursts. Hashing is consistent hashing with bounded loads: targets own
+//! points on a ring in proportion to their weight, and a request's key goes
+//! to the next target clockwise whose in-flight count is below
+//! [`LOAD_FACTOR`] times its weighted share. Adding or removing a target only
+//! moves the keys near its points, and a hot key spills over to the following
+//! targets instead of overloading one.
+//!
+//! `srv` upstreams round robin over targets looked up in DNS: the records of
+//! the lowest priority present, weighted by their SRV weights, are swapped in
+//! after each successful lookup. `consul` upstreams do the same with the
+//! passing instances of a Consul service, watched with blocking queries so
+//! changes apply as soon as Consul reports them.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use http::{header, header::HeaderName, Request, Uri};
+
+use crate::{
+    config::{parse_resolver, UpstreamStrategy, UpstreamTarget, MAX_TARGET_WEIGHT},
+    consul::{Catalog, ServiceInstance},
+    context::ClientIp,
+    dns::{self, SrvRecord},
+    stats::{target_key, RuntimeStats},
+};
+
+/// Ring points per unit of weight; enough to spread keys evenly over a few
+/// targets.
+const POINTS_PER_WEIGHT: u32 = 160;
+/// A target takes no more than this multiple of its share of the in-flight
+/// load.
+const LOAD_FACTOR: f64 = 1.25;
+/// Shortest time between two queries of a `consul` upstream.
+const CONSUL_MIN_QUERY_INTERVAL: Duration = Duration::from_secs(1);
+/// Wait after a failed `consul` query before asking again.
+const CONSUL_ERROR_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Picks the target of each request to a balanced upstream.
+pub(crate) enum Balancer {
+    RoundRobin(RoundRobin),
+    Hash(HashRing),
+    Srv(Arc<SrvTargets>),
+    Consul(Arc<ConsulTargets>),
+}
+
+impl Balancer {
+    /// `None` for strategies with a single target. `srv` lookups and `consul`
+    /// watches start in the background and stop when the balancer is dropped.
+    pub(crate) fn new(strategy: &UpstreamStrategy) -> Result<Option<Self>> {
+        Ok(match strategy {
+            UpstreamStrategy::RoundRobin { targets } => {
+                Some(Self::RoundRobin(RoundRobin::new(targets)?))
+            }
+            UpstreamStrategy::Hash { targets, key } => {
+                Some(Self::Hash(HashRing::new(targets, key)?))
+            }
+            UpstreamStrategy::Srv {
+                name,
+                scheme,
+                resolvers,
+                refresh_secs,
+            } => {
+                let srv = Arc::new(SrvTargets::new(name, scheme, resolvers, *refresh_secs)?);
+                if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                    runtime.spawn(refresh_loop(Arc::downgrade(&srv)));
+                } else {
+                    tracing::warn!(
+                        name,
+                        "srv upstream built outside a tokio runtime; targets will not load"
+                    );
+                }
+                Some(Self::Srv(srv))
+            }
+            UpstreamStrategy::Consul { .. } => {
+                let consul = Arc::new(ConsulTargets::new(strategy)?);
+                if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                    runtime.spawn(watch_loop(Arc::downgrade(&consul)));
+                } else {
+                    tracing::warn!(
+                        service = consul.service,
+                        "consul upstream built outside a tokio runtime; targets will not load"
+                    );
+                }
+                Some(Self::Consul(consul))
+            }
+            UpstreamStrategy::Single { .. } | UpstreamStrategy::LeastLatency { .. } => None,
+        })
+    }
+
+    /// `None` while an `srv` or `consul` upstream has no targets.
+    pub(crate) fn pick<B>(&self, req: &Request<B>, stats: &RuntimeStats) -> Option<Uri> {
+        match self {
+            Balancer::RoundRobin(round_robin) => Some(round_robin.pick().clone()),
+            Balancer::Hash(ring) => Some(ring.pick(req, stats).clone()),
+            Balancer::Srv(srv) => srv.pick(),
+            Balancer::Consul(consul) => pick_current(&consul.current),
+        }
+    }
+}
+
+struct Target {
+    uri: Uri,
+    /// Key of the target in [`RuntimeStats`], which tracks its in-flight requests.
+    stats_key: String,
+    weight: u32,
+}
+
+fn targets(targets: &[UpstreamTarget]) -> Result<Vec<Target>> {
+    if targets.is_empty() {
+        bail!("balanced upstreams need at least one target");
+    }
+    targets
+        .iter()
+        .map(|target| {
+            let uri = crate::client::parse_target(&target.url)?;
+            if !(1..=MAX_TARGET_WEIGHT).contains(&target.weight) {
+                bail!(
+                    "weight of upstream target `{}` must be between 1 and {MAX_TARGET_WEIGHT}",
+                    target.url
+                );
+            }
+            Ok(Target {
+                stats_key: target_key(&uri),
+                uri,
+                weight: target.weight,
+            })
+        })
+        .collect()
+}
+
+pub(crate) struct RoundRobin {
+    targets: Vec<Target>,
+    total_weight: i64,
+    /// Each target's running score; the highest is picked and pays the total.
+    current: Mutex<Vec<i64>>,
+}
+
+impl RoundRobin {
+    fn new(upstream_targets: &[UpstreamTarget]) -> Result<Self> {
+        Ok(Self::from_targets(targets(upstream_targets)?))
+    }
+
+    fn from_targets(targets: Vec<Target>) -> Self {
+        Self {
+            total_weight: targets.iter().map(|target| i64::from(target.weight)).sum(),
+            current: Mutex::new(vec![0; targets.len()]),
+            targets,
+        }
+    }
+
+    fn pick(&self) -> &Uri {
+        let mut current = self
+            .current
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut best = 0;
+        for (index, target) in self.targets.iter().enumerate() {
+            current[index] += i64::from(target.weight);
+            if current[index] > current[best] {
+                best = index;
+            }
+        }
+        current[best] -= self.total_weight;
+        &self.targets[best].uri
+    }
+}
+
+/// Targets of an `srv` upstream, replaced after each successful lookup.
+pub(crate) struct SrvTargets {
+    name: String,
+    scheme: String,
+    resolvers: Vec<SocketAddr>,
+    refresh: Duration,
+    current: RwLock<Option<Arc<RoundRobin>>>,
+}
+
+impl SrvTargets {
+    pub(crate) fn new(
+        name: &str,
+        scheme: &str,
+        resolvers: &[String],
+        refresh_secs: u64,
+    ) -> Result<Self> {
+        dns::encode_name(&mut Vec::new(), name)
+            .with_context(|| format!("invalid srv name `{name}`"))?;
+        if !matches!(scheme, "http" | "https") {
+            bail!("srv scheme must be `http` or `https`, got `{scheme}`");
+        }
+        if refresh_secs == 0 {
+            bail!("srv `refresh_secs` must be at least 1");
+        }
+        let resolvers = match resolvers.is_empty() {
+            true => dns::system_resolvers()?,
+            false => resolvers
+                .iter()
+                .map(|resolver| {
+                    parse_resolver(resolver)
+                        .with_context(|| format!("invalid srv resolver `{resolver}`"))
+                })
+                .collect::<Result<_>>()?,
+        };
+        Ok(Self {
+            name: name.to_string(),
+            scheme: scheme.to_string(),
+            resolvers,
+            refresh: Duration::from_secs(refresh_secs),
+            current: RwLock::default(),
+        })
+    }
+
+    fn pick(&self) -> Option<Uri> {
+        pick_current(&self.current)
+    }
+
+    /// Asks each resolver in turn and swaps in the answer of the first that
+    /// has one; on error the previous targets stay.
+    async fn refresh(&self) -> Result<()> {
+        let mut errors = Vec::new();
+        for resolver in &self.resolvers {
+            match dns::query_srv(*resolver, &self.name).await {
+                Ok(records) if !records.is_empty() => return self.replace(records),
+                Ok(_) => errors.push(format!("{resolver}: no SRV records")),
+                Err(err) => errors.push(format!("{resolver}: {err:#}")),
+            }
+        }
+        Err(anyhow!(errors.join("; ")))
+    }
+
+    fn replace(&self, records: Vec<SrvRecord>) -> Result<()> {
+        let targets = srv_targets(&self.scheme, records)?;
+        metrics::gauge!("jester_srv_targets", "name" => self.name.clone())
+            .set(targets.len() as f64);
+        if let Some(next) = swap_targets(&self.current, targets) {
+            tracing::info!(name = self.name, targets = ?next, "srv targets changed");
+        }
+        Ok(())
+    }
+}
+
+fn pick_current(current: &RwLock<Option<Arc<RoundRobin>>>) -> Option<Uri> {
+    let current = current
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()?;
+    Some(current.pick().clone())
+}
+
+/// Installs `targets` (none when empty), returning them as `(key, weight)`
+/// pairs when they differ from the previous ones.
+fn swap_targets(
+    current: &RwLock<Option<Arc<RoundRobin>>>,
+    targets: Vec<Target>,
+) -> Option<Vec<(String, u32)>> {
+    let mut current = current
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let summary = |targets: &[Target]| {
+        targets
+            .iter()
+            .map(|target| (target.stats_key.clone(), target.weight))
+            .collect::<Vec<_>>()
+    };
+    let previous = current
+        .as_ref()
+        .map(|round_robin| summary(&round_robin.targets));
+    let next = summary(&targets);
+    *current = (!targets.is_empty()).then(|| Arc::new(RoundRobin::from_targets(targets)));
+    (previous.as_ref() != Some(&next)).then_some(next)
+}
+
+/// The records of the lowest priority as round robin targets, in a stable
+/// order. A weight of `0` still gets an occasional request (RFC 2782).
+fn srv_targets(scheme: &str, mut records: Vec<SrvRecord>) -> Result<Vec<Target>> {
+    let priority = records
+        .iter()
+        .map(|record| record.priority)
+        .min()
+        .context("no SRV records")?;
+    records.retain(|record| record.priority == priority);
+    records.sort_by(|a, b| (&a.target, a.port).cmp(&(&b.target, b.port)));
+    records
+        .into_iter()
+        .map(|record| {
+            let uri = crate::client::parse_target(&format!(
+                "{scheme}://{}:{}",
+                record.target, record.port
+            ))?;
+            Ok(Target {
+                stats_key: target_key(&uri),
+                uri,
+                weight: u32::from(record.weight.max(1)),
+            })
+        })
+        .collect()
+}
+
+/// Looks up `srv` until the balancer that owns it is dropped (e.g. replaced
+/// by a config reload).
+async fn refresh_loop(srv: Weak<SrvTargets>) {
+    loop {
+        let Some(srv) = srv.upgrade() else {
+            return;
+        };
+        let outcome = match srv.refresh().await {
+            Ok(()) => "success",
+            Err(err) => {
+                tracing::warn!(name = srv.name, error = %err, "srv lookup failed; keeping previous targets");
+                "error"
+            }
+        };
+        metrics::counter!("jester_srv_refreshes_total", "name" => srv.name.clone(), "outcome" => outcome)
+            .increment(1);
+        let refresh = srv.refresh;
+        drop(srv);
+        tokio::time::sleep(refresh).await;
+    }
+}
+
+/// Targets of a `consul` upstream, replaced whenever Consul reports a change
+/// to the service's passing instances.
+pub(crate) struct ConsulTargets {
+    service: String,
+    address: String,
+    scheme: String,
+    tag: Option<String>,
+    datacenter: Option<String>,
+    token: Option<String>,
+    wait: Duration,
+    current: RwLock<Option<Arc<RoundRobin>>>,
+}
+
+impl ConsulTargets {
+    pub(crate) fn new(strategy: &UpstreamStrategy) -> Result<Self> {
+        let UpstreamStrategy::Consul {
+            service,
+            address,
+            scheme,
+            tag,
+            datacenter,
+            token,
+            wait_secs,
+        } = strategy
+        else {
+            bail!("not a consul upstream");
+        };
+        if service.is_empty() {
+            bail!("consul `service` must not be empty");
+        }
+        let agent = address
+            .parse::<Uri>()
+            .with_context(|| format!("invalid consul address `{address}`"))?;
+        if !matches!(agent.scheme_str(), Some("http" | "https")) || agent.host().is_none() {
+            bail!("consul address `{address}` must be an http:// or https:// URL");
+        }
+        if !matches!(scheme.as_str(), "http" | "https") {
+            bail!("consul scheme must be `http` or `https`, got `{scheme}`");
+        }
+        if !(1..=600).contains(wait_secs) {
+            bail!("consul `wait_secs` must be between 1 and 600");
+        }
+        Ok(Self {
+            service: service.clone(),
+            address: address.clone(),
+            scheme: scheme.clone(),
+            tag: tag.clone(),
+            datacenter: datacenter.clone(),
+            token: token.clone(),
+            wait: Duration::from_secs(*wait_secs),
+            current: RwLock::default(),
+        })
+    }
+
+    fn replace(&self, instances: Vec<ServiceInstance>) -> Result<()> {
+        let targets = consul_targets(&self.scheme, instances)?;
+        metrics::gauge!("jester_consul_targets", "service" => self.service.clone())
+            .set(targets.len() as f64);
+        if targets.is_empty() {
+            tracing::warn!(
+                service = self.service,
+                "consul reports no passing instances"
+            );
+        }
+        if let Some(next) = swap_targets(&self.current, targets) {
+            tracing::info!(service = self.service, targets = ?next, "consul targets changed");
+        }
+        Ok(())
+    }
+}
+
+/// Instances as round robin targets, in a stable order.
+fn consul_targets(scheme: &str, mut instances: Vec<ServiceInstance>) -> Result<Vec<Target>> {
+    instances.sort_by(|a, b| (&a.address, a.port).cmp(&(&b.address, b.port)));
+    instances
+        .into_iter()
+        .map(|instance| {
+            let host = match instance.address.contains(':') {
+                true => format!("[{}]", instance.address),
+                false => instance.address,
+            };
+            let uri = crate::client::parse_target(&format!("{scheme}://{host}:{}", instance.port))?;
+            Ok(Target {
+                stats_key: target_key(&uri),
+                uri,
+                weight: instance.weight,
+            })
+        })
+        .collect()
+}
+
+/// Watches the service until the balancer that owns `consul` is dropped. The
+/// balancer is only held between queries, so a reload frees it even while a
+/// blocking query is outstanding.
+async fn watch_loop(consul: Weak<ConsulTargets>) {
+    let catalog = {
+        let Some(consul) = consul.upgrade() else {
+            return;
+        };
+        match Catalog::new(&consul.address, consul.token.as_deref()) {
+            Ok(catalog) => catalog,
+            Err(err) => {
+                tracing::error!(service = consul.service, error = %format!("{err:#}"), "consul upstream disabled");
+                return;
+            }
+        }
+    };
+    let mut index = 0;
+    loop {
+        let Some((service, tag, datacenter, wait)) = consul.upgrade().map(|consul| {
+            (
+                consul.service.clone(),
+                consul.tag.clone(),
+                consul.datacenter.clone(),
+                consul.wait,
+            )
+        }) else {
+            return;
+        };
+        let started = tokio::time::Instant::now();
+        let result = catalog
+            .healthy(&service, tag.as_deref(), datacenter.as_deref(), index, wait)
+            .await;
+        let Some(consul) = consul.upgrade() else {
+            return;
+        };
+        let outcome = match result.and_then(|(next, instances)| {
+            consul.replace(instances)?;
+            Ok(next)
+        }) {
+            Ok(next) => {
+                index = next;
+                "success"
+            }
+            Err(err) => {
+                tracing::warn!(service, error = %format!("{err:#}"), "consul query failed; keeping previous targets");
+                index = 0;
+                "error"
+            }
+        };
+        metrics::counter!("jester_consul_queries_total", "service" => service, "outcome" => outcome)
+            .increment(1);
+        drop(consul);
+        // Blocking queries can return early without a change; never ask more
+        // than once per interval, and back off after errors.
+        let pause = match outcome {
+            "success" => CONSUL_MIN_QUERY_INTERVAL,
+            _ => CONSUL_ERROR_BACKOFF,
+        };
+        tokio::time::sleep_until(started + pause).await;
+    }
+}
+
+/// What a request is hashed by: `header:<name>`, `cookie:<name>`, or
+/// `client_ip`. Requests without the header or cookie hash by client address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HashKey {
+    Header(HeaderName),
+    Cookie(String),
+    ClientIp,
+}
+
+impl FromStr for HashKey {
+    type Err = anyhow::Error;
+
+    fn from_str(key: &str) -> Result<Self> {
+        if key == "client_ip" {
+            return Ok(Self::ClientIp);
+        }
+        match key.split_once(':') {
+            Some(("header", name)) => HeaderName::from_str(name)
+                .map(Self::Header)
+                .with_context(|| format!("invalid header name in hash key `{key}`")),
+            Some(("cookie", name)) if !name.is_empty() && !name.contains([';', '=', ' ']) => {
+                Ok(Self::Cookie(name.to_string()))
+            }
+            _ => bail!(
+                "hash key must be `header:<name>`, `cookie:<name>`, or `client_ip`, got `{key}`"
+            ),
+        }
+    }
+}
+
+impl HashKey {
+    fn value<'a, B>(&self, req: &'a Request<B>) -> Option<&'a [u8]> {
+        match self {
+            Self::Header(name) => req.headers().get(name).map(|value| value.as_bytes()),
+            Self::Cookie(name) => req
+                .headers()
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .find_map(|pair| {
+                    let (cookie, value) = pair.trim().split_once('=')?;
+                    (cookie == name).then_some(value.as_bytes())
+                }),
+            Self::ClientIp => None,
+        }
+    }
+}
+
+pub(crate) struct HashRing {
+    key: HashKey,
+    targets: Vec<Target>,
+    total_weight: u32,
+    /// `(point, target index)`, sorted by point.
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    fn new(upstream_targets: &[UpstreamTarget], key: &str) -> Result<Self> {
+        let targets = targets(upstream_targets)?;
+        let mut points: Vec<(u64, usize)> = targets
+            .iter()
+            .enumerate()
+            .flat_map(|(index, target)| {
+                (0..POINTS_PER_WEIGHT * target.weight).map(move |point| {
+                    let point = hash(&[target.stats_key.as_bytes(), &point.to_le_bytes()]);
+                    (point, index)
+                })
+            })
+            .collect();
+        points.sort_unstable();
+        Ok(Self {
+            key: key.parse()?,
+            total_weight: targets.iter().map(|target| target.weight).sum(),
+            targets,
+            points,
+        })
+    }
+
+    /// The target for `req`, skipping targets at their load bound.
+    fn pick<B>(&self, req: &Request<B>, stats: &RuntimeStats) -> &Uri {
+        let point = match self.key.value(req) {
+            Some(value) => hash(&[value]),
+            None => match ClientIp::of(req).map(|client| client.to_canonical()) {
+                Some(IpAddr::V4(ip)) => hash(&[&ip.octets()]),
+                Some(IpAddr::V6(ip)) => hash(&[&ip.octets()]),
+                None => hash(&[]),
+            },
+        };
+        let loads: Vec<usize> = self
+            .targets
+            .iter()
+            .map(|target| stats.target_inflight(&target.stats_key))
+            .collect();
+        &self.targets[self.walk(point, &loads)].uri
+    }
+
+    /// Index of the first target clockwise from `point` with room under its
+    /// bound; one always exists because not every target can be above its
+    /// share.
+    fn walk(&self, point: u64, loads: &[usize]) -> usize {
+        let total: usize = loads.iter().sum();
+        let share = LOAD_FACTOR * (total + 1) as f64 / f64::from(self.total_weight);
+        let start = self.points.partition_point(|&(p, _)| p < point);
+        let clockwise = self.points[start..].iter().chain(&self.points[..start]);
+        let mut fallback = None;
+        for &(_, index) in clockwise {
+            let bound = (share * f64::from(self.targets[index].weight)).ceil() as usize;
+            if loads[index] < bound {
+                return index;
+            }
+            fallback.get_or_insert(index);
+        }
+        fallback.unwrap_or_default()
+    }
+}
+
+/// FNV-1a over `parts`, finished with a 64-bit mix so neighbouring inputs
+/// land far apart on the ring. Unlike `DefaultHasher` the result is fixed
+/// across Rust releases and platforms, so every instance sends a key to the
+/// same target.
+fn hash(parts: &[&[u8]]) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    let mut hash = parts
+        .iter()
+        .flat_map(|part| part.iter())
+        .fold(OFFSET, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+        });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(targets: &[&str]) -> HashRing {
+        let targets: Vec<UpstreamTarget> = targets.iter().map(|&t| t.into()).collect();
+        HashRing::new(&targets, "header:x-user").unwrap()
+    }
+
+    fn owners(ring: &HashRing, loads: &[usize]) -> Vec<String> {
+        (0..1000u32)
+            .map(|user| {
+                ring.targets[ring.walk(hash(&[&user.to_le_bytes()]), loads)]
+                    .stats_key
+                    .clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn adding_a_target_only_moves_keys_to_it() {
+        let before = owners(&ring(&["http://a", "http://b", "http://c"]), &[0; 3]);
+        let after = owners(
+            &ring(&["http://a", "http://b", "http://c", "http://d"]),
+            &[0; 4],
+        );
+        let moved: Vec<_> = before.iter().zip(&after).filter(|(b, a)| b != a).collect();
+        assert!(moved.iter().all(|(_, to)| to.as_str() == "http://d"));
+        assert!((150..350).contains(&moved.len()), "{} moved", moved.len());
+    }
+
+    #[test]
+    fn ring_hashes_are_pinned() {
+        // Instances built with different toolchains must agree on owners.
+        assert_eq!(hash(&[]), 0xefd0_1f60_ba99_2926);
+        assert_eq!(hash(&[b"alice"]), 0x3507_d047_a67c_08f4);
+        assert_eq!(hash(&[b"ali", b"ce"]), 0x3507_d047_a67c_08f4);
+    }
+
+    #[test]
+    fn busy_targets_pass_keys_on() {
+        let ring = ring(&["http://a", "http://b"]);
+        let owners = owners(&ring, &[10, 0]);
+        assert!(owners.iter().all(|owner| owner == "http://b"));
+    }
+
+    #[test]
+    fn weights_scale_each_targets_share() {
+        let targets = [
+            UpstreamTarget::weighted("http://big", 3),
+            UpstreamTarget::from("http://small"),
+        ];
+        let round_robin = RoundRobin::new(&targets).unwrap();
+        let picks: Vec<String> = (0..8)
+            .map(|_| round_robin.pick().authority().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            picks,
+            ["big", "big", "small", "big", "big", "big", "small", "big"]
+        );
+
+        let ring = HashRing::new(&targets, "client_ip").unwrap();
+        let big = owners(&ring, &[0, 0])
+            .iter()
+            .filter(|owner| *owner == "http://big")
+            .count();
+        assert!(
+            (650..850).contains(&big),
+            "{big} of 1000 keys on the big target"
+        );
+        // Three times the weight means three times the in-flight bound: the
+        // small target is full at 3 while the big one still has room at 5.
+        assert_eq!(ring.targets[ring.walk(0, &[5, 3])].stats_key, "http://big");
+    }
+
+    #[test]
+    fn keys_come_from_headers_cookies_or_the_client() {
+        let req = Request::get("/")
+            .header("x-user", "alice")
+            .header(header::COOKIE, "theme=dark; session=s1")
+            .body(())
+            .unwrap();
+        let key = |key: &str| key.parse::<HashKey>().unwrap();
+        assert_eq!(key("header:x-user").value(&req), Some(b"alice".as_slice()));
+        assert_eq!(key("cookie:session").value(&req), Some(b"s1".as_slice()));
+        assert_eq!(key("cookie:missing").value(&req), None);
+        assert_eq!(key("client_ip"), HashKey::ClientIp);
+        assert!("path".parse::<HashKey>().is_err());
+        assert!("cookie:".parse::<HashKey>().is_err());
+    }
+
+    #[test]
+    fn srv_answers_replace_the_lowest_priority_targets() {
+        let record = |priority, weight, port, target: &str| SrvRecord {
+            priority,
+            weight,
+            port,
+            target: target.into(),
+        };
+        let srv =
+            SrvTargets::new("_http._tcp.example.com", "http", &["127.0.0.1".into()], 30).unwrap();
+        assert_eq!(srv.pick(), None);
+
+        srv.replace(vec![
+            record(20, 1, 80, "backup.example.com"),
+            record(10, 3, 8080, "b.example.com"),
+            record(10, 0, 8080, "a.example.com"),
+        ])
+        .unwrap();
+        let picks: Vec<String> = (0..4)
+            .map(|_| srv.pick().unwrap().authority().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            picks,
+            [
+                "b.example.com:8080",
+                "a.example.com:8080",
+                "b.example.com:8080",
+                "b.example.com:8080"
+            ]
+        );
+
+        srv.replace(vec![record(20, 1, 80, "backup.example.com")])
+            .unwrap();
+        assert_eq!(srv.pick().unwrap(), "http://backup.example.com:80/");
+
+        assert!(SrvTargets::new("", "http", &["127.0.0.1".into()], 30).is_err());
+        assert!(SrvTargets::new("_x._tcp.example.com", "ftp", &["127.0.0.1".into()], 30).is_err());
+        assert!(SrvTargets::new("_x._tcp.example.com", "http", &["127.0.0.1".into()], 0).is_err());
+    }
+}
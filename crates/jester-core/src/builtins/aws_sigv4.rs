@@ -0,0 +1,556 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use http::{header, uri::PathAndQuery, HeaderValue, Request, StatusCode, Uri};
+use hyper::body::Body;
+use serde::Deserialize;
+use serde_json::Value;
+use tower::{layer::layer_fn, Service};
+
+use super::{query_policy::percent_decode, reloadable::Reloadable};
+use crate::{
+    acme::unix_now,
+    config::units,
+    plugin::{
+        full_body, text_response, DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService,
+        ResponseFuture,
+    },
+    proxy::upstream_host,
+    router::UpstreamEndpoint,
+    sigv4::{amz_timestamps, payload_hash, uri_encode, SigV4, UNSIGNED_PAYLOAD},
+};
+
+/// Signs requests for the upstream with AWS Signature Version 4, so clients
+/// without AWS credentials can reach S3, API Gateway, OpenSearch, and other
+/// AWS endpoints through the proxy.
+///
+/// Any `Authorization` the client sent is replaced. The signature covers the
+/// `Host` the upstream will see and every `x-amz-*` header; the path and
+/// query are re-encoded in canonical form on the way out. Bodies are buffered
+/// up to `max_body_bytes` (`413` beyond) to be hashed, unless
+/// `unsigned_payload` is set, which only S3 accepts. Credentials come from
+/// the `AWS_*` environment variables, a shared credentials file profile, or
+/// the config itself, and are read when the filter is built.
+///
+/// Config: `{ service = "s3", region = "eu-west-1", credentials = { source = "env" },
+/// unsigned_payload = false, max_body_bytes = 1048576 }`.
+pub struct AwsSigV4Filter;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AwsSigV4Config {
+    service: String,
+    region: String,
+    credentials: CredentialsConfig,
+    unsigned_payload: bool,
+    #[serde(deserialize_with = "units::bytes", alias = "max_body_size")]
+    max_body_bytes: usize,
+}
+
+impl Default for AwsSigV4Config {
+    fn default() -> Self {
+        Self {
+            service: String::new(),
+            region: String::new(),
+            credentials: CredentialsConfig::Env,
+            unsigned_payload: false,
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Where the access key comes from.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case", deny_unknown_fields)]
+enum CredentialsConfig {
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, and `AWS_SESSION_TOKEN`.
+    Env,
+    Static {
+        access_key_id: String,
+        secret_access_key: String,
+        #[serde(default)]
+        session_token: Option<String>,
+    },
+    /// A profile of the shared credentials file; defaults follow the AWS CLI.
+    Profile {
+        #[serde(default)]
+        profile: Option<String>,
+        #[serde(default)]
+        path: Option<String>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl CredentialsConfig {
+    /// The credentials to sign with; a profile's file is watched, so rotated
+    /// keys are picked up without a restart.
+    fn resolve(self) -> Result<Arc<Reloadable<Credentials>>> {
+        let credentials = match self {
+            CredentialsConfig::Env => {
+                let var = |name: &str| {
+                    std::env::var(name).with_context(|| format!("aws-sigv4: `{name}` is not set"))
+                };
+                Credentials {
+                    access_key_id: var("AWS_ACCESS_KEY_ID")?,
+                    secret_access_key: var("AWS_SECRET_ACCESS_KEY")?,
+                    session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+                }
+            }
+            CredentialsConfig::Static {
+                access_key_id,
+                secret_access_key,
+                session_token,
+            } => Credentials {
+                access_key_id,
+                secret_access_key,
+                session_token,
+            },
+            CredentialsConfig::Profile { profile, path } => {
+                let profile = profile
+                    .or_else(|| std::env::var("AWS_PROFILE").ok())
+                    .unwrap_or_else(|| "default".into());
+                let path = match path.or_else(|| std::env::var("AWS_SHARED_CREDENTIALS_FILE").ok())
+                {
+                    Some(path) => PathBuf::from(path),
+                    None => std::env::var_os("HOME")
+                        .map(|home| PathBuf::from(home).join(".aws").join("credentials"))
+                        .context("aws-sigv4: HOME is not set; give the credentials `path`")?,
+                };
+                let display = path.display().to_string();
+                return Reloadable::watch("aws-sigv4", &path, move |file| {
+                    let file = std::str::from_utf8(file).context("not UTF-8")?;
+                    profile_credentials(file, &profile)
+                        .with_context(|| format!("aws-sigv4: profile `{profile}` in {display}"))?
+                        .checked()
+                })
+                .with_context(|| {
+                    format!("aws-sigv4: cannot load credentials file {}", path.display())
+                });
+            }
+        };
+        Ok(Reloadable::fixed(credentials.checked()?))
+    }
+}
+
+impl Credentials {
+    fn checked(self) -> Result<Self> {
+        if self.access_key_id.is_empty() || self.secret_access_key.is_empty() {
+            bail!("aws-sigv4: access key ID and secret access key must not be empty");
+        }
+        Ok(self)
+    }
+}
+
+/// Reads a profile from an INI-style shared credentials file.
+fn profile_credentials(file: &str, profile: &str) -> Result<Credentials> {
+    let mut section = None;
+    let mut values = BTreeMap::new();
+    for line in file.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with(['#', ';']) {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = Some(name.trim());
+            continue;
+        }
+        if section != Some(profile) {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            values.insert(key.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+    let mut take = |key: &str| values.remove(key);
+    Ok(Credentials {
+        access_key_id: take("aws_access_key_id").context("no aws_access_key_id")?,
+        secret_access_key: take("aws_secret_access_key").context("no aws_secret_access_key")?,
+        session_token: take("aws_session_token"),
+    })
+}
+
+struct Signer {
+    service: String,
+    region: String,
+    credentials: Arc<Reloadable<Credentials>>,
+    unsigned_payload: bool,
+    max_body_bytes: usize,
+}
+
+impl Signer {
+    fn from_config(cfg: AwsSigV4Config) -> Result<Self> {
+        if cfg.service.trim().is_empty() || cfg.region.trim().is_empty() {
+            bail!("aws-sigv4 needs a `service` and a `region`");
+        }
+        Ok(Self {
+            service: cfg.service,
+            region: cfg.region,
+            credentials: cfg.credentials.resolve()?,
+            unsigned_payload: cfg.unsigned_payload,
+            max_body_bytes: cfg.max_body_bytes,
+        })
+    }
+
+    /// Puts `req` in canonical form and adds the signature for `host`.
+    fn sign<B>(&self, req: &mut Request<B>, host: &str, payload_hash: &str, now: u64) {
+        let credentials = self.credentials.get();
+        let path = canonical_path(req.uri().path());
+        let query = canonical_query(req.uri().query().unwrap_or_default());
+        if let Some(uri) = with_path_and_query(req.uri(), &path, &query) {
+            *req.uri_mut() = uri;
+        }
+        // S3 signs the path as sent; every other service encodes it again.
+        let signed_path = match self.service.as_str() {
+            "s3" => path,
+            _ => uri_encode(path.as_bytes(), true),
+        };
+
+        let (_, amz_date) = amz_timestamps(now);
+        let headers = req.headers_mut();
+        headers.remove(header::AUTHORIZATION);
+        headers.remove("x-amz-security-token");
+        let mut set = |name: &'static str, value: &str| {
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.insert(name, value);
+            }
+        };
+        set(header::HOST.as_str(), host);
+        set("x-amz-date", &amz_date);
+        set("x-amz-content-sha256", payload_hash);
+        if let Some(token) = &credentials.session_token {
+            set("x-amz-security-token", token);
+        }
+
+        let mut signed = BTreeMap::<String, String>::new();
+        for (name, value) in headers.iter() {
+            if name != header::HOST && !name.as_str().starts_with("x-amz-") {
+                continue;
+            }
+            let value = String::from_utf8_lossy(value.as_bytes());
+            signed
+                .entry(name.as_str().to_string())
+                .and_modify(|joined| {
+                    joined.push(',');
+                    joined.push_str(value.trim());
+                })
+                .or_insert_with(|| value.trim().to_string());
+        }
+        let signed = signed
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+        let authorization = SigV4 {
+            access_key_id: &credentials.access_key_id,
+            secret_access_key: &credentials.secret_access_key,
+            region: &self.region,
+            service: &self.service,
+        }
+        .authorization(
+            req.method().as_str(),
+            &signed_path,
+            &query,
+            &signed,
+            payload_hash,
+        );
+        if let Ok(value) = HeaderValue::from_str(&authorization) {
+            req.headers_mut().insert(header::AUTHORIZATION, value);
+        }
+    }
+}
+
+/// Each segment of `path` percent-decoded and encoded again with only the
+/// unreserved characters left as they are.
+fn canonical_path(path: &str) -> String {
+    let path = path
+        .split('/')
+        .map(|segment| uri_encode(percent_decode(segment, false).as_bytes(), false))
+        .collect::<Vec<_>>()
+        .join("/");
+    match path.is_empty() {
+        true => "/".into(),
+        false => path,
+    }
+}
+
+/// Parameters encoded like [`canonical_path`] segments and sorted; a
+/// parameter without `=` gets an empty value.
+fn canonical_query(query: &str) -> String {
+    let encode = |component: &str| uri_encode(percent_decode(component, false).as_bytes(), false);
+    let mut params = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            (encode(name), encode(value))
+        })
+        .collect::<Vec<_>>();
+    params.sort();
+    params
+        .iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn with_path_and_query(uri: &Uri, path: &str, query: &str) -> Option<Uri> {
+    let path_and_query = match query {
+        "" => PathAndQuery::try_from(path),
+        query => PathAndQuery::try_from(format!("{path}?{query}")),
+    }
+    .ok()?;
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query);
+    Uri::from_parts(parts).ok()
+}
+
+#[derive(Clone)]
+struct AwsSigV4Service {
+    inner: JesterService,
+    signer: Arc<Signer>,
+}
+
+impl Service<HttpRequest> for AwsSigV4Service {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: HttpRequest) -> Self::Future {
+        let Some(upstream) = req.extensions().get::<UpstreamEndpoint>() else {
+            return Box::pin(async { Err(anyhow!("aws-sigv4 runs only in route filter chains")) });
+        };
+        let host = upstream_host(&req, upstream).unwrap_or_default();
+        let signer = self.signer.clone();
+        if req.body().is_end_stream() || signer.unsigned_payload {
+            let hash = match signer.unsigned_payload {
+                true => UNSIGNED_PAYLOAD.to_string(),
+                false => payload_hash(b""),
+            };
+            signer.sign(&mut req, &host, &hash, unix_now());
+            return self.inner.call(req);
+        }
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let body = match super::read_body(body, signer.max_body_bytes).await {
+                Ok(Some(body)) => body,
+                Ok(None) => {
+                    metrics::counter!("jester_aws_sigv4_rejected_total", "reason" => "too_large")
+                        .increment(1);
+                    return Ok(text_response(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "request body too large to sign",
+                    ));
+                }
+                Err(err) => return Err(anyhow!(err).context("failed to read request body")),
+            };
+            let mut req = Request::from_parts(parts, ());
+            signer.sign(&mut req, &host, &payload_hash(&body), unix_now());
+            let (parts, ()) = req.into_parts();
+            super::forward(inner, Request::from_parts(parts, full_body(body))).await
+        })
+    }
+}
+
+impl JesterPlugin for AwsSigV4Filter {
+    fn name(&self) -> &'static str {
+        "aws-sigv4"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: AwsSigV4Config = if cfg.is_null() {
+            AwsSigV4Config::default()
+        } else {
+            serde_json::from_value(cfg)?
+        };
+        let signer = Arc::new(Signer::from_config(cfg)?);
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            JesterService::new(AwsSigV4Service {
+                inner,
+                signer: signer.clone(),
+            })
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:req"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+    use crate::config::{HostHeader, UpstreamTls};
+
+    fn signer(service: &str, session_token: Option<&str>) -> Signer {
+        Signer {
+            service: service.into(),
+            region: "us-east-1".into(),
+            credentials: Reloadable::fixed(Credentials {
+                access_key_id: "AKIDEXAMPLE".into(),
+                secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into(),
+                session_token: session_token.map(str::to_string),
+            }),
+            unsigned_payload: false,
+            max_body_bytes: 16,
+        }
+    }
+
+    #[test]
+    fn paths_and_queries_are_canonicalized() {
+        assert_eq!(canonical_path(""), "/");
+        assert_eq!(canonical_path("/a b/%7Ec%2Fd/é"), "/a%20b/~c%2Fd/%C3%A9");
+        assert_eq!(
+            canonical_query("b=2&a=x%20y&&a=1&flag&c=+"),
+            "a=1&a=x%20y&b=2&c=%2B&flag="
+        );
+    }
+
+    #[test]
+    fn reads_profiles_from_the_shared_credentials_file() {
+        let file = "[default]\naws_access_key_id = AKIDDEFAULT\naws_secret_access_key = one\n\n\
+                    # staging\n[staging]\nAWS_ACCESS_KEY_ID=AKIDSTAGING\n\
+                    aws_secret_access_key=two\naws_session_token = token\n";
+        assert_eq!(
+            profile_credentials(file, "staging").unwrap(),
+            Credentials {
+                access_key_id: "AKIDSTAGING".into(),
+                secret_access_key: "two".into(),
+                session_token: Some("token".into()),
+            }
+        );
+        assert_eq!(
+            profile_credentials(file, "default").unwrap().session_token,
+            None
+        );
+        assert!(profile_credentials(file, "prod").is_err());
+    }
+
+    #[test]
+    fn signs_the_canonical_request() {
+        let mut req = Request::get("/bucket/a%20b?b=2&a=1")
+            .header(header::AUTHORIZATION, "Basic Zm9vOmJhcg==")
+            .header("x-amz-meta-owner", "jester")
+            .body(())
+            .unwrap();
+        let hash = payload_hash(b"");
+        signer("s3", Some("token")).sign(
+            &mut req,
+            "example.s3.amazonaws.com",
+            &hash,
+            1_440_938_160,
+        );
+
+        assert_eq!(req.uri(), "/bucket/a%20b?a=1&b=2");
+        let headers = req.headers();
+        assert_eq!(headers["host"], "example.s3.amazonaws.com");
+        assert_eq!(headers["x-amz-date"], "20150830T123600Z");
+        assert_eq!(headers["x-amz-content-sha256"], hash.as_str());
+        assert_eq!(headers["x-amz-security-token"], "token");
+        let expected = SigV4 {
+            access_key_id: "AKIDEXAMPLE",
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            region: "us-east-1",
+            service: "s3",
+        }
+        .authorization(
+            "GET",
+            "/bucket/a%20b",
+            "a=1&b=2",
+            &[
+                ("host", "example.s3.amazonaws.com"),
+                ("x-amz-content-sha256", &hash),
+                ("x-amz-date", "20150830T123600Z"),
+                ("x-amz-meta-owner", "jester"),
+                ("x-amz-security-token", "token"),
+            ],
+            &hash,
+        );
+        assert_eq!(headers[header::AUTHORIZATION], expected.as_str());
+
+        // Other services sign the path encoded twice.
+        let mut req = Request::get("/a%20b").body(()).unwrap();
+        signer("es", None).sign(&mut req, "search.example.com", &hash, 1_440_938_160);
+        assert!(req.headers().get("x-amz-security-token").is_none());
+        let expected = SigV4 {
+            access_key_id: "AKIDEXAMPLE",
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            region: "us-east-1",
+            service: "es",
+        }
+        .authorization(
+            "GET",
+            "/a%2520b",
+            "",
+            &[
+                ("host", "search.example.com"),
+                ("x-amz-content-sha256", &hash),
+                ("x-amz-date", "20150830T123600Z"),
+            ],
+            &hash,
+        );
+        assert_eq!(req.headers()[header::AUTHORIZATION], expected.as_str());
+    }
+
+    #[tokio::test]
+    async fn hashes_buffered_bodies_and_rejects_large_ones() {
+        let inner = JesterService::new(service_fn(|req: HttpRequest| async move {
+            let hash = req.headers()["x-amz-content-sha256"].clone();
+            let body = req.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(hash, payload_hash(&body).as_str());
+            Ok::<_, anyhow::Error>(text_response(StatusCode::OK, body))
+        }));
+        let service = AwsSigV4Service {
+            inner,
+            signer: Arc::new(signer("s3", None)),
+        };
+        let request = |body: &'static str| {
+            let mut req = Request::put("/bucket/key").body(full_body(body)).unwrap();
+            req.extensions_mut().insert(UpstreamEndpoint {
+                uri: "https://example.s3.amazonaws.com".parse().unwrap(),
+                keep_alive: true,
+                host_header: HostHeader::Upstream,
+                tls: UpstreamTls::default(),
+                pool: Default::default(),
+                protocol: Default::default(),
+                proxy_protocol: None,
+            });
+            req
+        };
+
+        let response = service.clone().oneshot(request("hello")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "hello");
+
+        let response = service
+            .clone()
+            .oneshot(request("more than sixteen bytes"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let unrouted = Request::get("/").body(full_body("")).unwrap();
+        assert!(service.oneshot(unrouted).await.is_err());
+    }
+}
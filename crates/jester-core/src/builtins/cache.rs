@@ -0,0 +1,504 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use http::{header, HeaderMap, HeaderValue, Request};
+use serde::Deserialize;
+use serde_json::Value;
+use tower::{layer::layer_fn, Service, ServiceExt};
+
+use super::coalesce::{SharedResponse, Sharing};
+use crate::{
+    config::units,
+    context::RequestContext,
+    plugin::{
+        full_body, DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService, ResponseFuture,
+    },
+};
+
+/// Serves repeated `GET` and `HEAD` requests from memory while their
+/// responses are fresh.
+///
+/// A response's lifetime is its `s-maxage` or `max-age` less its `Age`,
+/// falling back to `ttl_secs`; with the default of `0`, responses without one
+/// are not stored. Which requests and responses qualify follows the same
+/// shared-cache rules as `coalesce`. Hits carry an `Age` header. At most
+/// `max_entries` responses are kept; when full, expired entries are dropped
+/// first, then the least used.
+///
+/// With `refresh_ahead`, an entry hit at least `min_hits` times is fetched
+/// again in the background once less than `before_secs` of its lifetime
+/// remain, so popular paths keep being served from memory. At most
+/// `max_per_sec` background refreshes start per second on the route.
+///
+/// Config: `{ max_entries = 1024, max_bytes = 1048576, ttl_secs = 0,
+/// refresh_ahead = { before_secs = 10, min_hits = 5, max_per_sec = 1.0 } }`.
+pub struct CacheFilter;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CacheConfig {
+    max_entries: usize,
+    #[serde(deserialize_with = "units::bytes", alias = "max_size")]
+    max_bytes: u64,
+    #[serde(deserialize_with = "units::secs", alias = "ttl")]
+    ttl_secs: u64,
+    key_headers: Vec<String>,
+    refresh_ahead: Option<RefreshAheadConfig>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 1024,
+            max_bytes: 1024 * 1024,
+            ttl_secs: 0,
+            key_headers: vec![
+                "accept".into(),
+                "accept-encoding".into(),
+                "accept-language".into(),
+            ],
+            refresh_ahead: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RefreshAheadConfig {
+    #[serde(deserialize_with = "units::secs", alias = "before")]
+    before_secs: u64,
+    min_hits: u64,
+    max_per_sec: f64,
+}
+
+impl Default for RefreshAheadConfig {
+    fn default() -> Self {
+        Self {
+            before_secs: 10,
+            min_hits: 5,
+            max_per_sec: 1.0,
+        }
+    }
+}
+
+struct Entry {
+    response: Arc<SharedResponse>,
+    stored: Instant,
+    /// `Age` the response already had when it was stored.
+    initial_age: Duration,
+    expires: Instant,
+    /// Hits since the entry was stored.
+    hits: AtomicU64,
+    refreshing: AtomicBool,
+}
+
+impl Entry {
+    fn fresh(&self, now: Instant) -> bool {
+        now < self.expires
+    }
+
+    fn response(&self) -> HttpResponse {
+        let mut response = self.response.response();
+        let age = self.initial_age + self.stored.elapsed();
+        response
+            .headers_mut()
+            .insert(header::AGE, HeaderValue::from(age.as_secs()));
+        response
+    }
+}
+
+/// Starts at most `rate` background refreshes per second, allowing a burst of
+/// one second's worth.
+struct RefreshBudget {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RefreshBudget {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            state: Mutex::new((rate.max(1.0), Instant::now())),
+        }
+    }
+
+    fn try_take(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens =
+            (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate.max(1.0));
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct RefreshAhead {
+    before: Duration,
+    min_hits: u64,
+    budget: RefreshBudget,
+}
+
+struct Cache {
+    sharing: Sharing,
+    max_entries: usize,
+    default_ttl: Duration,
+    refresh_ahead: Option<RefreshAhead>,
+    entries: Mutex<HashMap<Vec<u8>, Arc<Entry>>>,
+}
+
+impl Cache {
+    fn lookup(&self, key: &[u8]) -> Option<Arc<Entry>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        if !entry.fresh(Instant::now()) {
+            entries.remove(key);
+            return None;
+        }
+        entry.hits.fetch_add(1, Ordering::Relaxed);
+        Some(entry.clone())
+    }
+
+    /// Stores `response` under `key` if it has a lifetime.
+    fn store(&self, key: Vec<u8>, response: Arc<SharedResponse>) {
+        let (lifetime, initial_age) = freshness(&response.headers);
+        let Some(remaining) = lifetime
+            .or(Some(self.default_ttl))
+            .and_then(|lifetime| lifetime.checked_sub(initial_age))
+            .filter(|remaining| !remaining.is_zero())
+        else {
+            return;
+        };
+        let now = Instant::now();
+        let entry = Arc::new(Entry {
+            response,
+            stored: now,
+            initial_age,
+            expires: now + remaining,
+            hits: AtomicU64::new(0),
+            refreshing: AtomicBool::new(false),
+        });
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.fresh(now));
+            if entries.len() >= self.max_entries {
+                let least_used = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.hits.load(Ordering::Relaxed))
+                    .map(|(key, _)| key.clone());
+                if let Some(least_used) = least_used {
+                    entries.remove(&least_used);
+                }
+            }
+        }
+        entries.insert(key, entry);
+    }
+
+    /// Whether `entry` is popular and close enough to expiry to fetch again;
+    /// claims the refresh if so.
+    fn claim_refresh(&self, entry: &Entry) -> bool {
+        let Some(refresh) = &self.refresh_ahead else {
+            return false;
+        };
+        let remaining = entry.expires.saturating_duration_since(Instant::now());
+        if remaining >= refresh.before
+            || entry.hits.load(Ordering::Relaxed) < refresh.min_hits
+            || entry.refreshing.swap(true, Ordering::AcqRel)
+        {
+            return false;
+        }
+        if !refresh.budget.try_take() {
+            entry.refreshing.store(false, Ordering::Release);
+            metrics::counter!("jester_cache_refreshes_total", "outcome" => "throttled")
+                .increment(1);
+            return false;
+        }
+        true
+    }
+}
+
+/// Lifetime from `s-maxage` or `max-age`, and the response's `Age`.
+fn freshness(headers: &HeaderMap) -> (Option<Duration>, Duration) {
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for directive in headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+    {
+        let Some((name, secs)) = directive.split_once('=') else {
+            continue;
+        };
+        let Ok(secs) = secs.trim().trim_matches('"').parse::<u64>() else {
+            continue;
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "max-age" => max_age = Some(secs),
+            "s-maxage" => s_maxage = Some(secs),
+            _ => {}
+        }
+    }
+    let age = headers
+        .get(header::AGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+    (
+        s_maxage.or(max_age).map(Duration::from_secs),
+        Duration::from_secs(age),
+    )
+}
+
+/// A copy of `req` without its body, for fetching the same resource again.
+fn refresh_request(req: &HttpRequest) -> HttpRequest {
+    let mut refresh = Request::new(full_body(""));
+    *refresh.method_mut() = req.method().clone();
+    *refresh.uri_mut() = req.uri().clone();
+    *refresh.version_mut() = req.version();
+    *refresh.headers_mut() = req.headers().clone();
+    *refresh.extensions_mut() = req.extensions().clone();
+    // Timings belong to the request that triggered the refresh.
+    refresh.extensions_mut().remove::<RequestContext>();
+    refresh
+}
+
+#[derive(Clone)]
+struct CacheService {
+    inner: JesterService,
+    cache: Arc<Cache>,
+}
+
+impl CacheService {
+    fn spawn_refresh(&self, key: Vec<u8>, entry: Arc<Entry>, req: &HttpRequest) {
+        let cache = self.cache.clone();
+        let response: ResponseFuture = Box::pin(self.inner.clone().oneshot(refresh_request(req)));
+        tokio::spawn(async move {
+            let refreshed = match response.await {
+                Ok(response) => cache
+                    .sharing
+                    .buffer(response)
+                    .await
+                    .map(|(_, shared)| shared),
+                Err(err) => Err(err),
+            };
+            match refreshed {
+                Ok(Some(shared)) => {
+                    cache.store(key, shared);
+                    metrics::counter!("jester_cache_refreshes_total", "outcome" => "refreshed")
+                        .increment(1);
+                }
+                Ok(None) | Err(_) => {
+                    entry.refreshing.store(false, Ordering::Release);
+                    metrics::counter!("jester_cache_refreshes_total", "outcome" => "failed")
+                        .increment(1);
+                }
+            }
+        });
+    }
+}
+
+impl Service<HttpRequest> for CacheService {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HttpRequest) -> Self::Future {
+        let Some(key) = self.cache.sharing.key(&req) else {
+            metrics::counter!("jester_cache_requests_total", "outcome" => "bypass").increment(1);
+            return self.inner.call(req);
+        };
+        if let Some(entry) = self.cache.lookup(&key) {
+            metrics::counter!("jester_cache_requests_total", "outcome" => "hit").increment(1);
+            if self.cache.claim_refresh(&entry) {
+                self.spawn_refresh(key, entry.clone(), &req);
+            }
+            let response = entry.response();
+            return Box::pin(async move { Ok(response) });
+        }
+        metrics::counter!("jester_cache_requests_total", "outcome" => "miss").increment(1);
+        let cache = self.cache.clone();
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let (response, shared) = cache.sharing.buffer(response.await?).await?;
+            if let Some(shared) = shared {
+                cache.store(key, shared);
+            }
+            Ok(response)
+        })
+    }
+}
+
+impl JesterPlugin for CacheFilter {
+    fn name(&self) -> &'static str {
+        "cache"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: CacheConfig = if cfg.is_null() {
+            CacheConfig::default()
+        } else {
+            serde_json::from_value(cfg)?
+        };
+        if cfg.max_entries == 0 {
+            bail!("cache max_entries must be at least 1");
+        }
+        let refresh_ahead = match cfg.refresh_ahead {
+            Some(refresh) if refresh.max_per_sec.is_nan() || refresh.max_per_sec <= 0.0 => {
+                bail!("cache refresh_ahead.max_per_sec must be positive")
+            }
+            Some(refresh) => Some(RefreshAhead {
+                before: Duration::from_secs(refresh.before_secs),
+                min_hits: refresh.min_hits,
+                budget: RefreshBudget::new(refresh.max_per_sec),
+            }),
+            None => None,
+        };
+        let cache = Arc::new(Cache {
+            sharing: Sharing::new(cfg.max_bytes, &cfg.key_headers)?,
+            max_entries: cfg.max_entries,
+            default_ttl: Duration::from_secs(cfg.ttl_secs),
+            refresh_ahead,
+            entries: Mutex::new(HashMap::new()),
+        });
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            JesterService::new(CacheService {
+                inner,
+                cache: cache.clone(),
+            })
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:req", "filter:resp"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+    use tower::service_fn;
+
+    use super::*;
+
+    fn request(path: &str) -> HttpRequest {
+        Request::get(path)
+            .header(header::HOST, "example.com")
+            .body(full_body(""))
+            .unwrap()
+    }
+
+    /// Upstream answering with its call count as the body.
+    fn counting(cache_control: &'static str) -> (JesterService, Arc<AtomicU64>) {
+        let calls = Arc::new(AtomicU64::new(0));
+        let counter = calls.clone();
+        let service = JesterService::new(service_fn(move |_req: HttpRequest| {
+            let call = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            async move {
+                let mut response = HttpResponse::new(full_body(format!("v{call}")));
+                let headers = response.headers_mut();
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(2));
+                headers.insert(
+                    header::CACHE_CONTROL,
+                    HeaderValue::from_static(cache_control),
+                );
+                Ok::<_, anyhow::Error>(response)
+            }
+        }));
+        (service, calls)
+    }
+
+    async fn fetch(service: &JesterService, path: &str) -> (HeaderMap, String) {
+        let response: ResponseFuture = Box::pin(service.clone().oneshot(request(path)));
+        let (parts, body) = response.await.unwrap().into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        (parts.headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn serves_fresh_responses_from_memory() {
+        let (upstream, calls) = counting("public, max-age=60");
+        let service = CacheFilter.layer(Value::Null).unwrap().layer(upstream);
+
+        let (headers, body) = fetch(&service, "/a").await;
+        assert_eq!(body, "v1");
+        assert!(!headers.contains_key(header::AGE));
+        let (headers, body) = fetch(&service, "/a").await;
+        assert_eq!(body, "v1");
+        assert_eq!(headers[header::AGE], "0");
+        assert_eq!(fetch(&service, "/b").await.1, "v2");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let (uncacheable, calls) = counting("no-store");
+        let service = CacheFilter.layer(Value::Null).unwrap().layer(uncacheable);
+        fetch(&service, "/a").await;
+        fetch(&service, "/a").await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn refreshes_popular_entries_before_they_expire() {
+        let (upstream, calls) = counting("max-age=5");
+        let service = CacheFilter
+            .layer(serde_json::json!({
+                "refresh_ahead": { "before_secs": 10, "min_hits": 2, "max_per_sec": 1.0 }
+            }))
+            .unwrap()
+            .layer(upstream);
+
+        assert_eq!(fetch(&service, "/hot").await.1, "v1");
+        assert_eq!(fetch(&service, "/hot").await.1, "v1");
+        assert_eq!(
+            calls.load(Ordering::SeqCst),
+            1,
+            "one hit is not popular yet"
+        );
+        assert_eq!(fetch(&service, "/hot").await.1, "v1");
+        for _ in 0..50 {
+            if calls.load(Ordering::SeqCst) == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(fetch(&service, "/hot").await.1, "v2");
+    }
+
+    #[test]
+    fn lifetime_prefers_s_maxage_and_subtracts_age() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("max-age=60, s-maxage=\"300\""),
+        );
+        headers.insert(header::AGE, HeaderValue::from_static("20"));
+        assert_eq!(
+            freshness(&headers),
+            (Some(Duration::from_secs(300)), Duration::from_secs(20))
+        );
+        let budget = RefreshBudget::new(1.0);
+        assert!(budget.try_take());
+        assert!(!budget.try_take());
+    }
+}
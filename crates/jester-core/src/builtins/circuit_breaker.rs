@@ -0,0 +1,489 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use http::{header, HeaderValue, StatusCode};
+use serde::Deserialize;
+use serde_json::Value;
+use tower::{layer::layer_fn, Service};
+
+use crate::{
+    cluster::Gossip,
+    config::units,
+    config::EventKind,
+    context::RequestContext,
+    error::ProxyError,
+    events::{Event, Events},
+    plugin::{
+        text_response, DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService,
+        ResponseFuture,
+    },
+    router::UpstreamEndpoint,
+    stats::target_key,
+};
+
+/// Fails fast for upstreams that keep failing.
+///
+/// Each upstream target has its own circuit. After `failures` consecutive
+/// failed requests the circuit opens and requests are answered locally with
+/// `503` for `open_secs`. Then up to `half_open_requests` trial requests go
+/// through: if they all succeed the circuit closes, and if any fails it opens
+/// again. Connection errors, upstream protocol errors, timeouts, and responses
+/// with one of `statuses` count as failures.
+///
+/// In cluster mode an opened circuit is gossiped: other instances open their
+/// circuit for the same upstream until the window ends, at most `open_secs`,
+/// and then send their own trial requests.
+///
+/// Config: `{ failures = 5, open_secs = 30, half_open_requests = 1, statuses = [502, 503, 504] }`.
+pub struct CircuitBreakerFilter;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CircuitBreakerConfig {
+    failures: u32,
+    #[serde(deserialize_with = "units::secs", alias = "open_for")]
+    open_secs: u64,
+    half_open_requests: u32,
+    statuses: Vec<u16>,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failures: 5,
+            open_secs: 30,
+            half_open_requests: 1,
+            statuses: vec![502, 503, 504],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Circuit {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { trials: u32, succeeded: u32 },
+}
+
+/// Whether an admitted request is one of the half-open trials.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Admission {
+    Normal,
+    Trial,
+}
+
+struct Breakers {
+    failures: u32,
+    open_for: Duration,
+    half_open_requests: u32,
+    statuses: Vec<StatusCode>,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl Breakers {
+    /// Lets a request to `upstream` through, or returns how long until the
+    /// circuit will admit one. A closed circuit opens for `shared`, the time
+    /// left on a circuit another instance opened.
+    fn admit(&self, upstream: &str, shared: Option<Duration>) -> Result<Admission, Duration> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(upstream.to_string())
+            .or_insert(Circuit::Closed { failures: 0 });
+        match *circuit {
+            Circuit::Closed { .. } => match shared {
+                Some(remaining) if !remaining.is_zero() => {
+                    *circuit = Circuit::Open {
+                        until: Instant::now() + remaining,
+                    };
+                    set_state_gauge(upstream, "open");
+                    Err(remaining)
+                }
+                _ => Ok(Admission::Normal),
+            },
+            Circuit::Open { until } => match until.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => Err(remaining),
+                _ => {
+                    *circuit = Circuit::HalfOpen {
+                        trials: 1,
+                        succeeded: 0,
+                    };
+                    set_state_gauge(upstream, "half_open");
+                    Ok(Admission::Trial)
+                }
+            },
+            Circuit::HalfOpen { trials, succeeded } if trials < self.half_open_requests => {
+                *circuit = Circuit::HalfOpen {
+                    trials: trials + 1,
+                    succeeded,
+                };
+                Ok(Admission::Trial)
+            }
+            Circuit::HalfOpen { .. } => Err(Duration::ZERO),
+        }
+    }
+
+    /// Records how an admitted request went; returns the transition it
+    /// caused, if any. Outcomes of requests admitted before the circuit last
+    /// opened are ignored.
+    fn record(&self, upstream: &str, admission: Admission, failed: bool) -> Option<EventKind> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.get_mut(upstream)?;
+        let next = match (*circuit, admission) {
+            (Circuit::Closed { .. }, Admission::Normal) if !failed => {
+                Circuit::Closed { failures: 0 }
+            }
+            (Circuit::Closed { failures }, Admission::Normal) if failures + 1 < self.failures => {
+                Circuit::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (Circuit::Closed { .. }, Admission::Normal) => self.open(),
+            (Circuit::HalfOpen { .. }, Admission::Trial) if failed => self.open(),
+            (Circuit::HalfOpen { trials, succeeded }, Admission::Trial) => {
+                match succeeded + 1 >= self.half_open_requests {
+                    true => Circuit::Closed { failures: 0 },
+                    false => Circuit::HalfOpen {
+                        trials,
+                        succeeded: succeeded + 1,
+                    },
+                }
+            }
+            _ => return None,
+        };
+        let previous = std::mem::replace(circuit, next);
+        match (previous, next) {
+            (Circuit::Closed { .. } | Circuit::HalfOpen { .. }, Circuit::Open { .. }) => {
+                set_state_gauge(upstream, "open");
+                Some(EventKind::CircuitOpened)
+            }
+            (Circuit::HalfOpen { .. }, Circuit::Closed { .. }) => {
+                set_state_gauge(upstream, "closed");
+                Some(EventKind::CircuitClosed)
+            }
+            _ => None,
+        }
+    }
+
+    /// Frees the slot of a trial request that never finished, so another
+    /// request can take it.
+    fn release(&self, upstream: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(Circuit::HalfOpen { trials, .. }) = circuits.get_mut(upstream) {
+            *trials = trials.saturating_sub(1);
+        }
+    }
+
+    fn open(&self) -> Circuit {
+        Circuit::Open {
+            until: Instant::now() + self.open_for,
+        }
+    }
+
+    fn failed(&self, outcome: &Result<HttpResponse>) -> bool {
+        match outcome {
+            Ok(response) => self.statuses.contains(&response.status()),
+            // Unclassified errors are upstream protocol errors, as in
+            // `ProxyError::classify`.
+            Err(err) => !matches!(
+                err.chain()
+                    .find_map(|cause| cause.downcast_ref::<ProxyError>()),
+                Some(
+                    ProxyError::BodyTooLarge { .. }
+                        | ProxyError::Unavailable(_)
+                        | ProxyError::Rejected { .. }
+                )
+            ),
+        }
+    }
+}
+
+fn set_state_gauge(upstream: &str, state: &'static str) {
+    for candidate in ["closed", "open", "half_open"] {
+        let value = if candidate == state { 1.0 } else { 0.0 };
+        metrics::gauge!("jester_circuit_state", "upstream" => upstream.to_string(), "state" => candidate)
+            .set(value);
+    }
+}
+
+/// Gives back a trial slot if the request is dropped before it completes.
+struct Trial {
+    breakers: Arc<Breakers>,
+    upstream: String,
+    settled: bool,
+}
+
+impl Drop for Trial {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.breakers.release(&self.upstream);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct CircuitBreakerService {
+    inner: JesterService,
+    breakers: Arc<Breakers>,
+}
+
+impl Service<HttpRequest> for CircuitBreakerService {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HttpRequest) -> Self::Future {
+        let upstream = req
+            .extensions()
+            .get::<UpstreamEndpoint>()
+            .map(|endpoint| target_key(&endpoint.uri));
+        let Some(upstream) = upstream else {
+            return self.inner.call(req);
+        };
+        let gossip = req.extensions().get::<Arc<Gossip>>().cloned();
+        let shared = gossip
+            .as_ref()
+            .and_then(|gossip| gossip.open_circuit(&upstream, self.breakers.open_for));
+        let admission = match self.breakers.admit(&upstream, shared) {
+            Ok(admission) => admission,
+            Err(remaining) => {
+                metrics::counter!("jester_circuit_rejections_total", "upstream" => upstream)
+                    .increment(1);
+                return Box::pin(async move { Ok(open_response(remaining)) });
+            }
+        };
+        let mut trial = (admission == Admission::Trial).then(|| Trial {
+            breakers: self.breakers.clone(),
+            upstream: upstream.clone(),
+            settled: false,
+        });
+        let breakers = self.breakers.clone();
+        let events = req.extensions().get::<Events>().cloned();
+        let route = req
+            .extensions()
+            .get::<RequestContext>()
+            .and_then(RequestContext::route);
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let outcome = response.await;
+            if let Some(trial) = &mut trial {
+                trial.settled = true;
+            }
+            let failed = breakers.failed(&outcome);
+            if let Some(kind) = breakers.record(&upstream, admission, failed) {
+                match kind {
+                    EventKind::CircuitOpened => {
+                        tracing::warn!(
+                            upstream,
+                            open_secs = breakers.open_for.as_secs(),
+                            "upstream circuit opened"
+                        );
+                        if let Some(gossip) = &gossip {
+                            gossip.share_open_circuit(&upstream, breakers.open_for);
+                        }
+                    }
+                    _ => tracing::info!(upstream, "upstream circuit closed"),
+                }
+                if let Some(events) = events {
+                    let mut event = Event::new(kind).upstream(&upstream);
+                    if let Some(route) = route {
+                        event = event.route(route);
+                    }
+                    events.publish(event);
+                }
+            }
+            outcome
+        })
+    }
+}
+
+fn open_response(remaining: Duration) -> HttpResponse {
+    let mut response = text_response(StatusCode::SERVICE_UNAVAILABLE, "upstream circuit open");
+    // Round up, and ask for at least a second while trial requests are out.
+    let secs = (remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0)).max(1);
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+    response
+}
+
+impl JesterPlugin for CircuitBreakerFilter {
+    fn name(&self) -> &'static str {
+        "circuit-breaker"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: CircuitBreakerConfig = if cfg.is_null() {
+            CircuitBreakerConfig::default()
+        } else {
+            serde_json::from_value(cfg)?
+        };
+        if cfg.failures == 0 || cfg.half_open_requests == 0 {
+            anyhow::bail!("failures and half_open_requests must be at least 1");
+        }
+        let statuses = cfg
+            .statuses
+            .iter()
+            .map(|code| StatusCode::from_u16(*code))
+            .collect::<Result<Vec<_>, _>>()?;
+        let breakers = Arc::new(Breakers {
+            failures: cfg.failures,
+            open_for: Duration::from_secs(cfg.open_secs),
+            half_open_requests: cfg.half_open_requests,
+            statuses,
+            circuits: Mutex::new(HashMap::new()),
+        });
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            JesterService::new(CircuitBreakerService {
+                inner,
+                breakers: breakers.clone(),
+            })
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:req", "filter:resp"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use http::Request;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+    use crate::plugin::full_body;
+
+    fn request() -> HttpRequest {
+        let mut req = Request::new(full_body(""));
+        req.extensions_mut().insert(UpstreamEndpoint {
+            uri: "http://127.0.0.1:8080".parse().unwrap(),
+            keep_alive: true,
+            host_header: Default::default(),
+            tls: Default::default(),
+            pool: Default::default(),
+            protocol: Default::default(),
+            proxy_protocol: None,
+        });
+        req
+    }
+
+    #[tokio::test]
+    async fn opens_after_consecutive_failures_and_closes_after_a_good_trial() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let healthy = Arc::new(AtomicBool::new(false));
+        let (counter, up) = (calls.clone(), healthy.clone());
+        let inner = JesterService::new(service_fn(move |_req: HttpRequest| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let up = up.load(Ordering::SeqCst);
+            async move {
+                match up {
+                    true => Ok::<_, anyhow::Error>(text_response(StatusCode::OK, "ok")),
+                    false => Err(ProxyError::Connect("refused".into()).into()),
+                }
+            }
+        }));
+        let service = CircuitBreakerFilter
+            .layer(serde_json::json!({ "failures": 2, "open_secs": 0 }))
+            .unwrap()
+            .layer(inner);
+
+        for _ in 0..2 {
+            assert!(service.clone().oneshot(request()).await.is_err());
+        }
+        // With `open_secs = 0` the next request is the trial; it fails and
+        // reopens the circuit.
+        assert!(service.clone().oneshot(request()).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        healthy.store(true, Ordering::SeqCst);
+        let trial = service.clone().oneshot(request()).await.unwrap();
+        assert_eq!(trial.status(), StatusCode::OK);
+        let closed = service.oneshot(request()).await.unwrap();
+        assert_eq!(closed.status(), StatusCode::OK);
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn open_circuits_answer_locally() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let inner = JesterService::new(service_fn(move |_req: HttpRequest| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async { Ok::<_, anyhow::Error>(text_response(StatusCode::BAD_GATEWAY, "down")) }
+        }));
+        let service = CircuitBreakerFilter
+            .layer(serde_json::json!({ "failures": 1, "open_secs": 30 }))
+            .unwrap()
+            .layer(inner);
+
+        let first = service.clone().oneshot(request()).await.unwrap();
+        assert_eq!(first.status(), StatusCode::BAD_GATEWAY);
+        let second = service.oneshot(request()).await.unwrap();
+        assert_eq!(second.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(second.headers()[header::RETRY_AFTER], "30");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn abandoned_trials_free_their_slot() {
+        let breakers = Breakers {
+            failures: 1,
+            open_for: Duration::ZERO,
+            half_open_requests: 1,
+            statuses: Vec::new(),
+            circuits: Mutex::new(HashMap::new()),
+        };
+        let upstream = "127.0.0.1:8080";
+        assert_eq!(breakers.admit(upstream, None), Ok(Admission::Normal));
+        assert_eq!(
+            breakers.record(upstream, Admission::Normal, true),
+            Some(EventKind::CircuitOpened)
+        );
+        assert_eq!(breakers.admit(upstream, None), Ok(Admission::Trial));
+        assert!(breakers.admit(upstream, None).is_err());
+        breakers.release(upstream);
+        assert_eq!(breakers.admit(upstream, None), Ok(Admission::Trial));
+    }
+
+    #[test]
+    fn circuits_opened_elsewhere_open_here_then_send_trials() {
+        let breakers = Breakers {
+            failures: 5,
+            open_for: Duration::from_secs(30),
+            half_open_requests: 1,
+            statuses: Vec::new(),
+            circuits: Mutex::new(HashMap::new()),
+        };
+        let upstream = "127.0.0.1:8080";
+        let shared = Some(Duration::from_millis(20));
+        assert_eq!(
+            breakers.admit(upstream, shared),
+            Err(Duration::from_millis(20))
+        );
+        assert!(breakers.admit(upstream, None).is_err());
+        std::thread::sleep(Duration::from_millis(30));
+        // The gossiped window may still be live on other nodes; a half-open
+        // circuit no longer consults it.
+        assert_eq!(breakers.admit(upstream, shared), Ok(Admission::Trial));
+        assert_eq!(
+            breakers.record(upstream, Admission::Trial, false),
+            Some(EventKind::CircuitClosed)
+        );
+        assert_eq!(breakers.admit(upstream, None), Ok(Admission::Normal));
+    }
+}
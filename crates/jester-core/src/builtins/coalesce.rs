@@ -0,0 +1,426 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderName, Method, StatusCode, Version};
+use http_body_util::BodyExt;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::watch;
+use tower::{layer::layer_fn, Service, ServiceExt};
+
+use crate::{
+    config::units,
+    plugin::{
+        full_body, DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService, ResponseFuture,
+    },
+};
+
+/// Collapses concurrent identical requests into one upstream fetch.
+///
+/// While a `GET` or `HEAD` is in flight, identical requests on the route wait
+/// for it and get a copy of its response instead of reaching the upstream,
+/// which keeps a hot object from stampeding the backend when it goes stale.
+/// Requests are identical when their method, host, path, query, and
+/// `key_headers` match. Requests with credentials (`Authorization`,
+/// `Cookie`) or `Cache-Control: no-cache` always go upstream on their own.
+///
+/// Only responses that a shared cache could store are fanned out: no
+/// `Set-Cookie`, no `private`/`no-store`/`no-cache`, a `Vary` limited to
+/// `key_headers`, and a `Content-Length` of at most `max_bytes`. Otherwise
+/// the waiting requests are sent upstream after all.
+///
+/// Config: `{ max_bytes = 1048576, key_headers = ["accept", "accept-encoding",
+/// "accept-language"] }`.
+pub struct CoalesceFilter;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CoalesceConfig {
+    #[serde(deserialize_with = "units::bytes", alias = "max_size")]
+    max_bytes: u64,
+    key_headers: Vec<String>,
+}
+
+impl Default for CoalesceConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 1024 * 1024,
+            key_headers: vec![
+                "accept".into(),
+                "accept-encoding".into(),
+                "accept-language".into(),
+            ],
+        }
+    }
+}
+
+/// What waiters receive once the leading request finishes: its response, or
+/// `None` when it could not be shared.
+type Outcome = Option<Arc<SharedResponse>>;
+
+/// A buffered response that can be handed to any number of requests.
+pub(super) struct SharedResponse {
+    pub(super) status: StatusCode,
+    pub(super) version: Version,
+    pub(super) headers: HeaderMap,
+    pub(super) body: Bytes,
+}
+
+impl SharedResponse {
+    pub(super) fn response(&self) -> HttpResponse {
+        let mut response = HttpResponse::new(full_body(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.version_mut() = self.version;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+/// The rules of a shared cache: which requests may be answered with another
+/// request's response, and which responses may be reused. The `cache` filter
+/// follows them too.
+pub(super) struct Sharing {
+    pub(super) max_bytes: u64,
+    pub(super) key_headers: Vec<HeaderName>,
+}
+
+impl Sharing {
+    pub(super) fn new(max_bytes: u64, key_headers: &[String]) -> Result<Self> {
+        let key_headers = key_headers
+            .iter()
+            .map(|name| HeaderName::from_bytes(name.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            max_bytes,
+            key_headers,
+        })
+    }
+
+    /// Identifies requests that may share a response; `None` for those that
+    /// must not.
+    pub(super) fn key(&self, req: &HttpRequest) -> Option<Vec<u8>> {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return None;
+        }
+        let headers = req.headers();
+        let private = [header::AUTHORIZATION, header::COOKIE, header::UPGRADE]
+            .iter()
+            .any(|name| headers.contains_key(name));
+        let no_cache = [header::CACHE_CONTROL, header::PRAGMA].iter().any(|name| {
+            directives(headers, name)
+                .any(|directive| directive == "no-cache" || directive == "no-store")
+        });
+        if private || no_cache {
+            return None;
+        }
+        let mut key = Vec::new();
+        key.extend_from_slice(req.method().as_str().as_bytes());
+        key.push(b' ');
+        if let Some(host) = headers.get(header::HOST) {
+            key.extend_from_slice(host.as_bytes());
+        }
+        if let Some(path) = req.uri().path_and_query() {
+            key.extend_from_slice(path.as_str().as_bytes());
+        }
+        for name in &self.key_headers {
+            key.push(b'\n');
+            for value in headers.get_all(name) {
+                key.extend_from_slice(value.as_bytes());
+                key.push(b',');
+            }
+        }
+        Some(key)
+    }
+
+    /// Buffers `response` if a shared cache could store it; otherwise hands it
+    /// back untouched.
+    pub(super) async fn buffer(&self, response: HttpResponse) -> Result<(HttpResponse, Outcome)> {
+        let headers = response.headers();
+        let storable = !headers.contains_key(header::SET_COOKIE)
+            && !directives(headers, &header::CACHE_CONTROL)
+                .any(|directive| matches!(directive.as_str(), "private" | "no-store" | "no-cache"))
+            && directives(headers, &header::VARY).all(|field| {
+                self.key_headers
+                    .iter()
+                    .any(|name| name.as_str() == field.as_str())
+            });
+        let length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if !storable
+            || response.status() == StatusCode::SWITCHING_PROTOCOLS
+            || length.is_none_or(|length| length > self.max_bytes)
+        {
+            return Ok((response, None));
+        }
+        let (parts, body) = response.into_parts();
+        // Hyper holds the body to its `Content-Length`, checked above.
+        let body = match body.collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(err) => bail!("failed to read upstream body for sharing: {err}"),
+        };
+        let shared = Arc::new(SharedResponse {
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+        });
+        Ok((
+            HttpResponse::from_parts(parts, full_body(body)),
+            Some(shared),
+        ))
+    }
+}
+
+/// Lowercased comma-separated entries of every `name` field, without values.
+pub(super) fn directives<'a>(
+    headers: &'a HeaderMap,
+    name: &HeaderName,
+) -> impl Iterator<Item = String> + 'a {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| {
+            let directive = directive.split('=').next().unwrap_or_default();
+            directive.trim().to_ascii_lowercase()
+        })
+        .filter(|directive| !directive.is_empty())
+}
+
+struct Coalescer {
+    sharing: Sharing,
+    in_flight: Mutex<HashMap<Vec<u8>, watch::Receiver<Option<Outcome>>>>,
+}
+
+/// Joining an in-flight fetch or leading a new one.
+enum Role {
+    Leader(Flight),
+    Waiter(watch::Receiver<Option<Outcome>>),
+}
+
+impl Coalescer {
+    fn join(self: &Arc<Self>, key: Vec<u8>) -> Role {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(receiver) = in_flight.get(&key) {
+            return Role::Waiter(receiver.clone());
+        }
+        let (sender, receiver) = watch::channel(None);
+        in_flight.insert(key.clone(), receiver);
+        Role::Leader(Flight {
+            coalescer: self.clone(),
+            key,
+            sender,
+        })
+    }
+}
+
+/// The leading request for a key; waiters are released when it is dropped,
+/// with its outcome if one was published.
+struct Flight {
+    coalescer: Arc<Coalescer>,
+    key: Vec<u8>,
+    sender: watch::Sender<Option<Outcome>>,
+}
+
+impl Flight {
+    fn publish(&self, outcome: Outcome) {
+        self.sender.send_replace(Some(outcome));
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        self.coalescer.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+#[derive(Clone)]
+struct CoalesceService {
+    inner: JesterService,
+    coalescer: Arc<Coalescer>,
+}
+
+impl Service<HttpRequest> for CoalesceService {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HttpRequest) -> Self::Future {
+        let Some(key) = self.coalescer.sharing.key(&req) else {
+            return self.inner.call(req);
+        };
+        match self.coalescer.join(key) {
+            Role::Leader(flight) => {
+                let coalescer = self.coalescer.clone();
+                let response = self.inner.call(req);
+                Box::pin(async move {
+                    let (response, outcome) = coalescer.sharing.buffer(response.await?).await?;
+                    flight.publish(outcome);
+                    Ok(response)
+                })
+            }
+            Role::Waiter(mut receiver) => {
+                // Only polled if the leader's response cannot be shared.
+                let fallback: ResponseFuture = Box::pin(self.inner.clone().oneshot(req));
+                Box::pin(async move {
+                    // An error means the leader went away without an outcome.
+                    let outcome = match receiver.wait_for(|outcome| outcome.is_some()).await {
+                        Ok(outcome) => outcome.clone().flatten(),
+                        Err(_) => None,
+                    };
+                    match outcome {
+                        Some(shared) => {
+                            metrics::counter!("jester_coalesced_requests_total", "outcome" => "shared")
+                                .increment(1);
+                            Ok(shared.response())
+                        }
+                        None => {
+                            metrics::counter!("jester_coalesced_requests_total", "outcome" => "unshared")
+                                .increment(1);
+                            fallback.await
+                        }
+                    }
+                })
+            }
+        }
+    }
+}
+
+impl JesterPlugin for CoalesceFilter {
+    fn name(&self) -> &'static str {
+        "coalesce"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: CoalesceConfig = if cfg.is_null() {
+            CoalesceConfig::default()
+        } else {
+            serde_json::from_value(cfg)?
+        };
+        let coalescer = Arc::new(Coalescer {
+            sharing: Sharing::new(cfg.max_bytes, &cfg.key_headers)?,
+            in_flight: Mutex::new(HashMap::new()),
+        });
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            JesterService::new(CoalesceService {
+                inner,
+                coalescer: coalescer.clone(),
+            })
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:req", "filter:resp"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use http::{HeaderValue, Request};
+    use tower::service_fn;
+
+    use super::*;
+
+    fn request(path: &str) -> HttpRequest {
+        Request::get(path)
+            .header(header::HOST, "example.com")
+            .body(full_body(""))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn concurrent_identical_requests_share_one_fetch() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let inner = JesterService::new(service_fn(move |req: HttpRequest| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let private = req.uri().path() == "/private";
+                let mut response = HttpResponse::new(full_body("hot"));
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_LENGTH, HeaderValue::from(3));
+                if private {
+                    response
+                        .headers_mut()
+                        .insert(header::SET_COOKIE, HeaderValue::from_static("id=1"));
+                }
+                Ok::<_, anyhow::Error>(response)
+            }
+        }));
+        let service = CoalesceFilter.layer(Value::Null).unwrap().layer(inner);
+
+        let fetch = |path: &'static str| {
+            let response: ResponseFuture = Box::pin(service.clone().oneshot(request(path)));
+            tokio::spawn(async move {
+                let response = response.await.unwrap();
+                response.into_body().collect().await.unwrap().to_bytes()
+            })
+        };
+        let hot = [fetch("/hot"), fetch("/hot"), fetch("/hot"), fetch("/other")];
+        for fetched in hot {
+            assert_eq!(fetched.await.unwrap(), "hot");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let private = [fetch("/private"), fetch("/private")];
+        for fetched in private {
+            fetched.await.unwrap();
+        }
+        assert_eq!(
+            calls.load(Ordering::SeqCst),
+            4,
+            "set-cookie responses are not shared"
+        );
+    }
+
+    #[test]
+    fn requests_with_credentials_are_never_coalesced() {
+        let sharing = Sharing {
+            max_bytes: 1024,
+            key_headers: vec![header::ACCEPT],
+        };
+        let mut req = request("/a?b=1");
+        assert!(sharing.key(&req).is_some());
+        req.headers_mut().insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("max-age=0, No-Cache"),
+        );
+        assert!(sharing.key(&req).is_none());
+
+        let mut req = request("/a");
+        req.headers_mut()
+            .insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer x"));
+        assert!(sharing.key(&req).is_none());
+
+        let json = {
+            let mut req = request("/a");
+            req.headers_mut()
+                .insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+            sharing.key(&req)
+        };
+        assert_ne!(json, sharing.key(&request("/a")));
+    }
+}
@@ -0,0 +1,585 @@
+use std::{
+    io::Write,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use http_body_util::BodyExt;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+use tower::{layer::layer_fn, Service};
+use zstd::dict::EncoderDictionary;
+
+use super::reloadable::Reloadable;
+use crate::{
+    config::units,
+    plugin::{
+        full_body, DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService, ResponseFuture,
+    },
+};
+
+/// Magic prefix of a `dcz` body, followed by the dictionary's SHA-256 (RFC 9842).
+const DCZ_HEADER: [u8; 8] = [0x5e, 0x2a, 0x4d, 0x18, 0x20, 0x00, 0x00, 0x00];
+
+/// Compresses upstream responses with the first of `algorithms` the client
+/// accepts.
+///
+/// Bodies are buffered, so only responses with a `Content-Length` between
+/// `min_bytes` and `max_bytes` and a `content_types` prefix are compressed.
+/// At most `max_concurrent` compressions run at once (default: one per CPU);
+/// responses arriving while all are busy are sent uncompressed. Levels are
+/// capped below the memory-hungry extremes (zstd 19, brotli 11, gzip 9).
+///
+/// `zstd_dictionaries` lists pre-trained dictionaries for JSON APIs. Clients
+/// that announce one of them in `Available-Dictionary` and accept `dcz` get
+/// dictionary-compressed zstd (RFC 9842); distributing the dictionaries is up
+/// to the application.
+///
+/// Config: `{ min_bytes = 1024, algorithms = ["zstd", "br", "gzip"],
+/// levels = { zstd = 3, br = 4, gzip = 6 }, zstd_dictionaries = ["api.dict"] }`.
+pub struct CompressionFilter;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CompressionConfig {
+    #[serde(deserialize_with = "units::bytes", alias = "min_size")]
+    min_bytes: u64,
+    #[serde(deserialize_with = "units::bytes", alias = "max_size")]
+    max_bytes: u64,
+    algorithms: Vec<Algorithm>,
+    levels: Levels,
+    content_types: Vec<String>,
+    zstd_dictionaries: Vec<String>,
+    max_concurrent: Option<usize>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            min_bytes: 1024,
+            max_bytes: 8 * 1024 * 1024,
+            algorithms: vec![Algorithm::Zstd, Algorithm::Brotli, Algorithm::Gzip],
+            levels: Levels::default(),
+            content_types: [
+                "text/",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .map(String::from)
+            .to_vec(),
+            zstd_dictionaries: Vec::new(),
+            max_concurrent: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Levels {
+    zstd: i32,
+    br: u32,
+    gzip: u32,
+}
+
+impl Default for Levels {
+    fn default() -> Self {
+        Self {
+            zstd: 3,
+            br: 4,
+            gzip: 6,
+        }
+    }
+}
+
+impl Levels {
+    fn validate(&self) -> Result<()> {
+        if !(1..=19).contains(&self.zstd) {
+            bail!("compression level for zstd must be between 1 and 19");
+        }
+        if self.br > 11 {
+            bail!("compression level for br must be between 0 and 11");
+        }
+        if self.gzip > 9 {
+            bail!("compression level for gzip must be between 0 and 9");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+enum Algorithm {
+    #[serde(rename = "zstd")]
+    Zstd,
+    #[serde(rename = "br")]
+    Brotli,
+    #[serde(rename = "gzip")]
+    Gzip,
+}
+
+impl Algorithm {
+    fn token(self) -> &'static str {
+        match self {
+            Algorithm::Zstd => "zstd",
+            Algorithm::Brotli => "br",
+            Algorithm::Gzip => "gzip",
+        }
+    }
+}
+
+/// The encoding chosen for one response.
+#[derive(Clone)]
+enum Encoding {
+    Plain(Algorithm),
+    Dictionary(Arc<Dictionary>),
+}
+
+impl Encoding {
+    fn token(&self) -> &'static str {
+        match self {
+            Encoding::Plain(algorithm) => algorithm.token(),
+            Encoding::Dictionary(_) => "dcz",
+        }
+    }
+}
+
+struct Dictionary {
+    /// The `Available-Dictionary` value that selects it.
+    key: String,
+    hash: [u8; 32],
+    encoder: EncoderDictionary<'static>,
+}
+
+impl Dictionary {
+    fn new(raw: &[u8], level: i32) -> Self {
+        let hash: [u8; 32] = Sha256::digest(raw).into();
+        Self {
+            key: format!(":{}:", BASE64.encode(hash)),
+            hash,
+            encoder: EncoderDictionary::copy(raw, level),
+        }
+    }
+}
+
+struct Compressor {
+    min_bytes: u64,
+    max_bytes: u64,
+    algorithms: Vec<Algorithm>,
+    levels: Levels,
+    content_types: Vec<String>,
+    /// Watched, so a retrained dictionary is served without a restart.
+    dictionaries: Vec<Arc<Reloadable<Dictionary>>>,
+    budget: Arc<Semaphore>,
+}
+
+impl Compressor {
+    fn from_config(cfg: CompressionConfig) -> Result<Self> {
+        cfg.levels.validate()?;
+        if cfg.algorithms.is_empty() {
+            bail!("compression requires at least one algorithm");
+        }
+        let dictionaries = cfg
+            .zstd_dictionaries
+            .iter()
+            .map(|path| {
+                let level = cfg.levels.zstd;
+                Reloadable::watch("compression", path, move |raw| {
+                    Ok(Dictionary::new(raw, level))
+                })
+                .with_context(|| format!("failed to read zstd dictionary {path}"))
+            })
+            .collect::<Result<_>>()?;
+        let max_concurrent = cfg
+            .max_concurrent
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |cpus| cpus.get()));
+        if max_concurrent == 0 {
+            bail!("compression max_concurrent must be at least 1");
+        }
+        Ok(Self {
+            min_bytes: cfg.min_bytes,
+            max_bytes: cfg.max_bytes,
+            algorithms: cfg.algorithms,
+            levels: cfg.levels,
+            content_types: cfg.content_types,
+            dictionaries,
+            budget: Arc::new(Semaphore::new(max_concurrent)),
+        })
+    }
+
+    /// Picks an encoding for a request, preferring a shared dictionary.
+    fn negotiate(&self, headers: &HeaderMap) -> Option<Encoding> {
+        let accepted = accepted_encodings(headers);
+        let accepts = |token: &str| {
+            accepted
+                .iter()
+                .find(|(name, _)| name == token)
+                .or_else(|| accepted.iter().find(|(name, _)| name == "*"))
+                .is_some_and(|(_, q)| *q > 0.0)
+        };
+        let dictionary = headers
+            .get("available-dictionary")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                self.dictionaries
+                    .iter()
+                    .map(|dictionary| dictionary.get())
+                    .find(|dictionary| dictionary.key == value.trim())
+            });
+        if let Some(dictionary) = dictionary.filter(|_| accepts("dcz")) {
+            return Some(Encoding::Dictionary(dictionary));
+        }
+        self.algorithms
+            .iter()
+            .find(|algorithm| accepts(algorithm.token()))
+            .map(|algorithm| Encoding::Plain(*algorithm))
+    }
+
+    /// Whether a response is a candidate for compression at all.
+    fn eligible(&self, response: &HttpResponse) -> bool {
+        let status = response.status();
+        if status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::PARTIAL_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+        {
+            return false;
+        }
+        let headers = response.headers();
+        if headers.contains_key(header::CONTENT_ENCODING) {
+            return false;
+        }
+        let no_transform = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.to_ascii_lowercase().contains("no-transform"));
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_ascii_lowercase);
+        let compressible = content_type.is_some_and(|content_type| {
+            self.content_types
+                .iter()
+                .any(|prefix| content_type.starts_with(prefix.as_str()))
+        });
+        !no_transform && compressible
+    }
+
+    fn compress(&self, encoding: &Encoding, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match encoding {
+            Encoding::Plain(Algorithm::Zstd) => zstd::bulk::compress(data, self.levels.zstd),
+            Encoding::Plain(Algorithm::Brotli) => {
+                let mut out = Vec::new();
+                {
+                    let mut writer =
+                        brotli::CompressorWriter::new(&mut out, 4096, self.levels.br, 22);
+                    writer.write_all(data)?;
+                }
+                Ok(out)
+            }
+            Encoding::Plain(Algorithm::Gzip) => {
+                let level = flate2::Compression::new(self.levels.gzip);
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Dictionary(dictionary) => {
+                let mut compressor =
+                    zstd::bulk::Compressor::with_prepared_dictionary(&dictionary.encoder)?;
+                let mut out = Vec::with_capacity(DCZ_HEADER.len() + 32 + data.len() / 2);
+                out.extend_from_slice(&DCZ_HEADER);
+                out.extend_from_slice(&dictionary.hash);
+                out.extend_from_slice(&compressor.compress(data)?);
+                Ok(out)
+            }
+        }
+    }
+
+    fn vary(&self) -> &'static str {
+        if self.dictionaries.is_empty() {
+            "accept-encoding"
+        } else {
+            "accept-encoding, available-dictionary"
+        }
+    }
+}
+
+/// `Accept-Encoding` tokens (lowercased) with their q-values.
+fn accepted_encodings(headers: &HeaderMap) -> Vec<(String, f32)> {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let name = parts.next()?.trim().to_ascii_lowercase();
+            if name.is_empty() {
+                return None;
+            }
+            let q = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse().ok())
+                .unwrap_or(1.0);
+            Some((name, q))
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+struct CompressionService {
+    inner: JesterService,
+    compressor: Arc<Compressor>,
+}
+
+impl Service<HttpRequest> for CompressionService {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HttpRequest) -> Self::Future {
+        let encoding = match req.method() {
+            &Method::HEAD => None,
+            _ => self.compressor.negotiate(req.headers()),
+        };
+        let compressor = self.compressor.clone();
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let mut response = response.await?;
+            if !compressor.eligible(&response) {
+                return Ok(response);
+            }
+            response
+                .headers_mut()
+                .append(header::VARY, HeaderValue::from_static(compressor.vary()));
+            let Some(encoding) = encoding else {
+                return Ok(response);
+            };
+            let length = response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok());
+            if !length.is_some_and(|length| {
+                (compressor.min_bytes..=compressor.max_bytes).contains(&length)
+            }) {
+                return Ok(response);
+            }
+            let Ok(permit) = compressor.budget.clone().try_acquire_owned() else {
+                metrics::counter!("jester_compression_skipped_total", "reason" => "busy")
+                    .increment(1);
+                return Ok(response);
+            };
+
+            let (mut parts, body) = response.into_parts();
+            // Hyper holds the body to its `Content-Length`, checked above.
+            let body = match body.collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(err) => bail!("failed to read upstream body for compression: {err}"),
+            };
+            let token = encoding.token();
+            let worker = compressor.clone();
+            let input = body.clone();
+            let compressed = tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                worker.compress(&encoding, &input)
+            })
+            .await?;
+            let compressed = match compressed {
+                Ok(compressed) if compressed.len() < body.len() => compressed,
+                Ok(_) => return Ok(HttpResponse::from_parts(parts, full_body(body))),
+                Err(err) => {
+                    tracing::warn!(error = %err, encoding = token, "compression failed");
+                    return Ok(HttpResponse::from_parts(parts, full_body(body)));
+                }
+            };
+            metrics::counter!("jester_compression_responses_total", "encoding" => token)
+                .increment(1);
+            metrics::counter!("jester_compression_input_bytes_total", "encoding" => token)
+                .increment(body.len() as u64);
+            metrics::counter!("jester_compression_output_bytes_total", "encoding" => token)
+                .increment(compressed.len() as u64);
+            parts
+                .headers
+                .insert(header::CONTENT_ENCODING, HeaderValue::from_static(token));
+            parts
+                .headers
+                .insert(header::CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+            super::weaken_etag(&mut parts.headers);
+            Ok(HttpResponse::from_parts(
+                parts,
+                full_body(Bytes::from(compressed)),
+            ))
+        })
+    }
+}
+
+impl JesterPlugin for CompressionFilter {
+    fn name(&self) -> &'static str {
+        "compression"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: CompressionConfig = if cfg.is_null() {
+            CompressionConfig::default()
+        } else {
+            serde_json::from_value(cfg)?
+        };
+        let compressor = Arc::new(Compressor::from_config(cfg)?);
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            JesterService::new(CompressionService {
+                inner,
+                compressor: compressor.clone(),
+            })
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:resp"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use http::Request;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    const JSON: &str = r#"{"items":[{"id":1,"name":"widget","tags":["a","b"]},{"id":2,"name":"gadget","tags":["a","c"]}]}"#;
+
+    fn service(cfg: Value) -> JesterService {
+        let inner = JesterService::new(service_fn(|_req: HttpRequest| async {
+            let body = JSON.repeat(40);
+            let mut resp = HttpResponse::new(full_body(body.clone()));
+            let headers = resp.headers_mut();
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+            headers.insert(header::ETAG, HeaderValue::from_static("\"v1\""));
+            Ok::<_, anyhow::Error>(resp)
+        }));
+        CompressionFilter.layer(cfg).unwrap().layer(inner)
+    }
+
+    fn request(headers: &[(&str, &str)]) -> HttpRequest {
+        let mut req = Request::builder();
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(full_body("")).unwrap()
+    }
+
+    async fn body(resp: HttpResponse) -> Bytes {
+        resp.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn uses_the_configured_preference_order() {
+        let service = service(serde_json::json!({ "algorithms": ["br", "gzip"] }));
+        let resp = service
+            .oneshot(request(&[("accept-encoding", "gzip, zstd, br;q=0.5")]))
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_ENCODING], "br");
+        assert_eq!(resp.headers()[header::VARY], "accept-encoding");
+        assert_eq!(resp.headers()[header::ETAG], "W/\"v1\"");
+        let compressed = body(resp).await;
+        let mut plain = String::new();
+        brotli::Decompressor::new(&compressed[..], 4096)
+            .read_to_string(&mut plain)
+            .unwrap();
+        assert_eq!(plain, JSON.repeat(40));
+    }
+
+    #[tokio::test]
+    async fn passes_through_without_an_acceptable_encoding() {
+        let service = service(serde_json::json!({ "algorithms": ["zstd"] }));
+        let resp = service
+            .oneshot(request(&[("accept-encoding", "gzip, zstd;q=0")]))
+            .await
+            .unwrap();
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(resp.headers()[header::VARY], "accept-encoding");
+        assert_eq!(body(resp).await, JSON.repeat(40));
+    }
+
+    #[tokio::test]
+    async fn compresses_with_an_available_dictionary() {
+        let path = std::env::temp_dir().join(format!("jester-zstd-dict-{}", std::process::id()));
+        std::fs::write(&path, JSON).unwrap();
+        let hash = format!(":{}:", BASE64.encode(Sha256::digest(JSON)));
+        let service = service(serde_json::json!({ "zstd_dictionaries": [path] }));
+
+        let resp = service
+            .clone()
+            .oneshot(request(&[
+                ("accept-encoding", "gzip, br, zstd, dcz"),
+                ("available-dictionary", &hash),
+            ]))
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_ENCODING], "dcz");
+        assert_eq!(
+            resp.headers()[header::VARY],
+            "accept-encoding, available-dictionary"
+        );
+        let compressed = body(resp).await;
+        assert_eq!(compressed[..8], DCZ_HEADER);
+        assert_eq!(compressed[8..40], Sha256::digest(JSON)[..]);
+        let plain = zstd::bulk::Decompressor::with_dictionary(JSON.as_bytes())
+            .unwrap()
+            .decompress(&compressed[40..], 1 << 20)
+            .unwrap();
+        assert_eq!(plain, JSON.repeat(40).as_bytes());
+
+        // An unknown dictionary falls back to the preference order.
+        let resp = service
+            .oneshot(request(&[
+                ("accept-encoding", "zstd, dcz"),
+                ("available-dictionary", ":AAAA:"),
+            ]))
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_ENCODING], "zstd");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn skips_bodies_outside_the_size_window() {
+        let service = service(serde_json::json!({ "max_bytes": 64 }));
+        let resp = service
+            .oneshot(request(&[("accept-encoding", "gzip")]))
+            .await
+            .unwrap();
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn rejects_levels_beyond_the_cpu_budget() {
+        let err = CompressionFilter
+            .layer(serde_json::json!({ "levels": { "zstd": 22 } }))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("zstd"));
+    }
+}
@@ -0,0 +1,363 @@
+use std::{
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use http::{header, HeaderName, HeaderValue, Method};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use serde_json::Value;
+use tower::{layer::layer_fn, Service};
+
+use crate::{
+    config::units,
+    plugin::{
+        full_body, DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService, ResponseFuture,
+    },
+};
+
+/// Placeholder in `policy` replaced by each response's nonce.
+const PLACEHOLDER: &str = "{nonce}";
+
+/// Adds a per-response nonce to the `Content-Security-Policy` so a strict,
+/// nonce-based policy can be enforced in front of applications that do not
+/// generate one themselves.
+///
+/// Every response gets `policy` with `{nonce}` replaced by 128 fresh random
+/// bits, replacing any policy the upstream sent. With `inject = true`, `tags`
+/// in HTML responses up to `max_bytes` get a matching `nonce` attribute.
+/// HTML that cannot be rewritten (compressed, or larger than `max_bytes`) is
+/// passed through untouched rather than with a policy that would block its
+/// scripts.
+///
+/// Config: `{ policy = "script-src 'nonce-{nonce}' 'strict-dynamic'; object-src 'none'; base-uri 'none'",
+/// report_only = false, inject = true, tags = ["script"], max_bytes = 2097152 }`.
+pub struct CspNonceFilter;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CspNonceConfig {
+    policy: String,
+    report_only: bool,
+    inject: bool,
+    tags: Vec<Tag>,
+    #[serde(deserialize_with = "units::bytes", alias = "max_size")]
+    max_bytes: usize,
+}
+
+impl Default for CspNonceConfig {
+    fn default() -> Self {
+        Self {
+            policy:
+                "script-src 'nonce-{nonce}' 'strict-dynamic'; object-src 'none'; base-uri 'none'"
+                    .into(),
+            report_only: false,
+            inject: true,
+            tags: vec![Tag::Script],
+            max_bytes: 2 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+enum Tag {
+    #[serde(rename = "script")]
+    Script,
+    #[serde(rename = "style")]
+    Style,
+}
+
+impl Tag {
+    fn name(self) -> &'static str {
+        match self {
+            Tag::Script => "script",
+            Tag::Style => "style",
+        }
+    }
+}
+
+struct Policy {
+    /// `policy` split around its placeholders.
+    parts: Vec<String>,
+    header: HeaderName,
+    inject: bool,
+    tags: Vec<Tag>,
+    max_bytes: usize,
+    rng: SystemRandom,
+}
+
+impl Policy {
+    fn from_config(cfg: CspNonceConfig) -> Result<Self> {
+        if !cfg.policy.contains(PLACEHOLDER) {
+            bail!("csp-nonce policy must contain `{PLACEHOLDER}`");
+        }
+        // Any nonce is base64, so checking one rendering checks them all.
+        HeaderValue::from_str(&cfg.policy.replace(PLACEHOLDER, "AAAA"))
+            .map_err(|_| anyhow::anyhow!("csp-nonce policy is not a valid header value"))?;
+        if cfg.inject && cfg.tags.is_empty() {
+            bail!("csp-nonce needs at least one tag to inject into");
+        }
+        Ok(Self {
+            parts: cfg.policy.split(PLACEHOLDER).map(String::from).collect(),
+            header: if cfg.report_only {
+                header::CONTENT_SECURITY_POLICY_REPORT_ONLY
+            } else {
+                header::CONTENT_SECURITY_POLICY
+            },
+            inject: cfg.inject,
+            tags: cfg.tags,
+            max_bytes: cfg.max_bytes,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    fn nonce(&self) -> Result<String> {
+        let mut bytes = [0u8; 16];
+        if self.rng.fill(&mut bytes).is_err() {
+            bail!("failed to generate a CSP nonce");
+        }
+        Ok(BASE64.encode(bytes))
+    }
+
+    fn header_value(&self, nonce: &str) -> HeaderValue {
+        HeaderValue::from_str(&self.parts.join(nonce)).expect("validated when the filter was built")
+    }
+
+    fn set_header(&self, response: &mut HttpResponse, nonce: &str) {
+        let headers = response.headers_mut();
+        headers.remove(header::CONTENT_SECURITY_POLICY);
+        headers.remove(header::CONTENT_SECURITY_POLICY_REPORT_ONLY);
+        headers.insert(self.header.clone(), self.header_value(nonce));
+    }
+}
+
+/// Adds `nonce="..."` to every opening `tags` element without one.
+fn inject(html: &[u8], tags: &[Tag], nonce: &str) -> Vec<u8> {
+    super::html::rewrite(html, |tag| {
+        let wanted = tags.iter().any(|wanted| wanted.name() == tag.name());
+        if wanted && !tag.is_end() && tag.attribute("nonce").is_none() {
+            tag.set_attribute("nonce", nonce);
+        }
+    })
+}
+
+#[derive(Clone)]
+struct CspNonceService {
+    inner: JesterService,
+    policy: Arc<Policy>,
+}
+
+impl Service<HttpRequest> for CspNonceService {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HttpRequest) -> Self::Future {
+        let head = req.method() == Method::HEAD;
+        let policy = self.policy.clone();
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let mut response = response.await?;
+            let nonce = policy.nonce()?;
+            if !policy.inject || head || !super::html::is_html(&response) {
+                policy.set_header(&mut response, &nonce);
+                return Ok(response);
+            }
+            if response.headers().contains_key(header::CONTENT_ENCODING) {
+                return Ok(skipped(response, "encoded"));
+            }
+            let length = response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<usize>().ok());
+            if length.is_some_and(|length| length > policy.max_bytes) {
+                return Ok(skipped(response, "too_large"));
+            }
+            let (mut parts, body) = response.into_parts();
+            let html = match super::buffer(body, policy.max_bytes).await {
+                Ok(Ok(html)) => html,
+                Ok(Err(body)) => {
+                    return Ok(skipped(HttpResponse::from_parts(parts, body), "too_large"))
+                }
+                Err(err) => bail!("failed to read upstream body: {err}"),
+            };
+            let html = Bytes::from(inject(&html, &policy.tags, &nonce));
+            parts
+                .headers
+                .insert(header::CONTENT_LENGTH, HeaderValue::from(html.len()));
+            // The body now differs on every response.
+            parts.headers.remove(header::ETAG);
+            let mut response = HttpResponse::from_parts(parts, full_body(html));
+            policy.set_header(&mut response, &nonce);
+            metrics::counter!("jester_csp_nonce_injected_total").increment(1);
+            Ok(response)
+        })
+    }
+}
+
+fn skipped(response: HttpResponse, reason: &'static str) -> HttpResponse {
+    tracing::debug!(reason, "HTML response sent without a CSP nonce");
+    metrics::counter!("jester_csp_nonce_skipped_total", "reason" => reason).increment(1);
+    response
+}
+
+impl JesterPlugin for CspNonceFilter {
+    fn name(&self) -> &'static str {
+        "csp-nonce"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: CspNonceConfig = if cfg.is_null() {
+            CspNonceConfig::default()
+        } else {
+            serde_json::from_value(cfg)?
+        };
+        let policy = Arc::new(Policy::from_config(cfg)?);
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            JesterService::new(CspNonceService {
+                inner,
+                policy: policy.clone(),
+            })
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:resp"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Request;
+    use http_body_util::BodyExt;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    const PAGE: &str = r#"<!doctype html><html><head>
+<SCRIPT src="/app.js"></SCRIPT>
+<script nonce="keep">boot()</script>
+<!-- <script>commented()</script> -->
+<style>body { color: red }</style>
+</head><body><script type="module">document.write("<script>inner()</script>")</script>
+<scripts></scripts></body></html>"#;
+
+    fn service(cfg: Value, content_type: &'static str, body: &'static str) -> JesterService {
+        let inner = JesterService::new(service_fn(move |_req: HttpRequest| async move {
+            let mut resp = HttpResponse::new(full_body(body));
+            let headers = resp.headers_mut();
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+            headers.insert(
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_static("default-src *"),
+            );
+            Ok::<_, anyhow::Error>(resp)
+        }));
+        CspNonceFilter.layer(cfg).unwrap().layer(inner)
+    }
+
+    fn nonce_of(resp: &HttpResponse) -> String {
+        let policy = resp.headers()[header::CONTENT_SECURITY_POLICY]
+            .to_str()
+            .unwrap();
+        let start = policy.find("'nonce-").unwrap() + 7;
+        policy[start..start + 24].to_string()
+    }
+
+    #[tokio::test]
+    async fn injects_the_header_nonce_into_script_tags() {
+        let service = service(Value::Null, "text/html; charset=utf-8", PAGE);
+        let resp = service
+            .clone()
+            .oneshot(Request::new(full_body("")))
+            .await
+            .unwrap();
+        let nonce = nonce_of(&resp);
+        assert_eq!(
+            resp.headers()
+                .get_all(header::CONTENT_SECURITY_POLICY)
+                .iter()
+                .count(),
+            1
+        );
+        let length: usize = resp.headers()[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body.len(), length);
+        let expected = PAGE
+            .replace(
+                r#"src="/app.js">"#,
+                &format!(r#"src="/app.js" nonce="{nonce}">"#),
+            )
+            .replace(
+                r#"type="module">"#,
+                &format!(r#"type="module" nonce="{nonce}">"#),
+            );
+        assert_eq!(body, expected);
+
+        let again = service.oneshot(Request::new(full_body(""))).await.unwrap();
+        assert_ne!(nonce_of(&again), nonce);
+    }
+
+    #[tokio::test]
+    async fn only_sets_the_header_on_other_responses() {
+        let cfg = serde_json::json!({ "report_only": true, "policy": "style-src 'nonce-{nonce}'" });
+        let resp = service(cfg, "application/json", "{}")
+            .oneshot(Request::new(full_body("")))
+            .await
+            .unwrap();
+        assert!(!resp.headers().contains_key(header::CONTENT_SECURITY_POLICY));
+        let policy = resp.headers()[header::CONTENT_SECURITY_POLICY_REPORT_ONLY]
+            .to_str()
+            .unwrap();
+        assert!(policy.starts_with("style-src 'nonce-"));
+    }
+
+    #[tokio::test]
+    async fn streams_oversized_html_through_untouched() {
+        let inner = JesterService::new(service_fn(|_req: HttpRequest| async {
+            let mut resp = HttpResponse::new(full_body(PAGE));
+            resp.headers_mut()
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html"));
+            Ok::<_, anyhow::Error>(resp)
+        }));
+        let service = CspNonceFilter
+            .layer(serde_json::json!({ "max_bytes": 16 }))
+            .unwrap()
+            .layer(inner);
+        let resp = service.oneshot(Request::new(full_body(""))).await.unwrap();
+        assert!(!resp.headers().contains_key(header::CONTENT_SECURITY_POLICY));
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, PAGE);
+    }
+
+    #[test]
+    fn injects_into_configured_tags_only() {
+        let html = inject(PAGE.as_bytes(), &[Tag::Style], "n");
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.contains(r#"<style nonce="n">"#));
+        assert_eq!(html.matches("nonce=").count(), 2);
+    }
+
+    #[test]
+    fn rejects_policies_without_a_placeholder() {
+        let cfg = serde_json::json!({ "policy": "script-src 'self'" });
+        assert!(CspNonceFilter.layer(cfg).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use http::{header, HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use serde_json::Value;
+use tower::{layer::layer_fn, ServiceExt};
+
+use crate::plugin::{DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService};
+
+/// Controls which client headers are forwarded upstream and which backend headers
+/// reach clients.
+///
+/// Patterns are case-insensitive and may use `*` as a wildcard (`x-internal-*`). With
+/// `allow`, only matching headers pass; `deny` then removes matches from what is
+/// left, and `rewrite` replaces the value of any remaining header matching a pattern.
+/// Framing headers (`host`, `content-length`, `transfer-encoding`) are never removed.
+///
+/// Config: `{ request = { allow = ["pattern"], deny = ["pattern"], rewrite = { pattern = "value" } },
+/// response = { ... } }`.
+pub struct HeaderPolicyFilter;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HeaderPolicyConfig {
+    request: PolicyConfig,
+    response: PolicyConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PolicyConfig {
+    allow: Option<Vec<String>>,
+    deny: Vec<String>,
+    rewrite: BTreeMap<String, String>,
+}
+
+struct Policy {
+    allow: Option<Vec<HeaderPattern>>,
+    deny: Vec<HeaderPattern>,
+    rewrite: Vec<(HeaderPattern, HeaderValue)>,
+}
+
+impl Policy {
+    fn is_noop(&self) -> bool {
+        self.allow.is_none() && self.deny.is_empty() && self.rewrite.is_empty()
+    }
+
+    fn forwards(&self, name: &HeaderName) -> bool {
+        let name = name.as_str();
+        let allowed = self
+            .allow
+            .as_ref()
+            .is_none_or(|allow| allow.iter().any(|pattern| pattern.matches(name)));
+        allowed && !self.deny.iter().any(|pattern| pattern.matches(name))
+    }
+
+    fn apply(&self, headers: &mut HeaderMap) {
+        const FRAMING: [HeaderName; 3] = [
+            header::HOST,
+            header::CONTENT_LENGTH,
+            header::TRANSFER_ENCODING,
+        ];
+        let dropped = headers
+            .keys()
+            .filter(|name| !FRAMING.contains(name) && !self.forwards(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in dropped {
+            headers.remove(name);
+        }
+        if self.rewrite.is_empty() {
+            return;
+        }
+        for (name, value) in headers.iter_mut() {
+            if let Some((_, replacement)) = self
+                .rewrite
+                .iter()
+                .find(|(pattern, _)| pattern.matches(name.as_str()))
+            {
+                *value = replacement.clone();
+            }
+        }
+    }
+}
+
+impl TryFrom<PolicyConfig> for Policy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: PolicyConfig) -> Result<Self> {
+        let parse = |patterns: Vec<String>| {
+            patterns
+                .into_iter()
+                .map(HeaderPattern::new)
+                .collect::<Result<Vec<_>>>()
+        };
+        let rewrite = value
+            .rewrite
+            .into_iter()
+            .map(|(pattern, replacement)| {
+                let value = HeaderValue::from_str(&replacement)
+                    .with_context(|| format!("invalid rewrite value for `{pattern}`"))?;
+                Ok((HeaderPattern::new(pattern)?, value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            allow: value.allow.map(parse).transpose()?,
+            deny: parse(value.deny)?,
+            rewrite,
+        })
+    }
+}
+
+/// Case-insensitive header name glob where `*` matches any run of characters.
+pub(crate) struct HeaderPattern {
+    parts: Vec<String>,
+}
+
+impl HeaderPattern {
+    pub(crate) fn new(pattern: String) -> Result<Self> {
+        let pattern = pattern.trim().to_ascii_lowercase();
+        if pattern.is_empty() {
+            bail!("header pattern must not be empty");
+        }
+        if let Some(bad) = pattern
+            .chars()
+            .find(|c| *c != '*' && HeaderName::from_bytes(c.to_string().as_bytes()).is_err())
+        {
+            bail!("invalid character `{bad}` in header pattern `{pattern}`");
+        }
+        Ok(Self {
+            parts: pattern.split('*').map(str::to_string).collect(),
+        })
+    }
+
+    pub(crate) fn matches(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        let (first, rest) = self.parts.split_first().expect("split yields one part");
+        let Some(mut remaining) = name.strip_prefix(first.as_str()) else {
+            return false;
+        };
+        let Some((last, middle)) = rest.split_last() else {
+            return remaining.is_empty();
+        };
+        for part in middle {
+            match remaining.find(part.as_str()) {
+                Some(index) => remaining = &remaining[index + part.len()..],
+                None => return false,
+            }
+        }
+        remaining.ends_with(last.as_str())
+    }
+}
+
+impl JesterPlugin for HeaderPolicyFilter {
+    fn name(&self) -> &'static str {
+        "header-policy"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: HeaderPolicyConfig = if cfg.is_null() {
+            HeaderPolicyConfig::default()
+        } else {
+            serde_json::from_value(cfg)?
+        };
+        let request = Arc::new(Policy::try_from(cfg.request)?);
+        let response = Arc::new(Policy::try_from(cfg.response)?);
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            let mut service = inner;
+            if !request.is_noop() {
+                let request = request.clone();
+                service = JesterService::new(service.map_request(move |mut req: HttpRequest| {
+                    request.apply(req.headers_mut());
+                    req
+                }));
+            }
+            if !response.is_noop() {
+                let response = response.clone();
+                service =
+                    JesterService::new(service.map_response(move |mut resp: HttpResponse| {
+                        response.apply(resp.headers_mut());
+                        resp
+                    }));
+            }
+            service
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:req", "filter:resp"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: Option<&[&str]>, deny: &[&str]) -> Policy {
+        let owned = |items: &[&str]| items.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        Policy::try_from(PolicyConfig {
+            allow: allow.map(owned),
+            deny: owned(deny),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn headers(names: &[&str]) -> HeaderMap {
+        names
+            .iter()
+            .map(|name| {
+                (
+                    HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    "1".parse().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn patterns_match_wildcards_case_insensitively() {
+        let pattern = HeaderPattern::new("X-Debug-*".into()).unwrap();
+        assert!(pattern.matches("x-debug-trace"));
+        assert!(!pattern.matches("x-debugger"));
+        let pattern = HeaderPattern::new("*-id".into()).unwrap();
+        assert!(pattern.matches("x-request-id"));
+        assert!(!pattern.matches("x-request-ids"));
+        assert!(HeaderPattern::new("bad header".into()).is_err());
+    }
+
+    #[test]
+    fn denylist_strips_matching_headers() {
+        let mut map = headers(&["cookie", "referer", "accept", "host"]);
+        policy(None, &["cookie", "referer"]).apply(&mut map);
+        assert_eq!(map.len(), 2);
+        assert!(map.contains_key("accept") && map.contains_key("host"));
+    }
+
+    #[test]
+    fn allowlist_keeps_framing_headers() {
+        let mut map = headers(&["accept-language", "cookie", "host", "content-length"]);
+        policy(Some(&["accept*"]), &[]).apply(&mut map);
+        assert!(map.contains_key("accept-language"));
+        assert!(!map.contains_key("cookie"));
+        assert!(map.contains_key("host") && map.contains_key("content-length"));
+    }
+
+    #[test]
+    fn response_policy_strips_and_rewrites() {
+        let policy = Policy::try_from(PolicyConfig {
+            deny: vec!["x-internal-*".into()],
+            rewrite: BTreeMap::from([("server".into(), "jester".into())]),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut map = headers(&["x-internal-node", "server", "content-type"]);
+        policy.apply(&mut map);
+        assert!(!map.contains_key("x-internal-node"));
+        assert_eq!(map["server"], "jester");
+        assert_eq!(map["content-type"], "1");
+    }
+}
@@ -0,0 +1,109 @@
+use std::{collections::BTreeMap, str::FromStr, sync::Arc};
+
+use anyhow::{Context, Result};
+use http::{header::HeaderName, HeaderMap, HeaderValue};
+use serde::Deserialize;
+use serde_json::Value;
+use tower::{layer::layer_fn, ServiceExt};
+
+use crate::plugin::{DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService};
+
+/// Sets or removes request and response headers.
+///
+/// Config: `{ request = { set = { name = "value" }, remove = ["name"] }, response = { ... } }`.
+pub struct HeadersFilter;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HeadersConfig {
+    request: HeaderOpsConfig,
+    response: HeaderOpsConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HeaderOpsConfig {
+    set: BTreeMap<String, String>,
+    remove: Vec<String>,
+}
+
+#[derive(Default)]
+struct HeaderOps {
+    set: Vec<(HeaderName, HeaderValue)>,
+    remove: Vec<HeaderName>,
+}
+
+impl HeaderOps {
+    fn apply(&self, headers: &mut HeaderMap) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+impl TryFrom<HeaderOpsConfig> for HeaderOps {
+    type Error = anyhow::Error;
+
+    fn try_from(value: HeaderOpsConfig) -> Result<Self> {
+        let set = value
+            .set
+            .into_iter()
+            .map(|(name, value)| {
+                let header = HeaderName::from_str(&name)
+                    .with_context(|| format!("invalid header name `{name}`"))?;
+                let value = HeaderValue::from_str(&value)
+                    .with_context(|| format!("invalid value for header `{name}`"))?;
+                Ok((header, value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let remove = value
+            .remove
+            .iter()
+            .map(|name| {
+                HeaderName::from_str(name).with_context(|| format!("invalid header name `{name}`"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { set, remove })
+    }
+}
+
+impl JesterPlugin for HeadersFilter {
+    fn name(&self) -> &'static str {
+        "headers"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: HeadersConfig = if cfg.is_null() {
+            HeadersConfig::default()
+        } else {
+            serde_json::from_value(cfg)?
+        };
+        let request = Arc::new(HeaderOps::try_from(cfg.request)?);
+        let response = Arc::new(HeaderOps::try_from(cfg.response)?);
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            let (request, response) = (request.clone(), response.clone());
+            JesterService::new(
+                inner
+                    .map_request(move |mut req: HttpRequest| {
+                        request.apply(req.headers_mut());
+                        req
+                    })
+                    .map_response(move |mut resp: HttpResponse| {
+                        response.apply(resp.headers_mut());
+                        resp
+                    }),
+            )
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:req", "filter:resp"]
+    }
+}
@@ -0,0 +1,299 @@
+//! Tag-level HTML scanning for the filters that rewrite buffered pages.
+//!
+//! This is not a full HTML parser: it finds start and end tags, skips
+//! comments, declarations, and the raw text of `<script>` and `<style>`, and
+//! lets a callback edit the attributes of each tag. Everything
+//! it does not touch is copied byte for byte.
+
+use std::ops::Range;
+
+use http::{header, StatusCode};
+
+use crate::plugin::HttpResponse;
+
+/// Elements whose contents are text, so `<` inside them starts no tag.
+const RAW_TEXT: [&str; 2] = ["script", "style"];
+
+/// Whether a response carries an HTML body worth rewriting.
+pub(super) fn is_html(response: &HttpResponse) -> bool {
+    let status = response.status();
+    if status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return false;
+    }
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("text/html"))
+}
+
+/// A start or end tag as seen by [`rewrite`].
+pub(super) struct Tag<'a> {
+    raw: &'a [u8],
+    /// Lowercased.
+    name: String,
+    end: bool,
+    attributes: Vec<Attribute>,
+    /// Where attributes are added: before the closing `>` or `/>`.
+    insert_at: usize,
+    edits: Vec<(Range<usize>, Vec<u8>)>,
+}
+
+struct Attribute {
+    /// Lowercased.
+    name: String,
+    /// End of the name in `raw`.
+    name_end: usize,
+    /// The value in `raw`, with and without its quotes.
+    value: Option<(Range<usize>, Range<usize>)>,
+}
+
+impl<'a> Tag<'a> {
+    pub(super) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(super) fn is_end(&self) -> bool {
+        self.end
+    }
+
+    /// The raw value of an attribute (entities are not decoded); `Some("")`
+    /// when it is present without a value.
+    pub(super) fn attribute(&self, name: &str) -> Option<&'a str> {
+        let attribute = self.attributes.iter().find(|attr| attr.name == name)?;
+        let Some((_, inner)) = &attribute.value else {
+            return Some("");
+        };
+        std::str::from_utf8(&self.raw[inner.clone()]).ok()
+    }
+
+    /// Sets an attribute to `value`, which is written as is apart from
+    /// escaping `"`.
+    pub(super) fn set_attribute(&mut self, name: &str, value: &str) {
+        let quoted = format!("\"{}\"", value.replace('"', "&quot;")).into_bytes();
+        match self.attributes.iter().find(|attr| attr.name == name) {
+            Some(Attribute {
+                value: Some((outer, _)),
+                ..
+            }) => self.edits.push((outer.clone(), quoted)),
+            Some(attribute) => {
+                let mut assignment = b"=".to_vec();
+                assignment.extend_from_slice(&quoted);
+                let at = attribute.name_end;
+                self.edits.push((at..at, assignment));
+            }
+            None => {
+                let mut attribute = format!(" {name}=").into_bytes();
+                attribute.extend_from_slice(&quoted);
+                self.edits.push((self.insert_at..self.insert_at, attribute));
+            }
+        }
+    }
+
+    fn write(mut self, out: &mut Vec<u8>) {
+        self.edits.sort_by_key(|(range, _)| range.start);
+        let mut at = 0;
+        for (range, replacement) in &self.edits {
+            out.extend_from_slice(&self.raw[at..range.start]);
+            out.extend_from_slice(replacement);
+            at = range.end;
+        }
+        out.extend_from_slice(&self.raw[at..]);
+    }
+}
+
+/// Calls `edit` on every tag of `html` and returns the edited page.
+pub(super) fn rewrite(html: &[u8], mut edit: impl FnMut(&mut Tag<'_>)) -> Vec<u8> {
+    let mut out = Vec::with_capacity(html.len() + 256);
+    let mut at = 0;
+    while let Some(offset) = html[at..].iter().position(|&byte| byte == b'<') {
+        let start = at + offset;
+        out.extend_from_slice(&html[at..start]);
+        let rest = &html[start..];
+        let end = if rest.starts_with(b"<!--") {
+            find(rest, b"-->").map_or(html.len(), |end| start + end + 3)
+        } else if rest.starts_with(b"<!") || rest.starts_with(b"<?") {
+            find(rest, b">").map_or(html.len(), |end| start + end + 1)
+        } else if let Some(mut tag) = parse_tag(rest) {
+            let tag_end = start + tag.raw.len();
+            let raw_text = (!tag.end && RAW_TEXT.contains(&tag.name.as_str()))
+                .then(|| format!("</{}", tag.name));
+            edit(&mut tag);
+            tag.write(&mut out);
+            // The contents of raw text elements run up to their end tag.
+            at = match raw_text {
+                Some(close) => find_ignore_case(&html[tag_end..], close.as_bytes())
+                    .map_or(html.len(), |close| tag_end + close),
+                None => tag_end,
+            };
+            out.extend_from_slice(&html[tag_end..at]);
+            continue;
+        } else {
+            start + 1
+        };
+        out.extend_from_slice(&html[start..end]);
+        at = end;
+    }
+    out.extend_from_slice(&html[at..]);
+    out
+}
+
+/// Length of the tag at the start of `rest`, up to its unquoted `>`.
+fn tag_len(rest: &[u8]) -> usize {
+    let mut quote = None;
+    for (index, &byte) in rest.iter().enumerate() {
+        match (quote, byte) {
+            (None, b'"' | b'\'') => quote = Some(byte),
+            (Some(open), _) if byte == open => quote = None,
+            (None, b'>') => return index + 1,
+            _ => {}
+        }
+    }
+    rest.len()
+}
+
+fn parse_tag(rest: &[u8]) -> Option<Tag<'_>> {
+    let end = rest.get(1) == Some(&b'/');
+    let name_start = if end { 2 } else { 1 };
+    if !rest.get(name_start)?.is_ascii_alphabetic() {
+        return None;
+    }
+    let raw = &rest[..tag_len(rest)];
+    let is_name_end = |byte: u8| byte.is_ascii_whitespace() || byte == b'/' || byte == b'>';
+    let name_end = raw[name_start..]
+        .iter()
+        .position(|&byte| is_name_end(byte))
+        .map_or(raw.len(), |len| name_start + len);
+    let name = String::from_utf8_lossy(&raw[name_start..name_end]).to_ascii_lowercase();
+
+    let mut attributes = Vec::new();
+    let mut at = name_end;
+    let gt = raw.len() - usize::from(raw.ends_with(b">"));
+    let close = gt - usize::from(raw[..gt].ends_with(b"/"));
+    loop {
+        while at < close && (raw[at].is_ascii_whitespace() || raw[at] == b'/') {
+            at += 1;
+        }
+        if at >= close {
+            break;
+        }
+        let start = at;
+        while at < close && !(raw[at].is_ascii_whitespace() || raw[at] == b'=' || raw[at] == b'/') {
+            at += 1;
+        }
+        let name_end = at;
+        let name = String::from_utf8_lossy(&raw[start..name_end]).to_ascii_lowercase();
+        let mut next = at;
+        while next < close && raw[next].is_ascii_whitespace() {
+            next += 1;
+        }
+        let mut value = None;
+        if next < close && raw[next] == b'=' {
+            next += 1;
+            while next < close && raw[next].is_ascii_whitespace() {
+                next += 1;
+            }
+            let value_start = next;
+            match raw.get(next) {
+                Some(&quote @ (b'"' | b'\'')) => {
+                    let inner_end = raw[next + 1..close]
+                        .iter()
+                        .position(|&byte| byte == quote)
+                        .map_or(close, |len| next + 1 + len);
+                    next = (inner_end + 1).min(close);
+                    value = Some((value_start..next, value_start + 1..inner_end));
+                }
+                // An unquoted value may end in `/`, as in `<a href=/docs/>`.
+                _ => {
+                    while next < gt && !raw[next].is_ascii_whitespace() {
+                        next += 1;
+                    }
+                    value = Some((value_start..next, value_start..next));
+                }
+            }
+            at = next;
+        }
+        attributes.push(Attribute {
+            name,
+            name_end,
+            value,
+        });
+    }
+    Some(Tag {
+        raw,
+        name,
+        end,
+        attributes,
+        insert_at: close.max(at),
+        edits: Vec::new(),
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn find_ignore_case(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(html: &str, edit: impl FnMut(&mut Tag<'_>)) -> String {
+        String::from_utf8(rewrite(html.as_bytes(), edit)).unwrap()
+    }
+
+    #[test]
+    fn untouched_pages_come_back_byte_for_byte() {
+        let page = "<!DOCTYPE html><!-- <a href=x> --><p class='a>b' hidden>x < y</p><br/>";
+        assert_eq!(edit(page, |_| {}), page);
+    }
+
+    #[test]
+    fn attributes_are_read_and_set_in_place() {
+        let page = r#"<A HREF='/x' data-y=1 hidden>link</a><img src=/i/>"#;
+        let out = edit(page, |tag| {
+            if tag.name() == "a" && !tag.is_end() {
+                assert_eq!(tag.attribute("href"), Some("/x"));
+                assert_eq!(tag.attribute("hidden"), Some(""));
+                tag.set_attribute("href", "/app/x");
+                tag.set_attribute("hidden", "until-found");
+                tag.set_attribute("title", "say \"hi\"");
+            }
+            if tag.name() == "img" {
+                assert_eq!(tag.attribute("src"), Some("/i/"));
+                tag.set_attribute("alt", "");
+            }
+        });
+        assert_eq!(
+            out,
+            r#"<A HREF="/app/x" data-y=1 hidden="until-found" title="say &quot;hi&quot;">link</a><img src=/i/ alt="">"#
+        );
+    }
+
+    #[test]
+    fn raw_text_is_not_scanned_for_tags() {
+        let page =
+            r#"<script>document.write("<a href='/x'>")</script><style>a<b{}</STYLE><a href="/y">"#;
+        let mut seen = Vec::new();
+        let out = edit(page, |tag| {
+            seen.push(format!(
+                "{}{}",
+                if tag.is_end() { "/" } else { "" },
+                tag.name()
+            ));
+        });
+        assert_eq!(seen, ["script", "/script", "style", "/style", "a"]);
+        assert_eq!(out, page);
+    }
+}
@@ -0,0 +1,524 @@
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use http::{header, Method};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use lol_html::{element, html_content::ContentType, send, OutputSink, Selector};
+use serde::Deserialize;
+use serde_json::Value;
+use tower::{layer::layer_fn, Service};
+
+use super::html;
+use crate::plugin::{
+    BoxError, DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService, ProxyBody,
+    ResponseFuture,
+};
+
+/// Rewrites HTML responses so applications that emit root-relative links can
+/// be hosted below a path prefix, and adds per-route snippets such as banners.
+///
+/// With `prefix = "/app"`, root-relative URLs (`/login`, but not `//cdn` or
+/// `https://...`) in the `attributes` of any tag get the prefix unless they
+/// already start with it. `base_href` adds a `<base href>` at the top of
+/// `<head>`, which wins over any the page has since browsers use the first.
+/// Each `inject` entry adds `html` once, at `head_start`, `head_end`,
+/// `body_start`, or `body_end`.
+///
+/// Pages are rewritten with lol_html as their chunks arrive. The filter asks
+/// the upstream for uncompressed responses; HTML that is compressed anyway
+/// passes through untouched.
+///
+/// Config: `{ prefix = "/app", attributes = ["href", "src", "action"],
+/// base_href = "/app/", inject = [{ at = "body_start", html = "<div>staging</div>" }] }`.
+pub struct HtmlRewriteFilter;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HtmlRewriteConfig {
+    prefix: Option<String>,
+    attributes: Vec<String>,
+    base_href: Option<String>,
+    inject: Vec<Snippet>,
+}
+
+impl Default for HtmlRewriteConfig {
+    fn default() -> Self {
+        Self {
+            prefix: None,
+            attributes: ["href", "src", "action"].map(String::from).to_vec(),
+            base_href: None,
+            inject: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Snippet {
+    at: Position,
+    html: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Position {
+    HeadStart,
+    HeadEnd,
+    BodyStart,
+    BodyEnd,
+}
+
+struct Rewriter {
+    prefix: Option<String>,
+    attributes: Vec<String>,
+    /// The `<base>` element added for `base_href`.
+    base: Option<String>,
+    inject: Vec<Snippet>,
+}
+
+impl Rewriter {
+    fn from_config(cfg: HtmlRewriteConfig) -> Result<Self> {
+        if let Some(prefix) = &cfg.prefix {
+            if !prefix.starts_with('/')
+                || prefix.ends_with('/')
+                || prefix.contains(|c: char| c.is_whitespace() || "\"'<>?#".contains(c))
+            {
+                bail!("html-rewrite prefix must be a path like `/app`, got `{prefix}`");
+            }
+        }
+        if cfg.prefix.is_none() && cfg.base_href.is_none() && cfg.inject.is_empty() {
+            bail!("html-rewrite needs a `prefix`, `base_href`, or `inject` entry");
+        }
+        let attributes: Vec<String> = cfg
+            .attributes
+            .into_iter()
+            .map(|name| name.to_ascii_lowercase())
+            .collect();
+        for name in &attributes {
+            if format!("[{name}]").parse::<Selector>().is_err() {
+                bail!("html-rewrite attribute `{name}` is not a valid attribute name");
+            }
+        }
+        Ok(Self {
+            prefix: cfg.prefix,
+            attributes,
+            base: cfg.base_href.map(|href| {
+                let href = href.replace('&', "&amp;").replace('"', "&quot;");
+                format!("<base href=\"{href}\">")
+            }),
+            inject: cfg.inject,
+        })
+    }
+
+    /// Starts rewriting one page, writing the result to `output`.
+    fn start(&self, output: Output) -> send::HtmlRewriter<'static, Output> {
+        let mut handlers = Vec::new();
+        if let Some(prefix) = &self.prefix {
+            for name in &self.attributes {
+                let (prefix, name) = (prefix.clone(), name.clone());
+                handlers.push(element!(
+                    format!("[{name}]"),
+                    move |el: &mut send::Element<'_, '_>| {
+                        if let Some(url) = el.get_attribute(&name) {
+                            if let Some(url) = prefixed(&prefix, &url) {
+                                el.set_attribute(&name, &url)?;
+                            }
+                        }
+                        Ok(())
+                    }
+                ));
+            }
+        }
+        let snippets = |at: Position| -> Vec<String> {
+            self.inject
+                .iter()
+                .filter(|snippet| snippet.at == at)
+                .map(|snippet| snippet.html.clone())
+                .collect()
+        };
+        // Prepending puts each piece before the earlier ones, so the base
+        // goes last to end up first.
+        let mut head_start = snippets(Position::HeadStart);
+        head_start.reverse();
+        head_start.extend(self.base.clone());
+        for (tag, start, end) in [
+            ("head", head_start, snippets(Position::HeadEnd)),
+            (
+                "body",
+                snippets(Position::BodyStart),
+                snippets(Position::BodyEnd),
+            ),
+        ] {
+            if start.is_empty() && end.is_empty() {
+                continue;
+            }
+            let mut done = false;
+            handlers.push(element!(tag, move |el: &mut send::Element<'_, '_>| {
+                if !std::mem::replace(&mut done, true) {
+                    for html in &start {
+                        el.prepend(html, ContentType::Html);
+                    }
+                    for html in &end {
+                        el.append(html, ContentType::Html);
+                    }
+                }
+                Ok(())
+            }));
+        }
+        send::HtmlRewriter::new(
+            send::Settings {
+                element_content_handlers: handlers,
+                strict: false,
+                ..send::Settings::new_send()
+            },
+            output,
+        )
+    }
+}
+
+/// `url` with `prefix` added, if it is root-relative and does not already
+/// start with the prefix.
+fn prefixed(prefix: &str, url: &str) -> Option<String> {
+    let root_relative = url.starts_with('/') && !url.starts_with("//");
+    let already = url
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '?', '#']));
+    (root_relative && !already).then(|| format!("{prefix}{url}"))
+}
+
+/// Collects what lol_html writes until the body hands it on.
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    fn take(&self) -> Option<Bytes> {
+        let mut buffer = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        (!buffer.is_empty()).then(|| Bytes::from(std::mem::take(&mut *buffer)))
+    }
+}
+
+impl OutputSink for Output {
+    fn handle_chunk(&mut self, chunk: &[u8]) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend_from_slice(chunk);
+    }
+}
+
+/// A response body fed through the rewriter chunk by chunk.
+struct RewritingBody {
+    inner: ProxyBody,
+    /// `None` once the page has ended. The mutex only makes the body `Sync`.
+    rewriter: Option<Mutex<send::HtmlRewriter<'static, Output>>>,
+    output: Output,
+    /// Trailers held back while the rewriter's last output goes out.
+    trailers: Option<Frame<Bytes>>,
+}
+
+impl RewritingBody {
+    fn write(&mut self, data: &[u8]) -> Result<(), BoxError> {
+        if let Some(rewriter) = &mut self.rewriter {
+            let rewriter = rewriter.get_mut().unwrap_or_else(PoisonError::into_inner);
+            rewriter.write(data).map_err(failed)?;
+        }
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<(), BoxError> {
+        if let Some(rewriter) = self.rewriter.take() {
+            let rewriter = rewriter
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner);
+            rewriter.end().map_err(failed)?;
+            metrics::counter!("jester_html_rewritten_total").increment(1);
+        }
+        Ok(())
+    }
+}
+
+fn failed(err: lol_html::errors::RewritingError) -> BoxError {
+    tracing::warn!(error = %err, "HTML rewriting failed mid-page");
+    metrics::counter!("jester_html_rewrite_errors_total").increment(1);
+    err.into()
+}
+
+impl Body for RewritingBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        loop {
+            if let Some(data) = self.output.take() {
+                return Poll::Ready(Some(Ok(Frame::data(data))));
+            }
+            if let Some(trailers) = self.trailers.take() {
+                return Poll::Ready(Some(Ok(trailers)));
+            }
+            if self.rewriter.is_none() {
+                return Poll::Ready(None);
+            }
+            match std::task::ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => self.write(&data)?,
+                    Err(trailers) => {
+                        self.end()?;
+                        self.trailers = Some(trailers);
+                    }
+                },
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => self.end()?,
+            }
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
+
+#[derive(Clone)]
+struct HtmlRewriteService {
+    inner: JesterService,
+    rewriter: Arc<Rewriter>,
+}
+
+impl Service<HttpRequest> for HtmlRewriteService {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: HttpRequest) -> Self::Future {
+        let head = req.method() == Method::HEAD;
+        // lol_html needs the markup itself; a `compression` filter listed
+        // after this one can compress the rewritten page again.
+        req.headers_mut().remove(header::ACCEPT_ENCODING);
+        let rewriter = self.rewriter.clone();
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let response = response.await?;
+            if head || !html::is_html(&response) {
+                return Ok(response);
+            }
+            if response.headers().contains_key(header::CONTENT_ENCODING) {
+                tracing::debug!("compressed HTML response passed through without rewriting");
+                metrics::counter!("jester_html_rewrite_skipped_total", "reason" => "encoded")
+                    .increment(1);
+                return Ok(response);
+            }
+            let (mut parts, body) = response.into_parts();
+            parts.headers.remove(header::CONTENT_LENGTH);
+            super::weaken_etag(&mut parts.headers);
+            let output = Output::default();
+            let body = RewritingBody {
+                inner: body,
+                rewriter: Some(Mutex::new(rewriter.start(output.clone()))),
+                output,
+                trailers: None,
+            };
+            Ok(HttpResponse::from_parts(parts, body.boxed()))
+        })
+    }
+}
+
+impl JesterPlugin for HtmlRewriteFilter {
+    fn name(&self) -> &'static str {
+        "html-rewrite"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: HtmlRewriteConfig = if cfg.is_null() {
+            HtmlRewriteConfig::default()
+        } else {
+            serde_json::from_value(cfg)?
+        };
+        let rewriter = Arc::new(Rewriter::from_config(cfg)?);
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            JesterService::new(HtmlRewriteService {
+                inner,
+                rewriter: rewriter.clone(),
+            })
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:resp"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderValue, Request};
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+    use crate::plugin::full_body;
+
+    const PAGE: &str = r#"<html><head><link rel=stylesheet href=/css/site.css></head>
+<body><a href="/login">in</a> <a href="https://example.com/x">out</a> <a href="//cdn/x">cdn</a>
+<a href="/app/already">ok</a> <a href="/application">other</a> <a href="docs">relative</a>
+<form action='/search?q=1'><img src="/logo.png"></form></body></html>"#;
+
+    fn rewriter(cfg: Value) -> Rewriter {
+        Rewriter::from_config(serde_json::from_value(cfg).unwrap()).unwrap()
+    }
+
+    /// Feeds `page` to the rewriter a few bytes at a time, so tags straddle
+    /// chunks.
+    fn rewrite(cfg: Value, page: &str) -> String {
+        let output = Output::default();
+        let mut rewriter = rewriter(cfg).start(output.clone());
+        for chunk in page.as_bytes().chunks(7) {
+            rewriter.write(chunk).unwrap();
+        }
+        rewriter.end().unwrap();
+        String::from_utf8(output.take().unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn prefixes_root_relative_links() {
+        let out = rewrite(serde_json::json!({ "prefix": "/app" }), PAGE);
+        let expected = PAGE
+            .replace("href=/css/site.css", r#"href="/app/css/site.css""#)
+            .replace(r#""/login""#, r#""/app/login""#)
+            .replace(r#""/application""#, r#""/app/application""#)
+            .replace("'/search?q=1'", r#""/app/search?q=1""#)
+            .replace(r#""/logo.png""#, r#""/app/logo.png""#);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn adds_base_and_snippets_once() {
+        let cfg = serde_json::json!({
+            "base_href": "/app/",
+            "inject": [
+                { "at": "head_start", "html": "<meta name=a>" },
+                { "at": "head_start", "html": "<meta name=b>" },
+                { "at": "head_end", "html": "<script src=/banner.js></script>" },
+                { "at": "body_start", "html": "<div id=banner>staging</div>" },
+            ],
+        });
+        let out = rewrite(
+            cfg,
+            "<head><base href=/><title>t</title></head><body><p></p></body><body></body>",
+        );
+        assert_eq!(
+            out,
+            "<head><base href=\"/app/\"><meta name=a><meta name=b><base href=/><title>t</title>\
+             <script src=/banner.js></script></head><body><div id=banner>staging</div><p></p></body><body></body>"
+        );
+    }
+
+    /// A body that sends its chunks one frame at a time.
+    struct ChunkedBody(std::vec::IntoIter<Bytes>);
+
+    impl Body for ChunkedBody {
+        type Data = Bytes;
+        type Error = BoxError;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut TaskContext<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+            Poll::Ready(self.0.next().map(|chunk| Ok(Frame::data(chunk))))
+        }
+    }
+
+    fn html_service(encoding: Option<&'static str>) -> JesterService {
+        let inner = JesterService::new(service_fn(move |req: HttpRequest| async move {
+            assert!(!req.headers().contains_key(header::ACCEPT_ENCODING));
+            if req.uri().path() == "/api" {
+                let mut resp = HttpResponse::new(full_body(r#"{"href":"/login"}"#));
+                resp.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/json"),
+                );
+                return Ok(resp);
+            }
+            // A page well past any buffering limit, sent in small chunks.
+            let chunks: Vec<Bytes> = std::iter::once(PAGE.to_string())
+                .chain((0..50_000).map(|i| format!("<a href=\"/p/{i}\">{i}</a>\n")))
+                .map(Bytes::from)
+                .collect();
+            let mut resp = HttpResponse::new(ChunkedBody(chunks.into_iter()).boxed());
+            let headers = resp.headers_mut();
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/html; charset=utf-8"),
+            );
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(1_000_000));
+            headers.insert(header::ETAG, HeaderValue::from_static("\"v1\""));
+            if let Some(encoding) = encoding {
+                headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+            }
+            Ok::<_, anyhow::Error>(resp)
+        }));
+        HtmlRewriteFilter
+            .layer(serde_json::json!({ "prefix": "/app" }))
+            .unwrap()
+            .layer(inner)
+    }
+
+    fn get(path: &str) -> HttpRequest {
+        Request::get(path)
+            .header(header::ACCEPT_ENCODING, "gzip, br")
+            .body(full_body(""))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rewrites_html_responses_as_they_stream() {
+        let service = html_service(None);
+        let page = service.clone().oneshot(get("/page")).await.unwrap();
+        assert_eq!(page.headers()[header::ETAG], "W/\"v1\"");
+        assert!(!page.headers().contains_key(header::CONTENT_LENGTH));
+        let body = page.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.starts_with("<html><head><link rel=stylesheet href=\"/app/css"));
+        assert!(body.ends_with("<a href=\"/app/p/49999\">49999</a>\n"));
+        assert!(!body.contains("\"/p/"));
+
+        let json = service.oneshot(get("/api")).await.unwrap();
+        let body = json.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, r#"{"href":"/login"}"#);
+    }
+
+    #[tokio::test]
+    async fn passes_compressed_pages_through() {
+        let page = html_service(Some("gzip"))
+            .oneshot(get("/page"))
+            .await
+            .unwrap();
+        assert_eq!(page.headers()[header::ETAG], "\"v1\"");
+        let body = page.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.starts_with(PAGE.as_bytes()));
+    }
+
+    #[test]
+    fn rejects_prefixes_that_are_not_paths() {
+        for prefix in ["app", "/app/", "/a b"] {
+            let cfg = serde_json::from_value(serde_json::json!({ "prefix": prefix })).unwrap();
+            assert!(Rewriter::from_config(cfg).is_err(), "{prefix}");
+        }
+        let cfg = serde_json::json!({ "prefix": "/app", "attributes": ["href]"] });
+        assert!(Rewriter::from_config(serde_json::from_value(cfg).unwrap()).is_err());
+        assert!(HtmlRewriteFilter.layer(Value::Null).is_err());
+    }
+}
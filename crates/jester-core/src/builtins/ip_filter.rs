@@ -0,0 +1,582 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, RwLock, Weak},
+    task::{Context as TaskContext, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use http::{Request, StatusCode, Uri};
+use http_body_util::{BodyExt, Limited};
+use serde::Deserialize;
+use serde_json::Value;
+use tower::{layer::layer_fn, Service};
+
+use super::reloadable::Reloadable;
+use crate::{
+    client::{HttpClient, UpstreamClients},
+    config::{units, UpstreamTls},
+    context::ClientIp,
+    plugin::{
+        full_body, text_response, DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService,
+        ResponseFuture,
+    },
+    stats::RuntimeStats,
+};
+
+/// Largest feed body accepted from a URL.
+const MAX_FEED_BYTES: usize = 16 * 1024 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Rejects clients by IP address, from static CIDR lists and refreshed blocklist
+/// feeds.
+///
+/// `deny` and every feed list blocked networks; `allow` exempts networks from all
+/// of them. Feeds hold one address or CIDR per line (`#` and `;` start
+/// comments) and are swapped in atomically. An `http://` or `https://` `url` is fetched every
+/// `refresh_secs` and blocks nothing until its first successful fetch. A local
+/// `file` is read when the filter is built and again whenever it changes, like
+/// other filter state files. A failed refresh keeps the previous list.
+///
+/// Config: `{ deny = ["203.0.113.0/24"], allow = [], status = 403,
+/// feeds = [{ name = "drop", url = "http://...", refresh_secs = 3600 }] }`.
+pub struct IpFilter;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct IpFilterConfig {
+    allow: Vec<String>,
+    deny: Vec<String>,
+    feeds: Vec<FeedConfig>,
+    status: u16,
+}
+
+impl Default for IpFilterConfig {
+    fn default() -> Self {
+        Self {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            feeds: Vec::new(),
+            status: 403,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FeedConfig {
+    /// Label for logs and metrics; defaults to the URL or path.
+    name: Option<String>,
+    url: Option<String>,
+    file: Option<String>,
+    #[serde(
+        default = "default_refresh_secs",
+        deserialize_with = "units::secs",
+        alias = "refresh"
+    )]
+    refresh_secs: u64,
+}
+
+fn default_refresh_secs() -> u64 {
+    300
+}
+
+/// A set of IPv4 and IPv6 networks, bucketed by prefix length so a lookup
+/// costs one hash probe per distinct prefix length.
+#[derive(Debug, Default)]
+pub(crate) struct CidrSet {
+    v4: BTreeMap<u8, HashSet<u32>>,
+    v6: BTreeMap<u8, HashSet<u128>>,
+    len: usize,
+}
+
+impl CidrSet {
+    pub(crate) fn parse<I, S>(entries: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut set = Self::default();
+        for entry in entries {
+            set.insert(entry.as_ref())?;
+        }
+        Ok(set)
+    }
+
+    /// Parses a feed body, returning the set and the number of lines skipped as
+    /// invalid.
+    fn parse_feed(text: &str) -> (Self, usize) {
+        let mut set = Self::default();
+        let mut invalid = 0;
+        for line in text.lines() {
+            let entry = line
+                .split(['#', ';'])
+                .next()
+                .and_then(|entry| entry.split_whitespace().next());
+            if let Some(entry) = entry {
+                if set.insert(entry).is_err() {
+                    invalid += 1;
+                }
+            }
+        }
+        (set, invalid)
+    }
+
+    /// Adds an address (`192.0.2.1`) or network (`192.0.2.0/24`).
+    pub(crate) fn insert(&mut self, entry: &str) -> Result<()> {
+        let (addr, prefix) = match entry.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (entry, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid address `{entry}`"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .with_context(|| format!("invalid prefix length in `{entry}`"))?,
+            None => max,
+        };
+        let inserted = match addr {
+            IpAddr::V4(addr) => self
+                .v4
+                .entry(prefix)
+                .or_default()
+                .insert(u32::from(addr) & mask_v4(prefix)),
+            IpAddr::V6(addr) => self
+                .v6
+                .entry(prefix)
+                .or_default()
+                .insert(u128::from(addr) & mask_v6(prefix)),
+        };
+        self.len += usize::from(inserted);
+        Ok(())
+    }
+
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(addr) => {
+                let bits = u32::from(addr);
+                self.v4
+                    .iter()
+                    .any(|(prefix, networks)| networks.contains(&(bits & mask_v4(*prefix))))
+            }
+            IpAddr::V6(addr) => {
+                let bits = u128::from(addr);
+                self.v6
+                    .iter()
+                    .any(|(prefix, networks)| networks.contains(&(bits & mask_v6(*prefix))))
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+}
+
+fn mask_v4(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0)
+}
+
+fn mask_v6(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0)
+}
+
+async fn fetch(http: &HttpClient, uri: &Uri) -> Result<String> {
+    let fetch = async {
+        let request = Request::get(uri.clone()).body(full_body(Bytes::new()))?;
+        let response = http.request(request).await?;
+        if !response.status().is_success() {
+            bail!("feed returned {}", response.status());
+        }
+        let body = Limited::new(response.into_body(), MAX_FEED_BYTES)
+            .collect()
+            .await
+            .map_err(|err| anyhow::anyhow!(err))?
+            .to_bytes();
+        Ok(String::from_utf8(body.to_vec())?)
+    };
+    tokio::time::timeout(FETCH_TIMEOUT, fetch)
+        .await
+        .context("feed fetch timed out")?
+        .with_context(|| format!("failed to fetch {uri}"))
+}
+
+struct Feed {
+    name: String,
+    entries: FeedEntries,
+}
+
+enum FeedEntries {
+    /// Fetched every `refresh`.
+    Url {
+        http: Box<HttpClient>,
+        uri: Uri,
+        refresh: Duration,
+        entries: RwLock<Arc<CidrSet>>,
+    },
+    /// Watched like the files of other filters.
+    File(Arc<Reloadable<CidrSet>>),
+}
+
+impl TryFrom<FeedConfig> for Feed {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: FeedConfig) -> Result<Self> {
+        if cfg.refresh_secs == 0 {
+            bail!("feed `refresh_secs` must be at least 1");
+        }
+        match (cfg.url, cfg.file) {
+            (Some(url), None) => {
+                let uri: Uri = url
+                    .parse()
+                    .with_context(|| format!("invalid feed url `{url}`"))?;
+                if !matches!(uri.scheme_str(), Some("http" | "https")) {
+                    bail!("feed url `{url}` must be an http:// or https:// URL");
+                }
+                let http =
+                    UpstreamClients::new(RuntimeStats::default()).get(&UpstreamTls::default())?;
+                Ok(Self {
+                    name: cfg.name.unwrap_or_else(|| uri.to_string()),
+                    entries: FeedEntries::Url {
+                        http: Box::new(http),
+                        uri,
+                        refresh: Duration::from_secs(cfg.refresh_secs),
+                        entries: RwLock::default(),
+                    },
+                })
+            }
+            (None, Some(file)) => {
+                let name = cfg.name.unwrap_or_else(|| file.clone());
+                let feed = name.clone();
+                let entries = Reloadable::watch("ip-filter", &file, move |data| {
+                    let (set, invalid) = CidrSet::parse_feed(std::str::from_utf8(data)?);
+                    metrics::gauge!("jester_ip_feed_entries", "feed" => feed.clone())
+                        .set(set.len() as f64);
+                    tracing::debug!(feed, entries = set.len(), invalid, "ip feed read");
+                    Ok(set)
+                })
+                .with_context(|| format!("failed to load feed `{name}`"))?;
+                Ok(Self {
+                    name,
+                    entries: FeedEntries::File(entries),
+                })
+            }
+            _ => bail!("each feed needs exactly one of `url` or `file`"),
+        }
+    }
+}
+
+impl Feed {
+    fn entries(&self) -> Arc<CidrSet> {
+        match &self.entries {
+            FeedEntries::Url { entries, .. } => entries
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clone(),
+            FeedEntries::File(entries) => entries.get(),
+        }
+    }
+
+    /// Fetches a URL feed and swaps in the new list; on error the old one
+    /// stays.
+    async fn refresh(&self) -> Result<()> {
+        let FeedEntries::Url {
+            http, uri, entries, ..
+        } = &self.entries
+        else {
+            return Ok(());
+        };
+        let result = fetch(http, uri).await;
+        let outcome = if result.is_ok() { "success" } else { "error" };
+        metrics::counter!("jester_ip_feed_refreshes_total", "feed" => self.name.clone(), "outcome" => outcome)
+            .increment(1);
+        let (set, invalid) = CidrSet::parse_feed(&result?);
+        let count = set.len();
+        *entries
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(set);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        metrics::gauge!("jester_ip_feed_entries", "feed" => self.name.clone()).set(count as f64);
+        metrics::gauge!("jester_ip_feed_last_success_timestamp_seconds", "feed" => self.name.clone())
+            .set(now.as_secs_f64());
+        tracing::info!(
+            feed = self.name,
+            entries = count,
+            invalid,
+            "ip feed refreshed"
+        );
+        Ok(())
+    }
+}
+
+/// Refreshes a URL `feed` every `refresh` until the filter that owns it is
+/// dropped (e.g. replaced by a config reload).
+async fn refresh_loop(feed: Weak<Feed>, refresh: Duration) {
+    loop {
+        let Some(feed) = feed.upgrade() else {
+            return;
+        };
+        if let Err(err) = feed.refresh().await {
+            tracing::warn!(feed = feed.name, error = %err, "ip feed refresh failed; keeping previous list");
+        }
+        drop(feed);
+        tokio::time::sleep(refresh).await;
+    }
+}
+
+struct Blocklist {
+    allow: CidrSet,
+    deny: CidrSet,
+    feeds: Vec<Arc<Feed>>,
+    status: StatusCode,
+}
+
+impl Blocklist {
+    /// Name of the list that blocks `ip`, if any.
+    fn blocked_by(&self, ip: IpAddr) -> Option<&str> {
+        if self.allow.contains(ip) {
+            return None;
+        }
+        if self.deny.contains(ip) {
+            return Some("deny");
+        }
+        self.feeds
+            .iter()
+            .find(|feed| feed.entries().contains(ip))
+            .map(|feed| feed.name.as_str())
+    }
+}
+
+#[derive(Clone)]
+struct IpFilterService {
+    inner: JesterService,
+    blocklist: Arc<Blocklist>,
+}
+
+impl Service<HttpRequest> for IpFilterService {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HttpRequest) -> Self::Future {
+        let peer = ClientIp::of(&req);
+        if let Some(list) = peer.and_then(|ip| self.blocklist.blocked_by(ip)) {
+            metrics::counter!("jester_ip_filter_rejected_total", "list" => list.to_string())
+                .increment(1);
+            tracing::debug!(peer = ?peer, list, "client blocked by ip-filter");
+            let status = self.blocklist.status;
+            return Box::pin(async move { Ok(text_response(status, "forbidden")) });
+        }
+        self.inner.call(req)
+    }
+}
+
+impl JesterPlugin for IpFilter {
+    fn name(&self) -> &'static str {
+        "ip-filter"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: IpFilterConfig = if cfg.is_null() {
+            IpFilterConfig::default()
+        } else {
+            serde_json::from_value(cfg)?
+        };
+        let feeds = cfg
+            .feeds
+            .into_iter()
+            .map(|feed| Feed::try_from(feed).map(Arc::new))
+            .collect::<Result<Vec<_>>>()?;
+        let urls = feeds.iter().filter_map(|feed| match feed.entries {
+            FeedEntries::Url { refresh, .. } => Some((feed, refresh)),
+            FeedEntries::File(_) => None,
+        });
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            for (feed, refresh) in urls {
+                runtime.spawn(refresh_loop(Arc::downgrade(feed), refresh));
+            }
+        } else if urls.count() > 0 {
+            tracing::warn!("ip-filter built outside a tokio runtime; url feeds will not load");
+        }
+        let blocklist = Arc::new(Blocklist {
+            allow: CidrSet::parse(&cfg.allow).context("invalid `allow` entry")?,
+            deny: CidrSet::parse(&cfg.deny).context("invalid `deny` entry")?,
+            feeds,
+            status: StatusCode::from_u16(cfg.status)?,
+        });
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            JesterService::new(IpFilterService {
+                inner,
+                blocklist: blocklist.clone(),
+            })
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:req"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Request;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+    use crate::plugin::full_body;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_set_matches_networks_and_addresses() {
+        let set =
+            CidrSet::parse(["10.0.0.0/8", "192.0.2.7", "2001:db8::/32", "0.0.0.0/0"]).unwrap();
+        assert!(set.contains(ip("10.1.2.3")));
+        assert!(set.contains(ip("::ffff:10.1.2.3")), "mapped IPv4 matches");
+        assert!(set.contains(ip("2001:db8:1::1")));
+        assert!(!set.contains(ip("2001:db9::1")));
+
+        let set = CidrSet::parse(["192.0.2.7"]).unwrap();
+        assert!(!set.contains(ip("192.0.2.8")));
+        assert!(CidrSet::parse(["10.0.0.0/33"]).is_err());
+        assert!(CidrSet::parse(["example.com"]).is_err());
+    }
+
+    #[test]
+    fn feed_parsing_skips_comments_and_invalid_lines() {
+        let (set, invalid) = CidrSet::parse_feed(
+            "; Spamhaus DROP\n1.10.16.0/20 ; SBL256894\n# comment\n\n203.0.113.9\nnot-an-ip\n",
+        );
+        assert_eq!(set.len(), 2);
+        assert_eq!(invalid, 1);
+        assert!(set.contains(ip("1.10.20.1")));
+    }
+
+    #[tokio::test]
+    async fn file_feeds_are_read_when_built() {
+        let path = std::env::temp_dir().join(format!("jester-ip-feed-{}", std::process::id()));
+        std::fs::write(&path, "198.51.100.0/24\nnot-an-ip\n").unwrap();
+        let config = || FeedConfig {
+            name: Some("test".into()),
+            url: None,
+            file: Some(path.to_string_lossy().into_owned()),
+            refresh_secs: 60,
+        };
+        let feed = Feed::try_from(config()).unwrap();
+        assert!(feed.entries().contains(ip("198.51.100.1")));
+        assert_eq!(feed.entries().len(), 1);
+        assert!(matches!(feed.entries, FeedEntries::File(_)));
+
+        std::fs::remove_file(&path).unwrap();
+        let err = Feed::try_from(config()).err().unwrap();
+        assert!(
+            format!("{err:#}").contains("failed to load feed `test`"),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn url_feed_refresh_swaps_entries() {
+        let body = Arc::new(RwLock::new(Some("198.51.100.0/24\n")));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = body.clone();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await;
+                let response = match *served.read().unwrap() {
+                    Some(body) => format!(
+                        "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                        body.len()
+                    ),
+                    None => {
+                        "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                            .into()
+                    }
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        let feed = Feed::try_from(FeedConfig {
+            name: Some("test".into()),
+            url: Some(format!("http://{addr}/drop.txt")),
+            file: None,
+            refresh_secs: 60,
+        })
+        .unwrap();
+        assert!(!feed.entries().contains(ip("198.51.100.1")));
+
+        feed.refresh().await.unwrap();
+        assert!(feed.entries().contains(ip("198.51.100.1")));
+
+        *body.write().unwrap() = Some("203.0.113.0/24\n");
+        feed.refresh().await.unwrap();
+        assert!(!feed.entries().contains(ip("198.51.100.1")));
+        assert!(feed.entries().contains(ip("203.0.113.1")));
+
+        *body.write().unwrap() = None;
+        assert!(feed.refresh().await.is_err());
+        assert!(
+            feed.entries().contains(ip("203.0.113.1")),
+            "failed refresh keeps the old list"
+        );
+
+        let feed = |url: &str| {
+            Feed::try_from(FeedConfig {
+                name: None,
+                url: Some(url.into()),
+                file: None,
+                refresh_secs: 60,
+            })
+        };
+        assert!(feed("https://www.spamhaus.org/drop/drop.txt").is_ok());
+        assert!(feed("ftp://example.com/drop.txt").is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_denied_clients_unless_allowed() {
+        let layer = IpFilter
+            .layer(serde_json::json!({ "deny": ["192.0.2.0/24"], "allow": ["192.0.2.10"] }))
+            .unwrap();
+        let service = layer.layer(JesterService::new(service_fn(|_req: HttpRequest| async {
+            Ok::<_, anyhow::Error>(HttpResponse::new(full_body("ok")))
+        })));
+        let request = |peer: &str| {
+            let mut req = Request::new(full_body(""));
+            req.extensions_mut().insert(ClientIp(ip(peer)));
+            req
+        };
+
+        let blocked = service.clone().oneshot(request("192.0.2.1")).await.unwrap();
+        assert_eq!(blocked.status(), StatusCode::FORBIDDEN);
+        let allowed = service
+            .clone()
+            .oneshot(request("192.0.2.10"))
+            .await
+            .unwrap();
+        assert_eq!(allowed.status(), StatusCode::OK);
+        let other = service.oneshot(request("198.51.100.1")).await.unwrap();
+        assert_eq!(other.status(), StatusCode::OK);
+    }
+}
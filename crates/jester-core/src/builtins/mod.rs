@@ -0,0 +1,158 @@
+//! Tier A filters compiled into the core crate and addressable as `type = "builtin"`.
+
+mod aws_sigv4;
+mod cache;
+mod circuit_breaker;
+mod coalesce;
+mod compression;
+mod csp_nonce;
+mod header_policy;
+mod headers;
+mod html;
+mod html_rewrite;
+mod ip_filter;
+mod openapi;
+mod query_policy;
+mod reloadable;
+mod retry_after;
+mod signed_url;
+mod timeout;
+mod xml_guard;
+
+pub use aws_sigv4::AwsSigV4Filter;
+pub use cache::CacheFilter;
+pub use circuit_breaker::CircuitBreakerFilter;
+pub use coalesce::CoalesceFilter;
+pub use compression::CompressionFilter;
+pub use csp_nonce::CspNonceFilter;
+pub use header_policy::HeaderPolicyFilter;
+pub use headers::HeadersFilter;
+pub use html_rewrite::HtmlRewriteFilter;
+pub(crate) use ip_filter::CidrSet;
+pub use ip_filter::IpFilter;
+pub use openapi::OpenApiFilter;
+pub use query_policy::QueryPolicyFilter;
+pub use retry_after::RetryAfterFilter;
+pub use signed_url::{sign_url, SignedUrlFilter};
+pub(crate) use timeout::TimeoutConfig;
+pub use timeout::TimeoutFilter;
+pub use xml_guard::XmlGuardFilter;
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::{Bytes, BytesMut};
+use http::{header, HeaderMap, HeaderValue};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame};
+use tower::ServiceExt;
+
+use crate::plugin::{
+    BoxError, HttpRequest, JesterPlugin, JesterService, ProxyBody, ResponseFuture,
+};
+
+/// Returns every builtin filter shipped with this crate.
+pub fn all() -> Vec<Arc<dyn JesterPlugin>> {
+    vec![
+        Arc::new(TimeoutFilter),
+        Arc::new(HeadersFilter),
+        Arc::new(RetryAfterFilter),
+        Arc::new(CircuitBreakerFilter),
+        Arc::new(HeaderPolicyFilter),
+        Arc::new(IpFilter),
+        Arc::new(CompressionFilter),
+        Arc::new(QueryPolicyFilter),
+        Arc::new(CoalesceFilter),
+        Arc::new(CacheFilter),
+        Arc::new(OpenApiFilter),
+        Arc::new(XmlGuardFilter),
+        Arc::new(CspNonceFilter),
+        Arc::new(HtmlRewriteFilter),
+        Arc::new(SignedUrlFilter),
+        Arc::new(AwsSigV4Filter),
+    ]
+}
+
+fn builtin_version() -> semver::Version {
+    semver::Version::parse(crate::version()).expect("crate version is valid semver")
+}
+
+/// Calls `inner` from inside a filter's own future.
+pub(crate) fn forward(inner: JesterService, req: HttpRequest) -> ResponseFuture {
+    Box::pin(inner.oneshot(req))
+}
+
+/// Buffers a request body; `None` once it grows past `limit` bytes.
+pub(crate) async fn read_body(
+    mut body: ProxyBody,
+    limit: usize,
+) -> Result<Option<Bytes>, BoxError> {
+    let mut buffered = BytesMut::new();
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame?.into_data() {
+            if buffered.len() + data.len() > limit {
+                return Ok(None);
+            }
+            buffered.extend_from_slice(&data);
+        }
+    }
+    Ok(Some(buffered.freeze()))
+}
+
+/// Buffers a response body up to `limit` bytes; past that, hands back a body
+/// that replays what was read and streams the rest.
+async fn buffer(mut body: ProxyBody, limit: usize) -> Result<Result<Bytes, ProxyBody>, BoxError> {
+    let mut buffered = BytesMut::new();
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame?.into_data() {
+            buffered.extend_from_slice(&data);
+            if buffered.len() > limit {
+                let prefix = Some(buffered.freeze());
+                return Ok(Err(Replay { prefix, rest: body }.boxed()));
+            }
+        }
+    }
+    Ok(Ok(buffered.freeze()))
+}
+
+struct Replay {
+    prefix: Option<Bytes>,
+    rest: ProxyBody,
+}
+
+impl Body for Replay {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        if let Some(prefix) = self.prefix.take() {
+            return Poll::Ready(Some(Ok(Frame::data(prefix))));
+        }
+        Pin::new(&mut self.rest).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.prefix.is_none() && self.rest.is_end_stream()
+    }
+}
+
+/// Marks a strong validator weak, since the compressed bytes differ.
+fn weaken_etag(headers: &mut HeaderMap) {
+    let Some(etag) = headers.get(header::ETAG) else {
+        return;
+    };
+    if etag.as_bytes().starts_with(b"W/") {
+        return;
+    }
+    let mut weak = b"W/".to_vec();
+    weak.extend_from_slice(etag.as_bytes());
+    if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+        headers.insert(header::ETAG, weak);
+    }
+}
@@ -0,0 +1,1377 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use http::{header, HeaderValue, Method, Request, StatusCode};
+use hyper::body::Body;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tower::{layer::layer_fn, Service};
+
+use super::{query_policy::percent_decode, reloadable::Reloadable};
+use crate::{
+    config::units,
+    plugin::{
+        full_body, DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService, ResponseFuture,
+    },
+};
+
+/// Deepest schema nesting followed; also stops `$ref` cycles.
+const MAX_DEPTH: usize = 64;
+/// Violations listed in one problem document.
+const MAX_VIOLATIONS: usize = 20;
+const METHODS: [&str; 8] = [
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+/// Schema keywords that are checked against requests.
+const ASSERTIONS: [&str; 25] = [
+    "type",
+    "nullable",
+    "enum",
+    "const",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "minimum",
+    "exclusiveMinimum",
+    "maximum",
+    "exclusiveMaximum",
+    "multipleOf",
+    "items",
+    "minItems",
+    "maxItems",
+    "uniqueItems",
+    "properties",
+    "additionalProperties",
+    "required",
+    "minProperties",
+    "maxProperties",
+    "allOf",
+    "anyOf",
+    "oneOf",
+    "not",
+];
+/// Schema keywords that only describe a value, so nothing is left unchecked
+/// by ignoring them.
+const ANNOTATIONS: [&str; 18] = [
+    "title",
+    "description",
+    "default",
+    "example",
+    "examples",
+    "deprecated",
+    "readOnly",
+    "writeOnly",
+    "format",
+    "discriminator",
+    "xml",
+    "externalDocs",
+    "contentMediaType",
+    "contentEncoding",
+    "$comment",
+    "$schema",
+    "$id",
+    "$anchor",
+];
+
+/// Validates requests against an OpenAPI 3 document before they reach the
+/// upstream.
+///
+/// `spec` is a JSON OpenAPI 3.x document describing the API below
+/// `base_path`. A request must match one of its paths (else `404`, unless
+/// `allow_unknown_paths`) and an operation on it (else `405`). Its path,
+/// query, header, and cookie parameters and a JSON body must satisfy their
+/// schemas, or the answer is `400` with an `application/problem+json`
+/// document (RFC 9457) listing each violation. Bodies are buffered, up to
+/// `max_body_bytes` (`413` beyond), only when the operation has a JSON schema
+/// for them; a body whose media type the operation does not accept gets
+/// `415`. Only local `$ref`s are followed. `pattern` uses the `regex` crate's
+/// syntax, and `format` is taken as an annotation. A document whose schemas
+/// use other keywords, or patterns that do not compile, fails to load.
+///
+/// Config: `{ spec = "api/openapi.json", base_path = "/v1",
+/// max_body_bytes = 1048576, allow_unknown_paths = false }`.
+pub struct OpenApiFilter;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OpenApiConfig {
+    spec: String,
+    #[serde(default)]
+    base_path: String,
+    #[serde(
+        default = "default_max_body_bytes",
+        deserialize_with = "units::bytes",
+        alias = "max_body_size"
+    )]
+    max_body_bytes: usize,
+    #[serde(default)]
+    allow_unknown_paths: bool,
+}
+
+fn default_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+/// A loaded document, compiled for request matching.
+struct Api {
+    document: Value,
+    /// Every schema `pattern` in the document, compiled.
+    patterns: HashMap<String, Regex>,
+    base_path: String,
+    /// Paths with more literal segments first, so `/users/me` beats
+    /// `/users/{id}`.
+    paths: Vec<PathItem>,
+    max_body_bytes: usize,
+    allow_unknown_paths: bool,
+}
+
+struct PathItem {
+    segments: Vec<Segment>,
+    operations: Vec<(Method, Operation)>,
+}
+
+enum Segment {
+    Literal(String),
+    /// `{name}`, possibly with literal text around it as in `{id}.json`.
+    Param {
+        prefix: String,
+        name: String,
+        suffix: String,
+    },
+}
+
+struct Operation {
+    parameters: Vec<Parameter>,
+    body: Option<RequestBody>,
+}
+
+struct Parameter {
+    name: String,
+    location: Location,
+    required: bool,
+    schema: Value,
+    /// Query arrays arrive as repeated keys rather than comma-separated.
+    explode: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Location {
+    Path,
+    Query,
+    Header,
+    Cookie,
+}
+
+impl Location {
+    fn as_str(self) -> &'static str {
+        match self {
+            Location::Path => "path",
+            Location::Query => "query",
+            Location::Header => "header",
+            Location::Cookie => "cookie",
+        }
+    }
+}
+
+struct RequestBody {
+    required: bool,
+    /// Lowercased media ranges from `content` with their schemas.
+    content: Vec<(String, Option<Arc<Value>>)>,
+}
+
+/// Why a request was refused; also the `reason` metric label.
+#[derive(Debug)]
+enum Rejection {
+    UnknownPath,
+    MethodNotAllowed(Vec<Method>),
+    Invalid(Vec<Violation>),
+    TooLarge,
+    UnsupportedMediaType,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Violation {
+    /// `query.limit`, `header.x-request-id`, or a pointer into the body such
+    /// as `body/items/0/sku`.
+    location: String,
+    message: String,
+}
+
+/// What is left to check once the head of a request has passed.
+struct Pending {
+    violations: Vec<Violation>,
+    /// Schema for a JSON body that still has to be read.
+    body: Option<Arc<Value>>,
+}
+
+impl Api {
+    /// Watches `cfg.spec`, so an edited document replaces the served one
+    /// without a restart.
+    fn watch(cfg: OpenApiConfig) -> Result<Arc<Reloadable<Self>>> {
+        let spec = cfg.spec.clone();
+        Reloadable::watch("openapi", &spec, move |text| {
+            let document = serde_json::from_slice(text)
+                .with_context(|| format!("OpenAPI document `{}` is not valid JSON", cfg.spec))?;
+            Self::new(document, cfg.clone())
+        })
+        .with_context(|| format!("failed to load OpenAPI document `{spec}`"))
+    }
+
+    fn new(document: Value, cfg: OpenApiConfig) -> Result<Self> {
+        match document.get("openapi").and_then(Value::as_str) {
+            Some(version) if version.starts_with("3.") => {}
+            _ => bail!("`{}` is not an OpenAPI 3 document", cfg.spec),
+        }
+        check_refs(&document, &document, 0)?;
+        let mut paths = Vec::new();
+        let mut schemas = SchemaCheck::new(&document);
+        for (template, item) in document
+            .get("paths")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+        {
+            let item = PathItem::new(&document, template, item)
+                .and_then(|item| {
+                    item.check_schemas(&mut schemas)?;
+                    Ok(item)
+                })
+                .with_context(|| format!("invalid OpenAPI path `{template}`"))?;
+            paths.push(item);
+        }
+        paths.sort_by_key(|item| std::cmp::Reverse(item.literals()));
+        let patterns = schemas.patterns;
+        Ok(Self {
+            document,
+            patterns,
+            base_path: cfg.base_path.trim_end_matches('/').to_string(),
+            paths,
+            max_body_bytes: cfg.max_body_bytes,
+            allow_unknown_paths: cfg.allow_unknown_paths,
+        })
+    }
+
+    /// Checks everything but the body. `Ok(None)` lets an unknown path
+    /// through untouched.
+    fn check<B: Body>(&self, req: &Request<B>) -> Result<Option<Pending>, Rejection> {
+        let unknown = || match self.allow_unknown_paths {
+            true => Ok(None),
+            false => Err(Rejection::UnknownPath),
+        };
+        let path = req.uri().path();
+        let Some(path) = path
+            .strip_prefix(self.base_path.as_str())
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        else {
+            return unknown();
+        };
+        let segments: Vec<&str> = path.strip_prefix('/').unwrap_or(path).split('/').collect();
+        let Some((item, path_params)) = self
+            .paths
+            .iter()
+            .find_map(|item| Some((item, item.matches(&segments)?)))
+        else {
+            return unknown();
+        };
+        let Some(operation) = item
+            .operations
+            .iter()
+            .find(|(method, _)| method == req.method())
+            .map(|(_, operation)| operation)
+        else {
+            let methods = item.operations.iter().map(|(method, _)| method.clone());
+            return Err(Rejection::MethodNotAllowed(methods.collect()));
+        };
+
+        let mut validator = Validator::new(self);
+        operation.check_parameters(req, &path_params, &mut validator);
+        let body = match &operation.body {
+            Some(body) => body.check(req, &mut validator)?,
+            None => None,
+        };
+        Ok(Some(Pending {
+            violations: validator.violations,
+            body,
+        }))
+    }
+}
+
+/// Fails on `$ref`s that do not resolve, so a broken document is caught at
+/// load time.
+fn check_refs(document: &Value, value: &Value, depth: usize) -> Result<()> {
+    if depth > MAX_DEPTH {
+        bail!("OpenAPI document is nested too deeply");
+    }
+    match value {
+        Value::Object(map) => {
+            if map.get("$ref").is_some_and(Value::is_string) {
+                resolve(document, value)?;
+            }
+            map.values()
+                .try_for_each(|value| check_refs(document, value, depth + 1))
+        }
+        Value::Array(items) => items
+            .iter()
+            .try_for_each(|value| check_refs(document, value, depth + 1)),
+        _ => Ok(()),
+    }
+}
+
+/// Follows `$ref`s to the value they point at.
+fn resolve<'a>(document: &'a Value, mut value: &'a Value) -> Result<&'a Value> {
+    for _ in 0..MAX_DEPTH {
+        let Some(reference) = value.get("$ref").and_then(Value::as_str) else {
+            return Ok(value);
+        };
+        value = reference
+            .strip_prefix('#')
+            .and_then(|pointer| document.pointer(pointer))
+            .with_context(|| {
+                format!("cannot resolve `$ref` `{reference}`; only local references are supported")
+            })?;
+    }
+    bail!("`$ref` chain is too long or cyclic")
+}
+
+/// Walks the schemas a document validates requests with: compiles their
+/// `pattern`s and fails on keywords that would otherwise be ignored.
+struct SchemaCheck<'a> {
+    document: &'a Value,
+    patterns: HashMap<String, Regex>,
+    /// `$ref`s already walked, which also ends recursive schemas.
+    seen: HashSet<String>,
+}
+
+impl<'a> SchemaCheck<'a> {
+    fn new(document: &'a Value) -> Self {
+        Self {
+            document,
+            patterns: HashMap::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    fn visit(&mut self, schema: &Value, depth: usize) -> Result<()> {
+        if depth > MAX_DEPTH {
+            bail!("schema is nested too deeply");
+        }
+        let map = match schema {
+            Value::Object(map) => map,
+            Value::Bool(_) => return Ok(()),
+            _ => bail!("schemas must be objects or booleans"),
+        };
+        let described = |keyword: &str| keyword.starts_with("x-") || ANNOTATIONS.contains(&keyword);
+        if let Some(reference) = map.get("$ref").and_then(Value::as_str) {
+            if let Some(keyword) = map.keys().find(|key| *key != "$ref" && !described(key)) {
+                bail!("schema keyword `{keyword}` next to `$ref` is not supported");
+            }
+            if !self.seen.insert(reference.to_string()) {
+                return Ok(());
+            }
+            let target = resolve(self.document, schema)?;
+            return self
+                .visit(target, depth + 1)
+                .with_context(|| format!("in `{reference}`"));
+        }
+        for (keyword, value) in map {
+            match keyword.as_str() {
+                "pattern" => {
+                    let pattern = value.as_str().context("`pattern` must be a string")?;
+                    if !self.patterns.contains_key(pattern) {
+                        let regex = Regex::new(pattern)
+                            .with_context(|| format!("unsupported `pattern` {pattern:?}"))?;
+                        self.patterns.insert(pattern.to_string(), regex);
+                    }
+                }
+                "properties" => {
+                    for property in value.as_object().into_iter().flat_map(|map| map.values()) {
+                        self.visit(property, depth + 1)?;
+                    }
+                }
+                "items" | "additionalProperties" | "not" => self.visit(value, depth + 1)?,
+                "allOf" | "anyOf" | "oneOf" => {
+                    for subschema in value.as_array().into_iter().flatten() {
+                        self.visit(subschema, depth + 1)?;
+                    }
+                }
+                keyword if ASSERTIONS.contains(&keyword) || described(keyword) => {}
+                keyword => bail!("schema keyword `{keyword}` is not supported"),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PathItem {
+    fn new(document: &Value, template: &str, item: &Value) -> Result<Self> {
+        let item = resolve(document, item)?;
+        let segments = template
+            .strip_prefix('/')
+            .context("paths must start with `/`")?
+            .split('/')
+            .map(Segment::parse)
+            .collect();
+        let shared = item.get("parameters");
+        let mut operations = Vec::new();
+        for name in METHODS {
+            let Some(operation) = item.get(name) else {
+                continue;
+            };
+            let method = Method::from_bytes(name.to_ascii_uppercase().as_bytes())?;
+            let operation = Operation::new(document, shared, operation)
+                .with_context(|| format!("invalid `{name}` operation"))?;
+            operations.push((method, operation));
+        }
+        Ok(Self {
+            segments,
+            operations,
+        })
+    }
+
+    /// Runs every operation's schemas through [`SchemaCheck`].
+    fn check_schemas(&self, schemas: &mut SchemaCheck<'_>) -> Result<()> {
+        for (method, operation) in &self.operations {
+            let parameters = operation.parameters.iter().map(|parameter| {
+                let location = parameter.location.as_str();
+                (
+                    format!("{location} parameter `{}`", parameter.name),
+                    &parameter.schema,
+                )
+            });
+            let bodies = operation
+                .body
+                .iter()
+                .flat_map(|body| &body.content)
+                .filter_map(|(media, schema)| {
+                    Some((format!("`{media}` body"), &**schema.as_ref()?))
+                });
+            for (what, schema) in parameters.chain(bodies) {
+                schemas
+                    .visit(schema, 0)
+                    .with_context(|| format!("invalid schema for the {method} {what}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn literals(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|segment| matches!(segment, Segment::Literal(_)))
+            .count()
+    }
+
+    /// The raw path parameters when `segments` fit this item.
+    fn matches<'a>(&self, segments: &[&'a str]) -> Option<Vec<(&str, &'a str)>> {
+        if segments.len() != self.segments.len() {
+            return None;
+        }
+        let mut params = Vec::new();
+        for (segment, pattern) in segments.iter().zip(&self.segments) {
+            match pattern {
+                Segment::Literal(literal) => {
+                    if literal != segment {
+                        return None;
+                    }
+                }
+                Segment::Param {
+                    prefix,
+                    name,
+                    suffix,
+                } => {
+                    let value = segment
+                        .strip_prefix(prefix.as_str())?
+                        .strip_suffix(suffix.as_str())
+                        .filter(|value| !value.is_empty())?;
+                    params.push((name.as_str(), value));
+                }
+            }
+        }
+        Some(params)
+    }
+}
+
+impl Segment {
+    fn parse(segment: &str) -> Self {
+        let param = segment.find('{').and_then(|open| {
+            let close = open + segment[open..].find('}')?;
+            Some(Segment::Param {
+                prefix: segment[..open].to_string(),
+                name: segment[open + 1..close].to_string(),
+                suffix: segment[close + 1..].to_string(),
+            })
+        });
+        param.unwrap_or_else(|| Segment::Literal(segment.to_string()))
+    }
+}
+
+impl Operation {
+    fn new(document: &Value, shared: Option<&Value>, operation: &Value) -> Result<Self> {
+        let mut parameters: Vec<Parameter> = Vec::new();
+        let declared = [shared, operation.get("parameters")]
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_array)
+            .flatten();
+        for parameter in declared {
+            let Some(parameter) = Parameter::new(document, parameter)? else {
+                continue;
+            };
+            // Operation parameters override path-level ones of the same name.
+            parameters.retain(|existing| {
+                existing.name != parameter.name || existing.location != parameter.location
+            });
+            parameters.push(parameter);
+        }
+        let body = operation
+            .get("requestBody")
+            .map(|body| RequestBody::new(document, body))
+            .transpose()?;
+        Ok(Self { parameters, body })
+    }
+
+    fn check_parameters<B>(
+        &self,
+        req: &Request<B>,
+        path_params: &[(&str, &str)],
+        validator: &mut Validator<'_>,
+    ) {
+        let query: Vec<(Cow<'_, str>, Cow<'_, str>)> = req
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (key, value) = param.split_once('=').unwrap_or((param, ""));
+                (percent_decode(key, true), percent_decode(value, true))
+            })
+            .collect();
+        let cookies: Vec<(&str, &str)> = req
+            .headers()
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .collect();
+        for parameter in &self.parameters {
+            let name = parameter.name.as_str();
+            let raw: Vec<Cow<'_, str>> = match parameter.location {
+                Location::Path => path_params
+                    .iter()
+                    .filter(|(param, _)| *param == name)
+                    .map(|(_, value)| percent_decode(value, false))
+                    .collect(),
+                Location::Query => query
+                    .iter()
+                    .filter(|(key, _)| key == name)
+                    .map(|(_, value)| value.clone())
+                    .collect(),
+                Location::Header => req
+                    .headers()
+                    .get_all(name)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .map(Cow::Borrowed)
+                    .collect(),
+                Location::Cookie => cookies
+                    .iter()
+                    .filter(|(cookie, _)| *cookie == name)
+                    .map(|(_, value)| Cow::Borrowed(*value))
+                    .collect(),
+            };
+            let location = format!("{}.{name}", parameter.location.as_str());
+            if raw.is_empty() {
+                if parameter.required {
+                    validator.fail(&location, "is required");
+                }
+                continue;
+            }
+            let value = parameter.coerce(&raw, validator.document);
+            validator.check(&parameter.schema, &value, &location, 0);
+        }
+    }
+}
+
+impl Parameter {
+    /// `None` for header parameters OpenAPI says to ignore.
+    fn new(document: &Value, parameter: &Value) -> Result<Option<Self>> {
+        let parameter = resolve(document, parameter)?;
+        let name = parameter
+            .get("name")
+            .and_then(Value::as_str)
+            .context("parameter without a `name`")?;
+        let location = match parameter.get("in").and_then(Value::as_str) {
+            Some("path") => Location::Path,
+            Some("query") => Location::Query,
+            Some("header") => Location::Header,
+            Some("cookie") => Location::Cookie,
+            other => bail!("parameter `{name}` has an unknown location {other:?}"),
+        };
+        let name = match location {
+            Location::Header => {
+                let name = name.to_ascii_lowercase();
+                if ["accept", "content-type", "authorization"].contains(&name.as_str()) {
+                    return Ok(None);
+                }
+                name
+            }
+            _ => name.to_string(),
+        };
+        let schema = parameter
+            .get("schema")
+            .or_else(|| {
+                parameter
+                    .get("content")?
+                    .as_object()?
+                    .values()
+                    .next()?
+                    .get("schema")
+            })
+            .cloned()
+            .unwrap_or(Value::Bool(true));
+        Ok(Some(Self {
+            name,
+            location,
+            required: location == Location::Path
+                || parameter
+                    .get("required")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false),
+            schema,
+            explode: parameter
+                .get("explode")
+                .and_then(Value::as_bool)
+                .unwrap_or(location == Location::Query),
+        }))
+    }
+
+    /// Turns raw strings into the JSON the schema describes. Values that do
+    /// not convert stay strings and fail the schema's `type`.
+    fn coerce(&self, raw: &[Cow<'_, str>], document: &Value) -> Value {
+        let schema = resolve(document, &self.schema).unwrap_or(&Value::Null);
+        if primary_type(schema) != Some("array") {
+            return coerce_scalar(&raw[0], schema, document);
+        }
+        let items = schema
+            .get("items")
+            .and_then(|items| resolve(document, items).ok())
+            .unwrap_or(&Value::Null);
+        let values: Vec<&str> = match self.location == Location::Query && self.explode {
+            true => raw.iter().map(|value| value.as_ref()).collect(),
+            false => raw[0].split(',').collect(),
+        };
+        Value::Array(
+            values
+                .into_iter()
+                .map(|value| coerce_scalar(value, items, document))
+                .collect(),
+        )
+    }
+}
+
+fn coerce_scalar(raw: &str, schema: &Value, document: &Value) -> Value {
+    let schema = resolve(document, schema).unwrap_or(&Value::Null);
+    let converted = match primary_type(schema) {
+        Some("integer") => raw.parse::<i64>().ok().map(Value::from),
+        Some("number") => raw
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number),
+        Some("boolean") => raw.parse::<bool>().ok().map(Value::Bool),
+        _ => None,
+    };
+    converted.unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+/// The schema's `type`, skipping `null` in a list of types.
+fn primary_type(schema: &Value) -> Option<&str> {
+    match schema.get("type")? {
+        Value::String(kind) => Some(kind),
+        Value::Array(kinds) => kinds
+            .iter()
+            .filter_map(Value::as_str)
+            .find(|kind| *kind != "null"),
+        _ => None,
+    }
+}
+
+impl RequestBody {
+    fn new(document: &Value, body: &Value) -> Result<Self> {
+        let body = resolve(document, body)?;
+        let content = body
+            .get("content")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+            .map(|(media, content)| {
+                let schema = resolve(document, content)
+                    .ok()
+                    .and_then(|content| content.get("schema"))
+                    .cloned()
+                    .map(Arc::new);
+                (media.to_ascii_lowercase(), schema)
+            })
+            .collect();
+        Ok(Self {
+            required: body
+                .get("required")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            content,
+        })
+    }
+
+    /// Returns the schema of a JSON body that still has to be read.
+    fn check<B: Body>(
+        &self,
+        req: &Request<B>,
+        validator: &mut Validator<'_>,
+    ) -> Result<Option<Arc<Value>>, Rejection> {
+        let empty = req.body().is_end_stream() || req.body().size_hint().exact() == Some(0);
+        if empty {
+            if self.required {
+                validator.fail("body", "is required");
+            }
+            return Ok(None);
+        }
+        let media_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| {
+                let essence = value.split(';').next().unwrap_or_default();
+                essence.trim().to_ascii_lowercase()
+            });
+        let accepts = |range: &str| match (range, &media_type) {
+            ("*/*", _) => true,
+            (range, Some(media_type)) => {
+                range == media_type
+                    || range
+                        .strip_suffix('*')
+                        .is_some_and(|kind| kind.ends_with('/') && media_type.starts_with(kind))
+            }
+            (_, None) => false,
+        };
+        let (range, schema) = self
+            .content
+            .iter()
+            .find(|(range, _)| Some(range) == media_type.as_ref())
+            .or_else(|| self.content.iter().find(|(range, _)| accepts(range)))
+            .ok_or(Rejection::UnsupportedMediaType)?;
+        let json = media_type.as_deref().is_some_and(|media_type| {
+            media_type == "application/json" || media_type.ends_with("+json")
+        }) || range == "application/json";
+        Ok(schema.clone().filter(|_| json))
+    }
+}
+
+/// Collects the ways a value fails a schema.
+struct Validator<'a> {
+    document: &'a Value,
+    patterns: &'a HashMap<String, Regex>,
+    violations: Vec<Violation>,
+}
+
+impl<'a> Validator<'a> {
+    fn new(api: &'a Api) -> Self {
+        Self {
+            document: &api.document,
+            patterns: &api.patterns,
+            violations: Vec::new(),
+        }
+    }
+
+    fn fail(&mut self, location: &str, message: impl Into<String>) {
+        if self.violations.len() < MAX_VIOLATIONS {
+            self.violations.push(Violation {
+                location: location.to_string(),
+                message: message.into(),
+            });
+        }
+    }
+
+    fn passes(&self, schema: &Value, value: &Value, depth: usize) -> bool {
+        let mut probe = Validator {
+            document: self.document,
+            patterns: self.patterns,
+            violations: Vec::new(),
+        };
+        probe.check(schema, value, "", depth);
+        probe.violations.is_empty()
+    }
+
+    fn check(&mut self, schema: &Value, value: &Value, location: &str, depth: usize) {
+        if depth > MAX_DEPTH || self.violations.len() >= MAX_VIOLATIONS {
+            return;
+        }
+        let Ok(schema) = resolve(self.document, schema) else {
+            return;
+        };
+        let Some(schema) = schema.as_object() else {
+            if *schema == Value::Bool(false) {
+                self.fail(location, "is not allowed");
+            }
+            return;
+        };
+        if value.is_null() && schema.get("nullable") == Some(&Value::Bool(true)) {
+            return;
+        }
+        let types: Vec<&str> = match schema.get("type") {
+            Some(Value::String(kind)) => vec![kind],
+            Some(Value::Array(kinds)) => kinds.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|kind| has_type(value, kind)) {
+            self.fail(location, format!("must be {}", types.join(" or ")));
+            return;
+        }
+        if let Some(Value::Array(allowed)) = schema.get("enum") {
+            if !allowed.contains(value) {
+                self.fail(
+                    location,
+                    format!("must be one of {}", Value::from(allowed.clone())),
+                );
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if expected != value {
+                self.fail(location, format!("must be {expected}"));
+            }
+        }
+        let number = |key: &str| schema.get(key).and_then(Value::as_f64);
+        let count = |key: &str| schema.get(key).and_then(Value::as_u64);
+        match value {
+            Value::String(text) => {
+                let len = text.chars().count() as u64;
+                if let Some(min) = count("minLength").filter(|min| len < *min) {
+                    self.fail(location, format!("must be at least {min} characters"));
+                }
+                if let Some(max) = count("maxLength").filter(|max| len > *max) {
+                    self.fail(location, format!("must be at most {max} characters"));
+                }
+                if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                    if self
+                        .patterns
+                        .get(pattern)
+                        .is_some_and(|regex| !regex.is_match(text))
+                    {
+                        self.fail(location, format!("must match `{pattern}`"));
+                    }
+                }
+            }
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or_default();
+                // OpenAPI 3.0 flags the bounds as exclusive; 3.1 gives the bound.
+                let flag = |key: &str| schema.get(key) == Some(&Value::Bool(true));
+                if let Some(min) = number("minimum") {
+                    if n < min || (flag("exclusiveMinimum") && n == min) {
+                        self.fail(location, format!("must be at least {min}"));
+                    }
+                }
+                if let Some(min) = number("exclusiveMinimum").filter(|min| n <= *min) {
+                    self.fail(location, format!("must be greater than {min}"));
+                }
+                if let Some(max) = number("maximum") {
+                    if n > max || (flag("exclusiveMaximum") && n == max) {
+                        self.fail(location, format!("must be at most {max}"));
+                    }
+                }
+                if let Some(max) = number("exclusiveMaximum").filter(|max| n >= *max) {
+                    self.fail(location, format!("must be less than {max}"));
+                }
+                if let Some(step) = number("multipleOf").filter(|step| *step > 0.0) {
+                    let ratio = n / step;
+                    if (ratio - ratio.round()).abs() > 1e-9 {
+                        self.fail(location, format!("must be a multiple of {step}"));
+                    }
+                }
+            }
+            Value::Array(items) => {
+                let len = items.len() as u64;
+                if let Some(min) = count("minItems").filter(|min| len < *min) {
+                    self.fail(location, format!("must have at least {min} items"));
+                }
+                if let Some(max) = count("maxItems").filter(|max| len > *max) {
+                    self.fail(location, format!("must have at most {max} items"));
+                }
+                if schema.get("uniqueItems") == Some(&Value::Bool(true))
+                    && (1..items.len()).any(|i| items[..i].contains(&items[i]))
+                {
+                    self.fail(location, "must not contain duplicate items");
+                }
+                if let Some(item_schema) = schema.get("items") {
+                    for (index, item) in items.iter().enumerate() {
+                        self.check(item_schema, item, &format!("{location}/{index}"), depth + 1);
+                    }
+                }
+            }
+            Value::Object(map) => {
+                let len = map.len() as u64;
+                if let Some(min) = count("minProperties").filter(|min| len < *min) {
+                    self.fail(location, format!("must have at least {min} properties"));
+                }
+                if let Some(max) = count("maxProperties").filter(|max| len > *max) {
+                    self.fail(location, format!("must have at most {max} properties"));
+                }
+                for name in schema
+                    .get("required")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_str)
+                {
+                    if !map.contains_key(name) {
+                        self.fail(&format!("{location}/{name}"), "is required");
+                    }
+                }
+                let properties = schema.get("properties").and_then(Value::as_object);
+                for (name, property) in map {
+                    let location = format!("{location}/{name}");
+                    match properties.and_then(|properties| properties.get(name)) {
+                        Some(property_schema) => {
+                            self.check(property_schema, property, &location, depth + 1)
+                        }
+                        None => {
+                            if let Some(additional) = schema.get("additionalProperties") {
+                                self.check(additional, property, &location, depth + 1);
+                            }
+                        }
+                    }
+                }
+            }
+            Value::Null | Value::Bool(_) => {}
+        }
+        let subschemas = |key: &str| {
+            schema
+                .get(key)
+                .and_then(Value::as_array)
+                .cloned()
+                .unwrap_or_default()
+        };
+        for subschema in subschemas("allOf") {
+            self.check(&subschema, value, location, depth + 1);
+        }
+        let any_of = subschemas("anyOf");
+        if !any_of.is_empty() && !any_of.iter().any(|s| self.passes(s, value, depth + 1)) {
+            self.fail(location, "must match a schema in anyOf");
+        }
+        let one_of = subschemas("oneOf");
+        if !one_of.is_empty()
+            && one_of
+                .iter()
+                .filter(|s| self.passes(s, value, depth + 1))
+                .count()
+                != 1
+        {
+            self.fail(location, "must match exactly one schema in oneOf");
+        }
+        if let Some(not) = schema.get("not") {
+            if self.passes(not, value, depth + 1) {
+                self.fail(location, "must not match the schema in not");
+            }
+        }
+    }
+}
+
+fn has_type(value: &Value, kind: &str) -> bool {
+    match kind {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        _ => true,
+    }
+}
+
+impl Rejection {
+    fn reason(&self) -> &'static str {
+        match self {
+            Rejection::UnknownPath => "unknown_path",
+            Rejection::MethodNotAllowed(_) => "method_not_allowed",
+            Rejection::Invalid(_) => "invalid",
+            Rejection::TooLarge => "too_large",
+            Rejection::UnsupportedMediaType => "unsupported_media_type",
+        }
+    }
+
+    fn response(&self) -> HttpResponse {
+        let (status, detail) = match self {
+            Rejection::UnknownPath => (StatusCode::NOT_FOUND, "path is not part of the API"),
+            Rejection::MethodNotAllowed(_) => (
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method is not allowed on this path",
+            ),
+            Rejection::Invalid(_) => (
+                StatusCode::BAD_REQUEST,
+                "request does not match the API description",
+            ),
+            Rejection::TooLarge => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "request body is too large to validate",
+            ),
+            Rejection::UnsupportedMediaType => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "request body media type is not accepted here",
+            ),
+        };
+        let mut problem = json!({
+            "type": "about:blank",
+            "title": status.canonical_reason().unwrap_or_default(),
+            "status": status.as_u16(),
+            "detail": detail,
+        });
+        if let Rejection::Invalid(violations) = self {
+            problem["errors"] = violations
+                .iter()
+                .map(|violation| {
+                    json!({ "location": violation.location, "message": violation.message })
+                })
+                .collect();
+        }
+        let mut response = HttpResponse::new(full_body(problem.to_string()));
+        *response.status_mut() = status;
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        if let Rejection::MethodNotAllowed(methods) = self {
+            let allow: Vec<&str> = methods.iter().map(Method::as_str).collect();
+            if let Ok(allow) = HeaderValue::from_str(&allow.join(", ")) {
+                response.headers_mut().insert(header::ALLOW, allow);
+            }
+        }
+        response
+    }
+}
+
+fn reject(rejection: Rejection) -> HttpResponse {
+    metrics::counter!("jester_openapi_rejected_total", "reason" => rejection.reason()).increment(1);
+    rejection.response()
+}
+
+#[derive(Clone)]
+struct OpenApiService {
+    inner: JesterService,
+    api: Arc<Reloadable<Api>>,
+}
+
+impl Service<HttpRequest> for OpenApiService {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HttpRequest) -> Self::Future {
+        let api = self.api.get();
+        let pending = match api.check(&req) {
+            Ok(Some(pending)) => pending,
+            Ok(None) => return self.inner.call(req),
+            Err(rejection) => return Box::pin(async move { Ok(reject(rejection)) }),
+        };
+        let Some(schema) = pending.body else {
+            if pending.violations.is_empty() {
+                return self.inner.call(req);
+            }
+            let rejection = Rejection::Invalid(pending.violations);
+            return Box::pin(async move { Ok(reject(rejection)) });
+        };
+        let inner = self.inner.clone();
+        let mut violations = pending.violations;
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let body = match super::read_body(body, api.max_body_bytes).await {
+                Ok(Some(body)) => body,
+                Ok(None) => return Ok(reject(Rejection::TooLarge)),
+                Err(err) => return Err(anyhow!(err).context("failed to read request body")),
+            };
+            let mut validator = Validator::new(&api);
+            match serde_json::from_slice::<Value>(&body) {
+                Ok(value) => validator.check(&schema, &value, "body", 0),
+                Err(err) => validator.fail("body", format!("is not valid JSON: {err}")),
+            }
+            violations.extend(validator.violations);
+            violations.truncate(MAX_VIOLATIONS);
+            if !violations.is_empty() {
+                return Ok(reject(Rejection::Invalid(violations)));
+            }
+            super::forward(inner, Request::from_parts(parts, full_body(body))).await
+        })
+    }
+}
+
+impl JesterPlugin for OpenApiFilter {
+    fn name(&self) -> &'static str {
+        "openapi"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: OpenApiConfig =
+            serde_json::from_value(cfg).context("openapi filter needs a `spec` path")?;
+        let api = Api::watch(cfg)?;
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            JesterService::new(OpenApiService {
+                inner,
+                api: api.clone(),
+            })
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:req"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+    use crate::plugin::text_response;
+
+    fn api() -> Api {
+        let document = json!({
+            "openapi": "3.0.3",
+            "paths": {
+                "/users/{id}": {
+                    "parameters": [{ "name": "id", "in": "path", "required": true,
+                                     "schema": { "type": "integer", "minimum": 1 } }],
+                    "get": {
+                        "parameters": [
+                            { "name": "fields", "in": "query",
+                              "schema": { "type": "array", "items": { "enum": ["name", "email"] } } },
+                            { "name": "x-tenant", "in": "header", "required": true,
+                              "schema": { "type": "string", "minLength": 3 } }
+                        ]
+                    }
+                },
+                "/users/me": { "get": {} },
+                "/orders": {
+                    "post": {
+                        "requestBody": { "$ref": "#/components/requestBodies/Order" }
+                    }
+                }
+            },
+            "components": {
+                "requestBodies": {
+                    "Order": {
+                        "required": true,
+                        "content": { "application/json": {
+                            "schema": { "$ref": "#/components/schemas/Order" } } }
+                    }
+                },
+                "schemas": {
+                    "Order": {
+                        "type": "object",
+                        "required": ["sku", "quantity"],
+                        "additionalProperties": false,
+                        "properties": {
+                            "sku": { "type": "string", "pattern": "^[a-z]+-\\d+$" },
+                            "quantity": { "type": "integer", "minimum": 1, "maximum": 10 },
+                            "note": { "type": "string", "nullable": true }
+                        }
+                    }
+                }
+            }
+        });
+        Api::new(
+            document,
+            OpenApiConfig {
+                spec: "inline".into(),
+                base_path: "/v1/".into(),
+                max_body_bytes: 64,
+                allow_unknown_paths: false,
+            },
+        )
+        .unwrap()
+    }
+
+    fn service() -> OpenApiService {
+        OpenApiService {
+            inner: JesterService::new(service_fn(|_req: HttpRequest| async move {
+                Ok::<_, anyhow::Error>(text_response(StatusCode::OK, "forwarded"))
+            })),
+            api: Reloadable::fixed(api()),
+        }
+    }
+
+    async fn send(request: http::request::Builder, body: &'static str) -> (StatusCode, Value) {
+        let resp = service()
+            .oneshot(request.body(full_body(body)).unwrap())
+            .await
+            .unwrap();
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn locations(problem: &Value) -> Vec<&str> {
+        problem["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| error["location"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn loading_rejects_dangling_refs() {
+        let document = json!({ "openapi": "3.1.0", "paths": {
+            "/a": { "get": { "parameters": [{ "$ref": "#/components/parameters/missing" }] } }
+        }});
+        let cfg = OpenApiConfig {
+            spec: "inline".into(),
+            base_path: String::new(),
+            max_body_bytes: 64,
+            allow_unknown_paths: false,
+        };
+        let err = Api::new(document, cfg).err().unwrap();
+        assert!(format!("{err:#}").contains("cannot resolve"));
+    }
+
+    #[test]
+    fn loading_rejects_schemas_that_would_go_unchecked() {
+        let load = |schema: Value| {
+            let document = json!({ "openapi": "3.1.0",
+                "paths": { "/a": { "get": { "parameters": [
+                    { "name": "q", "in": "query", "schema": { "$ref": "#/components/schemas/Q" } }
+                ] } } },
+                "components": { "schemas": { "Q": schema } }
+            });
+            let cfg = OpenApiConfig {
+                spec: "inline".into(),
+                base_path: String::new(),
+                max_body_bytes: 64,
+                allow_unknown_paths: false,
+            };
+            Api::new(document, cfg)
+                .map(|_| ())
+                .map_err(|err| format!("{err:#}"))
+        };
+        let err = load(json!({ "type": "object", "properties": {
+            "a": { "if": { "type": "string" }, "then": { "minLength": 1 } } } }))
+        .unwrap_err();
+        assert!(
+            err.contains("schema keyword `if` is not supported"),
+            "{err}"
+        );
+        assert!(err.contains("GET query parameter `q`"), "{err}");
+        let err = load(json!({ "type": "string", "pattern": "^(?!admin)" })).unwrap_err();
+        assert!(err.contains("unsupported `pattern`"), "{err}");
+        // Annotations, extensions, and recursive schemas are fine.
+        load(
+            json!({ "type": "object", "format": "tree", "x-owner": "search",
+            "properties": { "children": { "type": "array",
+                "items": { "$ref": "#/components/schemas/Q" } } } }),
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn parameters_are_coerced_and_checked() {
+        let get = |uri: &str| Request::get(uri).header("x-tenant", "acme");
+        assert_eq!(
+            send(get("/v1/users/7?fields=name&fields=email"), "")
+                .await
+                .0,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(Request::get("/v1/users/me"), "").await.0,
+            StatusCode::OK
+        );
+
+        let (status, problem) = send(
+            Request::get("/v1/users/0?fields=phone").header("x-tenant", "a"),
+            "",
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            locations(&problem),
+            ["path.id", "query.fields/0", "header.x-tenant"]
+        );
+        let (_, problem) = send(Request::get("/v1/users/abc"), "").await;
+        assert_eq!(problem["errors"][0]["message"], "must be integer");
+        assert_eq!(locations(&problem), ["path.id", "header.x-tenant"]);
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_and_methods_get_problem_details() {
+        let (status, problem) = send(Request::get("/v1/nowhere"), "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(problem["status"], 404);
+        assert_eq!(
+            send(Request::get("/users/me"), "").await.0,
+            StatusCode::NOT_FOUND
+        );
+
+        let resp = service()
+            .oneshot(Request::delete("/v1/orders").body(full_body("")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()[header::ALLOW], "POST");
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+    }
+
+    #[tokio::test]
+    async fn json_bodies_are_validated_against_their_schema() {
+        let post = || Request::post("/v1/orders").header(header::CONTENT_TYPE, "application/json");
+        let (status, _) = send(post(), r#"{"sku":"a-1","quantity":2,"note":null}"#).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, problem) = send(post(), r#"{"quantity":11,"gift":true}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            locations(&problem),
+            ["body/sku", "body/gift", "body/quantity"]
+        );
+        let (_, problem) = send(post(), r#"{"sku":"A1","quantity":2}"#).await;
+        assert_eq!(problem["errors"][0]["location"], "body/sku");
+        assert_eq!(
+            problem["errors"][0]["message"],
+            r"must match `^[a-z]+-\d+$`"
+        );
+
+        let (_, problem) = send(post(), "{").await;
+        assert!(problem["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("is not valid JSON"));
+        let (_, problem) = send(post(), "").await;
+        assert_eq!(problem["errors"][0]["location"], "body");
+
+        let text = Request::post("/v1/orders").header(header::CONTENT_TYPE, "text/plain");
+        assert_eq!(
+            send(text, "sku").await.0,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        let large = r#"{"sku":"a-1","quantity":2,"note":"................................................"}"#;
+        assert_eq!(send(post(), large).await.0, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
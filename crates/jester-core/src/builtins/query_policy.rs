@@ -0,0 +1,312 @@
+use std::{borrow::Cow, sync::Arc};
+
+use anyhow::Result;
+use http::{uri::PathAndQuery, StatusCode, Uri};
+use serde::Deserialize;
+use serde_json::Value;
+use tower::{layer::layer_fn, ServiceExt};
+
+use crate::plugin::{
+    text_response, DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService, ResponseFuture,
+};
+
+/// Limits and normalizes request query strings before they reach the upstream.
+///
+/// Queries longer than `max_length` bytes are answered with `414`, and ones with
+/// more than `max_params` parameters with `400`. `duplicates` decides what happens
+/// to repeated keys: `keep` (default) leaves them, `first`/`last` keep one
+/// occurrence, and `reject` answers `400`. `sort` orders parameters by key
+/// (stably, so repeated keys keep their relative order), which makes
+/// equivalent URLs identical for caches. Keys are compared percent-decoded;
+/// values are forwarded exactly as received, and empty `&&` segments are dropped
+/// whenever the query is rewritten.
+///
+/// Config: `{ max_length = 2048, max_params = 64, duplicates = "last", sort = true }`.
+pub struct QueryPolicyFilter;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct QueryPolicyConfig {
+    max_length: Option<usize>,
+    max_params: Option<usize>,
+    duplicates: Duplicates,
+    sort: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Duplicates {
+    #[default]
+    Keep,
+    First,
+    Last,
+    Reject,
+}
+
+/// Why a query was refused; also the `reason` metric label.
+#[derive(Debug, PartialEq, Eq)]
+enum Rejection {
+    TooLong,
+    TooManyParams,
+    Duplicate,
+}
+
+impl Rejection {
+    fn reason(&self) -> &'static str {
+        match self {
+            Rejection::TooLong => "too_long",
+            Rejection::TooManyParams => "too_many_params",
+            Rejection::Duplicate => "duplicate",
+        }
+    }
+
+    fn response(&self) -> HttpResponse {
+        match self {
+            Rejection::TooLong => text_response(StatusCode::URI_TOO_LONG, "query string too long"),
+            Rejection::TooManyParams => {
+                text_response(StatusCode::BAD_REQUEST, "too many query parameters")
+            }
+            Rejection::Duplicate => {
+                text_response(StatusCode::BAD_REQUEST, "duplicate query parameter")
+            }
+        }
+    }
+}
+
+struct QueryPolicy {
+    max_length: Option<usize>,
+    max_params: Option<usize>,
+    duplicates: Duplicates,
+    sort: bool,
+}
+
+impl QueryPolicy {
+    fn rewrites(&self) -> bool {
+        self.sort || matches!(self.duplicates, Duplicates::First | Duplicates::Last)
+    }
+
+    /// Checks `query` and returns its normalized form when it should change.
+    fn apply(&self, query: &str) -> Result<Option<String>, Rejection> {
+        if self.max_length.is_some_and(|max| query.len() > max) {
+            return Err(Rejection::TooLong);
+        }
+        let mut params = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| (decoded_key(param), param))
+            .collect::<Vec<_>>();
+        if self.max_params.is_some_and(|max| params.len() > max) {
+            return Err(Rejection::TooManyParams);
+        }
+        let repeated = |params: &[(Cow<'_, str>, &str)], index: usize| {
+            params[..index]
+                .iter()
+                .any(|(key, _)| *key == params[index].0)
+        };
+        match self.duplicates {
+            Duplicates::Keep => {}
+            Duplicates::Reject => {
+                if (0..params.len()).any(|index| repeated(&params, index)) {
+                    return Err(Rejection::Duplicate);
+                }
+            }
+            Duplicates::First => {
+                let keep = (0..params.len())
+                    .map(|index| !repeated(&params, index))
+                    .collect::<Vec<_>>();
+                let mut keep = keep.into_iter();
+                params.retain(|_| keep.next().unwrap_or(true));
+            }
+            Duplicates::Last => {
+                params.reverse();
+                let keep = (0..params.len())
+                    .map(|index| !repeated(&params, index))
+                    .collect::<Vec<_>>();
+                let mut keep = keep.into_iter();
+                params.retain(|_| keep.next().unwrap_or(true));
+                params.reverse();
+            }
+        }
+        if !self.rewrites() {
+            return Ok(None);
+        }
+        if self.sort {
+            params.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
+        let normalized = params
+            .iter()
+            .map(|(_, param)| *param)
+            .collect::<Vec<_>>()
+            .join("&");
+        Ok((normalized != query).then_some(normalized))
+    }
+}
+
+/// The parameter's key, percent-decoded with `+` as space, for comparisons.
+fn decoded_key(param: &str) -> Cow<'_, str> {
+    percent_decode(param.split_once('=').map_or(param, |(key, _)| key), true)
+}
+
+/// Percent-decodes a URI component; query components read `+` as space.
+pub(super) fn percent_decode(component: &str, plus_as_space: bool) -> Cow<'_, str> {
+    if !(component.contains('%') || plus_as_space && component.contains('+')) {
+        return Cow::Borrowed(component);
+    }
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let byte = match bytes[index] {
+            b'+' if plus_as_space => b' ',
+            b'%' => {
+                let hex = component
+                    .get(index + 1..index + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                if let Some(byte) = hex {
+                    index += 2;
+                    byte
+                } else {
+                    b'%'
+                }
+            }
+            byte => byte,
+        };
+        decoded.push(byte);
+        index += 1;
+    }
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+pub(super) fn with_query(uri: &Uri, query: &str) -> Option<Uri> {
+    let path_and_query = match query {
+        "" => PathAndQuery::try_from(uri.path()),
+        query => PathAndQuery::try_from(format!("{}?{query}", uri.path())),
+    }
+    .ok()?;
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query);
+    Uri::from_parts(parts).ok()
+}
+
+impl JesterPlugin for QueryPolicyFilter {
+    fn name(&self) -> &'static str {
+        "query-policy"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: QueryPolicyConfig = if cfg.is_null() {
+            QueryPolicyConfig::default()
+        } else {
+            serde_json::from_value(cfg)?
+        };
+        let policy = Arc::new(QueryPolicy {
+            max_length: cfg.max_length,
+            max_params: cfg.max_params,
+            duplicates: cfg.duplicates,
+            sort: cfg.sort,
+        });
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            let policy = policy.clone();
+            JesterService::new(tower::service_fn(move |mut req: HttpRequest| {
+                let inner = inner.clone();
+                let outcome = req.uri().query().map(|query| policy.apply(query));
+                let response: ResponseFuture = match outcome {
+                    Some(Err(rejection)) => {
+                        metrics::counter!("jester_query_rejected_total", "reason" => rejection.reason())
+                            .increment(1);
+                        Box::pin(async move { Ok(rejection.response()) })
+                    }
+                    Some(Ok(Some(normalized))) => {
+                        if let Some(uri) = with_query(req.uri(), &normalized) {
+                            *req.uri_mut() = uri;
+                        }
+                        Box::pin(inner.oneshot(req))
+                    }
+                    Some(Ok(None)) | None => Box::pin(inner.oneshot(req)),
+                };
+                response
+            }))
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:req"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Request;
+    use tower::service_fn;
+
+    use super::*;
+    use crate::plugin::full_body;
+
+    fn policy(duplicates: Duplicates, sort: bool) -> QueryPolicy {
+        QueryPolicy {
+            max_length: Some(64),
+            max_params: Some(4),
+            duplicates,
+            sort,
+        }
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let policy = policy(Duplicates::Reject, false);
+        assert_eq!(policy.apply(&"a".repeat(65)), Err(Rejection::TooLong));
+        assert_eq!(
+            policy.apply("a=1&b=2&c=3&d=4&e=5"),
+            Err(Rejection::TooManyParams)
+        );
+        assert_eq!(policy.apply("a=1&%61=2"), Err(Rejection::Duplicate));
+        assert_eq!(policy.apply("a=1&b=1"), Ok(None));
+    }
+
+    #[test]
+    fn normalizes_duplicates_and_order() {
+        assert_eq!(
+            policy(Duplicates::First, false).apply("b=1&a=2&b=3"),
+            Ok(Some("b=1&a=2".into()))
+        );
+        assert_eq!(
+            policy(Duplicates::Last, false).apply("b=1&a=2&b=3"),
+            Ok(Some("a=2&b=3".into()))
+        );
+        assert_eq!(
+            policy(Duplicates::Keep, true).apply("z=1&a=2&&z=0&m"),
+            Ok(Some("a=2&m&z=1&z=0".into()))
+        );
+        assert_eq!(policy(Duplicates::Keep, true).apply("a=1&b=2"), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn rewrites_the_forwarded_uri() {
+        let inner = JesterService::new(service_fn(|req: HttpRequest| async move {
+            Ok::<_, anyhow::Error>(text_response(StatusCode::OK, req.uri().to_string()))
+        }));
+        let service = QueryPolicyFilter
+            .layer(serde_json::json!({ "sort": true, "duplicates": "first", "max_length": 32 }))
+            .unwrap()
+            .layer(inner);
+
+        let req = Request::get("/search?q=b&lang=en&q=a")
+            .body(full_body(""))
+            .unwrap();
+        let resp = service.clone().oneshot(req).await.unwrap();
+        let body = http_body_util::BodyExt::collect(resp.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        assert_eq!(body, "/search?lang=en&q=b");
+
+        let long = format!("/search?q={}", "x".repeat(40));
+        let req = Request::get(long).body(full_body("")).unwrap();
+        let resp = service.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::URI_TOO_LONG);
+    }
+}
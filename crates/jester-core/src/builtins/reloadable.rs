@@ -0,0 +1,190 @@
+//! Filter state read from files (OpenAPI documents, credentials, dictionaries,
+//! blocklists) and swapped in whenever the file changes, so updating one needs
+//! no restart or config reload.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+
+/// How often watched files are checked for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+type Parse<T> = Box<dyn Fn(&[u8]) -> Result<T> + Send + Sync>;
+
+/// A value a filter reads on every request, kept current with the file it is
+/// parsed from.
+pub(crate) struct Reloadable<T> {
+    current: RwLock<Arc<T>>,
+    source: Option<Source<T>>,
+}
+
+struct Source<T> {
+    filter: &'static str,
+    path: PathBuf,
+    parse: Parse<T>,
+    /// Modification time and size of the last version read.
+    stamp: RwLock<Option<(SystemTime, u64)>>,
+}
+
+impl<T: Send + Sync + 'static> Reloadable<T> {
+    /// A value that never changes.
+    pub(crate) fn fixed(value: T) -> Arc<Self> {
+        Arc::new(Self {
+            current: RwLock::new(Arc::new(value)),
+            source: None,
+        })
+    }
+
+    /// Parses `path` now, failing the filter's build if that fails, then
+    /// parses it again whenever its modification time or size changes, for
+    /// as long as the filter holds the returned value. A version that fails
+    /// to parse is logged and counted, and the previous one stays.
+    pub(crate) fn watch(
+        filter: &'static str,
+        path: impl Into<PathBuf>,
+        parse: impl Fn(&[u8]) -> Result<T> + Send + Sync + 'static,
+    ) -> Result<Arc<Self>> {
+        let path = path.into();
+        let stamp = stamp(&std::fs::metadata(&path).ok());
+        let data =
+            std::fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        let value = parse(&data)?;
+        let reloadable = Arc::new(Self {
+            current: RwLock::new(Arc::new(value)),
+            source: Some(Source {
+                filter,
+                path,
+                parse: Box::new(parse),
+                stamp: RwLock::new(stamp),
+            }),
+        });
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(watch_loop(Arc::downgrade(&reloadable)));
+        } else {
+            tracing::warn!(
+                filter,
+                "built outside a tokio runtime; state files will not reload"
+            );
+        }
+        Ok(reloadable)
+    }
+
+    pub(crate) fn get(&self) -> Arc<T> {
+        self.current
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Reads the file again if it changed since the last check.
+    async fn check(&self) {
+        let Some(source) = &self.source else {
+            return;
+        };
+        let stamp = stamp(&tokio::fs::metadata(&source.path).await.ok());
+        {
+            let mut last = source
+                .stamp
+                .write()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            // A missing file is left alone until it reappears.
+            if stamp.is_none() || *last == stamp {
+                return;
+            }
+            // Remembered even if parsing fails, so a broken file is reported
+            // once rather than on every check.
+            *last = stamp;
+        }
+        let file = source.path.display().to_string();
+        let result = match tokio::fs::read(&source.path).await {
+            Ok(data) => (source.parse)(&data),
+            Err(err) => Err(err.into()),
+        };
+        let outcome = if result.is_ok() { "success" } else { "error" };
+        metrics::counter!(
+            "jester_filter_file_reloads_total",
+            "filter" => source.filter,
+            "file" => file.clone(),
+            "outcome" => outcome
+        )
+        .increment(1);
+        match result {
+            Ok(value) => {
+                *self
+                    .current
+                    .write()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(value);
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                metrics::gauge!(
+                    "jester_filter_file_last_reload_timestamp_seconds",
+                    "filter" => source.filter,
+                    "file" => file.clone()
+                )
+                .set(now.as_secs_f64());
+                tracing::info!(filter = source.filter, file, "filter state file reloaded");
+            }
+            Err(err) => tracing::warn!(
+                filter = source.filter,
+                file,
+                error = format!("{err:#}"),
+                "filter state file reload failed; keeping the previous version"
+            ),
+        }
+    }
+}
+
+fn stamp(metadata: &Option<std::fs::Metadata>) -> Option<(SystemTime, u64)> {
+    let metadata = metadata.as_ref()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Checks the file until the filter that owns it is dropped (e.g. replaced by
+/// a config reload).
+async fn watch_loop<T: Send + Sync + 'static>(reloadable: Weak<Reloadable<T>>) {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        let Some(reloadable) = reloadable.upgrade() else {
+            return;
+        };
+        reloadable.check().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn changed_files_are_swapped_in_and_broken_ones_skipped() {
+        let dir = std::env::temp_dir().join(format!("jester-reloadable-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("number");
+        std::fs::write(&path, "1").unwrap();
+        let parse = |data: &[u8]| -> Result<u32> { Ok(std::str::from_utf8(data)?.trim().parse()?) };
+        let number = Reloadable::watch("test", &path, parse).unwrap();
+        assert_eq!(*number.get(), 1);
+
+        // A new size is a change even within the clock's resolution.
+        std::fs::write(&path, "22").unwrap();
+        number.check().await;
+        assert_eq!(*number.get(), 22);
+        std::fs::write(&path, "not a number").unwrap();
+        number.check().await;
+        assert_eq!(*number.get(), 22);
+        std::fs::remove_file(&path).unwrap();
+        number.check().await;
+        assert_eq!(*number.get(), 22);
+        std::fs::write(&path, "333").unwrap();
+        number.check().await;
+        assert_eq!(*number.get(), 333);
+
+        assert!(Reloadable::watch("test", dir.join("missing"), parse).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Result;
+use http::{header, HeaderValue, StatusCode};
+use serde::Deserialize;
+use serde_json::Value;
+use tower::{layer::layer_fn, Service};
+
+use crate::{
+    cluster::Gossip,
+    config::units,
+    config::EventKind,
+    context::RequestContext,
+    events::{Event, Events},
+    plugin::{
+        text_response, DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService,
+        ResponseFuture,
+    },
+    router::UpstreamEndpoint,
+    stats::target_key,
+};
+
+/// Honors `Retry-After` from overloaded upstreams.
+///
+/// When an upstream answers with one of `statuses` and a `Retry-After` header, further
+/// requests to that upstream are answered locally with `503` and the remaining delay
+/// until it elapses (capped at `max_secs`). In cluster mode the window is
+/// gossiped, so other instances hold back too, for at most their own
+/// `max_secs`.
+///
+/// Config: `{ statuses = [429, 503], max_secs = 60 }`.
+pub struct RetryAfterFilter;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RetryAfterConfig {
+    statuses: Vec<u16>,
+    #[serde(deserialize_with = "units::secs", alias = "max_delay")]
+    max_secs: u64,
+}
+
+impl Default for RetryAfterConfig {
+    fn default() -> Self {
+        Self {
+            statuses: vec![429, 503],
+            max_secs: 60,
+        }
+    }
+}
+
+struct BackoffState {
+    statuses: Vec<StatusCode>,
+    max_delay: Duration,
+    blocked_until: Mutex<HashMap<String, Instant>>,
+}
+
+impl BackoffState {
+    fn remaining(&self, upstream: &str) -> Option<Duration> {
+        let mut blocked = self.blocked_until.lock().unwrap();
+        let until = *blocked.get(upstream)?;
+        let remaining = until.checked_duration_since(Instant::now());
+        if remaining.is_none() {
+            blocked.remove(upstream);
+        }
+        remaining
+    }
+
+    /// Starts a backoff window if `response` asks for one, sharing it with the
+    /// cluster and publishing an `upstream_backoff` event when the upstream was
+    /// not already backing off.
+    fn observe(
+        &self,
+        upstream: &str,
+        response: &HttpResponse,
+        events: Option<&Events>,
+        route: Option<String>,
+        gossip: Option<&Gossip>,
+    ) {
+        if !self.statuses.contains(&response.status()) {
+            return;
+        }
+        let Some(delay) = response
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(parse_retry_after)
+        else {
+            return;
+        };
+        let delay = delay.min(self.max_delay);
+        tracing::warn!(
+            upstream,
+            delay_secs = delay.as_secs(),
+            "upstream requested backoff"
+        );
+        metrics::counter!("jester_upstream_backoff_total", "upstream" => upstream.to_string())
+            .increment(1);
+        let now = Instant::now();
+        let previous = self
+            .blocked_until
+            .lock()
+            .unwrap()
+            .insert(upstream.to_string(), now + delay);
+        if let Some(gossip) = gossip {
+            gossip.share_backoff(upstream, delay);
+        }
+        let Some(events) = events else {
+            return;
+        };
+        if previous.is_some_and(|until| until > now) {
+            return;
+        }
+        let mut event = Event::new(EventKind::UpstreamBackoff)
+            .upstream(upstream)
+            .detail(format!("retry after {}s", delay.as_secs()));
+        if let Some(route) = route {
+            event = event.route(route);
+        }
+        events.publish(event);
+    }
+}
+
+/// Parses either delta-seconds or an HTTP-date.
+pub(crate) fn parse_retry_after(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[derive(Clone)]
+struct RetryAfterService {
+    inner: JesterService,
+    state: Arc<BackoffState>,
+}
+
+impl Service<HttpRequest> for RetryAfterService {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HttpRequest) -> Self::Future {
+        let upstream = req
+            .extensions()
+            .get::<UpstreamEndpoint>()
+            .map(|endpoint| target_key(&endpoint.uri));
+        let Some(upstream) = upstream else {
+            return self.inner.call(req);
+        };
+        let gossip = req.extensions().get::<Arc<Gossip>>().cloned();
+        let remaining = self
+            .state
+            .remaining(&upstream)
+            .or_else(|| gossip.as_ref()?.backoff(&upstream, self.state.max_delay));
+        if let Some(remaining) = remaining {
+            return Box::pin(async move { Ok(backoff_response(remaining)) });
+        }
+        let state = self.state.clone();
+        let events = req.extensions().get::<Events>().cloned();
+        let route = req
+            .extensions()
+            .get::<RequestContext>()
+            .and_then(RequestContext::route);
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let response = response.await?;
+            state.observe(
+                &upstream,
+                &response,
+                events.as_ref(),
+                route,
+                gossip.as_deref(),
+            );
+            Ok(response)
+        })
+    }
+}
+
+fn backoff_response(remaining: Duration) -> HttpResponse {
+    let mut response = text_response(StatusCode::SERVICE_UNAVAILABLE, "upstream backing off");
+    // Round up so clients never retry before the window closes.
+    let secs = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+    response
+}
+
+impl JesterPlugin for RetryAfterFilter {
+    fn name(&self) -> &'static str {
+        "retry-after"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: RetryAfterConfig = if cfg.is_null() {
+            RetryAfterConfig::default()
+        } else {
+            serde_json::from_value(cfg)?
+        };
+        let statuses = cfg
+            .statuses
+            .iter()
+            .map(|code| StatusCode::from_u16(*code))
+            .collect::<Result<Vec<_>, _>>()?;
+        let state = Arc::new(BackoffState {
+            statuses,
+            max_delay: Duration::from_secs(cfg.max_secs),
+            blocked_until: Mutex::new(HashMap::new()),
+        });
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            JesterService::new(RetryAfterService {
+                inner,
+                state: state.clone(),
+            })
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:req", "filter:resp"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use http::Request;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+    use crate::plugin::full_body;
+
+    fn request() -> HttpRequest {
+        let mut req = Request::new(full_body(""));
+        req.extensions_mut().insert(UpstreamEndpoint {
+            uri: "http://127.0.0.1:8080".parse().unwrap(),
+            keep_alive: true,
+            host_header: Default::default(),
+            tls: Default::default(),
+            pool: Default::default(),
+            protocol: Default::default(),
+            proxy_protocol: None,
+        });
+        req
+    }
+
+    #[tokio::test]
+    async fn blocks_upstream_until_retry_after_elapses() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let inner = JesterService::new(service_fn(move |_req: HttpRequest| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async {
+                let mut resp = text_response(StatusCode::SERVICE_UNAVAILABLE, "busy");
+                resp.headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from_static("30"));
+                Ok::<_, anyhow::Error>(resp)
+            }
+        }));
+        let service = RetryAfterFilter
+            .layer(serde_json::json!({ "max_secs": 10 }))
+            .unwrap()
+            .layer(inner);
+
+        let first = service.clone().oneshot(request()).await.unwrap();
+        assert_eq!(first.headers()[header::RETRY_AFTER], "30");
+
+        let second = service.oneshot(request()).await.unwrap();
+        assert_eq!(second.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(second.headers()[header::RETRY_AFTER], "10");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn parses_http_date_in_the_past_as_zero() {
+        let value = HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT");
+        assert_eq!(parse_retry_after(&value), Some(Duration::ZERO));
+    }
+}
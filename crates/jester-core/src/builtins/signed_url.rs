@@ -0,0 +1,362 @@
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL, Engine};
+use hmac::{Hmac, Mac};
+use http::StatusCode;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+use tower::{layer::layer_fn, Service};
+
+use crate::{
+    context::ClientIp,
+    plugin::{
+        text_response, DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService,
+        ResponseFuture,
+    },
+};
+
+const EXPIRES_PARAM: &str = "expires";
+const SIGNATURE_PARAM: &str = "signature";
+
+/// Serves only requests whose URL carries a valid, unexpired signature, for
+/// download links handed out by an application.
+///
+/// A signed URL ends in `expires=<unix seconds>&signature=<mac>`, where the
+/// MAC is HMAC-SHA256 over the path and remaining query, the expiry, and, with
+/// `bind_client_ip`, the client address (see [`sign_url`] and
+/// `jester sign-url`). Anything else is answered with `403`. The secret comes
+/// from `secret` or from the environment variable named by `secret_env`. Both
+/// parameters are removed before the request goes upstream unless
+/// `strip_params = false`.
+///
+/// Config: `{ secret_env = "DOWNLOAD_URL_SECRET", bind_client_ip = false, strip_params = true }`.
+pub struct SignedUrlFilter;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SignedUrlConfig {
+    secret: Option<String>,
+    secret_env: Option<String>,
+    bind_client_ip: bool,
+    strip_params: bool,
+}
+
+impl Default for SignedUrlConfig {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            secret_env: None,
+            bind_client_ip: false,
+            strip_params: true,
+        }
+    }
+}
+
+/// Signs `path_and_query` so the `signed-url` filter accepts it until
+/// `expires` (Unix seconds), from `client` only if given. Returns the URL with
+/// `expires` and `signature` appended to its query.
+pub fn sign_url(
+    secret: &[u8],
+    path_and_query: &str,
+    expires: u64,
+    client: Option<IpAddr>,
+) -> String {
+    let signature = BASE64URL.encode(
+        mac(secret, path_and_query, expires, client)
+            .finalize()
+            .into_bytes(),
+    );
+    let separator = if path_and_query.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    format!("{path_and_query}{separator}{EXPIRES_PARAM}={expires}&{SIGNATURE_PARAM}={signature}")
+}
+
+fn mac(secret: &[u8], resource: &str, expires: u64, client: Option<IpAddr>) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    let client = client
+        .map(|ip| ip.to_canonical().to_string())
+        .unwrap_or_default();
+    mac.update(format!("{resource}\n{expires}\n{client}").as_bytes());
+    mac
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rejection {
+    Missing,
+    Invalid,
+    Expired,
+}
+
+impl Rejection {
+    fn reason(self) -> &'static str {
+        match self {
+            Rejection::Missing => "missing",
+            Rejection::Invalid => "invalid",
+            Rejection::Expired => "expired",
+        }
+    }
+
+    fn response(self) -> HttpResponse {
+        let body = match self {
+            Rejection::Missing | Rejection::Invalid => "invalid signature",
+            Rejection::Expired => "link expired",
+        };
+        text_response(StatusCode::FORBIDDEN, body)
+    }
+}
+
+struct Verifier {
+    secret: Vec<u8>,
+    bind_client_ip: bool,
+    strip_params: bool,
+}
+
+impl Verifier {
+    fn from_config(cfg: SignedUrlConfig) -> Result<Self> {
+        let secret = match (cfg.secret, cfg.secret_env) {
+            (Some(secret), None) => secret,
+            (None, Some(name)) => std::env::var(&name)
+                .with_context(|| format!("signed-url secret_env `{name}` is not set"))?,
+            _ => bail!("signed-url needs exactly one of `secret` and `secret_env`"),
+        };
+        if secret.len() < 16 {
+            bail!("signed-url secret must be at least 16 bytes");
+        }
+        Ok(Self {
+            secret: secret.into_bytes(),
+            bind_client_ip: cfg.bind_client_ip,
+            strip_params: cfg.strip_params,
+        })
+    }
+
+    /// Checks the request's signature, returning the query with the signing
+    /// parameters removed.
+    fn verify(&self, req: &HttpRequest, now: u64) -> Result<String, Rejection> {
+        let mut expires = None;
+        let mut signature = None;
+        let mut rest = Vec::new();
+        for pair in req.uri().query().unwrap_or_default().split('&') {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let slot = match name {
+                EXPIRES_PARAM => &mut expires,
+                SIGNATURE_PARAM => &mut signature,
+                "" => continue,
+                _ => {
+                    rest.push(pair);
+                    continue;
+                }
+            };
+            if slot.replace(value).is_some() {
+                return Err(Rejection::Invalid);
+            }
+        }
+        let (Some(expires), Some(signature)) = (expires, signature) else {
+            return Err(Rejection::Missing);
+        };
+        let query = rest.join("&");
+        let resource = match query.as_str() {
+            "" => req.uri().path().to_string(),
+            query => format!("{}?{query}", req.uri().path()),
+        };
+        let expires: u64 = expires.parse().map_err(|_| Rejection::Invalid)?;
+        let signature = BASE64URL
+            .decode(signature)
+            .map_err(|_| Rejection::Invalid)?;
+        let client = self.bind_client_ip.then(|| ClientIp::of(req)).flatten();
+        if self.bind_client_ip && client.is_none() {
+            return Err(Rejection::Invalid);
+        }
+        mac(&self.secret, &resource, expires, client)
+            .verify_slice(&signature)
+            .map_err(|_| Rejection::Invalid)?;
+        if now >= expires {
+            return Err(Rejection::Expired);
+        }
+        Ok(query)
+    }
+}
+
+#[derive(Clone)]
+struct SignedUrlService {
+    inner: JesterService,
+    verifier: Arc<Verifier>,
+}
+
+impl Service<HttpRequest> for SignedUrlService {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: HttpRequest) -> Self::Future {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match self.verifier.verify(&req, now) {
+            Ok(query) => {
+                if self.verifier.strip_params {
+                    if let Some(uri) = super::query_policy::with_query(req.uri(), &query) {
+                        *req.uri_mut() = uri;
+                    }
+                }
+                self.inner.call(req)
+            }
+            Err(rejection) => {
+                metrics::counter!("jester_signed_url_rejected_total", "reason" => rejection.reason())
+                    .increment(1);
+                Box::pin(async move { Ok(rejection.response()) })
+            }
+        }
+    }
+}
+
+impl JesterPlugin for SignedUrlFilter {
+    fn name(&self) -> &'static str {
+        "signed-url"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: SignedUrlConfig = if cfg.is_null() {
+            SignedUrlConfig::default()
+        } else {
+            serde_json::from_value(cfg)?
+        };
+        let verifier = Arc::new(Verifier::from_config(cfg)?);
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            JesterService::new(SignedUrlService {
+                inner,
+                verifier: verifier.clone(),
+            })
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:req"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Request;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+    use crate::plugin::full_body;
+
+    const SECRET: &str = "0123456789abcdef";
+
+    fn verifier(bind_client_ip: bool) -> Verifier {
+        Verifier {
+            secret: SECRET.as_bytes().to_vec(),
+            bind_client_ip,
+            strip_params: true,
+        }
+    }
+
+    fn request(uri: &str, client: Option<IpAddr>) -> HttpRequest {
+        let mut req = Request::get(uri).body(full_body("")).unwrap();
+        if let Some(client) = client {
+            req.extensions_mut().insert(ClientIp(client));
+        }
+        req
+    }
+
+    #[test]
+    fn signed_urls_verify_until_they_expire() {
+        let url = sign_url(SECRET.as_bytes(), "/files/report.pdf?v=2", 1000, None);
+        assert!(url.starts_with("/files/report.pdf?v=2&expires=1000&signature="));
+        let verifier = verifier(false);
+        assert_eq!(verifier.verify(&request(&url, None), 999), Ok("v=2".into()));
+        assert_eq!(
+            verifier.verify(&request(&url, None), 1000),
+            Err(Rejection::Expired)
+        );
+
+        let tampered = [
+            url.replace("report", "secret"),
+            url.replace("v=2", "v=3"),
+            url.replace("expires=1000", "expires=9999"),
+        ];
+        for url in tampered {
+            assert_eq!(
+                verifier.verify(&request(&url, None), 999),
+                Err(Rejection::Invalid),
+                "{url}"
+            );
+        }
+        assert_eq!(
+            verifier.verify(&request("/files/report.pdf", None), 999),
+            Err(Rejection::Missing)
+        );
+    }
+
+    #[test]
+    fn bound_urls_only_work_for_their_client() {
+        let alice = IpAddr::from([192, 0, 2, 1]);
+        let url = sign_url(SECRET.as_bytes(), "/files/a", 1000, Some(alice));
+        let verifier = verifier(true);
+        assert!(verifier.verify(&request(&url, Some(alice)), 0).is_ok());
+        let mapped = IpAddr::from(std::net::Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped());
+        assert!(verifier.verify(&request(&url, Some(mapped)), 0).is_ok());
+        let mallory = IpAddr::from([192, 0, 2, 2]);
+        assert_eq!(
+            verifier.verify(&request(&url, Some(mallory)), 0),
+            Err(Rejection::Invalid)
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_valid_requests_without_the_signing_params() {
+        let inner = JesterService::new(service_fn(|req: HttpRequest| async move {
+            Ok::<_, anyhow::Error>(text_response(StatusCode::OK, req.uri().to_string()))
+        }));
+        let service = SignedUrlFilter
+            .layer(serde_json::json!({ "secret": SECRET }))
+            .unwrap()
+            .layer(inner);
+        let expires = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+
+        let url = sign_url(SECRET.as_bytes(), "/files/a", expires, None);
+        let resp = service.clone().oneshot(request(&url, None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = http_body_util::BodyExt::collect(resp.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        assert_eq!(body, "/files/a");
+
+        let resp = service.oneshot(request("/files/a", None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn needs_exactly_one_long_enough_secret() {
+        let layer = |cfg: Value| SignedUrlFilter.layer(cfg);
+        assert!(layer(Value::Null).is_err());
+        assert!(layer(serde_json::json!({ "secret": "short" })).is_err());
+        let both = serde_json::json!({ "secret": SECRET, "secret_env": "HOME" });
+        assert!(layer(both).is_err());
+    }
+}
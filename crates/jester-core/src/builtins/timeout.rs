@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use tower::{layer::layer_fn, timeout::error::Elapsed, timeout::Timeout, ServiceExt};
+
+use crate::{
+    config::units,
+    error::ProxyError,
+    plugin::{BoxError, DynLayer, JesterPlugin, JesterService},
+};
+
+/// Bounds the time spent in the remainder of the chain, including the upstream call.
+///
+/// Expiry surfaces as a `504 Gateway Timeout`; `body` replaces the default response text.
+///
+/// Config: `{ request_secs = <u64>, body = "<text>" }`; `request = "30s"` also works.
+pub struct TimeoutFilter;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TimeoutConfig {
+    #[serde(deserialize_with = "units::secs", alias = "request")]
+    pub(crate) request_secs: u64,
+    body: Option<String>,
+}
+
+impl JesterPlugin for TimeoutFilter {
+    fn name(&self) -> &'static str {
+        "timeout"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let TimeoutConfig { request_secs, body } =
+            serde_json::from_value(cfg).context("invalid timeout config")?;
+        let duration = Duration::from_secs(request_secs);
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            let body = body.clone();
+            JesterService::new(Timeout::new(inner, duration).map_err(move |err: BoxError| {
+                if err.is::<Elapsed>() {
+                    ProxyError::Timeout { body: body.clone() }.into()
+                } else {
+                    anyhow::Error::from_boxed(err)
+                }
+            }))
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:req"]
+    }
+}
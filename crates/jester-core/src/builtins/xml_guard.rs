@@ -0,0 +1,652 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::{anyhow, Result};
+use http::{header, Request, StatusCode};
+use hyper::body::Body;
+use serde::Deserialize;
+use serde_json::Value;
+use tower::{layer::layer_fn, Service};
+
+use crate::{
+    config::units,
+    plugin::{
+        full_body, text_response, DynLayer, HttpRequest, HttpResponse, JesterPlugin, JesterService,
+        ResponseFuture,
+    },
+};
+
+const PREDEFINED_ENTITIES: [&str; 5] = ["lt", "gt", "amp", "apos", "quot"];
+
+/// Rejects malformed or hostile XML request bodies, for routes fronting SOAP
+/// and other XML services.
+///
+/// Bodies with an XML media type (`text/xml`, `application/xml`, or
+/// `*+xml`, which covers SOAP 1.2) are buffered up to `max_bytes` (`413`
+/// beyond) and must be well-formed UTF-8 XML with elements nested at most
+/// `max_depth` deep, or the answer is `400`. Document type declarations are
+/// refused unless `allow_doctype` is set (SOAP forbids them); even then,
+/// external and parameter entities are refused, and expanding the declared
+/// entities may take at most `max_entity_expansions` substitutions, which
+/// stops billion-laughs payloads before anything downstream expands them.
+///
+/// Config: `{ max_bytes = 1048576, max_depth = 64, allow_doctype = false,
+/// max_entity_expansions = 1000 }`.
+pub struct XmlGuardFilter;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct XmlGuardConfig {
+    #[serde(deserialize_with = "units::bytes", alias = "max_size")]
+    max_bytes: usize,
+    max_depth: usize,
+    allow_doctype: bool,
+    max_entity_expansions: u64,
+}
+
+impl Default for XmlGuardConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 1024 * 1024,
+            max_depth: 64,
+            allow_doctype: false,
+            max_entity_expansions: 1000,
+        }
+    }
+}
+
+/// Why a body was refused; [`Violation::reason`] is the metric label.
+#[derive(Debug, PartialEq, Eq)]
+enum Violation {
+    Malformed(String),
+    TooDeep,
+    Doctype,
+    Entity(&'static str),
+}
+
+impl Violation {
+    fn reason(&self) -> &'static str {
+        match self {
+            Violation::Malformed(_) => "malformed",
+            Violation::TooDeep => "too_deep",
+            Violation::Doctype => "doctype",
+            Violation::Entity(_) => "entity",
+        }
+    }
+
+    fn message(&self, limits: &XmlGuardConfig) -> String {
+        match self {
+            Violation::Malformed(detail) => format!("malformed XML: {detail}"),
+            Violation::TooDeep => format!("XML nested deeper than {}", limits.max_depth),
+            Violation::Doctype => "XML document type declarations are not allowed".into(),
+            Violation::Entity(detail) => format!("XML entity rejected: {detail}"),
+        }
+    }
+}
+
+fn malformed(detail: impl Into<String>) -> Violation {
+    Violation::Malformed(detail.into())
+}
+
+/// Checks a whole document.
+fn check(document: &[u8], limits: &XmlGuardConfig) -> Result<(), Violation> {
+    let input = std::str::from_utf8(document).map_err(|_| malformed("body is not UTF-8"))?;
+    Scanner {
+        input,
+        pos: 0,
+        limits,
+        entities: HashMap::new(),
+        costs: HashMap::new(),
+        expansions: 0,
+    }
+    .document()
+}
+
+/// A single forward pass over the document. Elements are tracked on an
+/// explicit stack, so hostile nesting cannot exhaust the thread's stack.
+struct Scanner<'a> {
+    input: &'a str,
+    pos: usize,
+    limits: &'a XmlGuardConfig,
+    /// Internal entities from the DTD: name to replacement text.
+    entities: HashMap<&'a str, &'a str>,
+    /// Substitutions one reference to an entity costs, once computed.
+    costs: HashMap<&'a str, Option<u64>>,
+    expansions: u64,
+}
+
+impl<'a> Scanner<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        let found = self.rest().starts_with(token);
+        if found {
+            self.pos += token.len();
+        }
+        found
+    }
+
+    fn expect(&mut self, token: &str, context: &str) -> Result<(), Violation> {
+        match self.eat(token) {
+            true => Ok(()),
+            false => Err(malformed(format!("expected `{token}` {context}"))),
+        }
+    }
+
+    /// Skips whitespace, returning whether there was any.
+    fn skip_whitespace(&mut self) -> bool {
+        let rest = self.rest();
+        let trimmed = rest.trim_start_matches([' ', '\t', '\r', '\n']);
+        self.pos += rest.len() - trimmed.len();
+        trimmed.len() != rest.len()
+    }
+
+    /// Consumes everything up to and including `end`, returning what came
+    /// before it.
+    fn until(&mut self, end: &str, what: &str) -> Result<&'a str, Violation> {
+        let rest = self.rest();
+        let index = rest
+            .find(end)
+            .ok_or_else(|| malformed(format!("unterminated {what}")))?;
+        self.pos += index + end.len();
+        Ok(&rest[..index])
+    }
+
+    fn name(&mut self) -> Result<&'a str, Violation> {
+        let rest = self.rest();
+        let start = |c: char| c.is_alphabetic() || c == '_' || c == ':' || !c.is_ascii();
+        let len = rest
+            .char_indices()
+            .find(|&(index, c)| {
+                !(start(c) || (index > 0 && (c.is_ascii_digit() || c == '-' || c == '.')))
+            })
+            .map_or(rest.len(), |(index, _)| index);
+        if len == 0 {
+            return Err(malformed("expected a name"));
+        }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    fn quoted(&mut self) -> Result<&'a str, Violation> {
+        let quote = match self.rest().chars().next() {
+            Some(quote @ ('"' | '\'')) => quote,
+            _ => return Err(malformed("expected a quoted value")),
+        };
+        self.pos += 1;
+        self.until(&quote.to_string(), "quoted value")
+    }
+
+    fn document(mut self) -> Result<(), Violation> {
+        self.eat("\u{feff}");
+        let mut stack: Vec<&str> = Vec::new();
+        let mut root_seen = false;
+        let mut doctype_seen = false;
+        while self.pos < self.input.len() {
+            if !stack.is_empty() {
+                self.content(&mut stack)?;
+                continue;
+            }
+            // Prolog and epilog: markup and whitespace only.
+            if self.skip_whitespace() {
+                continue;
+            }
+            if self.eat("<!--") {
+                self.comment()?;
+            } else if self.eat("<?") {
+                self.until("?>", "processing instruction")?;
+            } else if self.rest().starts_with("<!DOCTYPE") {
+                if root_seen || doctype_seen {
+                    return Err(malformed("misplaced document type declaration"));
+                }
+                doctype_seen = true;
+                self.pos += "<!DOCTYPE".len();
+                self.doctype()?;
+            } else if root_seen {
+                return Err(malformed("content after the root element"));
+            } else if self.eat("<") {
+                root_seen = true;
+                self.start_tag(&mut stack)?;
+            } else {
+                return Err(malformed("text outside the root element"));
+            }
+        }
+        if let Some(open) = stack.last() {
+            return Err(malformed(format!("`{open}` is never closed")));
+        }
+        if !root_seen {
+            return Err(malformed("no root element"));
+        }
+        Ok(())
+    }
+
+    /// One piece of content inside an element.
+    fn content(&mut self, stack: &mut Vec<&'a str>) -> Result<(), Violation> {
+        if self.eat("</") {
+            let name = self.name()?;
+            self.skip_whitespace();
+            self.expect(">", "to close an end tag")?;
+            match stack.pop() {
+                Some(open) if open == name => Ok(()),
+                Some(open) => Err(malformed(format!("`</{name}>` closes `{open}`"))),
+                None => Err(malformed(format!("unexpected `</{name}>`"))),
+            }
+        } else if self.eat("<!--") {
+            self.comment()
+        } else if self.eat("<![CDATA[") {
+            self.until("]]>", "CDATA section").map(drop)
+        } else if self.eat("<?") {
+            self.until("?>", "processing instruction").map(drop)
+        } else if self.eat("<") {
+            self.start_tag(stack)
+        } else {
+            let rest = self.rest();
+            let text = &rest[..rest.find('<').unwrap_or(rest.len())];
+            self.pos += text.len();
+            if text.contains("]]>") {
+                return Err(malformed("`]]>` in text"));
+            }
+            self.references(text)
+        }
+    }
+
+    fn comment(&mut self) -> Result<(), Violation> {
+        let comment = self.until("-->", "comment")?;
+        match comment.contains("--") || comment.ends_with('-') {
+            true => Err(malformed("`--` inside a comment")),
+            false => Ok(()),
+        }
+    }
+
+    /// The rest of a start tag, after its `<`.
+    fn start_tag(&mut self, stack: &mut Vec<&'a str>) -> Result<(), Violation> {
+        let name = self.name()?;
+        if stack.len() >= self.limits.max_depth {
+            return Err(Violation::TooDeep);
+        }
+        let mut attributes: Vec<&str> = Vec::new();
+        loop {
+            let spaced = self.skip_whitespace();
+            if self.eat("/>") {
+                return Ok(());
+            }
+            if self.eat(">") {
+                stack.push(name);
+                return Ok(());
+            }
+            if !spaced {
+                return Err(malformed(format!("bad start tag `{name}`")));
+            }
+            let attribute = self.name()?;
+            if attributes.contains(&attribute) {
+                return Err(malformed(format!(
+                    "duplicate attribute `{attribute}` on `{name}`"
+                )));
+            }
+            attributes.push(attribute);
+            self.skip_whitespace();
+            self.expect("=", "after an attribute name")?;
+            self.skip_whitespace();
+            let value = self.quoted()?;
+            if value.contains('<') {
+                return Err(malformed(format!("`<` in attribute `{attribute}`")));
+            }
+            self.references(value)?;
+        }
+    }
+
+    /// Checks the character and entity references in text or an attribute.
+    fn references(&mut self, text: &'a str) -> Result<(), Violation> {
+        let mut rest = text;
+        while let Some(index) = rest.find('&') {
+            let after = &rest[index + 1..];
+            let end = after
+                .find(';')
+                .ok_or_else(|| malformed("unterminated reference"))?;
+            let reference = &after[..end];
+            rest = &after[end + 1..];
+            if let Some(number) = reference.strip_prefix('#') {
+                let code = match number.strip_prefix('x') {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => number.parse().ok(),
+                };
+                if code.and_then(char::from_u32).is_none_or(|c| c == '\0') {
+                    return Err(malformed(format!(
+                        "bad character reference `&{reference};`"
+                    )));
+                }
+            } else if !PREDEFINED_ENTITIES.contains(&reference) {
+                let cost = self.cost(reference, 0)?;
+                self.expansions = self.expansions.saturating_add(cost);
+                if self.expansions > self.limits.max_entity_expansions {
+                    return Err(Violation::Entity("too many entity expansions"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Substitutions a reference to `name` causes, counting the references
+    /// inside its replacement text; saturates rather than expanding anything.
+    fn cost(&mut self, name: &'a str, depth: usize) -> Result<u64, Violation> {
+        match self.costs.get(name) {
+            Some(Some(cost)) => return Ok(*cost),
+            Some(None) => return Err(Violation::Entity("recursive entity")),
+            None => {}
+        }
+        let Some(&text) = self.entities.get(name) else {
+            return Err(malformed(format!("undefined entity `&{name};`")));
+        };
+        if depth > self.limits.max_depth {
+            return Err(Violation::Entity("entities nested too deeply"));
+        }
+        self.costs.insert(name, None);
+        let mut cost: u64 = 1;
+        let mut rest = text;
+        while let Some(index) = rest.find('&') {
+            let after = &rest[index + 1..];
+            let Some(end) = after.find(';') else { break };
+            let reference = &after[..end];
+            rest = &after[end + 1..];
+            if !reference.starts_with('#') && !PREDEFINED_ENTITIES.contains(&reference) {
+                cost = cost.saturating_add(self.cost(reference, depth + 1)?);
+            }
+        }
+        self.costs.insert(name, Some(cost));
+        Ok(cost)
+    }
+
+    /// The rest of a document type declaration, after `<!DOCTYPE`.
+    fn doctype(&mut self) -> Result<(), Violation> {
+        if !self.limits.allow_doctype {
+            return Err(Violation::Doctype);
+        }
+        self.skip_whitespace();
+        self.name()?;
+        self.skip_whitespace();
+        if self.rest().starts_with("SYSTEM") || self.rest().starts_with("PUBLIC") {
+            return Err(Violation::Entity("external DTDs are not allowed"));
+        }
+        if self.eat("[") {
+            loop {
+                self.skip_whitespace();
+                if self.eat("]") {
+                    break;
+                } else if self.eat("<!--") {
+                    self.comment()?;
+                } else if self.eat("<?") {
+                    self.until("?>", "processing instruction")?;
+                } else if self.eat("<!ENTITY") {
+                    self.entity_declaration()?;
+                } else if self.rest().starts_with('%') {
+                    return Err(Violation::Entity("parameter entities are not allowed"));
+                } else if self.eat("<!") {
+                    self.markup_declaration()?;
+                } else {
+                    return Err(malformed("bad internal DTD subset"));
+                }
+            }
+            self.skip_whitespace();
+        }
+        self.expect(">", "to close the document type declaration")
+    }
+
+    fn entity_declaration(&mut self) -> Result<(), Violation> {
+        self.skip_whitespace();
+        if self.rest().starts_with('%') {
+            return Err(Violation::Entity("parameter entities are not allowed"));
+        }
+        let name = self.name()?;
+        self.skip_whitespace();
+        if self.rest().starts_with("SYSTEM") || self.rest().starts_with("PUBLIC") {
+            return Err(Violation::Entity("external entities are not allowed"));
+        }
+        let value = self.quoted()?;
+        if value.contains('%') {
+            return Err(Violation::Entity("parameter entities are not allowed"));
+        }
+        self.skip_whitespace();
+        self.expect(">", "to close an entity declaration")?;
+        // The first declaration of an entity is binding.
+        self.entities.entry(name).or_insert(value);
+        Ok(())
+    }
+
+    /// Skips an `ELEMENT`, `ATTLIST`, or `NOTATION` declaration.
+    fn markup_declaration(&mut self) -> Result<(), Violation> {
+        loop {
+            match self.rest().chars().next() {
+                Some('>') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                Some('"' | '\'') => {
+                    self.quoted()?;
+                }
+                Some(c) => self.pos += c.len_utf8(),
+                None => return Err(malformed("unterminated markup declaration")),
+            }
+        }
+    }
+}
+
+/// Whether `req` declares an XML body.
+fn is_xml<B>(req: &Request<B>) -> bool {
+    let Some(content_type) = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    media_type == "text/xml" || media_type == "application/xml" || media_type.ends_with("+xml")
+}
+
+fn reject(violation: &Violation, limits: &XmlGuardConfig) -> HttpResponse {
+    metrics::counter!("jester_xml_rejected_total", "reason" => violation.reason()).increment(1);
+    text_response(StatusCode::BAD_REQUEST, violation.message(limits))
+}
+
+#[derive(Clone)]
+struct XmlGuardService {
+    inner: JesterService,
+    limits: Arc<XmlGuardConfig>,
+}
+
+impl Service<HttpRequest> for XmlGuardService {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HttpRequest) -> Self::Future {
+        if !is_xml(&req) || req.body().is_end_stream() {
+            return self.inner.call(req);
+        }
+        let inner = self.inner.clone();
+        let limits = self.limits.clone();
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let body = match super::read_body(body, limits.max_bytes).await {
+                Ok(Some(body)) => body,
+                Ok(None) => {
+                    metrics::counter!("jester_xml_rejected_total", "reason" => "too_large")
+                        .increment(1);
+                    return Ok(text_response(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "XML body too large",
+                    ));
+                }
+                Err(err) => return Err(anyhow!(err).context("failed to read request body")),
+            };
+            if let Err(violation) = check(&body, &limits) {
+                return Ok(reject(&violation, &limits));
+            }
+            super::forward(inner, Request::from_parts(parts, full_body(body))).await
+        })
+    }
+}
+
+impl JesterPlugin for XmlGuardFilter {
+    fn name(&self) -> &'static str {
+        "xml-guard"
+    }
+
+    fn version(&self) -> semver::Version {
+        super::builtin_version()
+    }
+
+    fn layer(&self, cfg: Value) -> Result<DynLayer> {
+        let cfg: XmlGuardConfig = if cfg.is_null() {
+            XmlGuardConfig::default()
+        } else {
+            serde_json::from_value(cfg)?
+        };
+        let limits = Arc::new(cfg);
+        Ok(Box::new(layer_fn(move |inner: JesterService| {
+            JesterService::new(XmlGuardService {
+                inner,
+                limits: limits.clone(),
+            })
+        })))
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &["filter:req"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    fn limits(allow_doctype: bool) -> XmlGuardConfig {
+        XmlGuardConfig {
+            max_depth: 4,
+            allow_doctype,
+            ..Default::default()
+        }
+    }
+
+    fn reason(document: &str, allow_doctype: bool) -> Option<&'static str> {
+        check(document.as_bytes(), &limits(allow_doctype))
+            .err()
+            .map(|violation| violation.reason())
+    }
+
+    #[test]
+    fn accepts_well_formed_soap() {
+        let envelope = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- order -->
+<soap:Envelope xmlns:soap="http://www.w3.org/2003/05/soap-envelope">
+  <soap:Body>
+    <m:Order xmlns:m="urn:shop" id='7'>Fish &amp; chips &#x2713; <![CDATA[<raw>]]><m:Note/></m:Order>
+  </soap:Body>
+</soap:Envelope>
+"#;
+        assert_eq!(reason(envelope, false), None);
+    }
+
+    #[test]
+    fn rejects_malformed_documents() {
+        for document in [
+            "",
+            "<a>",
+            "<a></b>",
+            "<a/><b/>",
+            "text<a/>",
+            "<a x='1' x='2'/>",
+            "<a x=1/>",
+            "<a>&bogus;</a>",
+            "<a>AT&T</a>",
+            "<a><!-- a -- b --></a>",
+            "<a>&#0;</a>",
+        ] {
+            assert_eq!(reason(document, false), Some("malformed"), "{document}");
+        }
+        assert_eq!(reason("<a><b><c><d/></c></b></a>", false), None);
+        assert_eq!(
+            reason("<a><b><c><d><e/></d></c></b></a>", false),
+            Some("too_deep")
+        );
+    }
+
+    #[test]
+    fn stops_entity_attacks() {
+        let mut laughs = String::from("<!DOCTYPE lolz [<!ENTITY lol \"lol\">");
+        for level in 1..10 {
+            let previous = if level == 1 {
+                "lol".to_string()
+            } else {
+                format!("lol{}", level - 1)
+            };
+            laughs.push_str(&format!(
+                "<!ENTITY lol{level} \"{}\">",
+                format!("&{previous};").repeat(10)
+            ));
+        }
+        laughs.push_str("]><lolz>&lol9;</lolz>");
+        assert_eq!(reason(&laughs, false), Some("doctype"));
+        assert_eq!(reason(&laughs, true), Some("entity"));
+
+        let xxe = r#"<!DOCTYPE a [<!ENTITY x SYSTEM "file:///etc/passwd">]><a>&x;</a>"#;
+        assert_eq!(reason(xxe, true), Some("entity"));
+        let recursive = r#"<!DOCTYPE a [<!ENTITY x "&y;"><!ENTITY y "&x;">]><a>&x;</a>"#;
+        assert_eq!(reason(recursive, true), Some("entity"));
+        let modest = r#"<!DOCTYPE a [<!ELEMENT a ANY><!ENTITY co "ACME &amp; Co">]><a>&co;</a>"#;
+        assert_eq!(reason(modest, true), None);
+    }
+
+    #[tokio::test]
+    async fn only_xml_bodies_are_checked() {
+        let service = XmlGuardFilter
+            .layer(serde_json::json!({ "max_bytes": 64 }))
+            .unwrap()
+            .layer(JesterService::new(service_fn(
+                |req: HttpRequest| async move {
+                    let body = req.into_body().collect().await.unwrap().to_bytes();
+                    Ok::<_, anyhow::Error>(text_response(StatusCode::OK, body))
+                },
+            )));
+        let send = |content_type: &str, body: &'static str| {
+            let req = Request::post("/soap")
+                .header(header::CONTENT_TYPE, content_type)
+                .body(full_body(body))
+                .unwrap();
+            service.clone().oneshot(req)
+        };
+
+        let resp = send("application/soap+xml; charset=utf-8", "<a>ok</a>")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.into_body().collect().await.unwrap().to_bytes(),
+            "<a>ok</a>"
+        );
+        let resp = send("text/xml", "<a>").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = send("text/plain", "<a>").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let large = "<a>................................................................</a>";
+        let resp = send("text/xml", large).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,898 @@
+//! Upstream HTTP(S) client with per-connection bookkeeping.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context as _, Result};
+use http::{uri::Scheme, Response, Uri};
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper_util::{
+    client::legacy::{
+        connect::{Connected, Connection, HttpConnector},
+        Client,
+    },
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        client::{ServerCertVerified, ServerCertVerifier},
+        Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName,
+    },
+    TlsConnector,
+};
+use tower::Service;
+
+use crate::{
+    client_ip,
+    config::{ProxyProtocolVersion, UpstreamPool, UpstreamProtocol, UpstreamTls},
+    error::ProxyError,
+    plugin::{BoxError, ProxyBody},
+    stats::{target_key, InflightGuard, RuntimeStats},
+};
+
+pub(crate) type HttpClient = Client<TrackingConnector, ProxyBody>;
+
+/// Settings other than TLS that call for a client of their own.
+type ClientKey = (UpstreamPool, UpstreamProtocol, Option<ProxyProtocolVersion>);
+
+tokio::task_local! {
+    /// Client and listener addresses announced by connections opened for the
+    /// request being sent; see [`announcing`].
+    static PROXY_ADDRESSES: Option<(SocketAddr, SocketAddr)>;
+}
+
+/// Runs `send` so that connections it opens to upstreams with a PROXY
+/// protocol header announce `addresses`, the client's and the listener's.
+/// Without them, the header says the connection is the proxy's own.
+pub(crate) async fn announcing<F: Future>(
+    addresses: Option<(SocketAddr, SocketAddr)>,
+    send: F,
+) -> F::Output {
+    PROXY_ADDRESSES.scope(addresses, send).await
+}
+
+/// One pooled client per distinct combination of [`UpstreamTls`],
+/// [`UpstreamPool`], [`UpstreamProtocol`], and PROXY protocol settings, shared
+/// by every route using them and kept across config reloads.
+#[derive(Clone)]
+pub(crate) struct UpstreamClients {
+    stats: RuntimeStats,
+    roots: Arc<RootCertStore>,
+    clients: Arc<RwLock<HashMap<ClientKey, HashMap<UpstreamTls, HttpClient>>>>,
+}
+
+impl UpstreamClients {
+    /// Clients trusting the bundled Mozilla root certificates.
+    pub(crate) fn new(stats: RuntimeStats) -> Self {
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+        Self::with_roots(stats, roots)
+    }
+
+    pub(crate) fn with_roots(stats: RuntimeStats, roots: RootCertStore) -> Self {
+        Self {
+            stats,
+            roots: Arc::new(roots),
+            clients: Arc::default(),
+        }
+    }
+
+    /// The HTTP/1.1 client for `tls` with the default pool settings.
+    pub(crate) fn get(&self, tls: &UpstreamTls) -> Result<HttpClient> {
+        self.pooled(tls, &UpstreamPool::default(), UpstreamProtocol::Http1, None)
+    }
+
+    /// The client for `tls`, `pool`, `protocol`, and `proxy_protocol`, built
+    /// on first use. A `ca_file` is read then, so a changed bundle is only
+    /// picked up under a new path or after a restart.
+    pub(crate) fn pooled(
+        &self,
+        tls: &UpstreamTls,
+        pool: &UpstreamPool,
+        protocol: UpstreamProtocol,
+        proxy_protocol: Option<ProxyProtocolVersion>,
+    ) -> Result<HttpClient> {
+        let key = (*pool, protocol, proxy_protocol);
+        if let Some(client) = self
+            .clients
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&key)
+            .and_then(|clients| clients.get(tls))
+        {
+            return Ok(client.clone());
+        }
+        let client = self.build(tls, pool, protocol, proxy_protocol)?;
+        Ok(self
+            .clients
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(key)
+            .or_default()
+            .entry(tls.clone())
+            .or_insert(client)
+            .clone())
+    }
+
+    fn build(
+        &self,
+        tls: &UpstreamTls,
+        pool: &UpstreamPool,
+        protocol: UpstreamProtocol,
+        proxy_protocol: Option<ProxyProtocolVersion>,
+    ) -> Result<HttpClient> {
+        let mut connector = HttpConnector::new();
+        connector.enforce_http(false);
+        connector.set_keepalive(pool.tcp_keepalive_secs.map(Duration::from_secs));
+        let roots = match &tls.ca_file {
+            Some(ca_file) => load_roots(ca_file)?,
+            None => (*self.roots).clone(),
+        };
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        if tls.insecure_skip_verify {
+            tracing::warn!("upstream TLS certificate verification is disabled");
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoVerification));
+        }
+        if !tls.session_resumption {
+            config.resumption = tokio_rustls::rustls::client::Resumption::disabled();
+        }
+        config.enable_early_data = tls.early_data;
+        let mut builder = Client::builder(TokioExecutor::new());
+        builder.pool_timer(TokioTimer::new());
+        if protocol != UpstreamProtocol::Http1 {
+            // Targets that do not agree on h2 fail the handshake rather than
+            // falling back to HTTP/1.1.
+            config.alpn_protocols = vec![b"h2".to_vec()];
+            builder.http2_only(true);
+        }
+        if let Some(max_idle) = pool.max_idle_per_host {
+            builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(idle_timeout) = pool.idle_timeout_secs {
+            builder.pool_idle_timeout(Duration::from_secs(idle_timeout));
+        }
+        if proxy_protocol.is_some() {
+            // A connection announces one client, so none is handed to another.
+            builder.pool_max_idle_per_host(0);
+        }
+        Ok(builder.build(TrackingConnector {
+            inner: connector,
+            tls: TlsConnector::from(Arc::new(config)).early_data(tls.early_data),
+            proxy_protocol,
+            stats: self.stats.clone(),
+        }))
+    }
+}
+
+/// Scheme of upstream targets reached over a Unix domain socket.
+const UNIX_SCHEME: &str = "unix";
+
+/// Parses an upstream target: an `http://` or `https://` URL, or
+/// `unix:///path/to.sock` for a Unix domain socket. A socket path is carried
+/// hex-encoded in the URI's authority, so connections to each socket get
+/// their own pool.
+pub(crate) fn parse_target(target: &str) -> Result<Uri> {
+    let Some(path) = target.strip_prefix("unix://") else {
+        return Uri::from_str(target)
+            .with_context(|| format!("invalid upstream target `{target}`"));
+    };
+    if !cfg!(unix) {
+        bail!("upstream target `{target}`: unix sockets are not supported on this platform");
+    }
+    if !path.starts_with('/') || path.len() == 1 {
+        bail!(
+            "upstream target `{target}` must name an absolute socket path, \
+             as in `unix:///run/app.sock`"
+        );
+    }
+    let authority: String = path.bytes().map(|byte| format!("{byte:02x}")).collect();
+    Uri::from_str(&format!("{UNIX_SCHEME}://{authority}"))
+        .with_context(|| format!("invalid upstream target `{target}`"))
+}
+
+/// The socket path of a target parsed from `unix://`.
+pub(crate) fn socket_path(uri: &Uri) -> Option<PathBuf> {
+    if uri.scheme_str() != Some(UNIX_SCHEME) {
+        return None;
+    }
+    let hex = uri.host()?.as_bytes();
+    let bytes = hex
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(PathBuf::from(String::from_utf8(bytes).ok()?))
+}
+
+/// Reads a PEM bundle of trusted CA certificates.
+pub(crate) fn load_roots(path: &str) -> Result<RootCertStore> {
+    let pem = std::fs::read(path).with_context(|| format!("failed to read CA bundle {path}"))?;
+    let certs = rustls_pemfile::certs(&mut io::Cursor::new(pem))
+        .with_context(|| format!("invalid certificate data in CA bundle {path}"))?;
+    if certs.is_empty() {
+        bail!("no certificates found in CA bundle {path}");
+    }
+    let mut roots = RootCertStore::empty();
+    for cert in certs {
+        roots
+            .add(&Certificate(cert))
+            .with_context(|| format!("unusable certificate in CA bundle {path}"))?;
+    }
+    Ok(roots)
+}
+
+/// Accepts every server certificate, for `insecure_skip_verify`.
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// How the upstream connection that served a response was obtained.
+pub(crate) struct ConnectionUse {
+    /// Whether the connection had already carried an earlier request.
+    pub(crate) reused: bool,
+    /// Time it took to open the connection.
+    pub(crate) connect_time: Duration,
+}
+
+/// Reports on the connection behind `response`; call once per response.
+pub(crate) fn connection_use<B>(response: &Response<B>) -> Option<ConnectionUse> {
+    let info = response.extensions().get::<ConnectionInfo>()?;
+    Some(ConnectionUse {
+        reused: info.requests.fetch_add(1, Ordering::Relaxed) > 0,
+        connect_time: info.connect_time,
+    })
+}
+
+/// Attached to every upstream response through hyper's connection extras.
+#[derive(Clone)]
+struct ConnectionInfo {
+    requests: Arc<AtomicU64>,
+    connect_time: Duration,
+}
+
+/// Wraps [`HttpConnector`], adding TLS for `https://` targets, Unix domain
+/// sockets for `unix://` ones, and any PROXY protocol header, so every new
+/// connection counts towards `jester_upstream_open_connections` until it is
+/// closed.
+#[derive(Clone)]
+pub(crate) struct TrackingConnector {
+    inner: HttpConnector,
+    tls: TlsConnector,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+    stats: RuntimeStats,
+}
+
+impl Service<Uri> for TrackingConnector {
+    type Response = TrackedStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<TrackedStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let target = target_key(&dst);
+        let stats = self.stats.clone();
+        let proxy_header = self.proxy_protocol.map(|version| {
+            let addresses = PROXY_ADDRESSES.try_with(|addresses| *addresses);
+            client_ip::proxy_header(version, addresses.ok().flatten())
+        });
+        if let Some(path) = socket_path(&dst) {
+            let started = Instant::now();
+            return Box::pin(async move {
+                let mut io = connect_unix(&path).await?;
+                if let Some(header) = &proxy_header {
+                    io.write_all(header).await?;
+                }
+                metrics::counter!("jester_upstream_connections_opened_total", "target" => target.clone())
+                    .increment(1);
+                Ok(TrackedStream {
+                    io: TokioIo::new(io),
+                    info: ConnectionInfo {
+                        requests: Arc::default(),
+                        connect_time: started.elapsed(),
+                    },
+                    _open: stats.track_connection(&target),
+                })
+            });
+        }
+        let tls = (dst.scheme() == Some(&Scheme::HTTPS)).then(|| {
+            let host = dst.host().unwrap_or_default();
+            (self.tls.clone(), host.trim_matches(['[', ']']).to_string())
+        });
+        let connecting = self.inner.call(dst);
+        let started = Instant::now();
+        Box::pin(async move {
+            let mut tcp = connecting.await?.into_inner();
+            if let Some(header) = &proxy_header {
+                tcp.write_all(header).await?;
+            }
+            let io = match tls {
+                None => UpstreamIo::Plain(tcp),
+                Some((connector, host)) => {
+                    let server_name = ServerName::try_from(host.as_str())
+                        .map_err(|err| ProxyError::Tls(err.into()))?;
+                    let stream = connector
+                        .connect(server_name, tcp)
+                        .await
+                        .map_err(|err| ProxyError::Tls(err.into()))?;
+                    UpstreamIo::Tls(Box::new(stream))
+                }
+            };
+            metrics::counter!("jester_upstream_connections_opened_total", "target" => target.clone())
+                .increment(1);
+            Ok(TrackedStream {
+                io: TokioIo::new(io),
+                info: ConnectionInfo {
+                    requests: Arc::default(),
+                    connect_time: started.elapsed(),
+                },
+                _open: stats.track_connection(&target),
+            })
+        })
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &Path) -> Result<UpstreamIo, BoxError> {
+    let stream = tokio::net::UnixStream::connect(path)
+        .await
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+    Ok(UpstreamIo::Unix(stream))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(path: &Path) -> Result<UpstreamIo, BoxError> {
+    Err(format!(
+        "cannot connect to {}: unix sockets are not supported",
+        path.display()
+    )
+    .into())
+}
+
+pub(crate) struct TrackedStream {
+    io: TokioIo<UpstreamIo>,
+    info: ConnectionInfo,
+    _open: InflightGuard,
+}
+
+impl Connection for TrackedStream {
+    fn connected(&self) -> Connected {
+        Connected::new().extra(self.info.clone())
+    }
+}
+
+/// A plain, TLS, or Unix domain upstream socket.
+enum UpstreamIo {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl AsyncRead for UpstreamIo {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamIo::Plain(io) => Pin::new(io).poll_read(cx, buf),
+            UpstreamIo::Tls(io) => Pin::new(io).poll_read(cx, buf),
+            #[cfg(unix)]
+            UpstreamIo::Unix(io) => Pin::new(io).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamIo {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamIo::Plain(io) => Pin::new(io).poll_write(cx, buf),
+            UpstreamIo::Tls(io) => Pin::new(io).poll_write(cx, buf),
+            #[cfg(unix)]
+            UpstreamIo::Unix(io) => Pin::new(io).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamIo::Plain(io) => Pin::new(io).poll_flush(cx),
+            UpstreamIo::Tls(io) => Pin::new(io).poll_flush(cx),
+            #[cfg(unix)]
+            UpstreamIo::Unix(io) => Pin::new(io).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamIo::Plain(io) => Pin::new(io).poll_shutdown(cx),
+            UpstreamIo::Tls(io) => Pin::new(io).poll_shutdown(cx),
+            #[cfg(unix)]
+            UpstreamIo::Unix(io) => Pin::new(io).poll_shutdown(cx),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            UpstreamIo::Plain(io) => io.is_write_vectored(),
+            UpstreamIo::Tls(io) => io.is_write_vectored(),
+            #[cfg(unix)]
+            UpstreamIo::Unix(io) => io.is_write_vectored(),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamIo::Plain(io) => Pin::new(io).poll_write_vectored(cx, bufs),
+            UpstreamIo::Tls(io) => Pin::new(io).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            UpstreamIo::Unix(io) => Pin::new(io).poll_write_vectored(cx, bufs),
+        }
+    }
+}
+
+impl Read for TrackedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl Write for TrackedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::atomic::AtomicUsize};
+
+    use http::Request;
+    use hyper::{
+        server::conn::{http1, http2},
+        service::service_fn,
+    };
+    use tokio::net::TcpListener;
+    use tokio_rustls::{
+        rustls::{
+            server::{ServerSessionMemoryCache, StoresServerSessions},
+            Certificate, PrivateKey, ServerConfig,
+        },
+        TlsAcceptor,
+    };
+
+    use super::*;
+    use crate::plugin::full_body;
+
+    /// Counts the sessions the server resumed.
+    struct CountingStore {
+        inner: Arc<ServerSessionMemoryCache>,
+        resumed: AtomicUsize,
+    }
+
+    impl StoresServerSessions for CountingStore {
+        fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+            self.inner.put(key, value)
+        }
+
+        fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+            self.inner.get(key)
+        }
+
+        fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+            let session = self.inner.take(key);
+            if session.is_some() {
+                self.resumed.fetch_add(1, Ordering::Relaxed);
+            }
+            session
+        }
+
+        fn can_cache(&self) -> bool {
+            true
+        }
+    }
+
+    /// A TLS server for `localhost` with a self-signed certificate, returned
+    /// as a root store and as PEM. It speaks HTTP/2 to clients offering h2.
+    async fn tls_server() -> (SocketAddr, RootCertStore, String, Arc<CountingStore>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let pem = certified.cert.pem();
+        let cert = Certificate(certified.cert.der().to_vec());
+        let mut roots = RootCertStore::empty();
+        roots.add(&cert).unwrap();
+        let store = Arc::new(CountingStore {
+            inner: ServerSessionMemoryCache::new(16),
+            resumed: AtomicUsize::new(0),
+        });
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], PrivateKey(certified.key_pair.serialize_der()))
+            .unwrap();
+        config.session_storage = store.clone();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(tls) = acceptor.accept(tcp).await else {
+                        return;
+                    };
+                    let service = service_fn(|_req| async {
+                        Ok::<_, hyper::Error>(Response::new(full_body("ok")))
+                    });
+                    if tls.get_ref().1.alpn_protocol() == Some(b"h2") {
+                        http2::Builder::new(TokioExecutor::new())
+                            .serve_connection(TokioIo::new(tls), service)
+                            .await
+                            .ok();
+                    } else {
+                        http1::Builder::new()
+                            .serve_connection(TokioIo::new(tls), service)
+                            .await
+                            .ok();
+                    }
+                });
+            }
+        });
+        (addr, roots, pem, store)
+    }
+
+    /// Sends two requests, each on a fresh connection, and returns how many
+    /// handshakes the server resumed.
+    async fn resumed_handshakes(tls: UpstreamTls) -> usize {
+        let (addr, roots, _, store) = tls_server().await;
+        let client = UpstreamClients::with_roots(RuntimeStats::default(), roots)
+            .get(&tls)
+            .unwrap();
+        for _ in 0..2 {
+            let request = Request::get(format!("https://localhost:{}/", addr.port()))
+                .header(http::header::CONNECTION, "close")
+                .body(full_body(""))
+                .unwrap();
+            let response = client.request(request).await.unwrap();
+            assert_eq!(response.status(), http::StatusCode::OK);
+        }
+        store.resumed.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn https_connections_resume_sessions_when_enabled() {
+        assert_eq!(resumed_handshakes(UpstreamTls::default()).await, 1);
+        let disabled = UpstreamTls {
+            session_resumption: false,
+            ..UpstreamTls::default()
+        };
+        assert_eq!(resumed_handshakes(disabled).await, 0);
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificates_are_tls_errors() {
+        let (addr, _, _, _) = tls_server().await;
+        let client = UpstreamClients::with_roots(RuntimeStats::default(), RootCertStore::empty())
+            .get(&UpstreamTls::default())
+            .unwrap();
+        let request = Request::get(format!("https://localhost:{}/", addr.port()))
+            .body(full_body(""))
+            .unwrap();
+        let err = ProxyError::from(client.request(request).await.unwrap_err());
+        assert_eq!(err.kind(), "tls");
+        assert!(
+            err.to_string().contains("invalid peer certificate"),
+            "{err}"
+        );
+        assert_eq!(crate::error::cause(&err.into()), "tls_certificate");
+    }
+
+    #[tokio::test]
+    async fn connect_failures_name_their_cause() {
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let client = UpstreamClients::new(RuntimeStats::default())
+            .get(&UpstreamTls::default())
+            .unwrap();
+        for (url, cause) in [
+            (format!("http://{closed}/"), "connection_refused"),
+            ("http://jester.invalid/".to_string(), "dns"),
+        ] {
+            let request = Request::get(url).body(full_body("")).unwrap();
+            let err = ProxyError::from(client.request(request).await.unwrap_err());
+            assert_eq!(err.kind(), "connect");
+            assert_eq!(crate::error::cause(&err.into()), cause);
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_roots_or_skipping_verification_trust_private_certificates() {
+        let (addr, _, pem, _) = tls_server().await;
+        let ca_file = std::env::temp_dir().join(format!("jester-ca-{}.pem", addr.port()));
+        std::fs::write(&ca_file, pem).unwrap();
+        let clients = UpstreamClients::with_roots(RuntimeStats::default(), RootCertStore::empty());
+        let settings = [
+            UpstreamTls {
+                ca_file: Some(ca_file.display().to_string()),
+                ..UpstreamTls::default()
+            },
+            UpstreamTls {
+                insecure_skip_verify: true,
+                ..UpstreamTls::default()
+            },
+        ];
+        for tls in settings {
+            let request = Request::get(format!("https://localhost:{}/", addr.port()))
+                .body(full_body(""))
+                .unwrap();
+            let response = clients.get(&tls).unwrap().request(request).await.unwrap();
+            assert_eq!(response.status(), http::StatusCode::OK);
+        }
+        std::fs::remove_file(&ca_file).ok();
+
+        let missing = UpstreamTls {
+            ca_file: Some("/nonexistent/ca.pem".into()),
+            ..UpstreamTls::default()
+        };
+        assert!(clients.get(&missing).is_err());
+    }
+
+    #[tokio::test]
+    async fn pool_settings_get_their_own_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(http1::Builder::new().serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(|_req: Request<hyper::body::Incoming>| async {
+                        Ok::<_, hyper::Error>(Response::new(full_body("ok")))
+                    }),
+                ));
+            }
+        });
+
+        let clients = UpstreamClients::new(RuntimeStats::default());
+        let no_idle = UpstreamPool {
+            max_idle_per_host: Some(0),
+            ..UpstreamPool::default()
+        };
+        for (pool, connections) in [(UpstreamPool::default(), 1), (no_idle, 2)] {
+            let before = accepted.load(Ordering::Relaxed);
+            let client = clients
+                .pooled(
+                    &UpstreamTls::default(),
+                    &pool,
+                    UpstreamProtocol::Http1,
+                    None,
+                )
+                .unwrap();
+            for _ in 0..2 {
+                let request = Request::get(format!("http://{addr}/"))
+                    .body(full_body(""))
+                    .unwrap();
+                let response = client.request(request).await.unwrap();
+                http_body_util::BodyExt::collect(response.into_body())
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(accepted.load(Ordering::Relaxed) - before, connections);
+        }
+    }
+
+    #[tokio::test]
+    async fn h2_targets_negotiate_http2_with_alpn() {
+        let (addr, roots, _, _) = tls_server().await;
+        let clients = UpstreamClients::with_roots(RuntimeStats::default(), roots);
+        for (protocol, version) in [
+            (UpstreamProtocol::Http1, http::Version::HTTP_11),
+            (UpstreamProtocol::H2, http::Version::HTTP_2),
+        ] {
+            let client = clients
+                .pooled(
+                    &UpstreamTls::default(),
+                    &UpstreamPool::default(),
+                    protocol,
+                    None,
+                )
+                .unwrap();
+            let request = Request::get(format!("https://localhost:{}/", addr.port()))
+                .body(full_body(""))
+                .unwrap();
+            let response = client.request(request).await.unwrap();
+            assert_eq!(response.version(), version);
+        }
+    }
+
+    #[tokio::test]
+    async fn h2c_targets_multiplex_requests_over_one_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(http2::Builder::new(TokioExecutor::new()).serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(|_req: Request<hyper::body::Incoming>| async {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Ok::<_, hyper::Error>(Response::new(full_body("ok")))
+                    }),
+                ));
+            }
+        });
+
+        let client = UpstreamClients::new(RuntimeStats::default())
+            .pooled(
+                &UpstreamTls::default(),
+                &UpstreamPool::default(),
+                UpstreamProtocol::H2c,
+                None,
+            )
+            .unwrap();
+        // The first request opens the connection the others then share.
+        let send = |client: HttpClient| async move {
+            let request = Request::get(format!("http://{addr}/"))
+                .body(full_body(""))
+                .unwrap();
+            client.request(request).await.unwrap()
+        };
+        assert_eq!(send(client.clone()).await.version(), http::Version::HTTP_2);
+        let concurrent = (0..4).map(|_| tokio::spawn(send(client.clone())));
+        for response in concurrent {
+            assert_eq!(response.await.unwrap().status(), http::StatusCode::OK);
+        }
+        assert_eq!(accepted.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn unix_targets_round_trip_through_the_authority() {
+        let uri = parse_target("unix:///var/run/app.sock").unwrap();
+        assert_eq!(uri.scheme_str(), Some("unix"));
+        assert_eq!(socket_path(&uri), Some(PathBuf::from("/var/run/app.sock")));
+        assert_eq!(target_key(&uri), "unix:///var/run/app.sock");
+        assert!(parse_target("unix://relative.sock").is_err());
+        assert!(parse_target("unix:///").is_err());
+        assert_eq!(
+            socket_path(&parse_target("http://127.0.0.1:80").unwrap()),
+            None
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_upstreams_are_pooled_per_socket() {
+        let path = std::env::temp_dir().join(format!("jester-uds-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(http1::Builder::new().serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(|req: Request<hyper::body::Incoming>| async move {
+                        let host = req.headers()[http::header::HOST].clone();
+                        Ok::<_, hyper::Error>(Response::new(full_body(format!(
+                            "{} {}",
+                            req.uri(),
+                            host.to_str().unwrap()
+                        ))))
+                    }),
+                ));
+            }
+        });
+
+        let stats = RuntimeStats::default();
+        let client = UpstreamClients::new(stats.clone())
+            .get(&UpstreamTls::default())
+            .unwrap();
+        let base = parse_target(&format!("unix://{}", path.display())).unwrap();
+        for _ in 0..2 {
+            let mut parts = base.clone().into_parts();
+            parts.path_and_query = Some("/status?x=1".parse().unwrap());
+            let req = Request::get(Uri::from_parts(parts).unwrap())
+                .header(http::header::HOST, "localhost")
+                .body(full_body(""))
+                .unwrap();
+            let response = client.request(req).await.unwrap();
+            let body = http_body_util::BodyExt::collect(response.into_body())
+                .await
+                .unwrap()
+                .to_bytes();
+            assert_eq!(body, "/status?x=1 localhost");
+        }
+        assert_eq!(accepted.load(Ordering::Relaxed), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+//! Who the client is: the listener's `client_ip` policy applied to the peer,
+//! forwarding headers, and PROXY protocol headers.
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{bail, Context, Result};
+use http::{header::HeaderName, HeaderMap};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    builtins::CidrSet,
+    config::{ClientIpPolicy, ClientIpSource, ProxyProtocolVersion},
+    context::ConnectionInfo,
+};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+/// Signature opening a binary (v2) PROXY protocol header.
+const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest text (v1) header, CRLF included.
+const PROXY_V1_MAX: usize = 107;
+
+/// A listener's compiled [`ClientIpPolicy`].
+pub(crate) struct ClientIpResolver {
+    source: ClientIpSource,
+    trusted: CidrSet,
+    header: Option<HeaderName>,
+    depth: usize,
+}
+
+impl TryFrom<&ClientIpPolicy> for ClientIpResolver {
+    type Error = anyhow::Error;
+
+    fn try_from(policy: &ClientIpPolicy) -> Result<Self> {
+        Ok(Self {
+            source: policy.source,
+            trusted: CidrSet::parse(&policy.trusted_proxies)?,
+            header: policy
+                .header
+                .as_deref()
+                .map(|header| HeaderName::from_bytes(header.as_bytes()))
+                .transpose()?,
+            depth: policy.depth.max(1),
+        })
+    }
+}
+
+impl ClientIpResolver {
+    /// Whether a connection from `peer` starts with a PROXY protocol header.
+    pub(crate) fn expects_proxy_header(&self, peer: IpAddr) -> bool {
+        self.source == ClientIpSource::ProxyProtocol && self.trusted.contains(peer)
+    }
+
+    /// The client of a request on `connection`. Headers only count when the
+    /// peer is a trusted proxy; anything unparsable leaves the peer in place.
+    pub(crate) fn resolve(&self, connection: &ConnectionInfo, headers: &HeaderMap) -> IpAddr {
+        let peer = connection.client_addr.ip();
+        if !self.trusted.contains(peer) {
+            return peer;
+        }
+        match (self.source, &self.header) {
+            (ClientIpSource::XForwardedFor, _) => self.forwarded_for(peer, headers),
+            (ClientIpSource::Header, Some(name)) => headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_addr)
+                .unwrap_or(peer),
+            _ => peer,
+        }
+    }
+
+    /// Walks `x-forwarded-for` from the right, skipping trusted proxies, for at
+    /// most `depth` entries.
+    fn forwarded_for(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut entries: Vec<&str> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        entries.reverse();
+        let mut client = peer;
+        for entry in entries.into_iter().take(self.depth) {
+            let Some(addr) = parse_addr(entry) else {
+                break;
+            };
+            client = addr;
+            if !self.trusted.contains(addr) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// An address as proxies write it: bare, with a port, or bracketed IPv6.
+fn parse_addr(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    value
+        .parse::<IpAddr>()
+        .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .or_else(|| value.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+/// Reads a PROXY protocol v1 or v2 header off the start of `io`, returning the
+/// source address it carries; `None` for `LOCAL` and `UNKNOWN` connections,
+/// such as a load balancer's own health checks.
+pub(crate) async fn read_proxy_header<R>(io: &mut R) -> Result<Option<SocketAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    // The shortest v1 header, `PROXY UNKNOWN\r\n`, is longer than this.
+    let mut start = [0u8; 12];
+    io.read_exact(&mut start)
+        .await
+        .context("connection closed before its PROXY protocol header")?;
+    if start == PROXY_V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        io.read_exact(&mut fixed).await?;
+        let mut body = vec![0u8; usize::from(u16::from_be_bytes([fixed[2], fixed[3]]))];
+        io.read_exact(&mut body).await?;
+        return parse_v2(fixed[0], fixed[1], &body);
+    }
+    if !start.starts_with(b"PROXY ") {
+        bail!("connection did not start with a PROXY protocol header");
+    }
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == PROXY_V1_MAX {
+            bail!("PROXY protocol header is too long");
+        }
+        line.push(io.read_u8().await?);
+    }
+    parse_v1(&line)
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).context("PROXY protocol header is not ASCII")?;
+    let fields: Vec<&str> = line.trim_end().split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source.parse().context("invalid PROXY protocol source")?;
+            let port: u16 = port.parse().context("invalid PROXY protocol port")?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!("malformed PROXY protocol header"),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        bail!("unsupported PROXY protocol version");
+    }
+    match version_command & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        _ => bail!("unsupported PROXY protocol command"),
+    }
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    match family >> 4 {
+        1 if body.len() >= 12 => {
+            let ip: [u8; 4] = body[..4].try_into().expect("slice has four bytes");
+            Ok(Some(SocketAddr::new(IpAddr::from(ip), port(8))))
+        }
+        2 if body.len() >= 36 => {
+            let ip: [u8; 16] = body[..16].try_into().expect("slice has sixteen bytes");
+            Ok(Some(SocketAddr::new(IpAddr::from(ip), port(32))))
+        }
+        1 | 2 => bail!("truncated PROXY protocol addresses"),
+        // AF_UNSPEC and unix sockets carry no client address.
+        _ => Ok(None),
+    }
+}
+
+/// Encodes a PROXY protocol header announcing a connection from `source` to
+/// `destination`; without addresses, an `UNKNOWN` (v1) or `LOCAL` (v2) one.
+pub(crate) fn proxy_header(
+    version: ProxyProtocolVersion,
+    addresses: Option<(SocketAddr, SocketAddr)>,
+) -> Vec<u8> {
+    // Both addresses must be of one family; IPv4 ones are mapped when not.
+    let addresses = addresses.map(|(source, destination)| {
+        let source = SocketAddr::new(source.ip().to_canonical(), source.port());
+        let destination = SocketAddr::new(destination.ip().to_canonical(), destination.port());
+        match (source.ip(), destination.ip()) {
+            (IpAddr::V4(ip), IpAddr::V6(_)) => (
+                SocketAddr::new(ip.to_ipv6_mapped().into(), source.port()),
+                destination,
+            ),
+            (IpAddr::V6(_), IpAddr::V4(ip)) => (
+                source,
+                SocketAddr::new(ip.to_ipv6_mapped().into(), destination.port()),
+            ),
+            _ => (source, destination),
+        }
+    });
+    match version {
+        ProxyProtocolVersion::V1 => match addresses {
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+            Some((source, destination)) => {
+                let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!(
+                    "PROXY {family} {} {} {} {}\r\n",
+                    source.ip(),
+                    destination.ip(),
+                    source.port(),
+                    destination.port()
+                )
+                .into_bytes()
+            }
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut header = PROXY_V2_SIGNATURE.to_vec();
+            let (command, family, body) = match addresses {
+                None => (0x20, 0x00, Vec::new()),
+                Some((source, destination)) => {
+                    let mut body = Vec::with_capacity(36);
+                    let family = match (source.ip(), destination.ip()) {
+                        (IpAddr::V4(from), IpAddr::V4(to)) => {
+                            body.extend_from_slice(&from.octets());
+                            body.extend_from_slice(&to.octets());
+                            0x11
+                        }
+                        (from, to) => {
+                            body.extend_from_slice(&ipv6_octets(from));
+                            body.extend_from_slice(&ipv6_octets(to));
+                            0x21
+                        }
+                    };
+                    body.extend_from_slice(&source.port().to_be_bytes());
+                    body.extend_from_slice(&destination.port().to_be_bytes());
+                    (0x21, family, body)
+                }
+            };
+            header.extend_from_slice(&[command, family]);
+            let len = u16::try_from(body.len()).expect("addresses fit in a header");
+            header.extend_from_slice(&len.to_be_bytes());
+            header.extend_from_slice(&body);
+            header
+        }
+    }
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn resolver(source: ClientIpSource, header: Option<&str>, depth: usize) -> ClientIpResolver {
+        ClientIpResolver::try_from(&ClientIpPolicy {
+            source,
+            trusted_proxies: vec!["10.0.0.0/8".into()],
+            header: header.map(String::from),
+            depth,
+        })
+        .unwrap()
+    }
+
+    fn connection(peer: &str) -> ConnectionInfo {
+        let peer = SocketAddr::new(peer.parse().unwrap(), 50000);
+        ConnectionInfo {
+            listener: "edge".into(),
+            scheme: "https",
+            local_addr: "127.0.0.1:8443".parse().unwrap(),
+            peer_addr: peer,
+            client_addr: peer,
+            trust_forwarded_headers: false,
+            tls_handshake: Duration::ZERO,
+            missing_host: Default::default(),
+            absolute_form: Default::default(),
+            health_check_paths: Default::default(),
+            client_certificate: None,
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn forwarded_for_skips_trusted_hops_up_to_depth() {
+        let forwarded = headers(&[
+            ("x-forwarded-for", "198.51.100.9, 203.0.113.7"),
+            ("x-forwarded-for", "10.1.2.3"),
+        ]);
+        let ip = |value: &str| value.parse::<IpAddr>().unwrap();
+
+        let shallow = resolver(ClientIpSource::XForwardedFor, None, 1);
+        assert_eq!(
+            shallow.resolve(&connection("10.0.0.1"), &forwarded),
+            ip("10.1.2.3")
+        );
+        let deep = resolver(ClientIpSource::XForwardedFor, None, 3);
+        assert_eq!(
+            deep.resolve(&connection("10.0.0.1"), &forwarded),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            deep.resolve(&connection("192.0.2.1"), &forwarded),
+            ip("192.0.2.1"),
+            "untrusted peers cannot name the client"
+        );
+
+        let cdn = resolver(ClientIpSource::Header, Some("cf-connecting-ip"), 1);
+        let header = headers(&[("cf-connecting-ip", "[2001:db8::1]:443")]);
+        assert_eq!(
+            cdn.resolve(&connection("10.0.0.1"), &header),
+            ip("2001:db8::1")
+        );
+        let garbage = headers(&[("cf-connecting-ip", "unknown")]);
+        assert_eq!(
+            cdn.resolve(&connection("10.0.0.1"), &garbage),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_proxy_protocol_headers() {
+        let mut v1: &[u8] = b"PROXY TCP4 198.51.100.9 10.0.0.1 51234 443\r\n\x16\x03";
+        let source = read_proxy_header(&mut v1).await.unwrap();
+        assert_eq!(source, Some("198.51.100.9:51234".parse().unwrap()));
+        assert_eq!(v1, b"\x16\x03", "only the header is consumed");
+
+        let mut unknown: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut unknown).await.unwrap(), None);
+
+        let mut v2 = PROXY_V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[
+            0x21, 0x11, 0, 12, 198, 51, 100, 9, 10, 0, 0, 1, 0xc8, 0x22, 1, 0xbb,
+        ]);
+        let source = read_proxy_header(&mut v2.as_slice()).await.unwrap();
+        assert_eq!(source, Some("198.51.100.9:51234".parse().unwrap()));
+
+        let mut tls: &[u8] = b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03\x00";
+        assert!(read_proxy_header(&mut tls).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn written_proxy_headers_read_back() {
+        let v4 = "198.51.100.9:51234".parse().unwrap();
+        let v6 = "[2001:db8::9]:51234".parse().unwrap();
+        let local = "10.0.0.1:443".parse().unwrap();
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let header = proxy_header(version, Some((v4, local)));
+            let source = read_proxy_header(&mut header.as_slice()).await.unwrap();
+            assert_eq!(source, Some(v4), "{version:?}");
+
+            // A v6 client on a v4 listener is announced with mapped addresses.
+            let header = proxy_header(version, Some((v6, local)));
+            let source = read_proxy_header(&mut header.as_slice()).await.unwrap();
+            assert_eq!(source, Some(v6), "{version:?}");
+
+            let header = proxy_header(version, None);
+            assert_eq!(
+                read_proxy_header(&mut header.as_slice()).await.unwrap(),
+                None
+            );
+        }
+        assert_eq!(
+            proxy_header(ProxyProtocolVersion::V1, Some((v4, local))),
+            b"PROXY TCP4 198.51.100.9 10.0.0.1 51234 443\r\n"
+        );
+    }
+}
@@ -0,0 +1,507 @@
+//! Gossip between jester instances, so a fleet shares runtime state such as
+//! upstream backoff windows and open circuits instead of each instance
+//! rediscovering it.
+//!
+//! Every round a node sends its member list and state to a few random peers
+//! over UDP. Members are the addresses it has heard from recently; seeds and
+//! members learned from others are contacted but only advertised onward once
+//! they answer, so a dead node drops out of the fleet after
+//! [`MEMBER_TIMEOUT_ROUNDS`] quiet rounds.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{net::UdpSocket, sync::watch};
+
+use crate::config::{Cluster, Election};
+
+/// Peers each round is sent to.
+const FANOUT: usize = 3;
+/// Rounds without a message before a member is dropped.
+const MEMBER_TIMEOUT_ROUNDS: u32 = 10;
+/// Windows of each kind per message; the longest-running are kept.
+const MAX_WINDOWS: usize = 256;
+/// Longest window accepted from a peer. Filters cap windows further, to
+/// their own `max_secs` or `open_secs`, when they read them.
+const MAX_WINDOW: Duration = Duration::from_secs(3600);
+/// Largest message sent or accepted.
+const MAX_PACKET: usize = 60 * 1024;
+const MAC_LEN: usize = 32;
+
+/// This node's view of the cluster, shared with filters through the request
+/// extensions.
+pub(crate) struct Gossip {
+    node: String,
+    secret: Option<Vec<u8>>,
+    interval: Duration,
+    seeds: Vec<String>,
+    members: Mutex<HashMap<SocketAddr, Member>>,
+    /// `retry-after` backoff windows.
+    backoffs: Windows,
+    /// Circuits opened by a `circuit-breaker` on some node.
+    circuits: Windows,
+    election: Option<Election>,
+    leader: watch::Sender<bool>,
+}
+
+struct Member {
+    /// Name the member reported; `None` until it has answered.
+    node: Option<String>,
+    last_seen: Instant,
+}
+
+/// Upstream target keys to the end of a window on them, in Unix milliseconds.
+#[derive(Default)]
+struct Windows(Mutex<HashMap<String, u64>>);
+
+impl Windows {
+    fn share(&self, upstream: &str, length: Duration) {
+        let until = unix_millis(SystemTime::now() + length);
+        let mut windows = self.0.lock().unwrap();
+        let entry = windows.entry(upstream.to_string()).or_default();
+        *entry = (*entry).max(until);
+    }
+
+    /// Time left in the window on `upstream`, at most `max`.
+    fn remaining(&self, upstream: &str, max: Duration) -> Option<Duration> {
+        let now = unix_millis(SystemTime::now());
+        let mut windows = self.0.lock().unwrap();
+        let until = windows.get_mut(upstream)?;
+        if *until <= now {
+            windows.remove(upstream);
+            return None;
+        }
+        *until = (*until).min(now.saturating_add(max.as_millis() as u64));
+        Some(Duration::from_millis(*until - now))
+    }
+
+    /// Merges windows from a peer, dropping ended ones and capping the rest at
+    /// [`MAX_WINDOW`] from now.
+    fn merge(&self, received: HashMap<String, u64>) {
+        let now = unix_millis(SystemTime::now());
+        let cap = now + MAX_WINDOW.as_millis() as u64;
+        let mut windows = self.0.lock().unwrap();
+        for (upstream, until) in received {
+            if until <= now {
+                continue;
+            }
+            let entry = windows.entry(upstream).or_default();
+            *entry = (*entry).max(until.min(cap));
+        }
+    }
+
+    /// The longest-running open windows, at most [`MAX_WINDOWS`].
+    fn open(&self) -> HashMap<String, u64> {
+        let now = unix_millis(SystemTime::now());
+        let mut open: Vec<_> = {
+            let mut windows = self.0.lock().unwrap();
+            windows.retain(|_, until| *until > now);
+            windows.iter().map(|(k, v)| (k.clone(), *v)).collect()
+        };
+        open.sort_unstable_by_key(|(_, until)| std::cmp::Reverse(*until));
+        open.truncate(MAX_WINDOWS);
+        open.into_iter().collect()
+    }
+
+    /// Seconds left in each open window.
+    fn snapshot(&self) -> HashMap<String, u64> {
+        let now = unix_millis(SystemTime::now());
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(upstream, until)| (upstream.clone(), (until - now).div_ceil(1000)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    node: String,
+    members: Vec<SocketAddr>,
+    backoffs: HashMap<String, u64>,
+    #[serde(default)]
+    circuits: HashMap<String, u64>,
+}
+
+/// `GET /cluster`.
+#[derive(Debug, Serialize)]
+pub(crate) struct ClusterSnapshot {
+    node: String,
+    /// Whether this instance holds the leader lease; absent without an
+    /// election.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    leader: Option<bool>,
+    members: Vec<MemberEntry>,
+    /// Upstream target key to the seconds left in its backoff window.
+    backoffs: HashMap<String, u64>,
+    /// Upstream target key to the seconds left before its circuit admits
+    /// trial requests.
+    circuits: HashMap<String, u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct MemberEntry {
+    addr: SocketAddr,
+    node: String,
+    last_seen_secs: u64,
+}
+
+/// This instance's name in the cluster: `node`, or else the hostname.
+pub(crate) fn node_name(config: &Cluster) -> String {
+    config.node.clone().unwrap_or_else(|| {
+        std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+            .map(|name| name.trim().to_string())
+            .ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| config.bind.clone())
+    })
+}
+
+impl Gossip {
+    pub(crate) fn new(config: &Cluster) -> Self {
+        Self {
+            node: node_name(config),
+            secret: config.secret.clone().map(String::into_bytes),
+            interval: Duration::from_millis(config.interval_ms),
+            seeds: config.peers.clone(),
+            members: Mutex::default(),
+            backoffs: Windows::default(),
+            circuits: Windows::default(),
+            election: config.election.clone(),
+            leader: watch::Sender::new(false),
+        }
+    }
+
+    pub(crate) fn node(&self) -> &str {
+        &self.node
+    }
+
+    pub(crate) fn election(&self) -> Option<&Election> {
+        self.election.as_ref()
+    }
+
+    /// Whether this instance runs singleton tasks; `None` without an
+    /// election, when every instance runs them.
+    pub(crate) fn leader(&self) -> Option<watch::Receiver<bool>> {
+        self.election.as_ref().map(|_| self.leader.subscribe())
+    }
+
+    pub(crate) fn set_leader(&self, leader: bool) {
+        self.leader.send_if_modified(|current| {
+            let changed = *current != leader;
+            *current = leader;
+            changed
+        });
+    }
+
+    /// Shares a backoff window on `upstream` lasting `delay` from now.
+    pub(crate) fn share_backoff(&self, upstream: &str, delay: Duration) {
+        self.backoffs.share(upstream, delay);
+    }
+
+    /// Time left, at most `max`, in a backoff window on `upstream` reported
+    /// by any node.
+    pub(crate) fn backoff(&self, upstream: &str, max: Duration) -> Option<Duration> {
+        self.backoffs.remaining(upstream, max)
+    }
+
+    /// Shares that the circuit for `upstream` opened for `open_for`.
+    pub(crate) fn share_open_circuit(&self, upstream: &str, open_for: Duration) {
+        self.circuits.share(upstream, open_for);
+    }
+
+    /// Time left, at most `max`, before a circuit for `upstream` that any
+    /// node opened admits trial requests.
+    pub(crate) fn open_circuit(&self, upstream: &str, max: Duration) -> Option<Duration> {
+        self.circuits.remaining(upstream, max)
+    }
+
+    pub(crate) fn snapshot(&self) -> ClusterSnapshot {
+        let members = self
+            .members
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(addr, member)| {
+                Some(MemberEntry {
+                    addr: *addr,
+                    node: member.node.clone()?,
+                    last_seen_secs: member.last_seen.elapsed().as_secs(),
+                })
+            })
+            .collect();
+        ClusterSnapshot {
+            node: self.node.clone(),
+            leader: self.election.as_ref().map(|_| *self.leader.borrow()),
+            members,
+            backoffs: self.backoffs.snapshot(),
+            circuits: self.circuits.snapshot(),
+        }
+    }
+
+    /// Gossips on `socket` until `shutdown` fires.
+    pub(crate) async fn run(&self, socket: UdpSocket, mut shutdown: watch::Receiver<bool>) {
+        let mut rounds = tokio::time::interval(self.interval);
+        rounds.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut buf = vec![0; MAX_PACKET + MAC_LEN];
+        loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.changed() => return,
+                received = socket.recv_from(&mut buf) => match received {
+                    Ok((len, from)) => {
+                        let outcome = match self.receive(from, &buf[..len]) {
+                            Ok(()) => "received",
+                            Err(err) => {
+                                tracing::debug!(%from, error = %err, "rejected gossip message");
+                                "rejected"
+                            }
+                        };
+                        metrics::counter!("jester_cluster_messages_total", "outcome" => outcome)
+                            .increment(1);
+                    }
+                    Err(err) => tracing::debug!(error = %err, "gossip receive failed"),
+                },
+                _ = rounds.tick() => self.round(&socket).await,
+            }
+        }
+    }
+
+    async fn round(&self, socket: &UdpSocket) {
+        let mut targets = Vec::new();
+        for seed in &self.seeds {
+            match tokio::net::lookup_host(seed.as_str()).await {
+                Ok(addrs) => targets.extend(addrs),
+                Err(err) => {
+                    tracing::debug!(peer = seed, error = %err, "failed to resolve gossip peer")
+                }
+            }
+        }
+        let timeout = self.interval * MEMBER_TIMEOUT_ROUNDS;
+        {
+            let mut members = self.members.lock().unwrap();
+            members.retain(|_, member| member.last_seen.elapsed() < timeout);
+            metrics::gauge!("jester_cluster_members").set(
+                members
+                    .values()
+                    .filter(|member| member.node.is_some())
+                    .count() as f64,
+            );
+            targets.extend(members.keys());
+        }
+        targets.sort_unstable();
+        targets.dedup();
+        let packet = match self.encode() {
+            Ok(packet) => packet,
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to encode gossip message");
+                return;
+            }
+        };
+        for target in pick(targets, FANOUT) {
+            if let Err(err) = socket.send_to(&packet, target).await {
+                tracing::debug!(%target, error = %err, "gossip send failed");
+                continue;
+            }
+            metrics::counter!("jester_cluster_messages_total", "outcome" => "sent").increment(1);
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let message = Message {
+            node: self.node.clone(),
+            members: self
+                .members
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, member)| member.node.is_some())
+                .map(|(addr, _)| *addr)
+                .collect(),
+            backoffs: self.backoffs.open(),
+            circuits: self.circuits.open(),
+        };
+        let mut packet = serde_json::to_vec(&message)?;
+        if packet.len() > MAX_PACKET {
+            bail!("gossip message of {} bytes is too large", packet.len());
+        }
+        if let Some(secret) = &self.secret {
+            let tag = mac(secret, &packet).finalize().into_bytes();
+            packet.extend_from_slice(&tag);
+        }
+        Ok(packet)
+    }
+
+    fn receive(&self, from: SocketAddr, packet: &[u8]) -> Result<()> {
+        let body = match &self.secret {
+            Some(secret) => {
+                let Some(split) = packet.len().checked_sub(MAC_LEN) else {
+                    bail!("message is too short to be authenticated");
+                };
+                let (body, tag) = packet.split_at(split);
+                mac(secret, body)
+                    .verify_slice(tag)
+                    .ok()
+                    .context("message failed authentication")?;
+                body
+            }
+            None => packet,
+        };
+        let message: Message = serde_json::from_slice(body).context("malformed message")?;
+        if message.node == self.node {
+            // Our own round, sent to ourselves through a seed.
+            return Ok(());
+        }
+        let now = Instant::now();
+        {
+            let mut members = self.members.lock().unwrap();
+            members.insert(
+                from,
+                Member {
+                    node: Some(message.node),
+                    last_seen: now,
+                },
+            );
+            for addr in message.members {
+                members.entry(addr).or_insert(Member {
+                    node: None,
+                    last_seen: now,
+                });
+            }
+        }
+        self.backoffs.merge(message.backoffs);
+        self.circuits.merge(message.circuits);
+        Ok(())
+    }
+}
+
+/// Binds the gossip socket; failure aborts startup.
+pub(crate) async fn bind(config: &Cluster) -> Result<UdpSocket> {
+    let socket = UdpSocket::bind(&config.bind)
+        .await
+        .with_context(|| format!("failed to bind cluster gossip on {}", config.bind))?;
+    tracing::info!(addr = %socket.local_addr()?, "cluster gossip ready");
+    Ok(socket)
+}
+
+fn mac(secret: &[u8], body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(body);
+    mac
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Up to `count` of `targets`, chosen at random.
+fn pick(mut targets: Vec<SocketAddr>, count: usize) -> Vec<SocketAddr> {
+    let mut random = [0; 8];
+    for i in 0..targets.len().min(count) {
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut random).ok();
+        let j = i + (u64::from_le_bytes(random) as usize) % (targets.len() - i);
+        targets.swap(i, j);
+    }
+    targets.truncate(count);
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(node: &str, peers: Vec<String>) -> Cluster {
+        Cluster {
+            bind: "127.0.0.1:0".into(),
+            node: Some(node.into()),
+            peers,
+            secret: Some("fleet-secret".into()),
+            interval_ms: 100,
+            election: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn nodes_learn_members_and_share_backoffs() {
+        let a_socket = bind(&cluster("a", Vec::new())).await.unwrap();
+        let a_addr = a_socket.local_addr().unwrap();
+        let a = Gossip::new(&cluster("a", Vec::new()));
+        let b = Gossip::new(&cluster("b", vec![a_addr.to_string()]));
+        let b_socket = bind(&cluster("b", Vec::new())).await.unwrap();
+        b.share_backoff("10.0.0.5:8080", Duration::from_secs(30));
+
+        let (_stop, shutdown) = watch::channel(false);
+        tokio::select! {
+            _ = a.run(a_socket, shutdown.clone()) => unreachable!(),
+            _ = b.run(b_socket, shutdown) => unreachable!(),
+            _ = async {
+                while a.backoff("10.0.0.5:8080", Duration::MAX).is_none() || b.snapshot().members.is_empty() {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            } => {}
+        }
+        let remaining = a.backoff("10.0.0.5:8080", Duration::MAX).unwrap();
+        assert!(remaining > Duration::from_secs(28), "{remaining:?}");
+        assert_eq!(b.snapshot().members[0].node, "a");
+        assert_eq!(a.snapshot().members[0].node, "b");
+    }
+
+    #[test]
+    fn rejects_messages_signed_with_another_secret() {
+        let a = Gossip::new(&cluster("a", Vec::new()));
+        let mut other = cluster("b", Vec::new());
+        other.secret = Some("other".into());
+        let b = Gossip::new(&other);
+        b.share_backoff("10.0.0.5:8080", Duration::from_secs(30));
+        let from = "127.0.0.1:7946".parse().unwrap();
+        assert!(a.receive(from, &b.encode().unwrap()).is_err());
+        assert!(a.backoff("10.0.0.5:8080", Duration::MAX).is_none());
+        assert!(a.snapshot().members.is_empty());
+    }
+
+    #[test]
+    fn received_windows_are_capped_and_ended_ones_dropped() {
+        let a = Gossip::new(&cluster("a", Vec::new()));
+        let now = unix_millis(SystemTime::now());
+        let message = Message {
+            node: "b".into(),
+            members: Vec::new(),
+            backoffs: HashMap::from([
+                ("10.0.0.5:8080".to_string(), u64::MAX),
+                ("10.0.0.6:8080".to_string(), now - 1000),
+            ]),
+            circuits: HashMap::from([("10.0.0.7:8080".to_string(), now + 30_000)]),
+        };
+        let mut packet = serde_json::to_vec(&message).unwrap();
+        packet.extend_from_slice(&mac(b"fleet-secret", &packet).finalize().into_bytes());
+        a.receive("127.0.0.1:7946".parse().unwrap(), &packet)
+            .unwrap();
+
+        let capped = a.backoff("10.0.0.5:8080", Duration::MAX).unwrap();
+        assert!(capped <= MAX_WINDOW, "{capped:?}");
+        let filtered = a.backoff("10.0.0.5:8080", Duration::from_secs(60)).unwrap();
+        assert!(filtered <= Duration::from_secs(60), "{filtered:?}");
+        // Once capped, the window stays capped.
+        let again = a.backoff("10.0.0.5:8080", Duration::MAX).unwrap();
+        assert!(again <= Duration::from_secs(60), "{again:?}");
+        assert!(a.backoff("10.0.0.6:8080", Duration::MAX).is_none());
+        assert!(!a.snapshot().backoffs.contains_key("10.0.0.6:8080"));
+
+        let open = a.open_circuit("10.0.0.7:8080", Duration::MAX).unwrap();
+        assert!(open > Duration::from_secs(28), "{open:?}");
+        assert!(a.open_circuit("10.0.0.5:8080", Duration::MAX).is_none());
+    }
+}
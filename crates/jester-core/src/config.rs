@@ -1,9 +1,30 @@
-use std::{collections::HashSet, net::SocketAddr, str::FromStr, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    str::FromStr,
+    time::Duration,
+};
 
-use anyhow::{bail, Context, Result};
-use http::Uri;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use http::{Method, Uri};
 use serde::{Deserialize, Serialize};
 
+use crate::builtins::{CidrSet, TimeoutConfig};
+
+mod builder;
+pub mod diagnostic;
+pub mod interpolate;
+mod load;
+pub mod overlay;
+mod strict;
+pub(crate) mod units;
+mod winpath;
+
+pub use builder::{ConfigBuilder, FilterBuilder, ListenerBuilder, MatchersBuilder, RouteBuilder};
+use diagnostic::ConfigPath;
+pub use load::{annotate, load, load_checked, ConfigSource};
+
 /// Root configuration structure deserialized from TOML/JSON/YAML.
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(default)]
@@ -12,312 +33,4489 @@ pub struct Config {
     pub listeners: Vec<Listener>,
     pub routes: Vec<Route>,
     pub plugins: Option<Plugins>,
+    /// Global filter chain applied to every request before route selection.
+    pub filters: Vec<Filter>,
+    /// On-demand debugging of individual requests.
+    pub debug: Option<DebugRequests>,
+    /// External provider for the feature flags that gate filters.
+    pub flags: Option<FeatureFlags>,
+    /// Per-request upstream selection for trusted internal callers; off unless set.
+    pub upstream_override: Option<UpstreamOverride>,
+    /// Certificates obtained and renewed from an ACME CA.
+    pub acme: Option<Acme>,
+    /// `Via` headers on forwarded requests and responses (RFC 9110 §7.6.3).
+    pub via: Via,
+    /// What tap subscribers receive beyond access events.
+    pub tap: TapOptions,
+    /// `robots.txt`, `security.txt`, and ACME challenges answered by the
+    /// proxy itself, before route matching.
+    pub well_known: Vec<WellKnown>,
+    /// Where route and upstream state changes are published.
+    pub events: Vec<EventSink>,
+    /// Gossip with other jester instances to share runtime state.
+    pub cluster: Option<Cluster>,
+    /// Pooling of upstream connections; routes may override it.
+    pub upstream_pool: UpstreamPool,
+    /// How this file itself is read.
+    pub meta: Meta,
+    /// Restrictions the process places on itself once its sockets are bound.
+    pub hardening: Option<Hardening>,
+    /// How long-lived streams are wound down when the proxy drains.
+    pub shutdown: Shutdown,
 }
 
+/// Settings about the config file rather than the proxy.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Meta {
+    /// Reject keys no setting accepts instead of ignoring them, as
+    /// `--strict` does.
+    pub strict: bool,
+}
+
+/// Draining of WebSocket and server-sent event (SSE) streams, which would
+/// otherwise outlive every request and be cut when the process exits.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Admin {
-    pub listen: String,
+#[serde(default, deny_unknown_fields)]
+pub struct Shutdown {
+    /// How long streams get to finish once told the proxy is draining
+    /// (WebSockets by a `1001 Going Away` close frame, SSE streams by a last
+    /// `retry` event); those still open afterwards are cut.
+    #[serde(deserialize_with = "units::secs", alias = "stream_notice")]
+    pub stream_notice_secs: u64,
+    /// Reconnection delay sent to SSE clients in that last event.
+    #[serde(deserialize_with = "units::millis", alias = "sse_retry")]
+    pub sse_retry_ms: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
-#[serde(default)]
-pub struct Listener {
-    pub name: String,
-    pub bind: String,
-    pub tls: Option<Tls>,
-    pub alpn: Option<Vec<String>>,
-    pub http: Option<HttpTweaks>,
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            stream_notice_secs: 10,
+            sse_retry_ms: 1000,
+        }
+    }
 }
 
+/// Sandboxing applied after every listener, the admin API, and gossip are
+/// bound, for deployments that must not be able to touch more of the host
+/// than they serve. Native dylib plugins are refused while it is set.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Tls {
-    pub cert: String,
-    pub key: String,
+#[serde(default, deny_unknown_fields)]
+pub struct Hardening {
+    /// Clear every capability, including the bounding and ambient sets.
+    pub drop_capabilities: bool,
+    /// Directory to `chroot` into; every path in the config must then be
+    /// relative to it.
+    pub chroot: Option<String>,
+    /// Confine filesystem access with Landlock to the certificate, plugin, and
+    /// ACME paths in the config plus `read_paths` and `write_paths`.
+    pub landlock: bool,
+    /// Further paths (files or directories) readable under Landlock.
+    pub read_paths: Vec<String>,
+    /// Further paths (files or directories) writable under Landlock.
+    pub write_paths: Vec<String>,
+    /// Install a seccomp filter refusing `execve`, `ptrace`, `mount`, module
+    /// loading, and other system calls a proxy never needs.
+    pub seccomp: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
-#[serde(default)]
-pub struct HttpTweaks {
-    pub max_header_bytes: Option<u32>,
-    pub request_timeout_secs: Option<u64>,
+impl Default for Hardening {
+    fn default() -> Self {
+        Self {
+            drop_capabilities: true,
+            chroot: None,
+            landlock: false,
+            read_paths: Vec::new(),
+            write_paths: Vec::new(),
+            seccomp: true,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
-#[serde(default)]
-pub struct Route {
-    pub name: String,
-    pub matchers: Matchers,
-    pub filters: Vec<Filter>,
-    pub upstream: Upstream,
+/// Membership and runtime state shared with other jester instances over UDP
+/// gossip. Each round sends this node's view to a few random peers; peers
+/// learned from others are contacted too, and dropped once they go quiet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cluster {
+    /// UDP address to gossip on.
+    pub bind: String,
+    /// Unique name of this instance; defaults to the hostname.
+    pub node: Option<String>,
+    /// Seed peers (`host:port`), resolved every round.
     #[serde(default)]
-    pub response_filters: Vec<Filter>,
+    pub peers: Vec<String>,
+    /// Key authenticating messages with HMAC-SHA256. Required unless `bind`
+    /// is a loopback address.
+    pub secret: Option<String>,
+    #[serde(
+        default = "default_gossip_interval_ms",
+        deserialize_with = "units::millis",
+        alias = "interval"
+    )]
+    pub interval_ms: u64,
+    /// Elects the one instance that runs singleton tasks such as ACME
+    /// renewals; every instance runs them when unset.
+    pub election: Option<Election>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
-#[serde(default)]
-pub struct Matchers {
-    pub hosts: Option<Vec<String>>,
-    pub path_prefix: Option<String>,
-    pub methods: Option<Vec<String>>,
-    pub headers: Option<Vec<HeaderMatch>>,
+fn default_gossip_interval_ms() -> u64 {
+    1000
 }
 
+/// Where the leader lease is kept. The holder renews it every third of
+/// `lease_secs`; others take over once it has not been renewed for
+/// `lease_secs`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct HeaderMatch {
-    pub name: String,
-    pub value: String,
+#[serde(tag = "backend", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Election {
+    /// A key set with `NX` and a TTL on the Redis server at `address`
+    /// (`host:port`).
+    Redis {
+        address: String,
+        #[serde(default = "default_election_key")]
+        key: String,
+        password: Option<String>,
+        #[serde(
+            default = "default_lease_secs",
+            deserialize_with = "units::secs",
+            alias = "lease_duration"
+        )]
+        lease_secs: u64,
+    },
+    /// A `coordination.k8s.io/v1` Lease, using the pod's service account.
+    Kubernetes {
+        #[serde(default = "default_election_key")]
+        lease: String,
+        /// Defaults to the pod's namespace.
+        namespace: Option<String>,
+        #[serde(
+            default = "default_lease_secs",
+            deserialize_with = "units::secs",
+            alias = "lease_duration"
+        )]
+        lease_secs: u64,
+    },
+}
+
+fn default_election_key() -> String {
+    "jester-leader".into()
+}
+
+fn default_lease_secs() -> u64 {
+    15
 }
 
+/// A destination for data-plane events, such as routes changing on reload
+/// or an upstream asking for backoff. Delivery is best effort.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-pub enum Filter {
-    #[serde(rename = "builtin")]
-    Builtin {
-        name: String,
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum EventSink {
+    /// `POST`s each event as JSON to `url`.
+    Webhook {
+        url: String,
         #[serde(default)]
-        config: serde_json::Value,
+        headers: BTreeMap<String, String>,
+        #[serde(
+            default = "default_event_timeout_secs",
+            deserialize_with = "units::secs",
+            alias = "timeout"
+        )]
+        timeout_secs: u64,
+        /// Kinds to send; empty for all.
+        #[serde(default)]
+        kinds: Vec<EventKind>,
     },
-    #[serde(rename = "wasm")]
-    Wasm {
-        name: String,
-        module: String,
+    /// Publishes each event as JSON on `subject` of the NATS server at
+    /// `address` (`host:port`).
+    Nats {
+        address: String,
+        subject: String,
         #[serde(default)]
-        config: serde_json::Value,
+        kinds: Vec<EventKind>,
     },
-    #[serde(rename = "inproc")]
-    InProc {
-        name: String,
-        symbol: String,
+    /// Produces each event as a JSON record on `topic`, bootstrapping from
+    /// the Kafka brokers in `brokers` (`host:port`).
+    Kafka {
+        brokers: Vec<String>,
+        topic: String,
         #[serde(default)]
-        config: serde_json::Value,
+        kinds: Vec<EventKind>,
     },
 }
 
-impl Default for Filter {
+fn default_event_timeout_secs() -> u64 {
+    5
+}
+
+/// What an event reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    RouteAdded,
+    RouteRemoved,
+    RouteChanged,
+    /// An upstream answered with `Retry-After` and is skipped until it elapses.
+    UpstreamBackoff,
+    /// The `circuit-breaker` filter stopped sending requests to an upstream.
+    CircuitOpened,
+    /// A trial request succeeded and the upstream's circuit closed again.
+    CircuitClosed,
+}
+
+/// Files served for `hosts` (every host when empty) without reaching a
+/// route. The first entry that matches the host and configures the
+/// requested path answers it.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct WellKnown {
+    pub hosts: Vec<String>,
+    /// Body of `/robots.txt`.
+    pub robots_txt: Option<String>,
+    /// Body of `/.well-known/security.txt` (RFC 9116).
+    pub security_txt: Option<String>,
+    /// Directory holding HTTP-01 key authorizations, one file per token,
+    /// served under `/.well-known/acme-challenge/`.
+    pub acme_challenge_dir: Option<String>,
+}
+
+/// Body capture for tap subscribers. Each direction keeps at most its limit
+/// in memory; bytes past it are only counted, and the capture is marked
+/// truncated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TapOptions {
+    pub capture_bodies: bool,
+    #[serde(deserialize_with = "units::bytes", alias = "max_request_body_size")]
+    pub max_request_body_bytes: usize,
+    #[serde(deserialize_with = "units::bytes", alias = "max_response_body_size")]
+    pub max_response_body_bytes: usize,
+}
+
+impl Default for TapOptions {
     fn default() -> Self {
-        Filter::Builtin {
-            name: String::new(),
-            config: serde_json::Value::Null,
+        Self {
+            capture_bodies: false,
+            max_request_body_bytes: 64 * 1024,
+            max_response_body_bytes: 64 * 1024,
         }
     }
 }
 
+/// Identifies this proxy in `Via` headers as `<node> (jester/<version>)`.
+/// Routes with `suppress_via` leave them out.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "strategy")]
-pub enum Upstream {
-    #[serde(rename = "single")]
-    Single { target: String },
-    #[serde(rename = "round_robin")]
-    RoundRobin { targets: Vec<String> },
-    #[serde(rename = "least_latency")]
-    LeastLatency { targets: Vec<String> },
-    #[serde(rename = "hash")]
-    Hash { targets: Vec<String>, key: String },
+#[serde(default, deny_unknown_fields)]
+pub struct Via {
+    pub enabled: bool,
+    /// Name of this instance; a pseudonym is fine, e.g. `"${HOSTNAME:jester}"`.
+    pub node: String,
 }
 
-impl Default for Upstream {
+impl Default for Via {
     fn default() -> Self {
-        Upstream::Single {
-            target: String::new(),
+        Self {
+            enabled: true,
+            node: "jester".into(),
         }
     }
 }
 
+/// Admin API endpoints; at least one of `listen` and `socket` must be set.
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(default)]
-pub struct Plugins {
-    pub search_paths: Vec<String>,
-    pub allow_unsafe_dylib: bool,
+pub struct Admin {
+    /// TCP address for plain-HTTP access; keep it on loopback.
+    pub listen: Option<String>,
+    /// Unix socket path used by `jester ctl`; created with owner-only permissions.
+    pub socket: Option<String>,
 }
 
-impl Config {
-    /// Validates structural invariants and provides actionable error messages.
-    pub fn validate(&self) -> Result<()> {
-        if self.listeners.is_empty() {
-            bail!("at least one listener is required");
-        }
-        let mut listener_names = HashSet::new();
-        for listener in &self.listeners {
-            listener.validate()?;
-            if !listener_names.insert(listener.name.clone()) {
-                bail!("duplicate listener name `{}`", listener.name);
-            }
-        }
+/// Requests whose `header` equals `secret` are logged at trace level and get a
+/// `Server-Timing` breakdown in their response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DebugRequests {
+    /// Header carrying the secret; always stripped before forwarding upstream.
+    #[serde(default = "default_debug_header")]
+    pub header: String,
+    pub secret: String,
+}
 
-        if self.routes.is_empty() {
-            bail!("at least one route is required");
-        }
-        let mut route_names = HashSet::new();
-        for route in &self.routes {
-            route.validate()?;
-            if !route_names.insert(route.name.clone()) {
-                bail!("duplicate route name `{}`", route.name);
-            }
+fn default_debug_header() -> String {
+    "x-jester-debug".into()
+}
+
+/// Requests from `trusted` networks may name their upstream in `header`
+/// (`http://10.0.3.7:8080`), replacing the route's target while its filters
+/// still run. Each use is logged under the `jester::audit` target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamOverride {
+    /// Always stripped before forwarding, whoever sent it.
+    #[serde(default = "default_override_header")]
+    pub header: String,
+    /// Client addresses or networks (`10.0.0.0/8`), matched against the
+    /// address resolved by the listener's `client_ip` policy.
+    pub trusted: Vec<String>,
+}
+
+fn default_override_header() -> String {
+    "x-jester-upstream".into()
+}
+
+/// Certificates issued by an ACME CA (Let's Encrypt by default) through DNS-01
+/// challenges, which also covers wildcard names. Each certificate is written to
+/// its `cert` and `key` paths; listeners using those paths pick up renewals
+/// without a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Acme {
+    /// The CA's directory URL.
+    #[serde(default = "default_acme_directory")]
+    pub directory: String,
+    /// Account contacts, e.g. `mailto:ops@example.com`.
+    #[serde(default)]
+    pub contact: Vec<String>,
+    /// Where the account key is kept; created on first use.
+    pub state_dir: String,
+    /// Renew certificates expiring within this many days.
+    #[serde(default = "default_renew_before_days")]
+    pub renew_before_days: u64,
+    /// How often certificates are checked for renewal.
+    #[serde(
+        default = "default_acme_check_interval_secs",
+        deserialize_with = "units::secs",
+        alias = "check_interval"
+    )]
+    pub check_interval_secs: u64,
+    /// Orders running at once; further certificates wait their turn, which
+    /// keeps a large fleet clear of CA rate limits.
+    #[serde(default = "default_max_concurrent_renewals")]
+    pub max_concurrent_renewals: usize,
+    /// Where challenge TXT records are published.
+    pub dns: DnsProvider,
+    #[serde(default)]
+    pub propagation: Propagation,
+    pub certificates: Vec<AcmeCertificate>,
+}
+
+fn default_acme_directory() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".into()
+}
+
+fn default_renew_before_days() -> u64 {
+    30
+}
+
+fn default_acme_check_interval_secs() -> u64 {
+    12 * 60 * 60
+}
+
+fn default_max_concurrent_renewals() -> usize {
+    2
+}
+
+/// One certificate covering `domains`, e.g. `["example.com", "*.example.com"]`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeCertificate {
+    pub domains: Vec<String>,
+    /// PEM chain written here after issuance.
+    pub cert: String,
+    /// PEM private key written here (mode `0600`) after issuance.
+    pub key: String,
+}
+
+/// DNS API used to publish `_acme-challenge` TXT records.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "provider", rename_all = "snake_case", deny_unknown_fields)]
+pub enum DnsProvider {
+    /// Cloudflare API with a token allowed to edit the zone's DNS.
+    Cloudflare {
+        api_token: String,
+        zone_id: String,
+        /// API base URL; defaults to `https://api.cloudflare.com/client/v4`.
+        endpoint: Option<String>,
+    },
+    /// AWS Route 53, signing requests with an access key.
+    Route53 {
+        access_key_id: String,
+        secret_access_key: String,
+        hosted_zone_id: String,
+        /// API base URL; defaults to `https://route53.amazonaws.com`.
+        endpoint: Option<String>,
+    },
+    /// RFC 2136 dynamic updates signed with a TSIG key, as BIND and Knot accept.
+    Rfc2136 {
+        /// Primary server receiving the updates, `ip:port`.
+        server: String,
+        zone: String,
+        tsig_key: String,
+        /// Base64 key secret.
+        tsig_secret: String,
+        #[serde(default)]
+        tsig_algorithm: TsigAlgorithm,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TsigAlgorithm {
+    #[default]
+    HmacSha256,
+    HmacSha512,
+}
+
+/// How long to wait for challenge records to become visible before asking the
+/// CA to check them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Propagation {
+    /// Resolvers (`ip` or `ip:port`) that must all serve the records. Defaults
+    /// to the RFC 2136 server, or to `1.1.1.1` and `8.8.8.8`.
+    pub resolvers: Vec<String>,
+    #[serde(deserialize_with = "units::secs", alias = "timeout")]
+    pub timeout_secs: u64,
+    #[serde(deserialize_with = "units::secs", alias = "interval")]
+    pub interval_secs: u64,
+}
+
+impl Default for Propagation {
+    fn default() -> Self {
+        Self {
+            resolvers: Vec::new(),
+            timeout_secs: 300,
+            interval_secs: 5,
         }
-        Ok(())
     }
+}
 
-    /// Returns parsed listeners with ready-to-bind socket addresses.
-    pub fn resolved_listeners(&self) -> Result<Vec<ResolvedListener>> {
-        self.listeners
-            .iter()
-            .map(ResolvedListener::try_from)
-            .collect()
-    }
+/// Feature flags polled from an external provider. A filter with `flag = "name"`
+/// runs only while that flag is on: `true`, or a number giving the percentage
+/// of clients (bucketed by IP) it is rolled out to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeatureFlags {
+    #[serde(default)]
+    pub provider: FlagProvider,
+    /// `http://` or `https://` endpoint; for `ofrep`, the provider's base URL.
+    pub url: Option<String>,
+    /// Local JSON document, for the `json` provider.
+    pub file: Option<String>,
+    #[serde(
+        default = "default_flag_refresh_secs",
+        deserialize_with = "units::secs",
+        alias = "refresh"
+    )]
+    pub refresh_secs: u64,
+    /// Whether gated filters run while their flag is unknown (before the first
+    /// successful fetch, or when the provider does not define it).
+    #[serde(default = "default_true")]
+    pub default_enabled: bool,
 }
 
-/// Runtime representation of a listener with parsed socket/tls config.
-#[derive(Debug, Clone)]
-pub struct ResolvedListener {
-    pub name: String,
-    pub addr: SocketAddr,
-    pub tls: Tls,
-    pub alpn: Vec<String>,
+fn default_flag_refresh_secs() -> u64 {
+    30
 }
 
-impl TryFrom<&Listener> for ResolvedListener {
-    type Error = anyhow::Error;
+fn default_true() -> bool {
+    true
+}
 
-    fn try_from(listener: &Listener) -> Result<Self> {
-        let addr = listener.parse_bind_addr()?;
-        let tls = listener
-            .tls
-            .clone()
-            .context("TLS configuration is required for every listener in v0.0.1")?;
-        let alpn = listener
-            .alpn
-            .clone()
-            .unwrap_or_else(|| vec!["h2".into(), "http/1.1".into()]);
-        Ok(Self {
-            name: listener.name.clone(),
-            addr,
-            tls,
-            alpn,
-        })
-    }
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlagProvider {
+    /// A JSON object of flag names to `true`/`false` or a rollout percentage.
+    #[default]
+    Json,
+    /// OpenFeature Remote Evaluation Protocol bulk evaluation, as served by flagd.
+    Ofrep,
 }
 
-impl Listener {
+impl FeatureFlags {
     pub fn validate(&self) -> Result<()> {
-        if self.name.trim().is_empty() {
-            bail!("listener name must not be empty");
+        match (&self.url, &self.file, self.provider) {
+            (Some(url), None, _) => {
+                let uri: http::Uri = url
+                    .parse()
+                    .with_context(|| format!("invalid flags url `{url}`"))?;
+                if !matches!(uri.scheme_str(), Some("http" | "https")) {
+                    bail!("flags url `{url}` must be an http:// or https:// URL");
+                }
+            }
+            (None, Some(_), FlagProvider::Json) => {}
+            (None, Some(_), FlagProvider::Ofrep) => bail!("the ofrep flag provider needs a `url`"),
+            _ => bail!("flags need exactly one of `url` or `file`"),
         }
-        self.parse_bind_addr()
-            .with_context(|| format!("invalid bind address for listener `{}`", self.name))?;
-        if let Some(tls) = &self.tls {
-            tls.validate()?;
-        } else {
-            bail!("listener `{}` must specify tls.cert and tls.key", self.name);
+        if self.refresh_secs == 0 {
+            bail!("flags `refresh_secs` must be at least 1");
         }
         Ok(())
     }
+}
 
-    pub fn parse_bind_addr(&self) -> Result<SocketAddr> {
-        if self.bind.starts_with(':') {
-            let addr = format!("0.0.0.0{}", self.bind);
-            Ok(SocketAddr::from_str(&addr)?)
-        } else {
-            Ok(SocketAddr::from_str(&self.bind)?)
-        }
-    }
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Listener {
+    pub name: String,
+    pub bind: String,
+    pub kind: ListenerKind,
+    /// Transport of a proxy listener: TCP with TLS, or QUIC for HTTP/3.
+    pub protocol: ListenerProtocol,
+    /// Port of the `https://` URLs an `https_redirect` listener sends clients
+    /// to; 443, which is left out of the URL, unless set.
+    pub redirect_port: Option<u16>,
+    /// HTTPS ports by the port a client addressed an `https_redirect`
+    /// listener on (as given in `Host`, 80 when left out), such as
+    /// `8080 = 8443` in development; other ports go to `redirect_port`.
+    pub redirect_ports: BTreeMap<u16, u16>,
+    /// Leave the query string out of the `https://` URLs.
+    pub redirect_strip_query: bool,
+    /// Certificate and key clients connect with. Without it (or with
+    /// `tls = "none"`) the listener speaks plain HTTP/1.1, for running behind
+    /// another TLS terminator or in local development.
+    #[serde(deserialize_with = "tls_or_none")]
+    pub tls: Option<Tls>,
+    /// Protocols offered through ALPN, in order of preference: `h2` and
+    /// `http/1.1` unless set. Clients that offer none of them, or offer no
+    /// ALPN at all while `http/1.1` is left out, are turned away.
+    pub alpn: Option<Vec<String>>,
+    pub http: Option<HttpTweaks>,
+    /// Treat `x-forwarded-*` headers from clients as set by a trusted proxy and
+    /// append to them instead of overwriting.
+    pub trust_forwarded_headers: bool,
+    /// Log each client connection's lifecycle (accept, TLS, close summary) under
+    /// the `jester::connection` target.
+    pub log_connections: bool,
+    /// Accept TLS 1.3 early data (0-RTT) from resuming clients. Requests read
+    /// from it may be replays, so only idempotent ones on routes with
+    /// `early_data` set are served; others get `425 Too Early`.
+    pub early_data: bool,
+    /// Requests without any host, typically HTTP/1.0 clients that send no `Host`.
+    pub missing_host: MissingHost,
+    /// Requests whose target is an absolute URI (`GET http://host/path`).
+    pub absolute_form: AbsoluteForm,
+    /// How the client's address is determined behind load balancers and CDNs.
+    pub client_ip: ClientIpPolicy,
+    /// Server names (`*.` wildcards cover one label) this listener answers.
+    /// Listeners binding the same address share one socket and are chosen by
+    /// the SNI a client sends; the one without `server_names` takes the rest.
+    pub server_names: Vec<String>,
+    /// Paths (e.g. `/elb-status`) answered at the listener for load balancer
+    /// health checks: `200` while the proxy serves, `503` once it drains.
+    pub health_check_paths: Vec<String>,
+    /// Where a `kind = "stream"` listener sends its connections' bytes.
+    pub stream: Option<StreamTarget>,
+    /// Where a `kind = "udp"` listener sends its clients' datagrams.
+    pub udp: Option<UdpTarget>,
 }
 
-impl Tls {
-    pub fn validate(&self) -> Result<()> {
-        if self.cert.trim().is_empty() || self.key.trim().is_empty() {
-            bail!("tls cert and key paths must be provided");
-        }
-        Ok(())
+/// What a listener does with its connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerKind {
+    /// Terminate TLS and proxy requests through the routes.
+    #[default]
+    Proxy,
+    /// Plain HTTP (typically port 80) answering every request with a
+    /// redirect to the same host and path over HTTPS.
+    HttpsRedirect,
+    /// Relay raw TCP bytes to the `stream` upstream without parsing HTTP, for
+    /// databases and other protocols, or pass TLS through by SNI.
+    Stream,
+    /// Relay UDP datagrams to the `udp` upstream, for DNS, QUIC backends, or
+    /// game servers.
+    Udp,
+}
+
+/// Where a `kind = "stream"` listener relays its connections.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StreamTarget {
+    /// `host:port` connected to for each client; names are resolved per
+    /// connection. With `sni`, it takes the clients no entry claims.
+    #[serde(default)]
+    pub upstream: Option<String>,
+    /// Upstreams chosen by the server name in the client's TLS ClientHello;
+    /// the TLS session itself passes through to the upstream.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sni: Vec<SniUpstream>,
+    /// Open each upstream connection with a PROXY protocol header carrying
+    /// the client's address.
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    /// Give up on an upstream that does not accept within this long; 10
+    /// seconds by default.
+    #[serde(
+        default = "default_stream_connect_timeout_secs",
+        deserialize_with = "units::secs",
+        alias = "connect_timeout"
+    )]
+    pub connect_timeout_secs: u64,
+    /// Close connections that carry no bytes either way for this long.
+    #[serde(default, deserialize_with = "units::opt_secs", alias = "idle_timeout")]
+    pub idle_timeout_secs: Option<u64>,
+}
+
+/// A stream upstream serving the TLS clients that ask for `server_names`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniUpstream {
+    /// Exact names, or `*.` wildcards covering one label.
+    pub server_names: Vec<String>,
+    pub upstream: String,
+}
+
+fn default_stream_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn validate_host_port(upstream: &str) -> Result<()> {
+    let authority = http::uri::Authority::from_str(upstream)
+        .ok()
+        .filter(|authority| !authority.as_str().contains('@'));
+    match authority {
+        Some(authority) if authority.port_u16().is_some() => Ok(()),
+        _ => bail!("upstream `{upstream}` must be `host:port`"),
     }
 }
 
-impl Route {
-    pub fn validate(&self) -> Result<()> {
-        if self.name.trim().is_empty() {
-            bail!("route name must not be empty");
-        }
-        if self
-            .matchers
-            .hosts
-            .as_ref()
-            .map_or(true, |hosts| hosts.is_empty())
-        {
-            bail!(
-                "route `{}` must declare at least one host matcher",
-                self.name
-            );
+impl StreamTarget {
+    pub fn new(upstream: impl Into<String>) -> Self {
+        Self {
+            upstream: Some(upstream.into()),
+            sni: Vec::new(),
+            proxy_protocol: None,
+            connect_timeout_secs: default_stream_connect_timeout_secs(),
+            idle_timeout_secs: None,
         }
-        self.upstream.validate()?;
-        Ok(())
     }
 
-    pub fn request_timeout(&self) -> Option<Duration> {
-        self.filters.iter().find_map(|filter| match filter {
-            Filter::Builtin { name, config } if name == "timeout" => config
-                .get("request_secs")?
-                .as_u64()
-                .map(Duration::from_secs),
-            _ => None,
-        })
+    /// Routes clients whose ClientHello names one of `server_names` to
+    /// `upstream`.
+    pub fn sni(mut self, server_names: &[&str], upstream: impl Into<String>) -> Self {
+        self.sni.push(SniUpstream {
+            server_names: server_names.iter().map(|name| name.to_string()).collect(),
+            upstream: upstream.into(),
+        });
+        self
     }
-}
 
-impl Upstream {
     pub fn validate(&self) -> Result<()> {
-        match self {
-            Upstream::Single { target } => {
-                Uri::from_str(target)
-                    .with_context(|| format!("invalid upstream target `{target}`"))?;
-                Ok(())
+        match &self.upstream {
+            Some(upstream) => validate_host_port(upstream)?,
+            None if self.sni.is_empty() => bail!("stream needs an `upstream` or `sni` entries"),
+            None => {}
+        }
+        let mut claimed = HashSet::new();
+        for entry in &self.sni {
+            validate_host_port(&entry.upstream)?;
+            if entry.server_names.is_empty() {
+                bail!("sni entry for `{}` has no server_names", entry.upstream);
             }
-            Upstream::RoundRobin { .. } | Upstream::LeastLatency { .. } | Upstream::Hash { .. } => {
-                bail!("upstream strategy `{:?}` is not supported in v0.0.1", self)
+            for name in &entry.server_names {
+                if !is_dns_name(name) {
+                    bail!("invalid server name `{name}` in sni");
+                }
+                if !claimed.insert(name.to_ascii_lowercase()) {
+                    bail!("server name `{name}` appears in more than one sni entry");
+                }
             }
         }
-    }
-
-    pub fn single_target(&self) -> Option<&str> {
-        match self {
-            Upstream::Single { target } => Some(target.as_str()),
-            _ => None,
+        if self.connect_timeout_secs == 0 {
+            bail!("stream `connect_timeout_secs` must be at least 1");
         }
+        if self.idle_timeout_secs == Some(0) {
+            bail!("stream `idle_timeout_secs` must be at least 1");
+        }
+        Ok(())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Where a `kind = "udp"` listener relays its datagrams.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UdpTarget {
+    /// `host:port` each client session sends to; names are resolved per
+    /// session.
+    pub upstream: String,
+    /// End a client's session once no datagram has passed either way for
+    /// this long; 30 seconds by default.
+    #[serde(
+        default = "default_udp_idle_timeout_secs",
+        deserialize_with = "units::secs",
+        alias = "idle_timeout"
+    )]
+    pub idle_timeout_secs: u64,
+    /// Drop datagrams from new clients while this many sessions are open.
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
+}
 
-    #[test]
+fn default_udp_idle_timeout_secs() -> u64 {
+    30
+}
+
+impl UdpTarget {
+    pub fn new(upstream: impl Into<String>) -> Self {
+        Self {
+            upstream: upstream.into(),
+            idle_timeout_secs: default_udp_idle_timeout_secs(),
+            max_sessions: None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        validate_host_port(&self.upstream)?;
+        if self.idle_timeout_secs == 0 {
+            bail!("udp `idle_timeout_secs` must be at least 1");
+        }
+        if self.max_sessions == Some(0) {
+            bail!("udp `max_sessions` must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+/// How a proxy listener's clients connect.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerProtocol {
+    /// TLS over TCP, serving HTTP/1.1 and HTTP/2 as ALPN picks.
+    #[default]
+    Tcp,
+    /// HTTP/3 over QUIC on a UDP socket, advertised to the TCP listeners'
+    /// clients through `Alt-Svc`.
+    H3,
+}
+
+/// Handling of requests that name no host.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum MissingHost {
+    /// Route with an empty host, so only routes without host matchers (or `*`) apply.
+    #[default]
+    Match,
+    /// Answer `400 Bad Request`.
+    Reject,
+    /// Treat the request as if it carried this `Host`.
+    Map(String),
+}
+
+impl TryFrom<String> for MissingHost {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        match value.as_str() {
+            "match" => Ok(MissingHost::Match),
+            "reject" => Ok(MissingHost::Reject),
+            other => match other.strip_prefix("map:") {
+                Some(host) if !host.is_empty() => {
+                    http::uri::Authority::from_str(host)
+                        .with_context(|| format!("invalid missing_host mapping `{host}`"))?;
+                    Ok(MissingHost::Map(host.to_string()))
+                }
+                _ => {
+                    bail!("missing_host must be `match`, `reject`, or `map:<host>`, got `{value}`")
+                }
+            },
+        }
+    }
+}
+
+impl From<MissingHost> for String {
+    fn from(value: MissingHost) -> Self {
+        match value {
+            MissingHost::Match => "match".into(),
+            MissingHost::Reject => "reject".into(),
+            MissingHost::Map(host) => format!("map:{host}"),
+        }
+    }
+}
+
+/// Handling of absolute-form request targets, as sent to forward proxies and by
+/// some health checkers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AbsoluteForm {
+    /// Use the target's authority as the host, replacing any `Host` header
+    /// (RFC 9112 §3.2.2), and forward the request in origin form.
+    #[default]
+    Accept,
+    /// Answer `400 Bad Request`.
+    Reject,
+}
+
+/// Where a listener learns the client's address. The result is what ip-filter,
+/// flags, websocket limits, upstream overrides, and access logs see.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientIpPolicy {
+    pub source: ClientIpSource,
+    /// Peers (addresses or CIDRs) whose header or PROXY protocol claims are
+    /// believed; claims from other peers are ignored.
+    pub trusted_proxies: Vec<String>,
+    /// Header naming the client when `source = "header"`, e.g. `cf-connecting-ip`.
+    pub header: Option<String>,
+    /// Most `x-forwarded-for` entries examined, counting from the right.
+    pub depth: usize,
+}
+
+impl Default for ClientIpPolicy {
+    fn default() -> Self {
+        Self {
+            source: ClientIpSource::Peer,
+            trusted_proxies: Vec::new(),
+            header: None,
+            depth: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIpSource {
+    /// The TCP peer.
+    #[default]
+    Peer,
+    /// The rightmost `x-forwarded-for` entry that is not a trusted proxy.
+    XForwardedFor,
+    /// A header holding a single address, as set by CDNs.
+    Header,
+    /// The source address of a PROXY protocol (v1 or v2) header preceding TLS.
+    ProxyProtocol,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tls {
+    pub cert: String,
+    pub key: String,
+    /// PEM bundle of intermediate (and private root) certificates appended to
+    /// `cert` as needed to complete its chain. Roots are never sent.
+    #[serde(default)]
+    pub intermediates: Option<String>,
+    /// More certificates, each served to clients whose SNI it names; `cert`
+    /// serves the others and clients that send no SNI.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub certificates: Vec<TlsCertificate>,
+    /// PEM bundle of the CAs whose client certificates are accepted. Setting
+    /// it asks every client for a certificate (mutual TLS).
+    #[serde(default)]
+    pub client_ca: Option<String>,
+    /// Whether clients must present a certificate; `required` when unset.
+    #[serde(default)]
+    pub client_auth: Option<ClientAuth>,
+    /// Oldest protocol version negotiated; `1.2` when unset.
+    #[serde(default)]
+    pub min_version: Option<TlsVersion>,
+    /// Newest protocol version negotiated; `1.3` when unset.
+    #[serde(default)]
+    pub max_version: Option<TlsVersion>,
+    /// Cipher suites allowed, by IANA name, in the server's order of
+    /// preference; every suite rustls supports when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cipher_suites: Vec<String>,
+    /// Key exchange groups allowed (`X25519`, `secp256r1`, `secp384r1`), in
+    /// order of preference; all three when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub curves: Vec<String>,
+}
+
+/// The cipher suite QUIC protects its initial packets with.
+const QUIC_INITIAL_SUITE: &str = "TLS13_AES_128_GCM_SHA256";
+
+/// A TLS protocol version, written `"1.2"` or `"1.3"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl std::fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Tls12 => "TLS 1.2",
+            Self::Tls13 => "TLS 1.3",
+        })
+    }
+}
+
+/// How a listener with `tls.client_ca` treats clients without a certificate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuth {
+    /// The handshake fails.
+    #[default]
+    Required,
+    /// They are served without one; certificates presented are still verified.
+    Optional,
+}
+
+/// A listener certificate for the clients asking for `server_names`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsCertificate {
+    /// Exact names, or `*.` wildcards covering one label.
+    pub server_names: Vec<String>,
+    pub cert: String,
+    pub key: String,
+    #[serde(default)]
+    pub intermediates: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct HttpTweaks {
+    #[serde(deserialize_with = "units::opt_bytes", alias = "max_header_size")]
+    pub max_header_bytes: Option<u32>,
+    #[serde(deserialize_with = "units::opt_secs", alias = "request_timeout")]
+    pub request_timeout_secs: Option<u64>,
+    /// Close HTTP/1 connections idle this long between requests; it also bounds
+    /// how long a client may take to send request headers. `0` turns off
+    /// keep-alive, so every connection serves one request.
+    #[serde(deserialize_with = "units::opt_secs", alias = "keep_alive_timeout")]
+    pub keep_alive_timeout_secs: Option<u64>,
+    /// Send `Connection: close` on the response to this many-th request (a
+    /// GOAWAY on HTTP/2), so clients reconnect and L4 load balancers can
+    /// spread them again.
+    pub max_requests_per_connection: Option<u64>,
+    /// Streams an HTTP/2 client may have open at once; hyper's default (200)
+    /// when unset.
+    pub h2_max_concurrent_streams: Option<u32>,
+    /// Ping idle HTTP/2 clients this often to notice dead connections.
+    #[serde(deserialize_with = "units::opt_secs", alias = "h2_keepalive_interval")]
+    pub h2_keepalive_interval_secs: Option<u64>,
+    /// Close an HTTP/2 connection whose ping goes unanswered this long.
+    #[serde(deserialize_with = "units::opt_secs", alias = "h2_keepalive_timeout")]
+    pub h2_keepalive_timeout_secs: Option<u64>,
+}
+
+impl HttpTweaks {
+    pub fn validate(&self) -> Result<()> {
+        if self.h2_max_concurrent_streams == Some(0) {
+            bail!("h2_max_concurrent_streams must be at least 1");
+        }
+        if self.h2_keepalive_timeout_secs.is_some() && self.h2_keepalive_interval_secs.is_none() {
+            bail!("h2_keepalive_timeout_secs needs h2_keepalive_interval_secs: it bounds the wait for a ping reply");
+        }
+        if self.max_requests_per_connection == Some(0) {
+            bail!("max_requests_per_connection must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Route {
+    pub name: String,
+    pub matchers: Matchers,
+    pub filters: Vec<Filter>,
+    pub upstream: Upstream,
+    #[serde(default)]
+    pub response_filters: Vec<Filter>,
+    /// Limits for WebSocket connections upgraded on this route.
+    pub websocket: WebsocketLimits,
+    /// How upstream response bodies are relayed as they arrive.
+    pub streaming: Streaming,
+    /// Serve idempotent requests that arrive in TLS early data; only set this
+    /// on routes where a replayed request is harmless.
+    pub early_data: bool,
+    /// What happens to requests that match everything but `methods`.
+    pub method_mismatch: MethodMismatch,
+    /// Leave out `Via` headers, which name jester and its node, on this route.
+    pub suppress_via: bool,
+    /// Retry failed upstream attempts; unset sends every request once.
+    pub retry: Option<RetryPolicy>,
+    /// Classification such as `team` or `tier`, added to the route's request
+    /// metrics, request span, and access events.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Labels jester's own route metrics already carry.
+const RESERVED_LABELS: &[&str] = &[
+    "route",
+    "kind",
+    "cause",
+    "reason",
+    "outcome",
+    "content_type",
+    "grpc_status",
+];
+
+/// Handling of requests whose method is not in a route's `methods` matcher.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MethodMismatch {
+    /// Keep looking at later routes, ending in `404` if none match.
+    #[default]
+    #[serde(rename = "fallthrough")]
+    Fallthrough,
+    /// Answer `405 Method Not Allowed` with an `Allow` header. Later routes
+    /// that declare `methods` are still tried, so one path can be split into
+    /// per-method routes; routes without `methods` are not.
+    #[serde(rename = "405")]
+    NotAllowed,
+}
+
+/// Unset fields mean "no limit".
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebsocketLimits {
+    /// Largest single frame a client may send.
+    #[serde(deserialize_with = "units::opt_bytes", alias = "max_frame_size")]
+    pub max_frame_bytes: Option<u64>,
+    /// Largest message (all fragments of a data frame sequence) a client may send.
+    #[serde(deserialize_with = "units::opt_bytes", alias = "max_message_size")]
+    pub max_message_bytes: Option<u64>,
+    /// Close the socket after this long without traffic in either direction;
+    /// pings and pongs count as traffic.
+    #[serde(deserialize_with = "units::opt_secs", alias = "idle_timeout")]
+    pub idle_timeout_secs: Option<u64>,
+    /// Ping both peers this often; one that has not answered the previous
+    /// ping by then is closed.
+    #[serde(deserialize_with = "units::opt_secs", alias = "ping_interval")]
+    pub ping_interval_secs: Option<u64>,
+    /// How long a peer has to answer a ping; defaults to the ping interval.
+    #[serde(deserialize_with = "units::opt_secs", alias = "pong_timeout")]
+    pub pong_timeout_secs: Option<u64>,
+    /// Concurrent sockets allowed per client IP on this route.
+    pub max_connections_per_client: Option<usize>,
+}
+
+/// Response bodies are relayed chunk by chunk as the upstream sends them, so
+/// server-sent events and long polls reach the client at once. Unset fields
+/// keep that default.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Streaming {
+    /// Hold chunks arriving this soon after the first unsent one and send
+    /// them together, trading latency for fewer, larger writes.
+    #[serde(deserialize_with = "units::opt_millis", alias = "flush_interval")]
+    pub flush_interval_ms: Option<u64>,
+    /// Abort a response whose upstream sends nothing for this long, so a
+    /// stalled stream does not hold its client forever.
+    #[serde(deserialize_with = "units::opt_secs", alias = "idle_timeout")]
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl Streaming {
+    pub fn validate(&self) -> Result<()> {
+        if self.flush_interval_ms == Some(0) {
+            bail!(
+                "flush_interval must be more than zero; leave it unset to send every chunk at once"
+            );
+        }
+        if self.idle_timeout_secs == Some(0) {
+            bail!("idle_timeout must be at least 1s");
+        }
+        Ok(())
+    }
+}
+
+/// When and how often a route retries an upstream attempt that failed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Tries per request including the first, so `1` never retries.
+    pub attempts: u32,
+    /// Failures worth another try.
+    pub retry_on: Vec<RetryOn>,
+    /// Upstream statuses retried on top of `retry_on`, such as `429`.
+    pub statuses: Vec<u16>,
+    /// Deadline for the response headers of each try; unset leaves only the
+    /// route's own timeout.
+    #[serde(deserialize_with = "units::opt_millis", alias = "per_try_timeout")]
+    pub per_try_timeout_ms: Option<u64>,
+    /// The pause before the first retry, doubled per retry up to
+    /// `backoff_max_ms`; the actual pause is a random fraction of it.
+    #[serde(deserialize_with = "units::millis", alias = "backoff_base")]
+    pub backoff_base_ms: u64,
+    #[serde(deserialize_with = "units::millis", alias = "backoff_max")]
+    pub backoff_max_ms: u64,
+    /// Retries allowed per request over a ten-second window, on top of
+    /// `budget_min_per_sec`, so a failing upstream is not hit with a multiple
+    /// of its usual load.
+    pub budget_ratio: f64,
+    /// Retries always allowed per second regardless of traffic.
+    pub budget_min_per_sec: u32,
+    /// Request bodies up to this size are held so they can be sent again;
+    /// larger or unsized ones are sent once.
+    #[serde(deserialize_with = "units::bytes", alias = "max_body_size")]
+    pub max_body_bytes: u64,
+    /// Also retry resets, timeouts, and statuses for non-idempotent methods
+    /// such as `POST`, which the upstream may already have acted on.
+    pub non_idempotent: bool,
+    /// A registered plugin that decides which failures are retried, and may
+    /// rewrite the request or pick another target for the retry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub advisor: Option<String>,
+    /// Passed to the advisor's plugin when the route is built.
+    #[serde(skip_serializing_if = "serde_json::Value::is_null")]
+    pub advisor_config: serde_json::Value,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            retry_on: vec![RetryOn::ConnectFailure, RetryOn::Reset],
+            statuses: Vec::new(),
+            per_try_timeout_ms: None,
+            backoff_base_ms: 25,
+            backoff_max_ms: 250,
+            budget_ratio: 0.2,
+            budget_min_per_sec: 10,
+            max_body_bytes: 64 * 1024,
+            non_idempotent: false,
+            advisor: None,
+            advisor_config: serde_json::Value::Null,
+        }
+    }
+}
+
+/// A failed try that `retry_on` can name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// No connection (or TLS session) to the upstream could be set up, so
+    /// the request was never sent; retried whatever the method.
+    ConnectFailure,
+    /// The connection failed after the request was sent, before a response.
+    Reset,
+    /// `per_try_timeout_ms` elapsed.
+    Timeout,
+    /// Any `5xx` answer.
+    #[serde(rename = "5xx")]
+    ServerError,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Matchers {
+    pub hosts: Option<Vec<String>>,
+    pub path_prefix: Option<String>,
+    pub methods: Option<Vec<String>>,
+    pub headers: Option<Vec<HeaderMatch>>,
+    /// Only match at the times this names, e.g. a maintenance window.
+    pub time: Option<TimeMatch>,
+}
+
+/// Times of day, read in `timezone`, when a route matches.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeMatch {
+    /// IANA time zone such as `Europe/Berlin`; UTC when unset.
+    pub timezone: Option<String>,
+    /// Five-field cron expressions (`minute hour day-of-month month
+    /// day-of-week`); the route matches during every minute one names.
+    pub cron: Vec<String>,
+    pub windows: Vec<TimeWindow>,
+}
+
+/// A daily window, `HH:MM` to `HH:MM`, running past midnight when `to` is
+/// earlier than `from`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeWindow {
+    pub from: String,
+    pub to: String,
+    /// Weekdays (`mon`, `tue`, ...) the window starts on; every day when empty.
+    #[serde(default)]
+    pub days: Vec<String>,
+}
+
+/// Methods of the IANA HTTP Method Registry, and `PURGE`, which caches take.
+const KNOWN_METHODS: &[&str] = &[
+    "ACL",
+    "BASELINE-CONTROL",
+    "BIND",
+    "CHECKIN",
+    "CHECKOUT",
+    "CONNECT",
+    "COPY",
+    "DELETE",
+    "GET",
+    "HEAD",
+    "LABEL",
+    "LINK",
+    "LOCK",
+    "MERGE",
+    "MKACTIVITY",
+    "MKCALENDAR",
+    "MKCOL",
+    "MKREDIRECTREF",
+    "MKWORKSPACE",
+    "MOVE",
+    "OPTIONS",
+    "ORDERPATCH",
+    "PATCH",
+    "POST",
+    "PRI",
+    "PROPFIND",
+    "PROPPATCH",
+    "PURGE",
+    "PUT",
+    "QUERY",
+    "REBIND",
+    "REPORT",
+    "SEARCH",
+    "TRACE",
+    "UNBIND",
+    "UNCHECKOUT",
+    "UNLINK",
+    "UNLOCK",
+    "UPDATE",
+    "UPDATEREDIRECTREF",
+    "VERSION-CONTROL",
+];
+
+/// Parses a `methods` entry. Names are case-insensitive and match in
+/// uppercase, as clients send them; uppercasing is ASCII-only, so it does not
+/// depend on the locale. Names outside [`KNOWN_METHODS`] are extension methods;
+/// [`Config::lint`] warns about those that look like a typo of a known one.
+fn parse_method(name: &str) -> Result<Method> {
+    Method::from_bytes(name.to_ascii_uppercase().as_bytes())
+        .map_err(|_| anyhow!("`{name}` is not a valid method name"))
+}
+
+/// The known method an extension method `name` is likely a typo of.
+fn method_typo_of(name: &str) -> Option<&'static str> {
+    let upper = name.to_ascii_uppercase();
+    if KNOWN_METHODS.contains(&upper.as_str()) {
+        return None;
+    }
+    KNOWN_METHODS
+        .iter()
+        .copied()
+        .find(|known| swaps_neighbours(&upper, known))
+        .or_else(|| strict::nearest(&upper, KNOWN_METHODS.iter().copied()))
+}
+
+/// Whether `a` is `b` with two neighbouring characters swapped, as in `GTE`.
+fn swaps_neighbours(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let differ: Vec<usize> = (0..a.len()).filter(|&i| a[i] != b[i]).collect();
+    matches!(differ[..], [i, j] if j == i + 1 && a[i] == b[j] && a[j] == b[i])
+}
+
+impl Matchers {
+    /// `methods` as request methods, failing on the first invalid one.
+    pub fn parsed_methods(&self) -> Result<Option<Vec<Method>>> {
+        let Some(names) = &self.methods else {
+            return Ok(None);
+        };
+        if names.is_empty() {
+            bail!("`methods` is empty, so nothing would match; leave it out to match every method");
+        }
+        names
+            .iter()
+            .map(|name| parse_method(name))
+            .collect::<Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    /// Whether every request matched by `other` is also matched by `self`.
+    ///
+    /// Conservative: only exact host lists and `*` are compared, so wildcard
+    /// hosts or header matchers on `self` never count as shadowing.
+    fn shadows(&self, other: &Matchers) -> bool {
+        let hosts = self.hosts.as_deref().unwrap_or_default();
+        let hosts_covered = hosts.iter().any(|host| host == "*")
+            || other.hosts.as_deref().is_some_and(|theirs| {
+                !theirs.is_empty()
+                    && theirs
+                        .iter()
+                        .all(|host| hosts.iter().any(|ours| ours.eq_ignore_ascii_case(host)))
+            });
+        let path_covered = self.path_prefix.as_deref().is_none_or(|prefix| {
+            other
+                .path_prefix
+                .as_deref()
+                .is_some_and(|theirs| theirs.starts_with(prefix))
+        });
+        let methods_covered = self.methods.as_deref().is_none_or(|methods| {
+            other.methods.as_deref().is_some_and(|theirs| {
+                theirs
+                    .iter()
+                    .all(|method| methods.iter().any(|ours| ours.eq_ignore_ascii_case(method)))
+            })
+        });
+        let headers_covered = self.headers.as_deref().is_none_or(<[_]>::is_empty);
+        hosts_covered && path_covered && methods_covered && headers_covered && self.time.is_none()
+    }
+}
+
+fn default_wasm_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+/// Ready instances a `wasm` filter keeps per chain, so requests do not wait
+/// for one to be instantiated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WasmPool {
+    /// Instances per chain, and so the requests one chain's filter runs at
+    /// once.
+    pub size: usize,
+    /// Instances made when the chain is built; the rest are made on demand.
+    /// Defaults to `size`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prewarm: Option<usize>,
+    /// Requests an instance serves before a fresh one replaces it, bounding
+    /// what a plugin can leak between requests; `0` keeps instances forever.
+    pub max_uses: u64,
+    /// What a request does when every instance is busy.
+    pub on_exhausted: PoolExhausted,
+    /// How long `wait` waits for an instance before answering `503`.
+    #[serde(deserialize_with = "units::millis", alias = "wait_timeout")]
+    pub wait_timeout_ms: u64,
+}
+
+impl Default for WasmPool {
+    fn default() -> Self {
+        Self {
+            size: 8,
+            prewarm: None,
+            max_uses: 10_000,
+            on_exhausted: PoolExhausted::Wait,
+            wait_timeout_ms: 100,
+        }
+    }
+}
+
+impl WasmPool {
+    pub fn validate(&self) -> Result<()> {
+        if self.size == 0 {
+            bail!("pool.size must be at least 1");
+        }
+        if self.prewarm.is_some_and(|prewarm| prewarm > self.size) {
+            bail!("pool.prewarm must not exceed pool.size ({})", self.size);
+        }
+        Ok(())
+    }
+
+    /// Instances made when the chain is built.
+    pub fn prewarm(&self) -> usize {
+        self.prewarm.unwrap_or(self.size)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PoolExhausted {
+    /// Wait up to `wait_timeout_ms` for an instance to be returned.
+    Wait,
+    /// Answer `503` at once.
+    Reject,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeaderMatch {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Filter {
+    #[serde(rename = "builtin")]
+    Builtin {
+        name: String,
+        #[serde(default)]
+        config: serde_json::Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        phase: Option<Phase>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        order: Option<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        flag: Option<String>,
+    },
+    #[serde(rename = "wasm")]
+    Wasm {
+        name: String,
+        /// A component built against the SDK's `filter` world, with its
+        /// manifest next to it as `<module stem>.json`.
+        module: String,
+        #[serde(default)]
+        config: serde_json::Value,
+        #[serde(default)]
+        pool: WasmPool,
+        /// Request bodies the filter sees whole; larger ones are answered
+        /// with `413`.
+        #[serde(
+            default = "default_wasm_max_body_bytes",
+            deserialize_with = "units::bytes",
+            alias = "max_body_size"
+        )]
+        max_body_bytes: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        phase: Option<Phase>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        order: Option<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        flag: Option<String>,
+    },
+    #[serde(rename = "inproc")]
+    InProc {
+        name: String,
+        symbol: String,
+        #[serde(default)]
+        config: serde_json::Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        phase: Option<Phase>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        order: Option<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        flag: Option<String>,
+    },
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::Builtin {
+            name: String::new(),
+            config: serde_json::Value::Null,
+            phase: None,
+            order: None,
+            flag: None,
+        }
+    }
+}
+
+/// Point in the request lifecycle at which a filter runs.
+///
+/// Phases execute in declaration order of the variants; within a phase, filters
+/// are sorted by their `order` key (default `0`) and then by position in the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Before route selection; only valid in the top-level `filters` chain.
+    PreRouting,
+    /// After route selection, before the request is forwarded upstream.
+    PreUpstream,
+    /// After the upstream (or an earlier filter) produced a response.
+    PostUpstream,
+    /// After the final response is ready; intended for observation only.
+    Logging,
+}
+
+impl Phase {
+    /// Returns `true` for phases that act on the request on its way in.
+    pub fn is_request_phase(self) -> bool {
+        matches!(self, Phase::PreRouting | Phase::PreUpstream)
+    }
+}
+
+impl Filter {
+    pub fn name(&self) -> &str {
+        match self {
+            Filter::Builtin { name, .. }
+            | Filter::Wasm { name, .. }
+            | Filter::InProc { name, .. } => name,
+        }
+    }
+
+    pub fn config(&self) -> &serde_json::Value {
+        match self {
+            Filter::Builtin { config, .. }
+            | Filter::Wasm { config, .. }
+            | Filter::InProc { config, .. } => config,
+        }
+    }
+
+    /// Explicit phase, if the filter overrides the default of its chain.
+    pub fn phase(&self) -> Option<Phase> {
+        match self {
+            Filter::Builtin { phase, .. }
+            | Filter::Wasm { phase, .. }
+            | Filter::InProc { phase, .. } => *phase,
+        }
+    }
+
+    pub fn order(&self) -> i32 {
+        match self {
+            Filter::Builtin { order, .. }
+            | Filter::Wasm { order, .. }
+            | Filter::InProc { order, .. } => order.unwrap_or_default(),
+        }
+    }
+
+    /// Feature flag (see [`FeatureFlags`]) gating this filter, if any.
+    pub fn flag(&self) -> Option<&str> {
+        match self {
+            Filter::Builtin { flag, .. }
+            | Filter::Wasm { flag, .. }
+            | Filter::InProc { flag, .. } => flag.as_deref(),
+        }
+    }
+}
+
+/// Where a route sends traffic (`strategy` plus its targets) and how connections to
+/// those targets behave.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Upstream {
+    #[serde(flatten)]
+    pub strategy: UpstreamStrategy,
+    /// Reuse pooled keep-alive connections; disable for backends that misbehave with
+    /// persistent connections.
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: bool,
+    /// `Host` sent upstream: `"upstream"` (target authority, default), `"preserve"`
+    /// (client's host), or `"custom:<value>"`.
+    #[serde(default)]
+    pub host_header: HostHeader,
+    /// Connection settings for `https://` targets.
+    #[serde(default)]
+    pub tls: UpstreamTls,
+    /// Overrides of the top-level `[upstream_pool]` for this route. A route
+    /// whose settings differ from another's gets a pool of its own.
+    #[serde(default)]
+    pub pool: UpstreamPool,
+    /// HTTP version spoken to the targets.
+    #[serde(default)]
+    pub protocol: UpstreamProtocol,
+    /// Open each connection with a PROXY protocol header carrying the
+    /// client's address, for backends that read it. Such connections are
+    /// never pooled, since each belongs to one client.
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+/// PROXY protocol header sent to upstream targets: the text `v1` or the
+/// binary `v2` format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+/// How requests reach upstream targets. HTTP/2 multiplexes requests over one
+/// connection per target rather than pooling a connection per request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProtocol {
+    #[default]
+    Http1,
+    /// HTTP/2 over TLS, negotiated with ALPN; `https://` targets only.
+    H2,
+    /// HTTP/2 with prior knowledge over cleartext; `http://` and `unix://`
+    /// targets only.
+    H2c,
+}
+
+fn default_keep_alive() -> bool {
+    true
+}
+
+/// TLS behaviour of connections to `https://` upstream targets.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamTls {
+    /// Cache session tickets so new connections to a target skip the full
+    /// handshake.
+    pub session_resumption: bool,
+    /// Send the start of a request as TLS 1.3 early data on resumed
+    /// connections. Early data can be replayed by an attacker; enable it only
+    /// for upstreams where every request is safe to repeat.
+    pub early_data: bool,
+    /// PEM bundle of CA certificates trusted instead of the bundled Mozilla
+    /// roots, for upstreams behind a private CA.
+    pub ca_file: Option<String>,
+    /// Accept any certificate the upstream presents. For development only:
+    /// it leaves the connection open to interception.
+    pub insecure_skip_verify: bool,
+}
+
+impl Default for UpstreamTls {
+    fn default() -> Self {
+        Self {
+            session_resumption: true,
+            early_data: false,
+            ca_file: None,
+            insecure_skip_verify: false,
+        }
+    }
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self {
+            strategy: UpstreamStrategy::default(),
+            keep_alive: default_keep_alive(),
+            host_header: HostHeader::default(),
+            tls: UpstreamTls::default(),
+            pool: UpstreamPool::default(),
+            protocol: UpstreamProtocol::default(),
+            proxy_protocol: None,
+        }
+    }
+}
+
+/// Pooled connections to upstream targets. Unset fields keep the defaults,
+/// or on a route, the top-level `[upstream_pool]` values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamPool {
+    /// Idle connections kept per target; unlimited by default.
+    pub max_idle_per_host: Option<usize>,
+    /// Close pooled connections idle this long; 90 seconds by default.
+    #[serde(deserialize_with = "units::opt_secs", alias = "idle_timeout")]
+    pub idle_timeout_secs: Option<u64>,
+    /// Send TCP keepalive probes once a connection has been idle this long,
+    /// so dead peers and dropped NAT entries are noticed; off by default.
+    #[serde(deserialize_with = "units::opt_secs", alias = "tcp_keepalive")]
+    pub tcp_keepalive_secs: Option<u64>,
+}
+
+impl UpstreamPool {
+    /// These settings with unset fields taken from `defaults`.
+    pub fn or(&self, defaults: &UpstreamPool) -> UpstreamPool {
+        UpstreamPool {
+            max_idle_per_host: self.max_idle_per_host.or(defaults.max_idle_per_host),
+            idle_timeout_secs: self.idle_timeout_secs.or(defaults.idle_timeout_secs),
+            tcp_keepalive_secs: self.tcp_keepalive_secs.or(defaults.tcp_keepalive_secs),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.idle_timeout_secs == Some(0) || self.tcp_keepalive_secs == Some(0) {
+            bail!("upstream pool timeouts must be greater than zero when set");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum HostHeader {
+    /// Forward the `Host` the client sent, for name-based virtual hosting.
+    Preserve,
+    /// Replace `Host` with the upstream target's authority.
+    #[default]
+    Upstream,
+    Custom(String),
+}
+
+impl TryFrom<String> for HostHeader {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        match value.as_str() {
+            "preserve" => Ok(HostHeader::Preserve),
+            "upstream" => Ok(HostHeader::Upstream),
+            other => match other.strip_prefix("custom:") {
+                Some(host) if !host.is_empty() => {
+                    http::HeaderValue::from_str(host)
+                        .with_context(|| format!("invalid custom host header `{host}`"))?;
+                    Ok(HostHeader::Custom(host.to_string()))
+                }
+                _ => bail!(
+                    "host_header must be `preserve`, `upstream`, or `custom:<value>`, got `{value}`"
+                ),
+            },
+        }
+    }
+}
+
+impl From<HostHeader> for String {
+    fn from(value: HostHeader) -> Self {
+        match value {
+            HostHeader::Preserve => "preserve".into(),
+            HostHeader::Upstream => "upstream".into(),
+            HostHeader::Custom(host) => format!("custom:{host}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "strategy")]
+pub enum UpstreamStrategy {
+    #[serde(rename = "single")]
+    Single { target: String },
+    #[serde(rename = "round_robin")]
+    RoundRobin { targets: Vec<UpstreamTarget> },
+    #[serde(rename = "least_latency")]
+    LeastLatency { targets: Vec<String> },
+    #[serde(rename = "hash")]
+    Hash {
+        targets: Vec<UpstreamTarget>,
+        key: String,
+    },
+    /// Targets discovered from the SRV records of `name`, looked up again
+    /// every `refresh_secs`; `resolvers` default to `/etc/resolv.conf`.
+    #[serde(rename = "srv")]
+    Srv {
+        name: String,
+        #[serde(default = "default_srv_scheme")]
+        scheme: String,
+        #[serde(default)]
+        resolvers: Vec<String>,
+        #[serde(
+            default = "default_srv_refresh_secs",
+            deserialize_with = "units::secs",
+            alias = "refresh"
+        )]
+        refresh_secs: u64,
+    },
+    /// Targets from the passing instances of Consul `service`, watched with
+    /// blocking queries against the agent at `address`.
+    #[serde(rename = "consul")]
+    Consul {
+        service: String,
+        #[serde(default = "default_consul_address")]
+        address: String,
+        #[serde(default = "default_srv_scheme")]
+        scheme: String,
+        /// Only instances carrying this tag.
+        tag: Option<String>,
+        datacenter: Option<String>,
+        /// ACL token; defaults to `CONSUL_HTTP_TOKEN`.
+        token: Option<String>,
+        /// Longest a blocking query waits for a change.
+        #[serde(
+            default = "default_consul_wait_secs",
+            deserialize_with = "units::secs",
+            alias = "wait"
+        )]
+        wait_secs: u64,
+    },
+}
+
+fn default_consul_address() -> String {
+    "http://127.0.0.1:8500".into()
+}
+
+fn default_consul_wait_secs() -> u64 {
+    300
+}
+
+fn default_srv_scheme() -> String {
+    "http".into()
+}
+
+fn default_srv_refresh_secs() -> u64 {
+    30
+}
+
+/// Largest target weight; keeps hash rings a manageable size.
+pub const MAX_TARGET_WEIGHT: u32 = 100;
+
+/// A balanced upstream target: a URL, or `{ url, weight }` to send it
+/// `weight` times the traffic of a target with the default weight of `1`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "TargetSpec", into = "TargetSpec")]
+pub struct UpstreamTarget {
+    pub url: String,
+    pub weight: u32,
+}
+
+impl UpstreamTarget {
+    pub fn weighted(url: impl Into<String>, weight: u32) -> Self {
+        Self {
+            url: url.into(),
+            weight,
+        }
+    }
+}
+
+impl From<String> for UpstreamTarget {
+    fn from(url: String) -> Self {
+        Self::weighted(url, 1)
+    }
+}
+
+impl From<&str> for UpstreamTarget {
+    fn from(url: &str) -> Self {
+        Self::weighted(url, 1)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum TargetSpec {
+    Url(String),
+    Weighted { url: String, weight: u32 },
+}
+
+impl From<TargetSpec> for UpstreamTarget {
+    fn from(spec: TargetSpec) -> Self {
+        match spec {
+            TargetSpec::Url(url) => url.into(),
+            TargetSpec::Weighted { url, weight } => Self::weighted(url, weight),
+        }
+    }
+}
+
+impl From<UpstreamTarget> for TargetSpec {
+    fn from(target: UpstreamTarget) -> Self {
+        match target.weight {
+            1 => TargetSpec::Url(target.url),
+            weight => TargetSpec::Weighted {
+                url: target.url,
+                weight,
+            },
+        }
+    }
+}
+
+impl Default for UpstreamStrategy {
+    fn default() -> Self {
+        UpstreamStrategy::Single {
+            target: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Plugins {
+    pub search_paths: Vec<String>,
+    pub allow_unsafe_dylib: bool,
+}
+
+impl Config {
+    /// Validates structural invariants and provides actionable error messages.
+    pub fn validate(&self) -> Result<()> {
+        if self.listeners.is_empty() {
+            bail!("at least one listener is required");
+        }
+        let mut listener_names = HashSet::new();
+        let mut sockets: BTreeMap<SocketAddr, Vec<&Listener>> = BTreeMap::new();
+        let mut udp_sockets: HashMap<SocketAddr, &str> = HashMap::new();
+        for (index, listener) in self.listeners.iter().enumerate() {
+            let at = || diagnostic::at(ConfigPath::entry("listeners", index));
+            listener.validate().map_err(at())?;
+            if !listener_names.insert(listener.name.clone()) {
+                return Err(at()(anyhow!("duplicate listener name `{}`", listener.name)));
+            }
+            let addr = listener.parse_bind_addr().map_err(at())?;
+            // Port 0 asks for a fresh ephemeral port, so it is never shared.
+            if addr.port() == 0 {
+                continue;
+            }
+            // HTTP/3 and udp listeners bind UDP, so only they can clash with
+            // each other.
+            if listener.protocol == ListenerProtocol::H3 || listener.kind == ListenerKind::Udp {
+                if let Some(other) = udp_sockets.insert(addr, &listener.name) {
+                    return Err(at()(anyhow!(
+                        "listeners `{other}` and `{}` both bind UDP {addr}",
+                        listener.name
+                    )));
+                }
+            } else {
+                sockets.entry(addr).or_default().push(listener);
+            }
+        }
+        for (addr, sharing) in &sockets {
+            validate_shared_socket(*addr, sharing)?;
+        }
+
+        let needs_routes = self
+            .listeners
+            .iter()
+            .any(|listener| !matches!(listener.kind, ListenerKind::Stream | ListenerKind::Udp));
+        if self.routes.is_empty() && needs_routes {
+            bail!("at least one route is required");
+        }
+        let mut route_names = HashSet::new();
+        for (index, route) in self.routes.iter().enumerate() {
+            let at = || diagnostic::at(ConfigPath::entry("routes", index));
+            route.validate().map_err(at())?;
+            if !route_names.insert(route.name.clone()) {
+                return Err(at()(anyhow!("duplicate route name `{}`", route.name)));
+            }
+        }
+
+        for (index, filter) in self.filters.iter().enumerate() {
+            let at = || diagnostic::at(ConfigPath::entry("filters", index));
+            if filter.phase() == Some(Phase::PreUpstream) {
+                return Err(at()(anyhow!(
+                    "global filter `{}` cannot run in the pre_upstream phase; declare it on a route",
+                    filter.name()
+                )));
+            }
+            if let Filter::Wasm { pool, .. } = filter {
+                pool.validate()
+                    .with_context(|| format!("invalid wasm filter `{}`", filter.name()))
+                    .map_err(at())?;
+            }
+        }
+
+        let table = |key| diagnostic::at(ConfigPath::default().key(key));
+        if let Some(admin) = &self.admin {
+            admin.validate().map_err(table("admin"))?;
+        }
+        if let Some(debug) = &self.debug {
+            debug.validate().map_err(table("debug"))?;
+        }
+        if let Some(upstream_override) = &self.upstream_override {
+            upstream_override
+                .validate()
+                .map_err(table("upstream_override"))?;
+        }
+        if let Some(acme) = &self.acme {
+            acme.validate().map_err(table("acme"))?;
+        }
+        self.via.validate().map_err(table("via"))?;
+        self.upstream_pool
+            .validate()
+            .map_err(table("upstream_pool"))?;
+        for (index, well_known) in self.well_known.iter().enumerate() {
+            well_known.validate().map_err(diagnostic::at(
+                ConfigPath::default().key("well_known").index(index),
+            ))?;
+        }
+        for (index, sink) in self.events.iter().enumerate() {
+            sink.validate()
+                .map_err(diagnostic::at(ConfigPath::entry("events", index)))?;
+        }
+        if let Some(cluster) = &self.cluster {
+            cluster.validate().map_err(table("cluster"))?;
+        }
+        if let Some(hardening) = &self.hardening {
+            hardening.validate().map_err(table("hardening"))?;
+            if self
+                .plugins
+                .as_ref()
+                .is_some_and(|plugins| plugins.allow_unsafe_dylib)
+            {
+                bail!("hardening refuses native dylib plugins; unset plugins.allow_unsafe_dylib");
+            }
+        }
+        if let Some(flags) = &self.flags {
+            flags.validate().map_err(table("flags"))?;
+        } else if let Some(filter) = self
+            .filters
+            .iter()
+            .chain(
+                self.routes
+                    .iter()
+                    .flat_map(|route| route.filters.iter().chain(&route.response_filters)),
+            )
+            .find(|filter| filter.flag().is_some())
+        {
+            bail!(
+                "filter `{}` is gated on a feature flag but no [flags] provider is configured",
+                filter.name()
+            );
+        }
+        Ok(())
+    }
+
+    /// Reports likely mistakes in an otherwise valid configuration.
+    pub fn lint(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if let Some(addr) = self
+            .admin
+            .as_ref()
+            .and_then(|admin| admin.parse_listen_addr().ok().flatten())
+            .filter(|addr| !addr.ip().is_loopback())
+        {
+            warnings.push(format!(
+                "admin API listens on non-loopback address {addr}; it is unauthenticated"
+            ));
+        }
+        for (index, route) in self.routes.iter().enumerate() {
+            if let Some(earlier) = self.routes[..index]
+                .iter()
+                .find(|earlier| earlier.matchers.shadows(&route.matchers))
+            {
+                warnings.push(format!(
+                    "route `{}` is unreachable: route `{}` matches all of its requests first",
+                    route.name, earlier.name
+                ));
+            }
+            for name in route.matchers.methods.iter().flatten() {
+                if let Some(known) = method_typo_of(name) {
+                    warnings.push(format!(
+                        "route `{}` matches extension method `{}`; did you mean `{known}`?",
+                        route.name,
+                        name.to_ascii_uppercase()
+                    ));
+                }
+            }
+            if route.method_mismatch == MethodMismatch::NotAllowed
+                && route.matchers.methods.is_none()
+            {
+                warnings.push(format!(
+                    "route `{}` sets method_mismatch = \"405\" but declares no methods",
+                    route.name
+                ));
+            }
+        }
+        warnings
+    }
+
+    /// Returns parsed proxy listeners with ready-to-bind socket addresses;
+    /// `https_redirect` listeners are left out.
+    pub fn resolved_listeners(&self) -> Result<Vec<ResolvedListener>> {
+        self.listeners
+            .iter()
+            .filter(|listener| listener.kind == ListenerKind::Proxy)
+            .map(ResolvedListener::try_from)
+            .collect()
+    }
+}
+
+/// Runtime representation of a listener with parsed socket/tls config.
+#[derive(Debug, Clone)]
+pub struct ResolvedListener {
+    pub name: String,
+    pub addr: SocketAddr,
+    pub protocol: ListenerProtocol,
+    /// `None` for a plaintext listener.
+    pub tls: Option<Tls>,
+    pub alpn: Vec<String>,
+    pub trust_forwarded_headers: bool,
+    pub log_connections: bool,
+    pub early_data: bool,
+    pub missing_host: MissingHost,
+    pub absolute_form: AbsoluteForm,
+    pub client_ip: ClientIpPolicy,
+    pub server_names: Vec<String>,
+    pub health_check_paths: Vec<String>,
+    pub http: HttpTweaks,
+}
+
+impl TryFrom<&Listener> for ResolvedListener {
+    type Error = anyhow::Error;
+
+    fn try_from(listener: &Listener) -> Result<Self> {
+        let addr = listener.parse_bind_addr()?;
+        let tls = listener.tls.clone();
+        let alpn = match &tls {
+            Some(_) => listener
+                .alpn
+                .clone()
+                .unwrap_or_else(|| vec!["h2".into(), "http/1.1".into()]),
+            None => vec!["http/1.1".into()],
+        };
+        Ok(Self {
+            name: listener.name.clone(),
+            addr,
+            protocol: listener.protocol,
+            tls,
+            alpn,
+            trust_forwarded_headers: listener.trust_forwarded_headers,
+            log_connections: listener.log_connections,
+            early_data: listener.early_data,
+            missing_host: listener.missing_host.clone(),
+            absolute_form: listener.absolute_form,
+            client_ip: listener.client_ip.clone(),
+            server_names: listener
+                .server_names
+                .iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+            health_check_paths: listener.health_check_paths.clone(),
+            http: listener.http.clone().unwrap_or_default(),
+        })
+    }
+}
+
+/// ALPN protocols a TCP listener can serve.
+const ALPN_PROTOCOLS: [&str; 2] = ["h2", "http/1.1"];
+
+fn validate_alpn(alpn: &[String]) -> Result<()> {
+    if alpn.is_empty() {
+        bail!("at least one of `h2` and `http/1.1` is required");
+    }
+    let mut seen = HashSet::new();
+    for protocol in alpn {
+        if !ALPN_PROTOCOLS.contains(&protocol.as_str()) {
+            bail!("unknown protocol `{protocol}`; expected `h2` or `http/1.1`");
+        }
+        if !seen.insert(protocol) {
+            bail!("`{protocol}` is listed twice");
+        }
+    }
+    Ok(())
+}
+
+impl Listener {
+    pub fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            bail!("listener name must not be empty");
+        }
+        self.parse_bind_addr()
+            .with_context(|| format!("invalid bind address for listener `{}`", self.name))?;
+        match self.kind {
+            ListenerKind::HttpsRedirect => return self.validate_redirect(),
+            ListenerKind::Stream => return self.validate_stream(),
+            ListenerKind::Udp => return self.validate_udp(),
+            ListenerKind::Proxy => {}
+        }
+        if self.stream.is_some() {
+            bail!(
+                "listener `{}` sets stream but is not `kind = \"stream\"`",
+                self.name
+            );
+        }
+        if self.udp.is_some() {
+            bail!(
+                "listener `{}` sets udp but is not `kind = \"udp\"`",
+                self.name
+            );
+        }
+        if self.protocol == ListenerProtocol::H3 {
+            self.validate_h3()?;
+        }
+        if let Some(setting) = self.redirect_setting() {
+            bail!(
+                "listener `{}` sets {setting} but is not `kind = \"https_redirect\"`",
+                self.name
+            );
+        }
+        match &self.tls {
+            Some(tls) => tls.validate()?,
+            None => self.validate_plaintext()?,
+        }
+        // Early data is served before the client's certificate is verified.
+        if self.early_data && self.tls.as_ref().is_some_and(|tls| tls.client_ca.is_some()) {
+            bail!(
+                "listener `{}` cannot take both `early_data` and `tls.client_ca`",
+                self.name
+            );
+        }
+        if let Some(alpn) = &self.alpn {
+            validate_alpn(alpn)
+                .with_context(|| format!("invalid alpn for listener `{}`", self.name))?;
+        }
+        if let Some(http) = &self.http {
+            http.validate()
+                .with_context(|| format!("invalid http settings for listener `{}`", self.name))?;
+        }
+        self.client_ip
+            .validate()
+            .with_context(|| format!("invalid client_ip for listener `{}`", self.name))?;
+        if let Some(name) = self.server_names.iter().find(|name| !is_dns_name(name)) {
+            bail!("invalid server name `{name}` for listener `{}`", self.name);
+        }
+        self.validate_health_check_paths()
+    }
+
+    fn validate_health_check_paths(&self) -> Result<()> {
+        let mut seen = HashSet::new();
+        for path in &self.health_check_paths {
+            if !path.starts_with('/') || path.contains(['?', '#']) {
+                bail!(
+                    "health check path `{path}` for listener `{}` must be a path starting with `/`",
+                    self.name
+                );
+            }
+            if !seen.insert(path) {
+                bail!(
+                    "health check path `{path}` is listed twice for listener `{}`",
+                    self.name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse_bind_addr(&self) -> Result<SocketAddr> {
+        parse_socket_addr(&self.bind)
+    }
+
+    /// Redirect listeners speak plain HTTP and never reach the routes, so the
+    /// TLS and routing settings do not apply to them.
+    fn validate_redirect(&self) -> Result<()> {
+        let unsupported = [
+            ("tls", self.tls.is_some()),
+            ("alpn", self.alpn.is_some()),
+            ("early_data", self.early_data),
+            ("server_names", !self.server_names.is_empty()),
+            ("protocol", self.protocol != ListenerProtocol::Tcp),
+        ];
+        if let Some((setting, _)) = unsupported.iter().find(|(_, set)| *set) {
+            bail!(
+                "https_redirect listener `{}` does not take `{setting}`",
+                self.name
+            );
+        }
+        if self.redirect_port == Some(0) {
+            bail!("listener `{}` has redirect_port 0", self.name);
+        }
+        if let Some((from, to)) = self
+            .redirect_ports
+            .iter()
+            .find(|(from, to)| **from == 0 || **to == 0)
+        {
+            bail!(
+                "listener `{}` maps port {from} to {to} in redirect_ports; ports start at 1",
+                self.name
+            );
+        }
+        self.validate_health_check_paths()
+    }
+
+    /// The first setting only `https_redirect` listeners take that this one
+    /// sets.
+    fn redirect_setting(&self) -> Option<&'static str> {
+        [
+            ("redirect_port", self.redirect_port.is_some()),
+            ("redirect_ports", !self.redirect_ports.is_empty()),
+            ("redirect_strip_query", self.redirect_strip_query),
+        ]
+        .into_iter()
+        .find_map(|(setting, set)| set.then_some(setting))
+    }
+
+    /// Stream listeners relay bytes without reading TLS or HTTP, so only the
+    /// PROXY protocol part of `client_ip` applies to them.
+    fn validate_stream(&self) -> Result<()> {
+        let unsupported = [
+            ("tls", self.tls.is_some()),
+            ("alpn", self.alpn.is_some()),
+            ("early_data", self.early_data),
+            ("server_names", !self.server_names.is_empty()),
+            ("protocol", self.protocol != ListenerProtocol::Tcp),
+            ("http", self.http.is_some()),
+            ("trust_forwarded_headers", self.trust_forwarded_headers),
+            ("health_check_paths", !self.health_check_paths.is_empty()),
+            ("udp", self.udp.is_some()),
+        ];
+        let setting = unsupported
+            .iter()
+            .find_map(|(setting, set)| set.then_some(*setting))
+            .or(self.redirect_setting());
+        if let Some(setting) = setting {
+            bail!("stream listener `{}` does not take `{setting}`", self.name);
+        }
+        if !matches!(
+            self.client_ip.source,
+            ClientIpSource::Peer | ClientIpSource::ProxyProtocol
+        ) {
+            bail!(
+                "stream listener `{}` can only take the client from the peer or a PROXY protocol header",
+                self.name
+            );
+        }
+        self.client_ip
+            .validate()
+            .with_context(|| format!("invalid client_ip for listener `{}`", self.name))?;
+        let Some(stream) = &self.stream else {
+            bail!("stream listener `{}` needs a `stream` table", self.name);
+        };
+        stream
+            .validate()
+            .with_context(|| format!("invalid stream settings for listener `{}`", self.name))
+    }
+
+    /// Udp listeners relay datagrams without reading them, and a datagram
+    /// has no room for a PROXY protocol header, so the client is the peer.
+    fn validate_udp(&self) -> Result<()> {
+        let unsupported = [
+            ("tls", self.tls.is_some()),
+            ("alpn", self.alpn.is_some()),
+            ("early_data", self.early_data),
+            ("server_names", !self.server_names.is_empty()),
+            ("protocol", self.protocol != ListenerProtocol::Tcp),
+            ("http", self.http.is_some()),
+            ("trust_forwarded_headers", self.trust_forwarded_headers),
+            ("health_check_paths", !self.health_check_paths.is_empty()),
+            ("stream", self.stream.is_some()),
+            ("client_ip", self.client_ip.source != ClientIpSource::Peer),
+        ];
+        let setting = unsupported
+            .iter()
+            .find_map(|(setting, set)| set.then_some(*setting))
+            .or(self.redirect_setting());
+        if let Some(setting) = setting {
+            bail!("udp listener `{}` does not take `{setting}`", self.name);
+        }
+        let Some(udp) = &self.udp else {
+            bail!("udp listener `{}` needs a `udp` table", self.name);
+        };
+        udp.validate()
+            .with_context(|| format!("invalid udp settings for listener `{}`", self.name))
+    }
+
+    /// Without TLS there is no ALPN, early data, or SNI, and HTTP/3 cannot
+    /// run at all.
+    fn validate_plaintext(&self) -> Result<()> {
+        let unsupported = [
+            ("protocol = \"h3\"", self.protocol == ListenerProtocol::H3),
+            ("alpn", self.alpn.is_some()),
+            ("early_data", self.early_data),
+            ("server_names", !self.server_names.is_empty()),
+        ];
+        if let Some((setting, _)) = unsupported.iter().find(|(_, set)| *set) {
+            bail!(
+                "plaintext listener `{}` does not take `{setting}`; it needs a `tls` certificate",
+                self.name
+            );
+        }
+        Ok(())
+    }
+
+    /// HTTP/3 always negotiates `h3`, a QUIC socket serves one certificate,
+    /// and UDP carries no PROXY protocol header, so those settings do not apply.
+    fn validate_h3(&self) -> Result<()> {
+        let unsupported = [
+            ("alpn", self.alpn.is_some()),
+            ("early_data", self.early_data),
+            ("server_names", !self.server_names.is_empty()),
+            (
+                "client_ip.source = \"proxy_protocol\"",
+                self.client_ip.source == ClientIpSource::ProxyProtocol,
+            ),
+        ];
+        if let Some((setting, _)) = unsupported.iter().find(|(_, set)| *set) {
+            bail!("h3 listener `{}` does not take `{setting}`", self.name);
+        }
+        let Some(tls) = &self.tls else {
+            return Ok(());
+        };
+        // QUIC only runs over TLS 1.3, and protects its first packets with
+        // this suite whatever the handshake settles on.
+        if tls.max_version == Some(TlsVersion::Tls12) {
+            bail!(
+                "h3 listener `{}` needs TLS 1.3 but sets `tls.max_version = \"1.2\"`",
+                self.name
+            );
+        }
+        if !tls.cipher_suites.is_empty()
+            && !tls
+                .cipher_suites
+                .iter()
+                .any(|suite| suite.eq_ignore_ascii_case(QUIC_INITIAL_SUITE))
+        {
+            bail!(
+                "h3 listener `{}` needs {QUIC_INITIAL_SUITE} in `tls.cipher_suites`",
+                self.name
+            );
+        }
+        Ok(())
+    }
+}
+
+impl ClientIpPolicy {
+    pub fn validate(&self) -> Result<()> {
+        CidrSet::parse(&self.trusted_proxies).context("invalid `trusted_proxies` entry")?;
+        if self.source != ClientIpSource::Peer && self.trusted_proxies.is_empty() {
+            bail!("`trusted_proxies` must list the proxies allowed to name the client");
+        }
+        match (&self.header, self.source) {
+            (Some(header), ClientIpSource::Header) => {
+                http::HeaderName::from_bytes(header.as_bytes())
+                    .with_context(|| format!("invalid client ip header name `{header}`"))?;
+            }
+            (None, ClientIpSource::Header) => bail!("`source = \"header\"` needs a `header`"),
+            (Some(_), _) => bail!("`header` only applies to `source = \"header\"`"),
+            (None, _) => {}
+        }
+        if self.depth == 0 {
+            bail!("`depth` must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+impl Via {
+    pub fn validate(&self) -> Result<()> {
+        // A `Via` pseudonym is a token: no spaces, commas, or comments.
+        let valid = !self.node.is_empty()
+            && self
+                .node
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~:".contains(&byte));
+        if !valid {
+            bail!(
+                "via.node `{}` must be a single token such as a hostname",
+                self.node
+            );
+        }
+        Ok(())
+    }
+}
+
+impl WellKnown {
+    pub fn validate(&self) -> Result<()> {
+        if self.robots_txt.is_none()
+            && self.security_txt.is_none()
+            && self.acme_challenge_dir.is_none()
+        {
+            bail!(
+                "well_known entry for {:?} sets none of robots_txt, security_txt, acme_challenge_dir",
+                self.hosts
+            );
+        }
+        if self.hosts.iter().any(|host| host.trim().is_empty()) {
+            bail!("well_known hosts must not be empty strings");
+        }
+        if let Some(security_txt) = &self.security_txt {
+            // RFC 9116 §2.5: `Contact` and `Expires` are required fields.
+            for field in ["contact", "expires"] {
+                let present = security_txt.lines().any(|line| {
+                    line.split_once(':')
+                        .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case(field))
+                });
+                if !present {
+                    bail!("well_known security_txt needs a `{field}:` field (RFC 9116)");
+                }
+            }
+        }
+        if self
+            .acme_challenge_dir
+            .as_ref()
+            .is_some_and(|dir| dir.trim().is_empty())
+        {
+            bail!("well_known acme_challenge_dir must not be empty");
+        }
+        Ok(())
+    }
+}
+
+impl Hardening {
+    pub fn validate(&self) -> Result<()> {
+        if !cfg!(target_os = "linux") {
+            bail!("hardening is only supported on Linux");
+        }
+        if let Some(root) = &self.chroot {
+            if !root.starts_with('/') {
+                bail!("hardening chroot must be an absolute path, got `{root}`");
+            }
+        }
+        if let Some(path) = self
+            .read_paths
+            .iter()
+            .chain(&self.write_paths)
+            .find(|path| path.trim().is_empty())
+        {
+            bail!("hardening paths must not be empty, got {path:?}");
+        }
+        if !self.landlock && (!self.read_paths.is_empty() || !self.write_paths.is_empty()) {
+            bail!("hardening read_paths and write_paths require landlock = true");
+        }
+        Ok(())
+    }
+}
+
+impl Cluster {
+    pub fn validate(&self) -> Result<()> {
+        let bind = self
+            .bind
+            .parse::<SocketAddr>()
+            .with_context(|| format!("invalid cluster.bind `{}`", self.bind))?;
+        if self
+            .node
+            .as_deref()
+            .is_some_and(|node| node.trim().is_empty())
+        {
+            bail!("cluster.node must not be empty");
+        }
+        for peer in &self.peers {
+            let valid = peer
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+            if !valid {
+                bail!("cluster peer `{peer}` must be host:port");
+            }
+        }
+        match self.secret.as_deref() {
+            Some("") => bail!("cluster.secret must not be empty"),
+            None if !bind.ip().is_loopback() => bail!(
+                "cluster.secret is required when cluster.bind `{}` is not a loopback address",
+                self.bind
+            ),
+            _ => {}
+        }
+        if self.interval_ms < 100 {
+            bail!("cluster.interval_ms must be at least 100");
+        }
+        if let Some(election) = &self.election {
+            election.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl Election {
+    pub fn validate(&self) -> Result<()> {
+        let (name, lease_secs) = match self {
+            Election::Redis {
+                address,
+                key,
+                lease_secs,
+                ..
+            } => {
+                let valid = address
+                    .rsplit_once(':')
+                    .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+                if !valid {
+                    bail!("cluster.election address `{address}` must be host:port");
+                }
+                (key, lease_secs)
+            }
+            Election::Kubernetes {
+                lease,
+                namespace,
+                lease_secs,
+            } => {
+                if namespace.as_deref().is_some_and(str::is_empty) {
+                    bail!("cluster.election namespace must not be empty");
+                }
+                (lease, lease_secs)
+            }
+        };
+        if name.is_empty() {
+            bail!("cluster.election lease name must not be empty");
+        }
+        if *lease_secs < 3 {
+            bail!("cluster.election lease_secs must be at least 3");
+        }
+        Ok(())
+    }
+}
+
+impl EventSink {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            EventSink::Webhook {
+                url,
+                headers,
+                timeout_secs,
+                ..
+            } => {
+                let uri: http::Uri = url
+                    .parse()
+                    .with_context(|| format!("invalid event webhook url `{url}`"))?;
+                if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.host().is_none() {
+                    bail!("event webhook url `{url}` must be an http:// or https:// URL");
+                }
+                for (name, value) in headers {
+                    http::HeaderName::from_bytes(name.as_bytes())
+                        .with_context(|| format!("invalid event webhook header `{name}`"))?;
+                    http::HeaderValue::from_str(value).with_context(|| {
+                        format!("invalid value for event webhook header `{name}`")
+                    })?;
+                }
+                if *timeout_secs == 0 {
+                    bail!("event webhook `timeout_secs` must be at least 1");
+                }
+            }
+            EventSink::Nats {
+                address, subject, ..
+            } => {
+                let (host, port) = address.rsplit_once(':').unwrap_or_default();
+                if host.is_empty() || port.parse::<u16>().is_err() {
+                    bail!("event NATS address `{address}` must be `host:port`");
+                }
+                // Subjects are dot-separated tokens without whitespace; publishers
+                // may not use the `*` and `>` wildcards.
+                if subject.is_empty()
+                    || subject.split('.').any(|token| token.is_empty())
+                    || subject
+                        .chars()
+                        .any(|c| c.is_whitespace() || c == '*' || c == '>')
+                {
+                    bail!("invalid event NATS subject `{subject}`");
+                }
+            }
+            EventSink::Kafka { brokers, topic, .. } => {
+                if brokers.is_empty() {
+                    bail!("event Kafka sink needs at least one broker");
+                }
+                for broker in brokers {
+                    let (host, port) = broker.rsplit_once(':').unwrap_or_default();
+                    if host.is_empty() || port.parse::<u16>().is_err() {
+                        bail!("event Kafka broker `{broker}` must be `host:port`");
+                    }
+                }
+                if topic.is_empty()
+                    || topic.len() > 249
+                    || topic == "."
+                    || topic == ".."
+                    || !topic
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+                {
+                    bail!("invalid event Kafka topic `{topic}`");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the sink wants events of `kind`.
+    pub fn accepts(&self, kind: EventKind) -> bool {
+        let (EventSink::Webhook { kinds, .. }
+        | EventSink::Nats { kinds, .. }
+        | EventSink::Kafka { kinds, .. }) = self;
+        kinds.is_empty() || kinds.contains(&kind)
+    }
+}
+
+impl DebugRequests {
+    pub fn validate(&self) -> Result<()> {
+        http::HeaderName::from_bytes(self.header.as_bytes())
+            .with_context(|| format!("invalid debug header name `{}`", self.header))?;
+        if self.secret.trim().is_empty() {
+            bail!("debug.secret must not be empty");
+        }
+        Ok(())
+    }
+}
+
+impl UpstreamOverride {
+    pub fn validate(&self) -> Result<()> {
+        http::HeaderName::from_bytes(self.header.as_bytes())
+            .with_context(|| format!("invalid upstream override header name `{}`", self.header))?;
+        if self.trusted.is_empty() {
+            bail!("upstream_override.trusted must list at least one network");
+        }
+        CidrSet::parse(&self.trusted).context("invalid upstream_override.trusted entry")?;
+        Ok(())
+    }
+}
+
+impl Acme {
+    pub fn validate(&self) -> Result<()> {
+        let directory: Uri = self
+            .directory
+            .parse()
+            .with_context(|| format!("invalid acme.directory `{}`", self.directory))?;
+        if !matches!(directory.scheme_str(), Some("http" | "https")) {
+            bail!(
+                "acme.directory `{}` must be an https:// URL",
+                self.directory
+            );
+        }
+        if self.state_dir.trim().is_empty() {
+            bail!("acme.state_dir must not be empty");
+        }
+        if self.max_concurrent_renewals == 0 {
+            bail!("acme.max_concurrent_renewals must be at least 1");
+        }
+        if self.check_interval_secs == 0 || self.propagation.interval_secs == 0 {
+            bail!("acme intervals must be at least 1 second");
+        }
+        self.dns.validate()?;
+        for resolver in &self.propagation.resolvers {
+            parse_resolver(resolver)
+                .with_context(|| format!("invalid acme.propagation resolver `{resolver}`"))?;
+        }
+        if self.certificates.is_empty() {
+            bail!("acme.certificates must list at least one certificate");
+        }
+        for certificate in &self.certificates {
+            certificate.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl AcmeCertificate {
+    pub fn validate(&self) -> Result<()> {
+        if self.domains.is_empty() {
+            bail!("acme certificate for `{}` lists no domains", self.cert);
+        }
+        if let Some(domain) = self.domains.iter().find(|domain| !is_dns_name(domain)) {
+            bail!("invalid acme domain `{domain}`");
+        }
+        if self.cert.trim().is_empty() || self.key.trim().is_empty() {
+            bail!(
+                "acme certificate for {:?} needs `cert` and `key` paths",
+                self.domains
+            );
+        }
+        Ok(())
+    }
+}
+
+impl DnsProvider {
+    pub fn validate(&self) -> Result<()> {
+        let required: &[(&str, &str)] = match self {
+            DnsProvider::Cloudflare {
+                api_token, zone_id, ..
+            } => &[("api_token", api_token), ("zone_id", zone_id)],
+            DnsProvider::Route53 {
+                access_key_id,
+                secret_access_key,
+                hosted_zone_id,
+                ..
+            } => &[
+                ("access_key_id", access_key_id),
+                ("secret_access_key", secret_access_key),
+                ("hosted_zone_id", hosted_zone_id),
+            ],
+            DnsProvider::Rfc2136 {
+                server,
+                zone,
+                tsig_key,
+                tsig_secret,
+                ..
+            } => {
+                parse_resolver(server)
+                    .with_context(|| format!("invalid rfc2136 server `{server}`"))?;
+                BASE64
+                    .decode(tsig_secret.trim())
+                    .context("rfc2136 tsig_secret must be base64")?;
+                &[("zone", zone), ("tsig_key", tsig_key)]
+            }
+        };
+        if let Some((field, _)) = required.iter().find(|(_, value)| value.trim().is_empty()) {
+            bail!("acme.dns.{field} must not be empty");
+        }
+        if let DnsProvider::Cloudflare {
+            endpoint: Some(endpoint),
+            ..
+        }
+        | DnsProvider::Route53 {
+            endpoint: Some(endpoint),
+            ..
+        } = self
+        {
+            let uri: Uri = endpoint
+                .parse()
+                .with_context(|| format!("invalid acme.dns.endpoint `{endpoint}`"))?;
+            if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.host().is_none() {
+                bail!("acme.dns.endpoint `{endpoint}` must be an http:// or https:// URL");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a DNS server address, defaulting to port 53.
+pub(crate) fn parse_resolver(value: &str) -> Result<SocketAddr> {
+    if let Ok(ip) = value.parse::<std::net::IpAddr>() {
+        return Ok(SocketAddr::new(ip, 53));
+    }
+    Ok(value.parse::<SocketAddr>()?)
+}
+
+impl Admin {
+    pub fn validate(&self) -> Result<()> {
+        if self.listen.is_none() && self.socket.is_none() {
+            bail!("admin requires `listen`, `socket`, or both");
+        }
+        if self
+            .socket
+            .as_ref()
+            .is_some_and(|path| path.trim().is_empty())
+        {
+            bail!("admin.socket must not be empty");
+        }
+        self.parse_listen_addr()
+            .context("invalid admin listen address")?;
+        Ok(())
+    }
+
+    pub fn parse_listen_addr(&self) -> Result<Option<SocketAddr>> {
+        self.listen.as_deref().map(parse_socket_addr).transpose()
+    }
+}
+
+/// Listeners bound to one address are told apart by SNI, so each name picks
+/// exactly one of them and they agree on what precedes the TLS handshake.
+fn validate_shared_socket(addr: SocketAddr, listeners: &[&Listener]) -> Result<()> {
+    if listeners.len() > 1 {
+        if let Some(redirect) = listeners
+            .iter()
+            .find(|listener| listener.kind == ListenerKind::HttpsRedirect)
+        {
+            bail!(
+                "https_redirect listener `{}` cannot share {addr} with other listeners",
+                redirect.name
+            );
+        }
+        if let Some(stream) = listeners
+            .iter()
+            .find(|listener| listener.kind == ListenerKind::Stream)
+        {
+            bail!(
+                "stream listener `{}` cannot share {addr} with other listeners",
+                stream.name
+            );
+        }
+        if let Some(plaintext) = listeners.iter().find(|listener| listener.tls.is_none()) {
+            bail!(
+                "plaintext listener `{}` cannot share {addr} with other listeners",
+                plaintext.name
+            );
+        }
+    }
+    let mut fallback: Option<&str> = None;
+    let mut claimed: HashMap<String, &str> = HashMap::new();
+    for listener in listeners {
+        if listener.server_names.is_empty() {
+            if let Some(other) = fallback.replace(&listener.name) {
+                bail!(
+                    "listeners `{other}` and `{}` share {addr} without server_names; \
+                     at most one of them may omit it",
+                    listener.name
+                );
+            }
+        }
+        for name in &listener.server_names {
+            if let Some(other) = claimed.insert(name.to_ascii_lowercase(), &listener.name) {
+                bail!(
+                    "server name `{name}` on {addr} is claimed by both `{other}` and `{}`",
+                    listener.name
+                );
+            }
+        }
+        if listener.client_ip != listeners[0].client_ip {
+            bail!(
+                "listeners `{}` and `{}` share {addr} but not their client_ip policy",
+                listeners[0].name,
+                listener.name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A DNS name, optionally with a leading `*.` wildcard label.
+fn is_dns_name(name: &str) -> bool {
+    name.strip_prefix("*.")
+        .unwrap_or(name)
+        .split('.')
+        .all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// Parses `host:port`, treating a bare `:port` as all interfaces.
+fn parse_socket_addr(addr: &str) -> Result<SocketAddr> {
+    if addr.starts_with(':') {
+        Ok(SocketAddr::from_str(&format!("0.0.0.0{addr}"))?)
+    } else {
+        Ok(SocketAddr::from_str(addr)?)
+    }
+}
+
+/// Reads a listener's `tls` table, or `"none"` for a plaintext listener.
+fn tls_or_none<'de, D>(deserializer: D) -> std::result::Result<Option<Tls>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct TlsOrNone;
+
+    impl<'de> serde::de::Visitor<'de> for TlsOrNone {
+        type Value = Option<Tls>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a table with `cert` and `key`, or \"none\"")
+        }
+
+        fn visit_str<E: serde::de::Error>(
+            self,
+            value: &str,
+        ) -> std::result::Result<Self::Value, E> {
+            match value {
+                "none" => Ok(None),
+                other => Err(E::invalid_value(serde::de::Unexpected::Str(other), &self)),
+            }
+        }
+
+        fn visit_unit<E: serde::de::Error>(self) -> std::result::Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_map<A: serde::de::MapAccess<'de>>(
+            self,
+            map: A,
+        ) -> std::result::Result<Self::Value, A::Error> {
+            Tls::deserialize(serde::de::value::MapAccessDeserializer::new(map)).map(Some)
+        }
+    }
+
+    deserializer.deserialize_any(TlsOrNone)
+}
+
+impl Tls {
+    pub fn validate(&self) -> Result<()> {
+        if self.cert.trim().is_empty() || self.key.trim().is_empty() {
+            bail!("tls cert and key paths must be provided");
+        }
+        if self
+            .intermediates
+            .as_ref()
+            .is_some_and(|path| path.trim().is_empty())
+        {
+            bail!("tls intermediates path must not be empty");
+        }
+        match (&self.client_ca, self.client_auth) {
+            (Some(path), _) if path.trim().is_empty() => {
+                bail!("tls client_ca path must not be empty")
+            }
+            (None, Some(_)) => bail!("tls client_auth needs client_ca to verify certificates with"),
+            _ => {}
+        }
+        crate::tls::Policy::new(self)?;
+        let mut claimed = HashSet::new();
+        for certificate in &self.certificates {
+            if certificate.cert.trim().is_empty() || certificate.key.trim().is_empty() {
+                bail!("tls certificates need cert and key paths");
+            }
+            if certificate.server_names.is_empty() {
+                bail!("tls certificate `{}` has no server_names", certificate.cert);
+            }
+            for name in &certificate.server_names {
+                if !is_dns_name(name) {
+                    bail!(
+                        "invalid server name `{name}` for tls certificate `{}`",
+                        certificate.cert
+                    );
+                }
+                if !claimed.insert(name.to_ascii_lowercase()) {
+                    bail!("server name `{name}` appears in more than one tls certificate");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Every certificate, key, and intermediates file the listener reads.
+    pub fn files(&self) -> Vec<&str> {
+        let mut files = vec![self.cert.as_str(), self.key.as_str()];
+        files.extend(self.intermediates.as_deref());
+        files.extend(self.client_ca.as_deref());
+        for certificate in &self.certificates {
+            files.extend([certificate.cert.as_str(), certificate.key.as_str()]);
+            files.extend(certificate.intermediates.as_deref());
+        }
+        files
+    }
+
+    /// Whether `cert` is one of the certificates served.
+    pub fn serves(&self, cert: &str) -> bool {
+        self.cert == cert
+            || self
+                .certificates
+                .iter()
+                .any(|certificate| certificate.cert == cert)
+    }
+}
+
+impl Route {
+    pub fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            bail!("route name must not be empty");
+        }
+        if self
+            .matchers
+            .hosts
+            .as_ref()
+            .is_none_or(|hosts| hosts.is_empty())
+        {
+            bail!(
+                "route `{}` must declare at least one host matcher",
+                self.name
+            );
+        }
+        for filter in self.filters.iter().chain(&self.response_filters) {
+            if filter.phase() == Some(Phase::PreRouting) {
+                bail!(
+                    "filter `{}` on route `{}` cannot run in the pre_routing phase; move it to the top-level `filters` chain",
+                    filter.name(),
+                    self.name
+                );
+            }
+            if let Filter::Wasm { pool, .. } = filter {
+                pool.validate().with_context(|| {
+                    format!(
+                        "invalid wasm filter `{}` on route `{}`",
+                        filter.name(),
+                        self.name
+                    )
+                })?;
+            }
+        }
+        self.matchers
+            .parsed_methods()
+            .with_context(|| format!("invalid methods on route `{}`", self.name))?;
+        if let Some(time) = &self.matchers.time {
+            crate::schedule::Schedule::new(time)
+                .with_context(|| format!("invalid time matcher on route `{}`", self.name))?;
+        }
+        self.upstream.validate()?;
+        self.websocket
+            .validate()
+            .with_context(|| format!("invalid websocket limits on route `{}`", self.name))?;
+        self.streaming
+            .validate()
+            .with_context(|| format!("invalid streaming settings on route `{}`", self.name))?;
+        if let Some(retry) = &self.retry {
+            retry
+                .validate()
+                .with_context(|| format!("invalid retry policy on route `{}`", self.name))?;
+        }
+        for name in self.labels.keys() {
+            let mut chars = name.chars();
+            let valid = chars
+                .next()
+                .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid || name.starts_with("__") {
+                bail!(
+                    "label `{name}` on route `{}` is not a valid metric label name",
+                    self.name
+                );
+            }
+            if RESERVED_LABELS.contains(&name.as_str()) {
+                bail!(
+                    "label `{name}` on route `{}` is reserved for jester's own metrics",
+                    self.name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.filters.iter().find_map(|filter| match filter {
+            Filter::Builtin { name, config, .. } if name == "timeout" => {
+                let config = TimeoutConfig::deserialize(config).ok()?;
+                Some(Duration::from_secs(config.request_secs))
+            }
+            _ => None,
+        })
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=10).contains(&self.attempts) {
+            bail!("attempts must be between 1 and 10");
+        }
+        if self.per_try_timeout_ms == Some(0) {
+            bail!("per_try_timeout_ms must be greater than zero when set");
+        }
+        if self.backoff_base_ms > self.backoff_max_ms {
+            bail!("backoff_base_ms must not exceed backoff_max_ms");
+        }
+        if !(0.0..=1.0).contains(&self.budget_ratio) {
+            bail!("budget_ratio must be between 0 and 1");
+        }
+        if let Some(status) = self
+            .statuses
+            .iter()
+            .find(|status| !(100..=599).contains(*status))
+        {
+            bail!("{status} is not an HTTP status");
+        }
+        if self.advisor.is_none() && !self.advisor_config.is_null() {
+            bail!("advisor_config needs an advisor");
+        }
+        Ok(())
+    }
+}
+
+impl WebsocketLimits {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_frame_bytes == Some(0)
+            || self.max_message_bytes == Some(0)
+            || self.idle_timeout_secs == Some(0)
+            || self.ping_interval_secs == Some(0)
+            || self.pong_timeout_secs == Some(0)
+            || self.max_connections_per_client == Some(0)
+        {
+            bail!("limits must be greater than zero when set");
+        }
+        if self.pong_timeout_secs.is_some() && self.ping_interval_secs.is_none() {
+            bail!("pong_timeout needs a ping_interval");
+        }
+        Ok(())
+    }
+}
+
+impl Upstream {
+    pub fn validate(&self) -> Result<()> {
+        match &self.strategy {
+            UpstreamStrategy::Single { target } => {
+                crate::client::parse_target(target)?;
+            }
+            UpstreamStrategy::RoundRobin { .. } | UpstreamStrategy::Hash { .. } => {
+                crate::balance::Balancer::new(&self.strategy)?;
+            }
+            UpstreamStrategy::Srv {
+                name,
+                scheme,
+                resolvers,
+                refresh_secs,
+            } => {
+                crate::balance::SrvTargets::new(name, scheme, resolvers, *refresh_secs)?;
+            }
+            UpstreamStrategy::Consul { .. } => {
+                crate::balance::ConsulTargets::new(&self.strategy)?;
+            }
+            strategy @ UpstreamStrategy::LeastLatency { .. } => {
+                bail!("upstream strategy `{strategy:?}` is not supported in v0.0.1")
+            }
+        }
+        if self.tls.early_data && !self.tls.session_resumption {
+            bail!("upstream `tls.early_data` requires `tls.session_resumption`");
+        }
+        if let Some(ca_file) = &self.tls.ca_file {
+            if self.tls.insecure_skip_verify {
+                bail!(
+                    "upstream `tls.ca_file` and `tls.insecure_skip_verify` are mutually exclusive"
+                );
+            }
+            crate::client::load_roots(ca_file)?;
+        }
+        self.validate_protocol()?;
+        if self.proxy_protocol.is_some() && self.protocol != UpstreamProtocol::Http1 {
+            bail!(
+                "upstream `proxy_protocol` needs protocol `http1`: HTTP/2 carries requests \
+                 from many clients over one connection"
+            );
+        }
+        self.pool.validate()
+    }
+
+    /// Checks that the schemes of the targets suit `protocol`.
+    fn validate_protocol(&self) -> Result<()> {
+        let allowed: &[&str] = match self.protocol {
+            UpstreamProtocol::Http1 => return Ok(()),
+            UpstreamProtocol::H2 => &["https"],
+            UpstreamProtocol::H2c => &["http", "unix"],
+        };
+        let urls: Vec<&str> = match &self.strategy {
+            UpstreamStrategy::Single { target } => vec![target],
+            UpstreamStrategy::RoundRobin { targets } | UpstreamStrategy::Hash { targets, .. } => {
+                targets.iter().map(|target| target.url.as_str()).collect()
+            }
+            UpstreamStrategy::LeastLatency { targets } => {
+                targets.iter().map(String::as_str).collect()
+            }
+            UpstreamStrategy::Srv { scheme, .. } | UpstreamStrategy::Consul { scheme, .. } => {
+                vec![scheme]
+            }
+        };
+        for url in urls {
+            let scheme = url.split_once("://").map_or(url, |(scheme, _)| scheme);
+            if !allowed.contains(&scheme) {
+                match self.protocol {
+                    UpstreamProtocol::H2 => bail!(
+                        "upstream protocol `h2` needs https:// targets, got `{url}`; use `h2c` for HTTP/2 without TLS"
+                    ),
+                    _ => bail!(
+                        "upstream protocol `h2c` is HTTP/2 without TLS, got `{url}`; use `h2` for https:// targets"
+                    ),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn single_target(&self) -> Option<&str> {
+        match &self.strategy {
+            UpstreamStrategy::Single { target } => Some(target.as_str()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
     fn listener_bind_shorthand_defaults_to_all_interfaces() {
         let listener = Listener {
             name: "test".into(),
             bind: ":8080".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            listener.parse_bind_addr().unwrap(),
+            SocketAddr::from_str("0.0.0.0:8080").unwrap()
+        );
+    }
+
+    #[test]
+    fn https_redirect_listeners_take_no_tls_settings() {
+        let parse = |toml: &str| toml::from_str::<Listener>(toml).unwrap();
+        let redirect = parse(
+            r#"
+            name = "http"
+            bind = ":80"
+            kind = "https_redirect"
+            redirect_port = 8443
+            redirect_ports = { 8080 = 8443, 80 = 443 }
+            "#,
+        );
+        assert_eq!(redirect.kind, ListenerKind::HttpsRedirect);
+        assert_eq!(redirect.redirect_ports, [(80, 443), (8080, 8443)].into());
+        assert!(redirect.validate().is_ok());
+        let with_tls = Listener {
             tls: Some(Tls {
                 cert: "cert".into(),
                 key: "key".into(),
+                intermediates: None,
+                certificates: Vec::new(),
+                client_ca: None,
+                client_auth: None,
+                min_version: None,
+                max_version: None,
+                cipher_suites: Vec::new(),
+                curves: Vec::new(),
             }),
-            alpn: None,
-            http: None,
+            ..redirect.clone()
         };
+        assert!(with_tls.validate().unwrap_err().to_string().contains("tls"));
+        let proxy = Listener {
+            kind: ListenerKind::Proxy,
+            ..with_tls
+        };
+        assert!(
+            proxy.validate().is_err(),
+            "redirect_port needs https_redirect"
+        );
+        let stripping = Listener {
+            redirect_strip_query: true,
+            ..Listener::builder("edge", ":8443").build()
+        };
+        let err = stripping.validate().unwrap_err();
+        assert!(err.to_string().contains("redirect_strip_query"), "{err}");
+        let to_zero = Listener {
+            redirect_ports: [(8080, 0)].into(),
+            ..redirect.clone()
+        };
+        let err = to_zero.validate().unwrap_err();
+        assert!(err.to_string().contains("redirect_ports"), "{err}");
+
+        let sharing = Listener {
+            name: "other".into(),
+            kind: ListenerKind::Proxy,
+            redirect_port: None,
+            ..redirect.clone()
+        };
+        let addr = redirect.parse_bind_addr().unwrap();
+        assert!(validate_shared_socket(addr, &[&sharing, &redirect]).is_err());
+    }
+
+    #[test]
+    fn wasm_pools_are_read_and_checked() {
+        let filter: Filter = toml::from_str(
+            r#"
+            type = "wasm"
+            name = "auth"
+            module = "plugins/auth.wasm"
+            max_body_size = "64KiB"
+            pool = { size = 4, prewarm = 1, max_uses = 0, on_exhausted = "reject", wait_timeout = "250ms" }
+            "#,
+        )
+        .unwrap();
+        let Filter::Wasm {
+            pool,
+            max_body_bytes,
+            ..
+        } = &filter
+        else {
+            panic!("not a wasm filter");
+        };
+        assert_eq!(*max_body_bytes, 64 * 1024);
+        assert_eq!((pool.size, pool.prewarm(), pool.max_uses), (4, 1, 0));
+        assert_eq!(pool.on_exhausted, PoolExhausted::Reject);
+        assert_eq!(pool.wait_timeout_ms, 250);
+        assert_eq!(WasmPool::default().prewarm(), 8);
+
+        let route = Route::builder("api", Upstream::single("http://127.0.0.1:9"))
+            .host("example.com")
+            .filter(Filter::wasm("auth", "plugins/auth.wasm").pool(WasmPool {
+                size: 2,
+                prewarm: Some(3),
+                ..Default::default()
+            }))
+            .build();
+        let err = route.validate().unwrap_err();
         assert_eq!(
-            listener.parse_bind_addr().unwrap(),
-            SocketAddr::from_str("0.0.0.0:8080").unwrap()
+            format!("{err:#}"),
+            "invalid wasm filter `auth` on route `api`: pool.prewarm must not exceed pool.size (2)"
         );
     }
 
     #[test]
-    fn route_timeout_parses_builtin_filter() {
-        let mut route = Route::default();
-        route.name = "test".into();
-        route.matchers.hosts = Some(vec!["example.com".into()]);
-        route.upstream = Upstream::Single {
-            target: "http://127.0.0.1:8080".into(),
+    fn stream_listeners_take_an_upstream_and_no_http_settings() {
+        let parse = |toml: &str| toml::from_str::<Listener>(toml).unwrap();
+        let stream = parse(
+            r#"
+            name = "postgres"
+            bind = ":5432"
+            kind = "stream"
+
+            [stream]
+            upstream = "db.internal:5432"
+            proxy_protocol = "v2"
+            idle_timeout = "1h"
+            "#,
+        );
+        assert_eq!(stream.kind, ListenerKind::Stream);
+        let target = stream.stream.as_ref().unwrap();
+        assert_eq!(target.connect_timeout_secs, 10);
+        assert_eq!(target.idle_timeout_secs, Some(3600));
+        stream.validate().unwrap();
+
+        let invalid = |listener: Listener, expected: &str| {
+            let err = listener.validate().unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{err:#}");
+        };
+        invalid(
+            Listener {
+                stream: None,
+                ..stream.clone()
+            },
+            "needs a `stream` table",
+        );
+        invalid(
+            Listener {
+                stream: Some(StreamTarget::new("db.internal")),
+                ..stream.clone()
+            },
+            "must be `host:port`",
+        );
+        let passthrough = StreamTarget {
+            upstream: None,
+            ..StreamTarget::new("unused:1")
+        };
+        invalid(
+            Listener {
+                stream: Some(passthrough.clone()),
+                ..stream.clone()
+            },
+            "needs an `upstream` or `sni` entries",
+        );
+        let routed = passthrough
+            .clone()
+            .sni(&["db.example.com", "*.pg.example.com"], "10.0.0.7:5432");
+        Listener {
+            stream: Some(routed.clone()),
+            ..stream.clone()
+        }
+        .validate()
+        .unwrap();
+        invalid(
+            Listener {
+                stream: Some(routed.clone().sni(&["DB.example.com"], "10.0.0.8:5432")),
+                ..stream.clone()
+            },
+            "more than one sni entry",
+        );
+        invalid(
+            Listener {
+                stream: Some(passthrough.sni(&["db example"], "10.0.0.7:5432")),
+                ..stream.clone()
+            },
+            "invalid server name",
+        );
+        invalid(
+            Listener {
+                health_check_paths: vec!["/healthz".into()],
+                ..stream.clone()
+            },
+            "does not take `health_check_paths`",
+        );
+        invalid(
+            Listener {
+                client_ip: ClientIpPolicy {
+                    source: ClientIpSource::XForwardedFor,
+                    trusted_proxies: vec!["10.0.0.0/8".into()],
+                    ..Default::default()
+                },
+                ..stream.clone()
+            },
+            "PROXY protocol header",
+        );
+        invalid(
+            Listener {
+                kind: ListenerKind::Proxy,
+                ..stream.clone()
+            },
+            "is not `kind = \"stream\"`",
+        );
+
+        let sharing = Listener {
+            name: "other".into(),
+            kind: ListenerKind::Proxy,
+            stream: None,
+            ..stream.clone()
+        };
+        let addr = stream.parse_bind_addr().unwrap();
+        assert!(validate_shared_socket(addr, &[&sharing, &stream]).is_err());
+    }
+
+    #[test]
+    fn udp_listeners_take_an_upstream_and_bind_beside_tcp() {
+        let udp: Listener = toml::from_str(
+            r#"
+            name = "dns"
+            bind = ":53"
+            kind = "udp"
+
+            [udp]
+            upstream = "10.0.0.2:53"
+            max_sessions = 5000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(udp.kind, ListenerKind::Udp);
+        let target = udp.udp.as_ref().unwrap();
+        assert_eq!(target.idle_timeout_secs, 30);
+        assert_eq!(target.max_sessions, Some(5000));
+        udp.validate().unwrap();
+
+        let invalid = |listener: Listener, expected: &str| {
+            let err = listener.validate().unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{err:#}");
+        };
+        invalid(
+            Listener {
+                udp: None,
+                ..udp.clone()
+            },
+            "needs a `udp` table",
+        );
+        invalid(
+            Listener {
+                udp: Some(UdpTarget::new("10.0.0.2")),
+                ..udp.clone()
+            },
+            "must be `host:port`",
+        );
+        invalid(
+            Listener {
+                client_ip: ClientIpPolicy {
+                    source: ClientIpSource::ProxyProtocol,
+                    trusted_proxies: vec!["10.0.0.0/8".into()],
+                    ..Default::default()
+                },
+                ..udp.clone()
+            },
+            "does not take `client_ip`",
+        );
+        invalid(
+            Listener {
+                kind: ListenerKind::Proxy,
+                ..udp.clone()
+            },
+            "is not `kind = \"udp\"`",
+        );
+
+        // A udp listener needs no routes, and shares its port with TCP but
+        // not with an h3 listener.
+        let config = |listeners| Config {
+            listeners,
+            ..Default::default()
+        };
+        let tcp = Listener::builder("dns-tcp", ":53")
+            .stream(StreamTarget::new("10.0.0.2:53"))
+            .build();
+        config(vec![udp.clone(), tcp]).validate().unwrap();
+        let h3 = Listener::builder("edge-h3", ":53")
+            .tls("cert", "key")
+            .h3()
+            .build();
+        let err = config(vec![udp, h3]).validate().unwrap_err();
+        assert!(err.to_string().contains("both bind UDP"), "{err}");
+    }
+
+    #[test]
+    fn tls_certificates_claim_distinct_server_names() {
+        let parse = |toml: &str| toml::from_str::<Listener>(toml).unwrap();
+        let listener = parse(
+            r#"
+            name = "edge"
+            bind = ":443"
+
+            [tls]
+            cert = "default.crt"
+            key = "default.key"
+
+            [[tls.certificates]]
+            server_names = ["shop.example.com", "*.tenants.example.com"]
+            cert = "shop.crt"
+            key = "shop.key"
+            "#,
+        );
+        listener.validate().unwrap();
+        let tls = listener.tls.as_ref().unwrap();
+        assert!(tls.serves("shop.crt") && tls.serves("default.crt"));
+        assert!(!tls.serves("other.crt"));
+
+        let invalid = |certificates: Vec<TlsCertificate>, expected: &str| {
+            let listener = Listener {
+                tls: Some(Tls {
+                    certificates,
+                    ..tls.clone()
+                }),
+                ..listener.clone()
+            };
+            let err = listener.validate().unwrap_err();
+            assert!(err.to_string().contains(expected), "{err}");
+        };
+        let certificate = |names: &[&str]| TlsCertificate {
+            server_names: names.iter().map(|name| name.to_string()).collect(),
+            cert: "other.crt".into(),
+            key: "other.key".into(),
+            intermediates: None,
+        };
+        invalid(vec![certificate(&[])], "no server_names");
+        invalid(vec![certificate(&["bad name"])], "invalid server name");
+        invalid(
+            vec![
+                certificate(&["a.example.com"]),
+                certificate(&["A.example.com"]),
+            ],
+            "more than one tls certificate",
+        );
+    }
+
+    #[test]
+    fn route_methods_are_case_insensitive_and_typos_fail() {
+        let parse = |names: &[&str]| {
+            Matchers {
+                methods: Some(names.iter().map(|name| name.to_string()).collect()),
+                ..Default::default()
+            }
+            .parsed_methods()
+        };
+        let methods = parse(&["get", "Post", "propfind", "purge", "MYSYNC"])
+            .unwrap()
+            .unwrap();
+        let names: Vec<&str> = methods.iter().map(Method::as_str).collect();
+        assert_eq!(names, ["GET", "POST", "PROPFIND", "PURGE", "MYSYNC"]);
+        assert!(Matchers::default().parsed_methods().unwrap().is_none());
+
+        let err = |names: &[&str]| parse(names).unwrap_err().to_string();
+        assert_eq!(err(&["get "]), "`get ` is not a valid method name");
+        assert_eq!(err(&[""]), "`` is not a valid method name");
+        // A dotless ı is not an i, whatever the locale's case rules say.
+        assert_eq!(err(&["optıons"]), "`optıons` is not a valid method name");
+        assert!(err(&[]).contains("`methods` is empty"));
+
+        // Names close to a known method are still extension methods; the
+        // lint only hints at the method they were likely meant to be.
+        for (name, known) in [
+            ("DELET", "DELETE"),
+            ("patchh", "PATCH"),
+            ("GTE", "GET"),
+            ("pots", "POST"),
+        ] {
+            assert_eq!(method_typo_of(name), Some(known), "{name}");
+            assert!(parse(&[name]).is_ok(), "{name}");
+        }
+        for name in ["get", "Post", "MYSYNC", "PURGE"] {
+            assert_eq!(method_typo_of(name), None, "{name}");
+        }
+
+        let route = Route::builder("api", Upstream::single("http://127.0.0.1:9"))
+            .host("example.com")
+            .method("GET")
+            .method("gett")
+            .build();
+        route.validate().unwrap();
+        let warnings = Config {
+            routes: vec![route],
+            ..Default::default()
+        }
+        .lint();
+        assert_eq!(
+            warnings,
+            ["route `api` matches extension method `GETT`; did you mean `GET`?"]
+        );
+    }
+
+    #[test]
+    fn tls_client_auth_needs_a_client_ca() {
+        let parse = |toml: &str| toml::from_str::<Listener>(toml).unwrap();
+        let listener = parse(
+            r#"
+            name = "internal"
+            bind = ":8443"
+            tls = { cert = "edge.crt", key = "edge.key", client_ca = "clients.pem", client_auth = "optional" }
+            "#,
+        );
+        listener.validate().unwrap();
+        let tls = listener.tls.clone().unwrap();
+        assert_eq!(tls.client_auth, Some(ClientAuth::Optional));
+        assert!(tls.files().contains(&"clients.pem"));
+
+        let err = |listener: Listener| listener.validate().unwrap_err().to_string();
+        let without_ca = Tls {
+            client_ca: None,
+            ..tls.clone()
+        };
+        assert!(err(Listener {
+            tls: Some(without_ca),
+            ..listener.clone()
+        })
+        .contains("client_auth needs client_ca"));
+        assert!(err(Listener {
+            early_data: true,
+            ..listener
+        })
+        .contains("both `early_data` and `tls.client_ca`"));
+    }
+
+    #[test]
+    fn h3_listeners_keep_the_tls_settings_quic_needs() {
+        let listener = toml::from_str::<Listener>(
+            r#"
+            name = "edge-h3"
+            bind = ":443"
+            protocol = "h3"
+            tls = { cert = "edge.crt", key = "edge.key", min_version = "1.3", cipher_suites = ["TLS13_AES_128_GCM_SHA256"], curves = ["X25519"] }
+            "#,
+        )
+        .unwrap();
+        listener.validate().unwrap();
+        let tls = listener.tls.clone().unwrap();
+        assert_eq!(tls.min_version, Some(TlsVersion::Tls13));
+
+        let err = |tls: Tls| {
+            Listener {
+                tls: Some(tls),
+                ..listener.clone()
+            }
+            .validate()
+            .unwrap_err()
+            .to_string()
+        };
+        assert!(err(Tls {
+            max_version: Some(TlsVersion::Tls12),
+            min_version: None,
+            cipher_suites: Vec::new(),
+            ..tls.clone()
+        })
+        .contains("needs TLS 1.3"));
+        assert!(err(Tls {
+            cipher_suites: vec!["TLS13_AES_256_GCM_SHA384".into()],
+            ..tls.clone()
+        })
+        .contains("needs TLS13_AES_128_GCM_SHA256"));
+        assert!(err(Tls {
+            curves: vec!["x448".into()],
+            ..tls
+        })
+        .contains("unknown curve `x448`"));
+    }
+
+    #[test]
+    fn plaintext_listeners_take_no_tls_only_settings() {
+        let parse = |toml: &str| toml::from_str::<Listener>(toml);
+        let plain = parse("name = \"dev\"\nbind = \":8080\"\ntls = \"none\"").unwrap();
+        assert!(plain.tls.is_none());
+        assert!(plain.validate().is_ok());
+        let resolved = ResolvedListener::try_from(&plain).unwrap();
+        assert_eq!(resolved.alpn, ["http/1.1"]);
+        assert!(parse("name = \"dev\"\nbind = \":8080\"\ntls = \"off\"").is_err());
+        let omitted = parse("name = \"dev\"\nbind = \":8080\"").unwrap();
+        assert!(omitted.tls.is_none());
+
+        let with = |listener: Listener| listener.validate().unwrap_err().to_string();
+        assert!(with(Listener {
+            early_data: true,
+            ..plain.clone()
+        })
+        .contains("early_data"));
+        assert!(with(Listener {
+            alpn: Some(vec!["http/1.1".into()]),
+            ..plain.clone()
+        })
+        .contains("alpn"));
+        assert!(with(Listener {
+            server_names: vec!["example.com".into()],
+            ..plain.clone()
+        })
+        .contains("server_names"));
+
+        let tls = Listener::builder("edge", ":8080")
+            .tls("cert", "key")
+            .build();
+        let addr = plain.parse_bind_addr().unwrap();
+        assert!(validate_shared_socket(addr, &[&tls, &plain]).is_err());
+    }
+
+    #[test]
+    fn http_tweaks_reject_unusable_limits() {
+        let tweaks = HttpTweaks {
+            keep_alive_timeout_secs: Some(0),
+            max_requests_per_connection: Some(100),
+            h2_max_concurrent_streams: Some(100),
+            h2_keepalive_interval_secs: Some(20),
+            h2_keepalive_timeout_secs: Some(10),
+            ..Default::default()
+        };
+        assert!(tweaks.validate().is_ok());
+        let tweaks = HttpTweaks {
+            h2_max_concurrent_streams: Some(0),
+            ..Default::default()
+        };
+        assert!(tweaks.validate().is_err());
+        let tweaks = HttpTweaks {
+            h2_keepalive_timeout_secs: Some(10),
+            ..Default::default()
+        };
+        assert!(tweaks
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("h2_keepalive_interval_secs"));
+    }
+
+    #[test]
+    fn missing_host_parses_map_targets() {
+        let parse = |value: &str| MissingHost::try_from(value.to_string());
+        assert_eq!(parse("reject").unwrap(), MissingHost::Reject);
+        assert_eq!(
+            parse("map:legacy.example.com").unwrap(),
+            MissingHost::Map("legacy.example.com".into())
+        );
+        assert!(parse("map:").is_err());
+        assert!(parse("map:bad host").is_err());
+        assert!(parse("drop").is_err());
+    }
+
+    #[test]
+    fn well_known_entries_need_a_file_and_valid_security_txt() {
+        let parse = |toml: &str| toml::from_str::<WellKnown>(toml).unwrap().validate();
+        assert!(parse(r#"robots_txt = "User-agent: *""#).is_ok());
+        assert!(parse(r#"hosts = ["example.com"]"#).is_err());
+        assert!(parse(r#"acme_challenge_dir = " ""#).is_err());
+        let security = r#"security_txt = "Contact: mailto:security@example.com\nExpires: 2030-01-01T00:00:00Z""#;
+        assert!(parse(security).is_ok());
+        let err = parse(r#"security_txt = "Contact: mailto:security@example.com""#).unwrap_err();
+        assert!(err.to_string().contains("expires"));
+    }
+
+    #[test]
+    fn acme_parses_dns_providers() {
+        let parse = |dns: &str| {
+            toml::from_str::<Acme>(&format!(
+                r#"
+                state_dir = "/var/lib/jester/acme"
+                dns = {dns}
+
+                [[certificates]]
+                domains = ["example.com", "*.example.com"]
+                cert = "certs/example.crt"
+                key = "certs/example.key"
+                "#
+            ))
+        };
+        let acme = parse(r#"{ provider = "cloudflare", api_token = "t", zone_id = "z" }"#).unwrap();
+        assert!(acme.validate().is_ok());
+        assert_eq!(acme.max_concurrent_renewals, 2);
+        assert!(acme.directory.starts_with("https://acme-v02"));
+
+        let acme = parse(
+            r#"{ provider = "rfc2136", server = "10.0.0.53", zone = "example.com", tsig_key = "jester", tsig_secret = "c2VjcmV0" }"#,
+        )
+        .unwrap();
+        assert!(acme.validate().is_ok());
+        assert!(matches!(
+            acme.dns,
+            DnsProvider::Rfc2136 {
+                tsig_algorithm: TsigAlgorithm::HmacSha256,
+                ..
+            }
+        ));
+
+        let acme = parse(r#"{ provider = "route53", access_key_id = "a", secret_access_key = "", hosted_zone_id = "Z1" }"#).unwrap();
+        assert!(acme.validate().is_err());
+        assert!(
+            parse(r#"{ provider = "cloudflare", api_token = "t", zone_id = "z", ttl = 1 }"#)
+                .is_err()
+        );
+        let acme = parse(r#"{ provider = "cloudflare", api_token = "t", zone_id = "z", endpoint = "cloudflare.test" }"#).unwrap();
+        assert!(acme.validate().is_err());
+
+        let mut acme =
+            parse(r#"{ provider = "cloudflare", api_token = "t", zone_id = "z" }"#).unwrap();
+        acme.certificates[0].domains.push("a.*.example.com".into());
+        assert!(acme.validate().is_err());
+    }
+
+    #[test]
+    fn lint_reports_shadowed_routes_and_public_admin() {
+        let catch_all = test_route();
+        let mut api = test_route();
+        api.name = "api".into();
+        api.matchers.path_prefix = Some("/api".into());
+        let config = Config {
+            admin: Some(Admin {
+                listen: Some("0.0.0.0:9900".into()),
+                ..Default::default()
+            }),
+            routes: vec![catch_all.clone(), api.clone()],
+            ..Default::default()
+        };
+        let warnings = config.lint();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("non-loopback"));
+        assert!(warnings[1].contains("route `api` is unreachable"));
+
+        let config = Config {
+            routes: vec![api, catch_all],
+            ..Default::default()
+        };
+        assert!(config.lint().is_empty());
+    }
+
+    #[test]
+    fn health_check_paths_must_be_distinct_paths() {
+        let listener = |paths: &[&str]| {
+            Listener::builder("edge", ":443")
+                .tls("cert", "key")
+                .health_check_paths(paths.iter().copied())
+                .build()
+                .validate()
+        };
+        assert!(listener(&["/elb-status", "/healthz"]).is_ok());
+        assert!(listener(&["elb-status"]).is_err());
+        assert!(listener(&["/elb-status?deep=1"]).is_err());
+        let err = listener(&["/elb-status", "/elb-status"]).unwrap_err();
+        assert!(err.to_string().contains("listed twice"), "{err}");
+    }
+
+    #[test]
+    fn streaming_settings_accept_units_and_reject_zero() {
+        let parse = |toml: &str| toml::from_str::<Streaming>(toml).unwrap();
+        let streaming = parse("flush_interval = \"50ms\"\nidle_timeout = \"2m\"");
+        assert_eq!(streaming.flush_interval_ms, Some(50));
+        assert_eq!(streaming.idle_timeout_secs, Some(120));
+        assert!(streaming.validate().is_ok());
+        assert!(parse("").validate().is_ok());
+        assert!(parse("flush_interval = 0").validate().is_err());
+        assert!(parse("idle_timeout = 0").validate().is_err());
+    }
+
+    #[test]
+    fn listeners_sharing_an_address_need_distinct_server_names() {
+        let listener = |name: &str, bind: &str, names: &[&str]| {
+            Listener::builder(name, bind)
+                .tls("cert", "key")
+                .server_names(names.iter().copied())
+                .build()
+        };
+        let config = |listeners| Config {
+            listeners,
+            routes: vec![test_route()],
+            ..Default::default()
+        };
+        let valid = config(vec![
+            listener("shop", ":443", &["shop.example.com"]),
+            listener("tenants", ":443", &["*.tenants.example.com"]),
+            listener("default", ":443", &[]),
+            listener("other", ":8443", &[]),
+        ]);
+        assert!(valid.validate().is_ok());
+
+        let error = |listeners| config(listeners).validate().unwrap_err().to_string();
+        assert!(
+            error(vec![listener("a", ":443", &[]), listener("b", ":443", &[])])
+                .contains("at most one")
+        );
+        assert!(error(vec![
+            listener("a", ":443", &["Shop.example.com"]),
+            listener("b", ":443", &["shop.example.com"]),
+        ])
+        .contains("claimed by both"));
+        assert!(
+            error(vec![listener("a", ":443", &["shop example"])]).contains("invalid server name")
+        );
+        let ephemeral = config(vec![
+            listener("a", "127.0.0.1:0", &[]),
+            listener("b", "127.0.0.1:0", &[]),
+        ]);
+        assert!(ephemeral.validate().is_ok());
+    }
+
+    #[test]
+    fn listener_alpn_names_known_protocols_once() {
+        let listener = |alpn: &[&str]| {
+            Listener::builder("edge", ":443")
+                .tls("cert", "key")
+                .alpn(alpn.iter().copied())
+                .build()
+        };
+        assert!(listener(&["h2"]).validate().is_ok());
+        assert!(listener(&["http/1.1", "h2"]).validate().is_ok());
+        let error = |alpn: &[&str]| format!("{:#}", listener(alpn).validate().unwrap_err());
+        assert!(error(&[]).contains("at least one"));
+        assert!(error(&["h2", "spdy/3"]).contains("unknown protocol `spdy/3`"));
+        assert!(error(&["h2", "h2"]).contains("listed twice"));
+    }
+
+    #[test]
+    fn hardening_refuses_dylib_plugins_and_stray_landlock_paths() {
+        let config = |hardening: &str, plugins: Plugins| {
+            let mut config = Config {
+                listeners: vec![Listener::builder("edge", ":443").tls("cert", "key").build()],
+                routes: vec![test_route()],
+                plugins: Some(plugins),
+                ..Default::default()
+            };
+            config.hardening = Some(toml::from_str(hardening).unwrap());
+            config.validate()
         };
+        let defaults: Hardening = toml::from_str("").unwrap();
+        assert!(defaults.drop_capabilities && defaults.seccomp && !defaults.landlock);
+        assert!(config("", Plugins::default()).is_ok());
+        let dylib = Plugins {
+            allow_unsafe_dylib: true,
+            ..Plugins::default()
+        };
+        let error = config("", dylib).unwrap_err().to_string();
+        assert!(error.contains("refuses native dylib plugins"), "{error}");
+        let error = config(r#"read_paths = ["/srv"]"#, Plugins::default()).unwrap_err();
+        assert!(error.to_string().contains("require landlock"), "{error}");
+        assert!(config(
+            r#"landlock = true
+            read_paths = ["/srv"]"#,
+            Plugins::default()
+        )
+        .is_ok());
+        let error = config(r#"chroot = "jail""#, Plugins::default()).unwrap_err();
+        assert!(error.to_string().contains("absolute path"), "{error}");
+    }
+
+    #[test]
+    fn h3_listeners_bind_udp_beside_tcp_listeners() {
+        let config = |listeners| Config {
+            listeners,
+            routes: vec![test_route()],
+            ..Default::default()
+        };
+        let tcp = Listener::builder("edge", ":443").tls("cert", "key").build();
+        let h3 = Listener::builder("edge-h3", ":443")
+            .tls("cert", "key")
+            .h3()
+            .build();
+        let parsed: Listener = toml::from_str(
+            r#"
+            name = "edge-h3"
+            bind = ":443"
+            protocol = "h3"
+            tls = { cert = "cert", key = "key" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(parsed.protocol, ListenerProtocol::H3);
+        assert!(config(vec![tcp, h3.clone()]).validate().is_ok());
+
+        let twice = Listener {
+            name: "other-h3".into(),
+            ..h3.clone()
+        };
+        let err = config(vec![h3.clone(), twice]).validate().unwrap_err();
+        assert!(err.to_string().contains("both bind"), "{err}");
+        let alpn = Listener {
+            alpn: Some(vec!["h2".into()]),
+            ..h3.clone()
+        };
+        assert!(alpn.validate().unwrap_err().to_string().contains("alpn"));
+        let proxied = Listener {
+            client_ip: ClientIpPolicy {
+                source: ClientIpSource::ProxyProtocol,
+                trusted_proxies: vec!["10.0.0.0/8".into()],
+                ..Default::default()
+            },
+            ..h3
+        };
+        assert!(proxied.validate().is_err());
+        let redirect = Listener::builder("http", ":80")
+            .https_redirect()
+            .h3()
+            .build();
+        assert!(redirect
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("protocol"));
+    }
+
+    fn test_route() -> Route {
+        Route {
+            name: "test".into(),
+            matchers: Matchers {
+                hosts: Some(vec!["example.com".into()]),
+                ..Default::default()
+            },
+            upstream: Upstream::single("http://127.0.0.1:8080"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn route_timeout_parses_builtin_filter() {
+        let mut route = test_route();
         route.filters.push(Filter::Builtin {
             name: "timeout".into(),
             config: serde_json::json!({ "request_secs": 5 }),
+            phase: None,
+            order: None,
+            flag: None,
         });
         assert_eq!(route.request_timeout(), Some(Duration::from_secs(5)));
     }
+
+    #[test]
+    fn filter_phase_and_order_parse_from_toml() {
+        let route: Route = toml::from_str(
+            r#"
+            name = "api"
+            [matchers]
+            hosts = ["example.com"]
+            [upstream]
+            strategy = "single"
+            target = "http://127.0.0.1:8080"
+            [[filters]]
+            type = "builtin"
+            name = "headers"
+            phase = "post_upstream"
+            order = -5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(route.filters[0].phase(), Some(Phase::PostUpstream));
+        assert_eq!(route.filters[0].order(), -5);
+    }
+
+    #[test]
+    fn upstream_options_parse_alongside_strategy() {
+        let upstream: Upstream = toml::from_str(
+            r#"
+            strategy = "single"
+            target = "http://127.0.0.1:8080"
+            keep_alive = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(upstream.single_target(), Some("http://127.0.0.1:8080"));
+        assert!(!upstream.keep_alive);
+        assert!(Upstream::single("http://127.0.0.1:8080").keep_alive);
+    }
+
+    #[test]
+    fn upstream_protocols_must_suit_the_target_scheme() {
+        let upstream: Upstream = toml::from_str(
+            r#"
+            strategy = "single"
+            target = "https://10.0.0.1:8443"
+            protocol = "h2"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(upstream.protocol, UpstreamProtocol::H2);
+        upstream.validate().unwrap();
+
+        let h2c = |target: &str| {
+            Upstream::single(target)
+                .protocol(UpstreamProtocol::H2c)
+                .validate()
+        };
+        h2c("http://10.0.0.1:8080").unwrap();
+        h2c("unix:///run/app.sock").unwrap();
+        let err = h2c("https://10.0.0.1:8443").unwrap_err();
+        assert!(err.to_string().contains("use `h2` for https://"), "{err}");
+        let err = Upstream::single("http://10.0.0.1:8080")
+            .protocol(UpstreamProtocol::H2)
+            .validate()
+            .unwrap_err();
+        assert!(err.to_string().contains("use `h2c`"), "{err}");
+    }
+
+    #[test]
+    fn proxy_protocol_is_for_http1_upstreams() {
+        let upstream: Upstream = toml::from_str(
+            r#"
+            strategy = "single"
+            target = "http://10.0.0.1:5432"
+            proxy_protocol = "v2"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(upstream.proxy_protocol, Some(ProxyProtocolVersion::V2));
+        upstream.validate().unwrap();
+
+        let err = Upstream::single("http://10.0.0.1:8080")
+            .protocol(UpstreamProtocol::H2c)
+            .proxy_protocol(ProxyProtocolVersion::V1)
+            .validate()
+            .unwrap_err();
+        assert!(err.to_string().contains("needs protocol `http1`"), "{err}");
+    }
+
+    #[test]
+    fn targets_take_optional_weights() {
+        let upstream: Upstream = toml::from_str(
+            r#"
+            strategy = "round_robin"
+            targets = ["http://10.0.0.1:8080", { url = "http://10.0.0.2:8080", weight = 3 }]
+            "#,
+        )
+        .unwrap();
+        let UpstreamStrategy::RoundRobin { targets } = &upstream.strategy else {
+            panic!("expected round_robin, got {:?}", upstream.strategy);
+        };
+        assert_eq!(
+            targets,
+            &[
+                UpstreamTarget::from("http://10.0.0.1:8080"),
+                UpstreamTarget::weighted("http://10.0.0.2:8080", 3),
+            ]
+        );
+        upstream.validate().unwrap();
+
+        let zero = Upstream {
+            strategy: UpstreamStrategy::RoundRobin {
+                targets: vec![UpstreamTarget::weighted("http://10.0.0.1:8080", 0)],
+            },
+            ..upstream
+        };
+        assert!(zero.validate().is_err());
+    }
+
+    #[test]
+    fn srv_upstreams_default_their_scheme_and_refresh() {
+        let upstream: Upstream = toml::from_str(
+            r#"
+            strategy = "srv"
+            name = "_http._tcp.api.service.consul"
+            resolvers = ["127.0.0.1:8600"]
+            "#,
+        )
+        .unwrap();
+        let UpstreamStrategy::Srv {
+            scheme,
+            refresh_secs,
+            ..
+        } = &upstream.strategy
+        else {
+            panic!("expected srv, got {:?}", upstream.strategy);
+        };
+        assert_eq!((scheme.as_str(), *refresh_secs), ("http", 30));
+        upstream.validate().unwrap();
+
+        let bad_resolver = Upstream {
+            strategy: UpstreamStrategy::Srv {
+                name: "_http._tcp.api.service.consul".into(),
+                scheme: "http".into(),
+                resolvers: vec!["consul".into()],
+                refresh_secs: 30,
+            },
+            ..upstream
+        };
+        assert!(bad_resolver.validate().is_err());
+    }
+
+    #[test]
+    fn event_sinks_parse_and_validate() {
+        let config: Config = toml::from_str(
+            r#"
+            [[events]]
+            type = "webhook"
+            url = "https://hooks.example.com/jester"
+            kinds = ["route_added", "route_removed"]
+
+            [[events]]
+            type = "nats"
+            address = "127.0.0.1:4222"
+            subject = "jester.events"
+
+            [[events]]
+            type = "kafka"
+            brokers = ["kafka-0:9092", "kafka-1:9092"]
+            topic = "jester-events"
+            "#,
+        )
+        .unwrap();
+        let EventSink::Webhook { timeout_secs, .. } = &config.events[0] else {
+            panic!("expected webhook, got {:?}", config.events[0]);
+        };
+        assert_eq!(*timeout_secs, 5);
+        assert!(config.events[0].accepts(EventKind::RouteAdded));
+        assert!(!config.events[0].accepts(EventKind::UpstreamBackoff));
+        assert!(config.events[1].accepts(EventKind::UpstreamBackoff));
+        for sink in &config.events {
+            sink.validate().unwrap();
+        }
+
+        let wildcard = EventSink::Nats {
+            address: "127.0.0.1:4222".into(),
+            subject: "jester.>".into(),
+            kinds: Vec::new(),
+        };
+        assert!(wildcard.validate().is_err());
+        let bad_topic = EventSink::Kafka {
+            brokers: vec!["kafka-0:9092".into()],
+            topic: "jester events".into(),
+            kinds: Vec::new(),
+        };
+        assert!(bad_topic.validate().is_err());
+        let no_brokers = EventSink::Kafka {
+            brokers: Vec::new(),
+            topic: "jester-events".into(),
+            kinds: Vec::new(),
+        };
+        assert!(no_brokers.validate().is_err());
+        let no_host = EventSink::Webhook {
+            url: "/hook".into(),
+            headers: BTreeMap::new(),
+            timeout_secs: 5,
+            kinds: Vec::new(),
+        };
+        assert!(no_host.validate().is_err());
+    }
+
+    #[test]
+    fn cluster_defaults_and_rejects_bad_peers() {
+        let config: Config = toml::from_str(
+            r#"
+            [cluster]
+            bind = "0.0.0.0:7946"
+            peers = ["jester-0.jester:7946", "10.0.0.2:7946"]
+            secret = "fleet-secret"
+            "#,
+        )
+        .unwrap();
+        let cluster = config.cluster.unwrap();
+        assert_eq!(cluster.interval_ms, 1000);
+        cluster.validate().unwrap();
+
+        let unauthenticated = Cluster {
+            secret: None,
+            ..cluster.clone()
+        };
+        assert!(unauthenticated.validate().is_err());
+        let loopback = Cluster {
+            bind: "127.0.0.1:7946".into(),
+            ..unauthenticated
+        };
+        loopback.validate().unwrap();
+
+        let portless = Cluster {
+            peers: vec!["10.0.0.2".into()],
+            ..cluster.clone()
+        };
+        assert!(portless.validate().is_err());
+        let hostname_bind = Cluster {
+            bind: "jester:7946".into(),
+            ..cluster
+        };
+        assert!(hostname_bind.validate().is_err());
+    }
+
+    #[test]
+    fn cluster_elections_parse_per_backend() {
+        let config: Config = toml::from_str(
+            r#"
+            [cluster]
+            bind = "0.0.0.0:7946"
+            secret = "fleet-secret"
+            election = { backend = "kubernetes", lease_secs = 30 }
+            "#,
+        )
+        .unwrap();
+        let cluster = config.cluster.unwrap();
+        let Some(Election::Kubernetes {
+            lease, namespace, ..
+        }) = &cluster.election
+        else {
+            panic!("expected kubernetes, got {:?}", cluster.election);
+        };
+        assert_eq!((lease.as_str(), namespace), ("jester-leader", &None));
+        cluster.validate().unwrap();
+
+        let redis: Election = toml::from_str(
+            r#"backend = "redis"
+address = "redis"
+"#,
+        )
+        .unwrap();
+        assert!(redis.validate().is_err(), "address needs a port");
+    }
+
+    #[test]
+    fn host_header_policy_parses() {
+        let parse = |value: &str| HostHeader::try_from(value.to_string());
+        assert_eq!(parse("preserve").unwrap(), HostHeader::Preserve);
+        assert_eq!(
+            parse("custom:backend.internal").unwrap(),
+            HostHeader::Custom("backend.internal".into())
+        );
+        assert!(parse("custom:").is_err());
+        assert!(parse("original").is_err());
+    }
+
+    #[test]
+    fn route_rejects_pre_routing_filters() {
+        let mut route = test_route();
+        route.filters.push(Filter::Builtin {
+            name: "headers".into(),
+            config: serde_json::Value::Null,
+            phase: Some(Phase::PreRouting),
+            order: None,
+            flag: None,
+        });
+        assert!(route.validate().is_err());
+    }
+
+    #[test]
+    fn time_matchers_parse_and_validate() {
+        let route: Route = toml::from_str(
+            r#"
+name = "maintenance"
+upstream = { strategy = "single", target = "http://127.0.0.1:8080" }
+
+[matchers]
+hosts = ["example.com"]
+
+[matchers.time]
+timezone = "UTC"
+cron = ["* 2 * * sun"]
+windows = [{ from = "22:00", to = "06:00", days = ["fri", "sat"] }]
+"#,
+        )
+        .unwrap();
+        let time = route.matchers.time.as_ref().unwrap();
+        assert_eq!(time.windows[0].days, ["fri", "sat"]);
+        route.validate().unwrap();
+
+        let mut broken = route.clone();
+        broken.matchers.time.as_mut().unwrap().cron = vec!["* 25 * * *".into()];
+        let err = broken.validate().unwrap_err();
+        assert!(
+            format!("{err:#}").contains("invalid time matcher on route `maintenance`"),
+            "{err:#}"
+        );
+        // A timed route never shadows the routes after it.
+        assert!(!route.matchers.shadows(&test_route().matchers));
+    }
+
+    #[test]
+    fn route_labels_must_be_metric_label_names() {
+        let route: Route = toml::from_str(
+            r#"
+name = "payments"
+matchers = { hosts = ["pay.example.com"] }
+upstream = { strategy = "single", target = "http://127.0.0.1:8080" }
+labels = { team = "payments", tier = "1" }
+"#,
+        )
+        .unwrap();
+        assert_eq!(route.labels["team"], "payments");
+        assert!(route.validate().is_ok());
+
+        for name in ["owning-team", "1tier", "__name__", "route", ""] {
+            let mut route = route.clone();
+            route.labels.insert(name.into(), "x".into());
+            assert!(route.validate().is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn retry_policies_parse_and_validate() {
+        let route: Route = toml::from_str(
+            r#"
+name = "api"
+matchers = { hosts = ["api.example.com"] }
+upstream = { strategy = "single", target = "http://127.0.0.1:8080" }
+retry = { attempts = 2, retry_on = ["connect_failure", "5xx"], statuses = [429], per_try_timeout_ms = 500 }
+"#,
+        )
+        .unwrap();
+        let retry = route.retry.as_ref().unwrap();
+        assert_eq!(
+            retry.retry_on,
+            [RetryOn::ConnectFailure, RetryOn::ServerError]
+        );
+        assert_eq!(retry.backoff_max_ms, 250);
+        assert!(route.validate().is_ok());
+
+        let mut route = test_route();
+        route.retry = Some(RetryPolicy {
+            attempts: 0,
+            ..Default::default()
+        });
+        assert!(route.validate().is_err());
+        route.retry = Some(RetryPolicy {
+            statuses: vec![700],
+            ..Default::default()
+        });
+        assert!(route.validate().is_err());
+        route.retry = Some(RetryPolicy {
+            advisor_config: serde_json::json!({ "header": "idempotency-key" }),
+            ..Default::default()
+        });
+        assert!(route.validate().is_err());
+    }
+
+    #[test]
+    fn durations_and_sizes_accept_units() {
+        let config: Config = toml::from_str(
+            r#"
+[[listeners]]
+name = "edge"
+bind = ":8443"
+http = { request_timeout = "1m", max_header_size = "16KiB", keep_alive_timeout_secs = 75 }
+
+[[routes]]
+name = "api"
+matchers = { hosts = ["api.example.com"] }
+upstream = { strategy = "srv", name = "_api._tcp.example.com", refresh = "2m" }
+websocket = { idle_timeout = "1h", max_message_size = "1MiB", ping_interval = "30s", pong_timeout = "10s" }
+retry = { per_try_timeout = "2s", backoff_max = "1s" }
+filters = [{ type = "builtin", name = "timeout", config = { request = "30s" } }]
+"#,
+        )
+        .unwrap();
+        let http = config.listeners[0].http.as_ref().unwrap();
+        assert_eq!(http.request_timeout_secs, Some(60));
+        assert_eq!(http.max_header_bytes, Some(16 * 1024));
+        assert_eq!(http.keep_alive_timeout_secs, Some(75));
+        let route = &config.routes[0];
+        assert!(matches!(
+            route.upstream.strategy,
+            UpstreamStrategy::Srv {
+                refresh_secs: 120,
+                ..
+            }
+        ));
+        assert_eq!(route.websocket.idle_timeout_secs, Some(3600));
+        assert_eq!(route.websocket.max_message_bytes, Some(1 << 20));
+        assert_eq!(route.websocket.ping_interval_secs, Some(30));
+        assert_eq!(route.websocket.pong_timeout_secs, Some(10));
+        let retry = route.retry.as_ref().unwrap();
+        assert_eq!(retry.per_try_timeout_ms, Some(2000));
+        assert_eq!(retry.backoff_max_ms, 1000);
+        assert_eq!(route.request_timeout(), Some(Duration::from_secs(30)));
+
+        let err = toml::from_str::<Listener>(
+            "name = \"edge\"\nbind = \":8443\"\nhttp = { request_timeout_secs = \"1500ms\" }",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("line 3"), "{err}");
+        assert!(err.contains("not a whole number of seconds"), "{err}");
+
+        let err = toml::from_str::<WebsocketLimits>("idle_timout = \"1h\"")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown field `idle_timout`"), "{err}");
+        let limits = WebsocketLimits {
+            pong_timeout_secs: Some(5),
+            ..Default::default()
+        };
+        assert!(limits.validate().is_err());
+    }
 }
@@ -0,0 +1,756 @@
+//! Fluent constructors for configuration types.
+//!
+//! Required fields are taken by the entry-point constructors (`Route::builder(name, upstream)`,
+//! `Listener::builder(name, bind)`, ...) so a builder can never produce a value missing them;
+//! everything else is optional and chained.
+
+use anyhow::Result;
+
+use super::{
+    default_consul_address, default_consul_wait_secs, default_srv_refresh_secs, default_srv_scheme,
+    default_wasm_max_body_bytes, AbsoluteForm, Acme, Admin, ClientAuth, ClientIpPolicy, Cluster,
+    Config, EventSink, FeatureFlags, Filter, Hardening, HeaderMatch, HostHeader, HttpTweaks,
+    Listener, ListenerKind, ListenerProtocol, Matchers, MethodMismatch, MissingHost, Phase,
+    Plugins, ProxyProtocolVersion, RetryPolicy, Route, Shutdown, StreamTarget, Streaming,
+    TapOptions, TimeMatch, Tls, TlsCertificate, TlsVersion, UdpTarget, Upstream, UpstreamOverride,
+    UpstreamPool, UpstreamProtocol, UpstreamStrategy, UpstreamTarget, UpstreamTls, Via, WasmPool,
+    WebsocketLimits, WellKnown,
+};
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn admin(mut self, listen: impl Into<String>) -> Self {
+        self.config.admin.get_or_insert_with(Admin::default).listen = Some(listen.into());
+        self
+    }
+
+    pub fn admin_socket(mut self, path: impl Into<String>) -> Self {
+        self.config.admin.get_or_insert_with(Admin::default).socket = Some(path.into());
+        self
+    }
+
+    pub fn listener(mut self, listener: impl Into<Listener>) -> Self {
+        self.config.listeners.push(listener.into());
+        self
+    }
+
+    pub fn route(mut self, route: impl Into<Route>) -> Self {
+        self.config.routes.push(route.into());
+        self
+    }
+
+    /// Appends a filter to the global (pre-routing) chain.
+    pub fn filter(mut self, filter: impl Into<Filter>) -> Self {
+        self.config.filters.push(filter.into());
+        self
+    }
+
+    pub fn flags(mut self, flags: FeatureFlags) -> Self {
+        self.config.flags = Some(flags);
+        self
+    }
+
+    pub fn upstream_override(mut self, upstream_override: UpstreamOverride) -> Self {
+        self.config.upstream_override = Some(upstream_override);
+        self
+    }
+
+    pub fn acme(mut self, acme: Acme) -> Self {
+        self.config.acme = Some(acme);
+        self
+    }
+
+    pub fn via(mut self, via: Via) -> Self {
+        self.config.via = via;
+        self
+    }
+
+    pub fn tap(mut self, tap: TapOptions) -> Self {
+        self.config.tap = tap;
+        self
+    }
+
+    pub fn well_known(mut self, well_known: WellKnown) -> Self {
+        self.config.well_known.push(well_known);
+        self
+    }
+
+    pub fn cluster(mut self, cluster: Cluster) -> Self {
+        self.config.cluster = Some(cluster);
+        self
+    }
+
+    pub fn upstream_pool(mut self, pool: UpstreamPool) -> Self {
+        self.config.upstream_pool = pool;
+        self
+    }
+
+    pub fn event_sink(mut self, sink: EventSink) -> Self {
+        self.config.events.push(sink);
+        self
+    }
+
+    pub fn hardening(mut self, hardening: Hardening) -> Self {
+        self.config.hardening = Some(hardening);
+        self
+    }
+
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.config.shutdown = shutdown;
+        self
+    }
+
+    pub fn plugins(mut self, plugins: Plugins) -> Self {
+        self.config.plugins = Some(plugins);
+        self
+    }
+
+    /// Returns the configuration after running [`Config::validate`].
+    pub fn build(self) -> Result<Config> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+
+    /// Returns the configuration without validating it.
+    pub fn build_unchecked(self) -> Config {
+        self.config
+    }
+}
+
+impl Listener {
+    pub fn builder(name: impl Into<String>, bind: impl Into<String>) -> ListenerBuilder {
+        ListenerBuilder {
+            listener: Listener {
+                name: name.into(),
+                bind: bind.into(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ListenerBuilder {
+    listener: Listener,
+}
+
+impl ListenerBuilder {
+    pub fn tls(mut self, cert: impl Into<String>, key: impl Into<String>) -> Self {
+        self.listener.tls = Some(Tls {
+            cert: cert.into(),
+            key: key.into(),
+            intermediates: None,
+            certificates: Vec::new(),
+            client_ca: None,
+            client_auth: None,
+            min_version: None,
+            max_version: None,
+            cipher_suites: Vec::new(),
+            curves: Vec::new(),
+        });
+        self
+    }
+
+    /// Bundle of intermediates completing the chain set by [`Self::tls`].
+    pub fn tls_intermediates(mut self, path: impl Into<String>) -> Self {
+        if let Some(tls) = self.listener.tls.as_mut() {
+            tls.intermediates = Some(path.into());
+        }
+        self
+    }
+
+    /// Asks clients of the listener set up by [`Self::tls`] for a certificate
+    /// issued by a CA in `path`.
+    pub fn tls_client_ca(mut self, path: impl Into<String>, auth: ClientAuth) -> Self {
+        if let Some(tls) = self.listener.tls.as_mut() {
+            tls.client_ca = Some(path.into());
+            tls.client_auth = Some(auth);
+        }
+        self
+    }
+
+    /// Limits the listener set up by [`Self::tls`] to protocol versions
+    /// `min..=max`.
+    pub fn tls_versions(mut self, min: TlsVersion, max: TlsVersion) -> Self {
+        if let Some(tls) = self.listener.tls.as_mut() {
+            tls.min_version = Some(min);
+            tls.max_version = Some(max);
+        }
+        self
+    }
+
+    /// Limits the listener set up by [`Self::tls`] to `suites`, by IANA name,
+    /// preferring them in that order.
+    pub fn tls_cipher_suites(mut self, suites: &[&str]) -> Self {
+        if let Some(tls) = self.listener.tls.as_mut() {
+            tls.cipher_suites = suites.iter().map(|suite| suite.to_string()).collect();
+        }
+        self
+    }
+
+    /// Serves `cert` to clients whose SNI is one of `server_names`, beside
+    /// the certificate set by [`Self::tls`].
+    pub fn tls_certificate(
+        mut self,
+        server_names: &[&str],
+        cert: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Self {
+        if let Some(tls) = self.listener.tls.as_mut() {
+            tls.certificates.push(TlsCertificate {
+                server_names: server_names.iter().map(|name| name.to_string()).collect(),
+                cert: cert.into(),
+                key: key.into(),
+                intermediates: None,
+            });
+        }
+        self
+    }
+
+    pub fn alpn<I, S>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.listener.alpn = Some(protocols.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn http(mut self, http: HttpTweaks) -> Self {
+        self.listener.http = Some(http);
+        self
+    }
+
+    pub fn trust_forwarded_headers(mut self, trust: bool) -> Self {
+        self.listener.trust_forwarded_headers = trust;
+        self
+    }
+
+    pub fn log_connections(mut self, enabled: bool) -> Self {
+        self.listener.log_connections = enabled;
+        self
+    }
+
+    pub fn early_data(mut self, enabled: bool) -> Self {
+        self.listener.early_data = enabled;
+        self
+    }
+
+    pub fn missing_host(mut self, behavior: MissingHost) -> Self {
+        self.listener.missing_host = behavior;
+        self
+    }
+
+    pub fn absolute_form(mut self, behavior: AbsoluteForm) -> Self {
+        self.listener.absolute_form = behavior;
+        self
+    }
+
+    pub fn client_ip(mut self, policy: ClientIpPolicy) -> Self {
+        self.listener.client_ip = policy;
+        self
+    }
+
+    /// Names this listener answers on a socket it shares with others.
+    pub fn server_names<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.listener.server_names = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Paths answered at the listener for load balancer health checks.
+    pub fn health_check_paths<I, S>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.listener.health_check_paths = paths.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Serves HTTP/3 over QUIC instead of HTTP/1.1 and HTTP/2 over TCP.
+    pub fn h3(mut self) -> Self {
+        self.listener.protocol = ListenerProtocol::H3;
+        self
+    }
+
+    /// Makes this a plain-HTTP listener redirecting every request to HTTPS.
+    pub fn https_redirect(mut self) -> Self {
+        self.listener.kind = ListenerKind::HttpsRedirect;
+        self
+    }
+
+    pub fn redirect_port(mut self, port: u16) -> Self {
+        self.listener.redirect_port = Some(port);
+        self
+    }
+
+    /// Sends clients that addressed port `from` to HTTPS on port `to`.
+    pub fn redirect_port_map(mut self, from: u16, to: u16) -> Self {
+        self.listener.redirect_ports.insert(from, to);
+        self
+    }
+
+    /// Leaves the query string out of redirect URLs.
+    pub fn redirect_strip_query(mut self) -> Self {
+        self.listener.redirect_strip_query = true;
+        self
+    }
+
+    /// Makes this a listener relaying raw TCP bytes to `target`.
+    pub fn stream(mut self, target: StreamTarget) -> Self {
+        self.listener.kind = ListenerKind::Stream;
+        self.listener.stream = Some(target);
+        self
+    }
+
+    /// Makes this a listener relaying UDP datagrams to `target`.
+    pub fn udp(mut self, target: UdpTarget) -> Self {
+        self.listener.kind = ListenerKind::Udp;
+        self.listener.udp = Some(target);
+        self
+    }
+
+    pub fn build(self) -> Listener {
+        self.listener
+    }
+}
+
+impl From<ListenerBuilder> for Listener {
+    fn from(builder: ListenerBuilder) -> Self {
+        builder.build()
+    }
+}
+
+impl Route {
+    pub fn builder(name: impl Into<String>, upstream: impl Into<Upstream>) -> RouteBuilder {
+        RouteBuilder {
+            route: Route {
+                name: name.into(),
+                upstream: upstream.into(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RouteBuilder {
+    route: Route,
+}
+
+impl RouteBuilder {
+    /// Replaces all matchers at once.
+    pub fn matchers(mut self, matchers: impl Into<Matchers>) -> Self {
+        self.route.matchers = matchers.into();
+        self
+    }
+
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.route
+            .matchers
+            .hosts
+            .get_or_insert_with(Vec::new)
+            .push(host.into());
+        self
+    }
+
+    pub fn path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.route.matchers.path_prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn method(mut self, method: impl Into<String>) -> Self {
+        self.route
+            .matchers
+            .methods
+            .get_or_insert_with(Vec::new)
+            .push(method.into());
+        self
+    }
+
+    pub fn filter(mut self, filter: impl Into<Filter>) -> Self {
+        self.route.filters.push(filter.into());
+        self
+    }
+
+    pub fn response_filter(mut self, filter: impl Into<Filter>) -> Self {
+        self.route.response_filters.push(filter.into());
+        self
+    }
+
+    pub fn websocket(mut self, limits: WebsocketLimits) -> Self {
+        self.route.websocket = limits;
+        self
+    }
+
+    pub fn streaming(mut self, streaming: Streaming) -> Self {
+        self.route.streaming = streaming;
+        self
+    }
+
+    /// Opts the route in to serving idempotent requests from TLS early data.
+    pub fn early_data(mut self, enabled: bool) -> Self {
+        self.route.early_data = enabled;
+        self
+    }
+
+    pub fn method_mismatch(mut self, behavior: MethodMismatch) -> Self {
+        self.route.method_mismatch = behavior;
+        self
+    }
+
+    pub fn suppress_via(mut self, suppress: bool) -> Self {
+        self.route.suppress_via = suppress;
+        self
+    }
+
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.route.retry = Some(policy);
+        self
+    }
+
+    /// Adds a classification label to the route's metrics, span, and access events.
+    pub fn label(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.route.labels.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn build(self) -> Route {
+        self.route
+    }
+}
+
+impl From<RouteBuilder> for Route {
+    fn from(builder: RouteBuilder) -> Self {
+        builder.build()
+    }
+}
+
+impl Matchers {
+    pub fn builder() -> MatchersBuilder {
+        MatchersBuilder::default()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MatchersBuilder {
+    matchers: Matchers,
+}
+
+impl MatchersBuilder {
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.matchers
+            .hosts
+            .get_or_insert_with(Vec::new)
+            .push(host.into());
+        self
+    }
+
+    pub fn path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.matchers.path_prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn method(mut self, method: impl Into<String>) -> Self {
+        self.matchers
+            .methods
+            .get_or_insert_with(Vec::new)
+            .push(method.into());
+        self
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.matchers
+            .headers
+            .get_or_insert_with(Vec::new)
+            .push(HeaderMatch {
+                name: name.into(),
+                value: value.into(),
+            });
+        self
+    }
+
+    /// Limits the route to the times `time` names.
+    pub fn time(mut self, time: TimeMatch) -> Self {
+        self.matchers.time = Some(time);
+        self
+    }
+
+    pub fn build(self) -> Matchers {
+        self.matchers
+    }
+}
+
+impl From<MatchersBuilder> for Matchers {
+    fn from(builder: MatchersBuilder) -> Self {
+        builder.build()
+    }
+}
+
+impl Upstream {
+    pub fn single(target: impl Into<String>) -> Self {
+        UpstreamStrategy::Single {
+            target: target.into(),
+        }
+        .into()
+    }
+
+    pub fn round_robin<I, S>(targets: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<UpstreamTarget>,
+    {
+        UpstreamStrategy::RoundRobin {
+            targets: targets.into_iter().map(Into::into).collect(),
+        }
+        .into()
+    }
+
+    pub fn least_latency<I, S>(targets: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        UpstreamStrategy::LeastLatency {
+            targets: targets.into_iter().map(Into::into).collect(),
+        }
+        .into()
+    }
+
+    pub fn hash<I, S>(targets: I, key: impl Into<String>) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<UpstreamTarget>,
+    {
+        UpstreamStrategy::Hash {
+            targets: targets.into_iter().map(Into::into).collect(),
+            key: key.into(),
+        }
+        .into()
+    }
+
+    /// Targets from the SRV records of `name`, using the default scheme,
+    /// resolvers, and refresh interval.
+    pub fn srv(name: impl Into<String>) -> Self {
+        UpstreamStrategy::Srv {
+            name: name.into(),
+            scheme: default_srv_scheme(),
+            resolvers: Vec::new(),
+            refresh_secs: default_srv_refresh_secs(),
+        }
+        .into()
+    }
+
+    /// Targets from the passing instances of Consul `service`, asking the
+    /// local agent with the default scheme and wait.
+    pub fn consul(service: impl Into<String>) -> Self {
+        UpstreamStrategy::Consul {
+            service: service.into(),
+            address: default_consul_address(),
+            scheme: default_srv_scheme(),
+            tag: None,
+            datacenter: None,
+            token: None,
+            wait_secs: default_consul_wait_secs(),
+        }
+        .into()
+    }
+
+    pub fn keep_alive(mut self, enabled: bool) -> Self {
+        self.keep_alive = enabled;
+        self
+    }
+
+    pub fn host_header(mut self, policy: HostHeader) -> Self {
+        self.host_header = policy;
+        self
+    }
+
+    pub fn tls(mut self, tls: UpstreamTls) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn pool(mut self, pool: UpstreamPool) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    pub fn protocol(mut self, protocol: UpstreamProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn proxy_protocol(mut self, version: ProxyProtocolVersion) -> Self {
+        self.proxy_protocol = Some(version);
+        self
+    }
+}
+
+impl From<UpstreamStrategy> for Upstream {
+    fn from(strategy: UpstreamStrategy) -> Self {
+        Self {
+            strategy,
+            ..Default::default()
+        }
+    }
+}
+
+impl Filter {
+    pub fn builtin(name: impl Into<String>) -> FilterBuilder {
+        FilterBuilder {
+            filter: Filter::Builtin {
+                name: name.into(),
+                config: serde_json::Value::Null,
+                phase: None,
+                order: None,
+                flag: None,
+            },
+        }
+    }
+
+    pub fn wasm(name: impl Into<String>, module: impl Into<String>) -> FilterBuilder {
+        FilterBuilder {
+            filter: Filter::Wasm {
+                name: name.into(),
+                module: module.into(),
+                config: serde_json::Value::Null,
+                pool: WasmPool::default(),
+                max_body_bytes: default_wasm_max_body_bytes(),
+                phase: None,
+                order: None,
+                flag: None,
+            },
+        }
+    }
+
+    pub fn inproc(name: impl Into<String>, symbol: impl Into<String>) -> FilterBuilder {
+        FilterBuilder {
+            filter: Filter::InProc {
+                name: name.into(),
+                symbol: symbol.into(),
+                config: serde_json::Value::Null,
+                phase: None,
+                order: None,
+                flag: None,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FilterBuilder {
+    filter: Filter,
+}
+
+impl FilterBuilder {
+    pub fn config(mut self, value: serde_json::Value) -> Self {
+        match &mut self.filter {
+            Filter::Builtin { config, .. }
+            | Filter::Wasm { config, .. }
+            | Filter::InProc { config, .. } => *config = value,
+        }
+        self
+    }
+
+    pub fn phase(mut self, value: Phase) -> Self {
+        match &mut self.filter {
+            Filter::Builtin { phase, .. }
+            | Filter::Wasm { phase, .. }
+            | Filter::InProc { phase, .. } => *phase = Some(value),
+        }
+        self
+    }
+
+    pub fn order(mut self, value: i32) -> Self {
+        match &mut self.filter {
+            Filter::Builtin { order, .. }
+            | Filter::Wasm { order, .. }
+            | Filter::InProc { order, .. } => *order = Some(value),
+        }
+        self
+    }
+
+    /// Runs the filter only while the named feature flag is on.
+    pub fn flag(mut self, name: impl Into<String>) -> Self {
+        match &mut self.filter {
+            Filter::Builtin { flag, .. }
+            | Filter::Wasm { flag, .. }
+            | Filter::InProc { flag, .. } => *flag = Some(name.into()),
+        }
+        self
+    }
+
+    /// Instance pool settings of a `wasm` filter; ignored for others.
+    pub fn pool(mut self, value: WasmPool) -> Self {
+        if let Filter::Wasm { pool, .. } = &mut self.filter {
+            *pool = value;
+        }
+        self
+    }
+
+    pub fn build(self) -> Filter {
+        self.filter
+    }
+}
+
+impl From<FilterBuilder> for Filter {
+    fn from(builder: FilterBuilder) -> Self {
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builders_produce_valid_config() {
+        let config = Config::builder()
+            .listener(Listener::builder("edge", ":8443").tls("cert.pem", "key.pem"))
+            .route(
+                Route::builder("app", Upstream::single("http://127.0.0.1:8080"))
+                    .host("example.com")
+                    .path_prefix("/api")
+                    .filter(
+                        Filter::builtin("timeout").config(serde_json::json!({ "request_secs": 5 })),
+                    ),
+            )
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.routes[0].matchers.hosts,
+            Some(vec!["example.com".into()])
+        );
+        assert_eq!(
+            config.routes[0].request_timeout(),
+            Some(std::time::Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn config_builder_validates() {
+        let result = Config::builder()
+            .listener(Listener::builder("edge", ":8443").tls("cert.pem", "key.pem"))
+            .route(Route::builder(
+                "app",
+                Upstream::single("http://127.0.0.1:8080"),
+            ))
+            .build();
+        assert!(result.is_err(), "route without hosts must be rejected");
+    }
+}
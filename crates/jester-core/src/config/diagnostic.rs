@@ -0,0 +1,327 @@
+//! Config errors that point into the source: the line with the offending key
+//! or entry, a caret under it, and a hint where one is known.
+//!
+//! Validation wraps errors about one part of the config (`routes[2]`,
+//! `[admin]`) in [`Located`], and strict parsing does the same for each
+//! unknown key. [`annotate`] finds them in the TOML they were parsed from and
+//! renders them like TOML syntax errors.
+
+use std::{error::Error as StdError, fmt, ops::Range};
+
+use anyhow::anyhow;
+use toml::{
+    de::{DeTable, DeValue},
+    Spanned,
+};
+
+/// A place in the config: table keys and array indices from the root.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigPath(Vec<Segment>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl ConfigPath {
+    pub(crate) fn key(mut self, key: &str) -> Self {
+        self.0.push(Segment::Key(key.to_string()));
+        self
+    }
+
+    pub(crate) fn index(mut self, index: usize) -> Self {
+        self.0.push(Segment::Index(index));
+        self
+    }
+
+    /// The `name` of an entry of an array of tables such as `[[routes]]`,
+    /// which is where a reader looks for it.
+    pub(crate) fn entry(array: &str, index: usize) -> Self {
+        Self::default().key(array).index(index).key("name")
+    }
+}
+
+impl fmt::Display for ConfigPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (position, segment) in self.0.iter().enumerate() {
+            match segment {
+                Segment::Key(key) if position == 0 => write!(f, "{key}")?,
+                Segment::Key(key) => write!(f, ".{key}")?,
+                Segment::Index(index) => write!(f, "[{index}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An error about the part of the config at `path`. It displays, and chains,
+/// as the error it wraps, with the hint appended.
+#[derive(Debug)]
+pub struct Located {
+    path: ConfigPath,
+    hint: Option<String>,
+    error: anyhow::Error,
+}
+
+impl Located {
+    pub(crate) fn new(path: ConfigPath, error: anyhow::Error) -> Self {
+        Self {
+            path,
+            hint: None,
+            error,
+        }
+    }
+
+    pub(crate) fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    pub fn path(&self) -> &ConfigPath {
+        &self.path
+    }
+}
+
+impl fmt::Display for Located {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)?;
+        match &self.hint {
+            Some(hint) => write!(f, "; {hint}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl StdError for Located {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.error.chain().nth(1)
+    }
+}
+
+/// Several located errors reported together, such as every unknown key found
+/// by strict parsing.
+#[derive(Debug)]
+pub struct LocatedErrors {
+    summary: String,
+    errors: Vec<Located>,
+}
+
+impl LocatedErrors {
+    pub(crate) fn new(summary: impl Into<String>, errors: Vec<Located>) -> Self {
+        Self {
+            summary: summary.into(),
+            errors,
+        }
+    }
+}
+
+impl fmt::Display for LocatedErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.summary)?;
+        for error in &self.errors {
+            write!(f, "\n  {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl StdError for LocatedErrors {}
+
+/// Wraps an error in [`Located`] at `path`, for `map_err`.
+pub(crate) fn at(path: ConfigPath) -> impl FnOnce(anyhow::Error) -> anyhow::Error {
+    move |error| Located::new(path, error).into()
+}
+
+/// Replaces the located errors in `error` with snippets of `source`, the TOML
+/// the config was parsed from. Errors without a location, or whose location
+/// is not in `source`, are returned as they are.
+pub fn annotate(error: anyhow::Error, source: &str) -> anyhow::Error {
+    let located = error.chain().find_map(|cause| {
+        if let Some(located) = cause.downcast_ref::<Located>() {
+            return Some((None, vec![located]));
+        }
+        cause
+            .downcast_ref::<LocatedErrors>()
+            .map(|all| (Some(&all.summary), all.errors.iter().collect()))
+    });
+    let (Some((summary, located)), Ok(document)) = (located, DeTable::parse(source)) else {
+        return error;
+    };
+    let mut spans = located
+        .into_iter()
+        .map(|located| (locate(&document, &located.path), located))
+        .collect::<Vec<_>>();
+    // In the order a reader meets them; unlocated ones last.
+    spans.sort_by_key(|(span, _)| span.as_ref().map_or(usize::MAX, |span| span.start));
+    let mut sections = summary
+        .map(|summary| format!("{summary}:"))
+        .into_iter()
+        .collect::<Vec<_>>();
+    for (span, located) in spans {
+        sections.push(match span {
+            Some(span) => snippet(
+                source,
+                span,
+                &format!("{:#}", located.error),
+                located.hint.as_deref(),
+            ),
+            None => located.to_string(),
+        });
+    }
+    anyhow!("{}", sections.join("\n\n"))
+}
+
+/// The span of `path` in `document`, or of as much of it as is there: a key
+/// for a key, the whole value for an array index.
+fn locate(document: &Spanned<DeTable<'_>>, path: &ConfigPath) -> Option<Range<usize>> {
+    enum Node<'a, 'i> {
+        Table(&'a DeTable<'i>),
+        Array(&'a [Spanned<DeValue<'i>>]),
+        Scalar,
+    }
+    fn node<'a, 'i>(value: &'a DeValue<'i>) -> Node<'a, 'i> {
+        match value {
+            DeValue::Table(table) => Node::Table(table),
+            DeValue::Array(array) => Node::Array(array),
+            _ => Node::Scalar,
+        }
+    }
+
+    let mut current = Node::Table(document.get_ref());
+    let mut span = None;
+    for segment in &path.0 {
+        let (found, value) = match (segment, current) {
+            (Segment::Key(key), Node::Table(table)) => {
+                let Some((name, value)) = table.iter().find(|(name, _)| name.get_ref() == key)
+                else {
+                    break;
+                };
+                (name.span(), value)
+            }
+            (Segment::Index(index), Node::Array(items)) => {
+                let Some(item) = items.get(*index) else {
+                    break;
+                };
+                (item.span(), item)
+            }
+            _ => break,
+        };
+        span = Some(found);
+        current = node(value.get_ref());
+    }
+    span
+}
+
+/// `message` under the line of `source` holding `span`, in the layout TOML
+/// parse errors use.
+fn snippet(source: &str, span: Range<usize>, message: &str, hint: Option<&str>) -> String {
+    let start = span.start.min(source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |newline| newline + 1);
+    let line_end = source[start..]
+        .find('\n')
+        .map_or(source.len(), |newline| start + newline);
+    let line = source[line_start..line_end].trim_end_matches('\r');
+    let number = source[..line_start].matches('\n').count() + 1;
+    let column = source[line_start..start].chars().count() + 1;
+    let width = source[start..span.end.clamp(start, line_end)]
+        .chars()
+        .count()
+        .max(1);
+    let gutter = " ".repeat(number.to_string().len());
+    let mut rendered = format!(
+        "config error at line {number}, column {column}\n\
+         {gutter} |\n\
+         {number} | {line}\n\
+         {gutter} | {}{}\n\
+         {message}",
+        " ".repeat(column - 1),
+        "^".repeat(width),
+    );
+    if let Some(hint) = hint {
+        rendered.push_str(&format!("\nhelp: {hint}"));
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::bail;
+
+    use super::*;
+
+    const SOURCE: &str = r#"[admin]
+listen = "127.0.0.1:9000"
+
+[[routes]]
+name = "web"
+
+[[routes]]
+name = "api"
+matchers = { path_prefx = "/api" }
+"#;
+
+    #[test]
+    fn located_errors_read_as_the_error_they_wrap() {
+        let error = at(ConfigPath::entry("routes", 1))(
+            anyhow!("upstream refused").context("route `api` is invalid"),
+        );
+        assert_eq!(error.to_string(), "route `api` is invalid");
+        assert_eq!(
+            format!("{error:#}"),
+            "route `api` is invalid: upstream refused"
+        );
+        let located = error.downcast_ref::<Located>().unwrap();
+        assert_eq!(located.path().to_string(), "routes[1].name");
+    }
+
+    #[test]
+    fn annotations_point_at_the_entry_or_key() {
+        let error = at(ConfigPath::entry("routes", 1))(anyhow!("route `api` is invalid"));
+        assert_eq!(
+            annotate(error, SOURCE).to_string(),
+            "config error at line 8, column 1\n  |\n8 | name = \"api\"\n  | ^^^^\nroute `api` is invalid"
+        );
+
+        let unknown = Located::new(
+            ConfigPath::default()
+                .key("routes")
+                .index(1)
+                .key("matchers")
+                .key("path_prefx"),
+            anyhow!("unknown field `routes[api].matchers.path_prefx`"),
+        )
+        .hint("did you mean `path_prefix`?");
+        let unlocated = Located::new(
+            ConfigPath::default().key("listeners"),
+            anyhow!("unknown field `listeners.x`"),
+        );
+        let error = anyhow::Error::from(LocatedErrors::new(
+            "2 unknown fields",
+            vec![unlocated, unknown],
+        ))
+        .context("failed to parse jester.toml");
+        assert_eq!(
+            annotate(error, SOURCE).to_string(),
+            "2 unknown fields:\n\n\
+             config error at line 9, column 14\n  |\n9 | matchers = { path_prefx = \"/api\" }\n  |              ^^^^^^^^^^\n\
+             unknown field `routes[api].matchers.path_prefx`\nhelp: did you mean `path_prefix`?\n\n\
+             unknown field `listeners.x`"
+        );
+
+        let error = at(ConfigPath::default().key("admin"))(anyhow!("admin needs `listen`"));
+        assert!(annotate(error, SOURCE)
+            .to_string()
+            .starts_with("config error at line 1, column 2\n  |\n1 | [admin]\n  |  ^^^^^\n"));
+    }
+
+    #[test]
+    fn errors_without_a_location_are_left_alone() {
+        let plain = || -> anyhow::Result<()> { bail!("at least one listener is required") };
+        let error = annotate(plain().unwrap_err(), SOURCE);
+        assert_eq!(error.to_string(), "at least one listener is required");
+
+        let missing = at(ConfigPath::entry("listeners", 0))(anyhow!("no such listener"));
+        assert_eq!(annotate(missing, SOURCE).to_string(), "no such listener");
+    }
+}
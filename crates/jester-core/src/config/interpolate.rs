@@ -0,0 +1,186 @@
+//! `${VAR}` placeholders in config files, expanded from the environment
+//! before the TOML is parsed.
+//!
+//! - `${VAR}` is the variable's value, or empty (with a warning) when unset.
+//! - `${VAR:default}` falls back to `default` when it is unset.
+//! - `${VAR:?message}` fails the load with `message` when it is unset or
+//!   empty, as in the shell.
+//! - `$${VAR}` is a literal `${VAR}`, for values that contain one.
+
+use std::sync::OnceLock;
+
+use anyhow::{bail, Result};
+use regex::{Captures, Regex};
+
+/// What a placeholder turns into when its variable is not set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fallback<'a> {
+    Empty,
+    Default(&'a str),
+    Required(&'a str),
+}
+
+/// One `${...}` in a config file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Placeholder<'a> {
+    pub name: &'a str,
+    pub fallback: Fallback<'a>,
+    /// 1-based line of the file it is on.
+    pub line: usize,
+}
+
+fn pattern() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"\$(\$?)\{([A-Z0-9_]+)(?::(\?[^}]*|[^}]+))?\}").unwrap())
+}
+
+/// The placeholder `caps` matched; `None` for an escaped one.
+fn placeholder<'a>(input: &str, caps: &Captures<'a>) -> Option<Placeholder<'a>> {
+    if !caps[1].is_empty() {
+        return None;
+    }
+    let whole = caps.get(0).unwrap();
+    let fallback = match caps.get(3).map(|m| m.as_str()) {
+        None => Fallback::Empty,
+        Some(modifier) => match modifier.strip_prefix('?') {
+            Some(message) => Fallback::Required(message),
+            None => Fallback::Default(modifier),
+        },
+    };
+    Some(Placeholder {
+        name: caps.get(2).unwrap().as_str(),
+        fallback,
+        line: input[..whole.start()].matches('\n').count() + 1,
+    })
+}
+
+/// Every placeholder in `input`, escaped ones left out.
+pub fn placeholders(input: &str) -> Vec<Placeholder<'_>> {
+    pattern()
+        .captures_iter(input)
+        .filter_map(|caps| placeholder(input, &caps))
+        .collect()
+}
+
+/// Expands `input` from the process environment.
+pub fn expand(input: &str) -> Result<String> {
+    expand_with(input, |name| std::env::var(name).ok())
+}
+
+/// Expands `input`, looking variables up with `lookup`. Fails naming every
+/// required variable that is unset or empty.
+fn expand_with(input: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut missing = Vec::new();
+    let expanded = pattern().replace_all(input, |caps: &Captures| {
+        let Some(placeholder) = placeholder(input, caps) else {
+            return caps[0][1..].to_string();
+        };
+        let mut required = |message: &str, problem: &str| {
+            let mut line = format!(
+                "line {}: environment variable `{}` {problem}",
+                placeholder.line, placeholder.name
+            );
+            if !message.is_empty() {
+                line.push_str(&format!(": {message}"));
+            }
+            missing.push(line);
+            String::new()
+        };
+        match (lookup(placeholder.name), placeholder.fallback) {
+            (Some(value), Fallback::Required(message)) if value.is_empty() => {
+                required(message, "is empty")
+            }
+            (Some(value), _) => value,
+            (None, Fallback::Default(default)) => default.to_string(),
+            (None, Fallback::Empty) => {
+                tracing::warn!(
+                    variable = placeholder.name,
+                    line = placeholder.line,
+                    "environment variable is not set; its config placeholder expands to an empty string"
+                );
+                String::new()
+            }
+            (None, Fallback::Required(message)) => required(message, "is not set"),
+        }
+    });
+    match missing.as_slice() {
+        [] => Ok(expanded.into_owned()),
+        [only] => bail!("{only}"),
+        all => bail!(
+            "{} required environment variables are missing:\n  {}",
+            all.len(),
+            all.join("\n  ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(input: &str) -> Result<String> {
+        expand_with(input, |name| match name {
+            "HOST" => Some("edge.example.com".into()),
+            "BLANK" => Some(String::new()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn placeholders_expand_with_defaults_and_escapes() {
+        assert_eq!(
+            expand("a = \"${HOST}\"\nb = \"${PORT:8443}\"\nc = \"${UNSET}\"").unwrap(),
+            "a = \"edge.example.com\"\nb = \"8443\"\nc = \"\""
+        );
+        // Set but empty is a value, except for required variables.
+        assert_eq!(expand("${BLANK:fallback}").unwrap(), "");
+        assert_eq!(
+            expand("literal = \"$${HOST}\", \"$${PORT:1}\", \"$${X:?y}\"").unwrap(),
+            "literal = \"${HOST}\", \"${PORT:1}\", \"${X:?y}\""
+        );
+        assert_eq!(expand("${HOST:?needed}").unwrap(), "edge.example.com");
+        // Not placeholders: lowercase names and empty defaults.
+        assert_eq!(expand("${host} ${PORT:}").unwrap(), "${host} ${PORT:}");
+    }
+
+    #[test]
+    fn required_variables_fail_the_load_together() {
+        let err = expand("token = \"${TOKEN:?set it from the vault}\"").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: environment variable `TOKEN` is not set: set it from the vault"
+        );
+        let err = expand("a = \"${A:?}\"\nb = \"${BLANK:?}\"").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "2 required environment variables are missing:\n  \
+             line 1: environment variable `A` is not set\n  \
+             line 2: environment variable `BLANK` is empty"
+        );
+    }
+
+    #[test]
+    fn placeholders_are_listed_with_their_lines() {
+        let source = "a = \"${HOST}\"\n\nb = \"${PORT:80}\" # $${SKIPPED}\nc = \"${KEY:?secret}\"";
+        assert_eq!(
+            placeholders(source),
+            [
+                Placeholder {
+                    name: "HOST",
+                    fallback: Fallback::Empty,
+                    line: 1
+                },
+                Placeholder {
+                    name: "PORT",
+                    fallback: Fallback::Default("80"),
+                    line: 3
+                },
+                Placeholder {
+                    name: "KEY",
+                    fallback: Fallback::Required("secret"),
+                    line: 4
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! Loading config documents the way `jester run` does, which `POST
+//! /config/validate` on the admin API shares.
+
+use anyhow::{Context, Result};
+
+use super::{diagnostic, interpolate, overlay, winpath, Config};
+
+/// One TOML document of a config: a name for messages, such as its path, and
+/// its text with `${VAR}` placeholders not yet expanded.
+pub struct ConfigSource<'a> {
+    pub name: &'a str,
+    pub text: &'a str,
+}
+
+/// Parses `base` with each of `overlays` merged on in turn, after expanding
+/// the `${VAR}` placeholders of every document. With `strict`, or `[meta]
+/// strict` in the config, keys no setting accepts are errors. Returns the
+/// config and the TOML it was parsed from; with overlays that is the merged
+/// document.
+pub fn load(
+    base: &ConfigSource,
+    overlays: &[ConfigSource],
+    strict: bool,
+) -> Result<(Config, String)> {
+    let expanded = expand(base)?;
+    let source = if overlays.is_empty() {
+        expanded
+    } else {
+        let mut merged = toml::from_str::<toml::Table>(&expanded)
+            .with_context(|| format!("failed to parse {}", base.name))?;
+        for layer in overlays {
+            let table = toml::from_str::<toml::Table>(&expand(layer)?)
+                .with_context(|| format!("failed to parse {}", layer.name))?;
+            overlay::merge(&mut merged, table)
+                .with_context(|| format!("failed to apply overlay {}", layer.name))?;
+        }
+        toml::to_string(&merged)?
+    };
+    let config = toml::from_str::<Config>(&source)
+        .with_context(|| format!("failed to parse {}", base.name))?;
+    if strict || config.meta.strict {
+        config
+            .deny_unknown_fields(&toml::from_str(&source)?)
+            .map_err(|err| annotate(err, &source, !overlays.is_empty()))
+            .with_context(|| format!("failed to parse {}", base.name))?;
+    }
+    Ok((config, source))
+}
+
+/// [`load`], then [`Config::validate`].
+pub fn load_checked(
+    base: &ConfigSource,
+    overlays: &[ConfigSource],
+    strict: bool,
+) -> Result<(Config, String)> {
+    let (config, source) = load(base, overlays, strict)?;
+    config
+        .validate()
+        .map_err(|err| annotate(err, &source, !overlays.is_empty()))
+        .with_context(|| format!("invalid config {}", base.name))?;
+    Ok((config, source))
+}
+
+/// Shows the errors of validation or strict parsing located in `source` on
+/// their line. A `merged` source is no file a reader has, so its errors stay
+/// as they are.
+pub fn annotate(err: anyhow::Error, source: &str, merged: bool) -> anyhow::Error {
+    if merged {
+        err
+    } else {
+        diagnostic::annotate(err, source)
+    }
+}
+
+fn expand(source: &ConfigSource) -> Result<String> {
+    let expanded = interpolate::expand(source.text)
+        .with_context(|| format!("failed to expand {}", source.name))?;
+    Ok(winpath::escape_backslashes(&expanded).into_owned())
+}
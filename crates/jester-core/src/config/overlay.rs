@@ -0,0 +1,212 @@
+//! Per-environment overlays merged onto a base config, for `--overlay`.
+//!
+//! Tables merge key by key and scalars in the overlay replace the base's.
+//! Arrays of tables whose entries all have a `name` merge entry by entry:
+//! an overlay entry updates the base entry of the same name, or is appended
+//! when there is none. Any other array, including an empty one, is replaced
+//! as a whole.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use toml::{Table, Value};
+
+/// Where `--overlay <spec>` points: a path as given, or a bare name such as
+/// `prod` for `overlays/prod.toml` next to the base config.
+pub fn resolve(base: &Path, spec: &str) -> PathBuf {
+    let path = Path::new(spec);
+    if path.components().count() > 1 || path.extension().is_some() {
+        return path.to_path_buf();
+    }
+    base.parent()
+        .unwrap_or(Path::new(""))
+        .join("overlays")
+        .join(format!("{spec}.toml"))
+}
+
+/// Merges `overlay` onto `base`.
+pub fn merge(base: &mut Table, overlay: Table) -> Result<()> {
+    merge_table(base, overlay, "")
+}
+
+fn merge_table(base: &mut Table, overlay: Table, path: &str) -> Result<()> {
+    for (key, value) in overlay {
+        let path = match path {
+            "" => key.clone(),
+            _ => format!("{path}.{key}"),
+        };
+        match base.get_mut(&key) {
+            Some(existing) => merge_value(existing, value, &path)?,
+            None => {
+                base.insert(key, value);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn merge_value(base: &mut Value, overlay: Value, path: &str) -> Result<()> {
+    match (base, overlay) {
+        (Value::Table(base), Value::Table(overlay)) => merge_table(base, overlay, path),
+        (Value::Array(base), Value::Array(overlay)) => match (named(base), named(&overlay)) {
+            (Some(base_names), Some(overlay_names)) if !overlay_names.is_empty() => {
+                for names in [&base_names, &overlay_names] {
+                    if let Some(duplicate) = duplicate(names) {
+                        bail!("cannot merge `{path}`: more than one entry is named `{duplicate}`");
+                    }
+                }
+                for (name, entry) in overlay_names.into_iter().zip(overlay) {
+                    let Value::Table(entry) = entry else {
+                        unreachable!("named entries are tables");
+                    };
+                    let position = base_names.iter().position(|base| *base == name);
+                    match position {
+                        Some(index) => {
+                            let Value::Table(existing) = &mut base[index] else {
+                                unreachable!("named entries are tables");
+                            };
+                            merge_table(existing, entry, &format!("{path}[{name}]"))?;
+                        }
+                        None => base.push(Value::Table(entry)),
+                    }
+                }
+                Ok(())
+            }
+            (_, _) => {
+                *base = overlay;
+                Ok(())
+            }
+        },
+        (base, overlay) => {
+            *base = overlay;
+            Ok(())
+        }
+    }
+}
+
+/// The `name` of every entry, when all are tables with one.
+fn named(entries: &[Value]) -> Option<Vec<String>> {
+    entries
+        .iter()
+        .map(|entry| Some(entry.as_table()?.get("name")?.as_str()?.to_string()))
+        .collect()
+}
+
+fn duplicate(names: &[String]) -> Option<&str> {
+    names
+        .iter()
+        .enumerate()
+        .find(|(index, name)| names[..*index].contains(name))
+        .map(|(_, name)| name.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = r#"
+[[listeners]]
+name = "edge"
+bind = ":8443"
+tls = { cert = "dev.crt", key = "dev.key" }
+
+[[routes]]
+name = "app"
+matchers = { hosts = ["app.dev.example.com"] }
+upstream = { strategy = "single", target = "http://127.0.0.1:8080" }
+
+[[routes]]
+name = "admin"
+matchers = { hosts = ["admin.dev.example.com"] }
+upstream = { strategy = "single", target = "http://127.0.0.1:9090" }
+
+[via]
+node = "dev"
+"#;
+
+    #[test]
+    fn named_entries_merge_and_other_arrays_replace() {
+        let mut base: Table = toml::from_str(BASE).unwrap();
+        let overlay: Table = toml::from_str(
+            r#"
+[[listeners]]
+name = "edge"
+tls = { cert = "/etc/jester/prod.crt", key = "/etc/jester/prod.key" }
+
+[[routes]]
+name = "app"
+matchers = { hosts = ["app.example.com", "www.example.com"] }
+
+[[routes]]
+name = "metrics"
+matchers = { hosts = ["metrics.example.com"] }
+upstream = { strategy = "single", target = "http://127.0.0.1:9100" }
+"#,
+        )
+        .unwrap();
+        merge(&mut base, overlay).unwrap();
+
+        let listener = &base["listeners"][0];
+        assert_eq!(listener["bind"].as_str(), Some(":8443"));
+        assert_eq!(
+            listener["tls"]["cert"].as_str(),
+            Some("/etc/jester/prod.crt")
+        );
+        let routes = base["routes"].as_array().unwrap();
+        let names: Vec<_> = routes
+            .iter()
+            .map(|route| route["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["app", "admin", "metrics"]);
+        assert_eq!(
+            routes[0]["matchers"]["hosts"].as_array().unwrap().len(),
+            2,
+            "plain arrays are replaced, not appended to"
+        );
+        assert_eq!(
+            routes[0]["upstream"]["target"].as_str(),
+            Some("http://127.0.0.1:8080")
+        );
+        assert_eq!(base["via"]["node"].as_str(), Some("dev"));
+    }
+
+    #[test]
+    fn duplicate_names_cannot_be_merged() {
+        let mut base: Table = toml::from_str(
+            r#"
+[[filters]]
+type = "builtin"
+name = "headers"
+
+[[filters]]
+type = "builtin"
+name = "headers"
+"#,
+        )
+        .unwrap();
+        let overlay: Table = toml::from_str(
+            r#"
+[[filters]]
+name = "headers"
+config = { set = { "x-env" = "prod" } }
+"#,
+        )
+        .unwrap();
+        let err = merge(&mut base, overlay).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cannot merge `filters`: more than one entry is named `headers`"
+        );
+    }
+
+    #[test]
+    fn bare_names_resolve_next_to_the_base_config() {
+        let base = Path::new("/etc/jester/base.toml");
+        assert_eq!(
+            resolve(base, "prod"),
+            Path::new("/etc/jester/overlays/prod.toml")
+        );
+        assert_eq!(resolve(base, "prod.toml"), Path::new("prod.toml"));
+        assert_eq!(resolve(base, "envs/prod"), Path::new("envs/prod"));
+    }
+}
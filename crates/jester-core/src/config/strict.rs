@@ -0,0 +1,195 @@
+//! Strict parsing: keys serde would ignore are reported as errors, with the
+//! nearest known field as a hint, e.g. for a `path_prefx` typo.
+//!
+//! Each table of the source is compared with the parsed config serialized
+//! back. A key is unknown when the parsed config has no such field, nor one
+//! the key is the unit alias of (see [`units`](super::units)). Each is
+//! [`Located`], so the CLI can show it in the source.
+
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value as Json};
+use toml::{Table, Value};
+
+use super::{
+    diagnostic::{ConfigPath, Located, LocatedErrors},
+    Config,
+};
+
+impl Config {
+    /// Fails on keys of `source`, the table this config was parsed from, that
+    /// no field accepts.
+    pub fn deny_unknown_fields(&self, source: &Table) -> Result<()> {
+        let Json::Object(parsed) = serde_json::to_value(self)? else {
+            unreachable!("configs serialize as maps");
+        };
+        let mut unknown = Vec::new();
+        compare_table(source, &parsed, "", &ConfigPath::default(), &mut unknown);
+        match unknown.len() {
+            0 => Ok(()),
+            1 => Err(unknown.remove(0).into()),
+            count => Err(LocatedErrors::new(format!("{count} unknown fields"), unknown).into()),
+        }
+    }
+}
+
+/// `path` names a table for messages, as `routes[app]`; `at` is where it is
+/// in the source.
+fn compare_table(
+    source: &Table,
+    parsed: &Map<String, Json>,
+    path: &str,
+    at: &ConfigPath,
+    unknown: &mut Vec<Located>,
+) {
+    for (key, value) in source {
+        let path = match path {
+            "" => key.clone(),
+            _ => format!("{path}.{key}"),
+        };
+        let at = at.clone().key(key);
+        let field = parsed.get(key).or_else(|| {
+            parsed
+                .iter()
+                .find(|(field, _)| is_alias(key, field))
+                .map(|(_, value)| value)
+        });
+        match field {
+            Some(field) => compare(value, field, &path, &at, unknown),
+            None => {
+                let error = Located::new(at, anyhow!("unknown field `{path}`"));
+                unknown.push(match nearest(key, parsed.keys().map(String::as_str)) {
+                    Some(hint) => error.hint(format!("did you mean `{hint}`?")),
+                    None => error,
+                });
+            }
+        }
+    }
+}
+
+fn compare(source: &Value, parsed: &Json, path: &str, at: &ConfigPath, unknown: &mut Vec<Located>) {
+    match (source, parsed) {
+        (Value::Table(source), Json::Object(parsed)) => {
+            compare_table(source, parsed, path, at, unknown)
+        }
+        (Value::Array(source), Json::Array(parsed)) => {
+            for (index, (source, parsed)) in source.iter().zip(parsed).enumerate() {
+                let entry = match source.get("name").and_then(Value::as_str) {
+                    Some(name) => format!("{path}[{name}]"),
+                    None => format!("{path}[{index}]"),
+                };
+                compare(source, parsed, &entry, &at.clone().index(index), unknown);
+            }
+        }
+        // Scalars, and values such as `"5m"` that parse into another shape.
+        _ => {}
+    }
+}
+
+/// Whether `key` is the unit alias of `field`: `timeout` for `timeout_secs`,
+/// `max_body_size` for `max_body_bytes`.
+fn is_alias(key: &str, field: &str) -> bool {
+    if let Some(stem) = field.strip_suffix("_bytes") {
+        return key.strip_suffix("_size") == Some(stem);
+    }
+    match field
+        .strip_suffix("_secs")
+        .or_else(|| field.strip_suffix("_ms"))
+    {
+        // `lease` names the Kubernetes lease, so its duration is `lease_duration`.
+        Some("lease") => key == "lease_duration",
+        Some(stem) => key == stem,
+        None => false,
+    }
+}
+
+/// The name closest to `key`, if any is close enough to be a likely typo.
+pub(super) fn nearest<'a>(key: &str, names: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = (key.chars().count() / 3).max(1);
+    names
+        .into_iter()
+        .map(|name| (edit_distance(key, name), name))
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name)
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(source: &str) -> Result<()> {
+        let config: Config = toml::from_str(source).unwrap();
+        config.deny_unknown_fields(&toml::from_str(source).unwrap())
+    }
+
+    #[test]
+    fn typos_are_reported_with_the_nearest_field() {
+        let err = check(
+            r#"
+[[routes]]
+name = "app"
+matchers = { path_prefx = "/api" }
+upstream = { strategy = "single", target = "http://127.0.0.1:8080", keep_alvie = false }
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "2 unknown fields:\n  \
+             unknown field `routes[app].matchers.path_prefx`; did you mean `path_prefix`?\n  \
+             unknown field `routes[app].upstream.keep_alvie`; did you mean `keep_alive`?"
+        );
+
+        let err = check("[[routes]]\nname = \"app\"\ncolour = \"blue\"").unwrap_err();
+        assert_eq!(err.to_string(), "unknown field `routes[app].colour`");
+    }
+
+    #[test]
+    fn known_fields_aliases_and_filter_configs_pass() {
+        check(
+            r#"
+[meta]
+strict = true
+
+[upstream_pool]
+idle_timeout = "90s"
+
+[[routes]]
+name = "app"
+matchers = { path_prefix = "/api" }
+upstream = { strategy = "single", target = "http://127.0.0.1:8080", pool = { tcp_keepalive_secs = 30 } }
+retry = { per_try_timeout = "2s", max_body_size = "1MiB" }
+
+[[routes.filters]]
+type = "builtin"
+name = "headers"
+config = { set = { x-env = "prod" } }
+"#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn edit_distance_counts_single_character_edits() {
+        assert_eq!(edit_distance("path_prefx", "path_prefix"), 1);
+        assert_eq!(edit_distance("keep_alvie", "keep_alive"), 2);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(nearest("colour", ["enabled"]), None);
+    }
+}
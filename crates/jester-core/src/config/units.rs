@@ -0,0 +1,263 @@
+//! Durations and sizes written with units, such as `"30s"`, `"5m"`, or
+//! `"10MiB"`.
+//!
+//! Fields keep their unit-suffixed names (`idle_timeout_secs`,
+//! `max_body_bytes`) and bare integers keep meaning that unit. Each also
+//! accepts a string with units, and an alias without the suffix:
+//! `idle_timeout = "5m"`, `max_body_size = "64KiB"`.
+
+use std::fmt;
+
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Unit {
+    Secs,
+    Millis,
+    Bytes,
+}
+
+impl Unit {
+    fn expecting(self) -> &'static str {
+        match self {
+            Unit::Secs => "a number of seconds or a duration such as \"30s\" or \"5m\"",
+            Unit::Millis => "a number of milliseconds or a duration such as \"250ms\" or \"2s\"",
+            Unit::Bytes => "a number of bytes or a size such as \"64KiB\" or \"10MB\"",
+        }
+    }
+}
+
+/// Parses `value` into a count of `unit`.
+fn parse(value: &str, unit: Unit) -> Result<u64, String> {
+    let trimmed = value.trim();
+    if trimmed.is_empty() {
+        return Err("empty value".into());
+    }
+    let millis_or_bytes = match unit {
+        Unit::Bytes => parse_size(trimmed)?,
+        Unit::Secs | Unit::Millis => parse_duration(trimmed)?,
+    };
+    match unit {
+        Unit::Secs if millis_or_bytes % 1000 != 0 => {
+            Err(format!("`{value}` is not a whole number of seconds"))
+        }
+        Unit::Secs => Ok(millis_or_bytes / 1000),
+        Unit::Millis | Unit::Bytes => Ok(millis_or_bytes),
+    }
+}
+
+/// Milliseconds in a duration such as `90s` or `1h30m`.
+fn parse_duration(value: &str) -> Result<u64, String> {
+    let mut total: u64 = 0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let (number, after) = split_number(rest)
+            .ok_or_else(|| format!("invalid duration `{value}`: expected a number"))?;
+        let unit_len = after
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(after.len());
+        let (unit, after) = after.split_at(unit_len);
+        let scale = match unit.trim() {
+            "ms" => 1,
+            "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            "d" => 24 * 60 * 60 * 1000,
+            "" => {
+                return Err(format!(
+                    "invalid duration `{value}`: missing unit (ms, s, m, h, d)"
+                ))
+            }
+            other => {
+                return Err(format!(
+                    "invalid duration `{value}`: unknown unit `{other}` (expected ms, s, m, h, d)"
+                ))
+            }
+        };
+        total = number
+            .checked_mul(scale)
+            .and_then(|part| total.checked_add(part))
+            .ok_or_else(|| format!("duration `{value}` is too long"))?;
+        rest = after.trim_start();
+    }
+    Ok(total)
+}
+
+/// Bytes in a size such as `512`, `64KiB`, or `10MB`. Unit case is ignored.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let (number, unit) =
+        split_number(value).ok_or_else(|| format!("invalid size `{value}`: expected a number"))?;
+    let scale: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "ki" | "kib" => 1 << 10,
+        "m" | "mb" => 1000 * 1000,
+        "mi" | "mib" => 1 << 20,
+        "g" | "gb" => 1000 * 1000 * 1000,
+        "gi" | "gib" => 1 << 30,
+        other => {
+            return Err(format!(
+                "invalid size `{value}`: unknown unit `{other}` (expected B, KB, KiB, MB, MiB, GB, GiB)"
+            ))
+        }
+    };
+    number
+        .checked_mul(scale)
+        .ok_or_else(|| format!("size `{value}` is too large"))
+}
+
+/// Splits leading digits from the rest of `value`.
+fn split_number(value: &str) -> Option<(u64, &str)> {
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let number = value[..digits].parse().ok()?;
+    Some((number, &value[digits..]))
+}
+
+struct QuantityVisitor(Unit);
+
+impl Visitor<'_> for QuantityVisitor {
+    type Value = u64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(self.0.expecting())
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<u64, E> {
+        Ok(value)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<u64, E> {
+        u64::try_from(value).map_err(|_| E::custom(format!("{value} must not be negative")))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<u64, E> {
+        parse(value, self.0).map_err(E::custom)
+    }
+}
+
+fn quantity<'de, D, T>(deserializer: D, unit: Unit) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    let value = deserializer.deserialize_any(QuantityVisitor(unit))?;
+    T::try_from(value).map_err(|_| de::Error::custom(format!("{value} is too large")))
+}
+
+/// A quantity in `UNIT` (an index into `UNITS`), for optional fields.
+struct Quantity<T, const UNIT: usize>(T);
+
+const UNITS: [Unit; 3] = [Unit::Secs, Unit::Millis, Unit::Bytes];
+
+impl<'de, T: TryFrom<u64>, const UNIT: usize> Deserialize<'de> for Quantity<T, UNIT> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        quantity(deserializer, UNITS[UNIT]).map(Quantity)
+    }
+}
+
+fn optional<'de, D, T, const UNIT: usize>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    Ok(Option::<Quantity<T, UNIT>>::deserialize(deserializer)?.map(|quantity| quantity.0))
+}
+
+/// Seconds, as a bare integer or a duration string.
+pub(crate) fn secs<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+    deserializer: D,
+) -> Result<T, D::Error> {
+    quantity(deserializer, Unit::Secs)
+}
+
+/// Milliseconds, as a bare integer or a duration string.
+pub(crate) fn millis<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+    deserializer: D,
+) -> Result<T, D::Error> {
+    quantity(deserializer, Unit::Millis)
+}
+
+/// Bytes, as a bare integer or a size string.
+pub(crate) fn bytes<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+    deserializer: D,
+) -> Result<T, D::Error> {
+    quantity(deserializer, Unit::Bytes)
+}
+
+pub(crate) fn opt_secs<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    optional::<D, T, 0>(deserializer)
+}
+
+pub(crate) fn opt_millis<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    optional::<D, T, 1>(deserializer)
+}
+
+pub(crate) fn opt_bytes<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    optional::<D, T, 2>(deserializer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_convert_to_the_field_unit() {
+        assert_eq!(parse("30s", Unit::Secs), Ok(30));
+        assert_eq!(parse("5m", Unit::Secs), Ok(300));
+        assert_eq!(parse("1h30m", Unit::Secs), Ok(5400));
+        assert_eq!(parse("2s", Unit::Millis), Ok(2000));
+        assert_eq!(parse("250ms", Unit::Millis), Ok(250));
+        assert_eq!(
+            parse("1500ms", Unit::Secs),
+            Err("`1500ms` is not a whole number of seconds".into())
+        );
+        assert!(parse("30", Unit::Secs)
+            .unwrap_err()
+            .contains("missing unit"));
+        assert!(parse("5x", Unit::Secs)
+            .unwrap_err()
+            .contains("unknown unit `x`"));
+        assert!(parse("s", Unit::Secs).is_err());
+    }
+
+    #[test]
+    fn sizes_accept_decimal_and_binary_units() {
+        assert_eq!(parse("512", Unit::Bytes), Ok(512));
+        assert_eq!(parse("64KiB", Unit::Bytes), Ok(64 * 1024));
+        assert_eq!(parse("10MB", Unit::Bytes), Ok(10_000_000));
+        assert_eq!(parse("1 gib", Unit::Bytes), Ok(1 << 30));
+        assert!(parse("10 MiBs", Unit::Bytes).is_err());
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_field() {
+        #[derive(Debug, Deserialize)]
+        struct Limits {
+            #[serde(default, deserialize_with = "opt_secs", alias = "idle_timeout")]
+            idle_timeout_secs: Option<u64>,
+            #[serde(deserialize_with = "bytes")]
+            max_bytes: usize,
+        }
+        let limits: Limits = toml::from_str("idle_timeout = \"2m\"\nmax_bytes = 4096").unwrap();
+        assert_eq!(limits.idle_timeout_secs, Some(120));
+        assert_eq!(limits.max_bytes, 4096);
+
+        let err = toml::from_str::<Limits>("max_bytes = \"10 parsecs\"")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 1"), "{err}");
+        assert!(err.contains("max_bytes"), "{err}");
+        assert!(err.contains("unknown unit `parsecs`"), "{err}");
+    }
+}
@@ -0,0 +1,65 @@
+//! Windows paths written into TOML basic strings, as in
+//! `cert = "C:\certs\edge.pem"`.
+//!
+//! TOML reads backslashes in double-quoted strings as escapes, so such a path
+//! either fails to parse (`\c`) or silently changes (`\n`, `\t`). Strings
+//! starting with a drive letter get every backslash doubled before parsing;
+//! pairs already doubled stay as they are, so escaped paths, and `${VAR}`
+//! values spliced into either form, read the same. UNC and other paths should
+//! use literal strings: `'\\fileserver\certs\edge.pem'`.
+
+use std::{borrow::Cow, sync::OnceLock};
+
+use regex::{Captures, Regex};
+
+/// Escapes the backslashes of drive-letter paths in `source`'s basic strings.
+pub fn escape_backslashes(source: &str) -> Cow<'_, str> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let regex = RE.get_or_init(|| Regex::new(r#""[A-Za-z]:\\[^"\r\n]*""#).unwrap());
+    regex.replace_all(source, |caps: &Captures| {
+        let mut escaped = String::with_capacity(caps[0].len() + 8);
+        let mut chars = caps[0].chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                chars.next_if_eq(&'\\');
+                escaped.push_str(r"\\");
+            } else {
+                escaped.push(c);
+            }
+        }
+        escaped
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert(source: &str) -> String {
+        let table: toml::Table = toml::from_str(&escape_backslashes(source)).unwrap();
+        table["cert"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn drive_letter_paths_keep_their_backslashes() {
+        assert_eq!(cert(r#"cert = "C:\certs\edge.pem""#), r"C:\certs\edge.pem");
+        assert_eq!(
+            cert(r#"cert = "D:\new\tls\edge.pem""#),
+            r"D:\new\tls\edge.pem"
+        );
+        assert_eq!(
+            cert(r#"cert = "C:\\certs\\edge.pem""#),
+            r"C:\certs\edge.pem"
+        );
+        assert_eq!(cert(r#"cert = "C:\certs\\edge.pem""#), r"C:\certs\edge.pem");
+        assert_eq!(cert(r#"cert = "C:\certs\""#), r"C:\certs\");
+        assert_eq!(cert(r#"cert = 'C:\certs\edge.pem'"#), r"C:\certs\edge.pem");
+    }
+
+    #[test]
+    fn other_strings_are_left_alone() {
+        let source = "body = \"line\\nbreak\"\npath = \"/etc/jester\"\n";
+        assert!(matches!(escape_backslashes(source), Cow::Borrowed(_)));
+        assert_eq!(cert(r#"cert = "tab\there""#), "tab\there");
+    }
+}
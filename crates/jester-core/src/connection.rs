@@ -0,0 +1,252 @@
+//! Client connection lifecycle: byte accounting, metrics, and the optional
+//! connection log.
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::stats::{InflightGuard, RuntimeStats};
+
+/// One accepted client connection, from accept to close.
+pub(crate) struct Lifecycle {
+    listener: String,
+    peer_addr: SocketAddr,
+    log: bool,
+    accepted: Instant,
+    tls_handshake: Option<Duration>,
+    counters: Arc<Counters>,
+    _open: InflightGuard,
+}
+
+/// Shared between the connection task, its byte-counting stream, and the
+/// per-request service.
+#[derive(Default)]
+pub(crate) struct Counters {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    requests: AtomicU64,
+}
+
+impl Counters {
+    /// Counts a request and returns how many this connection has served.
+    pub(crate) fn request_served(&self) -> u64 {
+        self.requests.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Counts bytes that arrived without passing through a [`CountingStream`],
+    /// such as a datagram from the client.
+    pub(crate) fn received(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl Lifecycle {
+    pub(crate) fn accepted(
+        stats: &RuntimeStats,
+        listener: &str,
+        peer_addr: SocketAddr,
+        log: bool,
+    ) -> Self {
+        if log {
+            tracing::info!(target: "jester::connection", listener, %peer_addr, "connection accepted");
+        }
+        Self {
+            listener: listener.to_string(),
+            peer_addr,
+            log,
+            accepted: Instant::now(),
+            tls_handshake: None,
+            counters: Arc::default(),
+            _open: stats.track_client_connection(listener),
+        }
+    }
+
+    /// Moves the connection to `listener`, e.g. once SNI has picked it on a
+    /// shared socket.
+    pub(crate) fn reassign(&mut self, stats: &RuntimeStats, listener: &str, log: bool) {
+        if log {
+            tracing::info!(
+                target: "jester::connection",
+                listener,
+                peer_addr = %self.peer_addr,
+                from = self.listener,
+                "connection assigned by server name"
+            );
+        }
+        self.listener = listener.to_string();
+        self.log = log;
+        self._open = stats.track_client_connection(listener);
+    }
+
+    pub(crate) fn counters(&self) -> Arc<Counters> {
+        self.counters.clone()
+    }
+
+    pub(crate) fn tls_established(&mut self, handshake: Duration) {
+        self.tls_handshake = Some(handshake);
+        if self.log {
+            tracing::info!(
+                target: "jester::connection",
+                listener = self.listener,
+                peer_addr = %self.peer_addr,
+                tls_ms = handshake.as_secs_f64() * 1000.0,
+                "tls established"
+            );
+        }
+    }
+
+    /// Counts the client under the protocol it agreed on through ALPN, or
+    /// `none` if it offered no ALPN.
+    pub(crate) fn negotiated(&self, protocol: &'static str) {
+        metrics::counter!("jester_alpn_connections_total", "listener" => self.listener.clone(), "protocol" => protocol)
+            .increment(1);
+    }
+
+    /// Closes a connection whose protocol the listener does not serve.
+    pub(crate) fn alpn_rejected(self, reason: &'static str) {
+        metrics::counter!("jester_alpn_rejections_total", "listener" => self.listener.clone(), "reason" => reason)
+            .increment(1);
+        self.close("alpn_rejected");
+    }
+
+    /// Records the connection's totals; `reason` labels why it ended.
+    pub(crate) fn close(self, reason: &'static str) {
+        let duration = self.accepted.elapsed();
+        let requests = self.counters.requests.load(Ordering::Relaxed);
+        let bytes_in = self.counters.bytes_read.load(Ordering::Relaxed);
+        let bytes_out = self.counters.bytes_written.load(Ordering::Relaxed);
+        let listener = self.listener.clone();
+        metrics::counter!("jester_client_connections_total", "listener" => listener.clone(), "close" => reason)
+            .increment(1);
+        metrics::counter!("jester_client_bytes_received_total", "listener" => listener.clone())
+            .increment(bytes_in);
+        metrics::counter!("jester_client_bytes_sent_total", "listener" => listener.clone())
+            .increment(bytes_out);
+        metrics::histogram!("jester_client_connection_requests", "listener" => listener.clone())
+            .record(requests as f64);
+        metrics::histogram!("jester_client_connection_duration_seconds", "listener" => listener)
+            .record(duration.as_secs_f64());
+        if self.log {
+            tracing::info!(
+                target: "jester::connection",
+                listener = self.listener,
+                peer_addr = %self.peer_addr,
+                close = reason,
+                requests,
+                bytes_in,
+                bytes_out,
+                tls_ms = self.tls_handshake.map(|tls| tls.as_secs_f64() * 1000.0),
+                duration_ms = duration.as_secs_f64() * 1000.0,
+                "connection closed"
+            );
+        }
+    }
+}
+
+/// Why hyper stopped serving a connection.
+pub(crate) fn close_reason(err: &hyper::Error) -> &'static str {
+    if err.is_timeout() {
+        "timeout"
+    } else if err.is_incomplete_message() {
+        "client_reset"
+    } else if err.is_parse() || err.is_parse_too_large() {
+        "bad_request"
+    } else {
+        "error"
+    }
+}
+
+/// Counts raw bytes (TLS records included) moving through a client socket.
+pub(crate) struct CountingStream<S> {
+    inner: S,
+    counters: Arc<Counters>,
+}
+
+impl<S> CountingStream<S> {
+    pub(crate) fn new(inner: S, counters: Arc<Counters>) -> Self {
+        Self { inner, counters }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        self.counters
+            .bytes_read
+            .fetch_add(read as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = &poll {
+            self.counters
+                .bytes_written
+                .fetch_add(*written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn counts_bytes_and_open_connections() {
+        let stats = RuntimeStats::default();
+        let lifecycle =
+            Lifecycle::accepted(&stats, "edge", "127.0.0.1:50000".parse().unwrap(), false);
+        assert_eq!(stats.client_connections("edge"), 1);
+
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = CountingStream::new(server, lifecycle.counters());
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"hi").await.unwrap();
+        let counters = lifecycle.counters();
+        assert_eq!(counters.bytes_read.load(Ordering::Relaxed), 5);
+        assert_eq!(counters.bytes_written.load(Ordering::Relaxed), 2);
+
+        lifecycle.close("closed");
+        assert_eq!(stats.client_connections("edge"), 0);
+    }
+}
@@ -0,0 +1,191 @@
+//! Consul catalog queries behind `consul` upstreams.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use http::{HeaderValue, Request};
+use http_body_util::{BodyExt, Limited};
+use serde::Deserialize;
+
+use crate::{
+    client::{HttpClient, UpstreamClients},
+    config::{UpstreamTls, MAX_TARGET_WEIGHT},
+    plugin::full_body,
+    sigv4::uri_encode,
+    stats::RuntimeStats,
+};
+
+const MAX_RESPONSE_BYTES: usize = 4 * 1024 * 1024;
+/// Time on top of `wait` before a blocking query is abandoned; Consul adds
+/// up to `wait / 16` of jitter itself.
+const QUERY_GRACE: Duration = Duration::from_secs(10);
+
+/// A passing instance of a service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ServiceInstance {
+    pub(crate) address: String,
+    pub(crate) port: u16,
+    pub(crate) weight: u32,
+}
+
+/// Client for one Consul agent's HTTP API.
+pub(crate) struct Catalog {
+    http: HttpClient,
+    address: String,
+    token: Option<HeaderValue>,
+}
+
+impl Catalog {
+    /// `token` falls back to `CONSUL_HTTP_TOKEN`.
+    pub(crate) fn new(address: &str, token: Option<&str>) -> Result<Self> {
+        let token = token
+            .map(str::to_string)
+            .or_else(|| std::env::var("CONSUL_HTTP_TOKEN").ok())
+            .filter(|token| !token.is_empty())
+            .map(|token| HeaderValue::from_str(&token))
+            .transpose()
+            .context("invalid consul token")?;
+        Ok(Self {
+            http: UpstreamClients::new(RuntimeStats::default()).get(&UpstreamTls::default())?,
+            address: address.trim_end_matches('/').to_string(),
+            token,
+        })
+    }
+
+    /// Passing instances of `service`. With a non-zero `index` this is a
+    /// blocking query: Consul answers once the result changes or `wait`
+    /// elapses. Returns the index to pass next time.
+    pub(crate) async fn healthy(
+        &self,
+        service: &str,
+        tag: Option<&str>,
+        datacenter: Option<&str>,
+        index: u64,
+        wait: Duration,
+    ) -> Result<(u64, Vec<ServiceInstance>)> {
+        let mut url = format!(
+            "{}/v1/health/service/{}?passing=true&index={index}&wait={}s",
+            self.address,
+            uri_encode(service.as_bytes(), false),
+            wait.as_secs()
+        );
+        if let Some(tag) = tag {
+            url.push_str(&format!("&tag={}", uri_encode(tag.as_bytes(), false)));
+        }
+        if let Some(datacenter) = datacenter {
+            url.push_str(&format!("&dc={}", uri_encode(datacenter.as_bytes(), false)));
+        }
+        let mut request = Request::get(url.as_str());
+        if let Some(token) = &self.token {
+            request = request.header("x-consul-token", token);
+        }
+        let request = request.body(full_body(""))?;
+        let exchange = async {
+            let response = self.http.request(request).await?;
+            if !response.status().is_success() {
+                bail!("consul answered {}", response.status());
+            }
+            let next = response
+                .headers()
+                .get("x-consul-index")
+                .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+                .context("consul response has no X-Consul-Index")?;
+            let body = Limited::new(response.into_body(), MAX_RESPONSE_BYTES)
+                .collect()
+                .await
+                .map_err(|err| anyhow!(err))?
+                .to_bytes();
+            Ok::<_, anyhow::Error>((next, parse_health(&body)?))
+        };
+        let (next, instances) = tokio::time::timeout(wait + wait / 16 + QUERY_GRACE, exchange)
+            .await
+            .with_context(|| format!("query to {url} timed out"))?
+            .with_context(|| format!("query to {url} failed"))?;
+        // An index that goes backwards means Consul's state was reset; start
+        // over rather than block on an index it will not reach for a while.
+        let next = if next < index { 0 } else { next.max(1) };
+        Ok((next, instances))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthEntry {
+    node: NodeEntry,
+    service: ServiceEntry,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NodeEntry {
+    address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceEntry {
+    #[serde(default)]
+    address: String,
+    port: u16,
+    weights: Option<Weights>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Weights {
+    passing: u32,
+}
+
+/// Instances in a `/v1/health/service` answer, addressed by their service
+/// address or else their node's.
+fn parse_health(body: &[u8]) -> Result<Vec<ServiceInstance>> {
+    let entries: Vec<HealthEntry> =
+        serde_json::from_slice(body).context("malformed consul health response")?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| ServiceInstance {
+            address: match entry.service.address.is_empty() {
+                true => entry.node.address,
+                false => entry.service.address,
+            },
+            port: entry.service.port,
+            weight: entry
+                .service
+                .weights
+                .map_or(1, |weights| weights.passing)
+                .clamp(1, MAX_TARGET_WEIGHT),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instances_fall_back_to_the_node_address() {
+        let body = br#"[
+            {"Node": {"Node": "a", "Address": "10.0.0.1"},
+             "Service": {"ID": "api-1", "Address": "", "Port": 8080, "Weights": {"Passing": 3, "Warning": 1}},
+             "Checks": []},
+            {"Node": {"Node": "b", "Address": "10.0.0.2"},
+             "Service": {"ID": "api-2", "Address": "172.17.0.4", "Port": 9090},
+             "Checks": []}
+        ]"#;
+        assert_eq!(
+            parse_health(body).unwrap(),
+            [
+                ServiceInstance {
+                    address: "10.0.0.1".into(),
+                    port: 8080,
+                    weight: 3,
+                },
+                ServiceInstance {
+                    address: "172.17.0.4".into(),
+                    port: 9090,
+                    weight: 1,
+                },
+            ]
+        );
+    }
+}
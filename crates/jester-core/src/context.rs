@@ -0,0 +1,253 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use http::Request;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::{AbsoluteForm, MissingHost};
+
+/// The client connection a request arrived on; inserted into every request's
+/// extensions alongside [`RequestContext`].
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub listener: String,
+    /// `"https"` for TLS listeners.
+    pub scheme: &'static str,
+    pub local_addr: SocketAddr,
+    pub peer_addr: SocketAddr,
+    /// Source address from a trusted PROXY protocol header, else `peer_addr`.
+    pub client_addr: SocketAddr,
+    /// Whether `x-forwarded-*` headers from this client are trusted.
+    pub trust_forwarded_headers: bool,
+    /// Time spent on the TLS handshake when the connection was accepted.
+    pub tls_handshake: Duration,
+    pub missing_host: MissingHost,
+    pub absolute_form: AbsoluteForm,
+    /// The listener's `health_check_paths`, answered before routing.
+    pub health_check_paths: Arc<[String]>,
+    /// The certificate the client presented on a listener with
+    /// `tls.client_ca`, verified against it.
+    pub client_certificate: Option<Arc<ClientCertificate>>,
+}
+
+/// The parts of a verified client certificate requests are told about, also
+/// sent upstream as `x-client-cert-subject` and `x-client-cert-san`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// Short form of the subject, e.g. `CN=billing, O=Example`.
+    pub subject: String,
+    /// Subject alternative names, e.g. `DNS:billing.internal` or
+    /// `URI:spiffe://example/billing`.
+    pub subject_alt_names: Vec<String>,
+}
+
+/// The client's address under the listener's `client_ip` policy: the peer, or
+/// whom a trusted proxy says it relays. Inserted into every request's
+/// extensions; read it through [`ClientIp::of`] wherever the client matters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    /// Client address of `req`, falling back to the connection's peer.
+    pub fn of<B>(req: &Request<B>) -> Option<IpAddr> {
+        let extensions = req.extensions();
+        extensions
+            .get::<ClientIp>()
+            .map(|client| client.0)
+            .or_else(|| {
+                extensions
+                    .get::<ConnectionInfo>()
+                    .map(|connection| connection.peer_addr.ip())
+            })
+    }
+}
+
+/// Inserted into a request's extensions when it was read from TLS early data,
+/// before the client finished its handshake; such a request may be a replay.
+#[derive(Debug, Clone, Copy)]
+pub struct EarlyData;
+
+/// Per-request state shared between the connection handler, filters, and the
+/// upstream service.
+///
+/// A clone is inserted into every request's extensions before the global filter
+/// chain runs; all clones observe the same state. Filters pass values to the
+/// filters after them through [`RequestContext::set_value`]; whatever is set
+/// when the response is ready lands in the request's [`AccessEvent`].
+///
+/// [`AccessEvent`]: crate::tap::AccessEvent
+#[derive(Clone, Default)]
+pub struct RequestContext {
+    inner: Arc<Mutex<ContextState>>,
+}
+
+#[derive(Default)]
+struct ContextState {
+    route: Option<String>,
+    labels: Arc<BTreeMap<String, String>>,
+    received: Option<Instant>,
+    timings: Timings,
+    values: BTreeMap<String, Value>,
+}
+
+/// Where a request's time went, filled in as it moves through the proxy.
+///
+/// Phases that did not happen (no upstream call, a pooled connection, ...) are
+/// `None`.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Timings {
+    /// TLS handshake of the client connection the request arrived on.
+    pub tls_handshake: Option<Duration>,
+    /// Selecting the route.
+    pub routing: Option<Duration>,
+    /// From the request arriving until it was sent upstream: routing and filters.
+    pub queue: Option<Duration>,
+    /// Opening a new upstream connection; zero when a pooled one was reused.
+    pub upstream_connect: Option<Duration>,
+    /// From sending upstream until response headers arrived, excluding connect.
+    pub upstream_ttfb: Option<Duration>,
+    /// Reading the upstream response body; only measured when jester buffers it.
+    pub upstream_body: Option<Duration>,
+}
+
+impl Timings {
+    /// Time spent in request filters (global and route chains) before the
+    /// upstream call.
+    pub fn filters(&self) -> Option<Duration> {
+        self.queue
+            .map(|queue| queue.saturating_sub(self.routing.unwrap_or_default()))
+    }
+
+    /// Renders the phases as a `Server-Timing` header value, ending with `total`.
+    pub fn server_timing(&self, total: Duration) -> String {
+        let phases = [
+            ("tls", self.tls_handshake),
+            ("queue", self.queue),
+            ("connect", self.upstream_connect),
+            ("ttfb", self.upstream_ttfb),
+            ("body", self.upstream_body),
+            ("total", Some(total)),
+        ];
+        let mut value = String::new();
+        for (name, duration) in phases {
+            if let Some(duration) = duration {
+                if !value.is_empty() {
+                    value.push_str(", ");
+                }
+                write!(value, "{name};dur={:.3}", duration.as_secs_f64() * 1000.0)
+                    .expect("writing to a String cannot fail");
+            }
+        }
+        value
+    }
+}
+
+impl RequestContext {
+    /// Starts the context for a request that arrived at `received`.
+    pub(crate) fn received_at(received: Instant) -> Self {
+        let context = Self::default();
+        context.state().received = Some(received);
+        context
+    }
+
+    /// Phase timings recorded so far.
+    pub fn timings(&self) -> Timings {
+        self.state().timings
+    }
+
+    pub(crate) fn record_timings(&self, record: impl FnOnce(&mut Timings)) {
+        record(&mut self.state().timings);
+    }
+
+    /// Time since the request arrived.
+    pub(crate) fn elapsed(&self) -> Option<Duration> {
+        self.state().received.map(|received| received.elapsed())
+    }
+
+    /// Name of the route selected for this request, if routing has happened.
+    pub fn route(&self) -> Option<String> {
+        self.state().route.clone()
+    }
+
+    /// The selected route's configured `labels`; empty before routing.
+    pub fn route_labels(&self) -> Arc<BTreeMap<String, String>> {
+        self.state().labels.clone()
+    }
+
+    pub(crate) fn set_route(&self, name: &str, labels: &Arc<BTreeMap<String, String>>) {
+        let mut state = self.state();
+        state.route = Some(name.to_string());
+        state.labels = labels.clone();
+    }
+
+    /// Stores `value` under `key` for later filters and the access log,
+    /// returning what `key` held before. Keys are best namespaced by the
+    /// filter that owns them, such as `auth.user`.
+    pub fn set_value(&self, key: impl Into<String>, value: Value) -> Option<Value> {
+        self.state().values.insert(key.into(), value)
+    }
+
+    /// The value an earlier filter stored under `key`.
+    pub fn value(&self, key: &str) -> Option<Value> {
+        self.state().values.get(key).cloned()
+    }
+
+    /// Every value stored so far, by key.
+    pub fn values(&self) -> BTreeMap<String, Value> {
+        self.state().values.clone()
+    }
+
+    fn state(&self) -> MutexGuard<'_, ContextState> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_shared_between_clones() {
+        let context = RequestContext::default();
+        let later = context.clone();
+        assert_eq!(context.set_value("auth.user", "alice".into()), None);
+        assert_eq!(later.value("auth.user"), Some(Value::from("alice")));
+        assert_eq!(
+            later.set_value("auth.user", serde_json::json!({"id": 7})),
+            Some(Value::from("alice"))
+        );
+        assert_eq!(
+            context.values(),
+            BTreeMap::from([("auth.user".to_string(), serde_json::json!({"id": 7}))])
+        );
+        assert_eq!(context.value("missing"), None);
+    }
+
+    #[test]
+    fn server_timing_lists_recorded_phases() {
+        let timings = Timings {
+            queue: Some(Duration::from_micros(250)),
+            upstream_connect: Some(Duration::ZERO),
+            upstream_ttfb: Some(Duration::from_millis(12)),
+            ..Default::default()
+        };
+        assert_eq!(
+            timings.server_timing(Duration::from_millis(13)),
+            "queue;dur=0.250, connect;dur=0.000, ttfb;dur=12.000, total;dur=13.000"
+        );
+
+        let routed = Timings {
+            routing: Some(Duration::from_micros(50)),
+            ..timings
+        };
+        assert_eq!(routed.filters(), Some(Duration::from_micros(200)));
+    }
+}
@@ -0,0 +1,268 @@
+//! A minimal DNS client: just enough of the wire format for ACME DNS-01
+//! challenges and SRV service discovery.
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+use crate::config::parse_resolver;
+
+pub(crate) const CLASS_IN: u16 = 1;
+const TYPE_SRV: u16 = 33;
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+/// Compression pointers followed while reading one name, against loops.
+const MAX_POINTERS: usize = 32;
+
+/// One answer to an SRV query (RFC 2782).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SrvRecord {
+    pub(crate) priority: u16,
+    pub(crate) weight: u16,
+    pub(crate) port: u16,
+    pub(crate) target: String,
+}
+
+/// SRV records served for `name` by `resolver`; empty for a name that does
+/// not exist.
+pub(crate) async fn query_srv(resolver: SocketAddr, name: &str) -> Result<Vec<SrvRecord>> {
+    let mut query = header(rand_id(), 0x0100, [1, 0, 0, 0]);
+    encode_name(&mut query, name)?;
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    let response = exchange(resolver, &query).await?;
+    parse_srv_answers(&response)
+}
+
+/// The `nameserver` entries of `/etc/resolv.conf`.
+pub(crate) fn system_resolvers() -> Result<Vec<SocketAddr>> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf")
+        .context("cannot read /etc/resolv.conf; configure `resolvers`")?;
+    let resolvers = conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|server| parse_resolver(server.trim()).ok())
+        .collect::<Vec<_>>();
+    if resolvers.is_empty() {
+        bail!("no nameserver in /etc/resolv.conf; configure `resolvers`");
+    }
+    Ok(resolvers)
+}
+
+fn parse_srv_answers(message: &[u8]) -> Result<Vec<SrvRecord>> {
+    let malformed = || anyhow::anyhow!("malformed DNS response");
+    let u16_at = |at: usize| -> Result<u16> {
+        Ok(u16::from_be_bytes(
+            message.get(at..at + 2).ok_or_else(malformed)?.try_into()?,
+        ))
+    };
+    match message.get(3).ok_or_else(malformed)? & 0x0f {
+        0 => {}
+        3 => return Ok(Vec::new()),
+        rcode => bail!("DNS query failed: {}", rcode_name(rcode)),
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(message, at).ok_or_else(malformed)? + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        at = skip_name(message, at).ok_or_else(malformed)?;
+        let kind = u16_at(at)?;
+        let len = usize::from(u16_at(at + 8)?);
+        let rdata = at + 10;
+        message.get(rdata..rdata + len).ok_or_else(malformed)?;
+        at = rdata + len;
+        if kind != TYPE_SRV {
+            continue;
+        }
+        let target = read_name(message, rdata + 6).ok_or_else(malformed)?;
+        // "." means the service is decidedly not available (RFC 2782).
+        if target.is_empty() {
+            continue;
+        }
+        records.push(SrvRecord {
+            priority: u16_at(rdata)?,
+            weight: u16_at(rdata + 2)?,
+            port: u16_at(rdata + 4)?,
+            target,
+        });
+    }
+    Ok(records)
+}
+
+/// The name at `at`, following compression pointers, without the final dot.
+fn read_name(message: &[u8], mut at: usize) -> Option<String> {
+    let mut labels = Vec::new();
+    for _ in 0..MAX_POINTERS {
+        loop {
+            let len = *message.get(at)?;
+            match len {
+                0 => return Some(labels.join(".")),
+                len if len & 0xc0 == 0xc0 => {
+                    at = usize::from(u16::from_be_bytes([len & 0x3f, *message.get(at + 1)?]));
+                    break;
+                }
+                len => {
+                    let label = message.get(at + 1..at + 1 + usize::from(len))?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    at += 1 + usize::from(len);
+                }
+            }
+        }
+    }
+    None
+}
+
+pub(crate) fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        2 => "SERVFAIL".into(),
+        3 => "NXDOMAIN".into(),
+        5 => "REFUSED".into(),
+        8 => "NXRRSET".into(),
+        9 => "NOTAUTH (check the TSIG key)".into(),
+        10 => "NOTZONE".into(),
+        other => format!("rcode {other}"),
+    }
+}
+
+pub(crate) fn header(id: u16, flags: u16, counts: [u16; 4]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(512);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&flags.to_be_bytes());
+    for count in counts {
+        message.extend_from_slice(&count.to_be_bytes());
+    }
+    message
+}
+
+pub(crate) fn rand_id() -> u16 {
+    let mut id = [0; 2];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut id).ok();
+    u16::from_be_bytes(id)
+}
+
+pub(crate) fn encode_name(out: &mut Vec<u8>, name: &str) -> Result<()> {
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("invalid DNS name `{name}`");
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    Ok(())
+}
+
+/// Sends a message over UDP, retrying over TCP when the answer is truncated.
+pub(crate) async fn exchange(server: SocketAddr, message: &[u8]) -> Result<Vec<u8>> {
+    let local = match server {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let udp = async {
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(server).await?;
+        socket.send(message).await?;
+        let mut buf = vec![0; 4096];
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            if len >= 12 && buf[..2] == message[..2] {
+                buf.truncate(len);
+                return Ok::<_, anyhow::Error>(buf);
+            }
+        }
+    };
+    let response = tokio::time::timeout(DNS_TIMEOUT, udp)
+        .await
+        .with_context(|| format!("no DNS response from {server}"))??;
+    if response[2] & 0x02 == 0 {
+        return Ok(response);
+    }
+    let tcp = async {
+        let mut stream = TcpStream::connect(server).await?;
+        stream
+            .write_all(&(message.len() as u16).to_be_bytes())
+            .await?;
+        stream.write_all(message).await?;
+        let len = stream.read_u16().await?;
+        let mut buf = vec![0; usize::from(len)];
+        stream.read_exact(&mut buf).await?;
+        Ok::<_, anyhow::Error>(buf)
+    };
+    let response = tokio::time::timeout(DNS_TIMEOUT, tcp)
+        .await
+        .with_context(|| format!("no DNS response from {server} over TCP"))??;
+    if response.len() < 12 {
+        bail!("short DNS response from {server}");
+    }
+    Ok(response)
+}
+
+/// Offset just past the (possibly compressed) name at `at`.
+pub(crate) fn skip_name(message: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(at)?;
+        match len {
+            0 => return Some(at + 1),
+            len if len & 0xc0 == 0xc0 => return Some(at + 2),
+            len => at += 1 + usize::from(len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_srv_answers_with_compressed_targets() {
+        let mut response = header(7, 0x8180, [1, 3, 0, 0]);
+        encode_name(&mut response, "_http._tcp.example.com").unwrap();
+        response.extend_from_slice(&[0, 33, 0, 1]);
+        // web-1.example.com, spelled out.
+        response.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60, 0, 25]);
+        response.extend_from_slice(&[0, 10, 0, 60, 0x1f, 0x90]);
+        encode_name(&mut response, "web-1.example.com").unwrap();
+        // web-2 followed by a pointer to "example.com" in the question.
+        response.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60, 0, 14]);
+        response.extend_from_slice(&[0, 20, 0, 0, 0x1f, 0x91, 5]);
+        response.extend_from_slice(b"web-2");
+        response.extend_from_slice(&[0xc0, 23]);
+        // "." withdraws the service and is skipped.
+        response.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60, 0, 7]);
+        response.extend_from_slice(&[0, 10, 0, 0, 0, 0, 0]);
+
+        assert_eq!(
+            parse_srv_answers(&response).unwrap(),
+            [
+                SrvRecord {
+                    priority: 10,
+                    weight: 60,
+                    port: 8080,
+                    target: "web-1.example.com".into(),
+                },
+                SrvRecord {
+                    priority: 20,
+                    weight: 0,
+                    port: 8081,
+                    target: "web-2.example.com".into(),
+                },
+            ]
+        );
+        let nxdomain = header(7, 0x8183, [0, 0, 0, 0]);
+        assert!(parse_srv_answers(&nxdomain).unwrap().is_empty());
+        assert!(parse_srv_answers(&response[..response.len() - 3]).is_err());
+
+        let looping = [0xc0, 0];
+        assert_eq!(read_name(&looping, 0), None);
+    }
+}
@@ -0,0 +1,317 @@
+//! Winding down long-lived streams when the proxy drains.
+//!
+//! Listeners stop accepting as soon as shutdown begins, but WebSockets and
+//! server-sent event (SSE) streams would run until the process exits and
+//! then be cut mid-message. Instead each is told to go away at a message
+//! boundary: WebSockets get a `1001 Going Away` close frame, SSE streams a
+//! last event carrying `retry` before their body ends, so clients reconnect
+//! elsewhere. Streams still open once `[shutdown] stream_notice` has passed
+//! are cut; shutdown waits for the rest.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http::{header, HeaderMap};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use tokio::{sync::watch, time::Sleep};
+
+use crate::{
+    config::Shutdown,
+    plugin::{BoxError, HttpResponse, ProxyBody},
+};
+
+/// Drain state shared by the proxy and its open streams.
+#[derive(Clone)]
+pub(crate) struct Drain {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    draining: watch::Sender<bool>,
+    /// WebSocket and SSE streams currently open.
+    open: watch::Sender<usize>,
+    notice: Duration,
+    sse_retry: Duration,
+}
+
+impl Drain {
+    pub(crate) fn new(policy: &Shutdown) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                draining: watch::Sender::new(false),
+                open: watch::Sender::new(0),
+                notice: Duration::from_secs(policy.stream_notice_secs),
+                sse_retry: Duration::from_millis(policy.sse_retry_ms),
+            }),
+        }
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        *self.inner.draining.borrow()
+    }
+
+    /// How long streams get between their notice and being cut.
+    pub(crate) fn notice(&self) -> Duration {
+        self.inner.notice
+    }
+
+    /// Tells every open stream to go away.
+    pub(crate) fn start(&self) {
+        self.inner.draining.send_replace(true);
+    }
+
+    /// Resolves once draining starts.
+    pub(crate) fn started(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut draining = self.inner.draining.subscribe();
+        async move {
+            draining.wait_for(|draining| *draining).await.ok();
+        }
+    }
+
+    /// Counts a stream as open until the guard drops.
+    pub(crate) fn track(&self) -> StreamGuard {
+        self.inner.open.send_modify(|open| *open += 1);
+        StreamGuard {
+            drain: self.clone(),
+        }
+    }
+
+    /// Waits until every stream has finished, or the notice period has passed.
+    pub(crate) async fn streams_closed(&self) {
+        let mut open = self.inner.open.subscribe();
+        let closed = open.wait_for(|open| *open == 0);
+        if tokio::time::timeout(self.inner.notice, closed)
+            .await
+            .is_err()
+        {
+            tracing::info!(
+                open = *self.inner.open.borrow(),
+                "stream notice period over; cutting open streams"
+            );
+        }
+    }
+}
+
+/// Keeps a stream counted in [`Drain::streams_closed`].
+pub(crate) struct StreamGuard {
+    drain: Drain,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.drain.inner.open.send_modify(|open| *open -= 1);
+    }
+}
+
+/// Records how a stream ended after its notice: `closed` within the notice
+/// period, or `cut` at its end.
+pub(crate) fn record(kind: &'static str, outcome: &'static str) {
+    metrics::counter!("jester_drained_streams_total", "kind" => kind, "outcome" => outcome)
+        .increment(1);
+}
+
+/// Whether `headers` describe an SSE stream.
+pub(crate) fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("text/event-stream"))
+}
+
+/// Wraps an SSE response so it ends with a `retry` event once draining starts.
+pub(crate) fn event_stream(response: HttpResponse, drain: &Drain) -> HttpResponse {
+    let notice = drain.notice();
+    let last_event = Bytes::from(format!("retry: {}\n\n", drain.inner.sse_retry.as_millis()));
+    let started: Notice = Box::pin(drain.started());
+    let guard = drain.track();
+    response.map(|body| {
+        EventStream {
+            inner: body,
+            state: State::Streaming(Mutex::new(started)),
+            boundary: Boundary::default(),
+            notice,
+            last_event: Some(last_event),
+            _guard: guard,
+        }
+        .boxed()
+    })
+}
+
+type Notice = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+enum State {
+    /// `Mutex` only makes the notice future `Sync`; it is never contended.
+    Streaming(Mutex<Notice>),
+    /// Told to go away: ends at the next event boundary or at the deadline.
+    Draining(Pin<Box<Sleep>>),
+    Done,
+}
+
+struct EventStream {
+    inner: ProxyBody,
+    state: State,
+    boundary: Boundary,
+    notice: Duration,
+    last_event: Option<Bytes>,
+    _guard: StreamGuard,
+}
+
+impl Body for EventStream {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = &mut *self;
+        if let State::Streaming(started) = &mut this.state {
+            let started = started
+                .get_mut()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if started.as_mut().poll(cx).is_ready() {
+                this.state = State::Draining(Box::pin(tokio::time::sleep(this.notice)));
+            }
+        }
+        if let State::Draining(deadline) = &mut this.state {
+            if this.boundary.at_event_end() {
+                this.state = State::Done;
+                record("sse", "closed");
+                return Poll::Ready(this.last_event.take().map(|event| Ok(Frame::data(event))));
+            }
+            if deadline.as_mut().poll(cx).is_ready() {
+                this.state = State::Done;
+                record("sse", "cut");
+                return Poll::Ready(None);
+            }
+        }
+        if matches!(this.state, State::Done) {
+            return Poll::Ready(None);
+        }
+        let frame = std::task::ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    this.boundary.feed(data);
+                }
+            }
+            // The upstream ended the stream itself.
+            Some(Err(_)) | None => this.state = State::Done,
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        matches!(self.state, State::Done) || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
+
+/// Tracks whether the bytes of an event stream so far end between events: an
+/// event ends at a blank line, whatever mix of `\n`, `\r\n`, and `\r` ends
+/// its lines.
+#[derive(Default)]
+struct Boundary {
+    /// Whether the last line seen was empty (or nothing has been seen).
+    line_empty: bool,
+    /// The bytes so far end with a line ending; `\r` may still be joined by `\n`.
+    at_line_start: bool,
+    last: u8,
+    seen: bool,
+}
+
+impl Boundary {
+    fn feed(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.seen = true;
+            match byte {
+                b'\n' if self.last == b'\r' => {}
+                b'\n' | b'\r' => {
+                    self.line_empty = self.at_line_start;
+                    self.at_line_start = true;
+                }
+                _ => {
+                    self.at_line_start = false;
+                    self.line_empty = false;
+                }
+            }
+            self.last = byte;
+        }
+    }
+
+    fn at_event_end(&self) -> bool {
+        !self.seen || (self.at_line_start && self.line_empty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Response;
+
+    use super::*;
+    use crate::plugin::full_body;
+
+    fn boundary(chunks: &[&str]) -> bool {
+        let mut boundary = Boundary::default();
+        for chunk in chunks {
+            boundary.feed(chunk.as_bytes());
+        }
+        boundary.at_event_end()
+    }
+
+    #[test]
+    fn event_boundaries_follow_blank_lines() {
+        assert!(boundary(&[]));
+        assert!(boundary(&["data: a\n\n"]));
+        assert!(boundary(&["data: a\r\n", "\r\n"]));
+        assert!(boundary(&["data: a\r\r"]));
+        assert!(!boundary(&["data: a\n"]));
+        assert!(!boundary(&["data: a\r\n"]));
+        assert!(!boundary(&["data: a\n\ndata: b"]));
+    }
+
+    #[test]
+    fn event_stream_content_types_are_recognized() {
+        let headers = |content_type: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            headers
+        };
+        assert!(is_event_stream(&headers("text/event-stream")));
+        assert!(is_event_stream(&headers(
+            "Text/Event-Stream; charset=utf-8"
+        )));
+        assert!(!is_event_stream(&headers("text/plain")));
+    }
+
+    #[tokio::test]
+    async fn draining_streams_end_with_a_retry_event() {
+        let drain = Drain::new(&Shutdown {
+            stream_notice_secs: 5,
+            sse_retry_ms: 2500,
+        });
+        let response = event_stream(Response::new(full_body("data: a\n\n")), &drain);
+        let mut body = response.into_body();
+        let first = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(first, "data: a\n\n");
+        drain.start();
+        let last = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(last, "retry: 2500\n\n");
+        assert!(body.frame().await.is_none());
+        drop(body);
+        tokio::time::timeout(Duration::from_secs(1), drain.streams_closed())
+            .await
+            .unwrap();
+    }
+}
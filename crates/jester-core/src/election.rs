@@ -0,0 +1,644 @@
+//! Leader election between cluster members, so singleton tasks such as ACME
+//! renewals run on one instance at a time.
+//!
+//! The leader holds a lease in Redis or Kubernetes and renews it every third
+//! of its duration. Any error stepping through a round makes an instance
+//! step down: a missed renewal is cheaper than two instances both believing
+//! they lead. For the same reason a round is cut short once the lease has
+//! run out since the last renewal, so a slow backend cannot keep an instance
+//! leading after another could have taken over.
+
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use http::{header, Method, Request, StatusCode};
+use http_body_util::{BodyExt, Limited};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::watch,
+};
+
+use crate::{
+    acme::civil_from_unix,
+    client::{HttpClient, UpstreamClients},
+    cluster::Gossip,
+    config::{Election, UpstreamTls},
+    plugin::full_body,
+    stats::RuntimeStats,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Takes and renews the lease for `gossip`'s node until `shutdown` fires,
+/// then releases it.
+pub(crate) async fn run(gossip: Arc<Gossip>, mut shutdown: watch::Receiver<bool>) {
+    let Some(election) = gossip.election().cloned() else {
+        return;
+    };
+    let mut lease = match Lease::new(&election, gossip.node()) {
+        Ok(lease) => lease,
+        Err(err) => {
+            tracing::error!(error = %format!("{err:#}"), "leader election disabled; this instance will not lead");
+            return;
+        }
+    };
+    let interval = lease.duration() / 3;
+    let mut leading = false;
+    let mut renewed = None;
+    loop {
+        let started = Instant::now();
+        let leader = round(&mut lease, renewed).await;
+        renewed = leader.then_some(started);
+        if leader != leading {
+            match leader {
+                true => tracing::info!(node = gossip.node(), "acquired leadership"),
+                false => tracing::warn!(node = gossip.node(), "lost leadership"),
+            }
+            leading = leader;
+        }
+        gossip.set_leader(leader);
+        metrics::gauge!("jester_cluster_leader").set(if leader { 1.0 } else { 0.0 });
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        }
+    }
+    gossip.set_leader(false);
+    if leading {
+        if let Err(err) = lease.release().await {
+            tracing::warn!(error = %format!("{err:#}"), "failed to release leadership");
+        }
+    }
+}
+
+/// Runs `task` if `node` can take the lease, renewing it until `task` is
+/// done, and leaves the lease held for [`run`] to keep. `None` when another
+/// node leads, or once this one loses the lease mid-task, which drops `task`.
+pub(crate) async fn while_leading<F: Future>(
+    election: &Election,
+    node: &str,
+    task: F,
+) -> Result<Option<F::Output>> {
+    let mut lease = Lease::new(election, node)?;
+    let mut renewed = Instant::now();
+    if !lease.acquire().await? {
+        return Ok(None);
+    }
+    let interval = lease.duration() / 3;
+    let mut task = std::pin::pin!(task);
+    loop {
+        tokio::select! {
+            output = &mut task => return Ok(Some(output)),
+            _ = tokio::time::sleep(interval) => {
+                let started = Instant::now();
+                if !round(&mut lease, Some(renewed)).await {
+                    tracing::warn!(node, "lost leadership mid-task");
+                    return Ok(None);
+                }
+                renewed = started;
+            }
+        }
+    }
+}
+
+/// Takes or renews the lease; `true` when this node holds it afterwards.
+/// When the lease was last renewed by a round that started at `renewed`, the
+/// round gives up once the lease has run out since then.
+async fn round(lease: &mut Lease, renewed: Option<Instant>) -> bool {
+    let budget = match renewed {
+        Some(renewed) => lease.duration().saturating_sub(renewed.elapsed()),
+        None => lease.duration(),
+    };
+    match tokio::time::timeout(budget, lease.acquire()).await {
+        Ok(Ok(leader)) => leader,
+        Ok(Err(err)) => {
+            tracing::warn!(error = %format!("{err:#}"), "leader election round failed");
+            false
+        }
+        Err(_) => {
+            lease.reset();
+            tracing::warn!("leader election round outlasted the lease");
+            false
+        }
+    }
+}
+
+enum Lease {
+    Redis(RedisLease),
+    Kubernetes(Box<KubernetesLease>),
+}
+
+impl Lease {
+    fn new(election: &Election, node: &str) -> Result<Self> {
+        Ok(match election {
+            Election::Redis {
+                address,
+                key,
+                password,
+                lease_secs,
+            } => Lease::Redis(RedisLease {
+                address: address.clone(),
+                key: key.clone(),
+                password: password.clone(),
+                duration: Duration::from_secs(*lease_secs),
+                node: node.to_string(),
+                connection: None,
+            }),
+            Election::Kubernetes {
+                lease,
+                namespace,
+                lease_secs,
+            } => Lease::Kubernetes(Box::new(KubernetesLease::new(
+                lease,
+                namespace.as_deref(),
+                *lease_secs,
+                node,
+            )?)),
+        })
+    }
+
+    fn duration(&self) -> Duration {
+        match self {
+            Lease::Redis(redis) => redis.duration,
+            Lease::Kubernetes(kubernetes) => Duration::from_secs(kubernetes.lease_secs),
+        }
+    }
+
+    /// Takes the lease if it is free or expired, or renews it if held;
+    /// `true` when this node holds it afterwards.
+    async fn acquire(&mut self) -> Result<bool> {
+        match self {
+            Lease::Redis(redis) => redis.acquire().await,
+            Lease::Kubernetes(kubernetes) => kubernetes.acquire().await,
+        }
+    }
+
+    async fn release(&mut self) -> Result<()> {
+        match self {
+            Lease::Redis(redis) => redis.release().await,
+            Lease::Kubernetes(kubernetes) => kubernetes.release().await,
+        }
+    }
+
+    /// Forgets a connection left mid-command by a round that was cut short.
+    fn reset(&mut self) {
+        if let Lease::Redis(redis) = self {
+            redis.connection = None;
+        }
+    }
+}
+
+/// Renews the key when this node holds it, and takes it when nobody does.
+const REDIS_ACQUIRE: &str = "\
+local holder = redis.call('get', KEYS[1])
+if holder == ARGV[1] then
+  redis.call('pexpire', KEYS[1], ARGV[2])
+  return 1
+end
+if not holder then
+  redis.call('set', KEYS[1], ARGV[1], 'PX', ARGV[2])
+  return 1
+end
+return 0";
+
+const REDIS_RELEASE: &str = "\
+if redis.call('get', KEYS[1]) == ARGV[1] then
+  return redis.call('del', KEYS[1])
+end
+return 0";
+
+struct RedisLease {
+    address: String,
+    key: String,
+    password: Option<String>,
+    duration: Duration,
+    node: String,
+    connection: Option<RedisConnection>,
+}
+
+impl RedisLease {
+    async fn acquire(&mut self) -> Result<bool> {
+        let ttl = self.duration.as_millis().to_string();
+        let reply = self.eval(REDIS_ACQUIRE, &ttl).await?;
+        Ok(reply == RedisReply::Integer(1))
+    }
+
+    async fn release(&mut self) -> Result<()> {
+        self.eval(REDIS_RELEASE, "").await.map(drop)
+    }
+
+    async fn eval(&mut self, script: &str, arg: &str) -> Result<RedisReply> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => self
+                .connection
+                .insert(RedisConnection::connect(&self.address, self.password.as_deref()).await?),
+        };
+        let result = connection
+            .command(&["EVAL", script, "1", &self.key, &self.node, arg])
+            .await;
+        if result.is_err() {
+            // Reconnect next round rather than reuse a stream in an unknown
+            // state.
+            self.connection = None;
+        }
+        result
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RedisReply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+}
+
+struct RedisConnection {
+    stream: BufReader<TcpStream>,
+}
+
+impl RedisConnection {
+    async fn connect(address: &str, password: Option<&str>) -> Result<Self> {
+        let stream = tokio::time::timeout(REQUEST_TIMEOUT, TcpStream::connect(address))
+            .await
+            .with_context(|| format!("connecting to redis at {address} timed out"))?
+            .with_context(|| format!("failed to connect to redis at {address}"))?;
+        let mut connection = Self {
+            stream: BufReader::new(stream),
+        };
+        if let Some(password) = password {
+            connection
+                .command(&["AUTH", password])
+                .await
+                .context("redis AUTH failed")?;
+        }
+        Ok(connection)
+    }
+
+    async fn command(&mut self, args: &[&str]) -> Result<RedisReply> {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg.as_bytes());
+            request.extend_from_slice(b"\r\n");
+        }
+        let exchange = async {
+            self.stream.get_mut().write_all(&request).await?;
+            self.read_reply().await
+        };
+        tokio::time::timeout(REQUEST_TIMEOUT, exchange)
+            .await
+            .context("redis command timed out")?
+    }
+
+    async fn read_reply(&mut self) -> Result<RedisReply> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            bail!("redis closed the connection");
+        }
+        let line = line.trim_end_matches("\r\n");
+        let (kind, rest) = line.split_at(line.len().min(1));
+        match kind {
+            "+" => Ok(RedisReply::Status(rest.to_string())),
+            "-" => bail!("redis error: {rest}"),
+            ":" => Ok(RedisReply::Integer(
+                rest.parse().context("malformed redis integer")?,
+            )),
+            "$" => {
+                let len: i64 = rest.parse().context("malformed redis bulk length")?;
+                if len < 0 {
+                    return Ok(RedisReply::Bulk(None));
+                }
+                if len as usize > MAX_RESPONSE_BYTES {
+                    bail!("redis reply of {len} bytes is too large");
+                }
+                let mut data = vec![0; len as usize + 2];
+                self.stream.read_exact(&mut data).await?;
+                data.truncate(len as usize);
+                Ok(RedisReply::Bulk(Some(data)))
+            }
+            _ => bail!("unexpected redis reply `{line}`"),
+        }
+    }
+}
+
+/// A `coordination.k8s.io/v1` Lease. Expiry is judged by how long the
+/// lease has gone unchanged on this node's clock, as client-go does, so
+/// clock skew between nodes does not matter.
+struct KubernetesLease {
+    http: HttpClient,
+    url: String,
+    collection: String,
+    name: String,
+    lease_secs: u64,
+    node: String,
+    /// The last resource version seen, and when it was first seen.
+    observed: Option<(String, Instant)>,
+}
+
+impl KubernetesLease {
+    fn new(name: &str, namespace: Option<&str>, lease_secs: u64, node: &str) -> Result<Self> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")
+            .context("KUBERNETES_SERVICE_HOST is not set; is jester running in a pod?")?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".into());
+        let host = match host.contains(':') {
+            true => format!("[{host}]"),
+            false => host,
+        };
+        let namespace = match namespace {
+            Some(namespace) => namespace.to_string(),
+            None => std::fs::read_to_string(format!("{SERVICE_ACCOUNT}/namespace"))
+                .context("failed to read the pod's namespace")?
+                .trim()
+                .to_string(),
+        };
+        let tls = UpstreamTls {
+            ca_file: Some(format!("{SERVICE_ACCOUNT}/ca.crt")),
+            ..UpstreamTls::default()
+        };
+        let collection = format!(
+            "https://{host}:{port}/apis/coordination.k8s.io/v1/namespaces/{namespace}/leases"
+        );
+        Ok(Self {
+            http: UpstreamClients::new(RuntimeStats::default()).get(&tls)?,
+            url: format!("{collection}/{name}"),
+            collection,
+            name: name.to_string(),
+            lease_secs,
+            node: node.to_string(),
+            observed: None,
+        })
+    }
+
+    async fn acquire(&mut self) -> Result<bool> {
+        let (status, body) = self.send(Method::GET, &self.url, None).await?;
+        if status == StatusCode::NOT_FOUND {
+            let lease = json!({
+                "apiVersion": "coordination.k8s.io/v1",
+                "kind": "Lease",
+                "metadata": { "name": self.name },
+                "spec": {
+                    "holderIdentity": self.node,
+                    "leaseDurationSeconds": self.lease_secs,
+                    "acquireTime": micro_time(),
+                    "renewTime": micro_time(),
+                    "leaseTransitions": 0,
+                },
+            });
+            let (status, body) = self
+                .send(Method::POST, &self.collection, Some(lease))
+                .await?;
+            return self.written(status, &body);
+        }
+        if !status.is_success() {
+            bail!(
+                "reading lease answered {status}: {}",
+                String::from_utf8_lossy(&body)
+            );
+        }
+        let mut lease: Value = serde_json::from_slice(&body).context("malformed lease")?;
+        let version = lease["metadata"]["resourceVersion"]
+            .as_str()
+            .context("lease has no resourceVersion")?
+            .to_string();
+        if self.observed.as_ref().map(|(seen, _)| seen) != Some(&version) {
+            self.observed = Some((version, Instant::now()));
+        }
+        let spec = &lease["spec"];
+        let holder = spec["holderIdentity"].as_str().unwrap_or_default();
+        let duration = spec["leaseDurationSeconds"]
+            .as_u64()
+            .unwrap_or(self.lease_secs);
+        let expired = self
+            .observed
+            .as_ref()
+            .is_some_and(|(_, since)| since.elapsed() >= Duration::from_secs(duration));
+        if holder != self.node && !holder.is_empty() && !expired {
+            return Ok(false);
+        }
+        let now = micro_time();
+        if holder != self.node {
+            let transitions = spec["leaseTransitions"].as_u64().unwrap_or(0);
+            lease["spec"]["acquireTime"] = json!(now);
+            lease["spec"]["leaseTransitions"] = json!(transitions + 1);
+        }
+        lease["spec"]["holderIdentity"] = json!(self.node);
+        lease["spec"]["leaseDurationSeconds"] = json!(self.lease_secs);
+        lease["spec"]["renewTime"] = json!(now);
+        let (status, body) = self.send(Method::PUT, &self.url, Some(lease)).await?;
+        self.written(status, &body)
+    }
+
+    /// Outcome of creating or updating the lease: a conflict means another
+    /// node wrote it first.
+    fn written(&mut self, status: StatusCode, body: &[u8]) -> Result<bool> {
+        if status == StatusCode::CONFLICT {
+            return Ok(false);
+        }
+        if !status.is_success() {
+            bail!(
+                "writing lease answered {status}: {}",
+                String::from_utf8_lossy(body)
+            );
+        }
+        let lease: Value = serde_json::from_slice(body).context("malformed lease")?;
+        if let Some(version) = lease["metadata"]["resourceVersion"].as_str() {
+            self.observed = Some((version.to_string(), Instant::now()));
+        }
+        Ok(true)
+    }
+
+    /// Hands the lease back so another node can take it at once.
+    async fn release(&mut self) -> Result<()> {
+        let (status, body) = self.send(Method::GET, &self.url, None).await?;
+        if !status.is_success() {
+            bail!("reading lease answered {status}");
+        }
+        let mut lease: Value = serde_json::from_slice(&body).context("malformed lease")?;
+        if lease["spec"]["holderIdentity"].as_str() != Some(self.node.as_str()) {
+            return Ok(());
+        }
+        lease["spec"]["holderIdentity"] = Value::Null;
+        lease["spec"]["leaseDurationSeconds"] = json!(1);
+        let (status, _) = self.send(Method::PUT, &self.url, Some(lease)).await?;
+        if !status.is_success() && status != StatusCode::CONFLICT {
+            bail!("releasing lease answered {status}");
+        }
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<Value>,
+    ) -> Result<(StatusCode, Bytes)> {
+        // Projected tokens rotate, so read it for every request.
+        let token = std::fs::read_to_string(format!("{SERVICE_ACCOUNT}/token"))
+            .context("failed to read the service account token")?;
+        let request = Request::builder()
+            .method(method)
+            .uri(url)
+            .header(header::AUTHORIZATION, format!("Bearer {}", token.trim()))
+            .header(header::ACCEPT, "application/json");
+        let request = match body {
+            Some(body) => request
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(full_body(serde_json::to_vec(&body)?))?,
+            None => request.body(full_body(""))?,
+        };
+        let exchange = async {
+            let response = self.http.request(request).await?;
+            let status = response.status();
+            let body = Limited::new(response.into_body(), MAX_RESPONSE_BYTES)
+                .collect()
+                .await
+                .map_err(|err| anyhow!(err))?
+                .to_bytes();
+            Ok::<_, anyhow::Error>((status, body))
+        };
+        tokio::time::timeout(REQUEST_TIMEOUT, exchange)
+            .await
+            .with_context(|| format!("request to {url} timed out"))?
+            .with_context(|| format!("request to {url} failed"))
+    }
+}
+
+/// The current time as a Kubernetes `MicroTime`.
+fn micro_time() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let (year, month, day, hour, minute, second) = civil_from_unix(now.as_secs());
+    format!(
+        "{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{:06}Z",
+        now.subsec_micros()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Answers `EVAL` like a Redis holding `holder`'s key, replying to the
+    /// acquire script with `1` for the holder and `0` for anyone else.
+    async fn fake_redis(holder: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    loop {
+                        let mut args = Vec::new();
+                        let mut line = String::new();
+                        if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let count: usize = line[1..].trim().parse().unwrap();
+                        for _ in 0..count {
+                            line.clear();
+                            stream.read_line(&mut line).await.unwrap();
+                            let len: usize = line[1..].trim().parse().unwrap();
+                            let mut arg = vec![0; len + 2];
+                            stream.read_exact(&mut arg).await.unwrap();
+                            arg.truncate(len);
+                            args.push(String::from_utf8(arg).unwrap());
+                        }
+                        let reply = match args[0].as_str() {
+                            "AUTH" if args[1] == "secret" => "+OK\r\n".to_string(),
+                            "AUTH" => "-WRONGPASS invalid password\r\n".to_string(),
+                            _ => format!(":{}\r\n", u8::from(args[4] == holder)),
+                        };
+                        stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    fn redis(address: &str, node: &str, password: &str) -> Lease {
+        let election = Election::Redis {
+            address: address.into(),
+            key: "jester-leader".into(),
+            password: Some(password.into()),
+            lease_secs: 15,
+        };
+        Lease::new(&election, node).unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_the_redis_key_holder_leads() {
+        let address = fake_redis("a").await;
+        assert!(redis(&address, "a", "secret").acquire().await.unwrap());
+        assert!(!redis(&address, "b", "secret").acquire().await.unwrap());
+        let err = redis(&address, "a", "wrong").acquire().await.unwrap_err();
+        assert!(format!("{err:#}").contains("WRONGPASS"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn only_the_leader_runs_singleton_tasks() {
+        let address = fake_redis("a").await;
+        let election = Election::Redis {
+            address,
+            key: "jester-leader".into(),
+            password: None,
+            lease_secs: 15,
+        };
+        let ran = while_leading(&election, "a", async { 7 }).await.unwrap();
+        assert_eq!(ran, Some(7));
+        let ran = while_leading(&election, "b", async { unreachable!() })
+            .await
+            .unwrap();
+        assert_eq!(ran, None::<()>);
+    }
+
+    #[tokio::test]
+    async fn leaders_step_down_when_a_renewal_outlasts_the_lease() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            // Accepts and never answers.
+            let mut open = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                open.push(stream);
+            }
+        });
+        let mut lease = Lease::Redis(RedisLease {
+            address,
+            key: "jester-leader".into(),
+            password: None,
+            duration: Duration::from_millis(300),
+            node: "a".into(),
+            connection: None,
+        });
+        let renewed = Instant::now() - Duration::from_millis(100);
+        let started = Instant::now();
+        assert!(!round(&mut lease, Some(renewed)).await);
+        let took = started.elapsed();
+        assert!(took < Duration::from_secs(1), "{took:?}");
+        let Lease::Redis(redis) = &lease else {
+            unreachable!()
+        };
+        assert!(redis.connection.is_none());
+    }
+
+    #[test]
+    fn micro_times_have_six_fractional_digits() {
+        let time = micro_time();
+        assert_eq!(time.len(), "2024-01-01T00:00:00.000000Z".len(), "{time}");
+        assert!(time.ends_with('Z'));
+    }
+}
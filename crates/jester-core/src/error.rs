@@ -0,0 +1,301 @@
+use std::{error::Error, fmt, io};
+
+use http::StatusCode;
+use tokio_rustls::rustls;
+
+use crate::plugin::{text_response, BoxError, HttpResponse};
+
+/// Classified failure on the request path.
+///
+/// Services return `anyhow::Error`; a `ProxyError` anywhere in its chain decides the
+/// status code and `kind` label of the response. Unclassified errors are treated as
+/// upstream protocol errors.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// Could not establish a connection to the upstream.
+    Connect(BoxError),
+    /// TLS handshake with the upstream failed.
+    Tls(BoxError),
+    /// The request did not complete within its deadline; `body` overrides the
+    /// default response text.
+    Timeout { body: Option<String> },
+    /// A request or response body exceeded `limit` bytes.
+    BodyTooLarge { limit: u64 },
+    /// The upstream answered with something that is not valid HTTP, or hung up mid-response.
+    UpstreamProtocol(BoxError),
+    /// No upstream is currently able to take the request.
+    Unavailable(String),
+    /// A filter refused the request.
+    Rejected { status: StatusCode, reason: String },
+}
+
+impl ProxyError {
+    /// Finds a `ProxyError` in `err`'s chain, falling back to classifying the
+    /// transport errors raised by the upstream client.
+    pub fn classify(err: anyhow::Error) -> Self {
+        if let Some(found) = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<ProxyError>())
+        {
+            return found.shallow_clone();
+        }
+        if err
+            .chain()
+            .any(|cause| cause.is::<tower::timeout::error::Elapsed>())
+        {
+            return ProxyError::Timeout { body: None };
+        }
+        match err.downcast::<hyper_util::client::legacy::Error>() {
+            Ok(client_err) => Self::from(client_err),
+            Err(other) => ProxyError::UpstreamProtocol(other.into()),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::Connect(_) | ProxyError::Tls(_) | ProxyError::UpstreamProtocol(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            ProxyError::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::BodyTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ProxyError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::Rejected { status, .. } => *status,
+        }
+    }
+
+    /// Stable label used in logs and metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProxyError::Connect(_) => "connect",
+            ProxyError::Tls(_) => "tls",
+            ProxyError::Timeout { .. } => "timeout",
+            ProxyError::BodyTooLarge { .. } => "body_too_large",
+            ProxyError::UpstreamProtocol(_) => "upstream_protocol",
+            ProxyError::Unavailable(_) => "unavailable",
+            ProxyError::Rejected { .. } => "rejected",
+        }
+    }
+
+    /// Response sent to the client for this error.
+    pub fn to_response(&self) -> HttpResponse {
+        let body = match self {
+            ProxyError::Connect(_) | ProxyError::Tls(_) | ProxyError::UpstreamProtocol(_) => {
+                "upstream error".to_string()
+            }
+            ProxyError::Timeout { body } => body
+                .clone()
+                .unwrap_or_else(|| "upstream timed out".to_string()),
+            ProxyError::BodyTooLarge { .. } => "payload too large".to_string(),
+            ProxyError::Unavailable(_) => "upstream unavailable".to_string(),
+            ProxyError::Rejected { reason, .. } => reason.clone(),
+        };
+        text_response(self.status(), body)
+    }
+
+    /// Copies the classification; wrapped sources are flattened to their message.
+    fn shallow_clone(&self) -> Self {
+        let message = |err: &BoxError| -> BoxError { err.to_string().into() };
+        match self {
+            ProxyError::Connect(err) => ProxyError::Connect(message(err)),
+            ProxyError::Tls(err) => ProxyError::Tls(message(err)),
+            ProxyError::Timeout { body } => ProxyError::Timeout { body: body.clone() },
+            ProxyError::BodyTooLarge { limit } => ProxyError::BodyTooLarge { limit: *limit },
+            ProxyError::UpstreamProtocol(err) => ProxyError::UpstreamProtocol(message(err)),
+            ProxyError::Unavailable(reason) => ProxyError::Unavailable(reason.clone()),
+            ProxyError::Rejected { status, reason } => ProxyError::Rejected {
+                status: *status,
+                reason: reason.clone(),
+            },
+        }
+    }
+}
+
+impl From<hyper_util::client::legacy::Error> for ProxyError {
+    fn from(err: hyper_util::client::legacy::Error) -> Self {
+        if err.is_connect() {
+            // The connector reports TLS handshake failures as `ProxyError::Tls`.
+            let tls =
+                sources(&err).any(|cause| matches!(cause.downcast_ref(), Some(ProxyError::Tls(_))));
+            if tls {
+                return ProxyError::Tls(err.into());
+            }
+            ProxyError::Connect(err.into())
+        } else {
+            ProxyError::UpstreamProtocol(err.into())
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Transport errors are named after their innermost cause; wrappers such
+        // as "client error (Connect)" say little.
+        let root = |err: &BoxError| sources(err.as_ref()).last().map(ToString::to_string);
+        match self {
+            ProxyError::Connect(err) => {
+                write!(f, "failed to connect to upstream: {}", root(err).unwrap())
+            }
+            ProxyError::Tls(err) => {
+                write!(f, "upstream TLS handshake failed: {}", root(err).unwrap())
+            }
+            ProxyError::Timeout { .. } => f.write_str("request timed out"),
+            ProxyError::BodyTooLarge { limit } => write!(f, "body exceeds {limit} bytes"),
+            ProxyError::UpstreamProtocol(err) => {
+                write!(f, "upstream protocol error: {}", root(err).unwrap())
+            }
+            ProxyError::Unavailable(reason) => write!(f, "upstream unavailable: {reason}"),
+            ProxyError::Rejected { status, reason } => {
+                write!(f, "rejected by filter ({status}): {reason}")
+            }
+        }
+    }
+}
+
+impl Error for ProxyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProxyError::Connect(err) | ProxyError::Tls(err) | ProxyError::UpstreamProtocol(err) => {
+                Some(err.as_ref())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// `err` followed by each of its sources.
+fn sources<'a>(err: &'a (dyn Error + 'static)) -> impl Iterator<Item = &'a (dyn Error + 'static)> {
+    std::iter::successors(Some(err), |&err| err.source())
+}
+
+/// Why a request failed, in finer detail than [`ProxyError::kind`]: for a
+/// `connect` failure, whether the connection was refused, the name did not
+/// resolve, or the target was unreachable. The innermost recognized cause in
+/// `err`'s chain wins, falling back to the kind.
+pub fn cause(err: &anyhow::Error) -> &'static str {
+    err.chain()
+        .filter_map(cause_of)
+        .last()
+        .unwrap_or("upstream_protocol")
+}
+
+fn cause_of(err: &(dyn Error + 'static)) -> Option<&'static str> {
+    if let Some(err) = err.downcast_ref::<ProxyError>() {
+        return Some(err.kind());
+    }
+    if let Some(err) = err.downcast_ref::<io::Error>() {
+        // An `io::Error`'s source skips the error it wraps, so look inside.
+        if let Some(tls) = err.get_ref().and_then(|inner| inner.downcast_ref()) {
+            return Some(tls_cause(tls));
+        }
+        return match err.kind() {
+            io::ErrorKind::ConnectionRefused => Some("connection_refused"),
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => Some("connection_reset"),
+            io::ErrorKind::UnexpectedEof => Some("connection_closed"),
+            io::ErrorKind::TimedOut => Some("timeout"),
+            io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => {
+                Some("unreachable")
+            }
+            io::ErrorKind::AddrNotAvailable => Some("address_unavailable"),
+            io::ErrorKind::NotFound => Some("not_found"),
+            _ => None,
+        };
+    }
+    if let Some(tls) = err.downcast_ref::<rustls::Error>() {
+        return Some(tls_cause(tls));
+    }
+    if let Some(err) = err.downcast_ref::<hyper::Error>() {
+        return if err.is_timeout() {
+            Some("timeout")
+        } else if err.is_parse() || err.is_parse_status() {
+            Some("invalid_response")
+        } else if err.is_incomplete_message() {
+            Some("incomplete_response")
+        } else if err.is_closed() || err.is_canceled() {
+            Some("connection_closed")
+        } else {
+            None
+        };
+    }
+    if let Some(err) = err.downcast_ref::<h2::Error>() {
+        return Some(if err.is_go_away() {
+            "http2_goaway"
+        } else if err.is_reset() {
+            "http2_reset"
+        } else {
+            "http2"
+        });
+    }
+    if err.is::<tower::timeout::error::Elapsed>() {
+        return Some("timeout");
+    }
+    // The client's connector reports resolver failures with this message.
+    if err.to_string() == "dns error" {
+        return Some("dns");
+    }
+    None
+}
+
+fn tls_cause(err: &rustls::Error) -> &'static str {
+    match err {
+        rustls::Error::InvalidCertificate(_) | rustls::Error::NoCertificatesPresented => {
+            "tls_certificate"
+        }
+        rustls::Error::AlertReceived(_) => "tls_alert",
+        _ => "tls",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn classifies_wrapped_proxy_errors() {
+        let err = Err::<(), _>(ProxyError::Timeout {
+            body: Some("slow down".into()),
+        })
+        .context("route `app`")
+        .unwrap_err();
+        let classified = ProxyError::classify(err);
+        assert_eq!(classified.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(classified.kind(), "timeout");
+        assert!(matches!(
+            classified,
+            ProxyError::Timeout { body: Some(ref body) } if body == "slow down"
+        ));
+    }
+
+    #[test]
+    fn causes_come_from_the_innermost_recognized_error() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let err = anyhow::Error::from(ProxyError::Connect(refused.into())).context("route `app`");
+        assert_eq!(cause(&err), "connection_refused");
+        assert_eq!(
+            ProxyError::classify(err).to_string(),
+            "failed to connect to upstream: connection refused"
+        );
+
+        let handshake = io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::Error::AlertReceived(rustls::AlertDescription::HandshakeFailure),
+        );
+        let err = anyhow::Error::from(ProxyError::Tls(handshake.into()));
+        assert_eq!(cause(&err), "tls_alert");
+
+        let err = anyhow::Error::from(ProxyError::UpstreamProtocol("garbled".into()));
+        assert_eq!(cause(&err), "upstream_protocol");
+        let err = anyhow::Error::from(ProxyError::Timeout { body: None });
+        assert_eq!(cause(&err), "timeout");
+    }
+
+    #[test]
+    fn unclassified_errors_are_bad_gateway() {
+        let classified = ProxyError::classify(anyhow::anyhow!("boom"));
+        assert_eq!(classified.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(classified.kind(), "upstream_protocol");
+    }
+}
@@ -0,0 +1,903 @@
+//! Data-plane events, such as routes changing on reload or an upstream
+//! asking for backoff, delivered to the `[[events]]` sinks.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::BufMut;
+use http::{header, HeaderName, HeaderValue, Method, Request};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{broadcast, watch},
+};
+
+use crate::{
+    client::{HttpClient, UpstreamClients},
+    config::{EventKind, EventSink, Route, UpstreamTls},
+    plugin::full_body,
+    stats::RuntimeStats,
+};
+
+/// Events buffered per sink; a sink further behind loses the oldest.
+const CAPACITY: usize = 1024;
+const NATS_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Bound on connecting to a Kafka broker and on each request to one.
+const KAFKA_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest Kafka response accepted.
+const KAFKA_MAX_RESPONSE: usize = 16 << 20;
+const KAFKA_PRODUCE: i16 = 0;
+const KAFKA_METADATA: i16 = 3;
+
+/// One event, as sinks receive it (JSON).
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub kind: EventKind,
+    /// Unix seconds.
+    pub time: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Event {
+    pub(crate) fn new(kind: EventKind) -> Self {
+        Self {
+            kind,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            route: None,
+            upstream: None,
+            detail: None,
+        }
+    }
+
+    pub(crate) fn route(mut self, route: impl Into<String>) -> Self {
+        self.route = Some(route.into());
+        self
+    }
+
+    pub(crate) fn upstream(mut self, upstream: impl Into<String>) -> Self {
+        self.upstream = Some(upstream.into());
+        self
+    }
+
+    pub(crate) fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// Publishes events to the running sinks; filters find it in the request
+/// extensions.
+#[derive(Clone)]
+pub(crate) struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+}
+
+impl Events {
+    /// Hands `event` to every sink; dropped when none is running.
+    pub(crate) fn publish(&self, event: Event) {
+        tracing::debug!(kind = ?event.kind, route = ?event.route, upstream = ?event.upstream, "event");
+        self.sender.send(event).ok();
+    }
+
+    /// Delivers events published from now on to `sink` until `shutdown`
+    /// fires.
+    pub(crate) fn deliver(
+        &self,
+        sink: EventSink,
+        shutdown: watch::Receiver<bool>,
+    ) -> impl std::future::Future<Output = ()> {
+        deliver(sink, self.sender.subscribe(), shutdown)
+    }
+}
+
+async fn deliver(
+    sink: EventSink,
+    mut events: broadcast::Receiver<Event>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut sender = match Sender::new(&sink) {
+        Ok(sender) => sender,
+        Err(err) => {
+            tracing::error!(error = %err, "event sink disabled");
+            return;
+        }
+    };
+    let name = sender.name();
+    loop {
+        let event = tokio::select! {
+            biased;
+            _ = shutdown.changed() => return,
+            event = events.recv() => event,
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                tracing::warn!(
+                    sink = name,
+                    missed,
+                    "event sink fell behind; events dropped"
+                );
+                metrics::counter!("jester_events_total", "sink" => name.clone(), "outcome" => "dropped")
+                    .increment(missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        if !sink.accepts(event.kind) {
+            continue;
+        }
+        let outcome = match sender.send(&event).await {
+            Ok(()) => "delivered",
+            Err(err) => {
+                tracing::warn!(sink = name, kind = ?event.kind, error = %format!("{err:#}"), "event delivery failed");
+                "failed"
+            }
+        };
+        metrics::counter!("jester_events_total", "sink" => name.clone(), "outcome" => outcome)
+            .increment(1);
+    }
+}
+
+/// `route_added`, `route_removed`, and `route_changed` events turning
+/// `before` into `after`, compared by route name.
+pub(crate) fn route_changes(before: &[Route], after: &[Route]) -> Vec<Event> {
+    let json = |route: &Route| serde_json::to_value(route).ok();
+    let mut events = Vec::new();
+    for route in after {
+        match before.iter().find(|old| old.name == route.name) {
+            None => events.push(Event::new(EventKind::RouteAdded).route(&route.name)),
+            Some(old) if json(old) != json(route) => {
+                events.push(Event::new(EventKind::RouteChanged).route(&route.name))
+            }
+            Some(_) => {}
+        }
+    }
+    for route in before {
+        if !after.iter().any(|kept| kept.name == route.name) {
+            events.push(Event::new(EventKind::RouteRemoved).route(&route.name));
+        }
+    }
+    events
+}
+
+enum Sender {
+    Webhook {
+        http: Box<HttpClient>,
+        url: String,
+        headers: Vec<(HeaderName, HeaderValue)>,
+        timeout: Duration,
+    },
+    Nats {
+        address: String,
+        subject: String,
+        connection: Option<NatsConnection>,
+    },
+    Kafka {
+        brokers: Vec<String>,
+        topic: String,
+        producer: Option<KafkaProducer>,
+    },
+}
+
+impl Sender {
+    fn new(sink: &EventSink) -> Result<Self> {
+        Ok(match sink {
+            EventSink::Webhook {
+                url,
+                headers,
+                timeout_secs,
+                ..
+            } => Sender::Webhook {
+                http: Box::new(
+                    UpstreamClients::new(RuntimeStats::default()).get(&UpstreamTls::default())?,
+                ),
+                url: url.clone(),
+                headers: headers
+                    .iter()
+                    .map(|(name, value)| {
+                        Ok((
+                            HeaderName::from_bytes(name.as_bytes())?,
+                            HeaderValue::from_str(value)?,
+                        ))
+                    })
+                    .collect::<Result<_>>()?,
+                timeout: Duration::from_secs(*timeout_secs),
+            },
+            EventSink::Nats {
+                address, subject, ..
+            } => Sender::Nats {
+                address: address.clone(),
+                subject: subject.clone(),
+                connection: None,
+            },
+            EventSink::Kafka { brokers, topic, .. } => Sender::Kafka {
+                brokers: brokers.clone(),
+                topic: topic.clone(),
+                producer: None,
+            },
+        })
+    }
+
+    /// Label of the sink in logs and metrics.
+    fn name(&self) -> String {
+        match self {
+            Sender::Webhook { url, .. } => url.clone(),
+            Sender::Nats {
+                address, subject, ..
+            } => format!("nats://{address}/{subject}"),
+            Sender::Kafka { brokers, topic, .. } => {
+                format!("kafka://{}/{topic}", brokers.join(","))
+            }
+        }
+    }
+
+    async fn send(&mut self, event: &Event) -> Result<()> {
+        let payload = serde_json::to_vec(event)?;
+        match self {
+            Sender::Webhook {
+                http,
+                url,
+                headers,
+                timeout,
+            } => {
+                let mut request = Request::builder()
+                    .method(Method::POST)
+                    .uri(url.as_str())
+                    .header(header::CONTENT_TYPE, "application/json");
+                for (name, value) in headers.iter() {
+                    request = request.header(name, value);
+                }
+                let request = request.body(full_body(payload))?;
+                let response = tokio::time::timeout(*timeout, http.request(request))
+                    .await
+                    .context("webhook timed out")?
+                    .context("webhook request failed")?;
+                if !response.status().is_success() {
+                    bail!("webhook answered {}", response.status());
+                }
+                Ok(())
+            }
+            Sender::Nats {
+                address,
+                subject,
+                connection,
+            } => {
+                // A connection the server closed shows up on the first
+                // publish; reconnect once before giving up on the event.
+                for attempt in 0..2 {
+                    let nats = match connection {
+                        Some(nats) => nats,
+                        None => connection.insert(NatsConnection::connect(address).await?),
+                    };
+                    match nats.publish(subject, &payload).await {
+                        Ok(()) => return Ok(()),
+                        Err(err) if attempt == 0 => {
+                            tracing::debug!(%address, error = %err, "NATS connection lost; reconnecting");
+                            *connection = None;
+                        }
+                        Err(err) => {
+                            *connection = None;
+                            return Err(err);
+                        }
+                    }
+                }
+                unreachable!("the second attempt returns")
+            }
+            Sender::Kafka {
+                brokers,
+                topic,
+                producer,
+            } => {
+                // Partition leaders move; on any failure, look them up again
+                // once before giving up on the event.
+                let timestamp = (event.time * 1000) as i64;
+                for attempt in 0..2 {
+                    let kafka = match producer {
+                        Some(kafka) => kafka,
+                        None => producer.insert(KafkaProducer::connect(brokers, topic).await?),
+                    };
+                    match kafka.produce(topic, &payload, timestamp).await {
+                        Ok(()) => return Ok(()),
+                        Err(err) if attempt == 0 => {
+                            tracing::debug!(%topic, error = %format!("{err:#}"), "Kafka produce failed; refreshing metadata");
+                            *producer = None;
+                        }
+                        Err(err) => {
+                            *producer = None;
+                            return Err(err);
+                        }
+                    }
+                }
+                unreachable!("the second attempt returns")
+            }
+        }
+    }
+}
+
+/// A publish-only client of the NATS text protocol.
+struct NatsConnection {
+    stream: TcpStream,
+    /// Server output not yet split into lines.
+    pending: Vec<u8>,
+}
+
+impl NatsConnection {
+    async fn connect(address: &str) -> Result<Self> {
+        let connect = async {
+            let mut nats = Self {
+                stream: TcpStream::connect(address).await?,
+                pending: Vec::new(),
+            };
+            let info = loop {
+                if let Some(line) = nats.next_line() {
+                    break line;
+                }
+                let mut buf = [0; 4096];
+                match nats.stream.read(&mut buf).await? {
+                    0 => bail!("connection closed before the NATS INFO line"),
+                    n => nats.pending.extend_from_slice(&buf[..n]),
+                }
+            };
+            if !info.starts_with("INFO ") {
+                bail!("not a NATS server");
+            }
+            nats.stream
+                .write_all(
+                    b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"jester\"}\r\n",
+                )
+                .await?;
+            Ok(nats)
+        };
+        tokio::time::timeout(NATS_CONNECT_TIMEOUT, connect)
+            .await
+            .with_context(|| format!("timed out connecting to NATS at {address}"))?
+            .with_context(|| format!("cannot connect to NATS at {address}"))
+    }
+
+    async fn publish(&mut self, subject: &str, payload: &[u8]) -> Result<()> {
+        self.answer_pings().await?;
+        let mut message = format!("PUB {subject} {}\r\n", payload.len()).into_bytes();
+        message.extend_from_slice(payload);
+        message.extend_from_slice(b"\r\n");
+        self.stream.write_all(&message).await?;
+        Ok(())
+    }
+
+    /// Answers the `PING`s the server sent since the last publish, so it does
+    /// not drop an idle connection, and surfaces `-ERR`s.
+    async fn answer_pings(&mut self) -> Result<()> {
+        let mut buf = [0; 4096];
+        loop {
+            match self.stream.try_read(&mut buf) {
+                Ok(0) => bail!("NATS server closed the connection"),
+                Ok(n) => self.pending.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        while let Some(line) = self.next_line() {
+            if line.starts_with("PING") {
+                self.stream.write_all(b"PONG\r\n").await?;
+            } else if line.starts_with("-ERR") {
+                bail!("NATS error: {line}");
+            }
+        }
+        Ok(())
+    }
+
+    fn next_line(&mut self) -> Option<String> {
+        let end = self.pending.windows(2).position(|pair| pair == b"\r\n")?;
+        let line = String::from_utf8_lossy(&self.pending[..end]).into_owned();
+        self.pending.drain(..end + 2);
+        Some(line)
+    }
+}
+
+/// A producer for one Kafka topic: it spreads records round-robin over the
+/// partitions and waits for their leader to append each (`acks=1`). Plain
+/// TCP only; no TLS, SASL, batching, or compression.
+struct KafkaProducer {
+    /// Partition index and the `host:port` of its leader.
+    partitions: Vec<(i32, String)>,
+    connections: HashMap<String, KafkaConnection>,
+    next: usize,
+}
+
+impl KafkaProducer {
+    /// Looks up the partition leaders of `topic` on the first broker that
+    /// answers.
+    async fn connect(brokers: &[String], topic: &str) -> Result<Self> {
+        let mut failure = None;
+        for broker in brokers {
+            let partitions = async {
+                KafkaConnection::connect(broker)
+                    .await?
+                    .partitions(topic)
+                    .await
+            };
+            match partitions.await {
+                Ok(partitions) => {
+                    return Ok(Self {
+                        partitions,
+                        connections: HashMap::new(),
+                        next: 0,
+                    })
+                }
+                Err(err) => {
+                    tracing::debug!(%broker, error = %format!("{err:#}"), "Kafka broker unavailable");
+                    failure = Some(err);
+                }
+            }
+        }
+        Err(failure.unwrap_or_else(|| anyhow::anyhow!("no Kafka brokers configured")))
+    }
+
+    async fn produce(&mut self, topic: &str, value: &[u8], timestamp: i64) -> Result<()> {
+        let (partition, leader) = self.partitions[self.next % self.partitions.len()].clone();
+        self.next = self.next.wrapping_add(1);
+        let connection = match self.connections.entry(leader) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let connection = KafkaConnection::connect(entry.key()).await?;
+                entry.insert(connection)
+            }
+        };
+
+        let records = record_batch(value, timestamp);
+        let mut body = Vec::new();
+        body.put_i16(-1); // no transactional id
+        body.put_i16(1); // acks: the leader's
+        body.put_i32(KAFKA_TIMEOUT.as_millis() as i32);
+        body.put_i32(1);
+        put_string(&mut body, topic);
+        body.put_i32(1);
+        body.put_i32(partition);
+        body.put_i32(records.len() as i32);
+        body.put_slice(&records);
+        let response = connection.request(KAFKA_PRODUCE, 3, &body).await?;
+
+        let mut response = Decoder(&response);
+        if response.array()? != 1 {
+            bail!("Kafka produce response lists no topic");
+        }
+        response.string()?;
+        if response.array()? != 1 {
+            bail!("Kafka produce response lists no partition");
+        }
+        response.i32()?;
+        match response.i16()? {
+            0 => Ok(()),
+            code => {
+                bail!("Kafka rejected the record for partition {partition} (error code {code})")
+            }
+        }
+    }
+}
+
+/// A connection to one Kafka broker.
+struct KafkaConnection {
+    stream: TcpStream,
+    correlation_id: i32,
+}
+
+impl KafkaConnection {
+    async fn connect(address: &str) -> Result<Self> {
+        let stream = tokio::time::timeout(KAFKA_TIMEOUT, TcpStream::connect(address))
+            .await
+            .with_context(|| format!("timed out connecting to Kafka at {address}"))?
+            .with_context(|| format!("cannot connect to Kafka at {address}"))?;
+        Ok(Self {
+            stream,
+            correlation_id: 0,
+        })
+    }
+
+    /// The partitions of `topic` that have a leader, with the leader's
+    /// address, from a v4 `Metadata` request.
+    async fn partitions(&mut self, topic: &str) -> Result<Vec<(i32, String)>> {
+        let mut body = Vec::new();
+        body.put_i32(1);
+        put_string(&mut body, topic);
+        body.put_u8(0); // do not create the topic
+        let response = self.request(KAFKA_METADATA, 4, &body).await?;
+
+        let mut response = Decoder(&response);
+        response.i32()?; // throttle time
+        let mut brokers = HashMap::new();
+        for _ in 0..response.array()? {
+            let node = response.i32()?;
+            let host = response.string()?.unwrap_or_default();
+            let port = response.i32()?;
+            response.string()?; // rack
+            brokers.insert(node, format!("{host}:{port}"));
+        }
+        response.string()?; // cluster id
+        response.i32()?; // controller
+        if response.array()? != 1 {
+            bail!("Kafka metadata lists no topic");
+        }
+        match response.i16()? {
+            0 => {}
+            3 => bail!("Kafka topic `{topic}` does not exist"),
+            code => bail!("Kafka metadata for `{topic}` failed (error code {code})"),
+        }
+        response.string()?;
+        response.take(1)?; // internal
+        let mut partitions = Vec::new();
+        for _ in 0..response.array()? {
+            let error = response.i16()?;
+            let index = response.i32()?;
+            let leader = response.i32()?;
+            for _ in 0..2 {
+                // Replicas, then in-sync replicas.
+                let nodes = response.array()?;
+                response.take(nodes * 4)?;
+            }
+            if let (0, Some(address)) = (error, brokers.get(&leader)) {
+                partitions.push((index, address.clone()));
+            }
+        }
+        if partitions.is_empty() {
+            bail!("no partition of Kafka topic `{topic}` has a leader");
+        }
+        partitions.sort();
+        Ok(partitions)
+    }
+
+    /// Sends one request and returns the response body after its header.
+    async fn request(&mut self, api_key: i16, version: i16, body: &[u8]) -> Result<Vec<u8>> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let mut frame = Vec::with_capacity(body.len() + 20);
+        frame.put_i32(0); // size, set below
+        frame.put_i16(api_key);
+        frame.put_i16(version);
+        frame.put_i32(self.correlation_id);
+        put_string(&mut frame, "jester");
+        frame.put_slice(body);
+        let size = (frame.len() - 4) as i32;
+        frame[..4].copy_from_slice(&size.to_be_bytes());
+
+        let exchange = async {
+            self.stream.write_all(&frame).await?;
+            let size = self.stream.read_i32().await? as usize;
+            if !(4..=KAFKA_MAX_RESPONSE).contains(&size) {
+                bail!("Kafka response of {size} bytes");
+            }
+            let mut response = vec![0; size];
+            self.stream.read_exact(&mut response).await?;
+            Ok(response)
+        };
+        let mut response = tokio::time::timeout(KAFKA_TIMEOUT, exchange)
+            .await
+            .context("Kafka request timed out")??;
+        if response[..4] != self.correlation_id.to_be_bytes() {
+            bail!("Kafka response out of order");
+        }
+        response.drain(..4);
+        Ok(response)
+    }
+}
+
+/// A v2 record batch holding one record, with `value` and no key.
+fn record_batch(value: &[u8], timestamp: i64) -> Vec<u8> {
+    let mut record = Vec::new();
+    record.put_i8(0); // attributes
+    put_varint(&mut record, 0); // timestamp delta
+    put_varint(&mut record, 0); // offset delta
+    put_varint(&mut record, -1); // no key
+    put_varint(&mut record, value.len() as i64);
+    record.put_slice(value);
+    put_varint(&mut record, 0); // headers
+
+    // The part the CRC covers, from the attributes on.
+    let mut checked = Vec::new();
+    checked.put_i16(0); // attributes: no compression
+    checked.put_i32(0); // last offset delta
+    checked.put_i64(timestamp);
+    checked.put_i64(timestamp);
+    checked.put_i64(-1); // producer id
+    checked.put_i16(-1); // producer epoch
+    checked.put_i32(-1); // base sequence
+    checked.put_i32(1);
+    put_varint(&mut checked, record.len() as i64);
+    checked.put_slice(&record);
+
+    let mut batch = Vec::with_capacity(checked.len() + 21);
+    batch.put_i64(0); // base offset
+    batch.put_i32((checked.len() + 9) as i32);
+    batch.put_i32(-1); // partition leader epoch
+    batch.put_i8(2); // magic
+    batch.put_u32(crc32c(&checked));
+    batch.put_slice(&checked);
+    batch
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &str) {
+    buf.put_i16(value.len() as i16);
+    buf.put_slice(value.as_bytes());
+}
+
+/// A zigzag varint, as record fields are encoded.
+fn put_varint(buf: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+/// CRC-32C (Castagnoli), which record batches are checked with.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Reads the big-endian fields of a Kafka message.
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            bail!("truncated Kafka response");
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn i16(&mut self) -> Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    /// A nullable string.
+    fn string(&mut self) -> Result<Option<String>> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        Ok(Some(
+            String::from_utf8_lossy(self.take(len as usize)?).into_owned(),
+        ))
+    }
+
+    /// The length of an array; null arrays are empty.
+    fn array(&mut self) -> Result<usize> {
+        Ok(self.i32()?.max(0) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    use super::*;
+    use crate::config::Upstream;
+
+    #[test]
+    fn reloads_report_added_removed_and_changed_routes() {
+        let before = [
+            Route::builder("api", Upstream::single("http://10.0.0.1")).build(),
+            Route::builder("web", Upstream::single("http://10.0.0.2")).build(),
+            Route::builder("old", Upstream::single("http://10.0.0.3")).build(),
+        ];
+        let after = [
+            Route::builder("api", Upstream::single("http://10.0.0.1")).build(),
+            Route::builder("web", Upstream::single("http://10.0.0.9")).build(),
+            Route::builder("new", Upstream::single("http://10.0.0.4")).build(),
+        ];
+        let events = route_changes(&before, &after)
+            .into_iter()
+            .map(|event| (event.kind, event.route.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                (EventKind::RouteChanged, "web".into()),
+                (EventKind::RouteAdded, "new".into()),
+                (EventKind::RouteRemoved, "old".into()),
+            ]
+        );
+        assert!(route_changes(&before, &before).is_empty());
+    }
+
+    #[tokio::test]
+    async fn nats_sinks_publish_json_events() {
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let received = tokio::spawn(async move {
+            let (stream, _) = server.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            stream
+                .get_mut()
+                .write_all(b"INFO {\"server_id\":\"test\"}\r\n")
+                .await
+                .unwrap();
+            let mut lines = Vec::new();
+            for _ in 0..3 {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                lines.push(line.trim_end().to_string());
+            }
+            lines
+        });
+
+        let mut sender = Sender::new(&EventSink::Nats {
+            address,
+            subject: "jester.events".into(),
+            kinds: Vec::new(),
+        })
+        .unwrap();
+        let event = Event::new(EventKind::UpstreamBackoff)
+            .upstream("http://10.0.0.1:8080")
+            .detail("retry after 30s");
+        sender.send(&event).await.unwrap();
+
+        let lines = received.await.unwrap();
+        assert!(lines[0].starts_with("CONNECT {"));
+        let payload = serde_json::to_string(&event).unwrap();
+        assert_eq!(lines[1], format!("PUB jester.events {}", payload.len()));
+        let json: serde_json::Value = serde_json::from_str(&lines[2]).unwrap();
+        assert_eq!(json["kind"], "upstream_backoff");
+        assert_eq!(json["upstream"], "http://10.0.0.1:8080");
+        assert!(json.get("route").is_none());
+    }
+
+    /// Reads one request off a fake broker's connection: its API key,
+    /// correlation id, and body.
+    async fn kafka_request(stream: &mut TcpStream) -> Option<(i16, i32, Vec<u8>)> {
+        let size = stream.read_i32().await.ok()?;
+        let mut request = vec![0; size as usize];
+        stream.read_exact(&mut request).await.ok()?;
+        let mut header = Decoder(&request);
+        let api_key = header.i16().unwrap();
+        header.i16().unwrap();
+        let correlation_id = header.i32().unwrap();
+        header.string().unwrap();
+        Some((api_key, correlation_id, header.0.to_vec()))
+    }
+
+    async fn kafka_respond(stream: &mut TcpStream, correlation_id: i32, body: &[u8]) {
+        let mut frame = Vec::new();
+        frame.put_i32(body.len() as i32 + 4);
+        frame.put_i32(correlation_id);
+        frame.put_slice(body);
+        stream.write_all(&frame).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn kafka_sinks_produce_json_records_to_partition_leaders() {
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap();
+        let (produced, mut records) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            // The bootstrap connection, then the one to the partition leader.
+            loop {
+                let (mut stream, _) = server.accept().await.unwrap();
+                let produced = produced.clone();
+                tokio::spawn(async move {
+                    while let Some((api_key, correlation_id, body)) =
+                        kafka_request(&mut stream).await
+                    {
+                        let mut response = Vec::new();
+                        if api_key == KAFKA_METADATA {
+                            response.put_i32(0);
+                            response.put_i32(1);
+                            response.put_i32(7);
+                            put_string(&mut response, &address.ip().to_string());
+                            response.put_i32(address.port() as i32);
+                            response.put_i16(-1);
+                            response.put_i16(-1);
+                            response.put_i32(7);
+                            response.put_i32(1);
+                            response.put_i16(0);
+                            put_string(&mut response, "jester-events");
+                            response.put_u8(0);
+                            response.put_i32(2);
+                            for (partition, leader) in [(0, 7), (1, -1)] {
+                                response.put_i16(0);
+                                response.put_i32(partition);
+                                response.put_i32(leader);
+                                response.put_i32(1);
+                                response.put_i32(7);
+                                response.put_i32(0);
+                            }
+                        } else {
+                            assert_eq!(api_key, KAFKA_PRODUCE);
+                            produced.send(body).unwrap();
+                            response.put_i32(1);
+                            put_string(&mut response, "jester-events");
+                            response.put_i32(1);
+                            response.put_i32(0);
+                            response.put_i16(0);
+                            response.put_i64(42);
+                            response.put_i64(-1);
+                            response.put_i32(0);
+                        }
+                        kafka_respond(&mut stream, correlation_id, &response).await;
+                    }
+                });
+            }
+        });
+
+        let mut sender = Sender::new(&EventSink::Kafka {
+            brokers: vec!["127.0.0.1:1".into(), address.to_string()],
+            topic: "jester-events".into(),
+            kinds: Vec::new(),
+        })
+        .unwrap();
+        let event = Event::new(EventKind::RouteAdded).route("api");
+        sender.send(&event).await.unwrap();
+        sender.send(&event).await.unwrap();
+
+        for _ in 0..2 {
+            let body = records.recv().await.unwrap();
+            let mut body = Decoder(&body);
+            assert_eq!(body.string().unwrap(), None);
+            assert_eq!(body.i16().unwrap(), 1);
+            body.i32().unwrap();
+            assert_eq!(body.array().unwrap(), 1);
+            assert_eq!(body.string().unwrap().as_deref(), Some("jester-events"));
+            assert_eq!(body.array().unwrap(), 1);
+            // Partition 1 has no leader, so every record goes to 0.
+            assert_eq!(body.i32().unwrap(), 0);
+            let len = body.i32().unwrap() as usize;
+            let batch = body.take(len).unwrap();
+            assert_eq!(batch[16], 2);
+            assert_eq!(
+                crc32c(&batch[21..]),
+                u32::from_be_bytes(batch[17..21].try_into().unwrap())
+            );
+            let payload = serde_json::to_vec(&event).unwrap();
+            // The record ends in the value and an empty header count.
+            let value = &batch[batch.len() - 1 - payload.len()..batch.len() - 1];
+            let json: serde_json::Value = serde_json::from_slice(value).unwrap();
+            assert_eq!(json["kind"], "route_added");
+            assert_eq!(json["route"], "api");
+        }
+    }
+
+    #[test]
+    fn record_batches_use_zigzag_varints_and_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        let mut buf = Vec::new();
+        for value in [0, -1, 1, 300] {
+            put_varint(&mut buf, value);
+        }
+        assert_eq!(buf, [0x00, 0x01, 0x02, 0xd8, 0x04]);
+    }
+}
@@ -0,0 +1,442 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    builtins,
+    config::{Filter, Phase, RetryPolicy, Route},
+    flags,
+    plugin::{Constraint, DynLayer, JesterPlugin, JesterService, RetryAdvisor},
+    profile,
+};
+
+/// Resolves filter declarations into tower layers and assembles them into chains.
+///
+/// Builtin filters are looked up by `name`; `inproc` filters are looked up by
+/// `symbol` among plugins registered through [`FilterRegistry::register`].
+#[derive(Clone)]
+pub struct FilterRegistry {
+    builtins: HashMap<&'static str, Arc<dyn JesterPlugin>>,
+    inproc: HashMap<&'static str, Arc<dyn JesterPlugin>>,
+}
+
+impl Default for FilterRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            builtins: HashMap::new(),
+            inproc: HashMap::new(),
+        };
+        for plugin in builtins::all() {
+            registry.builtins.insert(plugin.name(), plugin);
+        }
+        registry
+    }
+}
+
+impl FilterRegistry {
+    /// Registers an in-process plugin addressable from `type = "inproc"` filters.
+    pub fn register(&mut self, plugin: Arc<dyn JesterPlugin>) {
+        self.inproc.insert(plugin.name(), plugin);
+    }
+
+    /// Builds the per-route chain: `filters` default to [`Phase::PreUpstream`] and
+    /// `response_filters` to [`Phase::PostUpstream`].
+    pub fn build_route_chain(&self, route: &Route, inner: JesterService) -> Result<JesterService> {
+        let filters = route
+            .filters
+            .iter()
+            .map(|filter| (filter, Phase::PreUpstream))
+            .chain(
+                route
+                    .response_filters
+                    .iter()
+                    .map(|filter| (filter, Phase::PostUpstream)),
+            );
+        self.assemble(filters, &route.name, inner)
+            .with_context(|| format!("failed to build filter chain for route `{}`", route.name))
+    }
+
+    /// Builds the top-level chain wrapping route dispatch; filters default to
+    /// [`Phase::PreRouting`].
+    pub fn build_global_chain(
+        &self,
+        filters: &[Filter],
+        inner: JesterService,
+    ) -> Result<JesterService> {
+        self.assemble(
+            filters.iter().map(|filter| (filter, Phase::PreRouting)),
+            "global",
+            inner,
+        )
+        .context("failed to build global filter chain")
+    }
+
+    /// Resolves the advisor a retry policy names, among in-process plugins
+    /// and then builtins.
+    pub(crate) fn retry_advisor(
+        &self,
+        policy: &RetryPolicy,
+    ) -> Result<Option<Arc<dyn RetryAdvisor>>> {
+        let Some(name) = &policy.advisor else {
+            return Ok(None);
+        };
+        let plugin = self
+            .inproc
+            .get(name.as_str())
+            .or_else(|| self.builtins.get(name.as_str()))
+            .with_context(|| format!("no plugin registered as `{name}`"))?;
+        let advisor = plugin
+            .retry_advisor(policy.advisor_config.clone())
+            .with_context(|| format!("invalid configuration for retry advisor `{name}`"))?
+            .with_context(|| format!("plugin `{name}` does not advise on retries"))?;
+        Ok(Some(advisor))
+    }
+
+    /// Checks each route's filters, together with the global chain, against
+    /// the [`Constraint`]s of their plugins.
+    pub fn check_ordering(&self, global: &[Filter], routes: &[Route]) -> Result<()> {
+        let (global_request, global_response) =
+            sorted(global.iter().map(|filter| (filter, Phase::PreRouting)));
+        for route in routes {
+            let (request, response) = sorted(
+                route
+                    .filters
+                    .iter()
+                    .map(|filter| (filter, Phase::PreUpstream))
+                    .chain(
+                        route
+                            .response_filters
+                            .iter()
+                            .map(|filter| (filter, Phase::PostUpstream)),
+                    ),
+            );
+            let plugins = global_request
+                .iter()
+                .chain(&request)
+                .chain(&response)
+                .chain(&global_response)
+                .filter_map(|filter| self.plugin(filter))
+                .map(|plugin| plugin.as_ref())
+                .collect::<Vec<_>>();
+            check_constraints(&plugins)
+                .with_context(|| format!("misordered filters for route `{}`", route.name))?;
+        }
+        Ok(())
+    }
+
+    /// Wraps `inner` so that request phases run outermost-first in sorted order and
+    /// response phases observe the response in sorted order on the way back out.
+    /// `chain` is the route's name, or `global`.
+    fn assemble<'a>(
+        &self,
+        filters: impl Iterator<Item = (&'a Filter, Phase)>,
+        chain: &str,
+        inner: JesterService,
+    ) -> Result<JesterService> {
+        let (request, response) = sorted(filters);
+        let mut service = inner;
+        for filter in request.into_iter().rev() {
+            service = self.resolve(filter, chain)?.layer(service);
+        }
+        for filter in response {
+            service = self.resolve(filter, chain)?.layer(service);
+        }
+        Ok(service)
+    }
+
+    /// The plugin a filter declaration refers to, if registered.
+    pub(crate) fn plugin(&self, filter: &Filter) -> Option<&Arc<dyn JesterPlugin>> {
+        match filter {
+            Filter::Builtin { name, .. } => self.builtins.get(name.as_str()),
+            Filter::InProc { symbol, .. } => self.inproc.get(symbol.as_str()),
+            Filter::Wasm { .. } => None,
+        }
+    }
+
+    fn resolve(&self, filter: &Filter, chain: &str) -> Result<DynLayer> {
+        let plugin = match filter {
+            Filter::Builtin { name, .. } => self
+                .plugin(filter)
+                .with_context(|| format!("unknown builtin filter `{name}`"))?,
+            Filter::InProc { symbol, .. } => self
+                .plugin(filter)
+                .with_context(|| format!("no in-process plugin registered as `{symbol}`"))?,
+            Filter::Wasm { .. } => return self.resolve_wasm(filter, chain),
+        };
+        let layer = plugin
+            .layer(filter.config().clone())
+            .with_context(|| format!("invalid configuration for filter `{}`", filter.name()))?;
+        Ok(self.wrap(filter, layer))
+    }
+
+    #[cfg(feature = "wasm")]
+    fn resolve_wasm(&self, filter: &Filter, chain: &str) -> Result<DynLayer> {
+        let layer = crate::wasm::layer(filter, chain)
+            .with_context(|| format!("invalid wasm filter `{}`", filter.name()))?;
+        Ok(self.wrap(filter, layer))
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    fn resolve_wasm(&self, filter: &Filter, _chain: &str) -> Result<DynLayer> {
+        bail!(
+            "wasm filter `{}` needs a jester built with the `wasm` feature",
+            filter.name()
+        )
+    }
+
+    /// Profiles `layer` and gates it on the filter's flag, if any.
+    fn wrap(&self, filter: &Filter, layer: DynLayer) -> DynLayer {
+        let layer = profile::layer(format!("filter:{}", filter.name()), layer);
+        match filter.flag() {
+            Some(flag) => flags::gate(flag, layer),
+            None => layer,
+        }
+    }
+}
+
+/// Splits filters into request and response phases, each in the order they
+/// see the request or response.
+fn sorted<'a>(
+    filters: impl Iterator<Item = (&'a Filter, Phase)>,
+) -> (Vec<&'a Filter>, Vec<&'a Filter>) {
+    let mut entries = filters
+        .enumerate()
+        .map(|(index, (filter, default_phase))| {
+            let phase = filter.phase().unwrap_or(default_phase);
+            (phase, filter.order(), index, filter)
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|(phase, order, index, _)| (*phase, *order, *index));
+    let (request, response): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .partition(|(phase, ..)| phase.is_request_phase());
+    let filters = |entries: Vec<(Phase, i32, usize, &'a Filter)>| {
+        entries.into_iter().map(|(.., filter)| filter).collect()
+    };
+    (filters(request), filters(response))
+}
+
+/// Checks `plugins`, in the order their filters run, against their constraints.
+fn check_constraints(plugins: &[&dyn JesterPlugin]) -> Result<()> {
+    for (position, plugin) in plugins.iter().enumerate() {
+        let (earlier, later) = plugins.split_at(position);
+        let runs = |filters: &[&dyn JesterPlugin], name: &str| {
+            filters.iter().any(|other| other.name() == name)
+        };
+        for constraint in plugin.constraints() {
+            match *constraint {
+                Constraint::Requires(name) if runs(earlier, name) => {}
+                Constraint::Requires(name) if runs(later, name) => bail!(
+                    "filter `{}` requires `{name}` to run before it, but it runs after",
+                    plugin.name()
+                ),
+                Constraint::Requires(name) => bail!(
+                    "filter `{}` requires `{name}` to run before it, but the chain has no `{name}`",
+                    plugin.name()
+                ),
+                Constraint::Precedes(name) if runs(earlier, name) => bail!(
+                    "filter `{}` must run before `{name}`, but runs after it",
+                    plugin.name()
+                ),
+                Constraint::Precedes(_) => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use bytes::Bytes;
+    use http::{Request, Response};
+    use http_body_util::{BodyExt, Empty};
+    use tower::{layer::layer_fn, service_fn, ServiceExt};
+
+    use super::*;
+    use crate::plugin::{HttpRequest, ProxyBody};
+
+    type Trace = Arc<Mutex<Vec<String>>>;
+
+    struct Recorder {
+        name: &'static str,
+        trace: Trace,
+        constraints: &'static [Constraint],
+    }
+
+    impl JesterPlugin for Recorder {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn version(&self) -> semver::Version {
+            semver::Version::new(0, 0, 0)
+        }
+
+        fn layer(&self, _cfg: serde_json::Value) -> Result<DynLayer> {
+            let name = self.name;
+            let trace = self.trace.clone();
+            Ok(Box::new(layer_fn(move |inner: JesterService| {
+                let (req_trace, resp_trace) = (trace.clone(), trace.clone());
+                JesterService::new(
+                    inner
+                        .map_request(move |req: HttpRequest| {
+                            req_trace.lock().unwrap().push(format!("req:{name}"));
+                            req
+                        })
+                        .map_response(move |resp| {
+                            resp_trace.lock().unwrap().push(format!("resp:{name}"));
+                            resp
+                        }),
+                )
+            })))
+        }
+
+        fn capabilities(&self) -> &'static [&'static str] {
+            &[]
+        }
+
+        fn constraints(&self) -> &'static [Constraint] {
+            self.constraints
+        }
+    }
+
+    fn inproc(symbol: &str, phase: Option<Phase>, order: Option<i32>) -> Filter {
+        Filter::InProc {
+            name: symbol.into(),
+            symbol: symbol.into(),
+            config: serde_json::Value::Null,
+            phase,
+            order,
+            flag: None,
+        }
+    }
+
+    fn empty_body() -> ProxyBody {
+        Empty::<Bytes>::new()
+            .map_err(|never| match never {})
+            .boxed()
+    }
+
+    #[tokio::test]
+    async fn route_chain_runs_phases_in_order() {
+        let trace = Trace::default();
+        let mut registry = FilterRegistry::default();
+        for name in ["auth", "rewrite", "gzip", "audit"] {
+            registry.register(Arc::new(Recorder {
+                name,
+                trace: trace.clone(),
+                constraints: &[],
+            }));
+        }
+
+        let route = Route {
+            name: "test".into(),
+            filters: vec![
+                inproc("rewrite", None, None),
+                inproc("audit", Some(Phase::Logging), None),
+                inproc("auth", None, Some(-10)),
+            ],
+            response_filters: vec![inproc("gzip", None, None)],
+            ..Default::default()
+        };
+
+        let inner_trace = trace.clone();
+        let inner = JesterService::new(service_fn(move |_req: HttpRequest| {
+            inner_trace.lock().unwrap().push("upstream".into());
+            async { Ok::<_, anyhow::Error>(Response::new(empty_body())) }
+        }));
+        let chain = registry.build_route_chain(&route, inner).unwrap();
+        chain.oneshot(Request::new(empty_body())).await.unwrap();
+
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec![
+                "req:audit",
+                "req:gzip",
+                "req:auth",
+                "req:rewrite",
+                "upstream",
+                "resp:rewrite",
+                "resp:auth",
+                "resp:gzip",
+                "resp:audit",
+            ]
+        );
+    }
+
+    #[test]
+    fn chains_must_honor_plugin_constraints() {
+        let mut registry = FilterRegistry::default();
+        for (name, constraints) in [
+            ("authn", &[][..]),
+            ("authz", &[Constraint::Requires("authn")][..]),
+            ("audit", &[Constraint::Precedes("authn")][..]),
+        ] {
+            registry.register(Arc::new(Recorder {
+                name,
+                trace: Trace::default(),
+                constraints,
+            }));
+        }
+        let check = |global: &[Filter], filters: Vec<Filter>| {
+            let route = Route {
+                name: "api".into(),
+                filters,
+                ..Default::default()
+            };
+            registry.check_ordering(global, &[route])
+        };
+
+        assert!(check(
+            &[],
+            vec![inproc("authn", None, None), inproc("authz", None, None)]
+        )
+        .is_ok());
+        // The global chain runs before the route's.
+        assert!(check(
+            &[inproc("authn", None, None)],
+            vec![inproc("authz", None, None)]
+        )
+        .is_ok());
+        let err = check(&[], vec![inproc("authz", None, None)]).unwrap_err();
+        assert!(format!("{err:#}").contains("route `api`"), "{err:#}");
+        assert!(format!("{err:#}").contains("has no `authn`"), "{err:#}");
+        // Sorting by `order` counts, not the position in the list.
+        let err = check(
+            &[],
+            vec![inproc("authn", None, Some(10)), inproc("authz", None, None)],
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("runs after"), "{err:#}");
+
+        assert!(check(&[], vec![inproc("audit", None, None)]).is_ok());
+        assert!(check(
+            &[],
+            vec![inproc("authn", None, None), inproc("audit", None, None)]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn unknown_builtin_is_rejected() {
+        let route = Route {
+            name: "test".into(),
+            filters: vec![Filter::Builtin {
+                name: "does-not-exist".into(),
+                config: serde_json::Value::Null,
+                phase: None,
+                order: None,
+                flag: None,
+            }],
+            ..Default::default()
+        };
+        let inner = JesterService::new(service_fn(|_req: HttpRequest| async {
+            Ok::<_, anyhow::Error>(Response::new(empty_body()))
+        }));
+        assert!(FilterRegistry::default()
+            .build_route_chain(&route, inner)
+            .is_err());
+    }
+}
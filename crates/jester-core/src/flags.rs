@@ -0,0 +1,401 @@
+//! Feature flags from an external provider, gating filters per request.
+
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock, Weak},
+    task::{ready, Context as TaskContext, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use http::{header, Method, Request, Uri};
+use http_body_util::{BodyExt, Limited};
+use serde::Deserialize;
+use tower::{layer::layer_fn, Service};
+
+use crate::{
+    client::{HttpClient, UpstreamClients},
+    config::{FeatureFlags, FlagProvider, UpstreamTls},
+    context::ClientIp,
+    plugin::{
+        full_body, DynLayer, HttpRequest, HttpResponse, JesterService, ProxyBody, ResponseFuture,
+    },
+    stats::RuntimeStats,
+};
+
+/// Largest flag document accepted from a provider.
+const MAX_FLAGS_BYTES: usize = 1024 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Value of one flag: on/off, or rolled out to a percentage of clients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FlagValue {
+    Enabled(bool),
+    Rollout(f64),
+}
+
+impl FlagValue {
+    fn from_json(value: &serde_json::Value) -> Option<Self> {
+        match value {
+            serde_json::Value::Bool(enabled) => Some(FlagValue::Enabled(*enabled)),
+            serde_json::Value::Number(percent) => {
+                Some(FlagValue::Rollout(percent.as_f64()?.clamp(0.0, 100.0)))
+            }
+            _ => None,
+        }
+    }
+}
+
+enum FlagSource {
+    Json(Provider),
+    File(PathBuf),
+    Ofrep(Provider),
+}
+
+/// A provider URL and the client that fetches it.
+struct Provider {
+    http: HttpClient,
+    uri: Uri,
+}
+
+impl Provider {
+    fn new(uri: Uri) -> Result<Self> {
+        let http = UpstreamClients::new(RuntimeStats::default()).get(&UpstreamTls::default())?;
+        Ok(Self { http, uri })
+    }
+
+    async fn fetch(&self, request: Request<ProxyBody>) -> Result<Vec<u8>> {
+        let fetch = async {
+            let response = self.http.request(request).await?;
+            if !response.status().is_success() {
+                bail!("flag provider returned {}", response.status());
+            }
+            let body = Limited::new(response.into_body(), MAX_FLAGS_BYTES)
+                .collect()
+                .await
+                .map_err(|err| anyhow::anyhow!(err))?
+                .to_bytes();
+            Ok(body.to_vec())
+        };
+        tokio::time::timeout(FETCH_TIMEOUT, fetch)
+            .await
+            .context("flag fetch timed out")?
+            .with_context(|| format!("failed to fetch {}", self.uri))
+    }
+}
+
+impl FlagSource {
+    async fn load(&self) -> Result<HashMap<String, FlagValue>> {
+        let body = match self {
+            FlagSource::File(path) => tokio::fs::read(path)
+                .await
+                .with_context(|| format!("failed to read {}", path.display()))?,
+            FlagSource::Json(provider) => {
+                let request = Request::get(provider.uri.clone()).body(full_body(Bytes::new()))?;
+                provider.fetch(request).await?
+            }
+            FlagSource::Ofrep(provider) => {
+                let request = Request::builder()
+                    .method(Method::POST)
+                    .uri(provider.uri.clone())
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(full_body(Bytes::from_static(br#"{"context":{}}"#)))?;
+                provider.fetch(request).await?
+            }
+        };
+        match self {
+            FlagSource::Ofrep(_) => parse_ofrep(&body),
+            FlagSource::Json(_) | FlagSource::File(_) => parse_json(&body),
+        }
+    }
+}
+
+/// `{ "waf": true, "new-cache": 25 }`; other value types are ignored.
+fn parse_json(body: &[u8]) -> Result<HashMap<String, FlagValue>> {
+    let flags: HashMap<String, serde_json::Value> =
+        serde_json::from_slice(body).context("flags must be a JSON object")?;
+    Ok(flags
+        .iter()
+        .filter_map(|(name, value)| Some((name.clone(), FlagValue::from_json(value)?)))
+        .collect())
+}
+
+/// OFREP bulk evaluation response: `{ "flags": [{ "key": "waf", "value": true }] }`.
+/// Flags that failed to evaluate carry no `value` and are skipped.
+fn parse_ofrep(body: &[u8]) -> Result<HashMap<String, FlagValue>> {
+    #[derive(Deserialize)]
+    struct Evaluation {
+        flags: Vec<Evaluated>,
+    }
+
+    #[derive(Deserialize)]
+    struct Evaluated {
+        key: String,
+        value: Option<serde_json::Value>,
+    }
+
+    let evaluation: Evaluation =
+        serde_json::from_slice(body).context("invalid OFREP bulk evaluation response")?;
+    Ok(evaluation
+        .flags
+        .into_iter()
+        .filter_map(|flag| {
+            let value = FlagValue::from_json(flag.value.as_ref()?)?;
+            Some((flag.key, value))
+        })
+        .collect())
+}
+
+/// The flags of one configuration, refreshed in the background while the
+/// pipeline built from it is alive.
+pub(crate) struct FlagSet {
+    source: FlagSource,
+    refresh: Duration,
+    default_enabled: bool,
+    values: RwLock<Arc<HashMap<String, FlagValue>>>,
+}
+
+impl FlagSet {
+    pub(crate) fn start(cfg: &FeatureFlags) -> Result<Arc<Self>> {
+        cfg.validate()?;
+        let source = match (&cfg.url, &cfg.file, cfg.provider) {
+            (Some(url), _, FlagProvider::Json) => FlagSource::Json(Provider::new(url.parse()?)?),
+            (Some(url), _, FlagProvider::Ofrep) => FlagSource::Ofrep(Provider::new(
+                format!("{}/ofrep/v1/evaluate/flags", url.trim_end_matches('/')).parse()?,
+            )?),
+            (None, Some(file), _) => FlagSource::File(file.into()),
+            (None, None, _) => bail!("flags need exactly one of `url` or `file`"),
+        };
+        let flags = Arc::new(Self {
+            source,
+            refresh: Duration::from_secs(cfg.refresh_secs),
+            default_enabled: cfg.default_enabled,
+            values: RwLock::default(),
+        });
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(refresh_loop(Arc::downgrade(&flags)));
+        }
+        Ok(flags)
+    }
+
+    /// Whether `flag` is on for a client.
+    pub(crate) fn enabled(&self, flag: &str, client: Option<IpAddr>) -> bool {
+        let value = self
+            .values
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(flag)
+            .copied();
+        match value {
+            None => self.default_enabled,
+            Some(FlagValue::Enabled(enabled)) => enabled,
+            Some(FlagValue::Rollout(percent)) => {
+                percent >= 100.0
+                    || client.is_some_and(|client| rollout_bucket(flag, client) < percent)
+            }
+        }
+    }
+
+    fn replace(&self, values: HashMap<String, FlagValue>) {
+        *self
+            .values
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(values);
+    }
+
+    /// Loads the flags and swaps them in; on error the previous values stay.
+    async fn refresh(&self) -> Result<()> {
+        let result = self.source.load().await;
+        let outcome = if result.is_ok() { "success" } else { "error" };
+        metrics::counter!("jester_flag_refreshes_total", "outcome" => outcome).increment(1);
+        let values = result?;
+        let count = values.len();
+        self.replace(values);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        metrics::gauge!("jester_flag_last_success_timestamp_seconds").set(now.as_secs_f64());
+        tracing::debug!(flags = count, "feature flags refreshed");
+        Ok(())
+    }
+}
+
+/// Stable position of a client in `[0, 100)` for one flag, so each client
+/// consistently sees a rollout either on or off.
+fn rollout_bucket(flag: &str, client: IpAddr) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    flag.hash(&mut hasher);
+    client.to_canonical().hash(&mut hasher);
+    (hasher.finish() % 10_000) as f64 / 100.0
+}
+
+async fn refresh_loop(flags: Weak<FlagSet>) {
+    loop {
+        let Some(flags) = flags.upgrade() else {
+            return;
+        };
+        if let Err(err) = flags.refresh().await {
+            tracing::warn!(error = %err, "feature flag refresh failed; keeping previous values");
+        }
+        let refresh = flags.refresh;
+        drop(flags);
+        tokio::time::sleep(refresh).await;
+    }
+}
+
+/// Wraps `layer` so requests bypass it while `flag` is off. Flags are read
+/// from the [`FlagSet`] in the request extensions; without one the filter runs.
+pub(crate) fn gate(flag: &str, layer: DynLayer) -> DynLayer {
+    let flag: Arc<str> = flag.into();
+    Box::new(layer_fn(move |inner: JesterService| {
+        JesterService::new(FlagGate {
+            flag: flag.clone(),
+            filtered: layer.layer(inner.clone()),
+            bypass: inner,
+        })
+    }))
+}
+
+#[derive(Clone)]
+struct FlagGate {
+    flag: Arc<str>,
+    filtered: JesterService,
+    bypass: JesterService,
+}
+
+impl Service<HttpRequest> for FlagGate {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        ready!(self.filtered.poll_ready(cx))?;
+        self.bypass.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HttpRequest) -> Self::Future {
+        let client = ClientIp::of(&req);
+        let enabled = req
+            .extensions()
+            .get::<Arc<FlagSet>>()
+            .is_none_or(|flags| flags.enabled(&self.flag, client));
+        if enabled {
+            Box::pin(self.filtered.call(req))
+        } else {
+            Box::pin(self.bypass.call(req))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use http::HeaderValue;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+    use crate::plugin::full_body;
+
+    fn flag_set(values: &[(&str, FlagValue)]) -> FlagSet {
+        let flags = FlagSet {
+            source: FlagSource::File("unused".into()),
+            refresh: Duration::from_secs(30),
+            default_enabled: true,
+            values: RwLock::default(),
+        };
+        flags.replace(
+            values
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect(),
+        );
+        flags
+    }
+
+    #[test]
+    fn parses_json_and_ofrep_documents() {
+        let json = parse_json(br#"{ "waf": false, "cache": 25, "note": "x" }"#).unwrap();
+        assert_eq!(json["waf"], FlagValue::Enabled(false));
+        assert_eq!(json["cache"], FlagValue::Rollout(25.0));
+        assert!(!json.contains_key("note"));
+
+        let ofrep = parse_ofrep(
+            br#"{ "flags": [
+                { "key": "waf", "value": true, "reason": "STATIC", "variant": "on" },
+                { "key": "broken", "errorCode": "PARSE_ERROR" }
+            ] }"#,
+        )
+        .unwrap();
+        assert_eq!(ofrep["waf"], FlagValue::Enabled(true));
+        assert!(!ofrep.contains_key("broken"));
+    }
+
+    #[test]
+    fn providers_may_be_http_or_https() {
+        let start = |url: &str| {
+            let cfg: FeatureFlags = toml::from_str(&format!("url = \"{url}\"")).unwrap();
+            FlagSet::start(&cfg).map(|_| ())
+        };
+        start("http://flagd:8016/flags.json").unwrap();
+        start("https://flags.example.com/flags.json").unwrap();
+        assert!(start("ftp://flags.example.com/flags.json").is_err());
+    }
+
+    #[test]
+    fn rollouts_bucket_clients_stably() {
+        let flags = flag_set(&[
+            ("off", FlagValue::Enabled(false)),
+            ("half", FlagValue::Rollout(50.0)),
+        ]);
+        let client = |n: u32| Some(IpAddr::from(Ipv4Addr::from(0x0a00_0000 + n)));
+        assert!(!flags.enabled("off", client(1)));
+        assert!(flags.enabled("unknown", client(1)));
+        let enabled = (0..1000)
+            .filter(|n| flags.enabled("half", client(*n)))
+            .count();
+        assert!((400..600).contains(&enabled), "{enabled} of 1000 enabled");
+        assert_eq!(
+            flags.enabled("half", client(7)),
+            flags.enabled("half", client(7))
+        );
+    }
+
+    #[tokio::test]
+    async fn gated_filters_are_bypassed_while_their_flag_is_off() {
+        let marker: DynLayer = Box::new(layer_fn(|inner: JesterService| {
+            JesterService::new(inner.map_response(|mut resp: HttpResponse| {
+                resp.headers_mut()
+                    .insert("x-filtered", HeaderValue::from_static("1"));
+                resp
+            }))
+        }));
+        let inner = JesterService::new(service_fn(|_req: HttpRequest| async {
+            Ok::<_, anyhow::Error>(HttpResponse::new(full_body("")))
+        }));
+        let service = gate("waf", marker).layer(inner);
+        let flags = Arc::new(flag_set(&[("waf", FlagValue::Enabled(false))]));
+        let call = |flags: Option<Arc<FlagSet>>| {
+            let mut req = Request::new(full_body(""));
+            if let Some(flags) = flags {
+                req.extensions_mut().insert(flags);
+            }
+            service.clone().oneshot(req)
+        };
+
+        let resp = call(Some(flags.clone())).await.unwrap();
+        assert!(!resp.headers().contains_key("x-filtered"));
+        flags.replace(HashMap::from([(
+            "waf".to_string(),
+            FlagValue::Enabled(true),
+        )]));
+        let resp = call(Some(flags)).await.unwrap();
+        assert!(resp.headers().contains_key("x-filtered"));
+        let resp = call(None).await.unwrap();
+        assert!(resp.headers().contains_key("x-filtered"));
+    }
+}
@@ -0,0 +1,230 @@
+//! gRPC calls proxied end to end.
+//!
+//! A call's outcome is its `grpc-status`, which normally arrives in the
+//! response trailers rather than with the (always `200`) response head. Calls
+//! are therefore logged and published once their response body ends, with the
+//! status it carried.
+
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Bytes;
+use http::{header, HeaderMap, StatusCode};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+
+use crate::plugin::{BoxError, HttpResponse, ProxyBody};
+
+/// `CANCELLED`: the client went away before the call finished.
+pub(crate) const CANCELLED: u16 = 1;
+
+/// Whether `headers` describe a gRPC message (`application/grpc`, with or
+/// without a `+proto`-style suffix).
+pub(crate) fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            let value = value.to_ascii_lowercase();
+            value
+                .strip_prefix("application/grpc")
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(['+', ';']))
+        })
+}
+
+/// The call's status if the response head settles it: a trailers-only
+/// response carries `grpc-status` in its headers, and a response that is not
+/// gRPC at all (e.g. one jester answered itself) gets the status clients
+/// derive from its HTTP status. `None` when it will come in the trailers.
+pub(crate) fn head_status(response: &HttpResponse) -> Option<u16> {
+    if let Some(code) = status_in(response.headers()) {
+        return Some(code);
+    }
+    if response.status() == StatusCode::OK && is_grpc(response.headers()) {
+        return None;
+    }
+    Some(from_http(response.status()))
+}
+
+/// The gRPC status a client reports for a non-gRPC response with HTTP
+/// `status`, per gRPC's HTTP-to-gRPC status mapping.
+fn from_http(status: StatusCode) -> u16 {
+    match status.as_u16() {
+        400 => 13,             // INTERNAL
+        401 => 16,             // UNAUTHENTICATED
+        403 => 7,              // PERMISSION_DENIED
+        404 => 12,             // UNIMPLEMENTED
+        429 | 502..=504 => 14, // UNAVAILABLE
+        _ => 2,                // UNKNOWN
+    }
+}
+
+fn status_in(headers: &HeaderMap) -> Option<u16> {
+    headers
+        .get("grpc-status")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+        .filter(|code| *code <= 16)
+}
+
+/// Metric label for a call's status: the numeric code, or `missing` when the
+/// response ended without one.
+pub(crate) fn status_label(code: Option<u16>) -> String {
+    code.map_or_else(|| "missing".into(), |code| code.to_string())
+}
+
+/// Calls `done` with the `grpc-status` (and `grpc-message`) from the
+/// response trailers once its body ends: `None` when it ends without one,
+/// [`CANCELLED`] when the body is dropped unfinished.
+pub(crate) fn on_end<F>(response: HttpResponse, done: F) -> HttpResponse
+where
+    F: FnOnce(Option<u16>, Option<String>) + Send + Sync + 'static,
+{
+    response.map(|body| {
+        TrailerWatch {
+            inner: body,
+            done: Some(Box::new(done)),
+        }
+        .boxed()
+    })
+}
+
+type Done = Box<dyn FnOnce(Option<u16>, Option<String>) + Send + Sync>;
+
+/// Passes a response body through, reporting the status in its trailers.
+struct TrailerWatch {
+    inner: ProxyBody,
+    done: Option<Done>,
+}
+
+impl TrailerWatch {
+    fn finish(&mut self, code: Option<u16>, message: Option<String>) {
+        if let Some(done) = self.done.take() {
+            done(code, message);
+        }
+    }
+}
+
+impl Body for TrailerWatch {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(trailers) = frame.trailers_ref() {
+                    let message = trailers
+                        .get("grpc-message")
+                        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+                    self.finish(status_in(trailers), message);
+                } else if self.inner.is_end_stream() {
+                    self.finish(None, None);
+                }
+            }
+            Some(Err(_)) | None => self.finish(None, None),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for TrailerWatch {
+    fn drop(&mut self) {
+        let code = (!self.inner.is_end_stream()).then_some(CANCELLED);
+        self.finish(code, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use http::Response;
+    use http_body_util::{BodyExt, Full};
+
+    use super::*;
+    use crate::plugin::full_body;
+
+    #[test]
+    fn grpc_content_types_are_recognized() {
+        let headers = |content_type: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            headers
+        };
+        assert!(is_grpc(&headers("application/grpc")));
+        assert!(is_grpc(&headers("application/grpc+proto")));
+        assert!(is_grpc(&headers("Application/gRPC; charset=utf-8")));
+        assert!(!is_grpc(&headers("application/grpc-web")));
+        assert!(!is_grpc(&headers("application/json")));
+        assert!(!is_grpc(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn head_status_covers_trailers_only_and_non_grpc_responses() {
+        let grpc = |status: StatusCode, grpc_status: Option<&str>| {
+            let mut response = Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "application/grpc");
+            if let Some(code) = grpc_status {
+                response = response.header("grpc-status", code);
+            }
+            response.body(full_body("")).unwrap()
+        };
+        assert_eq!(head_status(&grpc(StatusCode::OK, None)), None);
+        assert_eq!(head_status(&grpc(StatusCode::OK, Some("5"))), Some(5));
+        let plain = |status: StatusCode| {
+            Response::builder()
+                .status(status)
+                .body(full_body(""))
+                .unwrap()
+        };
+        assert_eq!(head_status(&plain(StatusCode::BAD_GATEWAY)), Some(14));
+        assert_eq!(head_status(&plain(StatusCode::NOT_FOUND)), Some(12));
+        assert_eq!(head_status(&plain(StatusCode::OK)), Some(2));
+    }
+
+    #[tokio::test]
+    async fn trailers_report_the_status_and_pass_through() {
+        let outcome = Arc::new(Mutex::new(None));
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "7".parse().unwrap());
+        trailers.insert("grpc-message", "denied".parse().unwrap());
+        let body = Full::new(Bytes::from_static(b"\0\0\0\0\0"))
+            .with_trailers(std::future::ready(Some(Ok(trailers))))
+            .map_err(|never| match never {})
+            .boxed();
+        let seen = outcome.clone();
+        let response = on_end(Response::new(body), move |code, message| {
+            *seen.lock().unwrap() = Some((code, message));
+        });
+        let collected = response.into_body().collect().await.unwrap();
+        assert_eq!(collected.trailers().unwrap()["grpc-status"], "7");
+        assert_eq!(
+            *outcome.lock().unwrap(),
+            Some((Some(7), Some("denied".to_string())))
+        );
+
+        let seen = outcome.clone();
+        let response = on_end(Response::new(full_body("partial")), move |code, _| {
+            *seen.lock().unwrap() = Some((code, None));
+        });
+        drop(response);
+        assert_eq!(*outcome.lock().unwrap(), Some((Some(CANCELLED), None)));
+    }
+}
@@ -0,0 +1,657 @@
+//! Restrictions jester places on itself once its sockets are bound
+//! (`[hardening]`): chroot, Landlock, dropped capabilities, and seccomp.
+//!
+//! Capabilities, `no_new_privs`, and Landlock domains belong to threads, so
+//! every existing thread applies them to itself; threads started later
+//! inherit them. The seccomp filter is synchronized to every thread by the
+//! kernel.
+
+use anyhow::Result;
+
+use crate::{
+    config::{Config, Hardening},
+    startup::HardeningReport,
+};
+
+/// Applies `config.hardening`, if set, and reports what was applied.
+pub(crate) fn apply(config: &Config) -> Result<Option<HardeningReport>> {
+    let Some(hardening) = &config.hardening else {
+        return Ok(None);
+    };
+    imp::apply(hardening, config).map(Some)
+}
+
+/// Paths readable and writable under Landlock: those the config names plus
+/// the hardening section's own.
+fn confined_paths(hardening: &Hardening, config: &Config) -> (Vec<String>, Vec<String>) {
+    let mut read: Vec<String> = config
+        .listeners
+        .iter()
+        .filter_map(|listener| listener.tls.as_ref())
+        .flat_map(|tls| {
+            let certificates = tls.certificates.iter().flat_map(|certificate| {
+                [
+                    Some(&certificate.cert),
+                    Some(&certificate.key),
+                    certificate.intermediates.as_ref(),
+                ]
+            });
+            [Some(&tls.cert), Some(&tls.key), tls.intermediates.as_ref()]
+                .into_iter()
+                .chain(certificates)
+        })
+        .flatten()
+        .chain(
+            config
+                .well_known
+                .iter()
+                .filter_map(|well_known| well_known.acme_challenge_dir.as_ref()),
+        )
+        .chain(
+            config
+                .plugins
+                .iter()
+                .flat_map(|plugins| &plugins.search_paths),
+        )
+        .chain(&hardening.read_paths)
+        .cloned()
+        .collect();
+    // Name resolution for upstreams, cluster peers, and ACME.
+    read.extend(RESOLVER_FILES.iter().map(|path| path.to_string()));
+    let mut write: Vec<String> = config
+        .acme
+        .iter()
+        .flat_map(|acme| {
+            let parents = acme
+                .certificates
+                .iter()
+                .flat_map(|cert| [&cert.cert, &cert.key])
+                .map(|path| parent_dir(path));
+            std::iter::once(acme.state_dir.clone()).chain(parents)
+        })
+        .chain(hardening.write_paths.iter().cloned())
+        .collect();
+    for paths in [&mut read, &mut write] {
+        let mut seen = std::collections::HashSet::new();
+        paths.retain(|path| seen.insert(path.clone()));
+    }
+    (read, write)
+}
+
+const RESOLVER_FILES: [&str; 4] = [
+    "/etc/hosts",
+    "/etc/resolv.conf",
+    "/etc/nsswitch.conf",
+    "/etc/gai.conf",
+];
+
+fn parent_dir(path: &str) -> String {
+    match std::path::Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_string_lossy().into_owned(),
+        _ => ".".into(),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use anyhow::{bail, Result};
+
+    use crate::{
+        config::{Config, Hardening},
+        startup::HardeningReport,
+    };
+
+    pub(super) fn apply(_: &Hardening, _: &Config) -> Result<HardeningReport> {
+        bail!("hardening is only supported on Linux")
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{
+        collections::HashSet,
+        io,
+        sync::atomic::{AtomicBool, AtomicI32, Ordering},
+        time::{Duration, Instant},
+    };
+
+    use anyhow::{bail, Context, Result};
+
+    use super::{confined_paths, landlock, seccomp};
+    use crate::{
+        config::{Config, Hardening},
+        startup::{HardeningReport, LandlockReport},
+    };
+
+    /// What [`confine_thread`] applies; set before any thread is signalled.
+    static RULESET: AtomicI32 = AtomicI32::new(-1);
+    static DROP_CAPABILITIES: AtomicBool = AtomicBool::new(false);
+    /// `0` until the signalled thread has run the handler, then the outcome
+    /// plus one (so a success is `1` and a failure its errno plus one).
+    static OUTCOME: AtomicI32 = AtomicI32::new(0);
+
+    /// How long a signalled thread may take to confine itself.
+    const THREAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub(super) fn apply(hardening: &Hardening, config: &Config) -> Result<HardeningReport> {
+        if let Some(root) = &hardening.chroot {
+            std::os::unix::fs::chroot(root)
+                .with_context(|| format!("failed to chroot into {root}"))?;
+            std::env::set_current_dir("/").context("failed to enter the new root")?;
+        }
+        let landlock = if hardening.landlock {
+            let (read, write) = confined_paths(hardening, config);
+            Some(landlock::Ruleset::new(&read, &write)?)
+        } else {
+            None
+        };
+        RULESET.store(
+            landlock.as_ref().map_or(-1, |ruleset| ruleset.fd()),
+            Ordering::SeqCst,
+        );
+        DROP_CAPABILITIES.store(hardening.drop_capabilities, Ordering::SeqCst);
+        let confined = confine_other_threads().and_then(|()| match confine_thread() {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)).context("failed to confine thread"),
+        });
+        RULESET.store(-1, Ordering::SeqCst);
+        confined?;
+        if hardening.seccomp {
+            seccomp::install()?;
+        }
+        Ok(HardeningReport {
+            capabilities_dropped: hardening.drop_capabilities,
+            chroot: hardening.chroot.clone(),
+            landlock: landlock.map(|ruleset| LandlockReport {
+                abi: ruleset.abi,
+                read_paths: ruleset.read_paths,
+                write_paths: ruleset.write_paths,
+            }),
+            seccomp_denied: if hardening.seccomp {
+                seccomp::DENIED.iter().map(|(name, _)| *name).collect()
+            } else {
+                Vec::new()
+            },
+            native_plugins_refused: true,
+        })
+    }
+
+    /// Capabilities, `no_new_privs`, and Landlock domains are per thread, and
+    /// runtime workers and blocking-pool threads already exist. As glibc does
+    /// for `setuid`, every other thread is signalled in turn and confines
+    /// itself in the handler; threads started meanwhile are caught by
+    /// listing the threads again until no new ones turn up.
+    fn confine_other_threads() -> Result<()> {
+        let signal = libc::SIGRTMIN();
+        // SAFETY: zeroed sigaction structs are valid; the handler only makes
+        // async-signal-safe system calls and touches atomics.
+        let previous = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = confine_on_signal as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(signal, &action, &mut previous) != 0 {
+                return Err(io::Error::last_os_error()).context("failed to install signal handler");
+            }
+            previous
+        };
+        let result = signal_threads(signal);
+        // SAFETY: restores the action saved above.
+        unsafe { libc::sigaction(signal, &previous, std::ptr::null_mut()) };
+        result
+    }
+
+    fn signal_threads(signal: libc::c_int) -> Result<()> {
+        // SAFETY: plain system calls without arguments.
+        let (pid, own) = unsafe { (libc::getpid(), libc::gettid()) };
+        let mut confined = HashSet::from([own]);
+        loop {
+            let threads: Vec<libc::pid_t> = std::fs::read_dir("/proc/self/task")
+                .context("failed to list threads")?
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .filter(|tid| !confined.contains(tid))
+                .collect();
+            if threads.is_empty() {
+                return Ok(());
+            }
+            for tid in threads {
+                confined.insert(tid);
+                OUTCOME.store(0, Ordering::SeqCst);
+                // SAFETY: tgkill with integer arguments only.
+                if unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, signal) } != 0 {
+                    // The thread has exited.
+                    continue;
+                }
+                let started = Instant::now();
+                let outcome = loop {
+                    match OUTCOME.load(Ordering::SeqCst) {
+                        0 if started.elapsed() > THREAD_TIMEOUT => {
+                            if std::path::Path::new(&format!("/proc/self/task/{tid}")).exists() {
+                                bail!("thread {tid} did not confine itself in time");
+                            }
+                            break 1;
+                        }
+                        0 => std::thread::sleep(Duration::from_millis(1)),
+                        outcome => break outcome,
+                    }
+                };
+                if outcome != 1 {
+                    return Err(io::Error::from_raw_os_error(outcome - 1))
+                        .with_context(|| format!("failed to confine thread {tid}"));
+                }
+            }
+        }
+    }
+
+    extern "C" fn confine_on_signal(_: libc::c_int) {
+        // SAFETY: errno is thread-local; it is restored for the interrupted code.
+        let errno = unsafe { *libc::__errno_location() };
+        OUTCOME.store(confine_thread() + 1, Ordering::SeqCst);
+        unsafe { *libc::__errno_location() = errno };
+    }
+
+    /// Sets `no_new_privs`, enters the Landlock domain, and clears every
+    /// capability set of the calling thread; returns `0` or an errno. Without
+    /// `CAP_SETPCAP` the bounding set stays as it is, which is harmless once
+    /// nothing is permitted and `no_new_privs` is set.
+    fn confine_thread() -> libc::c_int {
+        #[repr(C)]
+        struct Header {
+            version: u32,
+            pid: i32,
+        }
+        #[repr(C)]
+        #[derive(Default, Clone, Copy)]
+        struct Data {
+            effective: u32,
+            permitted: u32,
+            inheritable: u32,
+        }
+        const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+        // SAFETY: raw system calls whose pointer arguments reference live
+        // values of the layout the kernel expects.
+        unsafe {
+            let errno = || *libc::__errno_location();
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return errno();
+            }
+            let ruleset = RULESET.load(Ordering::SeqCst);
+            if ruleset >= 0 && libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0u32) != 0 {
+                return errno();
+            }
+            if !DROP_CAPABILITIES.load(Ordering::SeqCst) {
+                return 0;
+            }
+            if libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_CLEAR_ALL,
+                0,
+                0,
+                0,
+            ) != 0
+                && errno() != libc::EINVAL
+            {
+                return errno();
+            }
+            // Until past the last capability the kernel knows.
+            let mut cap = 0;
+            while libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) == 0 {
+                cap += 1;
+            }
+            if errno() != libc::EINVAL && errno() != libc::EPERM {
+                return errno();
+            }
+            let header = Header {
+                version: LINUX_CAPABILITY_VERSION_3,
+                pid: 0,
+            };
+            let data = [Data::default(); 2];
+            if libc::syscall(libc::SYS_capset, &header, data.as_ptr()) != 0 {
+                return errno();
+            }
+        }
+        0
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod landlock {
+    use std::{
+        fs::OpenOptions,
+        io,
+        os::{
+            fd::{AsRawFd, FromRawFd, OwnedFd},
+            unix::fs::OpenOptionsExt,
+        },
+    };
+
+    use anyhow::{Context, Result};
+
+    const EXECUTE: u64 = 1 << 0;
+    const WRITE_FILE: u64 = 1 << 1;
+    const READ_FILE: u64 = 1 << 2;
+    const READ_DIR: u64 = 1 << 3;
+    const REFER: u64 = 1 << 13;
+    const TRUNCATE: u64 = 1 << 14;
+    /// Rights that apply to files rather than directories.
+    const FILE: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE;
+
+    const CREATE_RULESET_VERSION: u32 = 1 << 0;
+    const RULE_PATH_BENEATH: u32 = 1;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// A Landlock ruleset every thread restricts itself with.
+    pub(super) struct Ruleset {
+        fd: OwnedFd,
+        pub(super) abi: i32,
+        /// Paths that got a rule; missing ones are skipped.
+        pub(super) read_paths: Vec<String>,
+        pub(super) write_paths: Vec<String>,
+    }
+
+    impl Ruleset {
+        pub(super) fn new(read: &[String], write: &[String]) -> Result<Self> {
+            // SAFETY: a null attribute with the version flag only queries the ABI.
+            let abi = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_create_ruleset,
+                    std::ptr::null::<RulesetAttr>(),
+                    0usize,
+                    CREATE_RULESET_VERSION,
+                )
+            };
+            if abi < 1 {
+                return Err(io::Error::last_os_error())
+                    .context("Landlock is not available on this kernel");
+            }
+            let handled = match abi {
+                1 => REFER - 1,
+                2 => (REFER << 1) - 1,
+                _ => (TRUNCATE << 1) - 1,
+            };
+            let attr = RulesetAttr {
+                handled_access_fs: handled,
+            };
+            // SAFETY: `attr` is a valid ruleset attribute of the size passed.
+            let fd = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_create_ruleset,
+                    &attr,
+                    std::mem::size_of::<RulesetAttr>(),
+                    0u32,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error())
+                    .context("failed to create Landlock ruleset");
+            }
+            let mut ruleset = Self {
+                // SAFETY: the syscall returned a fresh descriptor we now own.
+                fd: unsafe { OwnedFd::from_raw_fd(fd as i32) },
+                abi: abi as i32,
+                read_paths: Vec::new(),
+                write_paths: Vec::new(),
+            };
+            for path in read {
+                if ruleset.allow(path, READ_FILE | READ_DIR)? {
+                    ruleset.read_paths.push(path.clone());
+                }
+            }
+            for path in write {
+                if ruleset.allow(path, handled & !EXECUTE)? {
+                    ruleset.write_paths.push(path.clone());
+                }
+            }
+            Ok(ruleset)
+        }
+
+        /// Grants `access` beneath `path`; `false` when it does not exist.
+        fn allow(&self, path: &str, access: u64) -> Result<bool> {
+            let file = match OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+                .open(path)
+            {
+                Ok(file) => file,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    tracing::warn!(path, "Landlock path does not exist; skipping it");
+                    return Ok(false);
+                }
+                Err(err) => return Err(err).with_context(|| format!("failed to open {path}")),
+            };
+            let is_dir = file
+                .metadata()
+                .with_context(|| format!("failed to stat {path}"))?
+                .is_dir();
+            let attr = PathBeneathAttr {
+                allowed_access: if is_dir { access } else { access & FILE },
+                parent_fd: file.as_raw_fd(),
+            };
+            // SAFETY: `attr` is a valid path-beneath rule for the lifetime of the call.
+            let added = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    self.fd.as_raw_fd(),
+                    RULE_PATH_BENEATH,
+                    &attr,
+                    0u32,
+                )
+            };
+            if added != 0 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| format!("failed to add Landlock rule for {path}"));
+            }
+            Ok(true)
+        }
+
+        /// The ruleset's descriptor, for `landlock_restrict_self`.
+        pub(super) fn fd(&self) -> i32 {
+            self.fd.as_raw_fd()
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod seccomp {
+    use std::io;
+
+    use anyhow::{bail, Context, Result};
+    use libc::{c_long, sock_filter};
+
+    /// System calls refused with `EPERM`; everything else is allowed.
+    pub(super) const DENIED: &[(&str, c_long)] = &[
+        ("execve", libc::SYS_execve),
+        ("execveat", libc::SYS_execveat),
+        ("ptrace", libc::SYS_ptrace),
+        ("process_vm_readv", libc::SYS_process_vm_readv),
+        ("process_vm_writev", libc::SYS_process_vm_writev),
+        ("mount", libc::SYS_mount),
+        ("umount2", libc::SYS_umount2),
+        ("pivot_root", libc::SYS_pivot_root),
+        ("chroot", libc::SYS_chroot),
+        ("setns", libc::SYS_setns),
+        ("unshare", libc::SYS_unshare),
+        ("reboot", libc::SYS_reboot),
+        ("kexec_load", libc::SYS_kexec_load),
+        ("init_module", libc::SYS_init_module),
+        ("finit_module", libc::SYS_finit_module),
+        ("delete_module", libc::SYS_delete_module),
+        ("swapon", libc::SYS_swapon),
+        ("swapoff", libc::SYS_swapoff),
+        ("acct", libc::SYS_acct),
+        ("bpf", libc::SYS_bpf),
+        ("perf_event_open", libc::SYS_perf_event_open),
+        ("userfaultfd", libc::SYS_userfaultfd),
+        ("add_key", libc::SYS_add_key),
+        ("request_key", libc::SYS_request_key),
+        ("keyctl", libc::SYS_keyctl),
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+    /// Set in x32 system call numbers, which share the x86_64 audit arch.
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    /// Offsets into `struct seccomp_data`.
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    fn statement(code: u32, k: u32) -> sock_filter {
+        jump(code, k, 0, 0)
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+        sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// The filter: kill on a foreign architecture, refuse [`DENIED`] (and
+    /// x32 calls) with `EPERM`, allow the rest.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub(super) fn program() -> Vec<sock_filter> {
+        use libc::{
+            BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W, SECCOMP_RET_ALLOW,
+            SECCOMP_RET_ERRNO, SECCOMP_RET_KILL_PROCESS,
+        };
+        let denied = DENIED.len() as u8;
+        let mut program = vec![
+            statement(BPF_LD | BPF_W | BPF_ABS, ARCH_OFFSET),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            statement(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD | BPF_W | BPF_ABS, NR_OFFSET),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.push(jump(
+            BPF_JMP | libc::BPF_JGE | BPF_K,
+            X32_SYSCALL_BIT,
+            denied + 1,
+            0,
+        ));
+        for (index, (_, nr)) in DENIED.iter().enumerate() {
+            // Past the remaining comparisons and the allow to the refusal.
+            let skip = denied - index as u8;
+            program.push(jump(BPF_JMP | BPF_JEQ | BPF_K, *nr as u32, skip, 0));
+        }
+        program.push(statement(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+        program.push(statement(
+            BPF_RET | BPF_K,
+            SECCOMP_RET_ERRNO | libc::EPERM as u32,
+        ));
+        program
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub(super) fn program() -> Vec<sock_filter> {
+        Vec::new()
+    }
+
+    /// Installs the filter on every thread of the process.
+    pub(super) fn install() -> Result<()> {
+        let mut program = program();
+        if program.is_empty() {
+            bail!("seccomp hardening is not supported on this architecture");
+        }
+        let prog = libc::sock_fprog {
+            len: program.len() as u16,
+            filter: program.as_mut_ptr(),
+        };
+        // SAFETY: `prog` points at a filter that outlives the call; the
+        // kernel copies it.
+        let installed = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &prog,
+            )
+        };
+        match installed {
+            0 => Ok(()),
+            tid if tid > 0 => bail!("failed to install seccomp filter: thread {tid} refused it"),
+            _ => Err(io::Error::last_os_error()).context("failed to install seccomp filter"),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        fn denied_syscalls_jump_to_the_refusal() {
+            let program = program();
+            let refusal = program.len() - 1;
+            assert_eq!(
+                program[refusal].k,
+                libc::SECCOMP_RET_ERRNO | libc::EPERM as u32
+            );
+            assert_eq!(program[refusal - 1].k, libc::SECCOMP_RET_ALLOW);
+            for (name, nr) in DENIED {
+                let index = 4 + program[4..]
+                    .iter()
+                    .position(|op| op.k == *nr as u32)
+                    .unwrap_or_else(|| panic!("no check for {name}"));
+                assert_eq!(index + 1 + program[index].jt as usize, refusal, "{name}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn landlock_covers_certificates_and_acme_output() {
+        let config: Config = toml::from_str(
+            r#"
+            [[listeners]]
+            name = "public"
+            bind = "0.0.0.0:443"
+            tls = { cert = "/etc/jester/cert.pem", key = "/etc/jester/key.pem" }
+
+            [acme]
+            directory = "https://acme.example/directory"
+            state_dir = "/var/lib/jester/acme"
+            dns = { provider = "cloudflare", api_token = "token", zone_id = "zone" }
+
+            [[acme.certificates]]
+            domains = ["example.com"]
+            cert = "/var/lib/jester/certs/example.pem"
+            key = "/var/lib/jester/certs/example.key"
+            "#,
+        )
+        .unwrap();
+        let hardening = Hardening {
+            landlock: true,
+            read_paths: vec!["/etc/jester/cert.pem".into(), "/srv/static".into()],
+            ..Hardening::default()
+        };
+        let (read, write) = confined_paths(&hardening, &config);
+        assert_eq!(
+            &read[..3],
+            ["/etc/jester/cert.pem", "/etc/jester/key.pem", "/srv/static"]
+        );
+        assert!(read.contains(&"/etc/resolv.conf".to_string()));
+        assert_eq!(write, ["/var/lib/jester/acme", "/var/lib/jester/certs"]);
+    }
+}
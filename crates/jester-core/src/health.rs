@@ -0,0 +1,71 @@
+//! Load balancer health checks answered by the listener itself.
+//!
+//! `GET`/`HEAD` requests for one of a listener's `health_check_paths` get
+//! `200 ok` while the proxy serves and `503 draining` once it is shutting
+//! down, so a balancer stops sending traffic before the listeners close. They
+//! never reach the routes, filters, access log, or tap; the only trace they
+//! leave is `jester_health_checks_total{listener,state}`.
+
+use http::{header, HeaderValue, Method, Request, StatusCode};
+
+use crate::plugin::{text_response, HttpResponse};
+
+/// The response to `req` if it is a health check on one of `paths`.
+pub(crate) fn answer<B>(
+    paths: &[String],
+    req: &Request<B>,
+    listener: &str,
+    ready: bool,
+) -> Option<HttpResponse> {
+    if paths.is_empty() || !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return None;
+    }
+    let path = req.uri().path();
+    if !paths.iter().any(|candidate| candidate == path) {
+        return None;
+    }
+    let (status, state) = if ready {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "draining")
+    };
+    metrics::counter!("jester_health_checks_total", "listener" => listener.to_string(), "state" => state)
+        .increment(1);
+    let mut response = text_response(status, state);
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(method: Method, uri: &str, ready: bool) -> Option<StatusCode> {
+        let paths = ["/elb-status".to_string()];
+        let req = Request::builder().method(method).uri(uri).body(()).unwrap();
+        answer(&paths, &req, "edge", ready).map(|response| response.status())
+    }
+
+    #[test]
+    fn configured_paths_reflect_readiness() {
+        assert_eq!(
+            check(Method::GET, "/elb-status", true),
+            Some(StatusCode::OK)
+        );
+        assert_eq!(
+            check(Method::HEAD, "/elb-status?probe=1", true),
+            Some(StatusCode::OK)
+        );
+        assert_eq!(
+            check(Method::GET, "/elb-status", false),
+            Some(StatusCode::SERVICE_UNAVAILABLE)
+        );
+        assert_eq!(check(Method::POST, "/elb-status", true), None);
+        assert_eq!(check(Method::GET, "/elb-status/deep", true), None);
+        assert_eq!(check(Method::GET, "/", true), None);
+        let req = Request::get("/elb-status").body(()).unwrap();
+        assert!(answer(&[], &req, "edge", true).is_none());
+    }
+}
@@ -0,0 +1,483 @@
+//! HTTP/3 listeners (`protocol = "h3"`): QUIC on a UDP socket, with requests
+//! going through the same pipeline as those from TCP listeners.
+
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context as _, Result};
+use bytes::{Buf, Bytes};
+use h3::{
+    error::{Code, ConnectionError},
+    server::{RequestResolver, RequestStream},
+};
+use http::{header, HeaderMap, HeaderValue, Response};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use quinn::{
+    crypto::rustls::QuicServerConfig,
+    rustls::{
+        self,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+        sign::CertifiedKey,
+    },
+    Endpoint,
+};
+use tokio::{sync::watch, task::JoinSet};
+
+use crate::{
+    client_ip::ClientIpResolver,
+    config::{ClientAuth, ResolvedListener},
+    connection::Lifecycle,
+    context::ConnectionInfo,
+    plugin::ProxyBody,
+    proxy::{self, AppState},
+    tls::{self, SniCertificates, WatchedListener},
+};
+
+/// How long clients may remember the `Alt-Svc` advertisement, in seconds.
+const ALT_SVC_MAX_AGE: u32 = 86_400;
+
+pub(crate) struct Http3Listener {
+    pub(crate) name: String,
+    pub(crate) addr: SocketAddr,
+    source: ResolvedListener,
+    client_ip: Arc<ClientIpResolver>,
+    health_check_paths: Arc<[String]>,
+    config: quinn::ServerConfig,
+}
+
+impl TryFrom<ResolvedListener> for Http3Listener {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ResolvedListener) -> Result<Self> {
+        Ok(Self {
+            name: value.name.clone(),
+            addr: value.addr,
+            client_ip: Arc::new(
+                ClientIpResolver::try_from(&value.client_ip)
+                    .with_context(|| format!("invalid client_ip for listener `{}`", value.name))?,
+            ),
+            health_check_paths: value.health_check_paths.as_slice().into(),
+            config: server_config(&value)?,
+            source: value,
+        })
+    }
+}
+
+/// QUIC settings for `listener`: its certificates over TLS 1.3 with the `h3`
+/// ALPN, client certificates verified against its `tls.client_ca`, and its
+/// keep-alive timeout as the idle timeout.
+fn server_config(listener: &ResolvedListener) -> Result<quinn::ServerConfig> {
+    let tls = listener
+        .tls
+        .as_ref()
+        .with_context(|| format!("listener `{}` has no TLS certificate", listener.name))?;
+    let ring = rustls::crypto::ring::default_provider();
+    // Names are the same in both rustls versions; `Policy` has checked them.
+    let provider = Arc::new(rustls::crypto::CryptoProvider {
+        cipher_suites: crate::tls::select_by_name(
+            "cipher suite",
+            &ring.cipher_suites,
+            &tls.cipher_suites,
+            |suite| format!("{:?}", suite.suite()),
+        )?,
+        kx_groups: crate::tls::select_by_name("curve", &ring.kx_groups, &tls.curves, |group| {
+            format!("{:?}", group.name())
+        })?,
+        ..ring
+    });
+    let certificates = proxy::load_identities(listener)?.try_map(|(certs, key)| {
+        let certs = certs
+            .into_iter()
+            .map(|cert| CertificateDer::from(cert.0))
+            .collect();
+        let key = PrivateKeyDer::try_from(key.0).map_err(|err| {
+            anyhow!(
+                "invalid private key for listener `{}`: {err}",
+                listener.name
+            )
+        })?;
+        let certified = CertifiedKey::from_der(certs, key, &provider)
+            .context("invalid certificate/key pair")?;
+        Ok(Arc::new(certified))
+    })?;
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?;
+    let builder = match proxy::load_client_cas(listener)? {
+        Some((cas, auth)) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for ca in cas {
+                roots
+                    .add(CertificateDer::from(ca.0))
+                    .context("invalid certificate in tls.client_ca")?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match auth {
+                ClientAuth::Required => verifier,
+                ClientAuth::Optional => verifier.allow_unauthenticated(),
+            };
+            builder.with_client_cert_verifier(verifier.build()?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut crypto = builder.with_cert_resolver(Arc::new(certificates));
+    crypto.ignore_client_order = !tls.cipher_suites.is_empty();
+    crypto.alpn_protocols = vec![b"h3".to_vec()];
+    let mut config =
+        quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+    if let Some(timeout) = listener
+        .http
+        .keep_alive_timeout_secs
+        .filter(|secs| *secs > 0)
+    {
+        let mut transport = quinn::TransportConfig::default();
+        transport.max_idle_timeout(Some(Duration::from_secs(timeout).try_into()?));
+        config.transport_config(Arc::new(transport));
+    }
+    Ok(config)
+}
+
+impl ResolvesServerCert for SniCertificates<Arc<CertifiedKey>> {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.pick(client_hello.server_name()).clone())
+    }
+}
+
+/// `Alt-Svc` value pointing clients of the TCP listeners at the HTTP/3 ones.
+pub(crate) fn alt_svc(addrs: impl IntoIterator<Item = SocketAddr>) -> Option<HeaderValue> {
+    let services: Vec<String> = addrs
+        .into_iter()
+        .map(|addr| format!("h3=\":{}\"; ma={ALT_SVC_MAX_AGE}", addr.port()))
+        .collect();
+    if services.is_empty() {
+        return None;
+    }
+    HeaderValue::from_str(&services.join(", ")).ok()
+}
+
+impl Http3Listener {
+    pub(crate) fn bind(&self) -> std::io::Result<Endpoint> {
+        Endpoint::server(self.config.clone(), self.addr)
+    }
+
+    /// Whether the listener serves the certificate in `cert`.
+    pub(crate) fn serves(&self, cert: &str) -> bool {
+        self.source.tls.as_ref().is_some_and(|tls| tls.serves(cert))
+    }
+
+    /// Loads the certificate and key again; open connections keep the old ones.
+    pub(crate) fn reload(&self, endpoint: &Endpoint) -> Result<()> {
+        endpoint.set_server_config(Some(server_config(&self.source)?));
+        Ok(())
+    }
+
+    pub(crate) fn watched(self: &Arc<Self>, endpoint: &Endpoint) -> Option<WatchedListener> {
+        let tls = self.source.tls.as_ref()?;
+        let (listener, endpoint) = (self.clone(), endpoint.clone());
+        Some(WatchedListener::new(&self.name, tls, move || {
+            listener.reload(&endpoint)
+        }))
+    }
+
+    /// Whether the `served`-th request is the last a connection takes; a zero
+    /// keep-alive timeout means one request, as on HTTP/1.
+    fn last_request(&self, served: u64) -> bool {
+        let http = &self.source.http;
+        http.max_requests_per_connection
+            .is_some_and(|max| served >= max)
+            || http.keep_alive_timeout_secs == Some(0)
+    }
+
+    pub(crate) async fn serve(
+        self: Arc<Self>,
+        endpoint: Endpoint,
+        state: Arc<AppState>,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        let local_addr = endpoint.local_addr()?;
+        loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.changed() => {
+                    tracing::info!(listener = self.name, "listener shutting down");
+                    // Refuse new connections; open ones finish their requests.
+                    endpoint.set_server_config(None);
+                    break;
+                }
+                incoming = endpoint.accept() => {
+                    let Some(incoming) = incoming else {
+                        break;
+                    };
+                    let listener = self.clone();
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = listener.handle_connection(incoming, local_addr, state).await {
+                            tracing::warn!(error = %err, "connection closed with error");
+                        }
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_connection(
+        self: Arc<Self>,
+        incoming: quinn::Incoming,
+        local_addr: SocketAddr,
+        state: Arc<AppState>,
+    ) -> Result<()> {
+        let peer_addr = incoming.remote_address();
+        let mut lifecycle = Lifecycle::accepted(
+            &state.stats,
+            &self.name,
+            peer_addr,
+            self.source.log_connections,
+        );
+        let handshake = Instant::now();
+        let quic = match incoming.await {
+            Ok(quic) => quic,
+            Err(err) => {
+                lifecycle.close("tls_failed");
+                return Err(err.into());
+            }
+        };
+        let tls_handshake = handshake.elapsed();
+        let client_certificate = quic
+            .peer_identity()
+            .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+            .and_then(|chain| tls::client_certificate(&chain));
+        lifecycle.tls_established(tls_handshake);
+        lifecycle.negotiated("h3");
+        let mut conn =
+            match h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(quic)).await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    lifecycle.close(h3_close_reason(&err));
+                    return Err(err.into());
+                }
+            };
+        let connection = ConnectionInfo {
+            listener: self.name.clone(),
+            scheme: "https",
+            local_addr,
+            peer_addr,
+            client_addr: peer_addr,
+            trust_forwarded_headers: self.source.trust_forwarded_headers,
+            tls_handshake,
+            missing_host: self.source.missing_host.clone(),
+            absolute_form: self.source.absolute_form,
+            health_check_paths: self.health_check_paths.clone(),
+            client_certificate,
+        };
+        let counters = lifecycle.counters();
+        let mut requests = JoinSet::new();
+        let served = loop {
+            let resolver = match conn.accept().await {
+                Ok(Some(resolver)) => resolver,
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            };
+            let served = counters.request_served();
+            requests.spawn(serve_request(
+                resolver,
+                state.clone(),
+                connection.clone(),
+                self.client_ip.clone(),
+            ));
+            if self.last_request(served) {
+                // GOAWAY: requests already open finish, new ones go elsewhere.
+                break conn.shutdown(0).await;
+            }
+        };
+        // Dropping the connection closes it, so let its requests finish first.
+        while requests.join_next().await.is_some() {}
+        drop(conn);
+        lifecycle.close(match &served {
+            Ok(()) => "closed",
+            Err(err) => h3_close_reason(err),
+        });
+        match served {
+            Err(err) if !err.is_h3_no_error() => Err(anyhow!(err).context(format!(
+                "connection handling failed for listener `{}` from {peer_addr}",
+                self.name
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Why an HTTP/3 connection ended.
+fn h3_close_reason(err: &ConnectionError) -> &'static str {
+    if err.is_h3_no_error() {
+        "closed"
+    } else if matches!(err, ConnectionError::Timeout { .. }) {
+        "timeout"
+    } else {
+        "error"
+    }
+}
+
+async fn serve_request(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    state: Arc<AppState>,
+    connection: ConnectionInfo,
+    client_ip: Arc<ClientIpResolver>,
+) {
+    let (req, stream) = match resolver.resolve_request().await {
+        Ok(resolved) => resolved,
+        Err(err) => {
+            tracing::debug!(error = %err, "failed to read HTTP/3 request");
+            return;
+        }
+    };
+    let (mut send, recv) = stream.split();
+    let body = match RequestBody::read(req.headers(), recv).await {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::debug!(error = %err, "failed to read HTTP/3 request body");
+            return;
+        }
+    };
+    let req = req.map(|()| body);
+    let mut resp = match proxy::handle_request(state, connection, &client_ip, req).await {
+        Ok(resp) => resp,
+        Err(err) => {
+            tracing::error!(error = %err, "request handling failed");
+            proxy::internal_error()
+        }
+    };
+    proxy::strip_connection_headers(resp.headers_mut());
+    if let Err(err) = send_response(&mut send, resp).await {
+        tracing::debug!(error = format!("{err:#}"), "failed to send HTTP/3 response");
+    }
+}
+
+async fn send_response(
+    stream: &mut RequestStream<h3_quinn::SendStream<Bytes>, Bytes>,
+    resp: Response<ProxyBody>,
+) -> Result<()> {
+    let (parts, mut body) = resp.into_parts();
+    stream
+        .send_response(Response::from_parts(parts, ()))
+        .await?;
+    while let Some(frame) = body.frame().await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(err) => {
+                // Resetting tells the client the response is incomplete.
+                stream.stop_stream(Code::H3_INTERNAL_ERROR);
+                return Err(anyhow!(err).context("response body failed"));
+            }
+        };
+        match frame.into_data() {
+            Ok(data) => stream.send_data(data).await?,
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    stream.send_trailers(trailers).await?;
+                }
+            }
+        }
+    }
+    stream.finish().await?;
+    Ok(())
+}
+
+/// A request body read from its HTTP/3 stream.
+struct RequestBody {
+    /// The stream is only polled through `&mut`; the lock makes the body
+    /// `Sync` as the pipeline requires.
+    stream: Mutex<RequestStream<h3_quinn::RecvStream, Bytes>>,
+    /// A frame read ahead of the pipeline.
+    first: Option<Frame<Bytes>>,
+    reading: Reading,
+}
+
+#[derive(PartialEq, Eq)]
+enum Reading {
+    Data,
+    Trailers,
+    Done,
+}
+
+impl RequestBody {
+    /// Wraps `stream`. Without a `content-length`, the first frame is read up
+    /// front: a request that turns out to have no body is then sent upstream
+    /// without one, rather than as an empty chunked body.
+    async fn read(
+        headers: &HeaderMap,
+        mut stream: RequestStream<h3_quinn::RecvStream, Bytes>,
+    ) -> Result<Self, h3::error::StreamError> {
+        let mut first = None;
+        let mut reading = Reading::Data;
+        if !headers.contains_key(header::CONTENT_LENGTH) {
+            match stream.recv_data().await? {
+                Some(mut data) => first = Some(Frame::data(data.copy_to_bytes(data.remaining()))),
+                None => {
+                    first = stream.recv_trailers().await?.map(Frame::trailers);
+                    reading = Reading::Done;
+                }
+            }
+        }
+        Ok(Self {
+            stream: Mutex::new(stream),
+            first,
+            reading,
+        })
+    }
+
+    fn ended(&self) -> bool {
+        self.first.is_none() && self.reading == Reading::Done
+    }
+}
+
+impl Body for RequestBody {
+    type Data = Bytes;
+    type Error = h3::error::StreamError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        let this = self.get_mut();
+        if let Some(frame) = this.first.take() {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        let stream = this
+            .stream
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        if this.reading == Reading::Data {
+            match ready!(stream.poll_recv_data(cx))? {
+                Some(mut data) => {
+                    return Poll::Ready(Some(Ok(Frame::data(data.copy_to_bytes(data.remaining())))))
+                }
+                None => this.reading = Reading::Trailers,
+            }
+        }
+        if this.reading == Reading::Trailers {
+            let trailers = ready!(stream.poll_recv_trailers(cx))?;
+            this.reading = Reading::Done;
+            return Poll::Ready(trailers.map(|trailers| Ok(Frame::trailers(trailers))));
+        }
+        Poll::Ready(None)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.ended()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        if self.ended() {
+            SizeHint::with_exact(0)
+        } else {
+            SizeHint::default()
+        }
+    }
+}
@@ -1,7 +1,48 @@
+pub mod acme;
+pub mod admin;
+mod balance;
+pub mod builtins;
+mod client;
+mod client_ip;
+mod cluster;
 pub mod config;
+mod connection;
+mod consul;
+pub mod context;
+mod dns;
+mod drain;
+mod election;
+pub mod error;
+pub mod events;
+pub mod filter;
+mod flags;
+mod grpc;
+mod hardening;
+mod health;
+mod http3;
 pub mod plugin;
+pub mod plugin_host;
+mod profile;
 pub mod proxy;
+mod redirect;
+mod retry;
 pub mod router;
+mod schedule;
+mod sigv4;
+pub mod startup;
+pub mod stats;
+mod stream;
+mod streaming;
+pub mod tap;
+mod tls;
+mod udp;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod websocket;
+mod well_known;
+
+#[cfg(feature = "profiling")]
+pub use profile::HeapSampler;
 
 /// Returns the crate version baked in at compile time.
 pub const fn version() -> &'static str {
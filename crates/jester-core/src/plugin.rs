@@ -1,12 +1,34 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
 use bytes::Bytes;
-use http::{Request, Response};
+use http::{request::Parts, HeaderMap, Request, Response, StatusCode};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
 use serde_json::Value;
-use tower::{util::BoxService, Layer};
+use tower::{util::BoxCloneSyncService, Layer};
+
+use crate::error::ProxyError;
 
-pub type HttpRequest = Request<Bytes>;
-pub type HttpResponse = Response<Bytes>;
-pub type JesterService = BoxService<HttpRequest, HttpResponse, anyhow::Error>;
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+pub type ProxyBody = BoxBody<Bytes, BoxError>;
+pub type HttpRequest = Request<ProxyBody>;
+pub type HttpResponse = Response<ProxyBody>;
+pub type JesterService = BoxCloneSyncService<HttpRequest, HttpResponse, anyhow::Error>;
 pub type DynLayer = Box<dyn Layer<JesterService, Service = JesterService> + Send + Sync>;
+pub type ResponseFuture = Pin<Box<dyn Future<Output = anyhow::Result<HttpResponse>> + Send>>;
+
+/// Wraps a fully buffered payload as a [`ProxyBody`].
+pub fn full_body(data: impl Into<Bytes>) -> ProxyBody {
+    Full::new(data.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+/// Builds a proxy-generated plain-text response.
+pub fn text_response(status: StatusCode, msg: impl Into<Bytes>) -> HttpResponse {
+    let mut response = Response::new(full_body(msg));
+    *response.status_mut() = status;
+    response
+}
 
 /// Canonical plugin trait implemented by core + external extensions.
 pub trait JesterPlugin: Send + Sync + 'static {
@@ -14,4 +36,66 @@ pub trait JesterPlugin: Send + Sync + 'static {
     fn version(&self) -> semver::Version;
     fn layer(&self, cfg: Value) -> anyhow::Result<DynLayer>;
     fn capabilities(&self) -> &'static [&'static str];
+
+    /// Where the plugin's filters must sit relative to other plugins' filters;
+    /// chains that break them are refused when the config loads.
+    fn constraints(&self) -> &'static [Constraint] {
+        &[]
+    }
+
+    /// A [`RetryAdvisor`] for routes whose `[routes.retry]` names this plugin
+    /// as `advisor`, configured from their `advisor_config`. Plugins that do
+    /// not advise on retries keep the default.
+    fn retry_advisor(&self, _cfg: Value) -> anyhow::Result<Option<Arc<dyn RetryAdvisor>>> {
+        Ok(None)
+    }
+}
+
+/// Decides after each upstream attempt whether a route tries again, in place
+/// of the `retry_on`, `statuses`, and `non_idempotent` settings of its retry
+/// policy. `attempts`, the retry budget, and body replay limits still apply.
+pub trait RetryAdvisor: Send + Sync + 'static {
+    /// Called with the request as it was sent and the `attempt` (from 1) that
+    /// produced `outcome`. Changes made to `request` go out with the retry
+    /// when the answer is [`RetryDecision::Retry`] and are dropped otherwise;
+    /// pointing its `uri` at another target sends the retry there.
+    fn decide(
+        &self,
+        request: &mut Parts,
+        outcome: AttemptOutcome<'_>,
+        attempt: u32,
+    ) -> RetryDecision;
+}
+
+/// What an upstream attempt came to, as seen by a [`RetryAdvisor`].
+#[derive(Debug, Clone, Copy)]
+pub enum AttemptOutcome<'a> {
+    /// The upstream answered; the body has not been read.
+    Response {
+        status: StatusCode,
+        headers: &'a HeaderMap,
+    },
+    Failed(&'a ProxyError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Leave it to the route's retry policy.
+    Policy,
+    Retry,
+    /// Hand this outcome to the client.
+    Stop,
+}
+
+/// An ordering constraint between a plugin's filters and another plugin's,
+/// named as in [`JesterPlugin::name`]. Requests pass through the global chain's
+/// request filters, then the route's; responses through the route's response
+/// filters, then the global chain's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Constraint {
+    /// The named plugin must be in the chain and run before this one, e.g. an
+    /// authorization filter relying on the user an authentication filter found.
+    Requires(&'static str),
+    /// If the named plugin is in the chain, it must run after this one.
+    Precedes(&'static str),
 }
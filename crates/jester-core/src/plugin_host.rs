@@ -0,0 +1,315 @@
+//! Logging, metric, and request context functions the host provides to
+//! plugins, as declared in the SDK's `host.wit`.
+//!
+//! Log records go through `tracing` under the `jester::plugin` target with the
+//! plugin's name attached. Metrics must be declared in the plugin's manifest:
+//! a recording is accepted only for a declared name and kind, with exactly the
+//! declared label keys, and while the metric stays within its `max_series`
+//! label combinations. Accepted ones are exported as `jester_plugin_<name>`
+//! with a `plugin` label; dropped ones are counted in
+//! `jester_plugin_metric_rejections_total{plugin,reason}`. Context values
+//! cross the boundary as JSON text.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Context, Result};
+use jester_plugin_sdk::{manifest::DEFAULT_MAX_SERIES, MetricKind, PluginManifest};
+use metrics::Label;
+use tracing::Level;
+
+use crate::context::RequestContext;
+
+/// Label values longer than this are refused, so a plugin cannot smuggle
+/// unbounded data (request IDs, URLs) into metric labels.
+const MAX_LABEL_VALUE_BYTES: usize = 128;
+
+/// One plugin's view of the host's logging and metrics.
+pub struct PluginHost {
+    plugin: String,
+    metrics: HashMap<String, Guard>,
+}
+
+struct Guard {
+    kind: MetricKind,
+    labels: Vec<String>,
+    max_series: usize,
+    /// Label value combinations seen so far, in declaration order.
+    series: Mutex<HashSet<Vec<String>>>,
+}
+
+impl PluginHost {
+    /// Fails for plugins built against an SDK whose interfaces this host does
+    /// not provide.
+    pub fn new(manifest: &PluginManifest) -> Result<Self> {
+        manifest.check_sdk()?;
+        let metrics = manifest
+            .metrics
+            .iter()
+            .map(|metric| {
+                let guard = Guard {
+                    kind: metric.kind,
+                    labels: metric.labels.clone(),
+                    max_series: metric.max_series.unwrap_or(DEFAULT_MAX_SERIES),
+                    series: Mutex::default(),
+                };
+                (metric.name.clone(), guard)
+            })
+            .collect();
+        Ok(Self {
+            plugin: manifest.name.clone(),
+            metrics,
+        })
+    }
+
+    /// Emits a log record on behalf of the plugin.
+    pub fn log(&self, level: Level, message: &str, fields: &[(String, String)]) {
+        let fields = fields
+            .iter()
+            .map(|(key, value)| format!("{key}={value:?}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let plugin = self.plugin.as_str();
+        match level {
+            Level::TRACE => tracing::trace!(target: "jester::plugin", plugin, fields, "{message}"),
+            Level::DEBUG => tracing::debug!(target: "jester::plugin", plugin, fields, "{message}"),
+            Level::INFO => tracing::info!(target: "jester::plugin", plugin, fields, "{message}"),
+            Level::WARN => tracing::warn!(target: "jester::plugin", plugin, fields, "{message}"),
+            Level::ERROR => tracing::error!(target: "jester::plugin", plugin, fields, "{message}"),
+        }
+    }
+
+    pub fn counter_add(&self, name: &str, value: u64, labels: &[(String, String)]) -> Result<()> {
+        let labels = self.admit(name, MetricKind::Counter, labels)?;
+        metrics::counter!(self.metric_name(name), labels).increment(value);
+        Ok(())
+    }
+
+    pub fn gauge_set(&self, name: &str, value: f64, labels: &[(String, String)]) -> Result<()> {
+        let labels = self.admit(name, MetricKind::Gauge, labels)?;
+        metrics::gauge!(self.metric_name(name), labels).set(value);
+        Ok(())
+    }
+
+    pub fn histogram_record(
+        &self,
+        name: &str,
+        value: f64,
+        labels: &[(String, String)],
+    ) -> Result<()> {
+        let labels = self.admit(name, MetricKind::Histogram, labels)?;
+        metrics::histogram!(self.metric_name(name), labels).record(value);
+        Ok(())
+    }
+
+    /// The value stored under `key` in `context`, as JSON.
+    pub fn context_get(&self, context: &RequestContext, key: &str) -> Option<String> {
+        context.value(key).map(|value| value.to_string())
+    }
+
+    /// Stores the JSON `value` under `key` in `context`, returning the JSON
+    /// the key held before.
+    pub fn context_set(
+        &self,
+        context: &RequestContext,
+        key: &str,
+        value: &str,
+    ) -> Result<Option<String>> {
+        let value = serde_json::from_str(value)
+            .with_context(|| format!("value for context key `{key}` is not JSON"))?;
+        Ok(context
+            .set_value(key, value)
+            .map(|previous| previous.to_string()))
+    }
+
+    fn metric_name(&self, name: &str) -> String {
+        format!("jester_plugin_{name}")
+    }
+
+    /// Checks a recording against the metric's declaration, returning its
+    /// labels with `plugin` added.
+    fn admit(
+        &self,
+        name: &str,
+        kind: MetricKind,
+        labels: &[(String, String)],
+    ) -> Result<Vec<Label>> {
+        self.check(name, kind, labels).map_err(|(reason, message)| {
+            metrics::counter!(
+                "jester_plugin_metric_rejections_total",
+                "plugin" => self.plugin.clone(),
+                "reason" => reason
+            )
+            .increment(1);
+            anyhow!(message)
+        })
+    }
+
+    fn check(
+        &self,
+        name: &str,
+        kind: MetricKind,
+        labels: &[(String, String)],
+    ) -> std::result::Result<Vec<Label>, (&'static str, String)> {
+        let reject = |reason, message: String| Err((reason, message));
+        let Some(guard) = self.metrics.get(name) else {
+            return reject("undeclared", format!("metric `{name}` is not declared"));
+        };
+        if guard.kind != kind {
+            return reject(
+                "kind",
+                format!("metric `{name}` is declared as a {:?}", guard.kind),
+            );
+        }
+        let mut values = Vec::with_capacity(guard.labels.len());
+        for key in &guard.labels {
+            let mut matching = labels.iter().filter(|(label, _)| label == key);
+            match (matching.next(), matching.next()) {
+                (Some((_, value)), None) if value.len() <= MAX_LABEL_VALUE_BYTES => {
+                    values.push(value.clone())
+                }
+                (Some(_), None) => {
+                    return reject(
+                        "labels",
+                        format!("label `{key}` of metric `{name}` is too long"),
+                    )
+                }
+                _ => {
+                    return reject(
+                        "labels",
+                        format!("metric `{name}` needs exactly one `{key}` label"),
+                    )
+                }
+            }
+        }
+        if labels.len() != guard.labels.len() {
+            return reject(
+                "labels",
+                format!("metric `{name}` takes only the labels {:?}", guard.labels),
+            );
+        }
+        let mut series = guard
+            .series
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !series.contains(&values) {
+            if series.len() >= guard.max_series {
+                return reject(
+                    "cardinality",
+                    format!(
+                        "metric `{name}` already has {} label combinations",
+                        guard.max_series
+                    ),
+                );
+            }
+            series.insert(values.clone());
+        }
+        Ok(std::iter::once(Label::new("plugin", self.plugin.clone()))
+            .chain(
+                guard
+                    .labels
+                    .iter()
+                    .zip(values)
+                    .map(|(key, value)| Label::new(key.clone(), value)),
+            )
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host() -> PluginHost {
+        let manifest: PluginManifest = serde_json::from_value(serde_json::json!({
+            "name": "jwt-auth",
+            "sdk_version": jester_plugin_sdk::SDK_VERSION.to_string(),
+            "metrics": [
+                {"name": "tokens_verified", "kind": "counter", "labels": ["outcome"], "max_series": 2},
+                {"name": "key_age_seconds", "kind": "gauge"},
+            ],
+        }))
+        .unwrap();
+        PluginHost::new(&manifest).unwrap()
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn recordings_must_match_their_declaration() {
+        let host = host();
+        let ok = labels(&[("outcome", "ok")]);
+        assert!(host.counter_add("tokens_verified", 1, &ok).is_ok());
+        assert!(host.gauge_set("key_age_seconds", 12.0, &[]).is_ok());
+
+        let err = host.counter_add("tokens_issued", 1, &ok).unwrap_err();
+        assert!(err.to_string().contains("not declared"), "{err}");
+        assert!(host.gauge_set("tokens_verified", 1.0, &ok).is_err());
+        assert!(host.counter_add("tokens_verified", 1, &[]).is_err());
+        let extra = labels(&[("outcome", "ok"), ("user", "alice")]);
+        assert!(host.counter_add("tokens_verified", 1, &extra).is_err());
+        let twice = labels(&[("outcome", "ok"), ("outcome", "bad")]);
+        assert!(host.counter_add("tokens_verified", 1, &twice).is_err());
+        let long = labels(&[("outcome", &"x".repeat(200))]);
+        assert!(host.counter_add("tokens_verified", 1, &long).is_err());
+    }
+
+    #[test]
+    fn label_combinations_are_capped() {
+        let host = host();
+        for outcome in ["ok", "expired", "ok"] {
+            let labels = labels(&[("outcome", outcome)]);
+            assert!(host.counter_add("tokens_verified", 1, &labels).is_ok());
+        }
+        let third = labels(&[("outcome", "malformed")]);
+        let err = host.counter_add("tokens_verified", 1, &third).unwrap_err();
+        assert!(err.to_string().contains("label combinations"), "{err}");
+    }
+
+    #[test]
+    fn context_values_cross_as_json() {
+        let host = host();
+        let context = RequestContext::default();
+        assert_eq!(host.context_get(&context, "auth.user"), None);
+        let previous = host
+            .context_set(&context, "auth.user", r#"{"id": "alice"}"#)
+            .unwrap();
+        assert_eq!(previous, None);
+        assert_eq!(
+            context.value("auth.user"),
+            Some(serde_json::json!({"id": "alice"}))
+        );
+
+        context.set_value("quota.remaining", serde_json::json!(41));
+        assert_eq!(
+            host.context_get(&context, "quota.remaining").as_deref(),
+            Some("41")
+        );
+        let previous = host.context_set(&context, "auth.user", "null").unwrap();
+        assert_eq!(previous.as_deref(), Some(r#"{"id":"alice"}"#));
+
+        let err = host
+            .context_set(&context, "auth.user", "alice")
+            .unwrap_err();
+        assert!(err.to_string().contains("`auth.user` is not JSON"), "{err}");
+        assert_eq!(context.value("auth.user"), Some(serde_json::Value::Null));
+    }
+
+    #[test]
+    fn plugins_for_other_sdks_are_refused() {
+        let manifest = PluginManifest {
+            name: "legacy".into(),
+            sdk_version: Some("0.1.0".into()),
+            ..Default::default()
+        };
+        let err = PluginHost::new(&manifest).err().unwrap();
+        assert!(err.to_string().contains("built with SDK 0.1.0"), "{err}");
+    }
+}
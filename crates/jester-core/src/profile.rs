@@ -0,0 +1,352 @@
+//! On-demand profiling of where request handling spends its time.
+//!
+//! `POST /profile` on the admin API samples a share of requests for a while.
+//! Each poll of a sampled request's filters, route, and upstream call is timed,
+//! and the time spent in a layer itself, not in the layers it wraps, is added to
+//! its stack, e.g. `jester;filter:ip-filter;route:app;filter:compression`. The
+//! result is in the folded format flamegraph tools and Pyroscope read, with
+//! microseconds as the sample count.
+//!
+//! Only the work done while polling is seen: a filter that blocks its thread
+//! shows up, while one waiting on I/O does not. With the `profiling` feature,
+//! [`native`] adds CPU profiles of native stacks and heap profiles, and
+//! renders any profile as a flamegraph.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Write as _,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use tower::{layer::layer_fn, Service};
+
+use crate::plugin::{DynLayer, HttpRequest, HttpResponse, JesterService, ResponseFuture};
+
+#[cfg(feature = "profiling")]
+pub(crate) mod native;
+
+#[cfg(feature = "profiling")]
+pub use native::HeapSampler;
+
+/// Longest profile the admin API will take.
+pub(crate) const MAX_DURATION: Duration = Duration::from_secs(300);
+
+/// Response header of `POST /profile` carrying how many requests were sampled.
+pub(crate) const SAMPLED_HEADER: &str = "x-jester-sampled-requests";
+
+/// Refuses profiles that are empty or longer than [`MAX_DURATION`].
+pub(crate) fn check_duration(duration: Duration) -> Result<()> {
+    if duration.is_zero() || duration > MAX_DURATION {
+        bail!(
+            "duration must be between 1 and {} seconds",
+            MAX_DURATION.as_secs()
+        );
+    }
+    Ok(())
+}
+
+/// Starts profiles and picks the requests they sample.
+#[derive(Default)]
+pub(crate) struct Profiler {
+    session: Mutex<Option<Arc<Session>>>,
+}
+
+impl Profiler {
+    /// Starts sampling `rate` (0 to 1) of requests for `duration`. Only one
+    /// profile runs at a time.
+    pub(crate) fn start(&self, rate: f64, duration: Duration) -> Result<Arc<Session>> {
+        if !(rate > 0.0 && rate <= 1.0) {
+            bail!("rate must be greater than 0 and at most 1");
+        }
+        check_duration(duration)?;
+        let mut current = self.lock();
+        if current.as_ref().is_some_and(|session| !session.done()) {
+            bail!("a profile is already running");
+        }
+        let session = Arc::new(Session {
+            rate,
+            deadline: Instant::now() + duration,
+            seen: AtomicU64::new(0),
+            sampled: AtomicU64::new(0),
+            stacks: Mutex::default(),
+        });
+        *current = Some(session.clone());
+        Ok(session)
+    }
+
+    /// Whether a profile is currently running.
+    pub(crate) fn running(&self) -> bool {
+        self.lock().as_ref().is_some_and(|session| !session.done())
+    }
+
+    /// The profile a new request should be sampled for, if any.
+    pub(crate) fn sample(&self) -> Option<Sampled> {
+        let session = self.lock().clone()?;
+        if session.done() {
+            return None;
+        }
+        // Sample evenly: request `n` is taken when `n * rate` crosses an integer.
+        let n = session.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        if (n * session.rate).floor() == ((n + 1.0) * session.rate).floor() {
+            return None;
+        }
+        session.sampled.fetch_add(1, Ordering::Relaxed);
+        Some(Sampled(session))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Arc<Session>>> {
+        self.session
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// One running profile.
+pub(crate) struct Session {
+    rate: f64,
+    deadline: Instant,
+    seen: AtomicU64,
+    sampled: AtomicU64,
+    /// Microseconds of self time per folded stack.
+    stacks: Mutex<HashMap<String, u64>>,
+}
+
+impl Session {
+    fn done(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    /// Waits for the profile to end.
+    pub(crate) async fn finished(&self) {
+        tokio::time::sleep_until(self.deadline.into()).await;
+    }
+
+    pub(crate) fn sampled(&self) -> u64 {
+        self.sampled.load(Ordering::Relaxed)
+    }
+
+    /// The stacks recorded so far in folded format, one `stack micros` line
+    /// each, keeping those that pass through `route` when given.
+    pub(crate) fn folded(&self, route: Option<&str>) -> String {
+        let frame = route.map(|route| format!(";route:{route};"));
+        let stacks = self
+            .stacks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut lines: Vec<_> = stacks
+            .iter()
+            .filter(|(stack, _)| {
+                frame
+                    .as_deref()
+                    .is_none_or(|frame| format!("{stack};").contains(frame))
+            })
+            .collect();
+        lines.sort();
+        let mut folded = String::new();
+        for (stack, micros) in lines {
+            let _ = writeln!(folded, "{stack} {micros}");
+        }
+        folded
+    }
+
+    fn record(&self, stack: String, spent: Duration) {
+        let micros = spent.as_micros() as u64;
+        if micros == 0 {
+            return;
+        }
+        *self
+            .stacks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(stack)
+            .or_default() += micros;
+    }
+}
+
+/// Marks a request sampled by a profile; set by the proxy before the
+/// pipeline runs.
+#[derive(Clone)]
+pub(crate) struct Sampled(Arc<Session>);
+
+/// A layer entered on this thread: its frame and the time its inner layers
+/// took so far.
+struct Entered {
+    frame: Arc<str>,
+    inner: Duration,
+}
+
+thread_local! {
+    static STACK: RefCell<Vec<Entered>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Runs `f` as `frame`, charging its time less that of frames entered within
+/// it to the current stack.
+fn timed<R>(sampled: &Sampled, frame: &Arc<str>, f: impl FnOnce() -> R) -> R {
+    STACK.with_borrow_mut(|stack| {
+        stack.push(Entered {
+            frame: frame.clone(),
+            inner: Duration::ZERO,
+        })
+    });
+    let started = Instant::now();
+    let result = f();
+    let spent = started.elapsed();
+    let (stack, entered) = STACK.with_borrow_mut(|stack| {
+        let entered = stack.pop().expect("entered frame is on the stack");
+        if let Some(parent) = stack.last_mut() {
+            parent.inner += spent;
+        }
+        let mut path = String::new();
+        for outer in stack.iter() {
+            path.push_str(&outer.frame);
+            path.push(';');
+        }
+        path.push_str(&entered.frame);
+        (path, entered)
+    });
+    sampled.0.record(stack, spent.saturating_sub(entered.inner));
+    result
+}
+
+/// Wraps `service` so sampled requests are profiled as `frame`.
+pub(crate) fn wrap(frame: impl Into<Arc<str>>, service: JesterService) -> JesterService {
+    JesterService::new(Profiled {
+        frame: frame.into(),
+        inner: service,
+    })
+}
+
+/// Profiles the services `layer` produces as `frame`.
+pub(crate) fn layer(frame: String, layer: DynLayer) -> DynLayer {
+    let frame: Arc<str> = frame.into();
+    Box::new(layer_fn(move |inner| {
+        wrap(frame.clone(), layer.layer(inner))
+    }))
+}
+
+#[derive(Clone)]
+struct Profiled {
+    frame: Arc<str>,
+    inner: JesterService,
+}
+
+impl Service<HttpRequest> for Profiled {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HttpRequest) -> Self::Future {
+        let Some(sampled) = req.extensions().get::<Sampled>().cloned() else {
+            return self.inner.call(req);
+        };
+        // Filters often do their request work in `call`, before any poll.
+        let inner = timed(&sampled, &self.frame, || self.inner.call(req));
+        Box::pin(ProfiledFuture {
+            frame: self.frame.clone(),
+            sampled,
+            inner,
+        })
+    }
+}
+
+struct ProfiledFuture {
+    frame: Arc<str>,
+    sampled: Sampled,
+    inner: ResponseFuture,
+}
+
+impl Future for ProfiledFuture {
+    type Output = Result<HttpResponse>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        timed(&this.sampled, &this.frame, || this.inner.as_mut().poll(cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::plugin::{full_body, text_response};
+
+    fn busy(frame: &'static str, spin: Duration, inner: Option<JesterService>) -> JesterService {
+        wrap(
+            frame,
+            JesterService::new(tower::service_fn(
+                move |req: HttpRequest| -> ResponseFuture {
+                    let started = Instant::now();
+                    while started.elapsed() < spin {}
+                    match &inner {
+                        Some(inner) => inner.clone().call(req),
+                        None => Box::pin(std::future::ready(Ok(text_response(
+                            http::StatusCode::OK,
+                            "ok",
+                        )))),
+                    }
+                },
+            )),
+        )
+    }
+
+    #[tokio::test]
+    async fn time_is_charged_to_the_layer_that_spent_it() {
+        let profiler = Profiler::default();
+        let session = profiler.start(1.0, Duration::from_secs(60)).unwrap();
+        let inner = busy("route:app", Duration::from_millis(20), None);
+        let service = busy("jester", Duration::from_millis(5), Some(inner));
+
+        let mut req = HttpRequest::new(full_body(""));
+        req.extensions_mut().insert(profiler.sample().unwrap());
+        service.clone().oneshot(req).await.unwrap();
+        // Unsampled requests are not recorded.
+        service
+            .oneshot(HttpRequest::new(full_body("")))
+            .await
+            .unwrap();
+
+        let folded = session.folded(None);
+        let micros = |stack: &str| -> u64 {
+            folded
+                .lines()
+                .find_map(|line| line.strip_prefix(stack)?.strip_prefix(' '))
+                .unwrap_or_else(|| panic!("no `{stack}` in {folded}"))
+                .parse()
+                .unwrap()
+        };
+        let outer = micros("jester");
+        let inner = micros("jester;route:app");
+        assert!((5_000..20_000).contains(&outer), "{folded}");
+        assert!(inner >= 20_000, "{folded}");
+        assert_eq!(session.folded(Some("other")), "");
+        assert_eq!(session.sampled(), 1);
+    }
+
+    #[test]
+    fn rates_sample_requests_evenly_and_one_profile_runs_at_a_time() {
+        let profiler = Profiler::default();
+        assert!(profiler.start(0.0, Duration::from_secs(1)).is_err());
+        assert!(profiler.start(0.5, Duration::ZERO).is_err());
+        profiler.start(0.25, Duration::from_secs(60)).unwrap();
+        let sampled = (0..100).filter(|_| profiler.sample().is_some()).count();
+        assert_eq!(sampled, 25);
+        let Err(err) = profiler.start(1.0, Duration::from_secs(1)) else {
+            panic!("a second profile started");
+        };
+        assert_eq!(err.to_string(), "a profile is already running");
+    }
+}
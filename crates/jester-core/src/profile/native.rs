@@ -0,0 +1,352 @@
+//! Native CPU and heap profiles, built with the `profiling` feature.
+//!
+//! CPU profiles come from pprof-rs, which samples every thread's native stack
+//! on a `SIGPROF` timer, so they are only available on Unix. Heap profiles
+//! come from [`HeapSampler`], a global allocator that records the stack of
+//! roughly one allocation per [`SAMPLE_BYTES`] allocated while a profile runs.
+//! The result is the bytes allocated during the profile by call stack, not
+//! the heap still live at its end.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    collections::HashMap,
+    ffi::c_void,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+
+/// Average bytes allocated between two heap samples.
+const SAMPLE_BYTES: usize = 512 * 1024;
+/// Deepest stack recorded for a heap sample.
+const MAX_DEPTH: usize = 64;
+
+/// Whether a native profile of each kind is running; one of each at a time.
+static CPU_RUNNING: AtomicBool = AtomicBool::new(false);
+static HEAP_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Clears a running flag when the profile ends, however it ends.
+struct Running(&'static AtomicBool);
+
+impl Running {
+    fn claim(flag: &'static AtomicBool, kind: &str) -> Result<Self> {
+        if flag.swap(true, Ordering::AcqRel) {
+            bail!("a {kind} profile is already running");
+        }
+        Ok(Self(flag))
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Whether `kind` profiles can be taken in this process: CPU profiles on
+/// Unix, heap profiles once [`HeapSampler`] is the global allocator.
+pub(crate) fn available(kind: &str) -> bool {
+    match kind {
+        "cpu" => cfg!(unix),
+        _ => INSTALLED.load(Ordering::Relaxed),
+    }
+}
+
+/// Whether a `kind` profile is currently running.
+pub(crate) fn running(kind: &str) -> bool {
+    match kind {
+        "cpu" => CPU_RUNNING.load(Ordering::Acquire),
+        _ => HEAP_RUNNING.load(Ordering::Acquire),
+    }
+}
+
+/// Samples native stacks `frequency` times a second for `duration` and
+/// returns them folded, one `thread;frame;... samples` line each.
+#[cfg(unix)]
+pub(crate) async fn cpu(duration: Duration, frequency: i32) -> Result<String> {
+    let _running = Running::claim(&CPU_RUNNING, "cpu")?;
+    // Starting the profiler and symbolizing its report walk the binary's
+    // debug info, which can take a while; keep that off the runtime threads.
+    let guard = tokio::task::spawn_blocking(move || {
+        pprof::ProfilerGuardBuilder::default()
+            .frequency(frequency)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+    })
+    .await?
+    .map_err(|err| anyhow!("failed to start the cpu profiler: {err}"))?;
+    tokio::time::sleep(duration).await;
+    let mut lines = tokio::task::spawn_blocking(move || {
+        let report = guard
+            .report()
+            .build()
+            .map_err(|err| anyhow!("failed to build the cpu profile: {err}"))?;
+        let lines: Vec<String> = report
+            .data
+            .iter()
+            .map(|(frames, count)| {
+                let mut line = frames.thread_name_or_id();
+                for frame in frames.frames.iter().rev() {
+                    for symbol in frame.iter().rev() {
+                        let _ = write!(line, ";{symbol}");
+                    }
+                }
+                let _ = write!(line, " {count}");
+                line
+            })
+            .collect();
+        anyhow::Ok(lines)
+    })
+    .await??;
+    lines.sort();
+    Ok(fold(lines))
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn cpu(_duration: Duration, _frequency: i32) -> Result<String> {
+    bail!("cpu profiles are only available on Unix")
+}
+
+/// Global allocator recording heap samples while a heap profile runs.
+/// Install it in the binary to make `POST /profile?kind=heap` available:
+///
+/// ```ignore
+/// #[global_allocator]
+/// static ALLOC: jester_core::HeapSampler = jester_core::HeapSampler;
+/// ```
+///
+/// Outside a profile it adds two relaxed atomic loads per allocation.
+pub struct HeapSampler;
+
+/// Set by the first allocation through [`HeapSampler`].
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static SAMPLING: AtomicBool = AtomicBool::new(false);
+/// Instruction pointers of each sampled allocation, innermost first, and the
+/// bytes it stands for.
+static SAMPLES: Mutex<Vec<(Vec<usize>, usize)>> = Mutex::new(Vec::new());
+
+thread_local! {
+    /// Bytes this thread may still allocate before its next sample.
+    static UNTIL_SAMPLE: Cell<usize> = const { Cell::new(SAMPLE_BYTES) };
+    /// Set while this thread records a sample, so allocations made doing so
+    /// are not sampled themselves.
+    static RECORDING: Cell<bool> = const { Cell::new(false) };
+}
+
+unsafe impl GlobalAlloc for HeapSampler {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            observe(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            observe(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let ptr = System.realloc(ptr, layout, new_size);
+        if !ptr.is_null() {
+            observe(new_size.saturating_sub(layout.size()));
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+fn observe(size: usize) {
+    if !INSTALLED.load(Ordering::Relaxed) {
+        INSTALLED.store(true, Ordering::Relaxed);
+    }
+    if size == 0 || !SAMPLING.load(Ordering::Relaxed) {
+        return;
+    }
+    let Ok(Some(weight)) = UNTIL_SAMPLE.try_with(|until| {
+        let left = until.get();
+        if size < left {
+            until.set(left - size);
+            return None;
+        }
+        // Each interval this allocation crosses stands for `SAMPLE_BYTES`.
+        let over = size - left;
+        until.set(SAMPLE_BYTES - over % SAMPLE_BYTES);
+        Some((1 + over / SAMPLE_BYTES) * SAMPLE_BYTES)
+    }) else {
+        return;
+    };
+    if RECORDING.try_with(|recording| recording.replace(true)) != Ok(false) {
+        return;
+    }
+    let mut ips = [0usize; MAX_DEPTH];
+    let mut depth = 0;
+    backtrace::trace(|frame| {
+        ips[depth] = frame.ip() as usize;
+        depth += 1;
+        depth < MAX_DEPTH
+    });
+    if let Ok(mut samples) = SAMPLES.lock() {
+        samples.push((ips[..depth].to_vec(), weight));
+    }
+    let _ = RECORDING.try_with(|recording| recording.set(false));
+}
+
+/// Records heap samples for `duration` and returns them folded, one
+/// `frame;... bytes` line per stack.
+pub(crate) async fn heap(duration: Duration) -> Result<String> {
+    if !INSTALLED.load(Ordering::Relaxed) {
+        bail!("heap profiles need `jester_core::HeapSampler` as the global allocator");
+    }
+    let _running = Running::claim(&HEAP_RUNNING, "heap")?;
+    SAMPLES.lock().unwrap_or_else(|p| p.into_inner()).clear();
+    SAMPLING.store(true, Ordering::Relaxed);
+    tokio::time::sleep(duration).await;
+    SAMPLING.store(false, Ordering::Relaxed);
+    let samples = std::mem::take(&mut *SAMPLES.lock().unwrap_or_else(|p| p.into_inner()));
+    Ok(tokio::task::spawn_blocking(move || fold_samples(samples)).await?)
+}
+
+/// Symbolizes heap samples and sums their bytes per stack.
+fn fold_samples(samples: Vec<(Vec<usize>, usize)>) -> String {
+    let mut names: HashMap<usize, Vec<String>> = HashMap::new();
+    let mut stacks: HashMap<String, usize> = HashMap::new();
+    for (ips, bytes) in samples {
+        let mut frames = Vec::new();
+        for ip in ips {
+            let symbols = names.entry(ip).or_insert_with(|| resolve(ip));
+            frames.extend(symbols.iter().cloned());
+        }
+        // Drop the sampler's own frames, innermost first.
+        if let Some(last) = frames.iter().rposition(|frame| {
+            frame.contains("HeapSampler") || frame.contains("profile::native::observe")
+        }) {
+            frames.drain(..=last);
+        }
+        frames.reverse();
+        *stacks.entry(frames.join(";")).or_default() += bytes;
+    }
+    let mut lines: Vec<String> = stacks
+        .into_iter()
+        .filter(|(stack, _)| !stack.is_empty())
+        .map(|(stack, bytes)| format!("{stack} {bytes}"))
+        .collect();
+    lines.sort();
+    fold(lines)
+}
+
+/// Symbol names at `ip`, innermost inlined frame first.
+fn resolve(ip: usize) -> Vec<String> {
+    let mut symbols = Vec::new();
+    backtrace::resolve(ip as *mut c_void, |symbol| {
+        symbols.push(match symbol.name() {
+            Some(name) => format!("{name:#}"),
+            None => format!("{ip:#x}"),
+        });
+    });
+    if symbols.is_empty() {
+        symbols.push(format!("{ip:#x}"));
+    }
+    symbols
+}
+
+fn fold(lines: Vec<String>) -> String {
+    let mut folded = String::new();
+    for line in lines {
+        folded.push_str(&line);
+        folded.push('\n');
+    }
+    folded
+}
+
+/// Renders folded stacks as a flamegraph SVG.
+pub(crate) fn flamegraph(folded: &str, title: &str, count_name: &str) -> Result<Vec<u8>> {
+    let mut options = inferno::flamegraph::Options::default();
+    options.title = title.to_string();
+    options.count_name = count_name.to_string();
+    let mut svg = Vec::new();
+    if folded.is_empty() {
+        bail!("the profile recorded no samples");
+    }
+    inferno::flamegraph::from_lines(&mut options, folded.lines(), &mut svg)
+        .map_err(|err| anyhow!("failed to render the flamegraph: {err}"))?;
+    Ok(svg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flamegraphs_render_folded_stacks() {
+        let svg = flamegraph("jester;route:app 120\njester 30\n", "requests", "us").unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        assert!(svg.starts_with("<?xml"), "{svg}");
+        assert!(svg.contains("route:app"));
+        assert!(flamegraph("", "requests", "us").is_err());
+    }
+
+    #[inline(never)]
+    fn allocate_for_the_heap_test() {
+        observe(3 * SAMPLE_BYTES);
+    }
+
+    #[tokio::test]
+    async fn heap_profiles_charge_sampled_bytes_to_the_allocating_stack() {
+        INSTALLED.store(true, Ordering::Relaxed);
+        let profile = tokio::spawn(heap(Duration::from_millis(200)));
+        while !SAMPLING.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        allocate_for_the_heap_test();
+        let folded = profile.await.unwrap().unwrap();
+        let line = folded
+            .lines()
+            .find(|line| line.contains("allocate_for_the_heap_test"))
+            .unwrap_or_else(|| panic!("no sample from the test in {folded}"));
+        let (stack, bytes) = line.rsplit_once(' ').unwrap();
+        assert!(stack.ends_with("allocate_for_the_heap_test"), "{stack}");
+        assert!(
+            bytes.parse::<usize>().unwrap() >= 3 * SAMPLE_BYTES,
+            "{line}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cpu_profiles_sample_native_stacks() {
+        let done = std::sync::Arc::new(AtomicBool::new(false));
+        let spinning = done.clone();
+        let spin = std::thread::spawn(move || {
+            let mut x = 0u64;
+            while !spinning.load(Ordering::Relaxed) {
+                x = std::hint::black_box(x.wrapping_mul(31).wrapping_add(7));
+            }
+            x
+        });
+        let folded = cpu(Duration::from_millis(300), 199).await.unwrap();
+        done.store(true, Ordering::Relaxed);
+        spin.join().unwrap();
+        assert!(!folded.is_empty());
+        assert!(
+            folded.lines().all(|line| line
+                .rsplit_once(' ')
+                .is_some_and(|(_, count)| count.parse::<u64>().is_ok())),
+            "{folded}"
+        );
+        assert!(!running("cpu"));
+    }
+}
@@ -1,120 +1,1426 @@
-use std::{net::SocketAddr, sync::Arc, time::Instant};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    pin::pin,
+    sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock},
+    time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use bytes::Bytes;
-use http::{header, StatusCode, Uri};
-use http_body_util::{combinators::BoxBody, BodyExt, Full};
-use hyper::server::conn::http1;
+use http::{header, Method, StatusCode, Uri};
+use http_body_util::{BodyExt, Full};
+use hyper::server::conn::{http1, http2};
 use hyper::{body::Incoming, service::service_fn, Request, Response};
-use hyper_util::{
-    client::legacy::{connect::HttpConnector, Client},
-    rt::{TokioExecutor, TokioIo},
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use serde::Serialize;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::{watch, Notify},
+    task::{JoinHandle, JoinSet},
 };
-use tokio::{net::TcpListener, sync::watch, task::JoinSet, time::timeout};
-use tokio_rustls::{
-    rustls::{Certificate, PrivateKey, ServerConfig},
-    TlsAcceptor,
+use tokio_rustls::rustls::{
+    server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient},
+    sign::{any_supported_type, CertifiedKey},
+    Certificate, PrivateKey, RootCertStore, ServerConfig,
 };
+use tower::{Layer, ServiceExt};
+use tracing::Instrument;
 
 use crate::{
-    config::{Config, ResolvedListener},
-    router::{RouteHandle, Router},
+    acme,
+    admin::{serve_admin, AdminListener, AdminState, LogControl, LogHandle},
+    builtins::CidrSet,
+    client::{announcing, connection_use, socket_path, UpstreamClients},
+    client_ip::{self, ClientIpResolver},
+    cluster::{self, Gossip},
+    config::EventKind,
+    config::{
+        AbsoluteForm, AcmeCertificate, Admin, ClientAuth, Config, DebugRequests, Filter,
+        HostHeader, HttpTweaks, Listener, ListenerKind, ListenerProtocol, MissingHost,
+        ResolvedListener, Route, Streaming, UpstreamOverride, UpstreamPool, UpstreamProtocol,
+    },
+    connection::{close_reason, CountingStream, Lifecycle},
+    context::{ClientCertificate, ClientIp, ConnectionInfo, EarlyData, RequestContext},
+    drain::{self, Drain},
+    election,
+    error::{self, ProxyError},
+    events::{self, Event, Events},
+    filter::FilterRegistry,
+    flags::FlagSet,
+    grpc, hardening, health,
+    http3::{self, Http3Listener},
+    plugin::{
+        full_body, text_response, BoxError, DynLayer, HttpRequest, HttpResponse, JesterPlugin,
+        JesterService, ProxyBody, ResponseFuture,
+    },
+    profile::{self, Profiler},
+    redirect::RedirectListener,
+    retry::Retry,
+    router::{Router, Selection, UpstreamEndpoint},
+    startup::{Degraded, HardeningReport, StartupReport},
+    stats::{self, target_key, RuntimeStats},
+    stream::StreamListener,
+    streaming,
+    tap::{AccessEvent, CaptureLimits, Recording, Tap},
+    tls::{self, Acceptor, Handshake, Replayed, SniCertificates, WatchedListener},
+    udp::UdpListener,
+    websocket,
+    well_known::WellKnownFiles,
 };
 
-type ProxyBody = BoxBody<Bytes, hyper::Error>;
-type HttpClient = Client<HttpConnector, Incoming>;
+pub(crate) const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(50);
+
+/// How long a trusted proxy has to send its PROXY protocol header.
+pub(crate) const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client on a shared socket, or a stream listener routing by
+/// SNI, has to send its TLS ClientHello.
+pub(crate) const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Headers telling routing, filters, and upstreams about the certificate a
+/// client presented on a mutual-TLS listener. Clients cannot set them.
+const CLIENT_CERT_SUBJECT: &str = "x-client-cert-subject";
+const CLIENT_CERT_SAN: &str = "x-client-cert-san";
+
+/// Early data accepted per connection on listeners with `early_data` set;
+/// enough for a request's headers.
+const MAX_EARLY_DATA_BYTES: u32 = 16 * 1024;
+
+/// `EnvFilter` directive that turns on trace logging inside the spans of debug
+/// requests (see [`DebugRequests`]); subscribers should include it.
+pub const DEBUG_REQUEST_DIRECTIVE: &str = "[request{debug=true}]=trace";
 
 /// Primary proxy runtime handle.
+///
+/// Build one from a parsed [`Config`] with [`Proxy::new`], or assemble it in code
+/// with [`Proxy::builder`] when embedding jester in another application.
 pub struct Proxy {
     state: Arc<AppState>,
-    listeners: Vec<ListenerRuntime>,
+    control: Arc<ProxyControl>,
+    sockets: Vec<SocketRuntime>,
+    redirects: Vec<RedirectListener>,
+    streams: Vec<StreamListener>,
+    udps: Vec<UdpListener>,
+    http3: Vec<Http3Listener>,
+    admin: Option<AdminRuntime>,
+    degraded: Vec<Degraded>,
+    bind: BindOptions,
 }
 
-struct AppState {
-    router: Router,
-    client: HttpClient,
+/// What to do when some listeners cannot be bound at startup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BindPolicy {
+    /// Refuse to start unless every listener binds.
+    #[default]
+    FailFast,
+    /// Start with whichever listeners bound, as long as at least one did.
+    BestEffort,
+}
+
+/// Startup bind behaviour; `AddrInUse` failures are retried with exponential
+/// backoff before the policy is applied.
+#[derive(Debug, Clone, Copy)]
+pub struct BindOptions {
+    pub policy: BindPolicy,
+    pub retries: u32,
+    pub initial_backoff: Duration,
+}
+
+impl Default for BindOptions {
+    fn default() -> Self {
+        Self {
+            policy: BindPolicy::default(),
+            retries: 3,
+            initial_backoff: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Produces a fresh [`Config`] for `POST /reload`, e.g. by re-reading the file
+/// the proxy was started with.
+pub type ConfigLoader = Arc<dyn Fn() -> Result<Config> + Send + Sync>;
+
+pub(crate) struct AppState {
+    /// Swapped wholesale on reload; requests keep the pipeline they started with.
+    pipeline: RwLock<Arc<Pipeline>>,
+    tap: Tap,
+    pub(crate) stats: RuntimeStats,
+    events: Events,
+    /// Set when `[cluster]` is configured; fixed until restart.
+    gossip: Option<Arc<Gossip>>,
+    profiler: Profiler,
+    /// `Alt-Svc` advertising the HTTP/3 listeners, set once they are bound.
+    alt_svc: OnceLock<header::HeaderValue>,
+    /// Set once shutdown begins: health checks answer `503` and long-lived
+    /// streams are told to go away.
+    pub(crate) drain: Drain,
+}
+
+impl AppState {
+    fn pipeline(&self) -> Arc<Pipeline> {
+        self.pipeline
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+/// The config-derived part of request handling.
+struct Pipeline {
+    /// Global filter chain wrapping route dispatch.
+    service: JesterService,
+    debug: Option<DebugTrigger>,
+    upstream_override: Option<OverrideTrigger>,
+    /// Set when `[tap] capture_bodies` is on.
+    capture: Option<CaptureLimits>,
+}
+
+/// Everything besides the config needed to build a [`Pipeline`]; kept so a
+/// reload reuses the plugins, layers, and upstream connection pools.
+struct PipelineFactory {
+    registry: FilterRegistry,
+    layers: Vec<DynLayer>,
+    clients: UpstreamClients,
+    stats: RuntimeStats,
+}
+
+impl PipelineFactory {
+    fn build(&self, config: &Config) -> Result<Pipeline> {
+        let via = config
+            .via
+            .enabled
+            .then(|| Arc::from(format!("{} (jester/{})", config.via.node, crate::version())));
+        let upstream = profile::wrap(
+            "upstream",
+            upstream_service(
+                self.clients.clone(),
+                self.stats.clone(),
+                via,
+                config.upstream_pool,
+            ),
+        );
+        self.registry
+            .check_ordering(&config.filters, &config.routes)?;
+        let router = Router::build(&config.routes, &self.registry, upstream)?;
+        let mut service = self.registry.build_global_chain(
+            &config.filters,
+            dispatch_service(
+                router,
+                WellKnownFiles::new(&config.well_known)?,
+                self.stats.clone(),
+            ),
+        )?;
+        for layer in self.layers.iter().rev() {
+            service = layer.layer(service);
+        }
+        service = profile::wrap("jester", service);
+        if let Some(flags) = config.flags.as_ref().map(FlagSet::start).transpose()? {
+            // The pipeline owns the flags; their refresh task stops once it is replaced.
+            service = JesterService::new(service.map_request(move |mut req: HttpRequest| {
+                req.extensions_mut().insert(flags.clone());
+                req
+            }));
+        }
+        Ok(Pipeline {
+            service,
+            debug: config
+                .debug
+                .as_ref()
+                .map(DebugTrigger::try_from)
+                .transpose()?,
+            upstream_override: config
+                .upstream_override
+                .as_ref()
+                .map(OverrideTrigger::try_from)
+                .transpose()?,
+            capture: CaptureLimits::from_options(&config.tap),
+        })
+    }
+}
+
+/// Operations on a running proxy exposed through the admin API.
+pub(crate) struct ProxyControl {
+    state: Arc<AppState>,
+    factory: PipelineFactory,
+    config: Mutex<Config>,
+    loader: Option<ConfigLoader>,
+    drain: Notify,
+    /// Asks the certificate watcher to reload every listener's certificates.
+    certificates: Arc<Notify>,
+    startup: OnceLock<StartupReport>,
+}
+
+/// Result of a successful reload.
+#[derive(Debug, Serialize)]
+pub(crate) struct ReloadOutcome {
+    pub(crate) routes: usize,
+    /// Listener, admin, ACME, event sink, cluster, or shutdown changes were not applied;
+    /// they need a restart.
+    pub(crate) restart_required: bool,
+}
+
+impl ProxyControl {
+    pub(crate) fn registry(&self) -> &FilterRegistry {
+        &self.factory.registry
+    }
+
+    pub(crate) fn stats(&self) -> &RuntimeStats {
+        &self.state.stats
+    }
+
+    pub(crate) fn profiler(&self) -> &Profiler {
+        &self.state.profiler
+    }
+
+    pub(crate) fn gossip(&self) -> Option<&Gossip> {
+        self.state.gossip.as_deref()
+    }
+
+    /// The configuration currently serving traffic.
+    pub(crate) fn config(&self) -> Config {
+        self.current_config().clone()
+    }
+
+    /// The report logged when listeners were bound; `None` before that.
+    pub(crate) fn startup(&self) -> Option<&StartupReport> {
+        self.startup.get()
+    }
+
+    /// Builds and logs the startup report for listeners bound at `bound`.
+    fn report_startup(
+        &self,
+        bound: &[(String, SocketAddr)],
+        degraded: Vec<Degraded>,
+        hardening: Option<HardeningReport>,
+    ) -> StartupReport {
+        let report =
+            StartupReport::new(&self.config(), self.registry(), bound, degraded, hardening);
+        report.log();
+        self.startup.get_or_init(|| report.clone());
+        report
+    }
+
+    pub(crate) fn can_reload(&self) -> bool {
+        self.loader.is_some()
+    }
+
+    /// Loads a new config and swaps in its routes and filters; in-flight
+    /// requests finish on the old pipeline.
+    pub(crate) fn reload(&self) -> Result<ReloadOutcome> {
+        let result = self.try_reload();
+        let outcome = if result.is_ok() { "success" } else { "error" };
+        metrics::counter!("jester_config_reloads_total", "outcome" => outcome).increment(1);
+        match &result {
+            Ok(reload) => tracing::info!(
+                routes = reload.routes,
+                restart_required = reload.restart_required,
+                "configuration reloaded"
+            ),
+            Err(err) => tracing::warn!(error = %err, "configuration reload failed"),
+        }
+        result
+    }
+
+    fn try_reload(&self) -> Result<ReloadOutcome> {
+        let Some(loader) = &self.loader else {
+            bail!("no config source to reload from");
+        };
+        let config = loader()?;
+        config.validate()?;
+        let pipeline = self.factory.build(&config)?;
+        let mut current = self.current_config();
+        let restart_required = serde_json::to_value(&current.listeners)?
+            != serde_json::to_value(&config.listeners)?
+            || serde_json::to_value(&current.admin)? != serde_json::to_value(&config.admin)?
+            || serde_json::to_value(&current.acme)? != serde_json::to_value(&config.acme)?
+            || serde_json::to_value(&current.events)? != serde_json::to_value(&config.events)?
+            || serde_json::to_value(&current.cluster)? != serde_json::to_value(&config.cluster)?
+            || serde_json::to_value(&current.shutdown)? != serde_json::to_value(&config.shutdown)?;
+        *self
+            .state
+            .pipeline
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(pipeline);
+        for event in events::route_changes(&current.routes, &config.routes) {
+            self.state.events.publish(event);
+        }
+        let routes = config.routes.len();
+        *current = config;
+        Ok(ReloadOutcome {
+            routes,
+            restart_required,
+        })
+    }
+
+    /// Stops accepting connections and lets in-flight ones finish, as on a
+    /// shutdown signal.
+    pub(crate) fn drain(&self) {
+        tracing::info!("drain requested through the admin API");
+        self.drain.notify_one();
+    }
+
+    fn current_config(&self) -> MutexGuard<'_, Config> {
+        self.config
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Recognizes debug requests by their secret header.
+struct DebugTrigger {
+    header: http::HeaderName,
+    secret: String,
+}
+
+impl DebugTrigger {
+    /// Strips the trigger header and reports whether it carried the secret.
+    fn take(&self, headers: &mut http::HeaderMap) -> bool {
+        headers
+            .remove(&self.header)
+            .is_some_and(|value| constant_time_eq(value.as_bytes(), self.secret.as_bytes()))
+    }
+}
+
+impl TryFrom<&DebugRequests> for DebugTrigger {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &DebugRequests) -> Result<Self> {
+        Ok(Self {
+            header: http::HeaderName::from_bytes(value.header.as_bytes())?,
+            secret: value.secret.clone(),
+        })
+    }
+}
+
+/// Recognizes upstream overrides from trusted callers.
+struct OverrideTrigger {
+    header: http::HeaderName,
+    trusted: CidrSet,
+}
+
+/// Marks requests on routes with `suppress_via`.
+#[derive(Clone, Copy)]
+struct SuppressVia;
+
+/// Upstream named by a trusted caller; replaces the selected route's target.
+#[derive(Clone)]
+struct OverrideTarget(Uri);
+
+impl OverrideTrigger {
+    /// Strips the override header and returns the target it names if `client`
+    /// may use it; an unusable value from a trusted client is an error.
+    fn take(
+        &self,
+        headers: &mut http::HeaderMap,
+        client: IpAddr,
+    ) -> Result<Option<OverrideTarget>, &'static str> {
+        let Some(value) = headers.remove(&self.header) else {
+            return Ok(None);
+        };
+        if !self.trusted.contains(client) {
+            metrics::counter!("jester_upstream_overrides_total", "outcome" => "untrusted")
+                .increment(1);
+            tracing::warn!(target: "jester::audit", %client, "ignored upstream override from untrusted client");
+            return Ok(None);
+        }
+        let target = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse::<Uri>().ok())
+            .filter(|uri| matches!(uri.scheme_str(), Some("http" | "https")))
+            .filter(|uri| uri.authority().is_some());
+        match target {
+            Some(uri) => Ok(Some(OverrideTarget(uri))),
+            None => {
+                metrics::counter!("jester_upstream_overrides_total", "outcome" => "invalid")
+                    .increment(1);
+                Err("invalid upstream override")
+            }
+        }
+    }
+}
+
+impl TryFrom<&UpstreamOverride> for OverrideTrigger {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &UpstreamOverride) -> Result<Self> {
+        Ok(Self {
+            header: http::HeaderName::from_bytes(value.header.as_bytes())?,
+            trusted: CidrSet::parse(&value.trusted)?,
+        })
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+struct AdminRuntime {
+    addr: Option<SocketAddr>,
+    socket: Option<String>,
+    state: Arc<AdminState>,
 }
 
 struct ListenerRuntime {
     name: String,
     addr: SocketAddr,
-    acceptor: TlsAcceptor,
+    /// `None` on plaintext listeners.
+    tls: Option<Arc<ListenerTls>>,
+    trust_forwarded_headers: bool,
+    log_connections: bool,
+    missing_host: MissingHost,
+    absolute_form: AbsoluteForm,
+    client_ip: Arc<ClientIpResolver>,
+    limits: ConnectionLimits,
+    server_names: Vec<String>,
+    health_check_paths: Arc<[String]>,
+    /// Whether ALPN offers `http/1.1`, the protocol of clients offering none.
+    http1: bool,
+}
+
+/// Listeners bound to one address. When several share it, or one names its
+/// `server_names`, each connection goes to the listener its SNI picks.
+struct SocketRuntime {
+    addr: SocketAddr,
+    listeners: Vec<ListenerRuntime>,
+}
+
+impl SocketRuntime {
+    /// Groups listeners binding the same address, in configuration order.
+    /// Port 0 asks for a fresh ephemeral port, so those are never shared.
+    fn group(listeners: Vec<ListenerRuntime>) -> Vec<Self> {
+        let mut sockets: Vec<Self> = Vec::new();
+        for listener in listeners {
+            match sockets
+                .iter_mut()
+                .find(|socket| socket.addr == listener.addr && listener.addr.port() != 0)
+            {
+                Some(socket) => socket.listeners.push(listener),
+                None => sockets.push(Self {
+                    addr: listener.addr,
+                    listeners: vec![listener],
+                }),
+            }
+        }
+        sockets
+    }
+
+    fn names(&self) -> String {
+        let names: Vec<&str> = self.listeners.iter().map(|l| l.name.as_str()).collect();
+        names.join(", ")
+    }
+
+    fn selects_by_sni(&self) -> bool {
+        self.listeners.len() > 1 || !self.listeners[0].server_names.is_empty()
+    }
+
+    /// The listener for `server_name`: an exact name, then a wildcard, then
+    /// the listener without `server_names`.
+    fn select(&self, server_name: Option<&str>) -> Option<&ListenerRuntime> {
+        let claims = |pattern: &str| {
+            self.listeners
+                .iter()
+                .find(|listener| listener.server_names.iter().any(|name| name == pattern))
+        };
+        let exact = server_name.and_then(claims);
+        let wildcard = || {
+            let (_, parent) = server_name?.split_once('.')?;
+            claims(&format!("*.{parent}"))
+        };
+        exact.or_else(wildcard).or_else(|| {
+            self.listeners
+                .iter()
+                .find(|listener| listener.server_names.is_empty())
+        })
+    }
+}
+
+/// A listener's TLS acceptor, rebuilt when its certificate is replaced.
+struct ListenerTls {
+    source: ResolvedListener,
+    acceptor: RwLock<Acceptor>,
+}
+
+impl ListenerTls {
+    fn acceptor(&self) -> Acceptor {
+        self.acceptor
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Loads the certificate and key again; open connections keep the old ones.
+    fn reload(&self) -> Result<()> {
+        let acceptor = Acceptor::new(build_tls_config(&self.source)?);
+        *self
+            .acceptor
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = acceptor;
+        Ok(())
+    }
+
+    fn watched(self: Arc<Self>) -> Option<WatchedListener> {
+        let tls = self.source.tls.clone()?;
+        let name = self.source.name.clone();
+        Some(WatchedListener::new(&name, &tls, move || self.reload()))
+    }
+}
+
+/// Serves a renewed certificate on every listener configured with its files.
+fn reload_certificate(
+    listeners: &[Arc<ListenerTls>],
+    http3: &[(Arc<Http3Listener>, quinn::Endpoint)],
+    certificate: &AcmeCertificate,
+) {
+    for listener in listeners.iter().filter(|listener| {
+        listener
+            .source
+            .tls
+            .as_ref()
+            .is_some_and(|tls| tls.serves(&certificate.cert))
+    }) {
+        match listener.reload() {
+            Ok(()) => tracing::info!(
+                listener = listener.source.name,
+                "listener now serves the renewed certificate"
+            ),
+            Err(err) => tracing::warn!(
+                listener = listener.source.name,
+                error = format!("{err:#}"),
+                "failed to load renewed certificate"
+            ),
+        }
+    }
+    for (listener, endpoint) in http3
+        .iter()
+        .filter(|(listener, _)| listener.serves(&certificate.cert))
+    {
+        match listener.reload(endpoint) {
+            Ok(()) => tracing::info!(
+                listener = listener.name,
+                "listener now serves the renewed certificate"
+            ),
+            Err(err) => tracing::warn!(
+                listener = listener.name,
+                error = format!("{err:#}"),
+                "failed to load renewed certificate"
+            ),
+        }
+    }
+}
+
+/// Connection lifetime limits from a listener's `[listeners.http]` table.
+#[derive(Debug, Clone, Copy, Default)]
+struct ConnectionLimits {
+    keep_alive_timeout: Option<Duration>,
+    max_requests: Option<u64>,
+    h2_max_concurrent_streams: Option<u32>,
+    h2_keepalive_interval: Option<Duration>,
+    h2_keepalive_timeout: Option<Duration>,
+}
+
+impl From<&HttpTweaks> for ConnectionLimits {
+    fn from(http: &HttpTweaks) -> Self {
+        Self {
+            keep_alive_timeout: http.keep_alive_timeout_secs.map(Duration::from_secs),
+            max_requests: http.max_requests_per_connection,
+            h2_max_concurrent_streams: http.h2_max_concurrent_streams,
+            h2_keepalive_interval: http.h2_keepalive_interval_secs.map(Duration::from_secs),
+            h2_keepalive_timeout: http.h2_keepalive_timeout_secs.map(Duration::from_secs),
+        }
+    }
+}
+
+impl ConnectionLimits {
+    /// Whether the `served`-th request is the last an HTTP/2 connection takes;
+    /// a zero keep-alive timeout means one request, as on HTTP/1.
+    fn h2_last_request(&self, served: u64) -> bool {
+        self.max_requests.is_some_and(|max| served >= max)
+            || self.keep_alive_timeout == Some(Duration::ZERO)
+    }
 }
 
 impl Proxy {
     pub fn new(config: Config) -> Result<Self> {
-        config.validate()?;
-        let router = Router::build(&config.routes)?;
-        let listeners = config
-            .resolved_listeners()?
-            .into_iter()
-            .map(ListenerRuntime::try_from)
-            .collect::<Result<Vec<_>>>()?;
-        let client = build_client();
-        let state = Arc::new(AppState { router, client });
-        Ok(Self { state, listeners })
+        Self::builder().config(config).build()
+    }
+
+    /// Starts a [`ProxyBuilder`] with an empty configuration and the builtin filters.
+    pub fn builder() -> ProxyBuilder {
+        ProxyBuilder::default()
+    }
+
+    /// Access events for every request served by this proxy.
+    pub fn tap(&self) -> &Tap {
+        &self.state.tap
+    }
+
+    /// Live in-flight counters, shared with every request via its extensions.
+    pub fn stats(&self) -> &RuntimeStats {
+        &self.state.stats
+    }
+
+    /// Serves until Ctrl+C (or, on Windows, Ctrl+Break) is received, then
+    /// drains listeners. Elsewhere, SIGHUP reloads listener certificates.
+    pub async fn run(self) -> Result<()> {
+        #[cfg(not(windows))]
+        let certificates = self.control.certificates.clone();
+        self.serve(async move {
+            tracing::info!("awaiting shutdown signal (Ctrl+C)");
+            #[cfg(windows)]
+            {
+                let mut ctrl_break = tokio::signal::windows::ctrl_break()
+                    .context("failed to install ctrl-break handler")?;
+                tokio::select! {
+                    signal = tokio::signal::ctrl_c() => {
+                        signal.context("failed to install ctrl-c handler")
+                    }
+                    _ = ctrl_break.recv() => {
+                        tracing::info!("Ctrl+Break received");
+                        Ok(())
+                    }
+                }
+            }
+            #[cfg(not(windows))]
+            {
+                use tokio::signal::unix::{signal, SignalKind};
+
+                let mut hangup =
+                    signal(SignalKind::hangup()).context("failed to install SIGHUP handler")?;
+                let mut ctrl_c = pin!(tokio::signal::ctrl_c());
+                loop {
+                    tokio::select! {
+                        signal = &mut ctrl_c => {
+                            return signal.context("failed to install ctrl-c handler");
+                        }
+                        _ = hangup.recv() => {
+                            tracing::info!("SIGHUP received; reloading listener certificates");
+                            certificates.notify_one();
+                        }
+                    }
+                }
+            }
+        })
+        .await
+    }
+
+    /// Serves until `shutdown` resolves, then drains listeners.
+    ///
+    /// Runs on the caller's tokio runtime; no signal handlers are installed.
+    pub async fn run_until<F>(self, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        self.serve(async {
+            shutdown.await;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Binds every listener, then serves in a background task on the current tokio
+    /// runtime. The returned handle reports bound addresses and stops the proxy.
+    pub async fn start(self) -> Result<ProxyHandle> {
+        let mut bound = bind_listeners(
+            self.sockets,
+            self.redirects,
+            self.streams,
+            self.udps,
+            self.http3,
+            self.bind,
+        )
+        .await?;
+        if let Some(config) = &self.control.config().cluster {
+            bound.gossip = Some(cluster::bind(config).await?);
+        }
+        let local_addrs = bound_addrs(&bound)?;
+        let admin = bind_admin(self.admin, self.bind).await?;
+        let hardening = hardening::apply(&self.control.config())?;
+        let startup = self
+            .control
+            .report_startup(&local_addrs, self.degraded, hardening);
+        let admin_addr = admin.as_ref().and_then(|(_, listeners)| {
+            listeners.iter().find_map(|listener| match listener {
+                AdminListener::Tcp(tcp) => tcp.local_addr().ok(),
+                #[cfg(unix)]
+                AdminListener::Unix(..) => None,
+            })
+        });
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+        let tap = self.state.tap.clone();
+        let stats = self.state.stats.clone();
+        let control = self.control.clone();
+        let task = tokio::spawn(serve_bound(bound, admin, self.control, async move {
+            shutdown_rx.wait_for(|stop| *stop).await.ok();
+            Ok(())
+        }));
+        Ok(ProxyHandle {
+            shutdown_tx,
+            task,
+            local_addrs,
+            admin_addr,
+            tap,
+            stats,
+            startup,
+            control,
+        })
+    }
+
+    async fn serve<F>(self, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = Result<()>>,
+    {
+        let mut bound = bind_listeners(
+            self.sockets,
+            self.redirects,
+            self.streams,
+            self.udps,
+            self.http3,
+            self.bind,
+        )
+        .await?;
+        if let Some(config) = &self.control.config().cluster {
+            bound.gossip = Some(cluster::bind(config).await?);
+        }
+        let admin = bind_admin(self.admin, self.bind).await?;
+        let hardening = hardening::apply(&self.control.config())?;
+        self.control
+            .report_startup(&bound_addrs(&bound)?, self.degraded, hardening);
+        serve_bound(bound, admin, self.control, shutdown).await
+    }
+}
+
+/// Binds the admin API; unlike listeners, failure here always aborts startup.
+async fn bind_admin(
+    admin: Option<AdminRuntime>,
+    options: BindOptions,
+) -> Result<Option<(Arc<AdminState>, Vec<AdminListener>)>> {
+    let Some(admin) = admin else {
+        return Ok(None);
+    };
+    let mut listeners = Vec::new();
+    if let Some(addr) = admin.addr {
+        let tcp = bind_with_retry(addr, options)
+            .await
+            .with_context(|| format!("failed to bind admin API on {addr}"))?;
+        tracing::info!(addr = %tcp.local_addr()?, "admin API ready");
+        listeners.push(AdminListener::Tcp(tcp));
+    }
+    if let Some(path) = admin.socket {
+        listeners.push(AdminListener::bind_unix(&path)?);
+        tracing::info!(socket = path, "admin socket ready");
+    }
+    Ok(Some((admin.state, listeners)))
+}
+
+/// Listeners bound by [`bind_listeners`].
+struct Bound {
+    sockets: Vec<(SocketRuntime, TcpListener)>,
+    redirects: Vec<(RedirectListener, TcpListener)>,
+    streams: Vec<(StreamListener, TcpListener)>,
+    udps: Vec<(UdpListener, UdpSocket)>,
+    http3: Vec<(Arc<Http3Listener>, quinn::Endpoint)>,
+    gossip: Option<UdpSocket>,
+}
+
+/// Binds every listener up front so configuration mistakes surface before any
+/// traffic is accepted, reporting all failures at once.
+async fn bind_listeners(
+    sockets: Vec<SocketRuntime>,
+    redirects: Vec<RedirectListener>,
+    streams: Vec<StreamListener>,
+    udps: Vec<UdpListener>,
+    http3: Vec<Http3Listener>,
+    options: BindOptions,
+) -> Result<Bound> {
+    let total = sockets
+        .iter()
+        .map(|socket| socket.listeners.len())
+        .sum::<usize>()
+        + redirects.len()
+        + streams.len()
+        + udps.len()
+        + http3.len();
+    let mut bound = Bound {
+        sockets: Vec::with_capacity(sockets.len()),
+        redirects: Vec::with_capacity(redirects.len()),
+        streams: Vec::with_capacity(streams.len()),
+        udps: Vec::with_capacity(udps.len()),
+        http3: Vec::with_capacity(http3.len()),
+        gossip: None,
+    };
+    let mut failures = Vec::new();
+    for socket in sockets {
+        match bind_with_retry(socket.addr, options).await {
+            Ok(tcp) => {
+                let addr = tcp.local_addr()?;
+                for listener in &socket.listeners {
+                    tracing::debug!(listener = listener.name, %addr, "listener bound");
+                }
+                bound.sockets.push((socket, tcp));
+            }
+            Err(err) => {
+                for listener in &socket.listeners {
+                    failures.push(format!("`{}` ({}): {err}", listener.name, socket.addr));
+                }
+            }
+        }
+    }
+    for redirect in redirects {
+        match bind_with_retry(redirect.addr, options).await {
+            Ok(tcp) => {
+                tracing::debug!(listener = redirect.name, addr = %tcp.local_addr()?, "listener bound");
+                bound.redirects.push((redirect, tcp));
+            }
+            Err(err) => failures.push(format!("`{}` ({}): {err}", redirect.name, redirect.addr)),
+        }
+    }
+    for stream in streams {
+        match bind_with_retry(stream.addr, options).await {
+            Ok(tcp) => {
+                tracing::debug!(listener = stream.name, addr = %tcp.local_addr()?, "listener bound");
+                bound.streams.push((stream, tcp));
+            }
+            Err(err) => failures.push(format!("`{}` ({}): {err}", stream.name, stream.addr)),
+        }
+    }
+    // UDP sockets have no TIME_WAIT to outlast, so these are not retried.
+    for udp in udps {
+        match udp.bind().await {
+            Ok(socket) => {
+                tracing::debug!(listener = udp.name, addr = %socket.local_addr()?, "listener bound");
+                bound.udps.push((udp, socket));
+            }
+            Err(err) => failures.push(format!("`{}` ({}): {err}", udp.name, udp.addr)),
+        }
+    }
+    for listener in http3 {
+        match listener.bind() {
+            Ok(endpoint) => {
+                tracing::debug!(listener = listener.name, addr = %endpoint.local_addr()?, "listener bound");
+                bound.http3.push((Arc::new(listener), endpoint));
+            }
+            Err(err) => failures.push(format!("`{}` ({}): {err}", listener.name, listener.addr)),
+        }
+    }
+
+    if failures.is_empty() {
+        return Ok(bound);
+    }
+    let summary = format!(
+        "failed to bind {} of {total} listeners:\n  - {}",
+        failures.len(),
+        failures.join("\n  - ")
+    );
+    let any_bound = !bound.sockets.is_empty()
+        || !bound.redirects.is_empty()
+        || !bound.streams.is_empty()
+        || !bound.udps.is_empty()
+        || !bound.http3.is_empty();
+    match options.policy {
+        BindPolicy::BestEffort if any_bound => {
+            tracing::warn!("{summary}; continuing with the remaining listeners");
+            Ok(bound)
+        }
+        _ => bail!(summary),
+    }
+}
+
+/// Each listener's name with the address its socket is bound to.
+fn bound_addrs(bound: &Bound) -> Result<Vec<(String, SocketAddr)>> {
+    let mut addrs = Vec::new();
+    for (socket, tcp) in &bound.sockets {
+        let addr = tcp.local_addr()?;
+        addrs.extend(
+            socket
+                .listeners
+                .iter()
+                .map(|listener| (listener.name.clone(), addr)),
+        );
+    }
+    for (redirect, tcp) in &bound.redirects {
+        addrs.push((redirect.name.clone(), tcp.local_addr()?));
+    }
+    for (stream, tcp) in &bound.streams {
+        addrs.push((stream.name.clone(), tcp.local_addr()?));
+    }
+    for (udp, socket) in &bound.udps {
+        addrs.push((udp.name.clone(), socket.local_addr()?));
+    }
+    for (listener, endpoint) in &bound.http3 {
+        addrs.push((listener.name.clone(), endpoint.local_addr()?));
+    }
+    Ok(addrs)
+}
+
+async fn bind_with_retry(addr: SocketAddr, options: BindOptions) -> std::io::Result<TcpListener> {
+    let mut backoff = options.initial_backoff;
+    let mut attempt = 0;
+    loop {
+        match TcpListener::bind(addr).await {
+            Err(err) if err.kind() == ErrorKind::AddrInUse && attempt < options.retries => {
+                attempt += 1;
+                tracing::warn!(%addr, attempt, error = %err, "bind failed; retrying in {backoff:?}");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn serve_bound<F>(
+    bound: Bound,
+    admin: Option<(Arc<AdminState>, Vec<AdminListener>)>,
+    control: Arc<ProxyControl>,
+    shutdown: F,
+) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut join_set = JoinSet::new();
+    let alt_svc = http3::alt_svc(
+        bound
+            .http3
+            .iter()
+            .filter_map(|(_, endpoint)| endpoint.local_addr().ok()),
+    );
+    if let Some(alt_svc) = alt_svc {
+        control.state.alt_svc.set(alt_svc).ok();
+    }
+    if let Some(config) = control.config().acme {
+        let listeners = bound
+            .sockets
+            .iter()
+            .flat_map(|(socket, _)| {
+                socket
+                    .listeners
+                    .iter()
+                    .filter_map(|listener| listener.tls.clone())
+            })
+            .collect::<Vec<_>>();
+        let http3 = bound.http3.clone();
+        let rx = shutdown_rx.clone();
+        let leader = control
+            .state
+            .gossip
+            .as_ref()
+            .and_then(|gossip| gossip.leader());
+        join_set.spawn(async move {
+            let renewed = move |certificate: &AcmeCertificate| {
+                reload_certificate(&listeners, &http3, certificate)
+            };
+            acme::renew_loop(config, renewed, leader, rx).await;
+            Ok(())
+        });
+    }
+    let watched = bound
+        .sockets
+        .iter()
+        .flat_map(|(socket, _)| socket.listeners.iter())
+        .filter_map(|listener| listener.tls.clone()?.watched())
+        .chain(
+            bound
+                .http3
+                .iter()
+                .filter_map(|(listener, endpoint)| listener.watched(endpoint)),
+        )
+        .collect::<Vec<_>>();
+    if !watched.is_empty() {
+        let watch =
+            tls::watch_certificates(watched, control.certificates.clone(), shutdown_rx.clone());
+        join_set.spawn(async move {
+            watch.await;
+            Ok(())
+        });
+    }
+    let config = control.config();
+    for sink in config.events.iter().cloned() {
+        let deliver = control.state.events.deliver(sink, shutdown_rx.clone());
+        join_set.spawn(async move {
+            deliver.await;
+            Ok(())
+        });
+    }
+    for route in &config.routes {
+        control.state.events.publish(
+            Event::new(EventKind::RouteAdded)
+                .route(&route.name)
+                .detail("startup"),
+        );
+    }
+    if let (Some(gossip), Some(socket)) = (control.state.gossip.clone(), bound.gossip) {
+        if gossip.election().is_some() {
+            let election = election::run(gossip.clone(), shutdown_rx.clone());
+            join_set.spawn(async move {
+                election.await;
+                Ok(())
+            });
+        }
+        let rx = shutdown_rx.clone();
+        join_set.spawn(async move {
+            gossip.run(socket, rx).await;
+            Ok(())
+        });
+    }
+    for (socket, tcp) in bound.sockets {
+        let rx = shutdown_rx.clone();
+        let state = control.state.clone();
+        join_set.spawn(async move { serve_listener(socket, tcp, state, rx).await });
+    }
+    for (redirect, tcp) in bound.redirects {
+        join_set.spawn(redirect.serve(tcp, shutdown_rx.clone()));
+    }
+    for (stream, tcp) in bound.streams {
+        join_set.spawn(stream.serve(tcp, control.state.clone(), shutdown_rx.clone()));
+    }
+    for (udp, socket) in bound.udps {
+        join_set.spawn(udp.serve(socket, control.state.clone(), shutdown_rx.clone()));
+    }
+    for (listener, endpoint) in bound.http3 {
+        join_set.spawn(listener.serve(endpoint, control.state.clone(), shutdown_rx.clone()));
+    }
+    if let Some((admin, listeners)) = admin {
+        for listener in listeners {
+            join_set.spawn(serve_admin(listener, admin.clone(), shutdown_rx.clone()));
+        }
+    }
+
+    let result = tokio::select! {
+        result = shutdown => result,
+        _ = control.drain.notified() => Ok(()),
+    };
+    tracing::info!("shutdown signal received; draining listeners");
+    control.state.drain.start();
+    shutdown_tx.send(true).ok();
+
+    while let Some(joined) = join_set.join_next().await {
+        match joined {
+            Ok(Err(err)) => tracing::error!(error = %err, "listener failed"),
+            Err(err) => tracing::error!(error = %err, "listener task aborted"),
+            Ok(Ok(())) => {}
+        }
+    }
+    control.state.drain.streams_closed().await;
+
+    result
+}
+
+/// Handle to a proxy started with [`Proxy::start`].
+pub struct ProxyHandle {
+    shutdown_tx: watch::Sender<bool>,
+    task: JoinHandle<Result<()>>,
+    local_addrs: Vec<(String, SocketAddr)>,
+    admin_addr: Option<SocketAddr>,
+    tap: Tap,
+    stats: RuntimeStats,
+    startup: StartupReport,
+    control: Arc<ProxyControl>,
+}
+
+impl ProxyHandle {
+    /// Bound address of the named listener (useful with port `0`).
+    pub fn local_addr(&self, listener: &str) -> Option<SocketAddr> {
+        self.local_addrs
+            .iter()
+            .find(|(name, _)| name == listener)
+            .map(|(_, addr)| *addr)
+    }
+
+    pub fn local_addrs(&self) -> &[(String, SocketAddr)] {
+        &self.local_addrs
+    }
+
+    /// Listeners, certificates, plugins, and degraded subsystems as of startup.
+    pub fn startup_report(&self) -> &StartupReport {
+        &self.startup
+    }
+
+    /// Bound address of the admin API, when `admin` is configured.
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+
+    pub fn tap(&self) -> &Tap {
+        &self.tap
+    }
+
+    pub fn stats(&self) -> &RuntimeStats {
+        &self.stats
+    }
+
+    /// Reloads the config through the [`ProxyBuilder::config_loader`], as
+    /// `POST /reload` does; fails when none was set.
+    pub fn reload(&self) -> Result<()> {
+        if !self.control.can_reload() {
+            bail!("no config loader is set; reloads are unavailable");
+        }
+        self.control.reload().map(drop)
+    }
+
+    /// Loads every listener's certificates and keys again, as SIGHUP does
+    /// for [`Proxy::run`]. Changed files are picked up on their own within a
+    /// few seconds; this is for when waiting is not wanted.
+    pub fn reload_certificates(&self) {
+        self.control.certificates.notify_one();
+    }
+
+    /// Signals shutdown and waits for all listeners to drain.
+    pub async fn shutdown(self) -> Result<()> {
+        self.shutdown_tx.send(true).ok();
+        self.task.await.context("proxy task panicked")?
+    }
+}
+
+/// Programmatic construction of a [`Proxy`] without TOML files.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use jester_core::{
+///     config::{Listener, Route, Upstream},
+///     proxy::Proxy,
+/// };
+///
+/// let proxy = Proxy::builder()
+///     .listener(Listener::builder("edge", "127.0.0.1:8443").tls("certs/dev.crt", "certs/dev.key"))
+///     .route(Route::builder("app", Upstream::single("http://127.0.0.1:8080")).host("example.com"))
+///     .build()?;
+///
+/// let handle = proxy.start().await?;
+/// // ... later
+/// handle.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct ProxyBuilder {
+    config: Config,
+    registry: FilterRegistry,
+    layers: Vec<DynLayer>,
+    bind: BindOptions,
+    log_control: Option<Arc<dyn LogControl>>,
+    loader: Option<ConfigLoader>,
+    degraded: Vec<Degraded>,
+}
+
+impl ProxyBuilder {
+    /// Replaces the accumulated configuration, e.g. with one loaded from disk.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn listener(mut self, listener: impl Into<Listener>) -> Self {
+        self.config.listeners.push(listener.into());
+        self
+    }
+
+    pub fn route(mut self, route: impl Into<Route>) -> Self {
+        self.config.routes.push(route.into());
+        self
+    }
+
+    /// Serves the admin API on `listen` (plain HTTP; keep it on loopback).
+    pub fn admin(mut self, listen: impl Into<String>) -> Self {
+        self.config.admin.get_or_insert_with(Admin::default).listen = Some(listen.into());
+        self
+    }
+
+    /// Serves the admin API on a unix socket at `path`, for `jester ctl`.
+    pub fn admin_socket(mut self, path: impl Into<String>) -> Self {
+        self.config.admin.get_or_insert_with(Admin::default).socket = Some(path.into());
+        self
+    }
+
+    /// Where `POST /reload` gets its new configuration; without one, reloads
+    /// are refused.
+    pub fn config_loader(mut self, loader: ConfigLoader) -> Self {
+        self.loader = Some(loader);
+        self
+    }
+
+    /// Appends a filter to the global (pre-routing) chain.
+    pub fn filter(mut self, filter: impl Into<Filter>) -> Self {
+        self.config.filters.push(filter.into());
+        self
+    }
+
+    /// Registers an in-process plugin that `type = "inproc"` filters can reference
+    /// by its name.
+    pub fn plugin(mut self, plugin: Arc<dyn JesterPlugin>) -> Self {
+        self.registry.register(plugin);
+        self
+    }
+
+    /// Wraps the whole request pipeline, outside the global filter chain.
+    ///
+    /// Layers added first end up outermost, matching `tower::ServiceBuilder`.
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<JesterService, Service = JesterService> + Send + Sync + 'static,
+    {
+        self.layers.push(Box::new(layer));
+        self
     }
 
-    pub async fn run(self) -> Result<()> {
-        let (shutdown_tx, shutdown_rx) = watch::channel(false);
-        let mut join_set = JoinSet::new();
-        for listener in self.listeners {
-            let rx = shutdown_rx.clone();
-            let state = self.state.clone();
-            join_set.spawn(async move { serve_listener(listener, state, rx).await });
-        }
+    pub fn bind_options(mut self, options: BindOptions) -> Self {
+        self.bind = options;
+        self
+    }
 
-        tracing::info!("proxy listeners started; awaiting shutdown signal (Ctrl+C)");
-        tokio::signal::ctrl_c()
-            .await
-            .context("failed to install ctrl-c handler")?;
-        tracing::info!("shutdown signal received; draining listeners");
-        shutdown_tx.send(true).ok();
+    /// Lets the admin API read and change the log filter at runtime.
+    pub fn log_control(mut self, control: Arc<dyn LogControl>) -> Self {
+        self.log_control = Some(control);
+        self
+    }
 
-        while let Some(result) = join_set.join_next().await {
-            if let Err(err) = result {
-                tracing::error!(error = %err, "listener task aborted");
-            }
-        }
+    /// Reports a subsystem the embedder runs in a reduced mode, e.g. a config
+    /// served from a fallback copy, in the startup report.
+    pub fn degraded(mut self, subsystem: impl Into<String>, reason: impl Into<String>) -> Self {
+        self.degraded.push(Degraded::new(subsystem, reason));
+        self
+    }
 
-        Ok(())
+    /// Replaces the filter registry, e.g. to validate a config against the
+    /// plugins of a running proxy.
+    pub(crate) fn registry(mut self, registry: FilterRegistry) -> Self {
+        self.registry = registry;
+        self
     }
-}
 
-fn build_client() -> HttpClient {
-    let mut connector = HttpConnector::new();
-    connector.enforce_http(false);
-    Client::builder(TokioExecutor::new()).build(connector)
+    /// Validates the configuration and prepares listeners without binding them.
+    pub fn build(self) -> Result<Proxy> {
+        let Self {
+            config,
+            registry,
+            layers,
+            bind,
+            log_control,
+            loader,
+            degraded,
+        } = self;
+        config.validate()?;
+        let stats = RuntimeStats::default();
+        let factory = PipelineFactory {
+            registry,
+            layers,
+            clients: UpstreamClients::new(stats.clone()),
+            stats: stats.clone(),
+        };
+        let pipeline = factory.build(&config)?;
+        let (http3, listeners): (Vec<_>, Vec<_>) = config
+            .resolved_listeners()?
+            .into_iter()
+            .partition(|listener| listener.protocol == ListenerProtocol::H3);
+        let listeners = listeners
+            .into_iter()
+            .map(ListenerRuntime::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        let sockets = SocketRuntime::group(listeners);
+        let http3 = http3
+            .into_iter()
+            .map(Http3Listener::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        let redirects = config
+            .listeners
+            .iter()
+            .filter(|listener| listener.kind == ListenerKind::HttpsRedirect)
+            .map(RedirectListener::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        let streams = config
+            .listeners
+            .iter()
+            .filter(|listener| listener.kind == ListenerKind::Stream)
+            .map(StreamListener::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        let udps = config
+            .listeners
+            .iter()
+            .filter(|listener| listener.kind == ListenerKind::Udp)
+            .map(UdpListener::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        let state = Arc::new(AppState {
+            pipeline: RwLock::new(Arc::new(pipeline)),
+            tap: Tap::default(),
+            stats,
+            events: Events::default(),
+            gossip: config
+                .cluster
+                .as_ref()
+                .map(|cluster| Arc::new(Gossip::new(cluster))),
+            profiler: Profiler::default(),
+            alt_svc: OnceLock::new(),
+            drain: Drain::new(&config.shutdown),
+        });
+        let admin = config.admin.clone();
+        let control = Arc::new(ProxyControl {
+            state: state.clone(),
+            factory,
+            config: Mutex::new(config),
+            loader,
+            drain: Notify::new(),
+            certificates: Arc::default(),
+            startup: OnceLock::new(),
+        });
+        let admin = admin
+            .map(|admin| {
+                Ok::<_, anyhow::Error>(AdminRuntime {
+                    addr: admin.parse_listen_addr()?,
+                    socket: admin.socket,
+                    state: Arc::new(AdminState {
+                        control: control.clone(),
+                        log_control: log_control.map(LogHandle::new),
+                    }),
+                })
+            })
+            .transpose()?;
+        Ok(Proxy {
+            state,
+            control,
+            sockets,
+            redirects,
+            streams,
+            udps,
+            http3,
+            admin,
+            degraded,
+            bind,
+        })
+    }
 }
 
 async fn serve_listener(
-    listener: ListenerRuntime,
+    socket: SocketRuntime,
+    tcp: TcpListener,
     state: Arc<AppState>,
     mut shutdown: watch::Receiver<bool>,
 ) -> Result<()> {
-    let tcp = TcpListener::bind(listener.addr)
-        .await
-        .with_context(|| format!("failed to bind listener `{}`", listener.name))?;
-    tracing::info!(
-        listener = listener.name,
-        addr = %listener.addr,
-        "listener ready"
-    );
-
+    let socket = Arc::new(socket);
     loop {
         tokio::select! {
             biased;
             _ = shutdown.changed() => {
-                tracing::info!(listener = listener.name, "listener shutting down");
+                tracing::info!(listener = socket.names(), "listener shutting down");
                 break;
             }
             accept = tcp.accept() => {
-                let (stream, peer_addr) = accept?;
-                let acceptor = listener.acceptor.clone();
+                let (stream, peer_addr) = match accept {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        // Typically fd exhaustion; keep the listener alive and back off.
+                        tracing::warn!(listener = socket.names(), error = %err, "accept failed");
+                        tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let socket = socket.clone();
                 let state = state.clone();
-                let listener_name = listener.name.clone();
                 tokio::spawn(async move {
-                    if let Err(err) = handle_connection(acceptor, state, stream, peer_addr, listener_name).await {
+                    if let Err(err) = accept_connection(socket, state, stream, peer_addr).await {
                         tracing::warn!(error = %err, "connection closed with error");
                     }
                 });
@@ -125,101 +1431,776 @@ async fn serve_listener(
     Ok(())
 }
 
-async fn handle_connection(
-    acceptor: TlsAcceptor,
+/// Reads what precedes the TLS handshake (a PROXY protocol header, and on
+/// shared sockets the ClientHello) and hands the connection to its listener.
+/// Until SNI picks one, a shared socket's connections count against its first
+/// listener.
+async fn accept_connection(
+    socket: Arc<SocketRuntime>,
     state: Arc<AppState>,
-    stream: tokio::net::TcpStream,
+    mut stream: tokio::net::TcpStream,
     peer_addr: SocketAddr,
-    listener_name: String,
 ) -> Result<()> {
-    let tls = acceptor.accept(stream).await?;
-    let service = service_fn(move |req| {
+    let first = &socket.listeners[0];
+    let mut lifecycle =
+        Lifecycle::accepted(&state.stats, &first.name, peer_addr, first.log_connections);
+    let local_addr = stream.local_addr().unwrap_or(socket.addr);
+    let mut client_addr = peer_addr;
+    // Listeners sharing a socket share their client_ip policy.
+    if first.client_ip.expects_proxy_header(peer_addr.ip()) {
+        let header = tokio::time::timeout(
+            PROXY_HEADER_TIMEOUT,
+            client_ip::read_proxy_header(&mut stream),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("timed out waiting for the PROXY protocol header")));
+        match header {
+            Ok(source) => client_addr = source.unwrap_or(peer_addr),
+            Err(err) => {
+                lifecycle.close("proxy_header_failed");
+                return Err(err);
+            }
+        }
+    }
+    let (listener, stream) = if socket.selects_by_sni() {
+        let hello = tokio::time::timeout(CLIENT_HELLO_TIMEOUT, tls::read_client_hello(&mut stream))
+            .await
+            .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()));
+        let hello = match hello {
+            Ok(hello) => hello,
+            Err(err) => {
+                lifecycle.close("tls_failed");
+                return Err(err.into());
+            }
+        };
+        let Some(listener) = socket.select(hello.server_name()) else {
+            lifecycle.close("unknown_server_name");
+            bail!(
+                "no listener on {} serves server name {:?}",
+                socket.addr,
+                hello.server_name().unwrap_or_default()
+            );
+        };
+        if !std::ptr::eq(listener, first) {
+            lifecycle.reassign(&state.stats, &listener.name, listener.log_connections);
+        }
+        (listener, hello.replay(stream))
+    } else {
+        (first, Replayed::from(stream))
+    };
+    let connection = ConnectionInfo {
+        listener: listener.name.clone(),
+        scheme: match listener.tls {
+            Some(_) => "https",
+            None => "http",
+        },
+        local_addr,
+        peer_addr,
+        client_addr,
+        trust_forwarded_headers: listener.trust_forwarded_headers,
+        tls_handshake: Duration::ZERO,
+        missing_host: listener.missing_host.clone(),
+        absolute_form: listener.absolute_form,
+        health_check_paths: listener.health_check_paths.clone(),
+        client_certificate: None,
+    };
+    handle_connection(listener, state, stream, connection, lifecycle).await
+}
+
+async fn handle_connection(
+    listener: &ListenerRuntime,
+    state: Arc<AppState>,
+    stream: Replayed<tokio::net::TcpStream>,
+    mut connection: ConnectionInfo,
+    mut lifecycle: Lifecycle,
+) -> Result<()> {
+    let counters = lifecycle.counters();
+    let stream = CountingStream::new(stream, counters);
+    let Some(listener_tls) = &listener.tls else {
+        // Plaintext listeners serve HTTP/1.1 only.
+        return serve_connection(listener, state, stream, connection, lifecycle, false, None).await;
+    };
+    let handshake = Instant::now();
+    let (tls, tls_handshake) = match listener_tls.acceptor().accept(stream).await {
+        Ok(tls) => tls,
+        Err(err) if tls::is_alpn_mismatch(&err) => {
+            lifecycle.alpn_rejected("no_common_protocol");
+            return Err(err.into());
+        }
+        Err(err) => {
+            lifecycle.close("tls_failed");
+            return Err(err.into());
+        }
+    };
+    connection.tls_handshake = handshake.elapsed();
+    connection.client_certificate = tls.client_certificate();
+    lifecycle.tls_established(connection.tls_handshake);
+    let protocol = match tls.alpn_protocol() {
+        Some(b"h2") => "h2",
+        Some(b"http/1.1") => "http/1.1",
+        Some(_) => "other",
+        None => "none",
+    };
+    // Clients without ALPN speak HTTP/1.1, which the listener may not serve.
+    if protocol == "none" && !listener.http1 {
+        lifecycle.alpn_rejected("no_alpn");
+        bail!(
+            "client {} offered no ALPN protocol, and listener `{}` does not serve HTTP/1.1",
+            connection.peer_addr,
+            connection.listener
+        );
+    }
+    lifecycle.negotiated(protocol);
+    let http2 = protocol == "h2";
+    serve_connection(
+        listener,
+        state,
+        tls,
+        connection,
+        lifecycle,
+        http2,
+        tls_handshake,
+    )
+    .await
+}
+
+/// Serves HTTP/1.1 (with upgrades) or HTTP/2 on an established connection.
+/// `handshake` is set while TLS early data may still be arriving.
+async fn serve_connection<IO>(
+    listener: &ListenerRuntime,
+    state: Arc<AppState>,
+    io: IO,
+    connection: ConnectionInfo,
+    lifecycle: Lifecycle,
+    http2: bool,
+    handshake: Option<Arc<Handshake>>,
+) -> Result<()>
+where
+    IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let limits = listener.limits;
+    let client_ip = listener.client_ip.clone();
+    let counters = lifecycle.counters();
+    let ConnectionInfo {
+        listener: listener_name,
+        peer_addr,
+        ..
+    } = connection.clone();
+    // Alt-Svc points at HTTP/3 for the same origin, which plain HTTP is not.
+    let advertise_h3 = listener.tls.is_some();
+    // Set once an HTTP/2 connection has served its last request.
+    let last_request = Arc::new(Notify::new());
+    let finished = last_request.clone();
+    let service = service_fn(move |mut req: Request<Incoming>| {
         let state = state.clone();
+        let connection = connection.clone();
+        let client_ip = client_ip.clone();
+        let last_request = last_request.clone();
+        let alt_svc = state.alt_svc.get().cloned().filter(|_| advertise_h3);
+        let served = counters.request_served();
+        if handshake
+            .as_ref()
+            .is_some_and(|handshake| !handshake.is_complete())
+        {
+            req.extensions_mut().insert(EarlyData);
+        }
         async move {
-            match handle_request(state, req).await {
-                Ok(resp) => Ok::<_, hyper::Error>(resp),
+            let mut resp = match handle_request(state, connection, &client_ip, req).await {
+                Ok(resp) => resp,
                 Err(err) => {
                     tracing::error!(error = %err, "request handling failed");
-                    Ok(internal_error())
+                    internal_error()
+                }
+            };
+            if let Some(alt_svc) = alt_svc {
+                resp.headers_mut().entry(header::ALT_SVC).or_insert(alt_svc);
+            }
+            if http2 {
+                strip_connection_headers(resp.headers_mut());
+                if limits.h2_last_request(served) {
+                    last_request.notify_one();
                 }
+            } else if limits.max_requests.is_some_and(|max| served >= max)
+                && resp.status() != StatusCode::SWITCHING_PROTOCOLS
+            {
+                resp.headers_mut().insert(
+                    header::CONNECTION,
+                    header::HeaderValue::from_static("close"),
+                );
             }
+            Ok::<_, hyper::Error>(resp)
         }
     });
-    http1::Builder::new()
-        .preserve_header_case(true)
-        .title_case_headers(true)
-        .serve_connection(TokioIo::new(tls), service)
-        .with_upgrades()
-        .await
-        .with_context(|| {
-            format!("connection handling failed for listener `{listener_name}` from {peer_addr}")
-        })
+    let served = if http2 {
+        let mut builder = http2::Builder::new(TokioExecutor::new());
+        builder
+            .timer(TokioTimer::new())
+            .keep_alive_interval(limits.h2_keepalive_interval);
+        if let Some(streams) = limits.h2_max_concurrent_streams {
+            builder.max_concurrent_streams(streams);
+        }
+        if let Some(timeout) = limits.h2_keepalive_timeout {
+            builder.keep_alive_timeout(timeout);
+        }
+        let conn = builder.serve_connection(TokioIo::new(io), service);
+        tokio::pin!(conn);
+        tokio::select! {
+            served = conn.as_mut() => served,
+            _ = finished.notified() => {
+                // GOAWAY: streams already open finish, new ones go elsewhere.
+                conn.as_mut().graceful_shutdown();
+                conn.await
+            }
+        }
+    } else {
+        let mut builder = http1::Builder::new();
+        builder.preserve_header_case(true).title_case_headers(true);
+        match limits.keep_alive_timeout {
+            Some(Duration::ZERO) => {
+                builder.keep_alive(false);
+            }
+            Some(timeout) => {
+                builder
+                    .timer(TokioTimer::new())
+                    .header_read_timeout(timeout);
+            }
+            None => {}
+        }
+        builder
+            .serve_connection(TokioIo::new(io), service)
+            .with_upgrades()
+            .await
+    };
+    lifecycle.close(match &served {
+        Ok(()) => "closed",
+        Err(err) => close_reason(err),
+    });
+    served.with_context(|| {
+        format!("connection handling failed for listener `{listener_name}` from {peer_addr}")
+    })
 }
 
-async fn handle_request(
+pub(crate) async fn handle_request<B>(
     state: Arc<AppState>,
-    req: Request<Incoming>,
-) -> Result<Response<ProxyBody>> {
+    connection: ConnectionInfo,
+    client_ip: &ClientIpResolver,
+    mut req: Request<B>,
+) -> Result<Response<ProxyBody>>
+where
+    B: hyper::body::Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
     let start = Instant::now();
+    let ready = !state.drain.is_draining();
+    if let Some(response) = health::answer(
+        &connection.health_check_paths,
+        &req,
+        &connection.listener,
+        ready,
+    ) {
+        return Ok(response);
+    }
+    let client = client_ip.resolve(&connection, req.headers());
+    if let Err(reason) = normalize_target(&connection, &mut req) {
+        return Ok(response_with(StatusCode::BAD_REQUEST, reason));
+    }
+    set_client_certificate_headers(req.headers_mut(), connection.client_certificate.as_deref());
     let host = extract_host(&req);
+    let trace_id = trace_id(req.headers());
+    let pipeline = state.pipeline();
+    let debug_request = pipeline
+        .debug
+        .as_ref()
+        .is_some_and(|trigger| trigger.take(req.headers_mut()));
+    let upstream_override = match pipeline
+        .upstream_override
+        .as_ref()
+        .map(|trigger| trigger.take(req.headers_mut(), client))
+        .transpose()
+    {
+        Ok(target) => target.flatten(),
+        Err(reason) => return Ok(response_with(StatusCode::BAD_REQUEST, reason)),
+    };
     let span = tracing::info_span!(
         "request",
+        debug = debug_request,
         method = %req.method(),
         path = %req.uri().path(),
+        %client,
         host = host.as_deref().unwrap_or_default(),
+        trace_id = trace_id.as_deref().unwrap_or_default(),
         route = tracing::field::Empty,
+        labels = tracing::field::Empty,
         status = tracing::field::Empty,
+        timed_out = tracing::field::Empty,
+        error = tracing::field::Empty,
         duration_ms = tracing::field::Empty,
     );
-    let _enter = span.enter();
 
-    let host_ref = host.as_deref().unwrap_or("");
-    let route = match state.router.select(&req, host_ref).cloned() {
-        Some(route) => route,
-        None => {
-            span.record("status", StatusCode::NOT_FOUND.as_u16());
-            metrics::counter!("jester_requests_total", "outcome" => "miss").increment(1);
-            return Ok(not_found());
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let grpc = grpc::is_grpc(req.headers());
+    let context = RequestContext::received_at(start);
+    context.record_timings(|timings| timings.tls_handshake = Some(connection.tls_handshake));
+    let recording = pipeline
+        .capture
+        .filter(|_| state.tap.capturing())
+        .map(|limits| Recording::start(limits, req.headers()));
+    let mut req = req.map(|body| {
+        let body = body.map_err(Into::into).boxed();
+        match &recording {
+            Some(recording) => recording.request_body(body),
+            None => body,
+        }
+    });
+    req.extensions_mut().insert(context.clone());
+    if let Some(target) = upstream_override {
+        req.extensions_mut().insert(target);
+    }
+    req.extensions_mut().insert(state.stats.clone());
+    req.extensions_mut().insert(state.drain.clone());
+    req.extensions_mut().insert(state.events.clone());
+    if let Some(gossip) = &state.gossip {
+        req.extensions_mut().insert(gossip.clone());
+    }
+    req.extensions_mut().insert(ClientIp(client));
+    if let Some(sampled) = state.profiler.sample() {
+        req.extensions_mut().insert(sampled);
+    }
+    let listener = connection.listener.clone();
+    let client_cert = connection
+        .client_certificate
+        .as_ref()
+        .map(|certificate| certificate.subject.clone());
+    req.extensions_mut().insert(connection);
+
+    let response: ResponseFuture = Box::pin(pipeline.service.clone().oneshot(req));
+    let response = match response.instrument(span.clone()).await {
+        Ok(resp) => resp,
+        Err(err) => {
+            let cause = error::cause(&err);
+            let chain: Vec<String> = err.chain().map(ToString::to_string).collect();
+            let err = ProxyError::classify(err);
+            span.record("error", cause);
+            span.in_scope(|| {
+                tracing::error!(
+                    error = %err,
+                    kind = err.kind(),
+                    cause,
+                    chain = ?chain,
+                    "upstream request failed"
+                )
+            });
+            metrics::counter!("jester_requests_total", "outcome" => "error", "kind" => err.kind())
+                .increment(1);
+            let route = context.route().unwrap_or_default();
+            let labels = context.route_labels();
+            if let ProxyError::Timeout { .. } = err {
+                span.record("timed_out", true);
+                metrics::counter!(
+                    "jester_timeouts_total",
+                    stats::route_labels(&route, &labels, [])
+                )
+                .increment(1);
+            }
+            let error_labels = stats::route_labels(
+                &route,
+                &labels,
+                [("kind", err.kind().into()), ("cause", cause.into())],
+            );
+            metrics::counter!("jester_request_errors_total", error_labels).increment(1);
+            err.to_response()
         }
     };
-    span.record("route", &route.name.as_str());
+    let response = if debug_request {
+        debug_response(&context, response)
+            .instrument(span.clone())
+            .await
+    } else {
+        response
+    };
+    let response = if drain::is_event_stream(response.headers()) {
+        drain::event_stream(response, &state.drain)
+    } else {
+        response
+    };
+    let response = match context.route() {
+        Some(route) => stats::meter_response(&route, &context.route_labels(), response),
+        None => response,
+    };
+    let duration = start.elapsed();
+    span.record("status", response.status().as_u16());
+    span.record("duration_ms", duration.as_millis() as i64);
+    if let Some(route) = context.route() {
+        state.stats.observe_latency(
+            &route,
+            &context.route_labels(),
+            duration.as_secs_f64(),
+            trace_id.as_deref(),
+        );
+    }
 
-    metrics::counter!("jester_requests_total", "outcome" => "hit").increment(1);
-    let response = proxy_to_upstream(state.clone(), req, &route).await;
-    let duration = start.elapsed().as_millis() as u64;
+    let mut event = AccessEvent {
+        listener,
+        client,
+        client_cert,
+        method,
+        host,
+        path,
+        route: context.route(),
+        labels: (*context.route_labels()).clone(),
+        trace_id,
+        status: response.status().as_u16(),
+        grpc_status: None,
+        duration,
+        timings: context.timings(),
+        context: context.values(),
+    };
+    let response = match recording {
+        Some(recording) => recording.finish(response, event.clone(), state.tap.clone()),
+        None => response,
+    };
+    if !grpc {
+        log_access(&span, &event, None);
+        state.tap.publish(event);
+        return Ok(response);
+    }
+    let route = event.route.clone().unwrap_or_default();
+    if let Some(code) = grpc::head_status(&response) {
+        event.grpc_status = Some(code);
+        record_grpc(&route, &event.labels, Some(code));
+        log_access(&span, &event, None);
+        state.tap.publish(event);
+        return Ok(response);
+    }
+    // The status comes in the trailers: log and publish the call once it ends.
+    let tap = state.tap.clone();
+    Ok(grpc::on_end(response, move |code, message| {
+        event.grpc_status = code;
+        event.duration = start.elapsed();
+        record_grpc(&route, &event.labels, code);
+        log_access(&span, &event, message.as_deref());
+        tap.publish(event);
+    }))
+}
 
-    match response {
-        Ok(resp) => {
-            span.record("status", resp.status().as_u16());
-            span.record("duration_ms", duration as i64);
-            Ok(resp.map(|body| body.boxed()))
+/// Replaces whatever the client sent in the client certificate headers with
+/// what its verified certificate says, if it presented one.
+fn set_client_certificate_headers(
+    headers: &mut http::HeaderMap,
+    certificate: Option<&ClientCertificate>,
+) {
+    headers.remove(CLIENT_CERT_SUBJECT);
+    headers.remove(CLIENT_CERT_SAN);
+    let Some(certificate) = certificate else {
+        return;
+    };
+    let values = [
+        (CLIENT_CERT_SUBJECT, certificate.subject.clone()),
+        (CLIENT_CERT_SAN, certificate.subject_alt_names.join(", ")),
+    ];
+    for (name, value) in values {
+        // UTF-8 names pass as opaque bytes; control characters do not.
+        match header::HeaderValue::from_bytes(value.as_bytes()) {
+            Ok(value) if !value.is_empty() => {
+                headers.insert(name, value);
+            }
+            _ => {}
         }
-        Err(err) => {
-            span.record("status", StatusCode::BAD_GATEWAY.as_u16());
-            span.record("duration_ms", duration as i64);
-            tracing::error!(error = %err, route = %route.name, "upstream request failed");
-            metrics::counter!("jester_requests_total", "outcome" => "error").increment(1);
-            Ok(bad_gateway())
+    }
+}
+
+/// Logs a finished request on `jester::access`.
+fn log_access(span: &tracing::Span, event: &AccessEvent, grpc_message: Option<&str>) {
+    let timings = &event.timings;
+    let millis = |phase: Option<Duration>| phase.map(|phase| phase.as_secs_f64() * 1000.0);
+    let context = (!event.context.is_empty())
+        .then(|| serde_json::to_string(&event.context).unwrap_or_default());
+    let labels = (!event.labels.is_empty())
+        .then(|| serde_json::to_string(&event.labels).unwrap_or_default());
+    span.in_scope(|| {
+        tracing::info!(
+            target: "jester::access",
+            status = event.status,
+            grpc_status = event.grpc_status,
+            grpc_message,
+            client_cert = event.client_cert.as_deref(),
+            total_ms = event.duration.as_secs_f64() * 1000.0,
+            tls_ms = millis(timings.tls_handshake),
+            routing_ms = millis(timings.routing),
+            filters_ms = millis(timings.filters()),
+            connect_ms = millis(timings.upstream_connect),
+            ttfb_ms = millis(timings.upstream_ttfb),
+            context,
+            labels,
+            "request completed"
+        )
+    });
+}
+
+fn record_grpc(route: &str, labels: &BTreeMap<String, String>, code: Option<u16>) {
+    let labels = stats::route_labels(route, labels, [("grpc_status", grpc::status_label(code))]);
+    metrics::counter!("jester_grpc_responses_total", labels).increment(1);
+}
+
+/// Buffers the body of a debug request's response so its transfer time can be
+/// reported, then attaches the timing breakdown as `Server-Timing`.
+async fn debug_response(context: &RequestContext, response: HttpResponse) -> HttpResponse {
+    let (mut parts, body) = response.into_parts();
+    let reading = Instant::now();
+    let (body, trailers) = match body.collect().await {
+        Ok(body) => {
+            let trailers = body.trailers().cloned();
+            (body.to_bytes(), trailers)
+        }
+        Err(err) => return ProxyError::classify(anyhow!(err)).to_response(),
+    };
+    context.record_timings(|timings| timings.upstream_body = Some(reading.elapsed()));
+    let timings = context.timings();
+    let total = context.elapsed().unwrap_or_default();
+    tracing::info!(?timings, ?total, "debug request timings");
+    if let Ok(value) = header::HeaderValue::from_str(&timings.server_timing(total)) {
+        parts.headers.append("server-timing", value);
+    }
+    // gRPC statuses travel in the trailers.
+    let body = match trailers {
+        Some(trailers) => Full::new(body)
+            .with_trailers(std::future::ready(Some(Ok(trailers))))
+            .map_err(|never| match never {})
+            .boxed(),
+        None => full_body(body),
+    };
+    Response::from_parts(parts, body)
+}
+
+/// Innermost service of the global chain: selects a route and runs its filter chain.
+fn dispatch_service(
+    router: Router,
+    well_known: WellKnownFiles,
+    stats: RuntimeStats,
+) -> JesterService {
+    let router = Arc::new(router);
+    let well_known = Arc::new(well_known);
+    JesterService::new(tower::service_fn(move |req| {
+        dispatch(&router, &well_known, &stats, req)
+    }))
+}
+
+fn dispatch(
+    router: &Router,
+    well_known: &WellKnownFiles,
+    stats: &RuntimeStats,
+    mut req: HttpRequest,
+) -> ResponseFuture {
+    let host = extract_host(&req);
+    if let Some(response) = well_known.respond(&req, host.as_deref().unwrap_or("")) {
+        metrics::counter!("jester_requests_total", "outcome" => "well_known").increment(1);
+        return response;
+    }
+    let routing = Instant::now();
+    let selected = router.select(&req, host.as_deref().unwrap_or(""));
+    let context = req.extensions().get::<RequestContext>();
+    if let Some(context) = context {
+        context.record_timings(|timings| timings.routing = Some(routing.elapsed()));
+    }
+    let route = match selected {
+        Selection::Route(route) => route,
+        Selection::MethodNotAllowed(methods) => {
+            metrics::counter!("jester_requests_total", "outcome" => "method_not_allowed")
+                .increment(1);
+            return Box::pin(async move { Ok(method_not_allowed(&methods)) });
+        }
+        Selection::Miss => {
+            metrics::counter!("jester_requests_total", "outcome" => "miss").increment(1);
+            return Box::pin(async { Ok(not_found()) });
+        }
+    };
+    let span = tracing::Span::current();
+    span.record("route", route.name.as_str());
+    if !route.labels.is_empty() {
+        span.record(
+            "labels",
+            serde_json::to_string(&*route.labels).unwrap_or_default(),
+        );
+    }
+    if let Some(context) = context {
+        context.set_route(&route.name, &route.labels);
+    }
+    metrics::counter!("jester_requests_total", "outcome" => "hit").increment(1);
+
+    if req.extensions().get::<EarlyData>().is_some() {
+        if !(route.early_data && req.method().is_idempotent()) {
+            metrics::counter!("jester_early_data_requests_total", "outcome" => "rejected")
+                .increment(1);
+            return Box::pin(async { Ok(response_with(StatusCode::TOO_EARLY, "too early")) });
         }
+        metrics::counter!("jester_early_data_requests_total", "outcome" => "accepted").increment(1);
+        // RFC 8470: tell the upstream so it can answer 425 itself if needed.
+        req.headers_mut()
+            .insert("early-data", header::HeaderValue::from_static("1"));
+    }
+    let mut upstream = route.upstream.clone();
+    if let Some(balancer) = &route.balancer {
+        let Some(target) = balancer.pick(&req, stats) else {
+            metrics::counter!("jester_requests_total", "outcome" => "no_targets").increment(1);
+            return Box::pin(async {
+                Ok(response_with(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "no upstream targets",
+                ))
+            });
+        };
+        upstream.uri = target;
+    }
+    if let Some(OverrideTarget(target)) = req.extensions_mut().remove::<OverrideTarget>() {
+        let client = ClientIp::of(&req);
+        tracing::warn!(
+            target: "jester::audit",
+            client = ?client,
+            route = route.name,
+            upstream = %target,
+            "upstream overridden by trusted client"
+        );
+        metrics::counter!("jester_upstream_overrides_total", "outcome" => "applied").increment(1);
+        upstream.uri = target;
+    }
+    req.extensions_mut().insert(upstream);
+    req.extensions_mut().insert(route.websocket.clone());
+    req.extensions_mut().insert(route.streaming.clone());
+    if route.suppress_via {
+        req.extensions_mut().insert(SuppressVia);
     }
+    if let Some(retry) = &route.retry {
+        req.extensions_mut().insert(retry.clone());
+    }
+    let inflight = stats.track_route(&route.name);
+    let response: ResponseFuture = Box::pin(route.service.clone().oneshot(req));
+    Box::pin(async move {
+        let response = response.await?;
+        Ok(stats::hold_until_sent(response, inflight))
+    })
+}
+
+/// Innermost service of every route chain: forwards to the selected upstream.
+/// `via` is this proxy's `Via` entry without the protocol version, and `pool`
+/// the settings routes inherit for their connection pools.
+fn upstream_service(
+    clients: UpstreamClients,
+    stats: RuntimeStats,
+    via: Option<Arc<str>>,
+    pool: UpstreamPool,
+) -> JesterService {
+    JesterService::new(tower::service_fn(move |req| {
+        let clients = clients.clone();
+        let stats = stats.clone();
+        let via = via.clone();
+        async move { proxy_to_upstream(&clients, &stats, via.as_deref(), &pool, req).await }
+    }))
 }
 
 async fn proxy_to_upstream(
-    state: Arc<AppState>,
-    mut req: Request<Incoming>,
-    route: &RouteHandle,
-) -> Result<Response<Incoming>> {
-    let upstream_uri = build_upstream_uri(&route.upstream.uri, req.uri())?;
-    rewrite_request(&mut req, &route.upstream.uri, upstream_uri.clone());
-    let fut = state.client.request(req);
-    let response = if let Some(duration) = route.timeout() {
-        timeout(duration, fut)
-            .await
-            .context("request timed out")??
-    } else {
-        fut.await?
+    clients: &UpstreamClients,
+    stats: &RuntimeStats,
+    via: Option<&str>,
+    pool: &UpstreamPool,
+    mut req: HttpRequest,
+) -> Result<HttpResponse> {
+    let upstream = req
+        .extensions()
+        .get::<UpstreamEndpoint>()
+        .cloned()
+        .context("no upstream selected for request")?;
+    let target = target_key(&upstream.uri);
+    let inflight = stats.track_target(&target);
+    let upstream_uri = build_upstream_uri(&upstream.uri, req.uri())?;
+    let via = via.filter(|_| req.extensions().get::<SuppressVia>().is_none());
+    if let Some(via) = via {
+        let version = req.version();
+        append_via(req.headers_mut(), version, via);
+    }
+    let websocket = match websocket::is_upgrade(&req) {
+        true => Some(websocket::Session::open(&mut req, stats)?),
+        false => None,
     };
-    Ok(response)
+    let drain = req.extensions().get::<Drain>().cloned();
+    let streaming = req.extensions().get::<Arc<Streaming>>().cloned();
+    rewrite_request(&mut req, &upstream, upstream_uri);
+    if websocket.is_some() {
+        websocket::restore_upgrade_headers(req.headers_mut());
+    } else if !upstream.keep_alive && upstream.protocol == UpstreamProtocol::Http1 {
+        req.headers_mut().insert(
+            header::CONNECTION,
+            header::HeaderValue::from_static("close"),
+        );
+    }
+    let context = req.extensions().get::<RequestContext>().cloned();
+    if let Some(context) = &context {
+        let queue = context.elapsed();
+        context.record_timings(|timings| timings.queue = queue);
+    }
+    // Upgrades are never retried: the client's connection goes with the request.
+    let retry = req
+        .extensions()
+        .get::<Arc<Retry>>()
+        .cloned()
+        .filter(|_| websocket.is_none());
+    let sent = Instant::now();
+    let client = clients.pooled(
+        &upstream.tls,
+        &upstream.pool.or(pool),
+        upstream.protocol,
+        upstream.proxy_protocol,
+    )?;
+    let addresses = proxy_addresses(&req);
+    let send = async move {
+        Ok::<_, anyhow::Error>(match retry {
+            Some(retry) => retry.send(&client, req).await?,
+            None => client.request(req).await.map_err(ProxyError::from)?,
+        })
+    };
+    let mut response = match upstream.proxy_protocol {
+        Some(_) => announcing(addresses, send).await?,
+        None => send.await?,
+    };
+    let waited = sent.elapsed();
+    if let Some(via) = via {
+        let version = response.version();
+        append_via(response.headers_mut(), version, via);
+    }
+    if let Some(session) = websocket {
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            let upgraded = hyper::upgrade::on(&mut response);
+            tokio::spawn(session.relay(upgraded, stats.clone(), drain));
+        }
+    }
+    if let Some(usage) = connection_use(&response) {
+        let connect = if usage.reused {
+            Duration::ZERO
+        } else {
+            usage.connect_time
+        };
+        if let Some(context) = &context {
+            context.record_timings(|timings| {
+                timings.upstream_connect = Some(connect);
+                timings.upstream_ttfb = Some(waited.saturating_sub(connect));
+            });
+        }
+        let connection = if usage.reused { "reused" } else { "new" };
+        tracing::debug!(target, connection, "upstream response received");
+        metrics::counter!("jester_upstream_requests_total", "target" => target, "connection" => connection)
+            .increment(1);
+    }
+    let response = response.map(|body| body.map_err(BoxError::from).boxed());
+    let response = match streaming {
+        Some(settings) if response.status() != StatusCode::SWITCHING_PROTOCOLS => {
+            let route = context
+                .and_then(|context| context.route())
+                .unwrap_or_default();
+            streaming::relay(response, &settings, &route)
+        }
+        _ => response,
+    };
+    Ok(stats::hold_until_sent(response, inflight))
 }
 
 fn build_upstream_uri(base: &Uri, incoming: &Uri) -> Result<Uri> {
@@ -231,19 +2212,98 @@ fn build_upstream_uri(base: &Uri, incoming: &Uri) -> Result<Uri> {
     Uri::from_parts(parts).context("failed to construct upstream uri")
 }
 
-fn rewrite_request<B>(req: &mut Request<B>, base: &Uri, target: Uri) {
+/// The `Host` the upstream will see for `req` under its `host_header` policy.
+pub(crate) fn upstream_host<B>(req: &Request<B>, upstream: &UpstreamEndpoint) -> Option<String> {
+    match &upstream.host_header {
+        // A socket path names no host, so upstreams behind one see `localhost`.
+        HostHeader::Upstream if socket_path(&upstream.uri).is_some() => Some("localhost".into()),
+        HostHeader::Upstream => upstream.uri.authority().map(|a| a.as_str().to_string()),
+        HostHeader::Preserve => client_authority(req),
+        HostHeader::Custom(host) => Some(host.clone()),
+    }
+}
+
+fn rewrite_request<B>(req: &mut Request<B>, upstream: &UpstreamEndpoint, target: Uri) {
+    let host = upstream_host(req, upstream);
     *req.uri_mut() = target;
+    // Requests from HTTP/2 and HTTP/3 clients go out in the upstream's protocol.
+    if req.version() > http::Version::HTTP_11 {
+        *req.version_mut() = match upstream.protocol {
+            UpstreamProtocol::Http1 => http::Version::HTTP_11,
+            _ => http::Version::HTTP_2,
+        };
+    }
+    // HTTP/2 allows `te: trailers`, which gRPC servers expect.
+    let trailers = upstream.protocol != UpstreamProtocol::Http1
+        && req
+            .headers()
+            .get_all(header::TE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"));
     clean_hop_by_hop(req.headers_mut());
-    if let Some(authority) = base.authority() {
-        req.headers_mut().insert(
-            header::HOST,
-            header::HeaderValue::from_str(authority.as_str()).unwrap(),
+    if trailers {
+        req.headers_mut()
+            .insert(header::TE, header::HeaderValue::from_static("trailers"));
+    }
+    if let Some(value) = host.and_then(|host| header::HeaderValue::from_str(&host).ok()) {
+        req.headers_mut().insert(header::HOST, value);
+    }
+    if let Some(connection) = req.extensions().get::<ConnectionInfo>().cloned() {
+        let port = connection.local_addr.port().to_string();
+        let trusted = connection.trust_forwarded_headers;
+        set_forwarded(
+            req.headers_mut(),
+            "x-forwarded-proto",
+            connection.scheme,
+            trusted,
         );
+        set_forwarded(req.headers_mut(), "x-forwarded-port", &port, trusted);
+    }
+}
+
+/// The client and listener addresses a PROXY protocol header announces for
+/// `req`. The client's port is only known when its address is the connection's.
+fn proxy_addresses<B>(req: &Request<B>) -> Option<(SocketAddr, SocketAddr)> {
+    let connection = req.extensions().get::<ConnectionInfo>()?;
+    let client = ClientIp::of(req)?;
+    let port = match connection.client_addr.ip() == client {
+        true => connection.client_addr.port(),
+        false => 0,
+    };
+    Some((SocketAddr::new(client, port), connection.local_addr))
+}
+
+/// Adds this proxy after any earlier hops in `Via` (RFC 9110 §7.6.3), noting
+/// the protocol version the message was received with.
+fn append_via(headers: &mut http::HeaderMap, version: http::Version, via: &str) {
+    let protocol = match version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        http::Version::HTTP_2 => "2",
+        http::Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    if let Ok(value) = header::HeaderValue::from_str(&format!("{protocol} {via}")) {
+        headers.append(header::VIA, value);
+    }
+}
+
+/// Sets an `x-forwarded-*` header, appending to a value from a trusted proxy
+/// rather than replacing it.
+fn set_forwarded(headers: &mut http::HeaderMap, name: &'static str, value: &str, trusted: bool) {
+    let existing = trusted
+        .then(|| headers.get(name).and_then(|v| v.to_str().ok()))
+        .flatten()
+        .filter(|v| !v.trim().is_empty());
+    let value = match existing {
+        Some(existing) => format!("{existing}, {value}"),
+        None => value.to_string(),
+    };
+    if let Ok(value) = header::HeaderValue::from_str(&value) {
+        headers.insert(name, value);
     }
-    req.headers_mut().insert(
-        "x-forwarded-proto",
-        header::HeaderValue::from_static("https"),
-    );
 }
 
 fn clean_hop_by_hop(headers: &mut http::HeaderMap) {
@@ -260,63 +2320,301 @@ fn clean_hop_by_hop(headers: &mut http::HeaderMap) {
     }
 }
 
+/// Drops headers HTTP/2 forbids (RFC 9113 §8.2.2) from a response to an
+/// HTTP/2 client, including any an upstream's `Connection` header names.
+pub(crate) fn strip_connection_headers(headers: &mut http::HeaderMap) {
+    let named: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in named {
+        headers.remove(name.as_str());
+    }
+    for name in [
+        "connection",
+        "keep-alive",
+        "proxy-connection",
+        "transfer-encoding",
+        "upgrade",
+    ] {
+        headers.remove(name);
+    }
+}
+
+/// Applies the listener's policies for absolute-form targets and requests
+/// without a host, leaving an origin-form target and a `Host` header when the
+/// request is accepted.
+fn normalize_target<B>(
+    connection: &ConnectionInfo,
+    req: &mut Request<B>,
+) -> Result<(), &'static str> {
+    if let Some(authority) = req.uri().authority().cloned() {
+        // HTTP/2 and HTTP/3 always send the authority as `:authority`, in
+        // place of `Host` (RFC 9113 §8.3.1); that is not an absolute-form target.
+        let pseudo_header = req.version() > http::Version::HTTP_11;
+        if !pseudo_header && connection.absolute_form == AbsoluteForm::Reject {
+            metrics::counter!("jester_legacy_requests_total", "kind" => "absolute_form", "outcome" => "rejected")
+                .increment(1);
+            return Err("absolute-form request targets are not accepted");
+        }
+        let host = header::HeaderValue::from_str(authority.as_str())
+            .map_err(|_| "invalid request target")?;
+        let mut parts = req.uri().clone().into_parts();
+        parts.scheme = None;
+        parts.authority = None;
+        if parts.path_and_query.is_none() {
+            parts.path_and_query = Some(http::uri::PathAndQuery::from_static("/"));
+        }
+        *req.uri_mut() = Uri::from_parts(parts).map_err(|_| "invalid request target")?;
+        req.headers_mut().insert(header::HOST, host);
+        if !pseudo_header {
+            metrics::counter!("jester_legacy_requests_total", "kind" => "absolute_form", "outcome" => "accepted")
+                .increment(1);
+        }
+        return Ok(());
+    }
+    if req.headers().contains_key(header::HOST) {
+        return Ok(());
+    }
+    match &connection.missing_host {
+        MissingHost::Match => {}
+        MissingHost::Reject => {
+            metrics::counter!("jester_legacy_requests_total", "kind" => "missing_host", "outcome" => "rejected")
+                .increment(1);
+            return Err("missing host");
+        }
+        MissingHost::Map(host) => {
+            if let Ok(value) = header::HeaderValue::from_str(host) {
+                req.headers_mut().insert(header::HOST, value);
+            }
+            metrics::counter!("jester_legacy_requests_total", "kind" => "missing_host", "outcome" => "mapped")
+                .increment(1);
+        }
+    }
+    Ok(())
+}
+
+/// The host the client addressed, without its port, for routing.
 fn extract_host<B>(req: &Request<B>) -> Option<String> {
-    req.uri().host().map(|host| host.to_string()).or_else(|| {
-        req.headers()
-            .get(header::HOST)
-            .and_then(|value| value.to_str().ok().map(|s| s.to_string()))
-    })
+    let authority = client_authority(req)?;
+    let host = match authority.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => authority.split(':').next().unwrap_or_default(),
+    };
+    Some(host.to_string())
+}
+
+/// The authority the client addressed, including any port.
+fn client_authority<B>(req: &Request<B>) -> Option<String> {
+    req.uri()
+        .authority()
+        .map(|authority| authority.as_str().to_string())
+        .or_else(|| {
+            req.headers()
+                .get(header::HOST)
+                .and_then(|value| value.to_str().ok().map(|s| s.to_string()))
+        })
+}
+
+/// The trace ID of a W3C `traceparent` header (`00-<trace-id>-<parent-id>-<flags>`);
+/// an all-zero ID is invalid.
+fn trace_id(headers: &http::HeaderMap) -> Option<String> {
+    let value = headers.get("traceparent")?.to_str().ok()?;
+    let mut fields = value.trim().split('-');
+    let hex = |field: &str, len: usize| {
+        field.len() == len
+            && field
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    };
+    let (version, trace_id, parent_id, flags) = (
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+    );
+    let valid = hex(version, 2)
+        && version != "ff"
+        && (version != "00" || fields.next().is_none())
+        && hex(trace_id, 32)
+        && trace_id.bytes().any(|b| b != b'0')
+        && hex(parent_id, 16)
+        && hex(flags, 2);
+    valid.then(|| trace_id.to_string())
 }
 
 fn not_found() -> Response<ProxyBody> {
     response_with(StatusCode::NOT_FOUND, "no matching route")
 }
 
-fn bad_gateway() -> Response<ProxyBody> {
-    response_with(StatusCode::BAD_GATEWAY, "upstream error")
+fn method_not_allowed(methods: &[Method]) -> Response<ProxyBody> {
+    let mut response = response_with(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+    let allow = methods
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(value) = header::HeaderValue::from_str(&allow) {
+        response.headers_mut().insert(header::ALLOW, value);
+    }
+    response
 }
 
-fn internal_error() -> Response<ProxyBody> {
+pub(crate) fn internal_error() -> Response<ProxyBody> {
     response_with(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
 }
 
 fn response_with(status: StatusCode, msg: &'static str) -> Response<ProxyBody> {
-    let body = Full::new(Bytes::from_static(msg.as_bytes()))
-        .map_err(|never| match never {})
-        .boxed();
-    Response::builder().status(status).body(body).unwrap()
+    text_response(status, msg)
 }
 
 impl TryFrom<ResolvedListener> for ListenerRuntime {
     type Error = anyhow::Error;
 
     fn try_from(value: ResolvedListener) -> Result<Self> {
-        let server_config = build_tls_config(&value)?;
+        let acceptor = match value.tls {
+            Some(_) => Some(Acceptor::new(build_tls_config(&value)?)),
+            None => None,
+        };
         Ok(Self {
-            name: value.name,
+            name: value.name.clone(),
             addr: value.addr,
-            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            trust_forwarded_headers: value.trust_forwarded_headers,
+            log_connections: value.log_connections,
+            missing_host: value.missing_host.clone(),
+            absolute_form: value.absolute_form,
+            client_ip: Arc::new(
+                ClientIpResolver::try_from(&value.client_ip)
+                    .with_context(|| format!("invalid client_ip for listener `{}`", value.name))?,
+            ),
+            limits: ConnectionLimits::from(&value.http),
+            server_names: value.server_names.clone(),
+            health_check_paths: value.health_check_paths.as_slice().into(),
+            http1: value.alpn.iter().any(|protocol| protocol == "http/1.1"),
+            tls: acceptor.map(|acceptor| {
+                Arc::new(ListenerTls {
+                    source: value,
+                    acceptor: RwLock::new(acceptor),
+                })
+            }),
         })
     }
 }
 
 fn build_tls_config(listener: &ResolvedListener) -> Result<ServerConfig> {
-    let certs = load_certs(&listener.tls.cert)?;
-    let key = load_private_key(&listener.tls.key)?;
-    let mut config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)
-        .context("invalid certificate/key pair")?;
+    let certificates = load_identities(listener)?.try_map(|(certs, key)| {
+        let key = any_supported_type(&key).context("invalid certificate/key pair")?;
+        Ok(Arc::new(CertifiedKey::new(certs, key)))
+    })?;
+    let tls = listener
+        .tls
+        .as_ref()
+        .with_context(|| format!("listener `{}` has no TLS certificate", listener.name))?;
+    let policy = crate::tls::Policy::new(tls)?;
+    let builder = ServerConfig::builder()
+        .with_cipher_suites(&policy.cipher_suites)
+        .with_kx_groups(&policy.kx_groups)
+        .with_protocol_versions(&policy.versions)
+        .context("invalid tls protocol settings")?;
+    let builder = match load_client_cas(listener)? {
+        Some((cas, auth)) => {
+            let mut roots = RootCertStore::empty();
+            for ca in &cas {
+                roots
+                    .add(ca)
+                    .context("invalid certificate in tls.client_ca")?;
+            }
+            builder.with_client_cert_verifier(match auth {
+                ClientAuth::Required => AllowAnyAuthenticatedClient::new(roots).boxed(),
+                ClientAuth::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+            })
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(Arc::new(certificates));
+    // Listed suites are in the operator's order of preference, not the client's.
+    config.ignore_client_order = !tls.cipher_suites.is_empty();
     config.alpn_protocols = listener
         .alpn
         .iter()
         .map(|proto| proto.as_bytes().to_vec())
         .collect();
+    if listener.early_data {
+        config.max_early_data_size = MAX_EARLY_DATA_BYTES;
+        config.send_half_rtt_data = true;
+    }
     Ok(config)
 }
 
-fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+/// The listener's certificates with their private keys, by the server names
+/// they serve; each chain is completed from its `intermediates`.
+pub(crate) fn load_identities(
+    listener: &ResolvedListener,
+) -> Result<SniCertificates<(Vec<Certificate>, PrivateKey)>> {
+    let tls = listener
+        .tls
+        .as_ref()
+        .with_context(|| format!("listener `{}` has no TLS certificate", listener.name))?;
+    let identity = |cert: &str, key: &str, intermediates: Option<&str>| {
+        let certs = load_certs(cert)?;
+        let intermediates = match intermediates {
+            Some(path) => load_certs(path)?,
+            None => Vec::new(),
+        };
+        let leaf_only = certs.len();
+        let certs = crate::tls::complete_chain(certs, &intermediates)
+            .with_context(|| format!("invalid certificate chain in {cert}"))?;
+        if certs.len() > leaf_only {
+            tracing::info!(
+                listener = %listener.name,
+                cert,
+                appended = certs.len() - leaf_only,
+                "completed certificate chain from tls.intermediates"
+            );
+        }
+        let private_key = load_private_key(key)?;
+        crate::tls::check_key(&certs[0], &private_key)
+            .with_context(|| format!("{key} is not the key of {cert}"))?;
+        Ok::<_, anyhow::Error>((certs, private_key))
+    };
+    let mut identities =
+        SniCertificates::new(identity(&tls.cert, &tls.key, tls.intermediates.as_deref())?);
+    for certificate in &tls.certificates {
+        identities.add(
+            &certificate.server_names,
+            identity(
+                &certificate.cert,
+                &certificate.key,
+                certificate.intermediates.as_deref(),
+            )?,
+        );
+    }
+    Ok(identities)
+}
+
+/// The CAs client certificates are verified against, for listeners with
+/// `tls.client_ca`, and whether clients must present one.
+pub(crate) fn load_client_cas(
+    listener: &ResolvedListener,
+) -> Result<Option<(Vec<Certificate>, ClientAuth)>> {
+    let Some(tls) = &listener.tls else {
+        return Ok(None);
+    };
+    let Some(path) = &tls.client_ca else {
+        return Ok(None);
+    };
+    let cas = load_certs(path)?;
+    if cas.is_empty() {
+        bail!("no certificates found in tls.client_ca {path}");
+    }
+    Ok(Some((cas, tls.client_auth.unwrap_or_default())))
+}
+
+pub(crate) fn load_certs(path: &str) -> Result<Vec<Certificate>> {
     let data = std::fs::read(path).with_context(|| format!("failed to read cert {path}"))?;
     let mut reader = std::io::Cursor::new(data);
     let raw =
@@ -339,3 +2637,217 @@ fn load_private_key(path: &str) -> Result<PrivateKey> {
     }
     anyhow::bail!("no usable private keys found in {path}")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MethodMismatch, Upstream};
+
+    #[test]
+    fn builder_rejects_missing_listeners() {
+        let err = Proxy::builder().build().err().unwrap();
+        assert!(err.to_string().contains("at least one listener"));
+    }
+
+    #[test]
+    fn trace_ids_come_from_valid_traceparent_headers() {
+        let trace = |value: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert("traceparent", value.parse().unwrap());
+            trace_id(&headers)
+        };
+        assert_eq!(
+            trace("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        // Later versions may append fields.
+        assert!(trace("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra").is_some());
+        assert_eq!(trace_id(&http::HeaderMap::new()), None);
+        for invalid in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(trace(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn via_entries_follow_earlier_hops() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(header::VIA, "1.1 cdn-edge".parse().unwrap());
+        append_via(
+            &mut headers,
+            http::Version::HTTP_10,
+            "node-a (jester/0.0.1)",
+        );
+        append_via(&mut headers, http::Version::HTTP_2, "node-a (jester/0.0.1)");
+        let via: Vec<_> = headers.get_all(header::VIA).iter().collect();
+        assert_eq!(
+            via,
+            [
+                "1.1 cdn-edge",
+                "1.0 node-a (jester/0.0.1)",
+                "2 node-a (jester/0.0.1)"
+            ]
+        );
+    }
+
+    #[test]
+    fn forwarded_headers_append_only_when_trusted() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("x-forwarded-proto", "http".parse().unwrap());
+        set_forwarded(&mut headers, "x-forwarded-proto", "https", false);
+        assert_eq!(headers["x-forwarded-proto"], "https");
+
+        headers.insert("x-forwarded-proto", "http".parse().unwrap());
+        set_forwarded(&mut headers, "x-forwarded-proto", "https", true);
+        assert_eq!(headers["x-forwarded-proto"], "http, https");
+
+        set_forwarded(&mut headers, "x-forwarded-port", "443", true);
+        assert_eq!(headers["x-forwarded-port"], "443");
+    }
+
+    #[tokio::test]
+    async fn early_data_is_served_only_for_idempotent_requests_on_opted_in_routes() {
+        let routes = [
+            Route::builder("safe", Upstream::single("http://127.0.0.1:8080"))
+                .host("safe.test")
+                .early_data(true)
+                .build(),
+            Route::builder("unsafe", Upstream::single("http://127.0.0.1:8080"))
+                .host("unsafe.test")
+                .build(),
+        ];
+        // Echo back whether the upstream would see the `early-data` header.
+        let upstream = JesterService::new(tower::service_fn(|req: HttpRequest| async move {
+            let flagged = req.headers().contains_key("early-data");
+            Ok(text_response(StatusCode::OK, flagged.to_string()))
+        }));
+        let router = Router::build(&routes, &FilterRegistry::default(), upstream).unwrap();
+        let stats = RuntimeStats::default();
+        let well_known = WellKnownFiles::new(&[]).unwrap();
+        let send = |method: Method, host: &str, early: bool| {
+            let mut req = Request::builder()
+                .method(method)
+                .uri("/")
+                .header(header::HOST, host)
+                .body(full_body(""))
+                .unwrap();
+            if early {
+                req.extensions_mut().insert(EarlyData);
+            }
+            dispatch(&router, &well_known, &stats, req)
+        };
+
+        let resp = send(Method::GET, "safe.test", true).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.into_body().collect().await.unwrap().to_bytes(), "true");
+        let resp = send(Method::GET, "safe.test", false).await.unwrap();
+        assert_eq!(
+            resp.into_body().collect().await.unwrap().to_bytes(),
+            "false"
+        );
+        let resp = send(Method::POST, "safe.test", true).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_EARLY);
+        let resp = send(Method::GET, "unsafe.test", true).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_EARLY);
+        let resp = send(Method::POST, "unsafe.test", false).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn method_mismatch_answers_405_with_allow() {
+        let upstream = || Upstream::single("http://127.0.0.1:8080");
+        let routes = [
+            Route::builder("read", upstream())
+                .path_prefix("/items")
+                .method("GET")
+                .method("HEAD")
+                .method_mismatch(MethodMismatch::NotAllowed)
+                .build(),
+            Route::builder("write", upstream())
+                .path_prefix("/items")
+                .method("PUT")
+                .build(),
+            Route::builder("legacy", upstream())
+                .path_prefix("/legacy")
+                .method("GET")
+                .build(),
+            Route::builder("catch-all", upstream()).build(),
+        ];
+        let upstream = JesterService::new(tower::service_fn(|_req: HttpRequest| async {
+            Ok(text_response(StatusCode::OK, "ok"))
+        }));
+        let router = Router::build(&routes, &FilterRegistry::default(), upstream).unwrap();
+        let stats = RuntimeStats::default();
+        let well_known = WellKnownFiles::new(&[]).unwrap();
+        let send = |method: Method, path: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(full_body(""))
+                .unwrap();
+            dispatch(&router, &well_known, &stats, req)
+        };
+
+        let resp = send(Method::PUT, "/items").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = send(Method::DELETE, "/items/1").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()[header::ALLOW], "GET, HEAD, PUT");
+        // Without `method_mismatch = "405"` the request falls through.
+        let resp = send(Method::DELETE, "/legacy").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn legacy_request_targets_follow_listener_policy() {
+        let connection = |missing_host, absolute_form| ConnectionInfo {
+            listener: "edge".into(),
+            scheme: "https",
+            local_addr: "127.0.0.1:8443".parse().unwrap(),
+            peer_addr: "127.0.0.1:50000".parse().unwrap(),
+            client_addr: "127.0.0.1:50000".parse().unwrap(),
+            trust_forwarded_headers: false,
+            tls_handshake: Duration::ZERO,
+            missing_host,
+            absolute_form,
+            health_check_paths: Arc::from([]),
+            client_certificate: None,
+        };
+        let request = |uri: &str, host: Option<&str>| {
+            let mut builder = Request::builder().uri(uri).version(http::Version::HTTP_10);
+            if let Some(host) = host {
+                builder = builder.header(header::HOST, host);
+            }
+            builder.body(()).unwrap()
+        };
+        let accepting = connection(MissingHost::Match, AbsoluteForm::Accept);
+
+        let mut req = request("http://App.example.com:8443/health?full=1", Some("other"));
+        normalize_target(&accepting, &mut req).unwrap();
+        assert_eq!(req.uri(), "/health?full=1");
+        assert_eq!(req.headers()[header::HOST], "App.example.com:8443");
+        assert_eq!(extract_host(&req).as_deref(), Some("App.example.com"));
+        assert_eq!(
+            client_authority(&req).as_deref(),
+            Some("App.example.com:8443")
+        );
+
+        let mut req = request("/", None);
+        normalize_target(&accepting, &mut req).unwrap();
+        assert_eq!(extract_host(&req), None);
+        let mapping = connection(MissingHost::Map("legacy.test".into()), AbsoluteForm::Reject);
+        normalize_target(&mapping, &mut req).unwrap();
+        assert_eq!(extract_host(&req).as_deref(), Some("legacy.test"));
+        assert!(normalize_target(&mapping, &mut request("http://a.test/", None)).is_err());
+        let rejecting = connection(MissingHost::Reject, AbsoluteForm::Accept);
+        assert!(normalize_target(&rejecting, &mut request("/", None)).is_err());
+
+        let req = request("/", Some("[::1]:8443"));
+        assert_eq!(extract_host(&req).as_deref(), Some("::1"));
+    }
+}
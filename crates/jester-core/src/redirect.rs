@@ -0,0 +1,238 @@
+//! Plain-HTTP listeners (`kind = "https_redirect"`) that send every request
+//! to the same host and path over HTTPS.
+
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use http::{header, uri::Authority, HeaderValue, Method, Request, Response, StatusCode};
+use hyper::{server::conn::http1, service::service_fn};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use tokio::{net::TcpListener, sync::watch};
+
+use crate::{
+    config::Listener,
+    health,
+    plugin::{text_response, ProxyBody},
+};
+
+/// How long a client may take to send its request headers.
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+const HTTP_PORT: u16 = 80;
+const HTTPS_PORT: u16 = 443;
+
+pub(crate) struct RedirectListener {
+    pub(crate) name: String,
+    pub(crate) addr: SocketAddr,
+    target: Arc<Target>,
+    health_check_paths: Arc<[String]>,
+}
+
+/// Where requests are sent.
+struct Target {
+    /// HTTPS port for clients that addressed none of `ports`.
+    port: u16,
+    /// HTTPS ports by the port the client addressed.
+    ports: BTreeMap<u16, u16>,
+    strip_query: bool,
+}
+
+impl TryFrom<&Listener> for RedirectListener {
+    type Error = anyhow::Error;
+
+    fn try_from(listener: &Listener) -> Result<Self> {
+        Ok(Self {
+            name: listener.name.clone(),
+            addr: listener.parse_bind_addr().with_context(|| {
+                format!("invalid bind address for listener `{}`", listener.name)
+            })?,
+            target: Arc::new(Target {
+                port: listener.redirect_port.unwrap_or(HTTPS_PORT),
+                ports: listener.redirect_ports.clone(),
+                strip_query: listener.redirect_strip_query,
+            }),
+            health_check_paths: listener.health_check_paths.as_slice().into(),
+        })
+    }
+}
+
+impl RedirectListener {
+    pub(crate) async fn serve(
+        self,
+        tcp: TcpListener,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.changed() => {
+                    tracing::info!(listener = self.name, "listener shutting down");
+                    break;
+                }
+                accept = tcp.accept() => {
+                    let (stream, peer_addr) = match accept {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            tracing::warn!(listener = self.name, error = %err, "accept failed");
+                            tokio::time::sleep(crate::proxy::ACCEPT_ERROR_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    let name = self.name.clone();
+                    let health_check_paths = self.health_check_paths.clone();
+                    let target = self.target.clone();
+                    let shutdown = shutdown.clone();
+                    tokio::spawn(async move {
+                        let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                            let ready = !*shutdown.borrow();
+                            let response = health::answer(&health_check_paths, &req, &name, ready)
+                                .unwrap_or_else(|| redirect(&req, &target));
+                            async move { Ok::<_, hyper::Error>(response) }
+                        });
+                        let served = http1::Builder::new()
+                            .timer(TokioTimer::new())
+                            .header_read_timeout(HEADER_READ_TIMEOUT)
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                        if let Err(err) = served {
+                            tracing::debug!(%peer_addr, error = %err, "redirect connection failed");
+                        }
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `301` to the request's URL over HTTPS, or `308` for methods a client may
+/// not turn into a `GET` when following it.
+fn redirect<B>(req: &Request<B>, target: &Target) -> Response<ProxyBody> {
+    let authority = req.uri().authority().cloned().or_else(|| {
+        req.headers()
+            .get(header::HOST)
+            .and_then(|value| Authority::try_from(value.as_bytes()).ok())
+    });
+    // A `Host` never carries userinfo; refusing it keeps `a@b` from sending
+    // the client to `b`.
+    let usable =
+        |authority: &Authority| !authority.host().is_empty() && !authority.as_str().contains('@');
+    let Some(authority) = authority.filter(usable) else {
+        metrics::counter!("jester_https_redirects_total", "outcome" => "missing_host").increment(1);
+        return text_response(StatusCode::BAD_REQUEST, "missing host");
+    };
+    let addressed = authority.port_u16().unwrap_or(HTTP_PORT);
+    let port = match target.ports.get(&addressed).copied().unwrap_or(target.port) {
+        HTTPS_PORT => String::new(),
+        port => format!(":{port}"),
+    };
+    let path = match req.uri().path_and_query() {
+        Some(path) if !path.as_str().starts_with('/') => "/",
+        Some(path) if target.strip_query => path.path(),
+        Some(path) => path.as_str(),
+        None => "/",
+    };
+    let location = format!("https://{}{port}{path}", authority.host());
+    let Ok(location) = HeaderValue::from_str(&location) else {
+        metrics::counter!("jester_https_redirects_total", "outcome" => "invalid").increment(1);
+        return text_response(StatusCode::BAD_REQUEST, "invalid request target");
+    };
+    let status = if req.method() == Method::GET || req.method() == Method::HEAD {
+        StatusCode::MOVED_PERMANENTLY
+    } else {
+        StatusCode::PERMANENT_REDIRECT
+    };
+    metrics::counter!("jester_https_redirects_total", "outcome" => "redirected").increment(1);
+    let mut response = text_response(status, "");
+    response.headers_mut().insert(header::LOCATION, location);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(method: Method, uri: &str, host: Option<&str>, port: u16) -> (StatusCode, String) {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(host) = host {
+            req = req.header(header::HOST, host);
+        }
+        let target = Target {
+            port,
+            ports: BTreeMap::new(),
+            strip_query: false,
+        };
+        let response = redirect(&req.body(()).unwrap(), &target);
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .map(|value| value.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        (response.status(), location)
+    }
+
+    #[test]
+    fn redirects_to_the_same_host_and_path_over_https() {
+        assert_eq!(
+            location(Method::GET, "/a/b?c=d", Some("example.com"), 443),
+            (
+                StatusCode::MOVED_PERMANENTLY,
+                "https://example.com/a/b?c=d".into()
+            )
+        );
+        assert_eq!(
+            location(Method::HEAD, "/", Some("Example.com:80"), 8443),
+            (
+                StatusCode::MOVED_PERMANENTLY,
+                "https://Example.com:8443/".into()
+            )
+        );
+        assert_eq!(
+            location(Method::GET, "http://[::1]:80/x", None, 443),
+            (StatusCode::MOVED_PERMANENTLY, "https://[::1]/x".into())
+        );
+        assert_eq!(
+            location(Method::POST, "/form", Some("example.com"), 443),
+            (
+                StatusCode::PERMANENT_REDIRECT,
+                "https://example.com/form".into()
+            )
+        );
+    }
+
+    #[test]
+    fn ports_map_and_queries_can_be_stripped() {
+        let target = Target {
+            port: HTTPS_PORT,
+            ports: [(8080, 8443), (8443, 443)].into(),
+            strip_query: true,
+        };
+        let location = |uri: &str, host: &str| {
+            let req = Request::get(uri)
+                .header(header::HOST, host)
+                .body(())
+                .unwrap();
+            let response = redirect(&req, &target);
+            response.headers()[header::LOCATION]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            location("/a?b=c", "localhost:8080"),
+            "https://localhost:8443/a"
+        );
+        assert_eq!(location("/a?b=c", "dev.local:8443"), "https://dev.local/a");
+        // Ports outside the map, and a Host without one, take `port`.
+        assert_eq!(location("/?x", "example.com:9000"), "https://example.com/");
+        assert_eq!(location("/a?", "example.com"), "https://example.com/a");
+    }
+
+    #[test]
+    fn requests_without_a_usable_host_are_rejected() {
+        for host in [None, Some(""), Some("bad host"), Some("a@b")] {
+            let (status, location) = location(Method::GET, "/", host, 443);
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{host:?}");
+            assert!(location.is_empty());
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! Per-route retries of failed upstream attempts, limited by a retry budget.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use http::{request::Parts, Request, Response};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Incoming};
+
+use crate::{
+    client::HttpClient,
+    config::{RetryOn, RetryPolicy},
+    error::ProxyError,
+    plugin::{full_body, AttemptOutcome, HttpRequest, RetryAdvisor, RetryDecision},
+    stats::route_labels,
+};
+
+/// Span over which `budget_ratio` is measured.
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// A route's retry policy with the budget its requests share.
+pub(crate) struct Retry {
+    route: String,
+    labels: BTreeMap<String, String>,
+    policy: RetryPolicy,
+    budget: RetryBudget,
+    advisor: Option<Arc<dyn RetryAdvisor>>,
+}
+
+impl Retry {
+    pub(crate) fn new(
+        route: &str,
+        labels: &BTreeMap<String, String>,
+        policy: &RetryPolicy,
+        advisor: Option<Arc<dyn RetryAdvisor>>,
+    ) -> Self {
+        Self {
+            route: route.to_string(),
+            labels: labels.clone(),
+            budget: RetryBudget::new(policy.budget_ratio, policy.budget_min_per_sec),
+            policy: policy.clone(),
+            advisor,
+        }
+    }
+
+    /// Sends `req`, trying again while the policy and budget allow.
+    pub(crate) async fn send(
+        &self,
+        client: &HttpClient,
+        req: HttpRequest,
+    ) -> Result<Response<Incoming>> {
+        self.budget.record_request();
+        let (mut parts, body) = req.into_parts();
+        if !self.replayable(&body) {
+            return self
+                .try_once(client, Request::from_parts(parts, body))
+                .await;
+        }
+        let body = body.collect().await.map_err(|err| anyhow!(err))?.to_bytes();
+        let mut attempt = 1;
+        loop {
+            let outcome = self.try_once(client, replay(&parts, &body)).await;
+            let reason = match self.advise(&mut parts, &outcome, attempt) {
+                RetryDecision::Retry => "advisor",
+                RetryDecision::Stop => return outcome,
+                RetryDecision::Policy => match self.retriable(&parts, &outcome) {
+                    Some(reason) => reason,
+                    None => return outcome,
+                },
+            };
+            if attempt >= self.policy.attempts {
+                return outcome;
+            }
+            if !self.budget.try_spend() {
+                self.count(reason, "budget_exhausted");
+                return outcome;
+            }
+            self.count(reason, "retried");
+            tracing::debug!(
+                route = self.route,
+                attempt,
+                reason,
+                "retrying upstream request"
+            );
+            tokio::time::sleep(self.backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    fn count(&self, reason: &'static str, outcome: &'static str) {
+        let labels = route_labels(
+            &self.route,
+            &self.labels,
+            [("reason", reason.into()), ("outcome", outcome.into())],
+        );
+        metrics::counter!("jester_upstream_retries_total", labels).increment(1);
+    }
+
+    /// Whether `body` has a known length small enough to hold for replays.
+    fn replayable<B: Body>(&self, body: &B) -> bool {
+        let size = body.size_hint();
+        self.policy.attempts > 1
+            && (body.is_end_stream() || size.upper() == Some(size.lower()))
+            && size.lower() <= self.policy.max_body_bytes
+    }
+
+    async fn try_once(&self, client: &HttpClient, req: HttpRequest) -> Result<Response<Incoming>> {
+        let request = client.request(req);
+        let response = match self.policy.per_try_timeout_ms {
+            Some(millis) => tokio::time::timeout(Duration::from_millis(millis), request)
+                .await
+                .map_err(|_| ProxyError::Timeout { body: None })?,
+            None => request.await,
+        };
+        Ok(response.map_err(ProxyError::from)?)
+    }
+
+    /// Asks the route's advisor about `outcome`, keeping its changes to
+    /// `parts` only when it asks for a retry.
+    fn advise(
+        &self,
+        parts: &mut Parts,
+        outcome: &Result<Response<Incoming>>,
+        attempt: u32,
+    ) -> RetryDecision {
+        let Some(advisor) = &self.advisor else {
+            return RetryDecision::Policy;
+        };
+        let outcome = match outcome {
+            Ok(response) => AttemptOutcome::Response {
+                status: response.status(),
+                headers: response.headers(),
+            },
+            Err(err) => match err.downcast_ref::<ProxyError>() {
+                Some(err) => AttemptOutcome::Failed(err),
+                None => return RetryDecision::Policy,
+            },
+        };
+        let mut proposed = parts.clone();
+        let decision = advisor.decide(&mut proposed, outcome, attempt);
+        if decision == RetryDecision::Retry {
+            *parts = proposed;
+        }
+        decision
+    }
+
+    /// Why `outcome` may be retried, if it may.
+    fn retriable(
+        &self,
+        parts: &Parts,
+        outcome: &Result<Response<Incoming>>,
+    ) -> Option<&'static str> {
+        let reason = match outcome {
+            Ok(response) => {
+                let status = response.status();
+                if self.policy.statuses.contains(&status.as_u16()) {
+                    "status"
+                } else if status.is_server_error() && self.retries(RetryOn::ServerError) {
+                    "5xx"
+                } else {
+                    return None;
+                }
+            }
+            Err(err) => match err.downcast_ref::<ProxyError>()? {
+                ProxyError::Connect(_) | ProxyError::Tls(_)
+                    if self.retries(RetryOn::ConnectFailure) =>
+                {
+                    // Nothing reached the upstream, so any method is safe.
+                    return Some("connect_failure");
+                }
+                ProxyError::UpstreamProtocol(_) if self.retries(RetryOn::Reset) => "reset",
+                ProxyError::Timeout { .. } if self.retries(RetryOn::Timeout) => "timeout",
+                _ => return None,
+            },
+        };
+        (self.policy.non_idempotent || parts.method.is_idempotent()).then_some(reason)
+    }
+
+    fn retries(&self, on: RetryOn) -> bool {
+        self.policy.retry_on.contains(&on)
+    }
+
+    /// Full jitter: a random pause up to the exponential backoff for `attempt`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .policy
+            .backoff_base_ms
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.policy.backoff_max_ms);
+        let mut random = [0; 8];
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut random).ok();
+        Duration::from_millis(u64::from_le_bytes(random) % (ceiling + 1))
+    }
+}
+
+fn replay(parts: &Parts, body: &Bytes) -> HttpRequest {
+    Request::from_parts(parts.clone(), full_body(body.clone()))
+}
+
+/// Caps retries at `ratio` of the requests seen in the current window plus
+/// `min_per_sec` for each second of it.
+struct RetryBudget {
+    ratio: f64,
+    min_per_sec: u32,
+    window: Mutex<Window>,
+}
+
+struct Window {
+    started: Instant,
+    requests: u64,
+    retries: u64,
+}
+
+impl RetryBudget {
+    fn new(ratio: f64, min_per_sec: u32) -> Self {
+        Self {
+            ratio,
+            min_per_sec,
+            window: Mutex::new(Window {
+                started: Instant::now(),
+                requests: 0,
+                retries: 0,
+            }),
+        }
+    }
+
+    fn record_request(&self) {
+        self.current().requests += 1;
+    }
+
+    /// Takes one retry from the budget if any is left.
+    fn try_spend(&self) -> bool {
+        let mut window = self.current();
+        let allowed = f64::from(self.min_per_sec) * BUDGET_WINDOW.as_secs_f64()
+            + self.ratio * window.requests as f64;
+        if (window.retries as f64) < allowed {
+            window.retries += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn current(&self) -> std::sync::MutexGuard<'_, Window> {
+        let mut window = self.window.lock().unwrap_or_else(|err| err.into_inner());
+        if window.started.elapsed() >= BUDGET_WINDOW {
+            *window = Window {
+                started: Instant::now(),
+                requests: 0,
+                retries: 0,
+            };
+        }
+        window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_allows_a_ratio_of_requests_plus_a_floor() {
+        let budget = RetryBudget::new(0.5, 0);
+        assert!(!budget.try_spend(), "no requests, no floor");
+        for _ in 0..4 {
+            budget.record_request();
+        }
+        assert!(budget.try_spend());
+        assert!(budget.try_spend());
+        assert!(!budget.try_spend());
+
+        let floor = RetryBudget::new(0.0, 1);
+        assert_eq!((0..20).filter(|_| floor.try_spend()).count(), 10);
+    }
+
+    #[test]
+    fn backoff_stays_under_the_capped_exponential() {
+        let retry = Retry::new(
+            "test",
+            &BTreeMap::new(),
+            &RetryPolicy {
+                backoff_base_ms: 10,
+                backoff_max_ms: 30,
+                ..Default::default()
+            },
+            None,
+        );
+        for _ in 0..50 {
+            assert!(retry.backoff(1) <= Duration::from_millis(10));
+            assert!(retry.backoff(2) <= Duration::from_millis(20));
+            assert!(retry.backoff(5) <= Duration::from_millis(30));
+        }
+    }
+
+    /// Sends retries of failed connects to a fallback target, and stops
+    /// after a timeout.
+    struct Failover;
+
+    impl RetryAdvisor for Failover {
+        fn decide(
+            &self,
+            request: &mut Parts,
+            outcome: AttemptOutcome<'_>,
+            _attempt: u32,
+        ) -> RetryDecision {
+            request.uri = "http://fallback.internal/items".parse().unwrap();
+            match outcome {
+                AttemptOutcome::Failed(ProxyError::Connect(_)) => RetryDecision::Retry,
+                _ => RetryDecision::Stop,
+            }
+        }
+    }
+
+    #[test]
+    fn advisors_rewrite_only_the_requests_they_retry() {
+        let retry = Retry::new(
+            "test",
+            &BTreeMap::new(),
+            &RetryPolicy::default(),
+            Some(Arc::new(Failover)),
+        );
+        let (mut parts, ()) = Request::post("http://primary.internal/items")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        let timeout = Err(ProxyError::Timeout { body: None }.into());
+        assert_eq!(retry.advise(&mut parts, &timeout, 1), RetryDecision::Stop);
+        assert_eq!(parts.uri, "http://primary.internal/items");
+
+        let refused = Err(ProxyError::Connect("refused".into()).into());
+        assert_eq!(retry.advise(&mut parts, &refused, 1), RetryDecision::Retry);
+        assert_eq!(parts.uri, "http://fallback.internal/items");
+
+        let unadvised = Retry::new("test", &BTreeMap::new(), &RetryPolicy::default(), None);
+        assert_eq!(
+            unadvised.advise(&mut parts, &refused, 1),
+            RetryDecision::Policy
+        );
+    }
+}
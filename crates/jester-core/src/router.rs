@@ -1,9 +1,20 @@
-use std::{net::IpAddr, str::FromStr, time::Duration};
+use std::{collections::BTreeMap, net::IpAddr, str::FromStr, sync::Arc};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use http::{header::HeaderName, HeaderMap, Method, Request, Uri};
 
-use crate::config::{HeaderMatch, Matchers, Route, Upstream};
+use crate::{
+    balance::Balancer,
+    config::{
+        HeaderMatch, HostHeader, Matchers, MethodMismatch, ProxyProtocolVersion, Route, Streaming,
+        Upstream, UpstreamPool, UpstreamProtocol, UpstreamStrategy, UpstreamTls, WebsocketLimits,
+    },
+    filter::FilterRegistry,
+    plugin::JesterService,
+    profile,
+    retry::Retry,
+    schedule::Schedule,
+};
 
 #[derive(Clone)]
 pub struct Router {
@@ -11,47 +22,117 @@ pub struct Router {
 }
 
 impl Router {
-    pub fn build(routes: &[Route]) -> Result<Self> {
+    /// Compiles routes, wrapping `upstream` in each route's filter chain.
+    pub fn build(
+        routes: &[Route],
+        registry: &FilterRegistry,
+        upstream: JesterService,
+    ) -> Result<Self> {
         let handles = routes
             .iter()
-            .map(RouteHandle::try_from)
+            .map(|route| RouteHandle::build(route, registry, upstream.clone()))
             .collect::<Result<Vec<_>>>()?;
         Ok(Self { routes: handles })
     }
 
-    pub fn select<B>(&self, req: &Request<B>, host: &str) -> Option<&RouteHandle> {
+    /// Picks the first route matching the request. A route with
+    /// `method_mismatch = "405"` that matches all but the method claims the
+    /// request: only later routes declaring `methods` are still tried, and if
+    /// none accepts it the answer is `405` listing every method seen.
+    pub fn select<B>(&self, req: &Request<B>, host: &str) -> Selection<'_> {
         let path = req.uri().path();
         let method = req.method();
         let headers = req.headers();
-        self.routes
-            .iter()
-            .find(|route| route.matchers.matches(host, path, method, headers))
+        let mut allowed: Option<Vec<Method>> = None;
+        for route in &self.routes {
+            let matchers = &route.matchers;
+            if allowed.is_some() && matchers.methods.is_none() {
+                continue;
+            }
+            if !matchers.matches_resource(host, path, headers) {
+                continue;
+            }
+            if matchers.matches_method(method) {
+                return Selection::Route(route);
+            }
+            if route.method_mismatch == MethodMismatch::NotAllowed || allowed.is_some() {
+                let allowed = allowed.get_or_insert_with(Vec::new);
+                for method in matchers.methods.iter().flatten() {
+                    if !allowed.contains(method) {
+                        allowed.push(method.clone());
+                    }
+                }
+            }
+        }
+        match allowed {
+            Some(methods) => Selection::MethodNotAllowed(methods),
+            None => Selection::Miss,
+        }
     }
 }
 
+/// Outcome of [`Router::select`].
+pub enum Selection<'a> {
+    Route(&'a RouteHandle),
+    /// A route matched everything but the method; carries the methods for `Allow`.
+    MethodNotAllowed(Vec<Method>),
+    Miss,
+}
+
 #[derive(Clone)]
 pub struct RouteHandle {
     pub name: String,
     matchers: RouteMatchers,
     pub upstream: UpstreamEndpoint,
-    pub timeout: Option<Duration>,
+    /// Picks the target per request for `round_robin`, `hash`, and `srv` upstreams.
+    pub(crate) balancer: Option<Arc<Balancer>>,
+    pub websocket: Arc<WebsocketLimits>,
+    /// How upstream response bodies are relayed.
+    pub(crate) streaming: Arc<Streaming>,
+    /// Whether idempotent requests received in TLS early data may be served.
+    pub early_data: bool,
+    method_mismatch: MethodMismatch,
+    /// Whether `Via` headers are left out on this route.
+    pub suppress_via: bool,
+    /// Retry policy and budget shared by the route's requests.
+    pub(crate) retry: Option<Arc<Retry>>,
+    /// Classification labels added to the route's metrics and access events.
+    pub labels: Arc<BTreeMap<String, String>>,
+    /// Route filter chain terminating in the upstream service.
+    pub service: JesterService,
 }
 
 impl RouteHandle {
-    pub fn timeout(&self) -> Option<Duration> {
-        self.timeout
-    }
-}
-
-impl TryFrom<&Route> for RouteHandle {
-    type Error = anyhow::Error;
-
-    fn try_from(route: &Route) -> Result<Self> {
+    fn build(route: &Route, registry: &FilterRegistry, upstream: JesterService) -> Result<Self> {
         Ok(Self {
             name: route.name.clone(),
             matchers: RouteMatchers::try_from(&route.matchers)?,
             upstream: UpstreamEndpoint::try_from(&route.upstream)?,
-            timeout: route.request_timeout(),
+            balancer: Balancer::new(&route.upstream.strategy)?.map(Arc::new),
+            websocket: Arc::new(route.websocket.clone()),
+            streaming: Arc::new(route.streaming.clone()),
+            early_data: route.early_data,
+            method_mismatch: route.method_mismatch,
+            suppress_via: route.suppress_via,
+            retry: match &route.retry {
+                Some(policy) => {
+                    let advisor = registry.retry_advisor(policy).with_context(|| {
+                        format!("invalid retry policy on route `{}`", route.name)
+                    })?;
+                    Some(Arc::new(Retry::new(
+                        &route.name,
+                        &route.labels,
+                        policy,
+                        advisor,
+                    )))
+                }
+                None => None,
+            },
+            labels: Arc::new(route.labels.clone()),
+            service: profile::wrap(
+                format!("route:{}", route.name),
+                registry.build_route_chain(route, upstream)?,
+            ),
         })
     }
 }
@@ -59,17 +140,46 @@ impl TryFrom<&Route> for RouteHandle {
 #[derive(Clone)]
 pub struct UpstreamEndpoint {
     pub uri: Uri,
+    pub keep_alive: bool,
+    pub host_header: HostHeader,
+    pub tls: UpstreamTls,
+    /// The route's overrides of the global pool settings.
+    pub pool: UpstreamPool,
+    pub protocol: UpstreamProtocol,
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
 }
 
 impl TryFrom<&Upstream> for UpstreamEndpoint {
     type Error = anyhow::Error;
 
     fn try_from(value: &Upstream) -> Result<Self> {
-        let target = value
-            .single_target()
-            .context("v0.0.1 only supports a single upstream target per route")?;
-        let uri = Uri::from_str(target)?;
-        Ok(Self { uri })
+        let placeholder;
+        let target = match &value.strategy {
+            UpstreamStrategy::Single { target } => target,
+            // Stands in until the route's balancer picks a target.
+            UpstreamStrategy::RoundRobin { targets } | UpstreamStrategy::Hash { targets, .. }
+                if !targets.is_empty() =>
+            {
+                &targets[0].url
+            }
+            // Stands in until the first lookup; the balancer refuses requests
+            // before then.
+            UpstreamStrategy::Srv { scheme, .. } | UpstreamStrategy::Consul { scheme, .. } => {
+                placeholder = format!("{scheme}://localhost");
+                &placeholder
+            }
+            strategy => bail!("upstream strategy `{strategy:?}` is not supported"),
+        };
+        let uri = crate::client::parse_target(target)?;
+        Ok(Self {
+            uri,
+            keep_alive: value.keep_alive,
+            host_header: value.host_header.clone(),
+            tls: value.tls.clone(),
+            pool: value.pool,
+            protocol: value.protocol,
+            proxy_protocol: value.proxy_protocol,
+        })
     }
 }
 
@@ -79,10 +189,12 @@ struct RouteMatchers {
     path_prefix: Option<String>,
     methods: Option<Vec<Method>>,
     headers: Vec<HeaderPredicate>,
+    schedule: Option<Schedule>,
 }
 
 impl RouteMatchers {
-    fn matches(&self, host: &str, path: &str, method: &Method, headers: &HeaderMap) -> bool {
+    /// Everything but the method.
+    fn matches_resource(&self, host: &str, path: &str, headers: &HeaderMap) -> bool {
         if !self.hosts.is_empty() && !self.hosts.iter().any(|matcher| matcher.matches(host)) {
             return false;
         }
@@ -93,19 +205,19 @@ impl RouteMatchers {
             }
         }
 
-        if let Some(methods) = &self.methods {
-            if !methods.iter().any(|allowed| allowed == method) {
-                return false;
-            }
-        }
-
         for predicate in &self.headers {
             if !predicate.matches(headers) {
                 return false;
             }
         }
 
-        true
+        self.schedule.as_ref().is_none_or(Schedule::is_active)
+    }
+
+    fn matches_method(&self, method: &Method) -> bool {
+        self.methods
+            .as_ref()
+            .is_none_or(|methods| methods.iter().any(|allowed| allowed == method))
     }
 }
 
@@ -121,12 +233,7 @@ impl TryFrom<&Matchers> for RouteMatchers {
             .map(|pattern| HostMatcher::new(pattern.as_str()))
             .collect::<Result<Vec<_>>>()?;
 
-        let methods = matchers.methods.as_ref().map(|items| {
-            items
-                .iter()
-                .filter_map(|m| Method::from_bytes(m.as_bytes()).ok())
-                .collect::<Vec<_>>()
-        });
+        let methods = matchers.parsed_methods()?;
 
         let headers = matchers
             .headers
@@ -141,12 +248,13 @@ impl TryFrom<&Matchers> for RouteMatchers {
             path_prefix: matchers.path_prefix.clone(),
             methods,
             headers,
+            schedule: matchers.time.as_ref().map(Schedule::new).transpose()?,
         })
     }
 }
 
 #[derive(Clone)]
-enum HostMatcher {
+pub(crate) enum HostMatcher {
     Any,
     Exact(String),
     Wildcard(String),
@@ -154,7 +262,7 @@ enum HostMatcher {
 }
 
 impl HostMatcher {
-    fn new(pattern: &str) -> Result<Self> {
+    pub(crate) fn new(pattern: &str) -> Result<Self> {
         if pattern == "*" {
             return Ok(Self::Any);
         }
@@ -167,7 +275,7 @@ impl HostMatcher {
         Ok(Self::Exact(pattern.to_string()))
     }
 
-    fn matches(&self, host: &str) -> bool {
+    pub(crate) fn matches(&self, host: &str) -> bool {
         match self {
             HostMatcher::Any => true,
             HostMatcher::Exact(value) => host.eq_ignore_ascii_case(value),
@@ -219,15 +327,12 @@ mod tests {
             path_prefix: Some("/api".into()),
             methods: None,
             headers: None,
+            time: None,
         };
         let rm = RouteMatchers::try_from(&matchers).unwrap();
-        let request = Request::builder().uri("/api/test").body(()).unwrap();
-        rm.matches(
-            host,
-            request.uri().path(),
-            request.method(),
-            request.headers(),
-        )
+        let request = Request::builder().uri(path).body(()).unwrap();
+        rm.matches_resource(host, request.uri().path(), request.headers())
+            && rm.matches_method(request.method())
     }
 
     #[test]
@@ -240,4 +345,25 @@ mod tests {
     fn exact_hosts_match_case_insensitive() {
         assert!(test_matcher(vec!["Example.com"], "example.com", "/api"));
     }
+
+    #[test]
+    fn methods_match_in_uppercase_whatever_the_config_says() {
+        let matchers = Matchers {
+            methods: Some(vec!["get".into(), "Purge".into()]),
+            ..Default::default()
+        };
+        let rm = RouteMatchers::try_from(&matchers).unwrap();
+        assert!(rm.matches_method(&Method::GET));
+        assert!(rm.matches_method(&Method::from_bytes(b"PURGE").unwrap()));
+        assert!(!rm.matches_method(&Method::POST));
+
+        // A likely typo is only linted; it matches itself, not `GET`.
+        let typo = Matchers {
+            methods: Some(vec!["gett".into()]),
+            ..Default::default()
+        };
+        let rm = RouteMatchers::try_from(&typo).unwrap();
+        assert!(rm.matches_method(&Method::from_bytes(b"GETT").unwrap()));
+        assert!(!rm.matches_method(&Method::GET));
+    }
 }
@@ -0,0 +1,307 @@
+//! Time-of-day route matchers (`[routes.matchers.time]`): cron expressions
+//! naming the minutes a route is active, and daily windows, both read in a
+//! configured time zone.
+
+use anyhow::{bail, Context, Result};
+use jiff::{civil::DateTime, tz::TimeZone, Timestamp};
+
+use crate::config::{TimeMatch, TimeWindow};
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// When a route with a time matcher takes requests.
+#[derive(Clone)]
+pub(crate) struct Schedule {
+    zone: TimeZone,
+    cron: Vec<Cron>,
+    windows: Vec<Window>,
+}
+
+impl Schedule {
+    pub(crate) fn new(time: &TimeMatch) -> Result<Self> {
+        let zone = match &time.timezone {
+            Some(name) => {
+                TimeZone::get(name).with_context(|| format!("unknown time zone `{name}`"))?
+            }
+            None => TimeZone::UTC,
+        };
+        if time.cron.is_empty() && time.windows.is_empty() {
+            bail!("time matcher needs `cron` or `windows`");
+        }
+        let cron = time
+            .cron
+            .iter()
+            .map(|expr| Cron::parse(expr).with_context(|| format!("invalid cron `{expr}`")))
+            .collect::<Result<_>>()?;
+        let windows = time
+            .windows
+            .iter()
+            .map(Window::try_from)
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            zone,
+            cron,
+            windows,
+        })
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.is_active_at(Timestamp::now())
+    }
+
+    fn is_active_at(&self, at: Timestamp) -> bool {
+        let local = at.to_zoned(self.zone.clone()).datetime();
+        self.cron.iter().any(|cron| cron.matches(local))
+            || self.windows.iter().any(|window| window.contains(local))
+    }
+}
+
+/// A five-field cron expression, `minute hour day-of-month month
+/// day-of-week`, as bit sets of the values each field allows.
+#[derive(Clone)]
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether day-of-month and day-of-week were both restricted, in which
+    /// case cron matches a day allowed by either.
+    either_day: bool,
+}
+
+impl Cron {
+    fn parse(expr: &str) -> Result<Self> {
+        let fields = expr.split_whitespace().collect::<Vec<_>>();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            bail!("expected 5 fields, found {}", fields.len());
+        };
+        let weekdays = field(weekday, 0, 7, &WEEKDAYS).context("day of week")?;
+        Ok(Self {
+            minutes: field(minute, 0, 59, &[]).context("minute")?,
+            hours: field(hour, 0, 23, &[]).context("hour")?,
+            days: field(day, 1, 31, &[]).context("day of month")?,
+            months: field(month, 1, 12, &MONTHS).context("month")?,
+            // 7 is Sunday too.
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            either_day: !day.starts_with('*') && !weekday.starts_with('*'),
+        })
+    }
+
+    fn matches(&self, at: DateTime) -> bool {
+        let has = |set: u64, value: i8| set & (1 << value) != 0;
+        let day = has(self.days, at.day());
+        let weekday = has(self.weekdays, at.weekday().to_sunday_zero_offset());
+        let day = if self.either_day {
+            day || weekday
+        } else {
+            day && weekday
+        };
+        has(self.minutes, at.minute())
+            && has(self.hours, at.hour())
+            && has(self.months, at.month())
+            && day
+    }
+}
+
+/// Parses one cron field, a comma-separated list of `*`, `n`, `a-b`, each
+/// optionally stepped with `/step`. Values may be given by `names`, counted
+/// from `min`.
+fn field(field: &str, min: u8, max: u8, names: &[&str]) -> Result<u64> {
+    let value = |text: &str| -> Result<u8> {
+        let named = names
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(text))
+            .map(|index| index as u8 + min);
+        let value = match named {
+            Some(value) => value,
+            None => text
+                .parse()
+                .with_context(|| format!("`{text}` is not a number"))?,
+        };
+        if !(min..=max).contains(&value) {
+            bail!("{value} is outside {min}-{max}");
+        }
+        Ok(value)
+    };
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u8>().ok().filter(|step| *step > 0)),
+            None => (part, Some(1)),
+        };
+        let stepped = part.contains('/');
+        let Some(step) = step else {
+            bail!("invalid step in `{part}`");
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            // `n/step` runs from n to the end.
+            None if stepped => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if first > last {
+            bail!("`{range}` runs backwards");
+        }
+        for value in (first..=last).step_by(usize::from(step)) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+/// A daily window from `from` until `to`, running past midnight when `to` is
+/// earlier, starting on `days` (all days when empty).
+#[derive(Clone)]
+struct Window {
+    /// Minutes since midnight.
+    from: u16,
+    to: u16,
+    /// Bit set of weekdays, Sunday first.
+    days: u8,
+}
+
+impl TryFrom<&TimeWindow> for Window {
+    type Error = anyhow::Error;
+
+    fn try_from(window: &TimeWindow) -> Result<Self> {
+        let minutes = |text: &str| -> Result<u16> {
+            let parsed = text.split_once(':').and_then(|(hour, minute)| {
+                let hour = hour.parse::<u16>().ok().filter(|hour| *hour < 24)?;
+                let minute = minute.parse::<u16>().ok().filter(|minute| *minute < 60)?;
+                Some(hour * 60 + minute)
+            });
+            parsed.with_context(|| format!("`{text}` is not a time of day like `02:30`"))
+        };
+        let (from, to) = (minutes(&window.from)?, minutes(&window.to)?);
+        if from == to {
+            bail!("window from `{}` to `{}` is empty", window.from, window.to);
+        }
+        let mut days = 0;
+        for day in &window.days {
+            let Some(index) = WEEKDAYS
+                .iter()
+                .position(|name| name.eq_ignore_ascii_case(day))
+            else {
+                bail!("`{day}` is not a weekday like `mon`");
+            };
+            days |= 1 << index;
+        }
+        Ok(Self {
+            from,
+            to,
+            days: if days == 0 { 0x7f } else { days },
+        })
+    }
+}
+
+impl Window {
+    fn contains(&self, at: DateTime) -> bool {
+        let minute = at.hour() as u16 * 60 + at.minute() as u16;
+        let weekday = at.weekday().to_sunday_zero_offset();
+        let starts_on = |weekday: i8| self.days & (1 << weekday) != 0;
+        if self.from < self.to {
+            return starts_on(weekday) && (self.from..self.to).contains(&minute);
+        }
+        // Past midnight, the window belongs to the day it started.
+        (minute >= self.from && starts_on(weekday))
+            || (minute < self.to && starts_on((weekday + 6) % 7))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(zone: &str, datetime: &str) -> Timestamp {
+        datetime
+            .parse::<DateTime>()
+            .unwrap()
+            .to_zoned(TimeZone::get(zone).unwrap())
+            .unwrap()
+            .timestamp()
+    }
+
+    fn schedule(timezone: Option<&str>, cron: &[&str], windows: Vec<TimeWindow>) -> Schedule {
+        Schedule::new(&TimeMatch {
+            timezone: timezone.map(String::from),
+            cron: cron.iter().map(|expr| expr.to_string()).collect(),
+            windows,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn cron_names_the_active_minutes() {
+        // Sundays 02:00-02:59 UTC; 2026-10-18 is a Sunday.
+        let maintenance = schedule(None, &["* 2 * * sun"], Vec::new());
+        assert!(maintenance.is_active_at(at("UTC", "2026-10-18T02:00")));
+        assert!(maintenance.is_active_at(at("UTC", "2026-10-18T02:59")));
+        assert!(!maintenance.is_active_at(at("UTC", "2026-10-18T03:00")));
+        assert!(!maintenance.is_active_at(at("UTC", "2026-10-19T02:30")));
+
+        let steps = schedule(None, &["*/15 9-17 1,15 * *"], Vec::new());
+        assert!(steps.is_active_at(at("UTC", "2026-10-15T09:45")));
+        assert!(!steps.is_active_at(at("UTC", "2026-10-15T09:46")));
+        assert!(!steps.is_active_at(at("UTC", "2026-10-16T09:45")));
+
+        // Both day fields restricted: either one will do, as in cron.
+        let either = schedule(None, &["0 0 1 * mon"], Vec::new());
+        assert!(either.is_active_at(at("UTC", "2026-10-01T00:00")));
+        assert!(either.is_active_at(at("UTC", "2026-10-19T00:00")));
+        assert!(!either.is_active_at(at("UTC", "2026-10-20T00:00")));
+    }
+
+    #[test]
+    fn windows_run_past_midnight_in_their_time_zone() {
+        let nightly = schedule(
+            Some("Europe/Berlin"),
+            &[],
+            vec![TimeWindow {
+                from: "22:00".into(),
+                to: "06:00".into(),
+                days: vec!["fri".into()],
+            }],
+        );
+        // Friday 2026-10-16, 22:30 in Berlin is 20:30 UTC.
+        assert!(nightly.is_active_at(at("UTC", "2026-10-16T20:30")));
+        assert!(nightly.is_active_at(at("Europe/Berlin", "2026-10-17T05:59")));
+        assert!(!nightly.is_active_at(at("Europe/Berlin", "2026-10-17T06:00")));
+        assert!(!nightly.is_active_at(at("Europe/Berlin", "2026-10-17T22:30")));
+        assert!(!nightly.is_active_at(at("UTC", "2026-10-16T19:59")));
+    }
+
+    #[test]
+    fn invalid_schedules_are_refused() {
+        let parse = |timezone: Option<&str>, cron: &str| {
+            Schedule::new(&TimeMatch {
+                timezone: timezone.map(String::from),
+                cron: vec![cron.into()],
+                windows: Vec::new(),
+            })
+        };
+        assert!(parse(None, "0 2 * * sun").is_ok());
+        assert!(parse(Some("Mars/Olympus_Mons"), "0 2 * * *").is_err());
+        for cron in [
+            "0 2 * *",
+            "60 * * * *",
+            "0 5-2 * * *",
+            "*/0 * * * *",
+            "0 0 * * funday",
+        ] {
+            assert!(parse(None, cron).is_err(), "{cron}");
+        }
+        assert!(Schedule::new(&TimeMatch::default()).is_err());
+        let window = |from: &str, to: &str| TimeWindow {
+            from: from.into(),
+            to: to.into(),
+            days: Vec::new(),
+        };
+        assert!(Window::try_from(&window("24:00", "01:00")).is_err());
+        assert!(Window::try_from(&window("02:00", "02:00")).is_err());
+    }
+}
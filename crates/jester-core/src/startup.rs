@@ -0,0 +1,333 @@
+//! The summary a proxy logs once its listeners are bound, also served at
+//! `GET /startup` on the admin API.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{
+    config::{Config, Filter, Listener, ListenerKind, ListenerProtocol, Route},
+    filter::FilterRegistry,
+    proxy::load_certs,
+    tls::CertInfo,
+};
+
+/// Certificates closer than this to expiry count as degraded.
+const EXPIRY_WARNING_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// What a proxy came up with.
+#[derive(Debug, Clone, Serialize)]
+pub struct StartupReport {
+    pub version: &'static str,
+    pub listeners: Vec<ListenerReport>,
+    pub routes: usize,
+    /// Plugins behind the configured filters, by kind and name.
+    pub plugins: Vec<PluginReport>,
+    pub degraded: Vec<Degraded>,
+    /// What `[hardening]` applied after binding; `None` when it is not set.
+    pub hardening: Option<HardeningReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListenerReport {
+    pub name: String,
+    pub bind: String,
+    pub kind: ListenerKind,
+    pub protocol: ListenerProtocol,
+    /// Bound address; `None` when binding failed under
+    /// [`BindPolicy::BestEffort`](crate::proxy::BindPolicy::BestEffort).
+    pub addr: Option<SocketAddr>,
+    pub server_names: Vec<String>,
+    /// The served leaf certificate; `None` if it could not be read back.
+    pub certificate: Option<CertificateReport>,
+    /// Routes whose hosts the listener's `server_names` can reach.
+    pub routes: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateReport {
+    pub path: String,
+    /// Short form of the subject, e.g. `CN=example.com`.
+    pub subject: String,
+    /// `notAfter`, in seconds since the epoch.
+    pub not_after: u64,
+    /// Certificates in the served chain, leaf included.
+    pub chain: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PluginReport {
+    pub name: String,
+    /// `builtin` or `inproc`.
+    pub kind: &'static str,
+    pub version: String,
+    /// Filters configured with this plugin.
+    pub filters: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HardeningReport {
+    pub capabilities_dropped: bool,
+    pub chroot: Option<String>,
+    pub landlock: Option<LandlockReport>,
+    /// System calls the seccomp filter refuses; empty without one.
+    pub seccomp_denied: Vec<&'static str>,
+    /// Always true: hardened configs may not enable dylib plugins.
+    pub native_plugins_refused: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LandlockReport {
+    /// ABI version the kernel offers.
+    pub abi: i32,
+    /// Paths given a rule; those that did not exist are left out.
+    pub read_paths: Vec<String>,
+    pub write_paths: Vec<String>,
+}
+
+/// A subsystem running in a reduced mode.
+#[derive(Debug, Clone, Serialize)]
+pub struct Degraded {
+    pub subsystem: String,
+    pub reason: String,
+}
+
+impl Degraded {
+    pub fn new(subsystem: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self {
+            subsystem: subsystem.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl StartupReport {
+    /// Builds the report for `config` with listeners bound at `bound`;
+    /// `degraded` carries what the embedder reported.
+    pub(crate) fn new(
+        config: &Config,
+        registry: &FilterRegistry,
+        bound: &[(String, SocketAddr)],
+        mut degraded: Vec<Degraded>,
+        hardening: Option<HardeningReport>,
+    ) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let listeners = config
+            .listeners
+            .iter()
+            .map(|listener| {
+                let report = ListenerReport::new(listener, &config.routes, bound);
+                let subsystem = format!("listener `{}`", listener.name);
+                if report.addr.is_none() {
+                    degraded.push(Degraded::new(
+                        &subsystem,
+                        format!("failed to bind {}", listener.bind),
+                    ));
+                }
+                match &report.certificate {
+                    None if listener.tls.is_none() => {}
+                    None => degraded.push(Degraded::new(
+                        &subsystem,
+                        "certificate could not be read back",
+                    )),
+                    Some(cert) if cert.not_after <= now => {
+                        degraded.push(Degraded::new(&subsystem, "certificate has expired"))
+                    }
+                    Some(cert) if cert.not_after - now < EXPIRY_WARNING_SECS => {
+                        degraded.push(Degraded::new(
+                            &subsystem,
+                            format!(
+                                "certificate expires in {} hours",
+                                (cert.not_after - now) / 3600
+                            ),
+                        ))
+                    }
+                    Some(_) => {}
+                }
+                report
+            })
+            .collect();
+        Self {
+            version: crate::version(),
+            listeners,
+            routes: config.routes.len(),
+            plugins: plugins(config, registry),
+            degraded,
+            hardening,
+        }
+    }
+
+    /// Logs the report: a line per listener, the plugins, a warning per
+    /// degraded subsystem, and a closing summary.
+    pub(crate) fn log(&self) {
+        for listener in &self.listeners {
+            let Some(addr) = listener.addr else {
+                continue;
+            };
+            tracing::info!(
+                listener = listener.name,
+                %addr,
+                server_names = ?listener.server_names,
+                certificate = listener.certificate.as_ref().map(|cert| cert.subject.as_str()),
+                not_after = listener.certificate.as_ref().map(|cert| cert.not_after),
+                routes = listener.routes,
+                "listener ready"
+            );
+        }
+        let plugins: Vec<String> = self
+            .plugins
+            .iter()
+            .map(|plugin| format!("{}@{}", plugin.name, plugin.version))
+            .collect();
+        tracing::info!(plugins = plugins.join(", "), "plugins loaded");
+        if let Some(hardening) = &self.hardening {
+            tracing::info!(
+                capabilities_dropped = hardening.capabilities_dropped,
+                chroot = hardening.chroot,
+                landlock_abi = hardening.landlock.as_ref().map(|landlock| landlock.abi),
+                landlock_read = ?hardening.landlock.as_ref().map(|landlock| &landlock.read_paths),
+                landlock_write = ?hardening.landlock.as_ref().map(|landlock| &landlock.write_paths),
+                seccomp_denied = hardening.seccomp_denied.len(),
+                "hardening applied"
+            );
+        }
+        for degraded in &self.degraded {
+            tracing::warn!(
+                subsystem = degraded.subsystem,
+                reason = degraded.reason,
+                "running degraded"
+            );
+        }
+        tracing::info!(
+            version = self.version,
+            listeners = self.listeners.iter().filter(|l| l.addr.is_some()).count(),
+            routes = self.routes,
+            degraded = self.degraded.len(),
+            "jester started"
+        );
+    }
+}
+
+impl ListenerReport {
+    fn new(listener: &Listener, routes: &[Route], bound: &[(String, SocketAddr)]) -> Self {
+        let server_names: Vec<String> = listener
+            .server_names
+            .iter()
+            .map(|name| name.to_ascii_lowercase())
+            .collect();
+        Self {
+            name: listener.name.clone(),
+            bind: listener.bind.clone(),
+            kind: listener.kind,
+            protocol: listener.protocol,
+            addr: bound
+                .iter()
+                .find(|(name, _)| *name == listener.name)
+                .map(|(_, addr)| *addr),
+            certificate: listener.tls.as_ref().and_then(|tls| {
+                let certs = load_certs(&tls.cert).ok()?;
+                let info = CertInfo::parse(&certs.first()?.0)?;
+                let intermediates = match &tls.intermediates {
+                    Some(path) => load_certs(path).ok()?,
+                    None => Vec::new(),
+                };
+                let chain = crate::tls::complete_chain(certs.clone(), &intermediates).ok()?;
+                Some(CertificateReport {
+                    path: tls.cert.clone(),
+                    subject: info.subject_name(),
+                    not_after: info.not_after,
+                    chain: chain.len(),
+                })
+            }),
+            routes: match listener.kind {
+                ListenerKind::Proxy => routes
+                    .iter()
+                    .filter(|route| reachable(route.matchers.hosts.as_deref(), &server_names))
+                    .count(),
+                ListenerKind::HttpsRedirect | ListenerKind::Stream | ListenerKind::Udp => 0,
+            },
+            server_names,
+        }
+    }
+}
+
+/// Whether a route matching `hosts` can see requests from a listener
+/// answering `server_names`; a listener without names sees every host.
+fn reachable(hosts: Option<&[String]>, server_names: &[String]) -> bool {
+    let (Some(hosts), false) = (hosts, server_names.is_empty()) else {
+        return true;
+    };
+    let covers = |pattern: &str, name: &str| {
+        pattern
+            .strip_prefix("*.")
+            .is_some_and(|parent| name.ends_with(&format!(".{parent}")))
+    };
+    hosts.iter().any(|host| {
+        let host = host.to_ascii_lowercase();
+        host == "*"
+            || server_names
+                .iter()
+                .any(|name| host == *name || covers(&host, name) || covers(name, &host))
+    })
+}
+
+/// The plugins behind every configured filter, sorted by kind and name.
+fn plugins(config: &Config, registry: &FilterRegistry) -> Vec<PluginReport> {
+    let filters = config.filters.iter().chain(
+        config
+            .routes
+            .iter()
+            .flat_map(|route| route.filters.iter().chain(&route.response_filters)),
+    );
+    let mut plugins: BTreeMap<(&'static str, &'static str), PluginReport> = BTreeMap::new();
+    for filter in filters {
+        let Some(plugin) = registry.plugin(filter) else {
+            continue;
+        };
+        let kind = match filter {
+            Filter::Builtin { .. } => "builtin",
+            Filter::InProc { .. } | Filter::Wasm { .. } => "inproc",
+        };
+        plugins
+            .entry((kind, plugin.name()))
+            .or_insert_with(|| PluginReport {
+                name: plugin.name().to_string(),
+                kind,
+                version: plugin.version().to_string(),
+                filters: 0,
+            })
+            .filters += 1;
+    }
+    plugins.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_count_only_for_listeners_that_can_reach_their_hosts() {
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let shop = names(&["shop.example.com"]);
+        let tenants = names(&["*.tenants.example.com"]);
+
+        assert!(reachable(None, &shop));
+        assert!(reachable(Some(&names(&["api.example.com"])), &[]));
+        assert!(reachable(Some(&names(&["Shop.Example.com"])), &shop));
+        assert!(reachable(Some(&names(&["*.example.com"])), &shop));
+        assert!(reachable(
+            Some(&names(&["acme.tenants.example.com"])),
+            &tenants
+        ));
+        assert!(reachable(Some(&names(&["*"])), &tenants));
+        assert!(!reachable(Some(&names(&["api.example.com"])), &shop));
+        assert!(!reachable(Some(&names(&["tenants.example.com"])), &tenants));
+    }
+}
@@ -0,0 +1,543 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use http::{header, HeaderMap, Uri};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use metrics::Label;
+use prometheus_client::{
+    encoding::text,
+    metrics::{exemplar::HistogramWithExemplars, family::Family, histogram::exponential_buckets},
+    registry::Registry,
+};
+
+use crate::plugin::{BoxError, HttpResponse, ProxyBody};
+
+/// Live runtime counters shared across listeners, filters, and the upstream service.
+///
+/// A clone is inserted into every request's extensions so filters (load shedding,
+/// adaptive concurrency, ...) can read current load without extra plumbing.
+#[derive(Clone, Default)]
+pub struct RuntimeStats {
+    inner: Arc<StatsInner>,
+}
+
+#[derive(Default)]
+struct StatsInner {
+    routes: Gauges,
+    targets: Gauges,
+    connections: Gauges,
+    clients: Gauges,
+    websockets: Gauges,
+    websocket_clients: Mutex<HashMap<(String, IpAddr), usize>>,
+    latency: LatencyHistograms,
+}
+
+impl RuntimeStats {
+    /// Requests currently being handled by `route`.
+    pub fn route_inflight(&self, route: &str) -> usize {
+        self.inner.routes.get(route)
+    }
+
+    /// Requests currently outstanding against upstream `target`.
+    pub fn target_inflight(&self, target: &str) -> usize {
+        self.inner.targets.get(target)
+    }
+
+    /// Open connections (pooled or busy) to upstream `target`.
+    pub fn upstream_connections(&self, target: &str) -> usize {
+        self.inner.connections.get(target)
+    }
+
+    /// Open client connections on `listener`.
+    pub fn client_connections(&self, listener: &str) -> usize {
+        self.inner.clients.get(listener)
+    }
+
+    /// Open WebSocket connections on `route`.
+    pub fn websockets(&self, route: &str) -> usize {
+        self.inner.websockets.get(route)
+    }
+
+    /// In-flight counts for every route seen so far.
+    pub fn routes(&self) -> HashMap<String, usize> {
+        self.inner.routes.snapshot()
+    }
+
+    /// In-flight counts for every upstream target seen so far.
+    pub fn targets(&self) -> HashMap<String, usize> {
+        self.inner.targets.snapshot()
+    }
+
+    /// Open client connections for every listener seen so far.
+    pub fn listeners(&self) -> HashMap<String, usize> {
+        self.inner.clients.snapshot()
+    }
+
+    /// The request latency histograms in OpenMetrics text format, with the
+    /// trace ID of a recent request in each bucket as its exemplar.
+    pub fn encode_latency(&self) -> String {
+        let mut out = String::new();
+        text::encode(&mut out, &self.inner.latency.registry)
+            .expect("writing to a String cannot fail");
+        out
+    }
+
+    /// Records a request on `route` that took `seconds` to respond, in
+    /// `jester_request_duration_seconds{route}` with the route's `labels`.
+    /// `trace_id`, when the request had one, becomes the bucket's exemplar.
+    pub(crate) fn observe_latency(
+        &self,
+        route: &str,
+        labels: &BTreeMap<String, String>,
+        seconds: f64,
+        trace_id: Option<&str>,
+    ) {
+        let route_labels = route_labels(route, labels, []);
+        let key = route_labels
+            .iter()
+            .map(|label| (label.key().to_string(), label.value().to_string()))
+            .collect();
+        metrics::histogram!("jester_request_duration_seconds", route_labels).record(seconds);
+        let exemplar = trace_id.map(|id| vec![("trace_id".to_string(), id.to_string())]);
+        self.inner
+            .latency
+            .family
+            .get_or_create(&key)
+            .observe(seconds, exemplar);
+    }
+
+    pub(crate) fn track_route(&self, route: &str) -> InflightGuard {
+        self.inner
+            .routes
+            .track(route, "jester_route_inflight_requests", "route")
+    }
+
+    pub(crate) fn track_target(&self, target: &str) -> InflightGuard {
+        self.inner
+            .targets
+            .track(target, "jester_upstream_inflight_requests", "target")
+    }
+
+    pub(crate) fn track_connection(&self, target: &str) -> InflightGuard {
+        self.inner
+            .connections
+            .track(target, "jester_upstream_open_connections", "target")
+    }
+
+    pub(crate) fn track_client_connection(&self, listener: &str) -> InflightGuard {
+        self.inner
+            .clients
+            .track(listener, "jester_client_open_connections", "listener")
+    }
+
+    pub(crate) fn track_websocket(&self, route: &str) -> InflightGuard {
+        self.inner
+            .websockets
+            .track(route, "jester_websocket_open_connections", "route")
+    }
+
+    /// Reserves one of `max` WebSocket slots for `client` on `route`.
+    pub(crate) fn acquire_websocket_slot(
+        &self,
+        route: &str,
+        client: IpAddr,
+        max: usize,
+    ) -> Option<WebsocketSlot> {
+        let key = (route.to_string(), client);
+        let mut clients = self
+            .inner
+            .websocket_clients
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let count = clients.entry(key.clone()).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(WebsocketSlot {
+            stats: self.clone(),
+            key,
+        })
+    }
+}
+
+/// Releases a per-client WebSocket slot when dropped.
+pub(crate) struct WebsocketSlot {
+    stats: RuntimeStats,
+    key: (String, IpAddr),
+}
+
+impl Drop for WebsocketSlot {
+    fn drop(&mut self) {
+        let mut clients = self
+            .stats
+            .inner
+            .websocket_clients
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(count) = clients.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                clients.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Key identifying an upstream target in stats and metrics: `scheme://authority`,
+/// or `unix://<socket path>`.
+pub fn target_key(uri: &Uri) -> String {
+    if let Some(path) = crate::client::socket_path(uri) {
+        return format!("unix://{}", path.display());
+    }
+    match (uri.scheme_str(), uri.authority()) {
+        (Some(scheme), Some(authority)) => format!("{scheme}://{authority}"),
+        _ => uri.to_string(),
+    }
+}
+
+/// Metric labels for a request on `route`: its name, then `extra`, then the
+/// route's configured `labels`.
+pub(crate) fn route_labels<const N: usize>(
+    route: &str,
+    labels: &BTreeMap<String, String>,
+    extra: [(&'static str, String); N],
+) -> Vec<Label> {
+    std::iter::once(Label::new("route", route.to_string()))
+        .chain(extra.into_iter().map(|(key, value)| Label::new(key, value)))
+        .chain(
+            labels
+                .iter()
+                .map(|(key, value)| Label::new(key.clone(), value.clone())),
+        )
+        .collect()
+}
+
+/// Records a routed response in `jester_responses_by_content_type_total{route,content_type}`
+/// and, once its body has been sent (or abandoned), its size in
+/// `jester_response_bytes{route}`, both with the route's `labels`.
+pub(crate) fn meter_response(
+    route: &str,
+    labels: &BTreeMap<String, String>,
+    response: HttpResponse,
+) -> HttpResponse {
+    let content_type = content_type_label(response.headers());
+    metrics::counter!(
+        "jester_responses_by_content_type_total",
+        route_labels(route, labels, [("content_type", content_type)])
+    )
+    .increment(1);
+    let histogram = metrics::histogram!("jester_response_bytes", route_labels(route, labels, []));
+    response.map(|body| {
+        MeteredBody {
+            inner: body,
+            bytes: 0,
+            histogram,
+        }
+        .boxed()
+    })
+}
+
+/// Metric label for a `Content-Type`: the lowercased media type without
+/// parameters, `none` when absent, and `other` when it is not `type/subtype`.
+fn content_type_label(headers: &HeaderMap) -> String {
+    let Some(value) = headers.get(header::CONTENT_TYPE) else {
+        return "none".into();
+    };
+    let media_type = value
+        .to_str()
+        .unwrap_or_default()
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let token = |part: &str| {
+        !part.is_empty()
+            && part
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$&^_.+-".contains(&b))
+    };
+    match media_type.split_once('/') {
+        Some((kind, subtype)) if media_type.len() <= 64 && token(kind) && token(subtype) => {
+            media_type
+        }
+        _ => "other".into(),
+    }
+}
+
+/// Counts the data bytes of a response body as they are sent.
+struct MeteredBody {
+    inner: ProxyBody,
+    bytes: u64,
+    histogram: metrics::Histogram,
+}
+
+impl Body for MeteredBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(data) = frame.data_ref() {
+                self.bytes += data.len() as u64;
+            }
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for MeteredBody {
+    fn drop(&mut self) {
+        self.histogram.record(self.bytes as f64);
+    }
+}
+
+/// Keeps `guard` alive until `response`'s body has been sent (or abandoned),
+/// so streamed responses count as in flight until their last byte.
+pub(crate) fn hold_until_sent(response: HttpResponse, guard: InflightGuard) -> HttpResponse {
+    response.map(|body| {
+        GuardedBody {
+            inner: body,
+            _guard: guard,
+        }
+        .boxed()
+    })
+}
+
+struct GuardedBody {
+    inner: ProxyBody,
+    _guard: InflightGuard,
+}
+
+impl Body for GuardedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+type LabelPairs = Vec<(String, String)>;
+
+/// `jester_request_duration_seconds` kept alongside the `metrics` facade,
+/// which cannot carry exemplars.
+struct LatencyHistograms {
+    registry: Registry,
+    family: Family<
+        LabelPairs,
+        HistogramWithExemplars<LabelPairs>,
+        fn() -> HistogramWithExemplars<LabelPairs>,
+    >,
+}
+
+impl Default for LatencyHistograms {
+    fn default() -> Self {
+        let family: Family<_, _, fn() -> _> = Family::new_with_constructor(|| {
+            // 5ms to about 10s.
+            HistogramWithExemplars::new(exponential_buckets(0.005, 2.0, 12))
+        });
+        let mut registry = Registry::default();
+        registry.register(
+            "jester_request_duration_seconds",
+            "Time from receiving a routed request to sending its response head",
+            family.clone(),
+        );
+        Self { registry, family }
+    }
+}
+
+#[derive(Default)]
+struct Gauges {
+    counters: RwLock<HashMap<String, Arc<AtomicUsize>>>,
+}
+
+impl Gauges {
+    fn get(&self, key: &str) -> usize {
+        self.counters
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(key)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    fn snapshot(&self) -> HashMap<String, usize> {
+        self.counters
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .map(|(key, count)| (key.clone(), count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    fn counter(&self, key: &str) -> Arc<AtomicUsize> {
+        if let Some(count) = self
+            .counters
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(key)
+        {
+            return count.clone();
+        }
+        self.counters
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(key.to_string())
+            .or_default()
+            .clone()
+    }
+
+    fn track(&self, key: &str, metric: &'static str, label: &'static str) -> InflightGuard {
+        let count = self.counter(key);
+        let gauge = metrics::gauge!(metric, label => key.to_string());
+        let current = count.fetch_add(1, Ordering::Relaxed) + 1;
+        gauge.set(current as f64);
+        InflightGuard { count, gauge }
+    }
+}
+
+/// Decrements its counter (and the matching gauge) when dropped.
+pub(crate) struct InflightGuard {
+    count: Arc<AtomicUsize>,
+    gauge: metrics::Gauge,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        let current = self.count.fetch_sub(1, Ordering::Relaxed) - 1;
+        self.gauge.set(current as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guards_track_inflight_requests() {
+        let stats = RuntimeStats::default();
+        let first = stats.track_route("app");
+        let second = stats.track_route("app");
+        let target = stats.track_target("http://127.0.0.1:8080");
+        assert_eq!(stats.route_inflight("app"), 2);
+        assert_eq!(stats.target_inflight("http://127.0.0.1:8080"), 1);
+
+        drop(first);
+        drop(target);
+        assert_eq!(stats.route_inflight("app"), 1);
+        assert_eq!(stats.targets()["http://127.0.0.1:8080"], 0);
+        drop(second);
+        assert_eq!(stats.routes()["app"], 0);
+        assert_eq!(stats.route_inflight("unknown"), 0);
+    }
+
+    #[test]
+    fn latency_buckets_carry_trace_id_exemplars() {
+        let stats = RuntimeStats::default();
+        let labels = BTreeMap::from([("team".to_string(), "web".to_string())]);
+        stats.observe_latency(
+            "app",
+            &labels,
+            0.003,
+            Some("4bf92f3577b34da6a3ce929d0e0e4736"),
+        );
+        stats.observe_latency("app", &labels, 0.2, None);
+        let text = stats.encode_latency();
+        assert!(text.contains("# TYPE jester_request_duration_seconds histogram"));
+        assert!(text.contains(
+            "jester_request_duration_seconds_bucket{le=\"0.005\",route=\"app\",team=\"web\"} 1 \
+             # {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"} 0.003"
+        ));
+        assert!(
+            text.contains("jester_request_duration_seconds_count{route=\"app\",team=\"web\"} 2")
+        );
+        assert!(text.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn content_type_labels_drop_parameters_and_junk() {
+        let label = |value: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(value) = value {
+                headers.insert(header::CONTENT_TYPE, value.parse().unwrap());
+            }
+            content_type_label(&headers)
+        };
+        assert_eq!(label(Some("Text/HTML; charset=utf-8")), "text/html");
+        assert_eq!(
+            label(Some("application/vnd.api+json")),
+            "application/vnd.api+json"
+        );
+        assert_eq!(label(None), "none");
+        assert_eq!(label(Some("garbage")), "other");
+        assert_eq!(label(Some("text/<script>")), "other");
+    }
+
+    #[tokio::test]
+    async fn metered_bodies_pass_data_through() {
+        let response = HttpResponse::new(crate::plugin::full_body("hello"));
+        let body = meter_response("app", &BTreeMap::new(), response).into_body();
+        assert_eq!(body.size_hint().exact(), Some(5));
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+    }
+
+    #[tokio::test]
+    async fn held_guards_last_until_the_body_is_sent() {
+        let stats = RuntimeStats::default();
+        let response = HttpResponse::new(crate::plugin::full_body("hello"));
+        let response = hold_until_sent(response, stats.track_route("app"));
+        assert_eq!(stats.route_inflight("app"), 1);
+        let body = response.into_body();
+        assert_eq!(stats.route_inflight("app"), 1);
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+        assert_eq!(stats.route_inflight("app"), 0);
+    }
+
+    #[test]
+    fn websocket_slots_are_limited_per_client() {
+        let stats = RuntimeStats::default();
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let first = stats.acquire_websocket_slot("chat", client, 1);
+        assert!(first.is_some());
+        assert!(stats.acquire_websocket_slot("chat", client, 1).is_none());
+        assert!(stats
+            .acquire_websocket_slot("chat", "10.0.0.2".parse().unwrap(), 1)
+            .is_some());
+        drop(first);
+        assert!(stats.acquire_websocket_slot("chat", client, 1).is_some());
+    }
+}
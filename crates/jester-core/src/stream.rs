@@ -0,0 +1,316 @@
+//! Listeners (`kind = "stream"`) relaying raw TCP bytes to one upstream, for
+//! databases and other protocols that are not HTTP.
+//!
+//! Each client connection gets an upstream connection of its own, opened
+//! with a PROXY protocol header when `stream.proxy_protocol` is set. With
+//! `stream.sni`, the upstream is picked by the server name in the client's TLS
+//! ClientHello, which is then replayed to it: TLS is never terminated. Raw bytes
+//! have no message boundary to close at, so once the proxy drains, open
+//! connections get the `[shutdown] stream_notice` period to finish on their
+//! own and are then cut.
+
+use std::{io::ErrorKind, net::SocketAddr, pin::pin, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+
+use crate::{
+    client_ip::{self, ClientIpResolver},
+    config::{Listener, StreamTarget},
+    connection::{CountingStream, Lifecycle},
+    drain::{self, Drain},
+    proxy::{AppState, ACCEPT_ERROR_BACKOFF, CLIENT_HELLO_TIMEOUT, PROXY_HEADER_TIMEOUT},
+    tls::{self, ClientHello, Replayed},
+    websocket::{deadline_timer, idle_timer},
+};
+
+const RELAY_BUFFER_BYTES: usize = 16 * 1024;
+
+pub(crate) struct StreamListener {
+    pub(crate) name: String,
+    pub(crate) addr: SocketAddr,
+    target: StreamTarget,
+    client_ip: ClientIpResolver,
+    log_connections: bool,
+}
+
+impl TryFrom<&Listener> for StreamListener {
+    type Error = anyhow::Error;
+
+    fn try_from(listener: &Listener) -> Result<Self> {
+        Ok(Self {
+            name: listener.name.clone(),
+            addr: listener.parse_bind_addr().with_context(|| {
+                format!("invalid bind address for listener `{}`", listener.name)
+            })?,
+            target: listener.stream.clone().with_context(|| {
+                format!("stream listener `{}` has no `stream` table", listener.name)
+            })?,
+            client_ip: ClientIpResolver::try_from(&listener.client_ip)?,
+            log_connections: listener.log_connections,
+        })
+    }
+}
+
+impl StreamListener {
+    pub(crate) async fn serve(
+        self,
+        tcp: TcpListener,
+        state: Arc<AppState>,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        let listener = Arc::new(self);
+        loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.changed() => {
+                    tracing::info!(listener = listener.name, "listener shutting down");
+                    break;
+                }
+                accept = tcp.accept() => {
+                    let (stream, peer_addr) = match accept {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            tracing::warn!(listener = listener.name, error = %err, "accept failed");
+                            tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    tokio::spawn(listener.clone().relay(stream, peer_addr, state.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn relay(
+        self: Arc<Self>,
+        mut client: TcpStream,
+        peer_addr: SocketAddr,
+        state: Arc<AppState>,
+    ) {
+        let lifecycle =
+            Lifecycle::accepted(&state.stats, &self.name, peer_addr, self.log_connections);
+        let (upstream, target, hello) = match self.connect(&mut client, peer_addr).await {
+            Ok(connected) => connected,
+            Err((reason, err)) => {
+                tracing::warn!(listener = self.name, %peer_addr, error = %err, "stream connection failed");
+                lifecycle.close(reason);
+                return;
+            }
+        };
+        let target = format!("tcp://{target}");
+        metrics::counter!("jester_upstream_connections_opened_total", "target" => target.clone())
+            .increment(1);
+        let _open = state.stats.track_connection(&target);
+        let _draining = state.drain.track();
+        client.set_nodelay(true).ok();
+        upstream.set_nodelay(true).ok();
+        let client = match hello {
+            Some(hello) => hello.replay(client),
+            None => Replayed::from(client),
+        };
+        let client = CountingStream::new(client, lifecycle.counters());
+        let idle = self.target.idle_timeout_secs.map(Duration::from_secs);
+        let reason = copy_both_ways(client, upstream, idle, &state.drain).await;
+        match reason {
+            "drained" => drain::record("tcp", "cut"),
+            _ if state.drain.is_draining() => drain::record("tcp", "closed"),
+            _ => {}
+        }
+        lifecycle.close(reason);
+    }
+
+    /// Reads the client's PROXY protocol header if one is due, and its
+    /// ClientHello when routing by SNI, then connects upstream and sends the
+    /// upstream's header. Returns the upstream connection, its address, and
+    /// the ClientHello still to be relayed; errors carry the close reason.
+    async fn connect(
+        &self,
+        client: &mut TcpStream,
+        peer_addr: SocketAddr,
+    ) -> Result<(TcpStream, &str, Option<ClientHello>), (&'static str, anyhow::Error)> {
+        let mut client_addr = peer_addr;
+        if self.client_ip.expects_proxy_header(peer_addr.ip()) {
+            let header =
+                tokio::time::timeout(PROXY_HEADER_TIMEOUT, client_ip::read_proxy_header(client))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(anyhow!("timed out waiting for the PROXY protocol header"))
+                    });
+            match header {
+                Ok(source) => client_addr = source.unwrap_or(peer_addr),
+                Err(err) => return Err(("proxy_header_failed", err)),
+            }
+        }
+        let hello = if self.target.sni.is_empty() {
+            None
+        } else {
+            let hello = tokio::time::timeout(CLIENT_HELLO_TIMEOUT, tls::read_client_hello(client))
+                .await
+                .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()));
+            match hello {
+                Ok(hello) => Some(hello),
+                Err(err) => return Err(("tls_failed", anyhow!(err).context("no TLS ClientHello"))),
+            }
+        };
+        let server_name = hello.as_ref().and_then(ClientHello::server_name);
+        let Some(target) = self.upstream_for(server_name) else {
+            return Err((
+                "unknown_server_name",
+                anyhow!(
+                    "no sni entry serves server name {:?}",
+                    server_name.unwrap_or_default()
+                ),
+            ));
+        };
+        let unreachable = |err: anyhow::Error| ("upstream_unreachable", err);
+        let timeout = Duration::from_secs(self.target.connect_timeout_secs);
+        let mut upstream = tokio::time::timeout(timeout, TcpStream::connect(target))
+            .await
+            .map_err(|_| anyhow!("timed out connecting to {target}"))
+            .and_then(|connected| {
+                connected.with_context(|| format!("failed to connect to {target}"))
+            })
+            .map_err(unreachable)?;
+        if let Some(version) = self.target.proxy_protocol {
+            let local_addr = client.local_addr().unwrap_or(self.addr);
+            let header = client_ip::proxy_header(version, Some((client_addr, local_addr)));
+            upstream
+                .write_all(&header)
+                .await
+                .context("failed to send the PROXY protocol header")
+                .map_err(unreachable)?;
+        }
+        Ok((upstream, target, hello))
+    }
+
+    /// The upstream for `server_name`: an `sni` entry naming it exactly,
+    /// then one with a matching wildcard, then `upstream`.
+    fn upstream_for(&self, server_name: Option<&str>) -> Option<&str> {
+        let claims = |pattern: &str| {
+            self.target
+                .sni
+                .iter()
+                .find(|entry| {
+                    entry
+                        .server_names
+                        .iter()
+                        .any(|name| name.eq_ignore_ascii_case(pattern))
+                })
+                .map(|entry| entry.upstream.as_str())
+        };
+        let exact = server_name.and_then(claims);
+        let wildcard = || {
+            let (_, parent) = server_name?.split_once('.')?;
+            claims(&format!("*.{parent}"))
+        };
+        exact.or_else(wildcard).or(self.target.upstream.as_deref())
+    }
+}
+
+/// Copies bytes between `client` and `upstream` until both have finished
+/// sending, passing on each side's end of stream to the other, and returns
+/// why the connection closed.
+async fn copy_both_ways<C, U>(
+    client: C,
+    upstream: U,
+    idle: Option<Duration>,
+    drain: &Drain,
+) -> &'static str
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_rd, mut client_wr) = tokio::io::split(client);
+    let (mut upstream_rd, mut upstream_wr) = tokio::io::split(upstream);
+    let mut client_buf = vec![0; RELAY_BUFFER_BYTES];
+    let mut upstream_buf = vec![0; RELAY_BUFFER_BYTES];
+    let mut notice = pin!(drain.started());
+    let mut deadline = None;
+    let (mut client_open, mut upstream_open) = (true, true);
+    let reason = loop {
+        if !client_open && !upstream_open {
+            break "closed";
+        }
+        tokio::select! {
+            () = &mut notice, if deadline.is_none() => {
+                deadline = Some(Box::pin(tokio::time::sleep(drain.notice())));
+            }
+            () = deadline_timer(&mut deadline) => break "drained",
+            read = client_rd.read(&mut client_buf), if client_open => match read {
+                Ok(0) => {
+                    client_open = false;
+                    upstream_wr.shutdown().await.ok();
+                }
+                Ok(n) => {
+                    if upstream_wr.write_all(&client_buf[..n]).await.is_err() {
+                        break "upstream_reset";
+                    }
+                }
+                Err(_) => break "client_reset",
+            },
+            read = upstream_rd.read(&mut upstream_buf), if upstream_open => match read {
+                Ok(0) => {
+                    upstream_open = false;
+                    client_wr.shutdown().await.ok();
+                }
+                Ok(n) => {
+                    if client_wr.write_all(&upstream_buf[..n]).await.is_err() {
+                        break "client_reset";
+                    }
+                }
+                Err(_) => break "upstream_reset",
+            },
+            () = idle_timer(idle) => break "idle_timeout",
+        }
+    };
+    client_wr.shutdown().await.ok();
+    upstream_wr.shutdown().await.ok();
+    reason
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Shutdown;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn half_closes_pass_through() {
+        let drain = Drain::new(&Shutdown::default());
+        let (mut client, proxy_client) = tokio::io::duplex(64);
+        let (proxy_upstream, mut upstream) = tokio::io::duplex(64);
+        let relay = tokio::spawn({
+            let drain = drain.clone();
+            async move { copy_both_ways(proxy_client, proxy_upstream, None, &drain).await }
+        });
+
+        client.write_all(b"query").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        upstream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"query");
+        // The upstream still answers after the client is done sending.
+        upstream.write_all(b"rows").await.unwrap();
+        upstream.shutdown().await.unwrap();
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer, b"rows");
+        assert_eq!(relay.await.unwrap(), "closed");
+    }
+
+    #[tokio::test]
+    async fn quiet_connections_hit_the_idle_timeout() {
+        let drain = Drain::new(&Shutdown::default());
+        let (_client, proxy_client) = tokio::io::duplex(64);
+        let (proxy_upstream, _upstream) = tokio::io::duplex(64);
+        let idle = Some(Duration::from_millis(50));
+        let reason = copy_both_ways(proxy_client, proxy_upstream, idle, &drain).await;
+        assert_eq!(reason, "idle_timeout");
+    }
+}
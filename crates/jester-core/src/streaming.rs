@@ -0,0 +1,222 @@
+//! Relaying upstream response bodies as they arrive.
+//!
+//! Nothing on the response path waits for more of a body than it has: filters
+//! that rewrite or share bodies only take ones with a bounded
+//! `Content-Length`, so server-sent events, long polls, and other chunked
+//! bodies reach the client chunk by chunk. A route's `[routes.streaming]` can
+//! batch chunks over a `flush_interval` and abort bodies whose upstream goes
+//! quiet for `idle_timeout`.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use tokio::time::{Instant, Sleep};
+
+use crate::{
+    config::Streaming,
+    plugin::{BoxError, HttpResponse, ProxyBody},
+};
+
+/// Held-back chunks are sent once they add up to this much, flush interval
+/// or not.
+const MAX_BATCH_BYTES: usize = 64 * 1024;
+
+/// Applies `settings` to the body of `response`; a no-op when none are set.
+pub(crate) fn relay(response: HttpResponse, settings: &Streaming, route: &str) -> HttpResponse {
+    if settings.flush_interval_ms.is_none() && settings.idle_timeout_secs.is_none() {
+        return response;
+    }
+    let idle = settings.idle_timeout_secs.map(Duration::from_secs);
+    let route = route.to_string();
+    response.map(|body| {
+        Relay {
+            inner: body,
+            flush: settings.flush_interval_ms.map(Duration::from_millis),
+            pending: BytesMut::new(),
+            flush_at: None,
+            idle,
+            idle_at: idle.map(|idle| Box::pin(tokio::time::sleep(idle))),
+            held: None,
+            route,
+        }
+        .boxed()
+    })
+}
+
+struct Relay {
+    inner: ProxyBody,
+    flush: Option<Duration>,
+    /// Chunks held back until `flush_at`.
+    pending: BytesMut,
+    flush_at: Option<Pin<Box<Sleep>>>,
+    idle: Option<Duration>,
+    idle_at: Option<Pin<Box<Sleep>>>,
+    /// Trailers, an error, or the end, read while chunks were held back and
+    /// returned right after them.
+    held: Option<Option<Result<Frame<Bytes>, BoxError>>>,
+    route: String,
+}
+
+impl Relay {
+    fn take_pending(&mut self) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        self.flush_at = None;
+        Poll::Ready(Some(Ok(Frame::data(self.pending.split().freeze()))))
+    }
+
+    /// Sends held-back chunks before `next`.
+    fn after_pending(
+        &mut self,
+        next: Option<Result<Frame<Bytes>, BoxError>>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        if self.pending.is_empty() {
+            return Poll::Ready(next);
+        }
+        self.held = Some(next);
+        self.take_pending()
+    }
+}
+
+impl Body for Relay {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = &mut *self;
+        if let Some(held) = this.held.take() {
+            return Poll::Ready(held);
+        }
+        loop {
+            let frame = match Pin::new(&mut this.inner).poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => frame,
+                Poll::Ready(other) => return this.after_pending(other),
+                Poll::Pending => break,
+            };
+            if let (Some(idle_at), Some(idle)) = (&mut this.idle_at, this.idle) {
+                idle_at.as_mut().reset(Instant::now() + idle);
+            }
+            let data = match frame.into_data() {
+                Ok(data) => data,
+                Err(trailers) => return this.after_pending(Some(Ok(trailers))),
+            };
+            let Some(flush) = this.flush else {
+                return Poll::Ready(Some(Ok(Frame::data(data))));
+            };
+            if this.pending.is_empty() {
+                this.flush_at = Some(Box::pin(tokio::time::sleep(flush)));
+            }
+            this.pending.extend_from_slice(&data);
+            let due = this.flush_at.as_ref().is_some_and(|at| at.is_elapsed());
+            if due || this.pending.len() >= MAX_BATCH_BYTES {
+                return this.take_pending();
+            }
+        }
+        if let Some(flush_at) = &mut this.flush_at {
+            if flush_at.as_mut().poll(cx).is_ready() {
+                return this.take_pending();
+            }
+        }
+        if let Some(idle_at) = &mut this.idle_at {
+            if idle_at.as_mut().poll(cx).is_ready() {
+                this.idle_at = None;
+                metrics::counter!("jester_stream_idle_timeouts_total", "route" => this.route.clone())
+                    .increment(1);
+                tracing::info!(route = this.route, "upstream body idle; aborting response");
+                let err: BoxError = "upstream body idle timeout".into();
+                return this.after_pending(Some(Err(err)));
+            }
+        }
+        Poll::Pending
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.pending.is_empty() && self.held.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Response;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    /// A body fed chunk by chunk through a channel.
+    struct ChannelBody(mpsc::Receiver<&'static str>);
+
+    impl Body for ChannelBody {
+        type Data = Bytes;
+        type Error = BoxError;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+            self.0.poll_recv(cx).map(|chunk| {
+                chunk.map(|chunk| Ok(Frame::data(Bytes::from_static(chunk.as_bytes()))))
+            })
+        }
+    }
+
+    fn relayed(settings: Streaming) -> (mpsc::Sender<&'static str>, ProxyBody) {
+        let (tx, rx) = mpsc::channel(8);
+        let response = Response::new(ChannelBody(rx).boxed());
+        (tx, relay(response, &settings, "events").into_body())
+    }
+
+    #[tokio::test]
+    async fn chunks_pass_straight_through_by_default() {
+        let (tx, mut body) = relayed(Streaming::default());
+        tx.send("data: a\n\n").await.unwrap();
+        let first = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(first, "data: a\n\n");
+        drop(tx);
+        assert!(body.frame().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn flush_interval_batches_chunks() {
+        let (tx, mut body) = relayed(Streaming {
+            flush_interval_ms: Some(100),
+            idle_timeout_secs: None,
+        });
+        tx.send("a").await.unwrap();
+        tx.send("b").await.unwrap();
+        let started = Instant::now();
+        let batch = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(batch, "ab");
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        tx.send("c").await.unwrap();
+        drop(tx);
+        let rest = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(rest, "c");
+        assert!(body.frame().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn quiet_upstreams_hit_the_idle_timeout() {
+        let (tx, mut body) = relayed(Streaming {
+            flush_interval_ms: None,
+            idle_timeout_secs: Some(1),
+        });
+        tx.send("a").await.unwrap();
+        assert!(body.frame().await.unwrap().is_ok());
+        let started = Instant::now();
+        assert!(body.frame().await.unwrap().is_err());
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        drop(tx);
+    }
+}
@@ -0,0 +1,377 @@
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::{Bytes, BytesMut};
+use http::HeaderMap;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use tokio::sync::broadcast;
+
+use crate::{
+    config::TapOptions,
+    context::Timings,
+    plugin::{BoxError, HttpResponse, ProxyBody},
+};
+
+const DEFAULT_CAPACITY: usize = 1024;
+/// Captures hold bodies, so fewer of them are buffered for slow subscribers.
+const CAPTURE_CAPACITY: usize = 64;
+
+/// Access-log record for a single request, published once its response is ready.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessEvent {
+    pub listener: String,
+    /// Client address under the listener's `client_ip` policy.
+    pub client: IpAddr,
+    /// Subject of the certificate the client presented on a mutual-TLS
+    /// listener.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<String>,
+    pub method: String,
+    pub host: Option<String>,
+    pub path: String,
+    pub route: Option<String>,
+    /// The route's configured `labels`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Trace ID from the request's W3C `traceparent` header, if valid.
+    pub trace_id: Option<String>,
+    pub status: u16,
+    /// `grpc-status` of a gRPC call; `None` for other requests and for calls
+    /// whose response ended without one. Published when the call ends.
+    pub grpc_status: Option<u16>,
+    pub duration: Duration,
+    /// Where `duration` went, phase by phase.
+    pub timings: Timings,
+    /// Values filters stored in the request's [`RequestContext`].
+    ///
+    /// [`RequestContext`]: crate::context::RequestContext
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub context: BTreeMap<String, serde_json::Value>,
+}
+
+/// Fan-out of [`AccessEvent`]s to in-process subscribers (tests, `jester tap`).
+///
+/// Publishing is a no-op while nobody is subscribed; slow subscribers lag and
+/// lose events rather than applying backpressure to the data path.
+#[derive(Clone)]
+pub struct Tap {
+    sender: broadcast::Sender<AccessEvent>,
+    captures: broadcast::Sender<Capture>,
+}
+
+impl Default for Tap {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(DEFAULT_CAPACITY);
+        let (captures, _) = broadcast::channel(CAPTURE_CAPACITY);
+        Self { sender, captures }
+    }
+}
+
+impl Tap {
+    pub fn subscribe(&self) -> broadcast::Receiver<AccessEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Receives a [`Capture`] per request once its response body has been
+    /// sent; only published when `[tap] capture_bodies` is set.
+    pub fn subscribe_captures(&self) -> broadcast::Receiver<Capture> {
+        self.captures.subscribe()
+    }
+
+    pub(crate) fn publish(&self, event: AccessEvent) {
+        if self.sender.receiver_count() > 0 {
+            self.sender.send(event).ok();
+        }
+    }
+
+    /// Whether anyone would receive a capture started now.
+    pub(crate) fn capturing(&self) -> bool {
+        self.captures.receiver_count() > 0
+    }
+}
+
+/// An exchange with its headers and the captured part of both bodies.
+#[derive(Debug, Clone, Serialize)]
+pub struct Capture {
+    pub event: AccessEvent,
+    pub request_headers: Vec<Header>,
+    pub request_body: CapturedBody,
+    pub response_headers: Vec<Header>,
+    pub response_body: CapturedBody,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+fn headers(map: &HeaderMap) -> Vec<Header> {
+    map.iter()
+        .map(|(name, value)| Header {
+            name: name.to_string(),
+            value: String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        })
+        .collect()
+}
+
+/// The leading bytes of a body, up to the direction's capture limit.
+///
+/// Serialized as `size`, `captured`, and `truncated`, plus the data as
+/// `text` when it is UTF-8 and as `base64` otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct CapturedBody {
+    /// Bytes that went through, captured or not.
+    pub size: u64,
+    pub data: Bytes,
+    /// `size` exceeded the limit, so `data` holds only a prefix.
+    pub truncated: bool,
+}
+
+impl Serialize for CapturedBody {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut body = serializer.serialize_struct("CapturedBody", 4)?;
+        body.serialize_field("size", &self.size)?;
+        body.serialize_field("captured", &self.data.len())?;
+        body.serialize_field("truncated", &self.truncated)?;
+        match std::str::from_utf8(&self.data) {
+            Ok(text) => body.serialize_field("text", text)?,
+            Err(_) => body.serialize_field("base64", &BASE64.encode(&self.data))?,
+        }
+        body.end()
+    }
+}
+
+/// Per-direction capture limits, from [`TapOptions`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CaptureLimits {
+    request: usize,
+    response: usize,
+}
+
+impl CaptureLimits {
+    pub(crate) fn from_options(options: &TapOptions) -> Option<Self> {
+        options.capture_bodies.then_some(Self {
+            request: options.max_request_body_bytes,
+            response: options.max_response_body_bytes,
+        })
+    }
+}
+
+/// Counts every byte of a body but keeps only the first `limit`.
+struct Recorder {
+    limit: usize,
+    size: u64,
+    data: BytesMut,
+}
+
+impl Recorder {
+    fn new(limit: usize) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            limit,
+            size: 0,
+            data: BytesMut::new(),
+        }))
+    }
+
+    fn record(&mut self, chunk: &[u8]) {
+        self.size += chunk.len() as u64;
+        let room = self.limit.saturating_sub(self.data.len());
+        self.data.extend_from_slice(&chunk[..room.min(chunk.len())]);
+    }
+
+    fn body(&self) -> CapturedBody {
+        CapturedBody {
+            size: self.size,
+            data: Bytes::copy_from_slice(&self.data),
+            truncated: self.size > self.data.len() as u64,
+        }
+    }
+}
+
+fn lock(recorder: &Mutex<Recorder>) -> std::sync::MutexGuard<'_, Recorder> {
+    recorder
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// A request being captured; [`Recording::finish`] hands it to the response
+/// body, which publishes the capture once it ends or is dropped.
+pub(crate) struct Recording {
+    request_headers: Vec<Header>,
+    request: Arc<Mutex<Recorder>>,
+    response_limit: usize,
+}
+
+impl Recording {
+    pub(crate) fn start(limits: CaptureLimits, request_headers: &HeaderMap) -> Self {
+        Self {
+            request_headers: headers(request_headers),
+            request: Recorder::new(limits.request),
+            response_limit: limits.response,
+        }
+    }
+
+    pub(crate) fn request_body(&self, body: ProxyBody) -> ProxyBody {
+        TeeBody {
+            inner: body,
+            recorder: self.request.clone(),
+            publish: None,
+        }
+        .boxed()
+    }
+
+    pub(crate) fn finish(
+        self,
+        response: HttpResponse,
+        event: AccessEvent,
+        tap: Tap,
+    ) -> HttpResponse {
+        let response_headers = headers(response.headers());
+        let recorder = Recorder::new(self.response_limit);
+        response.map(|body| {
+            TeeBody {
+                inner: body,
+                recorder: recorder.clone(),
+                publish: Some(Publish {
+                    tap,
+                    event,
+                    recording: self,
+                    response_headers,
+                }),
+            }
+            .boxed()
+        })
+    }
+}
+
+struct Publish {
+    tap: Tap,
+    event: AccessEvent,
+    recording: Recording,
+    response_headers: Vec<Header>,
+}
+
+/// Passes a body through while recording it.
+struct TeeBody {
+    inner: ProxyBody,
+    recorder: Arc<Mutex<Recorder>>,
+    /// Set on response bodies: publishes the capture when the body is done.
+    publish: Option<Publish>,
+}
+
+impl TeeBody {
+    fn publish(&mut self) {
+        let Some(publish) = self.publish.take() else {
+            return;
+        };
+        let capture = Capture {
+            event: publish.event,
+            request_headers: publish.recording.request_headers,
+            request_body: lock(&publish.recording.request).body(),
+            response_headers: publish.response_headers,
+            response_body: lock(&self.recorder).body(),
+        };
+        publish.tap.captures.send(capture).ok();
+    }
+}
+
+impl Body for TeeBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        match &frame {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    lock(&self.recorder).record(data);
+                }
+            }
+            Poll::Ready(_) => self.publish(),
+            Poll::Pending => {}
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for TeeBody {
+    fn drop(&mut self) {
+        self.publish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::full_body;
+
+    #[tokio::test]
+    async fn captures_stop_at_the_limit_but_keep_counting() {
+        let tap = Tap::default();
+        let mut captures = tap.subscribe_captures();
+        let limits = CaptureLimits {
+            request: 4,
+            response: 1024,
+        };
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert("content-type", "text/plain".parse().unwrap());
+        let recording = Recording::start(limits, &request_headers);
+
+        let request = recording.request_body(full_body("hello streaming world"));
+        assert_eq!(
+            request.collect().await.unwrap().to_bytes(),
+            "hello streaming world"
+        );
+
+        let event = AccessEvent {
+            listener: "public".into(),
+            client: IpAddr::from([127, 0, 0, 1]),
+            client_cert: None,
+            method: "POST".into(),
+            host: None,
+            path: "/".into(),
+            route: None,
+            labels: BTreeMap::new(),
+            trace_id: None,
+            status: 200,
+            grpc_status: None,
+            duration: Duration::ZERO,
+            timings: Timings::default(),
+            context: BTreeMap::new(),
+        };
+        let response =
+            recording.finish(http::Response::new(full_body(vec![0xff, 0xfe])), event, tap);
+        response.into_body().collect().await.unwrap();
+
+        let capture = captures.recv().await.unwrap();
+        assert_eq!(capture.request_headers[0].value, "text/plain");
+        assert_eq!(capture.request_body.size, 21);
+        assert_eq!(capture.request_body.data, "hell");
+        assert!(capture.request_body.truncated);
+        assert!(!capture.response_body.truncated);
+        let json = serde_json::to_value(&capture.response_body).unwrap();
+        assert_eq!(json["base64"], "//4=");
+        assert_eq!(json["captured"], 2);
+    }
+}
@@ -0,0 +1,601 @@
+//! Server-side TLS for listeners: the standard tokio-rustls acceptor, and one
+//! that serves TLS 1.3 early data (0-RTT) before the handshake completes.
+//! Listeners with `tls.client_ca` also verify client certificates, and
+//! `tls.min_version` and friends narrow what handshakes negotiate.
+
+use std::{
+    future::poll_fn,
+    io::{self, Read, Write},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio_rustls::{
+    rustls::{server::Acceptor as HelloReader, ServerConfig, ServerConnection},
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+mod chain;
+mod policy;
+mod sni;
+mod watch;
+
+pub(crate) use chain::{check_key, complete as complete_chain, CertInfo, KeyMismatch};
+pub(crate) use policy::{select as select_by_name, Policy};
+pub(crate) use sni::SniCertificates;
+pub(crate) use watch::{watch as watch_certificates, WatchedListener};
+
+use crate::context::ClientCertificate;
+
+/// How a listener terminates TLS.
+#[derive(Clone)]
+pub(crate) enum Acceptor {
+    /// Completes the handshake before any request is read.
+    Standard(TlsAcceptor),
+    /// Starts serving as soon as the server's first flight is sent, so requests
+    /// in early data are read while the handshake finishes.
+    EarlyData(Arc<ServerConfig>),
+}
+
+impl Acceptor {
+    pub(crate) fn new(config: ServerConfig) -> Self {
+        if config.max_early_data_size > 0 {
+            Acceptor::EarlyData(Arc::new(config))
+        } else {
+            Acceptor::Standard(TlsAcceptor::from(Arc::new(config)))
+        }
+    }
+
+    /// Accepts a client; the returned [`Handshake`] is set for early-data
+    /// listeners only.
+    pub(crate) async fn accept<IO>(
+        &self,
+        io: IO,
+    ) -> io::Result<(ClientStream<IO>, Option<Arc<Handshake>>)>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self {
+            Acceptor::Standard(acceptor) => {
+                let stream = acceptor.accept(io).await?;
+                Ok((ClientStream::Tls(Box::new(stream)), None))
+            }
+            Acceptor::EarlyData(config) => {
+                let conn = ServerConnection::new(config.clone())
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                let mut stream = EarlyDataStream {
+                    io,
+                    conn,
+                    handshake: Arc::default(),
+                    closing: false,
+                };
+                poll_fn(|cx| stream.poll_first_flight(cx)).await?;
+                let handshake = stream.handshake.clone();
+                Ok((ClientStream::EarlyData(Box::new(stream)), Some(handshake)))
+            }
+        }
+    }
+}
+
+/// Most bytes read ahead looking for a ClientHello; real ones fit in one
+/// 16 KiB record, and post-quantum key shares still stay well below this.
+const MAX_CLIENT_HELLO_BYTES: usize = 64 * 1024;
+
+/// The start of a connection, read ahead to learn the server name the client
+/// asks for before choosing who terminates its TLS.
+pub(crate) struct ClientHello {
+    server_name: Option<String>,
+    bytes: Vec<u8>,
+}
+
+impl ClientHello {
+    /// The SNI the client sent, lowercased.
+    pub(crate) fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// `io` with the bytes read ahead put back in front, for an [`Acceptor`].
+    pub(crate) fn replay<IO>(self, io: IO) -> Replayed<IO> {
+        Replayed {
+            prefix: self.bytes,
+            offset: 0,
+            io,
+        }
+    }
+}
+
+/// Reads from `io` until a whole ClientHello has arrived.
+pub(crate) async fn read_client_hello<IO>(io: &mut IO) -> io::Result<ClientHello>
+where
+    IO: AsyncRead + Unpin,
+{
+    let mut reader = HelloReader::default();
+    let mut bytes = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let read = io.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        bytes.extend_from_slice(&chunk[..read]);
+        let mut unread = &chunk[..read];
+        while !unread.is_empty() {
+            reader.read_tls(&mut unread)?;
+        }
+        match reader.accept() {
+            Ok(Some(accepted)) => {
+                let server_name = accepted
+                    .client_hello()
+                    .server_name()
+                    .map(str::to_ascii_lowercase);
+                return Ok(ClientHello { server_name, bytes });
+            }
+            Ok(None) if bytes.len() < MAX_CLIENT_HELLO_BYTES => {}
+            Ok(None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "ClientHello is too large",
+                ))
+            }
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        }
+    }
+}
+
+/// A connection whose first bytes were already read, serving those again
+/// before reading on.
+pub(crate) struct Replayed<IO> {
+    prefix: Vec<u8>,
+    offset: usize,
+    io: IO,
+}
+
+impl<IO> From<IO> for Replayed<IO> {
+    fn from(io: IO) -> Self {
+        Self {
+            prefix: Vec::new(),
+            offset: 0,
+            io,
+        }
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for Replayed<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.offset < this.prefix.len() {
+            let remaining = &this.prefix[this.offset..];
+            let len = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..len]);
+            this.offset += len;
+            if this.offset == this.prefix.len() {
+                this.prefix = Vec::new();
+                this.offset = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for Replayed<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+/// Describes the leaf of the certificate chain a client presented, once the
+/// handshake verified it.
+pub(crate) fn client_certificate(chain: &[impl AsRef<[u8]>]) -> Option<Arc<ClientCertificate>> {
+    let info = CertInfo::parse(chain.first()?.as_ref())?;
+    Some(Arc::new(ClientCertificate {
+        subject: info.subject_name(),
+        subject_alt_names: info.subject_alt_names(),
+    }))
+}
+
+/// Whether a handshake failed because the client offered none of the
+/// listener's ALPN protocols.
+pub(crate) fn is_alpn_mismatch(err: &io::Error) -> bool {
+    err.get_ref()
+        .and_then(|err| err.downcast_ref::<tokio_rustls::rustls::Error>())
+        .is_some_and(|err| *err == tokio_rustls::rustls::Error::NoApplicationProtocol)
+}
+
+/// Whether a connection's TLS handshake has completed. Requests read before
+/// that arrived as early data and may be replays.
+#[derive(Debug, Default)]
+pub(crate) struct Handshake(AtomicBool);
+
+impl Handshake {
+    pub(crate) fn is_complete(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    fn complete(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+/// A client connection from either [`Acceptor`].
+pub(crate) enum ClientStream<IO> {
+    Tls(Box<TlsStream<IO>>),
+    EarlyData(Box<EarlyDataStream<IO>>),
+}
+
+impl<IO> ClientStream<IO> {
+    /// The protocol the client and listener agreed on through ALPN, if any.
+    pub(crate) fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            ClientStream::Tls(stream) => stream.get_ref().1.alpn_protocol(),
+            ClientStream::EarlyData(stream) => stream.conn.alpn_protocol(),
+        }
+    }
+
+    /// The verified client certificate, on listeners that ask for one. Early
+    /// data listeners never do, since they serve before it arrives.
+    pub(crate) fn client_certificate(&self) -> Option<Arc<ClientCertificate>> {
+        match self {
+            ClientStream::Tls(stream) => {
+                client_certificate(stream.get_ref().1.peer_certificates()?)
+            }
+            ClientStream::EarlyData(_) => None,
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncRead for ClientStream<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::EarlyData(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncWrite for ClientStream<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::EarlyData(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::EarlyData(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::EarlyData(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A rustls server connection driven by its reader and writer rather than an
+/// up-front handshake, so plaintext from early data is readable immediately.
+pub(crate) struct EarlyDataStream<IO> {
+    io: IO,
+    conn: ServerConnection,
+    handshake: Arc<Handshake>,
+    closing: bool,
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> EarlyDataStream<IO> {
+    /// Reads the ClientHello and sends the server's first flight.
+    fn poll_first_flight(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if self.conn.wants_write() || !self.conn.is_handshaking() {
+                return self.poll_write_tls(cx);
+            }
+            if !ready!(self.poll_read_tls(cx))? {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+
+    /// Writes all pending TLS records.
+    fn poll_write_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.conn.wants_write() {
+            let mut io = SyncIo {
+                io: &mut self.io,
+                cx,
+            };
+            match self.conn.write_tls(&mut io) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Reads and processes more TLS records; `false` at EOF.
+    fn poll_read_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        let mut io = SyncIo {
+            io: &mut self.io,
+            cx,
+        };
+        let read = match self.conn.read_tls(&mut io) {
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+            Err(err) => return Poll::Ready(Err(err)),
+        };
+        if let Err(err) = self.conn.process_new_packets() {
+            // Best effort: tell the client why before the connection drops.
+            let _ = self.poll_write_tls(cx);
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, err)));
+        }
+        if !self.conn.is_handshaking() {
+            self.handshake.complete();
+        }
+        Poll::Ready(Ok(read > 0))
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncRead for EarlyDataStream<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(mut early) = this.conn.early_data() {
+                let read = early.read(buf.initialize_unfilled())?;
+                if read > 0 {
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                }
+            }
+            match this.conn.reader().read(buf.initialize_unfilled()) {
+                Ok(read) => {
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+            // Handshake messages must go out before the client sends more.
+            if let Poll::Ready(Err(err)) = this.poll_write_tls(cx) {
+                return Poll::Ready(Err(err));
+            }
+            if !ready!(this.poll_read_tls(cx))? && this.conn.is_handshaking() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncWrite for EarlyDataStream<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            let written = this.conn.writer().write(buf)?;
+            if written > 0 || buf.is_empty() {
+                if let Poll::Ready(Err(err)) = this.poll_write_tls(cx) {
+                    return Poll::Ready(Err(err));
+                }
+                return Poll::Ready(Ok(written));
+            }
+            // rustls' buffers are full: drain them, and while the handshake is
+            // still running keep reading so it can finish and release plaintext.
+            ready!(this.poll_write_tls(cx))?;
+            if this.conn.is_handshaking() && !ready!(this.poll_read_tls(cx))? {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.conn.writer().flush()?;
+        ready!(this.poll_write_tls(cx))?;
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closing {
+            this.conn.send_close_notify();
+            this.closing = true;
+        }
+        ready!(this.poll_write_tls(cx))?;
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+/// Presents an async socket as blocking `Read`/`Write` for rustls, mapping
+/// `Pending` to `WouldBlock`.
+struct SyncIo<'a, 'b, IO> {
+    io: &'a mut IO,
+    cx: &'a mut Context<'b>,
+}
+
+impl<IO: AsyncRead + Unpin> Read for SyncIo<'_, '_, IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::new(&mut *self.io).poll_read(self.cx, &mut buf) {
+            Poll::Ready(Ok(())) => Ok(buf.filled().len()),
+            Poll::Ready(Err(err)) => Err(err),
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> Write for SyncIo<'_, '_, IO> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_rustls::rustls::{
+        Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore,
+    };
+
+    use super::*;
+
+    fn configs() -> (ServerConfig, Arc<ClientConfig>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert = Certificate(certified.cert.der().to_vec());
+        let mut roots = RootCertStore::empty();
+        roots.add(&cert).unwrap();
+        let mut server = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], PrivateKey(certified.key_pair.serialize_der()))
+            .unwrap();
+        server.max_early_data_size = 1024;
+        let mut client = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client.enable_early_data = true;
+        (server, Arc::new(client))
+    }
+
+    async fn send(client: &mut ClientConnection, io: &mut DuplexStream) {
+        let mut records = Vec::new();
+        while client.wants_write() {
+            client.write_tls(&mut records).unwrap();
+        }
+        io.write_all(&records).await.unwrap();
+    }
+
+    async fn receive(client: &mut ClientConnection, io: &mut DuplexStream) {
+        let mut buf = vec![0; 16 * 1024];
+        let read = io.read(&mut buf).await.unwrap();
+        client.read_tls(&mut &buf[..read]).unwrap();
+        client.process_new_packets().unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_early_data_before_the_handshake_completes() {
+        let (server_config, client_config) = configs();
+        let acceptor = Acceptor::new(server_config);
+        assert!(matches!(acceptor, Acceptor::EarlyData(_)));
+        let name = "localhost".try_into().unwrap();
+
+        // A full handshake first, so the client holds a ticket to resume with.
+        let (mut client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let mut client = ClientConnection::new(client_config.clone(), name).unwrap();
+        send(&mut client, &mut client_io).await;
+        let (mut server, handshake) = acceptor.accept(server_io).await.unwrap();
+        let handshake = handshake.unwrap();
+        while client.is_handshaking() {
+            receive(&mut client, &mut client_io).await;
+            send(&mut client, &mut client_io).await;
+        }
+        client.writer().write_all(b"ping").unwrap();
+        send(&mut client, &mut client_io).await;
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert!(handshake.is_complete());
+        server.write_all(b"pong").await.unwrap();
+        receive(&mut client, &mut client_io).await;
+
+        let (mut client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let name = "localhost".try_into().unwrap();
+        let mut client = ClientConnection::new(client_config, name).unwrap();
+        client.early_data().unwrap().write_all(b"early").unwrap();
+        send(&mut client, &mut client_io).await;
+        let (mut server, handshake) = acceptor.accept(server_io).await.unwrap();
+        let handshake = handshake.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"early");
+        assert!(!handshake.is_complete());
+
+        receive(&mut client, &mut client_io).await;
+        assert!(client.is_early_data_accepted());
+        send(&mut client, &mut client_io).await;
+        client.writer().write_all(b"later").unwrap();
+        send(&mut client, &mut client_io).await;
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"later");
+        assert!(handshake.is_complete());
+    }
+
+    #[tokio::test]
+    async fn replays_the_client_hello_to_the_chosen_acceptor() {
+        let (mut server_config, client_config) = configs();
+        server_config.max_early_data_size = 0;
+        let acceptor = Acceptor::new(server_config);
+        let name = "LocalHost".try_into().unwrap();
+
+        let (mut client_io, mut server_io) = tokio::io::duplex(64 * 1024);
+        let mut client = ClientConnection::new(client_config, name).unwrap();
+        send(&mut client, &mut client_io).await;
+        let hello = read_client_hello(&mut server_io).await.unwrap();
+        assert_eq!(hello.server_name(), Some("localhost"));
+
+        let server = tokio::spawn(async move {
+            let (mut server, _) = acceptor.accept(hello.replay(server_io)).await.unwrap();
+            let mut buf = [0; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+        while client.is_handshaking() {
+            receive(&mut client, &mut client_io).await;
+            send(&mut client, &mut client_io).await;
+        }
+        client.writer().write_all(b"ping").unwrap();
+        send(&mut client, &mut client_io).await;
+        assert_eq!(&server.await.unwrap(), b"ping");
+
+        let mut plaintext: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert!(read_client_hello(&mut plaintext).await.is_err());
+    }
+}
@@ -0,0 +1,503 @@
+//! Listener certificate chains: the few X.509 fields needed to check them, and
+//! completion from a bundle of intermediates at load time, and whether a key
+//! belongs to the leaf. Client certificates on mutual-TLS listeners are
+//! described with the same fields.
+
+use std::fmt;
+
+use anyhow::{bail, Result};
+use ring::signature::{self, KeyPair};
+use tokio_rustls::rustls::{Certificate, PrivateKey};
+
+/// Names and expiry of one certificate. Names are the DER contents of the
+/// `Name` sequence, comparable byte for byte.
+pub(crate) struct CertInfo<'a> {
+    pub(crate) subject: &'a [u8],
+    pub(crate) issuer: &'a [u8],
+    /// `notAfter`, in seconds since the epoch.
+    pub(crate) not_after: u64,
+    /// The `subjectPublicKey` bits: a DER `RSAPublicKey`, an EC point, or an
+    /// Ed25519 key.
+    public_key: &'a [u8],
+    /// Contents of the `extensions` field, if any.
+    extensions: Option<&'a [u8]>,
+}
+
+impl<'a> CertInfo<'a> {
+    pub(crate) fn parse(der: &'a [u8]) -> Option<Self> {
+        let (_, cert, _) = der_element(der)?;
+        let (_, tbs, _) = der_element(cert)?;
+        let (tag, _, mut rest) = der_element(tbs)?;
+        if tag == 0xa0 {
+            // Explicit version; the serial number follows.
+            (_, _, rest) = der_element(rest)?;
+        }
+        let (_, _, rest) = der_element(rest)?; // signature algorithm
+        let (_, issuer, rest) = der_element(rest)?;
+        let (_, validity, rest) = der_element(rest)?;
+        let (_, subject, rest) = der_element(rest)?;
+        let (_, _, validity) = der_element(validity)?; // notBefore
+        let (tag, time, _) = der_element(validity)?;
+        let (_, spki, mut rest) = der_element(rest)?;
+        let (_, _, spki) = der_element(spki)?; // algorithm
+        let (_, public_key, _) = der_element(spki)?;
+        // The first byte of a bit string counts unused bits; keys use none.
+        let (0, public_key) = public_key.split_first()? else {
+            return None;
+        };
+        let mut extensions = None;
+        // Optional unique identifiers, then explicitly tagged extensions.
+        while let Some((tag, contents, remainder)) = der_element(rest) {
+            if tag == 0xa3 {
+                extensions = der_element(contents).map(|(_, extensions, _)| extensions);
+            }
+            rest = remainder;
+        }
+        Some(Self {
+            subject,
+            issuer,
+            not_after: parse_time(tag, time)?,
+            public_key,
+            extensions,
+        })
+    }
+
+    /// Short form of the subject, e.g. `CN=example.com`.
+    pub(crate) fn subject_name(&self) -> String {
+        describe(self.subject)
+    }
+
+    /// Subject alternative names in OpenSSL's notation: `DNS:example.com`,
+    /// `IP:10.0.0.1`, `email:ops@example.com`, `URI:spiffe://example/web`.
+    pub(crate) fn subject_alt_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut extensions = self.extensions.unwrap_or_default();
+        while let Some((_, extension, rest)) = der_element(extensions) {
+            extensions = rest;
+            let Some((_, oid, mut fields)) = der_element(extension) else {
+                continue;
+            };
+            if oid != [0x55, 0x1d, 0x11] {
+                continue;
+            }
+            // An optional `critical` flag precedes the value.
+            let mut value = None;
+            while let Some((tag, contents, rest)) = der_element(fields) {
+                if tag == 0x04 {
+                    value = Some(contents);
+                }
+                fields = rest;
+            }
+            let Some((_, mut general_names, _)) = value.and_then(der_element) else {
+                continue;
+            };
+            while let Some((tag, name, rest)) = der_element(general_names) {
+                general_names = rest;
+                let text = || String::from_utf8_lossy(name);
+                names.push(match tag {
+                    0x81 => format!("email:{}", text()),
+                    0x82 => format!("DNS:{}", text()),
+                    0x86 => format!("URI:{}", text()),
+                    0x87 => match <[u8; 4]>::try_from(name) {
+                        Ok(v4) => format!("IP:{}", std::net::Ipv4Addr::from(v4)),
+                        Err(_) => match <[u8; 16]>::try_from(name) {
+                            Ok(v6) => format!("IP:{}", std::net::Ipv6Addr::from(v6)),
+                            Err(_) => continue,
+                        },
+                    },
+                    _ => continue,
+                });
+            }
+        }
+        names
+    }
+
+    fn self_issued(&self) -> bool {
+        self.subject == self.issuer
+    }
+}
+
+/// Checks that `chain` runs from the leaf up, each certificate issued by the
+/// next, and appends what is missing from `intermediates`. The chain must end
+/// at a root: one from `intermediates` (a private CA, not sent to clients) or
+/// a public root CA.
+pub(crate) fn complete(
+    mut chain: Vec<Certificate>,
+    intermediates: &[Certificate],
+) -> Result<Vec<Certificate>> {
+    let bundle = intermediates
+        .iter()
+        .enumerate()
+        .map(|(index, cert)| {
+            CertInfo::parse(&cert.0).ok_or_else(|| {
+                anyhow::anyhow!("intermediate {} is not a valid certificate", index + 1)
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let parsed = chain
+        .iter()
+        .enumerate()
+        .map(|(index, cert)| {
+            CertInfo::parse(&cert.0)
+                .ok_or_else(|| anyhow::anyhow!("certificate {} is not valid", index + 1))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if parsed.is_empty() {
+        bail!("no certificates found");
+    }
+    for (index, pair) in parsed.windows(2).enumerate() {
+        if pair[0].issuer == pair[1].subject {
+            continue;
+        }
+        let misplaced = parsed.iter().any(|cert| cert.subject == pair[0].issuer)
+            || parsed[index + 1..]
+                .iter()
+                .any(|cert| cert.issuer == pair[0].subject && !cert.self_issued());
+        if misplaced {
+            bail!(
+                "chain is out of order at certificate {} (`{}`); list the leaf first, then each issuer in turn",
+                index + 1,
+                describe(pair[0].subject)
+            );
+        }
+        bail!(
+            "certificate {} (`{}`) did not issue `{}`; remove it or replace it with `{}`",
+            index + 2,
+            describe(pair[1].subject),
+            describe(pair[0].subject),
+            describe(pair[0].issuer)
+        );
+    }
+
+    let mut top = parsed.last().map(|cert| (cert.subject, cert.issuer));
+    let mut appended = Vec::new();
+    while let Some((subject, issuer)) = top.filter(|(subject, issuer)| subject != issuer) {
+        let Some(found) = bundle
+            .iter()
+            .position(|cert| cert.subject == issuer && cert.issuer != subject)
+        else {
+            break;
+        };
+        let cert = &bundle[found];
+        if cert.self_issued() {
+            // A private root: the chain is complete without sending it.
+            top = None;
+            break;
+        }
+        if appended.contains(&found) {
+            bail!("tls.intermediates contains an issuing loop");
+        }
+        appended.push(found);
+        top = Some((cert.subject, cert.issuer));
+    }
+    if let Some((subject, issuer)) = top.filter(|(subject, issuer)| subject != issuer) {
+        let public_root = webpki_roots::TLS_SERVER_ROOTS
+            .iter()
+            .any(|anchor| anchor.subject == issuer);
+        if !public_root {
+            bail!(
+                "chain is incomplete: `{}` is issued by `{}`, which is neither in the file nor a public root CA; add that intermediate to tls.intermediates (for a private CA, add its root there too)",
+                describe(subject),
+                describe(issuer)
+            );
+        }
+    }
+    chain.extend(
+        appended
+            .into_iter()
+            .map(|index| intermediates[index].clone()),
+    );
+    Ok(chain)
+}
+
+/// A private key that is not the key of the certificate it was loaded with,
+/// as when a renewal has replaced only one of the two files so far.
+#[derive(Debug)]
+pub(crate) struct KeyMismatch;
+
+impl fmt::Display for KeyMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("private key does not match the certificate")
+    }
+}
+
+impl std::error::Error for KeyMismatch {}
+
+/// Checks that `key` is the private key of `leaf`, whose public key rustls
+/// otherwise never compares it with. Keys whose public half cannot be
+/// derived here pass, and are left to rustls to accept or reject.
+pub(crate) fn check_key(leaf: &Certificate, key: &PrivateKey) -> Result<()> {
+    let info = CertInfo::parse(&leaf.0).ok_or_else(|| anyhow::anyhow!("invalid certificate"))?;
+    match public_key(&key.0) {
+        Some(public_key) if public_key != info.public_key => Err(KeyMismatch.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The public half of a PKCS#8, PKCS#1 (RSA), or SEC1 (EC) private key, in
+/// the form a certificate's `subjectPublicKey` holds it.
+fn public_key(der: &[u8]) -> Option<Vec<u8>> {
+    if let Ok(key) =
+        signature::RsaKeyPair::from_pkcs8(der).or_else(|_| signature::RsaKeyPair::from_der(der))
+    {
+        return Some(key.public_key().as_ref().to_vec());
+    }
+    let rng = ring::rand::SystemRandom::new();
+    for algorithm in [
+        &signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+        &signature::ECDSA_P384_SHA384_ASN1_SIGNING,
+    ] {
+        if let Ok(key) = signature::EcdsaKeyPair::from_pkcs8(algorithm, der, &rng) {
+            return Some(key.public_key().as_ref().to_vec());
+        }
+    }
+    if let Ok(key) = signature::Ed25519KeyPair::from_pkcs8_maybe_unchecked(der) {
+        return Some(key.public_key().as_ref().to_vec());
+    }
+    // SEC1 keys carry their public point in an optional `[1]` field.
+    let (_, mut fields, _) = der_element(der)?;
+    while let Some((tag, contents, rest)) = der_element(fields) {
+        if tag == 0xa1 {
+            let (_, bits, _) = der_element(contents)?;
+            let (0, point) = bits.split_first()? else {
+                return None;
+            };
+            return Some(point.to_vec());
+        }
+        fields = rest;
+    }
+    None
+}
+
+/// Short form of a `Name` for messages, e.g. `CN=R3, O=Let's Encrypt`.
+fn describe(name: &[u8]) -> String {
+    let mut parts = Vec::new();
+    let mut sets = name;
+    while let Some((_, set, rest)) = der_element(sets) {
+        sets = rest;
+        let Some((_, attribute, _)) = der_element(set) else {
+            continue;
+        };
+        let Some((_, oid, value)) = der_element(attribute) else {
+            continue;
+        };
+        let label = match oid {
+            [0x55, 0x04, 0x03] => "CN",
+            [0x55, 0x04, 0x0a] => "O",
+            [0x55, 0x04, 0x0b] => "OU",
+            [0x55, 0x04, 0x06] => "C",
+            _ => continue,
+        };
+        if let Some((_, value, _)) = der_element(value) {
+            parts.push(format!("{label}={}", String::from_utf8_lossy(value)));
+        }
+    }
+    if parts.is_empty() {
+        "unnamed certificate".into()
+    } else {
+        parts.join(", ")
+    }
+}
+
+/// Seconds since the epoch of a `UTCTime` (tag `0x17`) or `GeneralizedTime`.
+fn parse_time(tag: u8, time: &[u8]) -> Option<u64> {
+    let time = std::str::from_utf8(time).ok()?.strip_suffix('Z')?;
+    let (year, rest) = match tag {
+        0x17 => {
+            let year: i64 = time.get(..2)?.parse().ok()?;
+            (
+                if year < 50 { 2000 + year } else { 1900 + year },
+                &time[2..],
+            )
+        }
+        0x18 => (time.get(..4)?.parse().ok()?, &time[4..]),
+        _ => return None,
+    };
+    let field = |at: usize| rest.get(at..at + 2)?.parse::<i64>().ok();
+    let days = days_from_civil(year, field(0)?, field(2)?);
+    let secs = days * 86_400 + field(4)? * 3600 + field(6)? * 60 + field(8)?;
+    u64::try_from(secs).ok()
+}
+
+/// Splits one DER element off `input`: tag, contents, remainder.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        usize::from(first)
+    } else {
+        let count = usize::from(first & 0x7f);
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let (bytes, remainder) = rest.split_at_checked(count)?;
+        rest = remainder;
+        bytes
+            .iter()
+            .fold(0, |len, byte| len << 8 | usize::from(*byte))
+    };
+    let (contents, rest) = rest.split_at_checked(len)?;
+    Some((tag, contents, rest))
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair, SanType};
+
+    use super::*;
+
+    struct Issued {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    fn ca(name: &str, issuer: Option<&Issued>) -> Issued {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate().unwrap();
+        let cert = match issuer {
+            Some(issuer) => params.signed_by(&key, &issuer.cert, &issuer.key),
+            None => params.self_signed(&key),
+        }
+        .unwrap();
+        Issued { cert, key }
+    }
+
+    fn leaf(issuer: &Issued) -> Certificate {
+        let params = CertificateParams::new(vec!["example.com".into()]).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &issuer.cert, &issuer.key).unwrap();
+        Certificate(cert.der().to_vec())
+    }
+
+    fn der(issued: &Issued) -> Certificate {
+        Certificate(issued.cert.der().to_vec())
+    }
+
+    #[test]
+    fn reads_names_and_expiry() {
+        let mut params = CertificateParams::new(vec!["example.com".into()]).unwrap();
+        params.not_after = rcgen::date_time_ymd(2030, 1, 2);
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Jester");
+        let key = KeyPair::generate().unwrap();
+        let cert = params.clone().self_signed(&key).unwrap();
+        let info = CertInfo::parse(cert.der()).unwrap();
+        assert_eq!(info.not_after, 1_893_542_400);
+        assert!(info.self_issued());
+        assert_eq!(
+            describe(info.subject),
+            "CN=rcgen self signed cert, O=Jester"
+        );
+
+        params.not_after = rcgen::date_time_ymd(2051, 6, 30);
+        let cert = params.self_signed(&key).unwrap();
+        assert_eq!(
+            CertInfo::parse(cert.der()).unwrap().not_after,
+            days_from_civil(2051, 6, 30) as u64 * 86_400
+        );
+        assert!(CertInfo::parse(b"\x30\x03\x02\x01").is_none());
+
+        let mut params =
+            CertificateParams::new(vec!["client.example.com".into(), "10.0.0.7".into()]).unwrap();
+        params.subject_alt_names.extend([
+            SanType::Rfc822Name("ops@example.com".try_into().unwrap()),
+            SanType::URI("spiffe://example/web".try_into().unwrap()),
+            SanType::IpAddress("::1".parse().unwrap()),
+        ]);
+        let cert = params.self_signed(&key).unwrap();
+        assert_eq!(
+            CertInfo::parse(cert.der()).unwrap().subject_alt_names(),
+            [
+                "DNS:client.example.com",
+                "IP:10.0.0.7",
+                "email:ops@example.com",
+                "URI:spiffe://example/web",
+                "IP:::1"
+            ]
+        );
+        let root = ca("Jester Root", None);
+        let info = CertInfo::parse(root.cert.der()).unwrap();
+        assert!(info.subject_alt_names().is_empty());
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+    }
+
+    #[test]
+    fn keys_are_checked_against_the_leaf() {
+        let params = CertificateParams::new(vec!["example.com".into()]).unwrap();
+        let check = |key: &KeyPair, other: &KeyPair| {
+            let cert = params.clone().self_signed(key).unwrap();
+            let leaf = Certificate(cert.der().to_vec());
+            let key = PrivateKey(other.serialize_der());
+            check_key(&leaf, &key)
+        };
+        let ecdsa = KeyPair::generate().unwrap();
+        let renewed = KeyPair::generate().unwrap();
+        let ed25519 = KeyPair::generate_for(&rcgen::PKCS_ED25519).unwrap();
+        let p384 = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P384_SHA384).unwrap();
+        check(&ecdsa, &ecdsa).unwrap();
+        check(&ed25519, &ed25519).unwrap();
+        check(&p384, &p384).unwrap();
+        for (key, other) in [(&ecdsa, &renewed), (&ecdsa, &ed25519), (&p384, &ecdsa)] {
+            let err = check(key, other).unwrap_err();
+            assert!(err.is::<KeyMismatch>(), "{err}");
+        }
+    }
+
+    #[test]
+    fn appends_intermediates_up_to_a_private_root() {
+        let root = ca("Jester Root", None);
+        let intermediate = ca("Jester Intermediate", Some(&root));
+        let leaf = leaf(&intermediate);
+
+        let chain = complete(vec![leaf.clone()], &[der(&root), der(&intermediate)]).unwrap();
+        assert_eq!(chain, vec![leaf.clone(), der(&intermediate)]);
+
+        let chain = complete(vec![leaf.clone(), der(&intermediate)], &[der(&root)]).unwrap();
+        assert_eq!(chain.len(), 2);
+
+        let err = complete(vec![leaf.clone()], &[der(&root)]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("is issued by `CN=Jester Intermediate`"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn rejects_misordered_and_foreign_chains() {
+        let root = ca("Jester Root", None);
+        let intermediate = ca("Jester Intermediate", Some(&root));
+        let other = ca("Other Root", None);
+        let leaf = leaf(&intermediate);
+
+        let err = complete(vec![der(&intermediate), leaf.clone()], &[der(&root)]).unwrap_err();
+        assert!(err.to_string().contains("out of order"), "{err}");
+
+        let err = complete(vec![leaf, der(&other)], &[der(&root)]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("certificate 2 (`CN=Other Root`) did not issue"),
+            "{err}"
+        );
+
+        let self_signed = der(&other);
+        assert_eq!(
+            complete(vec![self_signed.clone()], &[]).unwrap(),
+            vec![self_signed]
+        );
+    }
+}
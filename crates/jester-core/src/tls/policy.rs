@@ -0,0 +1,168 @@
+//! The protocol versions, cipher suites, and key exchange groups a listener
+//! negotiates, from its `tls.min_version`, `max_version`, `cipher_suites`, and
+//! `curves`. Names match rustls's case-insensitively.
+
+use anyhow::{anyhow, bail, Result};
+use tokio_rustls::rustls::{
+    version::{TLS12, TLS13},
+    SupportedCipherSuite, SupportedKxGroup, SupportedProtocolVersion, ALL_CIPHER_SUITES,
+    ALL_KX_GROUPS,
+};
+
+use crate::config::{Tls, TlsVersion};
+
+/// What a TCP listener's handshakes may use.
+pub(crate) struct Policy {
+    pub(crate) versions: Vec<&'static SupportedProtocolVersion>,
+    /// In the server's order of preference.
+    pub(crate) cipher_suites: Vec<SupportedCipherSuite>,
+    pub(crate) kx_groups: Vec<&'static SupportedKxGroup>,
+}
+
+impl Policy {
+    pub(crate) fn new(tls: &Tls) -> Result<Self> {
+        let min = tls.min_version.unwrap_or(TlsVersion::Tls12);
+        let max = tls.max_version.unwrap_or(TlsVersion::Tls13);
+        if min > max {
+            bail!("tls min_version ({min}) is above max_version ({max})");
+        }
+        let versions = [(TlsVersion::Tls12, &TLS12), (TlsVersion::Tls13, &TLS13)]
+            .into_iter()
+            .filter(|(version, _)| (min..=max).contains(version))
+            .collect::<Vec<_>>();
+        let cipher_suites = select(
+            "cipher suite",
+            ALL_CIPHER_SUITES,
+            &tls.cipher_suites,
+            cipher_suite_name,
+        )?;
+        for (version, supported) in &versions {
+            if !cipher_suites
+                .iter()
+                .any(|suite| suite.version() == *supported)
+            {
+                bail!("tls cipher_suites has no {version} suite, but {version} is allowed");
+            }
+        }
+        let kx_groups = select("curve", &ALL_KX_GROUPS, &tls.curves, |group| {
+            format!("{:?}", group.name)
+        })?;
+        Ok(Self {
+            versions: versions.into_iter().map(|(_, version)| version).collect(),
+            cipher_suites,
+            kx_groups,
+        })
+    }
+}
+
+/// The IANA name of `suite`, e.g. `TLS13_AES_128_GCM_SHA256`.
+fn cipher_suite_name(suite: &SupportedCipherSuite) -> String {
+    format!("{:?}", suite.suite())
+}
+
+/// The entries of `supported` that `names` lists, in that order; all of them
+/// when `names` is empty.
+pub(crate) fn select<T: Clone>(
+    what: &str,
+    supported: &[T],
+    names: &[String],
+    name_of: impl Fn(&T) -> String,
+) -> Result<Vec<T>> {
+    if names.is_empty() {
+        return Ok(supported.to_vec());
+    }
+    for (i, name) in names.iter().enumerate() {
+        if names[..i]
+            .iter()
+            .any(|seen| seen.eq_ignore_ascii_case(name))
+        {
+            bail!("{what} `{name}` is listed twice");
+        }
+    }
+    names
+        .iter()
+        .map(|name| {
+            supported
+                .iter()
+                .find(|item| name_of(item).eq_ignore_ascii_case(name))
+                .cloned()
+                .ok_or_else(|| {
+                    let known = supported.iter().map(&name_of).collect::<Vec<_>>();
+                    anyhow!(
+                        "unknown {what} `{name}`; expected one of {}",
+                        known.join(", ")
+                    )
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tls(edit: impl FnOnce(&mut Tls)) -> Tls {
+        let mut tls: Tls = toml::from_str("cert = \"cert\"\nkey = \"key\"").unwrap();
+        edit(&mut tls);
+        tls
+    }
+
+    #[test]
+    fn defaults_allow_everything_rustls_supports() {
+        let policy = Policy::new(&tls(|_| {})).unwrap();
+        assert_eq!(policy.versions, [&TLS12, &TLS13]);
+        assert_eq!(policy.cipher_suites.len(), ALL_CIPHER_SUITES.len());
+        assert_eq!(policy.kx_groups.len(), ALL_KX_GROUPS.len());
+    }
+
+    #[test]
+    fn listed_suites_and_curves_keep_their_order() {
+        let policy = Policy::new(&tls(|tls| {
+            tls.min_version = Some(TlsVersion::Tls13);
+            tls.cipher_suites = vec![
+                "tls13_chacha20_poly1305_sha256".into(),
+                "TLS13_AES_256_GCM_SHA384".into(),
+            ];
+            tls.curves = vec!["secp384r1".into(), "x25519".into()];
+        }))
+        .unwrap();
+        assert_eq!(policy.versions, [&TLS13]);
+        let suites = policy
+            .cipher_suites
+            .iter()
+            .map(cipher_suite_name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            suites,
+            ["TLS13_CHACHA20_POLY1305_SHA256", "TLS13_AES_256_GCM_SHA384"]
+        );
+        let curves = policy
+            .kx_groups
+            .iter()
+            .map(|group| format!("{:?}", group.name))
+            .collect::<Vec<_>>();
+        assert_eq!(curves, ["secp384r1", "X25519"]);
+    }
+
+    #[test]
+    fn inconsistent_settings_fail() {
+        let err = |edit: fn(&mut Tls)| Policy::new(&tls(edit)).err().unwrap().to_string();
+        assert_eq!(
+            err(|tls| {
+                tls.min_version = Some(TlsVersion::Tls13);
+                tls.max_version = Some(TlsVersion::Tls12);
+            }),
+            "tls min_version (TLS 1.3) is above max_version (TLS 1.2)"
+        );
+        assert_eq!(
+            err(|tls| tls.cipher_suites = vec!["TLS13_AES_128_GCM_SHA256".into()]),
+            "tls cipher_suites has no TLS 1.2 suite, but TLS 1.2 is allowed"
+        );
+        assert!(err(|tls| tls.curves = vec!["P-521".into()])
+            .starts_with("unknown curve `P-521`; expected one of X25519, secp256r1"));
+        assert_eq!(
+            err(|tls| tls.curves = vec!["X25519".into(), "x25519".into()]),
+            "curve `x25519` is listed twice"
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! Certificate choice by the server name a client asks for, for listeners
+//! with `tls.certificates`.
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use anyhow::Result;
+use tokio_rustls::rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+/// A listener's certificates by server name, and the one serving the rest.
+pub(crate) struct SniCertificates<K> {
+    default: K,
+    named: Vec<K>,
+    /// Index into `named` by lowercase name; wildcards are kept as `*.parent`.
+    names: HashMap<String, usize>,
+}
+
+impl<K> SniCertificates<K> {
+    pub(crate) fn new(default: K) -> Self {
+        Self {
+            default,
+            named: Vec::new(),
+            names: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, server_names: &[String], key: K) {
+        for name in server_names {
+            self.names
+                .insert(name.to_ascii_lowercase(), self.named.len());
+        }
+        self.named.push(key);
+    }
+
+    /// The certificate for `server_name`: one naming it exactly, then one
+    /// with a matching wildcard, then the default.
+    pub(crate) fn pick(&self, server_name: Option<&str>) -> &K {
+        let Some(name) = server_name.map(str::to_ascii_lowercase) else {
+            return &self.default;
+        };
+        let wildcard = || {
+            let (_, parent) = name.split_once('.')?;
+            self.names.get(&format!("*.{parent}"))
+        };
+        match self.names.get(&name).or_else(wildcard) {
+            Some(index) => &self.named[*index],
+            None => &self.default,
+        }
+    }
+
+    pub(crate) fn try_map<U>(
+        self,
+        mut f: impl FnMut(K) -> Result<U>,
+    ) -> Result<SniCertificates<U>> {
+        Ok(SniCertificates {
+            default: f(self.default)?,
+            named: self.named.into_iter().map(f).collect::<Result<_>>()?,
+            names: self.names,
+        })
+    }
+}
+
+impl<K> fmt::Debug for SniCertificates<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SniCertificates")
+            .field("names", &self.names)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for SniCertificates<Arc<CertifiedKey>> {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.pick(client_hello.server_name()).clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_exact_names_then_wildcards_then_the_default() {
+        let mut certificates = SniCertificates::new("default");
+        certificates.add(&["api.example.com".into()], "api");
+        certificates.add(&["*.example.com".into(), "Example.com".into()], "wildcard");
+        let pick = |name| *certificates.pick(name);
+        assert_eq!(pick(Some("API.example.com")), "api");
+        assert_eq!(pick(Some("www.example.com")), "wildcard");
+        assert_eq!(pick(Some("example.com")), "wildcard");
+        // A wildcard covers one label.
+        assert_eq!(pick(Some("a.b.example.com")), "default");
+        assert_eq!(pick(Some("example.org")), "default");
+        assert_eq!(pick(None), "default");
+    }
+}
@@ -0,0 +1,124 @@
+//! Reloads listener certificates when their files change on disk, or when a
+//! reload is requested (SIGHUP), so renewals by certbot and the like are
+//! served without a restart. Open connections keep the certificate they
+//! negotiated.
+
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use tokio::sync::{watch, Notify};
+
+use super::KeyMismatch;
+use crate::config::Tls;
+
+/// How often certificate files are checked for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+type Stamp = Option<(SystemTime, u64)>;
+
+/// A TLS listener, the files its certificates are read from, and how to
+/// rebuild its TLS config from them.
+pub(crate) struct WatchedListener {
+    name: String,
+    files: Vec<PathBuf>,
+    /// Modification time and size of each file as of the last reload.
+    stamps: Vec<Stamp>,
+    /// Set while the files hold a key that does not match its certificate,
+    /// so the next check retries even if nothing else changes.
+    mismatched: bool,
+    reload: Box<dyn Fn() -> Result<()> + Send + Sync>,
+}
+
+impl WatchedListener {
+    pub(crate) fn new(
+        name: &str,
+        tls: &Tls,
+        reload: impl Fn() -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        let files = tls
+            .files()
+            .into_iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        Self {
+            name: name.to_string(),
+            stamps: files
+                .iter()
+                .map(|file| stamp(std::fs::metadata(file)))
+                .collect(),
+            files,
+            mismatched: false,
+            reload: Box::new(reload),
+        }
+    }
+
+    /// Reloads when any file changed, or unconditionally when `requested`.
+    async fn check(&mut self, requested: bool) {
+        let mut stamps = Vec::with_capacity(self.files.len());
+        for file in &self.files {
+            stamps.push(stamp(tokio::fs::metadata(file).await));
+        }
+        // A missing file is most likely being replaced; wait for it.
+        let changed = stamps != self.stamps;
+        if !requested && !self.mismatched && (!changed || stamps.contains(&None)) {
+            return;
+        }
+        // Remembered even if the reload fails, so a broken file is retried
+        // once it changes again, not on every check. A key written before its
+        // certificate (or the other way round) is the exception: that pair is
+        // retried every check until the other file catches up.
+        self.stamps = stamps;
+        let result = (self.reload)();
+        let mismatched = result
+            .as_ref()
+            .is_err_and(|err| err.chain().any(|cause| cause.is::<KeyMismatch>()));
+        let repeated = mismatched && self.mismatched && !changed && !requested;
+        self.mismatched = mismatched;
+        if repeated {
+            return;
+        }
+        let outcome = if result.is_ok() { "success" } else { "error" };
+        metrics::counter!(
+            "jester_tls_certificate_reloads_total",
+            "listener" => self.name.clone(),
+            "outcome" => outcome
+        )
+        .increment(1);
+        match result {
+            Ok(()) => tracing::info!(listener = self.name, "listener certificates reloaded"),
+            Err(err) => tracing::warn!(
+                listener = self.name,
+                error = format!("{err:#}"),
+                "failed to reload listener certificates; keeping the previous ones"
+            ),
+        }
+    }
+}
+
+fn stamp(metadata: std::io::Result<std::fs::Metadata>) -> Stamp {
+    let metadata = metadata.ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Checks `listeners` until shutdown, reloading all of them whenever
+/// `requested` is notified.
+pub(crate) async fn watch(
+    mut listeners: Vec<WatchedListener>,
+    requested: Arc<Notify>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let requested = tokio::select! {
+            _ = shutdown.changed() => return,
+            () = requested.notified() => true,
+            () = tokio::time::sleep(CHECK_INTERVAL) => false,
+        };
+        for listener in &mut listeners {
+            listener.check(requested).await;
+        }
+    }
+}
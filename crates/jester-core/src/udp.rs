@@ -0,0 +1,272 @@
+//! Listeners (`kind = "udp"`) relaying datagrams to one upstream, for DNS,
+//! QUIC backends, and game servers.
+//!
+//! Each client address gets a session: an upstream socket of its own, so the
+//! upstream's replies can be told apart and sent back to that client from the
+//! listener's socket. A session ends once no datagram has passed either way
+//! for `udp.idle_timeout`. UDP has nothing to tell a client it should go away,
+//! so sessions end as soon as the listener shuts down.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use tokio::{net::UdpSocket, sync::watch};
+
+use crate::{
+    config::{Listener, UdpTarget},
+    connection::{Counters, Lifecycle},
+    proxy::{AppState, ACCEPT_ERROR_BACKOFF},
+};
+
+/// The largest payload a UDP datagram can carry.
+const MAX_DATAGRAM_BYTES: usize = 65_535;
+
+pub(crate) struct UdpListener {
+    pub(crate) name: String,
+    pub(crate) addr: SocketAddr,
+    target: UdpTarget,
+    log_connections: bool,
+}
+
+impl TryFrom<&Listener> for UdpListener {
+    type Error = anyhow::Error;
+
+    fn try_from(listener: &Listener) -> Result<Self> {
+        Ok(Self {
+            name: listener.name.clone(),
+            addr: listener.parse_bind_addr().with_context(|| {
+                format!("invalid bind address for listener `{}`", listener.name)
+            })?,
+            target: listener
+                .udp
+                .clone()
+                .with_context(|| format!("udp listener `{}` has no `udp` table", listener.name))?,
+            log_connections: listener.log_connections,
+        })
+    }
+}
+
+impl UdpListener {
+    pub(crate) async fn bind(&self) -> io::Result<UdpSocket> {
+        UdpSocket::bind(self.addr).await
+    }
+
+    pub(crate) async fn serve(
+        self,
+        socket: UdpSocket,
+        state: Arc<AppState>,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<()> {
+        let relay = Arc::new(Relay {
+            listener: self,
+            socket,
+            sessions: Mutex::default(),
+            state,
+            shutdown: shutdown.clone(),
+        });
+        let mut buf = vec![0; MAX_DATAGRAM_BYTES];
+        loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.changed() => {
+                    tracing::info!(listener = relay.listener.name, "listener shutting down");
+                    break;
+                }
+                received = relay.socket.recv_from(&mut buf) => {
+                    let (len, peer_addr) = match received {
+                        Ok(received) => received,
+                        Err(err) => {
+                            tracing::warn!(listener = relay.listener.name, error = %err, "udp receive failed");
+                            tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    relay.forward(&buf[..len], peer_addr).await;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One client's association with the upstream.
+struct Session {
+    upstream: Arc<UdpSocket>,
+    counters: Arc<Counters>,
+    last_active: Instant,
+}
+
+/// A serving listener: the socket clients send to and replies leave from,
+/// and the open sessions by client address.
+struct Relay {
+    listener: UdpListener,
+    socket: UdpSocket,
+    sessions: Mutex<HashMap<SocketAddr, Session>>,
+    state: Arc<AppState>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Relay {
+    /// Sends a client's datagram upstream through its session, opening one
+    /// for a client without.
+    async fn forward(self: &Arc<Self>, datagram: &[u8], peer_addr: SocketAddr) {
+        let existing = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut(&peer_addr)
+            .map(|session| {
+                session.last_active = Instant::now();
+                (session.upstream.clone(), session.counters.clone())
+            });
+        let opened = match existing {
+            Some(session) => Some(session),
+            None => self.open(peer_addr).await,
+        };
+        let Some((upstream, counters)) = opened else {
+            return;
+        };
+        counters.received(datagram.len());
+        if let Err(err) = upstream.send(datagram).await {
+            tracing::debug!(listener = self.listener.name, %peer_addr, error = %err, "udp send failed");
+            self.dropped("upstream_unreachable");
+        }
+    }
+
+    /// Opens a session for `peer_addr` unless `max_sessions` are open, and
+    /// starts relaying the upstream's replies to it.
+    async fn open(
+        self: &Arc<Self>,
+        peer_addr: SocketAddr,
+    ) -> Option<(Arc<UdpSocket>, Arc<Counters>)> {
+        let target = &self.listener.target;
+        let open = self.sessions.lock().unwrap().len();
+        if target.max_sessions.is_some_and(|max| open >= max) {
+            self.dropped("session_limit");
+            return None;
+        }
+        let lifecycle = Lifecycle::accepted(
+            &self.state.stats,
+            &self.listener.name,
+            peer_addr,
+            self.listener.log_connections,
+        );
+        let upstream = match connect(&target.upstream).await {
+            Ok(upstream) => Arc::new(upstream),
+            Err(err) => {
+                tracing::warn!(listener = self.listener.name, %peer_addr, error = %err, "udp session failed");
+                lifecycle.close("upstream_unreachable");
+                self.dropped("upstream_unreachable");
+                return None;
+            }
+        };
+        let counters = lifecycle.counters();
+        self.sessions.lock().unwrap().insert(
+            peer_addr,
+            Session {
+                upstream: upstream.clone(),
+                counters: counters.clone(),
+                last_active: Instant::now(),
+            },
+        );
+        tokio::spawn(self.clone().relay(lifecycle, peer_addr, upstream.clone()));
+        Some((upstream, counters))
+    }
+
+    /// Sends the upstream's replies back to the client until the session
+    /// goes idle, the upstream refuses it, or the listener shuts down.
+    async fn relay(
+        self: Arc<Self>,
+        lifecycle: Lifecycle,
+        peer_addr: SocketAddr,
+        upstream: Arc<UdpSocket>,
+    ) {
+        let target = format!("udp://{}", self.listener.target.upstream);
+        metrics::counter!("jester_upstream_connections_opened_total", "target" => target.clone())
+            .increment(1);
+        let _open = self.state.stats.track_connection(&target);
+        let counters = lifecycle.counters();
+        let idle = Duration::from_secs(self.listener.target.idle_timeout_secs);
+        let mut shutdown = self.shutdown.clone();
+        let mut shutdown = pin!(async move {
+            shutdown.wait_for(|stop| *stop).await.ok();
+        });
+        let mut buf = vec![0; MAX_DATAGRAM_BYTES];
+        let mut expires = Instant::now() + idle;
+        // Only this task removes the session, and it checks for activity and
+        // removes under one lock, so no datagram goes to a session about to end.
+        let end = |reason| {
+            self.sessions.lock().unwrap().remove(&peer_addr);
+            reason
+        };
+        let reason = loop {
+            tokio::select! {
+                () = &mut shutdown => break end("drained"),
+                received = upstream.recv(&mut buf) => {
+                    let Ok(len) = received else {
+                        // An ICMP error, such as port unreachable, for an
+                        // earlier datagram.
+                        break end("upstream_unreachable");
+                    };
+                    if let Some(session) = self.sessions.lock().unwrap().get_mut(&peer_addr) {
+                        session.last_active = Instant::now();
+                    }
+                    match self.socket.send_to(&buf[..len], peer_addr).await {
+                        Ok(sent) => counters.sent(sent),
+                        Err(err) => {
+                            tracing::debug!(listener = self.listener.name, %peer_addr, error = %err, "udp reply failed");
+                        }
+                    }
+                }
+                () = tokio::time::sleep_until(expires.into()) => {
+                    let mut sessions = self.sessions.lock().unwrap();
+                    match sessions.get(&peer_addr).map(|session| session.last_active) {
+                        Some(last_active) if last_active.elapsed() < idle => expires = last_active + idle,
+                        _ => {
+                            sessions.remove(&peer_addr);
+                            break "idle_timeout";
+                        }
+                    }
+                }
+            }
+        };
+        lifecycle.close(reason);
+    }
+
+    fn dropped(&self, reason: &'static str) {
+        metrics::counter!(
+            "jester_udp_dropped_datagrams_total",
+            "listener" => self.listener.name.clone(),
+            "reason" => reason
+        )
+        .increment(1);
+    }
+}
+
+/// A socket connected to `upstream`, so it receives only that peer's
+/// datagrams.
+async fn connect(upstream: &str) -> Result<UdpSocket> {
+    let addr = tokio::net::lookup_host(upstream)
+        .await
+        .with_context(|| format!("failed to resolve {upstream}"))?
+        .next()
+        .with_context(|| format!("{upstream} resolved to no address"))?;
+    let local: SocketAddr = if addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket
+        .connect(addr)
+        .await
+        .with_context(|| format!("failed to connect to {upstream}"))?;
+    Ok(socket)
+}
@@ -0,0 +1,586 @@
+//! `type = "wasm"` filters: components built against the SDK's `filter` world
+//! (`crates/jester-plugin-sdk/wit`), run with wasmtime.
+//!
+//! The request, body included, is handed to the component's `http-filter`,
+//! which answers with a response or with the request to forward. Each chain
+//! that uses a filter runs it on a [`Pool`] of ready instances, so requests do
+//! not pay for instantiation. The component is compiled once per process and
+//! again only when its file changes. The host functions it imports act
+//! through the filter's [`PluginHost`], with the context functions reading
+//! and writing the [`RequestContext`] of the request being filtered.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    task::{Context as TaskContext, Poll},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context, Result};
+use http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri};
+use jester_plugin_sdk::PluginManifest;
+use tower::{layer::layer_fn, Service};
+use tracing::Level;
+use wasmtime::{
+    component::{Component, Linker},
+    Engine,
+};
+
+use crate::{
+    builtins::{forward, read_body},
+    config,
+    context::RequestContext,
+    plugin::{
+        full_body, text_response, DynLayer, HttpRequest, HttpResponse, JesterService,
+        ResponseFuture,
+    },
+    plugin_host::PluginHost,
+};
+
+mod pool;
+
+use pool::{Checkout, Pool};
+
+wasmtime::component::bindgen!({
+    path: "../jester-plugin-sdk/wit",
+    world: "filter",
+});
+
+use exports::jester::plugin::http::{Request as WasmRequest, Response as WasmResponse};
+use jester::plugin::host::{self, Level as WasmLevel};
+
+/// What an instance's host functions act for.
+pub(crate) struct State {
+    host: Arc<PluginHost>,
+    /// The context of the request being filtered, set for each call.
+    context: Option<RequestContext>,
+}
+
+impl host::Host for State {
+    fn log(&mut self, level: WasmLevel, message: String, fields: Vec<(String, String)>) {
+        let level = match level {
+            WasmLevel::Trace => Level::TRACE,
+            WasmLevel::Debug => Level::DEBUG,
+            WasmLevel::Info => Level::INFO,
+            WasmLevel::Warn => Level::WARN,
+            WasmLevel::Error => Level::ERROR,
+        };
+        self.host.log(level, &message, &fields);
+    }
+
+    fn counter_add(
+        &mut self,
+        name: String,
+        value: u64,
+        labels: Vec<(String, String)>,
+    ) -> Result<(), String> {
+        self.host
+            .counter_add(&name, value, &labels)
+            .map_err(|err| err.to_string())
+    }
+
+    fn gauge_set(
+        &mut self,
+        name: String,
+        value: f64,
+        labels: Vec<(String, String)>,
+    ) -> Result<(), String> {
+        self.host
+            .gauge_set(&name, value, &labels)
+            .map_err(|err| err.to_string())
+    }
+
+    fn histogram_record(
+        &mut self,
+        name: String,
+        value: f64,
+        labels: Vec<(String, String)>,
+    ) -> Result<(), String> {
+        self.host
+            .histogram_record(&name, value, &labels)
+            .map_err(|err| err.to_string())
+    }
+
+    fn context_get(&mut self, key: String) -> Option<String> {
+        let context = self.context.as_ref()?;
+        self.host.context_get(context, &key)
+    }
+
+    fn context_set(&mut self, key: String, value: String) -> Result<Option<String>, String> {
+        let Some(context) = &self.context else {
+            return Err("no request context".to_string());
+        };
+        self.host
+            .context_set(context, &key, &value)
+            .map_err(|err| format!("{err:#}"))
+    }
+}
+
+/// Builds the layer of a `wasm` filter in the chain named `chain`: a route's
+/// name, or `global`.
+pub(crate) fn layer(filter: &config::Filter, chain: &str) -> Result<DynLayer> {
+    let config::Filter::Wasm {
+        name,
+        module,
+        config,
+        pool,
+        max_body_bytes,
+        ..
+    } = filter
+    else {
+        bail!("filter `{}` is not a wasm filter", filter.name());
+    };
+    if !config.is_null() {
+        bail!("wasm filters take no `config`; the `filter` world has no way to pass it");
+    }
+    pool.validate()?;
+    let manifest = manifest(Path::new(module))?;
+    let host = Arc::new(PluginHost::new(&manifest)?);
+    let linker = linker()?;
+    let pre = FilterPre::new(linker.instantiate_pre(&component(Path::new(module))?)?)
+        .with_context(|| format!("{module} does not export the `filter` world"))?;
+    let pool = Pool::new(chain, name, pre, host, pool.clone())?;
+    let max_body_bytes = *max_body_bytes;
+    Ok(Box::new(layer_fn(move |inner: JesterService| {
+        JesterService::new(WasmService {
+            inner,
+            pool: pool.clone(),
+            max_body_bytes,
+        })
+    })))
+}
+
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(Engine::default)
+}
+
+fn linker() -> Result<Linker<State>> {
+    let mut linker = Linker::new(engine());
+    Filter::add_to_linker(&mut linker, |state: &mut State| state)?;
+    Ok(linker)
+}
+
+/// The manifest next to `module`, as `<module stem>.json`.
+fn manifest(module: &Path) -> Result<PluginManifest> {
+    let path = module.with_extension("json");
+    let data = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read plugin manifest {}", path.display()))?;
+    let manifest: PluginManifest = serde_json::from_str(&data)
+        .with_context(|| format!("failed to parse plugin manifest {}", path.display()))?;
+    manifest
+        .validate()
+        .with_context(|| format!("invalid plugin manifest {}", path.display()))?;
+    Ok(manifest)
+}
+
+type Stamp = Option<(SystemTime, u64)>;
+
+/// Compiles `module`, or reuses the compilation of an unchanged file.
+fn component(module: &Path) -> Result<Component> {
+    static COMPILED: OnceLock<Mutex<HashMap<PathBuf, (Stamp, Component)>>> = OnceLock::new();
+    let stamp = std::fs::metadata(module)
+        .ok()
+        .and_then(|metadata| Some((metadata.modified().ok()?, metadata.len())));
+    let mut compiled = COMPILED
+        .get_or_init(Mutex::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some((compiled_from, component)) = compiled.get(module) {
+        if stamp.is_some() && *compiled_from == stamp {
+            return Ok(component.clone());
+        }
+    }
+    let component = Component::from_file(engine(), module)
+        .with_context(|| format!("failed to compile {}", module.display()))?;
+    compiled.insert(module.to_path_buf(), (stamp, component.clone()));
+    Ok(component)
+}
+
+#[derive(Clone)]
+struct WasmService {
+    inner: JesterService,
+    pool: Arc<Pool>,
+    max_body_bytes: usize,
+}
+
+impl Service<HttpRequest> for WasmService {
+    type Response = HttpResponse;
+    type Error = anyhow::Error;
+    type Future = ResponseFuture;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: HttpRequest) -> Self::Future {
+        let inner = self.inner.clone();
+        let pool = self.pool.clone();
+        let limit = self.max_body_bytes;
+        Box::pin(async move {
+            let (mut parts, body) = req.into_parts();
+            let Some(body) = read_body(body, limit)
+                .await
+                .map_err(|err| anyhow::anyhow!(err))?
+            else {
+                return Ok(text_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "request body too large",
+                ));
+            };
+            let request = WasmRequest {
+                method: parts.method.to_string(),
+                uri: parts.uri.to_string(),
+                headers: to_fields(&parts.headers),
+                body: body.to_vec(),
+            };
+            let instance = match pool.checkout().await {
+                Checkout::Ready(instance) => instance,
+                Checkout::Exhausted => {
+                    return Ok(text_response(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "filter busy",
+                    ))
+                }
+                Checkout::Failed(err) => {
+                    tracing::warn!(
+                        filter = pool.filter(),
+                        error = format!("{err:#}"),
+                        "failed to instantiate wasm filter"
+                    );
+                    return Ok(text_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "filter failed",
+                    ));
+                }
+            };
+            let context = parts.extensions.get::<RequestContext>().cloned();
+            let outcome = match instance.call(request, context).await {
+                Ok(outcome) => outcome,
+                Err(err) => {
+                    tracing::warn!(
+                        filter = pool.filter(),
+                        error = format!("{err:#}"),
+                        "wasm filter trapped"
+                    );
+                    return Ok(text_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "filter failed",
+                    ));
+                }
+            };
+            match outcome {
+                Ok(response) => respond(response)
+                    .with_context(|| format!("wasm filter `{}` answered badly", pool.filter())),
+                Err(request) => {
+                    parts.method = Method::from_bytes(request.method.as_bytes())?;
+                    parts.uri = request.uri.parse::<Uri>()?;
+                    parts.headers = from_fields(request.headers, request.body.len())?;
+                    let req = HttpRequest::from_parts(parts, full_body(request.body));
+                    forward(inner, req).await
+                }
+            }
+        })
+    }
+}
+
+fn respond(response: WasmResponse) -> Result<HttpResponse> {
+    let mut answer = HttpResponse::new(full_body(Vec::new()));
+    *answer.status_mut() = StatusCode::from_u16(response.status)?;
+    *answer.headers_mut() = from_fields(response.headers, response.body.len())?;
+    *answer.body_mut() = full_body(response.body);
+    Ok(answer)
+}
+
+/// Header values that are not UTF-8 reach the plugin with replacement
+/// characters.
+fn to_fields(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+/// Headers from a plugin, framed for a body of `length` bytes.
+fn from_fields(fields: Vec<(String, String)>, length: usize) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::with_capacity(fields.len());
+    for (name, value) in fields {
+        headers.append(
+            HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid header name `{name}`"))?,
+            HeaderValue::try_from(value)
+                .with_context(|| format!("invalid value for header `{name}`"))?,
+        );
+    }
+    headers.remove(header::TRANSFER_ENCODING);
+    if length > 0 || headers.contains_key(header::CONTENT_LENGTH) {
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+    }
+    Ok(headers)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use bytes::Bytes;
+    use http::Request;
+    use http_body_util::BodyExt;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+    use crate::config::Filter;
+
+    /// Hands the request back unchanged.
+    pub(super) const PASS: &str = "
+      (i32.store8 (i32.const 0) (i32.const 1))
+      (i32.store (i32.const 4) (local.get 0))
+      (i32.store (i32.const 8) (local.get 1))
+      (i32.store (i32.const 12) (local.get 2))
+      (i32.store (i32.const 16) (local.get 3))
+      (i32.store (i32.const 20) (local.get 4))
+      (i32.store (i32.const 24) (local.get 5))
+      (i32.store (i32.const 28) (local.get 6))
+      (i32.store (i32.const 32) (local.get 7))";
+
+    /// Answers `418 teapot`.
+    const RESPOND: &str = "
+      (i32.store8 (i32.const 0) (i32.const 0))
+      (i32.store16 (i32.const 4) (i32.const 418))
+      (i32.store (i32.const 8) (i32.const 0))
+      (i32.store (i32.const 12) (i32.const 0))
+      (i32.store (i32.const 16) (i32.const 512))
+      (i32.store (i32.const 20) (i32.const 6))";
+
+    pub(super) const TRAP: &str = "unreachable";
+
+    /// A component of the `filter` world whose `http-filter` runs `body`, a
+    /// core function of the eight flattened request fields that leaves the
+    /// result at address 0.
+    pub(super) fn guest(body: &str) -> String {
+        format!(
+            r#"(component
+  (type $headers (list (tuple string string)))
+  (type $request (record (field "method" string) (field "uri" string)
+    (field "headers" $headers) (field "body" (list u8))))
+  (type $response (record (field "status" u16) (field "headers" $headers)
+    (field "body" (list u8))))
+  (core module $m
+    (memory (export "memory") 1)
+    (data (i32.const 512) "teapot")
+    (global $next (mut i32) (i32.const 1024))
+    (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+      (local $at i32)
+      (local.set $at (i32.and
+        (i32.add (global.get $next) (i32.sub (local.get 2) (i32.const 1)))
+        (i32.sub (i32.const 0) (local.get 2))))
+      (global.set $next (i32.add (local.get $at) (local.get 3)))
+      (local.get $at))
+    (func (export "filter") (param i32 i32 i32 i32 i32 i32 i32 i32) (result i32)
+      {body}
+      (i32.const 0)))
+  (core instance $i (instantiate $m))
+  (func $filter (param "req" $request) (result (result $response (error $request)))
+    (canon lift (core func $i "filter") (memory $i "memory") (realloc (func $i "realloc"))))
+  (instance $http
+    (export "headers" (type $headers))
+    (export "request" (type $request))
+    (export "response" (type $response))
+    (export "http-filter" (func $filter)))
+  (export "jester:plugin/http@{version}" (instance $http)))"#,
+            version = jester_plugin_sdk::SDK_VERSION,
+        )
+    }
+
+    pub(super) fn pre(body: &str) -> FilterPre<State> {
+        let component = Component::new(engine(), guest(body)).unwrap();
+        FilterPre::new(linker().unwrap().instantiate_pre(&component).unwrap()).unwrap()
+    }
+
+    pub(super) fn host() -> Arc<PluginHost> {
+        Arc::new(PluginHost::new(&manifest()).unwrap())
+    }
+
+    fn manifest() -> PluginManifest {
+        PluginManifest {
+            name: "test".into(),
+            sdk_version: Some(jester_plugin_sdk::SDK_VERSION.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Answers `200` with the JSON stored under `in` as its body, after
+    /// storing `true` under `out`.
+    fn context_guest() -> String {
+        format!(
+            r#"(component
+  (type $headers (list (tuple string string)))
+  (type $request (record (field "method" string) (field "uri" string)
+    (field "headers" $headers) (field "body" (list u8))))
+  (type $response (record (field "status" u16) (field "headers" $headers)
+    (field "body" (list u8))))
+  (import "jester:plugin/host@{version}" (instance $host
+    (export "context-get" (func (param "key" string) (result (option string))))
+    (export "context-set" (func (param "key" string) (param "value" string)
+      (result (result (option string) (error string)))))))
+  (core module $memory
+    (memory (export "memory") 1)
+    (global $next (mut i32) (i32.const 1024))
+    (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+      (local $at i32)
+      (local.set $at (i32.and
+        (i32.add (global.get $next) (i32.sub (local.get 2) (i32.const 1)))
+        (i32.sub (i32.const 0) (local.get 2))))
+      (global.set $next (i32.add (local.get $at) (local.get 3)))
+      (local.get $at)))
+  (core instance $m (instantiate $memory))
+  (core func $get (canon lower (func $host "context-get")
+    (memory $m "memory") (realloc (func $m "realloc"))))
+  (core func $set (canon lower (func $host "context-set")
+    (memory $m "memory") (realloc (func $m "realloc"))))
+  (core module $filter
+    (import "env" "memory" (memory 1))
+    (import "host" "get" (func $get (param i32 i32 i32)))
+    (import "host" "set" (func $set (param i32 i32 i32 i32 i32)))
+    (data (i32.const 512) "in")
+    (data (i32.const 520) "out")
+    (data (i32.const 528) "true")
+    (func (export "filter") (param i32 i32 i32 i32 i32 i32 i32 i32) (result i32)
+      (i32.store (i32.const 260) (i32.const 0))
+      (i32.store (i32.const 264) (i32.const 0))
+      (call $get (i32.const 512) (i32.const 2) (i32.const 256))
+      (call $set (i32.const 520) (i32.const 3) (i32.const 528) (i32.const 4) (i32.const 320))
+      (i32.store8 (i32.const 0) (i32.const 0))
+      (i32.store16 (i32.const 4) (i32.const 200))
+      (i32.store (i32.const 8) (i32.const 0))
+      (i32.store (i32.const 12) (i32.const 0))
+      (i32.store (i32.const 16) (i32.load (i32.const 260)))
+      (i32.store (i32.const 20) (i32.load (i32.const 264)))
+      (i32.const 0)))
+  (core instance $i (instantiate $filter
+    (with "env" (instance (export "memory" (memory $m "memory"))))
+    (with "host" (instance (export "get" (func $get)) (export "set" (func $set))))))
+  (func $filter (param "req" $request) (result (result $response (error $request)))
+    (canon lift (core func $i "filter") (memory $m "memory") (realloc (func $m "realloc"))))
+  (instance $http
+    (export "headers" (type $headers))
+    (export "request" (type $request))
+    (export "response" (type $response))
+    (export "http-filter" (func $filter)))
+  (export "jester:plugin/http@{version}" (instance $http)))"#,
+            version = jester_plugin_sdk::SDK_VERSION,
+        )
+    }
+
+    /// Writes the component and its manifest as `<dir>/<name>.wat` and `.json`.
+    fn module(name: &str, component: String) -> String {
+        let dir = std::env::temp_dir().join(format!("jester-wasm-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(format!("{name}.json")),
+            serde_json::to_string(&manifest()).unwrap(),
+        )
+        .unwrap();
+        let path = dir.join(format!("{name}.wat"));
+        std::fs::write(&path, component).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn service(filter: Filter) -> JesterService {
+        let layer = super::layer(&filter, "api").unwrap();
+        layer.layer(JesterService::new(service_fn(
+            |req: HttpRequest| async move {
+                let method = req.method().to_string();
+                let echoed = req.headers().get("x-echo").cloned();
+                let body = req.into_body().collect().await.unwrap().to_bytes();
+                let mut response = text_response(StatusCode::OK, format!("{method} {body:?}"));
+                if let Some(echoed) = echoed {
+                    response.headers_mut().insert("x-echo", echoed);
+                }
+                Ok::<_, anyhow::Error>(response)
+            },
+        )))
+    }
+
+    async fn send(service: &JesterService, body: &'static str) -> (StatusCode, HeaderMap, Bytes) {
+        let req = Request::post("/items")
+            .header("x-echo", "yes")
+            .body(full_body(body))
+            .unwrap();
+        let response = service.clone().oneshot(req).await.unwrap();
+        let (parts, body) = response.into_parts();
+        (
+            parts.status,
+            parts.headers,
+            body.collect().await.unwrap().to_bytes(),
+        )
+    }
+
+    #[tokio::test]
+    async fn requests_are_forwarded_or_answered() {
+        let pass = service(Filter::wasm("pass", module("pass", guest(PASS))).build());
+        let (status, headers, body) = send(&pass, "hello").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["x-echo"], "yes");
+        assert_eq!(body, "POST b\"hello\"");
+
+        let respond = service(Filter::wasm("respond", module("respond", guest(RESPOND))).build());
+        let (status, _, body) = send(&respond, "hello").await;
+        assert_eq!(status.as_u16(), 418);
+        assert_eq!(body, "teapot");
+    }
+
+    #[tokio::test]
+    async fn traps_and_large_bodies_are_answered_by_jester() {
+        let trap = service(Filter::wasm("trap", module("trap", guest(TRAP))).build());
+        for _ in 0..2 {
+            let (status, _, body) = send(&trap, "hello").await;
+            assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(body, "filter failed");
+        }
+
+        let mut filter = Filter::wasm("small", module("small", guest(PASS))).build();
+        if let Filter::Wasm { max_body_bytes, .. } = &mut filter {
+            *max_body_bytes = 4;
+        }
+        let (status, _, _) = send(&service(filter), "hello").await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn filters_share_the_request_context() {
+        let filter = Filter::wasm("context", module("context", context_guest())).build();
+        let service = service(filter);
+        let context = RequestContext::default();
+        context.set_value("in", serde_json::json!({"user": "alice"}));
+        let mut req = Request::get("/").body(full_body("")).unwrap();
+        req.extensions_mut().insert(context.clone());
+        let response = service.clone().oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, r#"{"user":"alice"}"#);
+        assert_eq!(context.value("out"), Some(serde_json::json!(true)));
+
+        let (status, _, body) = send(&service, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "", "requests without a context read nothing");
+    }
+
+    #[test]
+    fn filters_without_a_component_or_with_config_are_refused() {
+        let filter = Filter::wasm("missing", "/nonexistent/missing.wasm").build();
+        let err = super::layer(&filter, "api").err().unwrap();
+        assert!(format!("{err:#}").contains("plugin manifest"), "{err:#}");
+
+        let mut filter = Filter::wasm("configured", module("configured", guest(PASS))).build();
+        if let Filter::Wasm { config, .. } = &mut filter {
+            *config = serde_json::json!({"limit": 1});
+        }
+        let err = super::layer(&filter, "api").err().unwrap();
+        assert!(err.to_string().contains("take no `config`"), "{err}");
+    }
+}
@@ -0,0 +1,399 @@
+//! Ready instances of one `wasm` filter in one chain.
+//!
+//! At most `size` instances exist and run at once. A request checks one out,
+//! instantiating it only when none is idle, and returns it afterwards. An
+//! instance that has served `max_uses` requests, or that trapped, is dropped
+//! and a fresh one is instantiated in the background to take its place.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use wasmtime::Store;
+
+use super::{engine, FilterPre, State, WasmRequest, WasmResponse};
+use crate::{
+    config::{PoolExhausted, WasmPool},
+    context::RequestContext,
+    plugin_host::PluginHost,
+};
+
+pub(crate) struct Pool {
+    chain: String,
+    filter: String,
+    pre: FilterPre<State>,
+    host: Arc<PluginHost>,
+    settings: WasmPool,
+    idle: Mutex<Vec<Instance>>,
+    /// One per instance that may run at once.
+    permits: Arc<Semaphore>,
+    /// Instances that exist, idle or busy, or are being made on demand.
+    live: AtomicUsize,
+}
+
+struct Instance {
+    store: Store<State>,
+    bindings: super::Filter,
+    uses: u64,
+}
+
+/// What a request gets from [`Pool::checkout`].
+pub(crate) enum Checkout {
+    Ready(Lease),
+    /// Every instance stayed busy; see [`WasmPool::on_exhausted`].
+    Exhausted,
+    /// No idle instance, and making one failed.
+    Failed(anyhow::Error),
+}
+
+/// A checked-out instance, returned to its pool by [`Lease::call`] or when
+/// dropped.
+pub(crate) struct Lease {
+    pool: Arc<Pool>,
+    instance: Option<Instance>,
+    /// Released once the instance is back.
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Pool {
+    /// Makes the pool and its `prewarm` instances; failing to make one fails
+    /// the chain, as an invalid filter does.
+    pub(crate) fn new(
+        chain: &str,
+        filter: &str,
+        pre: FilterPre<State>,
+        host: Arc<PluginHost>,
+        settings: WasmPool,
+    ) -> Result<Arc<Self>> {
+        let pool = Arc::new(Self {
+            chain: chain.to_string(),
+            filter: filter.to_string(),
+            pre,
+            host,
+            permits: Arc::new(Semaphore::new(settings.size)),
+            idle: Mutex::new(Vec::with_capacity(settings.size)),
+            live: AtomicUsize::new(0),
+            settings,
+        });
+        for _ in 0..pool.settings.prewarm() {
+            let instance = pool.instantiate("prewarm")?;
+            pool.live.fetch_add(1, Ordering::Relaxed);
+            pool.idle().push(instance);
+        }
+        pool.report();
+        Ok(pool)
+    }
+
+    pub(crate) fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    pub(crate) async fn checkout(self: &Arc<Self>) -> Checkout {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) if self.settings.on_exhausted == PoolExhausted::Reject => {
+                self.exhausted("rejected");
+                None
+            }
+            Err(_) => {
+                let wait = Duration::from_millis(self.settings.wait_timeout_ms);
+                match tokio::time::timeout(wait, self.permits.clone().acquire_owned()).await {
+                    Ok(Ok(permit)) => {
+                        self.exhausted("waited");
+                        Some(permit)
+                    }
+                    _ => {
+                        self.exhausted("timed_out");
+                        None
+                    }
+                }
+            }
+        };
+        let Some(permit) = permit else {
+            return Checkout::Exhausted;
+        };
+        let idle = self.idle().pop();
+        let instance = match idle {
+            Some(instance) => instance,
+            None => {
+                // Counted before it exists, so a replacement finishing
+                // meanwhile does not push the pool past `size`.
+                self.live.fetch_add(1, Ordering::Relaxed);
+                match self.instantiate("demand") {
+                    Ok(instance) => instance,
+                    Err(err) => {
+                        self.live.fetch_sub(1, Ordering::Relaxed);
+                        return Checkout::Failed(err);
+                    }
+                }
+            }
+        };
+        self.report();
+        Checkout::Ready(Lease {
+            pool: self.clone(),
+            instance: Some(instance),
+            permit: Some(permit),
+        })
+    }
+
+    fn instantiate(&self, reason: &'static str) -> Result<Instance> {
+        let mut store = Store::new(
+            engine(),
+            State {
+                host: self.host.clone(),
+                context: None,
+            },
+        );
+        let bindings = self.pre.instantiate(&mut store)?;
+        metrics::counter!(
+            "jester_wasm_instantiations_total",
+            "route" => self.chain.clone(),
+            "filter" => self.filter.clone(),
+            "reason" => reason
+        )
+        .increment(1);
+        Ok(Instance {
+            store,
+            bindings,
+            uses: 0,
+        })
+    }
+
+    /// Takes back an instance after a call, unless it is used up.
+    fn give_back(self: &Arc<Self>, instance: Instance) {
+        let max_uses = self.settings.max_uses;
+        if max_uses > 0 && instance.uses >= max_uses {
+            self.retire(instance, "max_uses");
+        } else {
+            self.idle().push(instance);
+            self.report();
+        }
+    }
+
+    /// Drops `instance` and makes a replacement off the request path.
+    fn retire(self: &Arc<Self>, instance: Instance, reason: &'static str) {
+        drop(instance);
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        metrics::counter!(
+            "jester_wasm_instance_retirements_total",
+            "route" => self.chain.clone(),
+            "filter" => self.filter.clone(),
+            "reason" => reason
+        )
+        .increment(1);
+        self.report();
+        let pool = self.clone();
+        tokio::task::spawn_blocking(move || match pool.instantiate("replace") {
+            Ok(instance) => {
+                let mut idle = pool.idle();
+                let reserve =
+                    pool.live
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |live| {
+                            (live < pool.settings.size).then_some(live + 1)
+                        });
+                if reserve.is_ok() {
+                    idle.push(instance);
+                }
+                drop(idle);
+                pool.report();
+            }
+            Err(err) => tracing::warn!(
+                route = pool.chain,
+                filter = pool.filter,
+                error = format!("{err:#}"),
+                "failed to replace wasm filter instance; the next request makes one"
+            ),
+        });
+    }
+
+    fn idle(&self) -> std::sync::MutexGuard<'_, Vec<Instance>> {
+        self.idle
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn exhausted(&self, outcome: &'static str) {
+        metrics::counter!(
+            "jester_wasm_pool_exhausted_total",
+            "route" => self.chain.clone(),
+            "filter" => self.filter.clone(),
+            "outcome" => outcome
+        )
+        .increment(1);
+    }
+
+    fn report(&self) {
+        let idle = self.idle().len();
+        let live = self.live.load(Ordering::Relaxed);
+        for (state, count) in [("idle", idle), ("busy", live.saturating_sub(idle))] {
+            metrics::gauge!(
+                "jester_wasm_pool_instances",
+                "route" => self.chain.clone(),
+                "filter" => self.filter.clone(),
+                "state" => state
+            )
+            .set(count as f64);
+        }
+    }
+}
+
+impl Lease {
+    /// Runs the filter on a blocking thread, which then returns the instance
+    /// to the pool, or retires it if the call trapped. The instance goes back
+    /// even if the request is dropped meanwhile.
+    pub(crate) async fn call(
+        mut self,
+        request: WasmRequest,
+        context: Option<RequestContext>,
+    ) -> Result<Result<WasmResponse, WasmRequest>> {
+        let pool = self.pool.clone();
+        let (Some(mut instance), Some(permit)) = (self.instance.take(), self.permit.take()) else {
+            unreachable!("leases are called once");
+        };
+        let call = tokio::task::spawn_blocking(move || {
+            instance.store.data_mut().context = context;
+            let result = instance
+                .bindings
+                .jester_plugin_http()
+                .call_http_filter(&mut instance.store, &request);
+            instance.store.data_mut().context = None;
+            instance.uses += 1;
+            match result {
+                Ok(_) => pool.give_back(instance),
+                Err(_) => pool.retire(instance, "trap"),
+            }
+            drop(permit);
+            result
+        });
+        match call.await {
+            Ok(result) => result,
+            Err(err) => {
+                // The instance went down with the thread that ran it.
+                self.pool.live.fetch_sub(1, Ordering::Relaxed);
+                self.pool.report();
+                Err(err.into())
+            }
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(instance) = self.instance.take() {
+            self.pool.give_back(instance);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasm::tests::{host, pre, PASS, TRAP};
+
+    fn pool(body: &str, settings: WasmPool) -> Arc<Pool> {
+        Pool::new("api", "test", pre(body), host(), settings).unwrap()
+    }
+
+    fn request() -> WasmRequest {
+        WasmRequest {
+            method: "GET".into(),
+            uri: "/".into(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    async fn lease(pool: &Arc<Pool>) -> Lease {
+        match pool.checkout().await {
+            Checkout::Ready(lease) => lease,
+            Checkout::Exhausted => panic!("pool exhausted"),
+            Checkout::Failed(err) => panic!("{err:#}"),
+        }
+    }
+
+    /// Waits for background replacements to land.
+    async fn settled(pool: &Pool, idle: usize) {
+        for _ in 0..200 {
+            if pool.idle().len() == idle {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("pool has {} idle instances, not {idle}", pool.idle().len());
+    }
+
+    #[tokio::test]
+    async fn used_up_and_trapped_instances_are_replaced() {
+        let settings = WasmPool {
+            size: 1,
+            max_uses: 2,
+            ..Default::default()
+        };
+        let pool = pool(PASS, settings.clone());
+        for uses in [1, 2] {
+            assert!(lease(&pool)
+                .await
+                .call(request(), None)
+                .await
+                .unwrap()
+                .is_err());
+            if uses == 1 {
+                assert_eq!(pool.idle()[0].uses, 1);
+            }
+        }
+        settled(&pool, 1).await;
+        assert_eq!(
+            pool.idle()[0].uses,
+            0,
+            "a fresh instance replaced the used one"
+        );
+        assert_eq!(pool.live.load(Ordering::Relaxed), 1);
+
+        let pool = self::pool(TRAP, settings);
+        assert!(lease(&pool).await.call(request(), None).await.is_err());
+        settled(&pool, 1).await;
+        assert_eq!(pool.live.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn exhausted_pools_reject_or_wait() {
+        let pool = self::pool(
+            PASS,
+            WasmPool {
+                size: 1,
+                prewarm: Some(0),
+                on_exhausted: PoolExhausted::Reject,
+                ..Default::default()
+            },
+        );
+        assert_eq!(pool.live.load(Ordering::Relaxed), 0, "nothing prewarmed");
+        let held = lease(&pool).await;
+        assert!(matches!(pool.checkout().await, Checkout::Exhausted));
+        drop(held);
+        assert_eq!(pool.idle().len(), 1, "a dropped lease returns its instance");
+        drop(lease(&pool).await);
+
+        let pool = self::pool(
+            PASS,
+            WasmPool {
+                size: 1,
+                wait_timeout_ms: 20,
+                ..Default::default()
+            },
+        );
+        let held = lease(&pool).await;
+        assert!(matches!(pool.checkout().await, Checkout::Exhausted));
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            drop(held);
+        });
+        assert!(matches!(pool.checkout().await, Checkout::Ready(_)));
+        assert_eq!(pool.live.load(Ordering::Relaxed), 1);
+    }
+}
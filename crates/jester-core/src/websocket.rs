@@ -0,0 +1,485 @@
+//! WebSocket upgrade passthrough with per-route limits.
+
+use std::{future::pending, pin::pin, sync::Arc, time::Duration};
+
+use http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    config::WebsocketLimits,
+    context::{ClientIp, RequestContext},
+    drain::{self, Drain},
+    error::ProxyError,
+    stats::{RuntimeStats, WebsocketSlot},
+};
+
+/// Close frame (server to client, unmasked) with status 1009 "message too big".
+const CLOSE_MESSAGE_TOO_BIG: [u8; 4] = [0x88, 0x02, 0x03, 0xF1];
+/// Close frame (server to client, unmasked) with status 1001 "going away".
+const CLOSE_GOING_AWAY: [u8; 4] = [0x88, 0x02, 0x03, 0xE9];
+/// Empty ping frame (server to client, unmasked).
+const PING_CLIENT: [u8; 2] = [0x89, 0x00];
+const RELAY_BUFFER_BYTES: usize = 16 * 1024;
+
+/// Whether `req` asks to upgrade to the WebSocket protocol.
+pub(crate) fn is_upgrade<B>(req: &Request<B>) -> bool {
+    let upgrade_token = req
+        .headers()
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    let websocket = req
+        .headers()
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    upgrade_token && websocket
+}
+
+/// Puts back the hop-by-hop headers the upgrade handshake needs after the
+/// generic request rewrite removed them.
+pub(crate) fn restore_upgrade_headers(headers: &mut HeaderMap) {
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+}
+
+/// An accepted upgrade request, holding its slot against the route limits.
+pub(crate) struct Session {
+    route: String,
+    limits: Arc<WebsocketLimits>,
+    client: OnUpgrade,
+    _slot: Option<WebsocketSlot>,
+}
+
+impl Session {
+    /// Claims the client's upgrade handle and a per-client slot, rejecting the
+    /// request with `429` when the client already has too many sockets open.
+    pub(crate) fn open<B>(req: &mut Request<B>, stats: &RuntimeStats) -> Result<Self, ProxyError> {
+        let route = req
+            .extensions()
+            .get::<RequestContext>()
+            .and_then(RequestContext::route)
+            .unwrap_or_default();
+        let limits = req
+            .extensions()
+            .get::<Arc<WebsocketLimits>>()
+            .cloned()
+            .unwrap_or_default();
+        let peer = ClientIp::of(req);
+        let slot = match (limits.max_connections_per_client, peer) {
+            (Some(max), Some(ip)) => Some(
+                stats
+                    .acquire_websocket_slot(&route, ip, max)
+                    .ok_or_else(|| {
+                        rejected(&route, "per_client");
+                        ProxyError::Rejected {
+                            status: StatusCode::TOO_MANY_REQUESTS,
+                            reason: "too many websocket connections".into(),
+                        }
+                    })?,
+            ),
+            _ => None,
+        };
+        Ok(Self {
+            route,
+            limits,
+            client: hyper::upgrade::on(req),
+            _slot: slot,
+        })
+    }
+
+    /// Relays bytes between both upgraded connections until either side closes
+    /// or a limit trips. Once the proxy drains, the client gets a close frame
+    /// between two of the upstream's frames and the notice period to answer it.
+    /// Keepalive pings go into each direction the same way, between frames;
+    /// the pongs answering them are relayed on like any other frame.
+    pub(crate) async fn relay(
+        self,
+        upstream: OnUpgrade,
+        stats: RuntimeStats,
+        drain: Option<Drain>,
+    ) {
+        let (client, upstream) = match tokio::try_join!(self.client, upstream) {
+            Ok(pair) => pair,
+            Err(err) => {
+                tracing::warn!(route = self.route, error = %err, "websocket upgrade failed");
+                return;
+            }
+        };
+        let _open = stats.track_websocket(&self.route);
+        let _draining = drain.as_ref().map(Drain::track);
+        let (mut client_rd, mut client_wr) = tokio::io::split(TokioIo::new(client));
+        let (mut upstream_rd, mut upstream_wr) = tokio::io::split(TokioIo::new(upstream));
+        let mut meter = FrameMeter::new(&self.limits);
+        // Finds the boundaries between the upstream's frames; never trips.
+        let mut downstream = FrameMeter::new(&WebsocketLimits::default());
+        let idle = self.limits.idle_timeout_secs.map(Duration::from_secs);
+        let mut ping_timer = self.limits.ping_interval_secs.map(|secs| {
+            let every = Duration::from_secs(secs);
+            tokio::time::interval_at(tokio::time::Instant::now() + every, every)
+        });
+        let pong_timeout = Duration::from_secs(
+            self.limits
+                .pong_timeout_secs
+                .or(self.limits.ping_interval_secs)
+                .unwrap_or_default(),
+        );
+        let mut client_keepalive = Keepalive::default();
+        let mut upstream_keepalive = Keepalive::default();
+        let mut client_buf = vec![0; RELAY_BUFFER_BYTES];
+        let mut upstream_buf = vec![0; RELAY_BUFFER_BYTES];
+        let mut notice = pin!(drain_notice(drain.as_ref()));
+        let mut deadline = None;
+        // The close frame is owed once draining starts, and sent once the
+        // upstream's bytes so far end on a frame boundary.
+        let mut close_owed = false;
+        let mut close_sent = false;
+
+        let closed_by = loop {
+            if close_owed && downstream.at_boundary() {
+                close_owed = false;
+                close_sent = true;
+                if client_wr.write_all(&CLOSE_GOING_AWAY).await.is_err() {
+                    break None;
+                }
+            }
+            client_keepalive.answered(meter.pongs);
+            upstream_keepalive.answered(downstream.pongs);
+            if client_keepalive.owed && !close_owed && !close_sent && downstream.at_boundary() {
+                client_keepalive.sent(meter.pongs, pong_timeout);
+                if client_wr.write_all(&PING_CLIENT).await.is_err() {
+                    break None;
+                }
+            }
+            if upstream_keepalive.owed && meter.at_boundary() {
+                upstream_keepalive.sent(downstream.pongs, pong_timeout);
+                if upstream_wr.write_all(&ping_upstream()).await.is_err() {
+                    break None;
+                }
+            }
+            tokio::select! {
+                () = &mut notice, if deadline.is_none() => {
+                    let notice = drain.as_ref().map(Drain::notice).unwrap_or_default();
+                    deadline = Some(Box::pin(tokio::time::sleep(notice)));
+                    close_owed = true;
+                }
+                () = deadline_timer(&mut deadline) => break Some("drained"),
+                read = client_rd.read(&mut client_buf) => match read {
+                    Ok(0) | Err(_) => break None,
+                    Ok(n) => {
+                        if let Err(violation) = meter.feed(&client_buf[..n]) {
+                            client_wr.write_all(&CLOSE_MESSAGE_TOO_BIG).await.ok();
+                            break Some(violation);
+                        }
+                        if upstream_wr.write_all(&client_buf[..n]).await.is_err() {
+                            break None;
+                        }
+                    }
+                },
+                read = upstream_rd.read(&mut upstream_buf) => match read {
+                    Ok(0) | Err(_) => break None,
+                    // Nothing may follow the close frame sent to the client.
+                    Ok(_) if close_sent => {}
+                    Ok(n) => {
+                        downstream.feed(&upstream_buf[..n]).ok();
+                        if client_wr.write_all(&upstream_buf[..n]).await.is_err() {
+                            break None;
+                        }
+                    }
+                },
+                _ = ping_tick(&mut ping_timer) => {
+                    client_keepalive.due();
+                    upstream_keepalive.due();
+                }
+                () = client_keepalive.expired() => {
+                    break Some("pong_timeout");
+                }
+                () = upstream_keepalive.expired() => {
+                    if !close_sent && downstream.at_boundary() {
+                        client_wr.write_all(&CLOSE_GOING_AWAY).await.ok();
+                    }
+                    break Some("pong_timeout");
+                }
+                _ = idle_timer(idle) => break Some("idle_timeout"),
+            }
+        };
+        client_wr.shutdown().await.ok();
+        upstream_wr.shutdown().await.ok();
+        match closed_by {
+            Some("drained") => drain::record("websocket", "cut"),
+            Some(reason) => {
+                tracing::info!(route = self.route, reason, "websocket closed by limit");
+                rejected(&self.route, reason);
+            }
+            None if deadline.is_some() => drain::record("websocket", "closed"),
+            None => {}
+        }
+    }
+}
+
+/// Resolves once the proxy starts draining; never without a drain.
+async fn drain_notice(drain: Option<&Drain>) {
+    match drain {
+        Some(drain) => drain.started().await,
+        None => pending().await,
+    }
+}
+
+pub(crate) async fn deadline_timer(deadline: &mut Option<std::pin::Pin<Box<tokio::time::Sleep>>>) {
+    match deadline {
+        Some(deadline) => deadline.as_mut().await,
+        None => pending().await,
+    }
+}
+
+fn rejected(route: &str, reason: &'static str) {
+    metrics::counter!("jester_websocket_limit_total", "route" => route.to_string(), "reason" => reason)
+        .increment(1);
+}
+
+async fn ping_tick(timer: &mut Option<tokio::time::Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => pending().await,
+    }
+}
+
+/// Empty ping frame (proxy to upstream), masked as clients must.
+fn ping_upstream() -> [u8; 6] {
+    let mut frame = [0x89, 0x80, 0, 0, 0, 0];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut frame[2..]).ok();
+    frame
+}
+
+/// Keepalive state for one peer: whether a ping is waiting for the next frame
+/// boundary, and the pong count and deadline of the one in flight.
+#[derive(Default)]
+struct Keepalive {
+    owed: bool,
+    waiting: Option<(u64, tokio::time::Instant)>,
+}
+
+impl Keepalive {
+    /// Owes the peer a ping unless the last one is still unanswered; that one's
+    /// deadline decides.
+    fn due(&mut self) {
+        self.owed = self.waiting.is_none();
+    }
+
+    fn sent(&mut self, pongs: u64, timeout: Duration) {
+        self.owed = false;
+        self.waiting = Some((pongs, tokio::time::Instant::now() + timeout));
+    }
+
+    /// Any pong since the ping went out answers it.
+    fn answered(&mut self, pongs: u64) {
+        if self.waiting.is_some_and(|(before, _)| pongs > before) {
+            self.waiting = None;
+        }
+    }
+
+    /// Resolves once the ping in flight goes unanswered past its deadline.
+    async fn expired(&self) {
+        match self.waiting {
+            Some((_, deadline)) => tokio::time::sleep_until(deadline).await,
+            None => pending().await,
+        }
+    }
+}
+
+pub(crate) async fn idle_timer(idle: Option<Duration>) {
+    match idle {
+        Some(duration) => tokio::time::sleep(duration).await,
+        None => pending().await,
+    }
+}
+
+/// Tracks frame boundaries in the client-to-upstream byte stream without
+/// buffering payloads.
+struct FrameMeter {
+    max_frame: Option<u64>,
+    max_message: Option<u64>,
+    header: Vec<u8>,
+    payload_left: u64,
+    message_len: u64,
+    /// Pong frames seen so far.
+    pongs: u64,
+}
+
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    len: u64,
+}
+
+impl FrameMeter {
+    fn new(limits: &WebsocketLimits) -> Self {
+        Self {
+            max_frame: limits.max_frame_bytes,
+            max_message: limits.max_message_bytes,
+            header: Vec::with_capacity(14),
+            payload_left: 0,
+            message_len: 0,
+            pongs: 0,
+        }
+    }
+
+    /// Whether the bytes fed so far end between two frames.
+    fn at_boundary(&self) -> bool {
+        self.header.is_empty() && self.payload_left == 0
+    }
+
+    fn feed(&mut self, mut data: &[u8]) -> Result<(), &'static str> {
+        while !data.is_empty() {
+            if self.payload_left > 0 {
+                let skipped = self.payload_left.min(data.len() as u64);
+                self.payload_left -= skipped;
+                data = &data[skipped as usize..];
+                continue;
+            }
+            self.header.push(data[0]);
+            data = &data[1..];
+            if let Some(frame) = parse_header(&self.header) {
+                self.header.clear();
+                self.check(&frame)?;
+                self.payload_left = frame.len;
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&mut self, frame: &FrameHeader) -> Result<(), &'static str> {
+        if self.max_frame.is_some_and(|max| frame.len > max) {
+            return Err("frame_too_large");
+        }
+        if frame.opcode == 0xA {
+            self.pongs += 1;
+        }
+        // Control frames (opcode >= 8) may interleave with fragments.
+        if frame.opcode < 8 {
+            if frame.opcode != 0 {
+                self.message_len = 0;
+            }
+            self.message_len = self.message_len.saturating_add(frame.len);
+            if self.max_message.is_some_and(|max| self.message_len > max) {
+                return Err("message_too_large");
+            }
+            if frame.fin {
+                self.message_len = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a complete frame header, or returns `None` if more bytes are needed.
+fn parse_header(buf: &[u8]) -> Option<FrameHeader> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let masked = buf[1] & 0x80 != 0;
+    let (len, offset) = match buf[1] & 0x7F {
+        126 => (
+            u64::from(u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?)),
+            4,
+        ),
+        127 => (u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?), 10),
+        len => (u64::from(len), 2),
+    };
+    let needed = offset + if masked { 4 } else { 0 };
+    (buf.len() >= needed).then_some(FrameHeader {
+        fin: buf[0] & 0x80 != 0,
+        opcode: buf[0] & 0x0F,
+        len,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masked_frame(fin: bool, opcode: u8, payload_len: usize) -> Vec<u8> {
+        let mut frame = vec![(u8::from(fin) << 7) | opcode];
+        match payload_len {
+            0..=125 => frame.push(0x80 | payload_len as u8),
+            126..=0xFFFF => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(payload_len as u16).to_be_bytes());
+            }
+            _ => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(payload_len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&[1, 2, 3, 4]);
+        frame.extend(std::iter::repeat_n(0u8, payload_len));
+        frame
+    }
+
+    fn meter_with(max_frame: Option<u64>, max_message: Option<u64>) -> FrameMeter {
+        FrameMeter::new(&WebsocketLimits {
+            max_frame_bytes: max_frame,
+            max_message_bytes: max_message,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn detects_upgrade_requests() {
+        let req = Request::get("/ws")
+            .header(header::CONNECTION, "keep-alive, Upgrade")
+            .header(header::UPGRADE, "websocket")
+            .body(())
+            .unwrap();
+        assert!(is_upgrade(&req));
+        let plain = Request::get("/").body(()).unwrap();
+        assert!(!is_upgrade(&plain));
+    }
+
+    #[test]
+    fn frame_limit_applies_across_split_reads() {
+        let mut meter = meter_with(Some(200), None);
+        let ok = masked_frame(true, 1, 200);
+        let (head, tail) = ok.split_at(3);
+        meter.feed(head).unwrap();
+        meter.feed(tail).unwrap();
+        assert_eq!(
+            meter.feed(&masked_frame(true, 2, 201)),
+            Err("frame_too_large")
+        );
+    }
+
+    #[test]
+    fn message_limit_sums_fragments_but_not_control_frames() {
+        let mut meter = meter_with(None, Some(100));
+        meter.feed(&masked_frame(false, 1, 60)).unwrap();
+        meter.feed(&masked_frame(true, 9, 50)).unwrap();
+        assert_eq!(
+            meter.feed(&masked_frame(true, 0, 41)),
+            Err("message_too_large")
+        );
+
+        let mut meter = meter_with(None, Some(100));
+        meter.feed(&masked_frame(true, 1, 100)).unwrap();
+        meter.feed(&masked_frame(true, 1, 100)).unwrap();
+    }
+
+    #[test]
+    fn counts_pongs_between_fragments() {
+        let mut meter = meter_with(None, None);
+        meter.feed(&masked_frame(false, 1, 10)).unwrap();
+        let pong = masked_frame(true, 0xA, 4);
+        let (head, tail) = pong.split_at(1);
+        meter.feed(head).unwrap();
+        assert_eq!(meter.pongs, 0);
+        meter.feed(tail).unwrap();
+        meter.feed(&masked_frame(true, 9, 0)).unwrap();
+        assert_eq!(meter.pongs, 1);
+    }
+}
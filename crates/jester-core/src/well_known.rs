@@ -0,0 +1,288 @@
+//! Files the proxy answers itself, per host and before route matching:
+//! `robots.txt`, `security.txt`, and ACME HTTP-01 challenges.
+
+use std::{io, path::PathBuf};
+
+use anyhow::Result;
+use bytes::Bytes;
+use http::{header, HeaderValue, Method, Request, StatusCode};
+
+use crate::{
+    config::WellKnown,
+    plugin::{full_body, text_response, HttpResponse, ResponseFuture},
+    router::HostMatcher,
+};
+
+const ROBOTS_TXT: &str = "/robots.txt";
+const SECURITY_TXT: &str = "/.well-known/security.txt";
+const ACME_CHALLENGE: &str = "/.well-known/acme-challenge/";
+
+/// The compiled `[[well_known]]` entries, in order.
+pub(crate) struct WellKnownFiles {
+    sites: Vec<Site>,
+}
+
+struct Site {
+    /// Empty for every host.
+    hosts: Vec<HostMatcher>,
+    robots_txt: Option<Bytes>,
+    security_txt: Option<Bytes>,
+    acme_challenge_dir: Option<PathBuf>,
+}
+
+/// A path the proxy may answer itself.
+#[derive(Debug, PartialEq, Eq)]
+enum Resource<'a> {
+    Robots,
+    Security,
+    Challenge(&'a str),
+}
+
+impl<'a> Resource<'a> {
+    fn of(path: &'a str) -> Option<Self> {
+        match path {
+            ROBOTS_TXT => Some(Resource::Robots),
+            SECURITY_TXT => Some(Resource::Security),
+            _ => path.strip_prefix(ACME_CHALLENGE).map(Resource::Challenge),
+        }
+    }
+}
+
+impl Site {
+    fn serves(&self, host: &str, resource: &Resource<'_>) -> bool {
+        let configured = match resource {
+            Resource::Robots => self.robots_txt.is_some(),
+            Resource::Security => self.security_txt.is_some(),
+            Resource::Challenge(_) => self.acme_challenge_dir.is_some(),
+        };
+        configured
+            && (self.hosts.is_empty() || self.hosts.iter().any(|matcher| matcher.matches(host)))
+    }
+}
+
+impl WellKnownFiles {
+    pub(crate) fn new(config: &[WellKnown]) -> Result<Self> {
+        let sites = config
+            .iter()
+            .map(|entry| {
+                Ok(Site {
+                    hosts: entry
+                        .hosts
+                        .iter()
+                        .map(|pattern| HostMatcher::new(pattern))
+                        .collect::<Result<_>>()?,
+                    robots_txt: entry.robots_txt.clone().map(Bytes::from),
+                    security_txt: entry.security_txt.clone().map(Bytes::from),
+                    acme_challenge_dir: entry.acme_challenge_dir.as_ref().map(PathBuf::from),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { sites })
+    }
+
+    /// Answers `req` if it asks for a file configured for `host`; `None`
+    /// leaves it to the routes.
+    pub(crate) fn respond<B>(&self, req: &Request<B>, host: &str) -> Option<ResponseFuture> {
+        let resource = Resource::of(req.uri().path())?;
+        let site = self
+            .sites
+            .iter()
+            .find(|site| site.serves(host, &resource))?;
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            let mut response = text_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+            response
+                .headers_mut()
+                .insert(header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+            return Some(Box::pin(async { Ok(response) }));
+        }
+        let text = |body: &Option<Bytes>| {
+            let response = file_response(
+                body.clone().unwrap_or_default(),
+                "text/plain; charset=utf-8",
+            );
+            Box::pin(async { Ok(response) }) as ResponseFuture
+        };
+        Some(match resource {
+            Resource::Robots => text(&site.robots_txt),
+            Resource::Security => text(&site.security_txt),
+            Resource::Challenge(token) => {
+                let path = is_token(token)
+                    .then(|| site.acme_challenge_dir.as_ref().map(|dir| dir.join(token)))
+                    .flatten();
+                Box::pin(async move {
+                    let Some(path) = path else {
+                        return Ok(no_challenge());
+                    };
+                    Ok(match tokio::fs::read(&path).await {
+                        // RFC 8555 §8.3: the key authorization, as an octet stream.
+                        Ok(body) => file_response(body.into(), "application/octet-stream"),
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => no_challenge(),
+                        Err(err) => {
+                            tracing::warn!(
+                                path = %path.display(),
+                                error = %err,
+                                "failed to read ACME challenge"
+                            );
+                            text_response(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+                        }
+                    })
+                })
+            }
+        })
+    }
+}
+
+/// ACME tokens are base64url without padding, which also keeps them from
+/// naming anything outside the challenge directory.
+fn is_token(token: &str) -> bool {
+    !token.is_empty()
+        && token
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
+
+fn no_challenge() -> HttpResponse {
+    text_response(StatusCode::NOT_FOUND, "no such challenge")
+}
+
+fn file_response(body: Bytes, content_type: &'static str) -> HttpResponse {
+    let mut response = http::Response::new(full_body(body));
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    fn files(entries: Vec<WellKnown>) -> WellKnownFiles {
+        WellKnownFiles::new(&entries).unwrap()
+    }
+
+    async fn get(
+        files: &WellKnownFiles,
+        method: Method,
+        host: &str,
+        path: &str,
+    ) -> Option<(StatusCode, String)> {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(full_body(""))
+            .unwrap();
+        let response = files.respond(&req, host)?.await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        Some((status, String::from_utf8_lossy(&body).into_owned()))
+    }
+
+    #[test]
+    fn only_well_known_paths_are_resources() {
+        assert_eq!(Resource::of("/robots.txt"), Some(Resource::Robots));
+        assert_eq!(
+            Resource::of("/.well-known/security.txt"),
+            Some(Resource::Security)
+        );
+        assert_eq!(
+            Resource::of("/.well-known/acme-challenge/abc"),
+            Some(Resource::Challenge("abc"))
+        );
+        assert_eq!(Resource::of("/robots.txt/x"), None);
+        assert_eq!(Resource::of("/security.txt"), None);
+        assert!(!is_token("../secret"));
+        assert!(!is_token(""));
+        assert!(is_token("LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0"));
+    }
+
+    #[tokio::test]
+    async fn first_entry_for_the_host_and_path_answers() {
+        let files = files(vec![
+            WellKnown {
+                hosts: vec!["staging.example.com".into()],
+                robots_txt: Some("User-agent: *\nDisallow: /\n".into()),
+                ..WellKnown::default()
+            },
+            WellKnown {
+                robots_txt: Some("User-agent: *\nAllow: /\n".into()),
+                security_txt: Some("Contact: mailto:security@example.com\n".into()),
+                ..WellKnown::default()
+            },
+        ]);
+        let (status, body) = get(&files, Method::GET, "staging.example.com", "/robots.txt")
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("Disallow"));
+        let (_, body) = get(&files, Method::GET, "www.example.com", "/robots.txt")
+            .await
+            .unwrap();
+        assert!(body.contains("Allow: /"));
+        // Falls through to the catch-all, which configures security.txt.
+        let (_, body) = get(
+            &files,
+            Method::GET,
+            "staging.example.com",
+            "/.well-known/security.txt",
+        )
+        .await
+        .unwrap();
+        assert!(body.starts_with("Contact:"));
+
+        let (status, _) = get(&files, Method::POST, "www.example.com", "/robots.txt")
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert!(get(
+            &files,
+            Method::GET,
+            "www.example.com",
+            "/.well-known/acme-challenge/x"
+        )
+        .await
+        .is_none());
+        assert!(get(&files, Method::GET, "www.example.com", "/index.html")
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn challenges_are_read_from_the_directory() {
+        let dir = std::env::temp_dir().join(format!("jester-well-known-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("token_1"), "token_1.thumbprint").unwrap();
+        let files = files(vec![WellKnown {
+            hosts: vec!["*.example.com".into()],
+            acme_challenge_dir: Some(dir.display().to_string()),
+            ..WellKnown::default()
+        }]);
+
+        let found = get(
+            &files,
+            Method::GET,
+            "a.example.com",
+            "/.well-known/acme-challenge/token_1",
+        )
+        .await;
+        assert_eq!(found, Some((StatusCode::OK, "token_1.thumbprint".into())));
+        for token in ["token_2", "..%2Fsecret", ""] {
+            let path = format!("/.well-known/acme-challenge/{token}");
+            let (status, _) = get(&files, Method::GET, "a.example.com", &path)
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND, "{token}");
+        }
+        assert!(get(
+            &files,
+            Method::GET,
+            "example.org",
+            "/.well-known/acme-challenge/token_1"
+        )
+        .await
+        .is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
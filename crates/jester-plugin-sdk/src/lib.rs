@@ -1,9 +1,33 @@
 pub mod manifest;
 
-pub use manifest::PluginManifest;
+pub use manifest::{MetricDeclaration, MetricKind, PluginManifest};
 
+use semver::{Version, VersionReq};
 use serde_json::Value;
 
+/// Version of the WIT interfaces below. Plugins name the version they were
+/// built against as `sdk_version` in their manifest; see
+/// [`compatible_sdk_versions`].
+pub const SDK_VERSION: Version = Version::new(0, 3, 0);
+
+/// SDK versions whose plugins this host loads: those of the same major
+/// version (minor while below 1.0) that are no newer than [`SDK_VERSION`] in
+/// anything but the patch, which never changes the interfaces.
+pub fn compatible_sdk_versions() -> VersionReq {
+    let req = match SDK_VERSION.major {
+        0 => format!(
+            ">=0.{minor}.0, <0.{next}.0",
+            minor = SDK_VERSION.minor,
+            next = SDK_VERSION.minor + 1
+        ),
+        major => format!(
+            ">={major}.0.0, <{major}.{next}.0",
+            next = SDK_VERSION.minor + 1
+        ),
+    };
+    VersionReq::parse(&req).expect("SDK version range is well formed")
+}
+
 /// Trait implemented by WASM or native plugins compiled outside the core workspace.
 pub trait Plugin {
     fn name(&self) -> &'static str;
@@ -14,3 +38,29 @@ pub trait Plugin {
 
 /// Reference WIT interface exposed by the host runtime.
 pub const HTTP_WIT: &str = include_str!("../wit/http.wit");
+
+/// WIT of the logging, metric, and request context functions the host
+/// provides to plugins.
+pub const HOST_WIT: &str = include_str!("../wit/host.wit");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wit_packages_carry_the_sdk_version() {
+        let package = format!("package jester:plugin@{SDK_VERSION};");
+        for wit in [HTTP_WIT, HOST_WIT] {
+            assert!(wit.starts_with(&package), "{wit}");
+        }
+    }
+
+    #[test]
+    fn compatible_versions_differ_only_in_patch() {
+        let req = compatible_sdk_versions();
+        assert!(req.matches(&SDK_VERSION));
+        assert!(req.matches(&Version::new(0, 3, 7)));
+        assert!(!req.matches(&Version::new(0, 2, 0)));
+        assert!(!req.matches(&Version::new(0, 4, 0)));
+    }
+}
@@ -1,6 +1,13 @@
+use std::collections::HashSet;
+
+use anyhow::{bail, Context, Result};
+use semver::Version;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+/// Label combinations a metric may record unless its declaration says otherwise.
+pub const DEFAULT_MAX_SERIES: usize = 100;
+
 /// On-disk JSON manifest located next to each plugin artifact.
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(default)]
@@ -9,12 +16,195 @@ pub struct PluginManifest {
     pub version: String,
     pub description: Option<String>,
     pub module: Option<String>,
+    /// The [`SDK_VERSION`](crate::SDK_VERSION) the plugin was built against.
+    pub sdk_version: Option<String>,
     pub capabilities: Vec<String>,
     pub config_schema: Option<Value>,
+    /// Metrics the plugin records through the host's metric functions.
+    pub metrics: Vec<MetricDeclaration>,
+    /// Plugins that must be in the chain and run before this one.
+    pub requires: Vec<String>,
+    /// Plugins that, when in the chain, must run after this one.
+    pub precedes: Vec<String>,
+}
+
+/// A metric a plugin may record, and the label keys every recording carries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricDeclaration {
+    /// Exported as `jester_plugin_<name>` with a `plugin` label added.
+    pub name: String,
+    pub kind: MetricKind,
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// Distinct label value combinations kept; recordings beyond them are
+    /// dropped. [`DEFAULT_MAX_SERIES`] unless set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_series: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
 }
 
 impl PluginManifest {
     pub fn requires_capability(&self, cap: &str) -> bool {
         self.capabilities.iter().any(|c| c == cap)
     }
+
+    /// The declaration of metric `name`, if the plugin made one.
+    pub fn metric(&self, name: &str) -> Option<&MetricDeclaration> {
+        self.metrics.iter().find(|metric| metric.name == name)
+    }
+
+    /// Fails unless the plugin was built against an SDK whose interfaces
+    /// this host provides, naming the versions it would accept.
+    pub fn check_sdk(&self) -> Result<()> {
+        let supported = crate::compatible_sdk_versions();
+        let Some(declared) = &self.sdk_version else {
+            bail!(
+                "plugin `{}` does not declare the `sdk_version` it was built with; \
+                 this host loads plugins built with SDK {supported}",
+                self.name
+            );
+        };
+        let version = Version::parse(declared).with_context(|| {
+            format!(
+                "plugin `{}` has invalid sdk_version `{declared}`",
+                self.name
+            )
+        })?;
+        if !supported.matches(&version) {
+            bail!(
+                "plugin `{}` was built with SDK {version}, but this host loads plugins \
+                 built with SDK {supported}; rebuild it against a compatible SDK",
+                self.name
+            );
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            bail!("plugin name must not be empty");
+        }
+        if let Some(own) = self
+            .requires
+            .iter()
+            .chain(&self.precedes)
+            .find(|other| **other == self.name)
+        {
+            bail!("plugin `{own}` cannot be ordered relative to itself");
+        }
+        if let Some(both) = self
+            .requires
+            .iter()
+            .find(|other| self.precedes.contains(other))
+        {
+            bail!("plugin `{}` both requires and precedes `{both}`", self.name);
+        }
+        let mut names = HashSet::new();
+        for metric in &self.metrics {
+            if !is_metric_name(&metric.name) {
+                bail!(
+                    "metric name `{}` must be lowercase letters, digits, and `_`",
+                    metric.name
+                );
+            }
+            if !names.insert(&metric.name) {
+                bail!("metric `{}` is declared twice", metric.name);
+            }
+            let mut labels = HashSet::new();
+            for label in &metric.labels {
+                if !is_metric_name(label) || label == "plugin" {
+                    bail!("metric `{}` has invalid label `{label}`", metric.name);
+                }
+                if !labels.insert(label) {
+                    bail!("metric `{}` lists label `{label}` twice", metric.name);
+                }
+            }
+            if metric.max_series == Some(0) {
+                bail!("metric `{}` has max_series 0", metric.name);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_metric_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordering_declarations_are_checked() {
+        let manifest = |requires: &[&str], precedes: &[&str]| PluginManifest {
+            name: "authz".into(),
+            requires: requires.iter().map(|name| name.to_string()).collect(),
+            precedes: precedes.iter().map(|name| name.to_string()).collect(),
+            ..Default::default()
+        };
+        assert!(manifest(&["authn"], &["cache"]).validate().is_ok());
+        assert!(manifest(&["authz"], &[]).validate().is_err());
+        assert!(manifest(&["authn"], &["authn"]).validate().is_err());
+    }
+
+    #[test]
+    fn sdk_versions_are_negotiated() {
+        let manifest = |sdk_version: Option<&str>| PluginManifest {
+            name: "jwt-auth".into(),
+            sdk_version: sdk_version.map(String::from),
+            ..Default::default()
+        };
+        assert!(manifest(Some(&crate::SDK_VERSION.to_string()))
+            .check_sdk()
+            .is_ok());
+        for sdk_version in [None, Some("two"), Some("0.2.0"), Some("1.0.0")] {
+            let err = manifest(sdk_version).check_sdk().unwrap_err();
+            assert!(err.to_string().contains("jwt-auth"), "{err}");
+        }
+        let err = manifest(Some("0.2.0")).check_sdk().unwrap_err();
+        assert!(err.to_string().contains(">=0.3.0, <0.4.0"), "{err}");
+    }
+
+    #[test]
+    fn metric_declarations_are_checked() {
+        let manifest = |metrics: Value| -> PluginManifest {
+            serde_json::from_value(serde_json::json!({
+                "name": "jwt-auth",
+                "version": "0.1.0",
+                "metrics": metrics,
+            }))
+            .unwrap()
+        };
+        let valid = manifest(serde_json::json!([
+            {"name": "tokens_verified", "kind": "counter", "labels": ["outcome"]},
+            {"name": "key_age_seconds", "kind": "gauge", "max_series": 4},
+        ]));
+        assert!(valid.validate().is_ok());
+        assert_eq!(
+            valid.metric("tokens_verified").unwrap().kind,
+            MetricKind::Counter
+        );
+        assert!(valid.metric("missing").is_none());
+
+        for metrics in [
+            serde_json::json!([{"name": "Tokens", "kind": "counter"}]),
+            serde_json::json!([{"name": "a", "kind": "counter"}, {"name": "a", "kind": "gauge"}]),
+            serde_json::json!([{"name": "a", "kind": "counter", "labels": ["plugin"]}]),
+            serde_json::json!([{"name": "a", "kind": "counter", "labels": ["x", "x"]}]),
+            serde_json::json!([{"name": "a", "kind": "counter", "max_series": 0}]),
+        ] {
+            assert!(manifest(metrics.clone()).validate().is_err(), "{metrics}");
+        }
+    }
 }
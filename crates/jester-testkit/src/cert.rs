@@ -0,0 +1,99 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use tempfile::TempDir;
+
+/// Self-signed certificate written to a temporary directory for the lifetime of
+/// the value, so listener configs can reference it by path.
+pub struct TestCert {
+    _dir: TempDir,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    der: Vec<u8>,
+    key_der: Vec<u8>,
+    /// Set for CAs from [`TestCert::generate_ca`], which issue certificates.
+    issuer: Option<(rcgen::Certificate, KeyPair)>,
+}
+
+impl TestCert {
+    /// Generates a certificate valid for `hosts` (DNS names or IP literals).
+    pub fn generate(hosts: &[&str]) -> Result<Self> {
+        let names = hosts
+            .iter()
+            .map(|host| host.to_string())
+            .collect::<Vec<_>>();
+        let certified = rcgen::generate_simple_self_signed(names)
+            .context("failed to generate test certificate")?;
+        Self::write(certified.cert, certified.key_pair, false)
+    }
+
+    /// Generates a CA named `name`, e.g. for a listener's `tls.client_ca`,
+    /// that [`TestCert::issue`] signs certificates with.
+    pub fn generate_ca(name: &str) -> Result<Self> {
+        let mut params = CertificateParams::new(Vec::new())?;
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate()?;
+        let cert = params
+            .self_signed(&key)
+            .context("failed to generate test CA")?;
+        Self::write(cert, key, true)
+    }
+
+    /// Issues a certificate for `common_name`, with `names` (DNS names or IP
+    /// literals) as its subject alternative names, signed by this CA.
+    pub fn issue(&self, common_name: &str, names: &[&str]) -> Result<Self> {
+        let Some((issuer, issuer_key)) = &self.issuer else {
+            bail!("only certificates from TestCert::generate_ca issue others");
+        };
+        let mut params = CertificateParams::new(
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>(),
+        )?;
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        let key = KeyPair::generate()?;
+        let cert = params
+            .signed_by(&key, issuer, issuer_key)
+            .context("failed to issue test certificate")?;
+        Self::write(cert, key, false)
+    }
+
+    fn write(cert: rcgen::Certificate, key: KeyPair, issues: bool) -> Result<Self> {
+        let dir = tempfile::tempdir().context("failed to create certificate directory")?;
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, cert.pem())?;
+        std::fs::write(&key_path, key.serialize_pem())?;
+        Ok(Self {
+            _dir: dir,
+            cert_path,
+            key_path,
+            der: cert.der().to_vec(),
+            key_der: key.serialize_der(),
+            issuer: issues.then_some((cert, key)),
+        })
+    }
+
+    pub fn cert_path(&self) -> &Path {
+        &self.cert_path
+    }
+
+    pub fn key_path(&self) -> &Path {
+        &self.key_path
+    }
+
+    /// DER encoding of the certificate, for building client trust stores.
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    /// DER encoding of the private key, for presenting the certificate.
+    pub fn key_der(&self) -> &[u8] {
+        &self.key_der
+    }
+}
@@ -0,0 +1,309 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use http::{header, HeaderMap, Request, Response, StatusCode, Version};
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Incoming, client::conn::http1::SendRequest, upgrade::Upgraded};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{version::TLS12, Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName},
+    TlsConnector,
+};
+
+use crate::TestCert;
+
+/// Name presented via SNI; test certificates always include it.
+pub(crate) const SERVER_NAME: &str = "localhost";
+
+/// HTTPS client pinned to a single proxy address and trusting one test cert.
+#[derive(Clone)]
+pub struct TestClient {
+    addr: SocketAddr,
+    roots: RootCertStore,
+    /// Presented to listeners that ask, from [`TestClient::client_cert`].
+    identity: Option<(Vec<Certificate>, PrivateKey)>,
+    tls12_only: bool,
+    connector: TlsConnector,
+    /// Offers `h2` through ALPN, for [`TestClient::http2`].
+    http2_connector: TlsConnector,
+    server_name: String,
+    http2: bool,
+}
+
+/// Fully buffered response returned by [`TestClient`].
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub version: Version,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// Trailers sent after the body, e.g. gRPC's `grpc-status`.
+    pub trailers: Option<HeaderMap>,
+}
+
+impl TestResponse {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    async fn collect(response: Response<Incoming>) -> Result<Self> {
+        let (parts, body) = response.into_parts();
+        let collected = body.collect().await?;
+        let trailers = collected.trailers().cloned();
+        Ok(Self {
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers,
+            body: collected.to_bytes(),
+            trailers,
+        })
+    }
+}
+
+/// Sends one request over plain HTTP/1.1, e.g. to the admin API.
+pub(crate) async fn send_plain(
+    addr: SocketAddr,
+    request: Request<Full<Bytes>>,
+) -> Result<TestResponse> {
+    let tcp = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("failed to connect to {addr}"))?;
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(tcp)).await?;
+    tokio::spawn(connection);
+    TestResponse::collect(sender.send_request(request).await?).await
+}
+
+impl TestClient {
+    pub fn new(addr: SocketAddr, cert: &TestCert) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(cert.der().to_vec()))
+            .context("failed to trust test certificate")?;
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots.clone())
+            .with_no_client_auth();
+        let (connector, http2_connector) = connectors(config);
+        Ok(Self {
+            addr,
+            roots,
+            identity: None,
+            tls12_only: false,
+            connector,
+            http2_connector,
+            server_name: SERVER_NAME.into(),
+            http2: false,
+        })
+    }
+
+    /// Presents `cert`, e.g. one from [`TestCert::issue`], to listeners that
+    /// ask for a client certificate.
+    pub fn client_cert(mut self, cert: &TestCert) -> Result<Self> {
+        self.identity = Some((
+            vec![Certificate(cert.der().to_vec())],
+            PrivateKey(cert.key_der().to_vec()),
+        ));
+        self.reconnect()?;
+        Ok(self)
+    }
+
+    /// Offers only TLS 1.2, e.g. to check a listener's `tls.min_version`.
+    pub fn tls12_only(mut self) -> Result<Self> {
+        self.tls12_only = true;
+        self.reconnect()?;
+        Ok(self)
+    }
+
+    /// Rebuilds the connectors after a TLS setting changes.
+    fn reconnect(&mut self) -> Result<()> {
+        let builder = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups();
+        let builder = if self.tls12_only {
+            builder.with_protocol_versions(&[&TLS12])?
+        } else {
+            builder.with_safe_default_protocol_versions()?
+        };
+        let builder = builder.with_root_certificates(self.roots.clone());
+        let config = match self.identity.clone() {
+            Some((certs, key)) => builder
+                .with_client_auth_cert(certs, key)
+                .context("invalid client certificate")?,
+            None => builder.with_no_client_auth(),
+        };
+        (self.connector, self.http2_connector) = connectors(config);
+        Ok(())
+    }
+
+    /// The TLS version and cipher suite a handshake with the proxy settles
+    /// on, by their Debug names, e.g. `TLSv1_3` and `TLS13_AES_256_GCM_SHA384`.
+    pub async fn negotiated(&self) -> Result<(String, String)> {
+        let tcp = TcpStream::connect(self.addr)
+            .await
+            .with_context(|| format!("failed to connect to proxy at {}", self.addr))?;
+        let server_name = ServerName::try_from(self.server_name.as_str())?;
+        let tls = self.connector.connect(server_name, tcp).await?;
+        let connection = tls.get_ref().1;
+        let version = connection
+            .protocol_version()
+            .context("no protocol version negotiated")?;
+        let suite = connection
+            .negotiated_cipher_suite()
+            .context("no cipher suite negotiated")?;
+        Ok((format!("{version:?}"), format!("{:?}", suite.suite())))
+    }
+
+    /// Sends [`get`](Self::get) and [`send`](Self::send) requests over
+    /// HTTP/2, failing unless the proxy agrees to it through ALPN.
+    pub fn http2(mut self) -> Self {
+        self.http2 = true;
+        self
+    }
+
+    /// Presents `name` via SNI instead of `localhost`.
+    pub fn server_name(mut self, name: impl Into<String>) -> Self {
+        self.server_name = name.into();
+        self
+    }
+
+    /// Sends `GET path` with the given `Host` header.
+    pub async fn get(&self, host: &str, path: &str) -> Result<TestResponse> {
+        let request = Request::get(path)
+            .header(header::HOST, host)
+            .body(Full::new(Bytes::new()))?;
+        self.send(request).await
+    }
+
+    /// Sends an arbitrary request over a fresh connection.
+    pub async fn send(&self, request: Request<Full<Bytes>>) -> Result<TestResponse> {
+        if self.http2 {
+            let mut sender = self.connect_http2().await?;
+            return TestResponse::collect(sender.send_request(http2_target(request)?).await?).await;
+        }
+        let mut sender = self.connect().await?;
+        TestResponse::collect(sender.send_request(request).await?).await
+    }
+
+    /// Sends a request and returns as soon as the response head arrives,
+    /// leaving the body to be read as it streams in.
+    pub async fn stream(&self, request: Request<Full<Bytes>>) -> Result<Response<Incoming>> {
+        if self.http2 {
+            let mut sender = self.connect_http2().await?;
+            return Ok(sender.send_request(http2_target(request)?).await?);
+        }
+        let mut sender = self.connect().await?;
+        Ok(sender.send_request(request).await?)
+    }
+
+    /// Opens an HTTP/2 connection that can carry several concurrent requests.
+    pub async fn http2_connection(
+        &self,
+    ) -> Result<hyper::client::conn::http2::SendRequest<Full<Bytes>>> {
+        self.connect_http2().await
+    }
+
+    /// Opens a connection that can carry several requests.
+    pub async fn connection(&self) -> Result<TestConnection> {
+        Ok(TestConnection {
+            sender: self.connect().await?,
+        })
+    }
+
+    /// Sends an upgrade request and, on `101 Switching Protocols`, returns the
+    /// upgraded connection.
+    pub async fn upgrade(
+        &self,
+        request: Request<Full<Bytes>>,
+    ) -> Result<(StatusCode, Option<TokioIo<Upgraded>>)> {
+        let mut sender = self.connect().await?;
+        let mut response = sender.send_request(request).await?;
+        let status = response.status();
+        if status != StatusCode::SWITCHING_PROTOCOLS {
+            return Ok((status, None));
+        }
+        let upgraded = hyper::upgrade::on(&mut response).await?;
+        Ok((status, Some(TokioIo::new(upgraded))))
+    }
+
+    async fn connect(&self) -> Result<SendRequest<Full<Bytes>>> {
+        let tcp = TcpStream::connect(self.addr)
+            .await
+            .with_context(|| format!("failed to connect to proxy at {}", self.addr))?;
+        let server_name = ServerName::try_from(self.server_name.as_str())?;
+        let tls = self.connector.connect(server_name, tcp).await?;
+        let (sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(tls)).await?;
+        tokio::spawn(connection.with_upgrades());
+        Ok(sender)
+    }
+
+    async fn connect_http2(&self) -> Result<hyper::client::conn::http2::SendRequest<Full<Bytes>>> {
+        let tcp = TcpStream::connect(self.addr)
+            .await
+            .with_context(|| format!("failed to connect to proxy at {}", self.addr))?;
+        let server_name = ServerName::try_from(self.server_name.as_str())?;
+        let tls = self.http2_connector.connect(server_name, tcp).await?;
+        if tls.get_ref().1.alpn_protocol() != Some(b"h2") {
+            bail!("proxy did not negotiate h2");
+        }
+        let (sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(tls)).await?;
+        tokio::spawn(connection);
+        Ok(sender)
+    }
+}
+
+/// Connectors for HTTP/1.1 and, offering `h2` through ALPN, HTTP/2.
+fn connectors(config: ClientConfig) -> (TlsConnector, TlsConnector) {
+    let mut http2_config = config.clone();
+    http2_config.alpn_protocols = vec![b"h2".to_vec()];
+    (
+        TlsConnector::from(Arc::new(config)),
+        TlsConnector::from(Arc::new(http2_config)),
+    )
+}
+
+/// Moves the `Host` header of an origin-form request into its target, which
+/// HTTP/2 sends as `:scheme` and `:authority`.
+fn http2_target(mut request: Request<Full<Bytes>>) -> Result<Request<Full<Bytes>>> {
+    if request.uri().authority().is_none() {
+        if let Some(host) = request.headers_mut().remove(header::HOST) {
+            let path = request
+                .uri()
+                .path_and_query()
+                .map_or("/", |path| path.as_str());
+            *request.uri_mut() = format!("https://{}{path}", host.to_str()?).parse()?;
+        }
+    }
+    *request.version_mut() = Version::HTTP_2;
+    Ok(request)
+}
+
+/// A single keep-alive connection opened by [`TestClient::connection`].
+pub struct TestConnection {
+    sender: SendRequest<Full<Bytes>>,
+}
+
+impl TestConnection {
+    /// Sends `GET path` with the given `Host` header on this connection.
+    pub async fn get(&mut self, host: &str, path: &str) -> Result<TestResponse> {
+        let request = Request::get(path)
+            .header(header::HOST, host)
+            .body(Full::new(Bytes::new()))?;
+        self.sender.ready().await?;
+        TestResponse::collect(self.sender.send_request(request).await?).await
+    }
+
+    /// Waits until the proxy has closed the connection, up to `timeout`.
+    pub async fn closed_within(&mut self, timeout: std::time::Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !self.sender.is_closed() {
+            if tokio::time::Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        true
+    }
+}
@@ -0,0 +1,81 @@
+use std::{future::poll_fn, net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::{Request, Version};
+use quinn::{
+    crypto::rustls::QuicClientConfig,
+    rustls::{self, pki_types::CertificateDer, RootCertStore},
+    Endpoint,
+};
+
+use crate::{client::SERVER_NAME, TestCert, TestResponse};
+
+/// HTTP/3 client pinned to a single proxy address and trusting one test cert.
+#[derive(Clone)]
+pub struct Http3Client {
+    addr: SocketAddr,
+    config: quinn::ClientConfig,
+}
+
+impl Http3Client {
+    pub fn new(addr: SocketAddr, cert: &TestCert) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(cert.der().to_vec()))
+            .context("failed to trust test certificate")?;
+        let mut tls = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        Ok(Self {
+            addr,
+            config: quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?)),
+        })
+    }
+
+    /// Sends `GET path` with `host` as its authority.
+    pub async fn get(&self, host: &str, path: &str) -> Result<TestResponse> {
+        let request = Request::get(format!("https://{host}{path}")).body(Bytes::new())?;
+        self.send(request).await
+    }
+
+    /// Sends an arbitrary request over a fresh connection.
+    pub async fn send(&self, request: Request<Bytes>) -> Result<TestResponse> {
+        let mut endpoint = Endpoint::client(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+        endpoint.set_default_client_config(self.config.clone());
+        let quic = endpoint
+            .connect(self.addr, SERVER_NAME)?
+            .await
+            .with_context(|| format!("failed to connect to {} over QUIC", self.addr))?;
+        let (mut driver, mut sender) = h3::client::new(h3_quinn::Connection::new(quic)).await?;
+        let driver = tokio::spawn(async move { poll_fn(|cx| driver.poll_close(cx)).await });
+
+        let (parts, body) = request.into_parts();
+        let mut stream = sender.send_request(Request::from_parts(parts, ())).await?;
+        if !body.is_empty() {
+            stream.send_data(body).await?;
+        }
+        stream.finish().await?;
+        let response = stream.recv_response().await?;
+        let mut body = BytesMut::new();
+        while let Some(mut chunk) = stream.recv_data().await? {
+            body.put(chunk.copy_to_bytes(chunk.remaining()));
+        }
+        let trailers = stream.recv_trailers().await?;
+        drop(sender);
+        driver.abort();
+        endpoint.close(0u32.into(), b"");
+        let (parts, ()) = response.into_parts();
+        Ok(TestResponse {
+            status: parts.status,
+            version: Version::HTTP_3,
+            headers: parts.headers,
+            body: body.freeze(),
+            trailers,
+        })
+    }
+}
@@ -0,0 +1,18 @@
+//! Helpers for end-to-end tests of jester configurations and plugins.
+//!
+//! [`TestProxy`] boots a real proxy on an ephemeral port with a throwaway
+//! certificate, [`MockUpstream`] records what reaches the backend, and
+//! [`TestClient`] speaks TLS to the proxy while trusting the generated cert;
+//! [`Http3Client`] does the same over QUIC.
+
+mod cert;
+mod client;
+mod http3;
+mod proxy;
+mod upstream;
+
+pub use cert::TestCert;
+pub use client::{TestClient, TestConnection, TestResponse};
+pub use http3::Http3Client;
+pub use proxy::{TestProxy, TestProxyBuilder};
+pub use upstream::{MockUpstream, RecordedRequest};
@@ -0,0 +1,175 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use http::Request;
+use http_body_util::Full;
+use jester_core::{
+    admin::LogControl,
+    config::{Config, Filter, HttpTweaks, Listener, Route},
+    plugin::{JesterPlugin, JesterService},
+    proxy::{ConfigLoader, Proxy, ProxyBuilder, ProxyHandle},
+    stats::RuntimeStats,
+    tap::{AccessEvent, Tap},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tower::Layer;
+
+use crate::{
+    client::{send_plain, SERVER_NAME},
+    TestCert, TestClient, TestResponse,
+};
+
+const LISTENER_NAME: &str = "testkit";
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A running proxy bound to `127.0.0.1` on an ephemeral port.
+pub struct TestProxy {
+    handle: ProxyHandle,
+    addr: SocketAddr,
+    cert: TestCert,
+    events: broadcast::Receiver<AccessEvent>,
+}
+
+impl TestProxy {
+    pub fn builder() -> TestProxyBuilder {
+        TestProxyBuilder {
+            inner: Proxy::builder(),
+            http: None,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn cert(&self) -> &TestCert {
+        &self.cert
+    }
+
+    /// Client that trusts this proxy's certificate.
+    pub fn client(&self) -> TestClient {
+        TestClient::new(self.addr, &self.cert).expect("test certificate is valid")
+    }
+
+    /// Waits for the next access event, failing after a few seconds.
+    pub async fn next_event(&mut self) -> Result<AccessEvent> {
+        loop {
+            match tokio::time::timeout(EVENT_TIMEOUT, self.events.recv()).await {
+                Ok(Ok(event)) => return Ok(event),
+                Ok(Err(RecvError::Lagged(_))) => continue,
+                Ok(Err(RecvError::Closed)) => bail!("proxy stopped publishing events"),
+                Err(_) => bail!("no access event within {EVENT_TIMEOUT:?}"),
+            }
+        }
+    }
+
+    pub fn stats(&self) -> &RuntimeStats {
+        self.handle.stats()
+    }
+
+    pub fn tap(&self) -> &Tap {
+        self.handle.tap()
+    }
+
+    /// Sends a request to the admin API enabled with [`TestProxyBuilder::admin`].
+    pub async fn admin_request(&self, request: Request<Full<Bytes>>) -> Result<TestResponse> {
+        let addr = self
+            .handle
+            .admin_addr()
+            .context("admin API is not enabled; call TestProxyBuilder::admin")?;
+        send_plain(addr, request).await
+    }
+
+    pub async fn shutdown(self) -> Result<()> {
+        self.handle.shutdown().await
+    }
+}
+
+/// Mirrors [`ProxyBuilder`]; listeners are always replaced by the testkit listener.
+pub struct TestProxyBuilder {
+    inner: ProxyBuilder,
+    http: Option<HttpTweaks>,
+}
+
+impl TestProxyBuilder {
+    /// Uses routes, filters, and plugin settings from an existing config.
+    pub fn config(mut self, mut config: Config) -> Self {
+        config.listeners.clear();
+        self.inner = self.inner.config(config);
+        self
+    }
+
+    pub fn route(mut self, route: impl Into<Route>) -> Self {
+        self.inner = self.inner.route(route);
+        self
+    }
+
+    pub fn filter(mut self, filter: impl Into<Filter>) -> Self {
+        self.inner = self.inner.filter(filter);
+        self
+    }
+
+    pub fn plugin(mut self, plugin: Arc<dyn JesterPlugin>) -> Self {
+        self.inner = self.inner.plugin(plugin);
+        self
+    }
+
+    /// Serves the admin API on an ephemeral loopback port.
+    pub fn admin(mut self) -> Self {
+        self.inner = self.inner.admin("127.0.0.1:0");
+        self
+    }
+
+    /// Also serves the admin API on a unix socket at `path`.
+    pub fn admin_socket(mut self, path: impl Into<String>) -> Self {
+        self.inner = self.inner.admin_socket(path);
+        self
+    }
+
+    /// HTTP settings for the testkit listener.
+    pub fn listener_http(mut self, http: HttpTweaks) -> Self {
+        self.http = Some(http);
+        self
+    }
+
+    pub fn config_loader(mut self, loader: ConfigLoader) -> Self {
+        self.inner = self.inner.config_loader(loader);
+        self
+    }
+
+    pub fn log_control(mut self, control: Arc<dyn LogControl>) -> Self {
+        self.inner = self.inner.log_control(control);
+        self
+    }
+
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<JesterService, Service = JesterService> + Send + Sync + 'static,
+    {
+        self.inner = self.inner.layer(layer);
+        self
+    }
+
+    pub async fn start(self) -> Result<TestProxy> {
+        let cert = TestCert::generate(&[SERVER_NAME, "127.0.0.1"])?;
+        let mut listener = Listener::builder(LISTENER_NAME, "127.0.0.1:0").tls(
+            cert.cert_path().to_string_lossy(),
+            cert.key_path().to_string_lossy(),
+        );
+        if let Some(http) = self.http {
+            listener = listener.http(http);
+        }
+        let handle = self.inner.listener(listener).build()?.start().await?;
+        let addr = handle
+            .local_addr(LISTENER_NAME)
+            .context("testkit listener was not bound")?;
+        let events = handle.tap().subscribe();
+        Ok(TestProxy {
+            handle,
+            addr,
+            cert,
+            events,
+        })
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    convert::Infallible,
+    future::{ready, Ready},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use http::{HeaderMap, Method, Request, Response, StatusCode, Uri, Version};
+use http_body_util::{combinators::WithTrailers, BodyExt, Full};
+use hyper::{
+    body::Incoming,
+    server::conn::{http1, http2},
+    service::service_fn,
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio::{net::TcpListener, task::JoinHandle};
+
+type Handler = Arc<dyn Fn(&RecordedRequest) -> Response<MockBody> + Send + Sync>;
+type MockBody = WithTrailers<Full<Bytes>, Ready<Option<Result<HeaderMap, Infallible>>>>;
+
+/// Request as received by a [`MockUpstream`].
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// Plain-HTTP backend on an ephemeral port that records every request.
+///
+/// The server stops when the value is dropped.
+pub struct MockUpstream {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockUpstream {
+    /// Answers every request with `200 ok`.
+    pub async fn start() -> Result<Self> {
+        Self::with_response(StatusCode::OK, "ok").await
+    }
+
+    /// Answers every request with a fixed status and body.
+    pub async fn with_response(status: StatusCode, body: &'static str) -> Result<Self> {
+        Self::with_handler(move |_| {
+            let mut response = Response::new(Full::new(Bytes::from_static(body.as_bytes())));
+            *response.status_mut() = status;
+            response
+        })
+        .await
+    }
+
+    /// Answers each request with the response produced by `handler`.
+    pub async fn with_handler<F>(handler: F) -> Result<Self>
+    where
+        F: Fn(&RecordedRequest) -> Response<Full<Bytes>> + Send + Sync + 'static,
+    {
+        Self::serve(without_trailers(handler), false).await
+    }
+
+    /// Like [`with_handler`](Self::with_handler), but speaking cleartext
+    /// HTTP/2 with prior knowledge (h2c) instead of HTTP/1.1.
+    pub async fn h2c_with_handler<F>(handler: F) -> Result<Self>
+    where
+        F: Fn(&RecordedRequest) -> Response<Full<Bytes>> + Send + Sync + 'static,
+    {
+        Self::serve(without_trailers(handler), true).await
+    }
+
+    /// Like [`h2c_with_handler`](Self::h2c_with_handler), also sending the
+    /// returned trailers after the body, as gRPC servers send `grpc-status`.
+    pub async fn h2c_with_trailers<F>(handler: F) -> Result<Self>
+    where
+        F: Fn(&RecordedRequest) -> (Response<Full<Bytes>>, HeaderMap) + Send + Sync + 'static,
+    {
+        Self::serve(
+            Arc::new(move |request: &RecordedRequest| {
+                let (response, trailers) = handler(request);
+                response.map(|body| body.with_trailers(ready(Some(Ok(trailers)))))
+            }),
+            true,
+        )
+        .await
+    }
+
+    async fn serve(handler: Handler, http2: bool) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let task = tokio::spawn(accept_loop(listener, handler, requests.clone(), http2));
+        Ok(Self {
+            addr,
+            requests,
+            task,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Base URL suitable for `Upstream::single`.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Snapshot of the requests received so far.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn without_trailers<F>(handler: F) -> Handler
+where
+    F: Fn(&RecordedRequest) -> Response<Full<Bytes>> + Send + Sync + 'static,
+{
+    Arc::new(move |request: &RecordedRequest| {
+        handler(request).map(|body| body.with_trailers(ready(None)))
+    })
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    handler: Handler,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    http2: bool,
+) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let handler = handler.clone();
+        let requests = requests.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req: Request<Incoming>| {
+                let handler = handler.clone();
+                let requests = requests.clone();
+                async move {
+                    let (parts, body) = req.into_parts();
+                    let body = body
+                        .collect()
+                        .await
+                        .map(|collected| collected.to_bytes())
+                        .unwrap_or_default();
+                    let recorded = RecordedRequest {
+                        method: parts.method,
+                        uri: parts.uri,
+                        version: parts.version,
+                        headers: parts.headers,
+                        body,
+                    };
+                    let response = handler(&recorded);
+                    requests.lock().unwrap().push(recorded);
+                    Ok::<_, Infallible>(response)
+                }
+            });
+            if http2 {
+                http2::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                    .ok();
+            } else {
+                http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                    .ok();
+            }
+        });
+    }
+}
@@ -0,0 +1,521 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL, Engine};
+use bytes::Bytes;
+use http::{Method, Response, StatusCode};
+use http_body_util::Full;
+use jester_core::config::Acme;
+use jester_testkit::{MockUpstream, RecordedRequest};
+use serde_json::{json, Value};
+use tokio::net::UdpSocket;
+
+/// TXT records held by the mock DNS APIs, and what was asked of them.
+#[derive(Default)]
+struct Zone {
+    records: BTreeMap<String, Vec<String>>,
+    /// `("create" | "delete", name)` per API change.
+    changes: Vec<(&'static str, String)>,
+    /// Cloudflare record IDs.
+    ids: HashMap<String, (String, String)>,
+    /// Resolver queries per name.
+    queries: HashMap<String, usize>,
+}
+
+impl Zone {
+    fn add(&mut self, name: &str, value: String) {
+        self.records.entry(name.into()).or_default().push(value);
+    }
+
+    fn remove(&mut self, name: &str, value: &str) {
+        if let Some(values) = self.records.get_mut(name) {
+            values.retain(|kept| kept != value);
+            if values.is_empty() {
+                self.records.remove(name);
+            }
+        }
+    }
+}
+
+type SharedZone = Arc<Mutex<Zone>>;
+
+/// Serves TXT queries from `zone`, but only from a name's second query on,
+/// so records look slow to propagate.
+async fn resolver(zone: SharedZone) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 512];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+            let query = &buf[..len];
+            let mut at = 12;
+            let mut labels = Vec::new();
+            while query[at] != 0 {
+                let len = usize::from(query[at]);
+                labels.push(String::from_utf8_lossy(&query[at + 1..at + 1 + len]).into_owned());
+                at += 1 + len;
+            }
+            let name = labels.join(".");
+            let values = {
+                let zone = &mut *zone.lock().unwrap();
+                let queries = zone.queries.entry(name.clone()).or_default();
+                *queries += 1;
+                match *queries {
+                    1 => Vec::new(),
+                    _ => zone.records.get(&name).cloned().unwrap_or_default(),
+                }
+            };
+            let mut response = query[..2].to_vec();
+            response.extend_from_slice(&[0x81, 0x80, 0, 1]);
+            response.extend_from_slice(&(values.len() as u16).to_be_bytes());
+            response.extend_from_slice(&[0, 0, 0, 0]);
+            response.extend_from_slice(&query[12..at + 5]);
+            for value in values {
+                response.extend_from_slice(&[0xc0, 12, 0, 16, 0, 1, 0, 0, 0, 60]);
+                response.extend_from_slice(&(value.len() as u16 + 1).to_be_bytes());
+                response.push(value.len() as u8);
+                response.extend_from_slice(value.as_bytes());
+            }
+            socket.send_to(&response, peer).await.ok();
+        }
+    });
+    addr
+}
+
+async fn cloudflare(zone: SharedZone) -> MockUpstream {
+    MockUpstream::with_handler(move |request| {
+        assert_eq!(request.headers["authorization"], "Bearer cf-token");
+        let zone = &mut *zone.lock().unwrap();
+        let path = request.uri.path();
+        let id = if request.method == Method::POST {
+            assert_eq!(path, "/client/v4/zones/cf-zone/dns_records");
+            let record: Value = serde_json::from_slice(&request.body).unwrap();
+            assert_eq!(record["type"], "TXT");
+            let name = record["name"].as_str().unwrap().to_string();
+            let value = record["content"].as_str().unwrap().to_string();
+            let id = format!("record-{}", zone.changes.len());
+            zone.add(&name, value.clone());
+            zone.ids.insert(id.clone(), (name.clone(), value));
+            zone.changes.push(("create", name));
+            id
+        } else {
+            assert_eq!(request.method, Method::DELETE);
+            let id = path
+                .strip_prefix("/client/v4/zones/cf-zone/dns_records/")
+                .unwrap()
+                .to_string();
+            let (name, value) = zone.ids.remove(&id).expect("deleted an unknown record");
+            zone.remove(&name, &value);
+            zone.changes.push(("delete", name));
+            id
+        };
+        let body = json!({ "success": true, "errors": [], "result": { "id": id } });
+        Response::new(Full::new(Bytes::from(body.to_string())))
+    })
+    .await
+    .unwrap()
+}
+
+async fn route53(zone: SharedZone) -> MockUpstream {
+    MockUpstream::with_handler(move |request| {
+        assert_eq!(request.method, Method::POST);
+        assert_eq!(request.uri.path(), "/2013-04-01/hostedzone/Z123/rrset/");
+        let authorization = request.headers["authorization"].to_str().unwrap();
+        assert!(
+            authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDTEST/")
+                && authorization.contains("/us-east-1/route53/aws4_request"),
+            "{authorization}"
+        );
+        let body = String::from_utf8_lossy(&request.body);
+        let tag = |tag: &str| {
+            let start = body.find(&format!("<{tag}>")).unwrap() + tag.len() + 2;
+            body[start..].split(&format!("</{tag}>")).next().unwrap()
+        };
+        let (action, name) = (tag("Action"), tag("Name"));
+        let values = body
+            .split("<Value>")
+            .skip(1)
+            .map(|value| value.split("</Value>").next().unwrap().trim_matches('"'));
+        let zone = &mut *zone.lock().unwrap();
+        match action {
+            "UPSERT" => {
+                zone.records.remove(name);
+                for value in values {
+                    zone.add(name, value.into());
+                }
+                zone.changes.push(("create", name.into()));
+            }
+            "DELETE" => {
+                for value in values {
+                    zone.remove(name, value);
+                }
+                zone.changes.push(("delete", name.into()));
+            }
+            action => panic!("unexpected Route 53 action {action}"),
+        }
+        Response::new(Full::new(Bytes::from_static(
+            b"<ChangeResourceRecordSetsResponse/>",
+        )))
+    })
+    .await
+    .unwrap()
+}
+
+/// An order as the mock CA tracks it.
+struct CaOrder {
+    domains: Vec<String>,
+    /// Indexes of the authorizations whose challenge the client answered.
+    validated: HashSet<usize>,
+    finalized: bool,
+    /// Resources the client touched, in order.
+    steps: Vec<&'static str>,
+}
+
+/// What the mock CA saw.
+#[derive(Default)]
+struct Ca {
+    nonces: HashSet<String>,
+    issued_nonces: usize,
+    orders: Vec<CaOrder>,
+    /// Orders between `newOrder` and their certificate download, now and at
+    /// most.
+    open: usize,
+    peak: usize,
+}
+
+impl Ca {
+    /// Checks a JWS-signed request's URL and nonce, returning its payload.
+    fn verify(&mut self, request: &RecordedRequest, base: &str) -> Option<Value> {
+        let jws: Value = serde_json::from_slice(&request.body).unwrap();
+        let decode = |field: &str| BASE64URL.decode(jws[field].as_str().unwrap()).unwrap();
+        let protected: Value = serde_json::from_slice(&decode("protected")).unwrap();
+        assert_eq!(protected["alg"], "ES256");
+        assert_eq!(protected["url"], format!("{base}{}", request.uri.path()));
+        assert!(
+            self.nonces.remove(protected["nonce"].as_str().unwrap()),
+            "request reused a nonce"
+        );
+        // Only account creation embeds the key; everything else names it.
+        let new_account = request.uri.path() == "/account";
+        assert_eq!(protected.get("jwk").is_some(), new_account);
+        assert_eq!(protected.get("kid").is_some(), !new_account);
+        let payload = decode("payload");
+        (!payload.is_empty()).then(|| serde_json::from_slice(&payload).unwrap())
+    }
+
+    fn order(&self, n: usize, base: &str) -> Value {
+        let order = &self.orders[n];
+        let status = if order.finalized {
+            "valid"
+        } else if order.validated.len() == order.domains.len() {
+            "ready"
+        } else {
+            "pending"
+        };
+        json!({
+            "status": status,
+            "authorizations": (0..order.domains.len())
+                .map(|i| format!("{base}/authz/{n}/{i}"))
+                .collect::<Vec<_>>(),
+            "finalize": format!("{base}/finalize/{n}"),
+            "certificate": order.finalized.then(|| format!("{base}/cert/{n}")),
+        })
+    }
+
+    fn authorization(&self, n: usize, i: usize, base: &str) -> Value {
+        let domain = &self.orders[n].domains[i];
+        let status = match self.orders[n].validated.contains(&i) {
+            true => "valid",
+            false => "pending",
+        };
+        json!({
+            "status": status,
+            "identifier": { "type": "dns", "value": domain.trim_start_matches("*.") },
+            "wildcard": domain.starts_with("*."),
+            "challenges": [
+                { "type": "http-01", "url": format!("{base}/challenge/{n}/{i}"), "token": "unused", "status": status },
+                { "type": "dns-01", "url": format!("{base}/challenge/{n}/{i}"), "token": format!("token-{n}-{i}"), "status": status },
+            ],
+        })
+    }
+}
+
+/// A CA following RFC 8555 closely enough for one client: every order's
+/// authorizations turn valid once their `dns-01` challenge is answered,
+/// which must happen while the challenge record is published.
+async fn ca(zone: SharedZone) -> (MockUpstream, Arc<Mutex<Ca>>) {
+    let state = Arc::new(Mutex::new(Ca::default()));
+    let ca = state.clone();
+    let server = MockUpstream::with_handler(move |request| {
+        let base = format!("http://{}", request.headers["host"].to_str().unwrap());
+        let ca = &mut *ca.lock().unwrap();
+        ca.issued_nonces += 1;
+        let nonce = format!("nonce-{}", ca.issued_nonces);
+        ca.nonces.insert(nonce.clone());
+
+        let path = request.uri.path();
+        let segments = path.split('/').skip(1).collect::<Vec<_>>();
+        let index = |at: usize| segments[at].parse::<usize>().unwrap();
+        let (status, location, body) = match (&request.method, segments[0]) {
+            (&Method::GET, "directory") => (
+                StatusCode::OK,
+                None,
+                json!({
+                    "newNonce": format!("{base}/nonce"),
+                    "newAccount": format!("{base}/account"),
+                    "newOrder": format!("{base}/order"),
+                })
+                .to_string(),
+            ),
+            (&Method::HEAD, "nonce") => (StatusCode::OK, None, String::new()),
+            (&Method::POST, resource) => {
+                let payload = ca.verify(request, &base);
+                match (resource, segments.len()) {
+                    ("account", 1) => {
+                        assert_eq!(payload.unwrap()["termsOfServiceAgreed"], true);
+                        let account = json!({ "status": "valid" }).to_string();
+                        (
+                            StatusCode::CREATED,
+                            Some(format!("{base}/account/1")),
+                            account,
+                        )
+                    }
+                    ("order", 1) => {
+                        let domains = payload.unwrap()["identifiers"]
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .map(|identifier| identifier["value"].as_str().unwrap().to_string())
+                            .collect();
+                        ca.orders.push(CaOrder {
+                            domains,
+                            validated: HashSet::new(),
+                            finalized: false,
+                            steps: vec!["new-order"],
+                        });
+                        ca.open += 1;
+                        ca.peak = ca.peak.max(ca.open);
+                        let n = ca.orders.len() - 1;
+                        let location = format!("{base}/order/{n}");
+                        (
+                            StatusCode::CREATED,
+                            Some(location),
+                            ca.order(n, &base).to_string(),
+                        )
+                    }
+                    ("order", 2) => (StatusCode::OK, None, ca.order(index(1), &base).to_string()),
+                    ("authz", 3) => {
+                        let (n, i) = (index(1), index(2));
+                        ca.orders[n].steps.push("authorization");
+                        let authorization = ca.authorization(n, i, &base);
+                        (StatusCode::OK, None, authorization.to_string())
+                    }
+                    ("challenge", 3) => {
+                        let (n, i) = (index(1), index(2));
+                        let domain = ca.orders[n].domains[i].trim_start_matches("*.");
+                        let name = format!("_acme-challenge.{domain}");
+                        assert!(
+                            zone.lock().unwrap().records.contains_key(&name),
+                            "challenge answered before {name} was published"
+                        );
+                        ca.orders[n].validated.insert(i);
+                        ca.orders[n].steps.push("challenge");
+                        let challenge = json!({ "type": "dns-01", "status": "valid" });
+                        (StatusCode::OK, None, challenge.to_string())
+                    }
+                    ("finalize", 2) => {
+                        let n = index(1);
+                        let order = &mut ca.orders[n];
+                        assert_eq!(order.validated.len(), order.domains.len());
+                        assert!(!payload.unwrap()["csr"].as_str().unwrap().is_empty());
+                        order.finalized = true;
+                        order.steps.push("finalize");
+                        (StatusCode::OK, None, ca.order(n, &base).to_string())
+                    }
+                    ("cert", 2) => {
+                        let order = &mut ca.orders[index(1)];
+                        order.steps.push("certificate");
+                        let certified =
+                            rcgen::generate_simple_self_signed(order.domains.clone()).unwrap();
+                        ca.open -= 1;
+                        (StatusCode::OK, None, certified.cert.pem())
+                    }
+                    _ => panic!("unexpected ACME request to {path}"),
+                }
+            }
+            (method, _) => panic!("unexpected {method} {path}"),
+        };
+        let mut response = Response::builder()
+            .status(status)
+            .header("replay-nonce", nonce);
+        if let Some(location) = location {
+            response = response.header("location", location);
+        }
+        response.body(Full::new(Bytes::from(body))).unwrap()
+    })
+    .await
+    .unwrap();
+    (server, state)
+}
+
+fn acme(
+    dir: &Path,
+    ca: &MockUpstream,
+    dns: Value,
+    resolver: SocketAddr,
+    max_concurrent_renewals: usize,
+    certificates: &[&[&str]],
+) -> Acme {
+    let certificates = certificates
+        .iter()
+        .enumerate()
+        .map(|(i, domains)| {
+            json!({
+                "domains": domains,
+                "cert": dir.join(format!("{i}.crt")),
+                "key": dir.join(format!("{i}.key")),
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::from_value(json!({
+        "directory": format!("{}/directory", ca.url()),
+        "state_dir": dir.join("state"),
+        "max_concurrent_renewals": max_concurrent_renewals,
+        "dns": dns,
+        "propagation": {
+            "resolvers": [resolver.to_string()],
+            "timeout_secs": 10,
+            "interval_secs": 1,
+        },
+        "certificates": certificates,
+    }))
+    .unwrap()
+}
+
+/// Checks every certificate was written and every challenge record was
+/// published, polled for, and removed again.
+fn assert_issued(acme: &Acme, zone: &Zone) {
+    for certificate in &acme.certificates {
+        let cert = std::fs::read_to_string(&certificate.cert).unwrap();
+        assert!(cert.starts_with("-----BEGIN CERTIFICATE-----"));
+        let key = std::fs::read_to_string(&certificate.key).unwrap();
+        assert!(key.contains("PRIVATE KEY"));
+        let name = format!(
+            "_acme-challenge.{}",
+            certificate.domains[0].trim_start_matches("*.")
+        );
+        assert!(zone.changes.contains(&("create", name.clone())));
+        assert!(zone.changes.contains(&("delete", name.clone())));
+        assert!(
+            zone.queries[&name] >= 2,
+            "{name} was never polled for again"
+        );
+    }
+    assert!(zone.records.is_empty(), "left behind {:?}", zone.records);
+}
+
+#[tokio::test]
+async fn acme_orders_certificates_through_cloudflare() {
+    let dir = tempfile::tempdir().unwrap();
+    let zone = SharedZone::default();
+    let (ca, orders) = ca(zone.clone()).await;
+    let cloudflare = cloudflare(zone.clone()).await;
+    let dns = json!({
+        "provider": "cloudflare",
+        "api_token": "cf-token",
+        "zone_id": "cf-zone",
+        "endpoint": format!("{}/client/v4", cloudflare.url()),
+    });
+    let resolver = resolver(zone.clone()).await;
+    let acme = acme(
+        dir.path(),
+        &ca,
+        dns,
+        resolver,
+        2,
+        &[&["a.example.com"], &["b.example.com"], &["c.example.com"]],
+    );
+
+    jester_core::acme::provision(&acme, None).await.unwrap();
+
+    assert_issued(&acme, &zone.lock().unwrap());
+    let orders = orders.lock().unwrap();
+    assert_eq!(orders.orders.len(), 3);
+    for order in &orders.orders {
+        let mut steps = order.steps.clone();
+        steps.dedup();
+        assert_eq!(
+            steps,
+            [
+                "new-order",
+                "authorization",
+                "challenge",
+                "authorization",
+                "finalize",
+                "certificate"
+            ]
+        );
+    }
+    // Two orders overlap while their records propagate; the third waits.
+    assert_eq!(orders.peak, 2);
+    assert_eq!(orders.open, 0);
+}
+
+#[tokio::test]
+async fn acme_orders_certificates_through_route53() {
+    let dir = tempfile::tempdir().unwrap();
+    let zone = SharedZone::default();
+    let (ca, orders) = ca(zone.clone()).await;
+    let route53 = route53(zone.clone()).await;
+    let dns = json!({
+        "provider": "route53",
+        "access_key_id": "AKIDTEST",
+        "secret_access_key": "secret",
+        "hosted_zone_id": "/hostedzone/Z123",
+        "endpoint": route53.url(),
+    });
+    let resolver = resolver(zone.clone()).await;
+    let acme = acme(
+        dir.path(),
+        &ca,
+        dns,
+        resolver,
+        1,
+        &[&["example.com", "*.example.com"], &["example.net"]],
+    );
+
+    jester_core::acme::provision(&acme, None).await.unwrap();
+
+    let zone = zone.lock().unwrap();
+    assert_issued(&acme, &zone);
+    // `example.com` and `*.example.com` share one record with two values.
+    let apex = "_acme-challenge.example.com".to_string();
+    assert_eq!(
+        zone.changes
+            .iter()
+            .filter(|(_, name)| *name == apex)
+            .count(),
+        2
+    );
+    let orders = orders.lock().unwrap();
+    let mut steps = orders.orders[0].steps.clone();
+    steps.dedup();
+    assert_eq!(
+        steps,
+        [
+            "new-order",
+            "authorization",
+            "challenge",
+            "authorization",
+            "challenge",
+            "authorization",
+            "finalize",
+            "certificate"
+        ]
+    );
+    assert_eq!(orders.peak, 1);
+}
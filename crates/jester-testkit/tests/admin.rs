@@ -0,0 +1,578 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use http::{header, Method, Request, StatusCode};
+use http_body_util::Full;
+use jester_core::{
+    admin::LogControl,
+    config::{Config, EventSink, Filter, Listener, Route, Upstream},
+};
+use jester_testkit::{MockUpstream, TestProxy, TestResponse};
+use serde_json::Value;
+
+async fn validate(proxy: &TestProxy, content_type: &str, body: String) -> Value {
+    let request = Request::post("/config/validate")
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Full::new(Bytes::from(body)))
+        .unwrap();
+    let TestResponse { status, body, .. } = proxy.admin_request(request).await.unwrap();
+    assert_eq!(status, StatusCode::OK);
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn validates_candidate_configs_against_running_instance() {
+    let proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::single("http://127.0.0.1:1")).host("example.com"))
+        .admin()
+        .start()
+        .await
+        .unwrap();
+    let cert = proxy.cert();
+    let listener = Listener::builder("edge", "127.0.0.1:8443")
+        .tls(
+            cert.cert_path().to_string_lossy(),
+            cert.key_path().to_string_lossy(),
+        )
+        .build();
+    let app = Route::builder("app", Upstream::single("http://127.0.0.1:8080"))
+        .host("example.com")
+        .build();
+    let mut shadowed = app.clone();
+    shadowed.name = "shadowed".into();
+
+    let valid = Config::builder()
+        .listener(listener.clone())
+        .route(app)
+        .route(shadowed)
+        .build_unchecked();
+    let result = validate(
+        &proxy,
+        "application/json",
+        serde_json::to_string(&valid).unwrap(),
+    )
+    .await;
+    assert_eq!(result["valid"], true);
+    assert_eq!(result["version"], jester_core::version());
+    assert_eq!(result["diagnostics"][0]["severity"], "warning");
+    assert!(result["diagnostics"][0]["message"]
+        .as_str()
+        .unwrap()
+        .contains("route `shadowed` is unreachable"));
+
+    let unknown_filter = format!(
+        r#"
+        [[listeners]]
+        name = "edge"
+        bind = "127.0.0.1:8443"
+        tls = {{ cert = "{}", key = "{}" }}
+
+        [[routes]]
+        name = "app"
+        matchers = {{ hosts = ["example.com"] }}
+        upstream = {{ strategy = "single", target = "http://127.0.0.1:8080" }}
+        filters = [{{ type = "builtin", name = "no-such-filter" }}]
+        "#,
+        listener.tls.as_ref().unwrap().cert,
+        listener.tls.as_ref().unwrap().key,
+    );
+    let result = validate(&proxy, "application/toml", unknown_filter).await;
+    assert_eq!(result["valid"], false);
+    assert_eq!(result["diagnostics"][0]["severity"], "error");
+    assert!(result["diagnostics"][0]["message"]
+        .as_str()
+        .unwrap()
+        .contains("no-such-filter"));
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn validates_candidates_with_overlays_and_strict_keys() {
+    let proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::single("http://127.0.0.1:1")).host("example.com"))
+        .admin()
+        .start()
+        .await
+        .unwrap();
+    let base = format!(
+        r#"
+[[listeners]]
+name = "edge"
+bind = "127.0.0.1:8443"
+tls = {{ cert = "{}", key = "{}" }}
+
+[[routes]]
+name = "app"
+matchers = {{ hosts = ["${{JESTER_TEST_UNSET_HOST:example.com}}"] }}
+upstream = {{ strategy = "single", target = "http://127.0.0.1:8080" }}
+"#,
+        proxy.cert().cert_path().display(),
+        proxy.cert().key_path().display(),
+    );
+    let multipart = |overlay: &str| {
+        format!(
+            "--b\r\n\
+             content-disposition: form-data; name=\"config\"; filename=\"base.toml\"\r\n\r\n\
+             {base}\r\n\
+             --b\r\n\
+             content-disposition: form-data; name=\"overlay\"; filename=\"prod.toml\"\r\n\r\n\
+             {overlay}\r\n\
+             --b--\r\n"
+        )
+    };
+    let validate = |query: &'static str, body: String| {
+        let request = Request::post(format!("/config/validate{query}"))
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap();
+        let proxy = &proxy;
+        async move {
+            let TestResponse { status, body, .. } = proxy.admin_request(request).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            serde_json::from_slice::<Value>(&body).unwrap()
+        }
+    };
+
+    let result = validate("", multipart("")).await;
+    assert_eq!(result["valid"], true, "{result}");
+
+    // The overlay's route merges onto the base route of the same name.
+    let bad_filter = r#"
+[[routes]]
+name = "app"
+filters = [{ type = "builtin", name = "no-such-filter" }]
+"#;
+    let result = validate("", multipart(bad_filter)).await;
+    assert_eq!(result["valid"], false);
+    assert!(result["diagnostics"][0]["message"]
+        .as_str()
+        .unwrap()
+        .contains("no-such-filter"));
+
+    let typo = r#"
+[[routes]]
+name = "app"
+retry_budgt = 3
+"#;
+    assert_eq!(validate("", multipart(typo)).await["valid"], true);
+    let result = validate("?strict=true", multipart(typo)).await;
+    assert_eq!(result["valid"], false);
+    assert!(result["diagnostics"][0]["message"]
+        .as_str()
+        .unwrap()
+        .contains("retry_budgt"));
+
+    proxy.shutdown().await.unwrap();
+}
+
+struct FakeFilter(Mutex<String>);
+
+impl LogControl for FakeFilter {
+    fn directives(&self) -> String {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set_directives(&self, directives: &str) -> Result<()> {
+        if directives.contains(' ') {
+            bail!("spaces are not allowed");
+        }
+        *self.0.lock().unwrap() = directives.to_string();
+        Ok(())
+    }
+}
+
+async fn log_level(proxy: &TestProxy, method: Method, body: &str) -> TestResponse {
+    let request = Request::builder()
+        .method(method)
+        .uri("/log-level")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap();
+    proxy.admin_request(request).await.unwrap()
+}
+
+#[tokio::test]
+async fn log_level_can_be_changed_and_reset() {
+    let filter = Arc::new(FakeFilter(Mutex::new("info".into())));
+    let proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::single("http://127.0.0.1:1")).host("example.com"))
+        .admin()
+        .log_control(filter.clone())
+        .start()
+        .await
+        .unwrap();
+
+    let response = log_level(&proxy, Method::PUT, "info,jester_core::proxy=trace").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(filter.directives(), "info,jester_core::proxy=trace");
+    let current: Value =
+        serde_json::from_slice(&log_level(&proxy, Method::GET, "").await.body).unwrap();
+    assert_eq!(current["directives"], "info,jester_core::proxy=trace");
+
+    let rejected = log_level(&proxy, Method::PUT, "not valid").await;
+    assert_eq!(rejected.status, StatusCode::BAD_REQUEST);
+    assert_eq!(filter.directives(), "info,jester_core::proxy=trace");
+
+    assert_eq!(
+        log_level(&proxy, Method::DELETE, "").await.status,
+        StatusCode::OK
+    );
+    assert_eq!(filter.directives(), "info");
+
+    proxy.shutdown().await.unwrap();
+}
+
+async fn admin_json(proxy: &TestProxy, method: Method, path: &str) -> (StatusCode, Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(path)
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = proxy.admin_request(request).await.unwrap();
+    let json = serde_json::from_slice(&response.body).unwrap_or(Value::Null);
+    (response.status, json)
+}
+
+#[tokio::test]
+async fn reload_swaps_routes_and_reports_live_state() {
+    let next: Arc<Mutex<Option<Config>>> = Arc::default();
+    let loader = next.clone();
+    let proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::single("http://127.0.0.1:1")).host("example.com"))
+        .admin()
+        .config_loader(Arc::new(move || {
+            loader
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("no config staged"))
+        }))
+        .start()
+        .await
+        .unwrap();
+
+    let (status, routes) = admin_json(&proxy, Method::GET, "/routes").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(routes[0]["name"], "app");
+    assert_eq!(routes[0]["inflight"], 0);
+    let (status, stats) = admin_json(&proxy, Method::GET, "/stats").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(stats["listeners"].is_object());
+
+    let (status, _) = admin_json(&proxy, Method::POST, "/reload").await;
+    assert_eq!(
+        status,
+        StatusCode::CONFLICT,
+        "loader errors keep the old config"
+    );
+
+    let cert = proxy.cert();
+    *next.lock().unwrap() = Some(
+        Config::builder()
+            .listener(Listener::builder("edge", "127.0.0.1:8443").tls(
+                cert.cert_path().to_string_lossy(),
+                cert.key_path().to_string_lossy(),
+            ))
+            .route(
+                Route::builder("api", Upstream::single("http://127.0.0.1:1"))
+                    .host("api.example.com"),
+            )
+            .build()
+            .unwrap(),
+    );
+    let (status, outcome) = admin_json(&proxy, Method::POST, "/reload").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(outcome["routes"], 1);
+    assert_eq!(outcome["restart_required"], true);
+    let (_, routes) = admin_json(&proxy, Method::GET, "/routes").await;
+    assert_eq!(routes[0]["name"], "api");
+
+    let response = proxy.client().get("example.com", "/").await.unwrap();
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn startup_report_describes_listeners_and_plugins() {
+    let proxy = TestProxy::builder()
+        .route(
+            Route::builder("app", Upstream::single("http://127.0.0.1:1"))
+                .host("example.com")
+                .filter(Filter::builtin("cache"))
+                .filter(Filter::builtin("coalesce")),
+        )
+        .route(
+            Route::builder("api", Upstream::single("http://127.0.0.1:1"))
+                .host("api.example.com")
+                .filter(Filter::builtin("coalesce")),
+        )
+        .admin()
+        .start()
+        .await
+        .unwrap();
+
+    let (status, report) = admin_json(&proxy, Method::GET, "/startup").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(report["version"], jester_core::version());
+    assert_eq!(report["routes"], 2);
+    let listener = &report["listeners"][0];
+    assert_eq!(listener["addr"], proxy.addr().to_string());
+    assert_eq!(listener["routes"], 2);
+    assert_eq!(listener["certificate"]["chain"], 1);
+    assert!(listener["certificate"]["not_after"].as_u64().unwrap() > 0);
+    let plugins: Vec<(&str, u64)> = report["plugins"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|plugin| {
+            (
+                plugin["name"].as_str().unwrap(),
+                plugin["filters"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(plugins, [("cache", 1), ("coalesce", 2)]);
+    assert_eq!(report["degraded"], serde_json::json!([]));
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn drain_stops_accepting_connections() {
+    let proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::single("http://127.0.0.1:1")).host("example.com"))
+        .admin()
+        .start()
+        .await
+        .unwrap();
+    let (status, _) = admin_json(&proxy, Method::POST, "/reload").await;
+    assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+
+    let (status, _) = admin_json(&proxy, Method::POST, "/drain").await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let mut refused = false;
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(proxy.addr()).await.is_err() {
+            refused = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert!(refused, "listener still accepting after drain");
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn metrics_link_latency_buckets_to_trace_ids() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "ok")
+        .await
+        .unwrap();
+    let proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .admin()
+        .start()
+        .await
+        .unwrap();
+
+    let request = Request::get("/")
+        .header(header::HOST, "example.com")
+        .header(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = proxy.client().send(request).await.unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+
+    let request = Request::get("/metrics")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = proxy.admin_request(request).await.unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.headers[header::CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .starts_with("application/openmetrics-text"));
+    let text = response.text();
+    assert!(text.contains("jester_request_duration_seconds_count{route=\"app\"} 1"));
+    assert!(text.contains("# {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"}"));
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn profile_reports_time_per_layer_of_sampled_requests() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "ok")
+        .await
+        .unwrap();
+    let proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .admin()
+        .start()
+        .await
+        .unwrap();
+
+    let profile = admin_json(&proxy, Method::POST, "/profile?seconds=0");
+    assert_eq!(profile.await.0, StatusCode::BAD_REQUEST);
+    // Heap profiles need the sampling allocator, which tests do not install.
+    let heap = admin_json(&proxy, Method::POST, "/profile?kind=heap&seconds=1");
+    assert_eq!(heap.await.0, StatusCode::NOT_IMPLEMENTED);
+    let kind = admin_json(&proxy, Method::POST, "/profile?kind=gpu");
+    assert_eq!(kind.await.0, StatusCode::BAD_REQUEST);
+
+    let request = Request::post("/profile?seconds=1&route=app")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let (profile, conflict) = tokio::join!(proxy.admin_request(request), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let client = proxy.client();
+        for _ in 0..5 {
+            let response = client.get("example.com", "/").await.unwrap();
+            assert_eq!(response.status, StatusCode::OK);
+        }
+        admin_json(&proxy, Method::POST, "/profile?seconds=1")
+            .await
+            .0
+    });
+    assert_eq!(conflict, StatusCode::CONFLICT);
+
+    let profile = profile.unwrap();
+    assert_eq!(profile.status, StatusCode::OK);
+    assert_eq!(profile.headers["x-jester-sampled-requests"], "5");
+    let folded = String::from_utf8(profile.body.to_vec()).unwrap();
+    assert!(
+        folded
+            .lines()
+            .any(|line| line.starts_with("jester;route:app;upstream ")),
+        "{folded}"
+    );
+    for line in folded.lines() {
+        let (stack, micros) = line.rsplit_once(' ').unwrap();
+        assert!(stack.starts_with("jester"), "{line}");
+        assert!(micros.parse::<u64>().unwrap() > 0, "{line}");
+    }
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn admin_api_is_served_on_unix_socket() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("admin.sock");
+    let proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::single("http://127.0.0.1:1")).host("example.com"))
+        .admin_socket(socket.to_string_lossy())
+        .start()
+        .await
+        .unwrap();
+
+    let mut stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+    stream
+        .write_all(b"GET /routes HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.contains(r#""name": "app""#), "{response}");
+
+    proxy.shutdown().await.unwrap();
+    assert!(!socket.exists(), "socket file is removed on shutdown");
+}
+
+#[tokio::test]
+async fn reloads_publish_route_events_to_webhooks() {
+    let hook = MockUpstream::start().await.unwrap();
+    let sink = EventSink::Webhook {
+        url: format!("{}/events", hook.url()),
+        headers: [("x-token".to_string(), "secret".to_string())].into(),
+        timeout_secs: 5,
+        kinds: Vec::new(),
+    };
+    let next: Arc<Mutex<Option<Config>>> = Arc::default();
+    let loader = next.clone();
+    let proxy = TestProxy::builder()
+        .config(
+            Config::builder()
+                .route(
+                    Route::builder("app", Upstream::single("http://127.0.0.1:1"))
+                        .host("example.com"),
+                )
+                .event_sink(sink.clone())
+                .build_unchecked(),
+        )
+        .admin()
+        .config_loader(Arc::new(move || {
+            loader
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("no config staged"))
+        }))
+        .start()
+        .await
+        .unwrap();
+
+    let cert = proxy.cert();
+    *next.lock().unwrap() = Some(
+        Config::builder()
+            .listener(Listener::builder("edge", "127.0.0.1:8443").tls(
+                cert.cert_path().to_string_lossy(),
+                cert.key_path().to_string_lossy(),
+            ))
+            .route(
+                Route::builder("api", Upstream::single("http://127.0.0.1:1"))
+                    .host("api.example.com"),
+            )
+            .event_sink(sink)
+            .build()
+            .unwrap(),
+    );
+    let (status, _) = admin_json(&proxy, Method::POST, "/reload").await;
+    assert_eq!(status, StatusCode::OK);
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while hook.requests().len() < 3 {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "got {:?}",
+            hook.requests()
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let events: Vec<Value> = hook
+        .requests()
+        .iter()
+        .map(|request| {
+            assert_eq!(request.method, Method::POST);
+            assert_eq!(request.uri.path(), "/events");
+            assert_eq!(request.headers["x-token"], "secret");
+            serde_json::from_slice(&request.body).unwrap()
+        })
+        .collect();
+    let summary: Vec<_> = events
+        .iter()
+        .map(|event| (event["kind"].as_str(), event["route"].as_str()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (Some("route_added"), Some("app")),
+            (Some("route_added"), Some("api")),
+            (Some("route_removed"), Some("app")),
+        ]
+    );
+    assert_eq!(events[0]["detail"], "startup");
+
+    proxy.shutdown().await.unwrap();
+}
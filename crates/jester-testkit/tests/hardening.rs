@@ -0,0 +1,75 @@
+//! Hardening confines the whole test process, so it gets a binary of its own
+//! with a single test.
+#![cfg(target_os = "linux")]
+
+use std::{io::ErrorKind, process::Command};
+
+use bytes::Bytes;
+use http::{Request, StatusCode};
+use http_body_util::Full;
+use jester_core::config::{Config, Hardening, Route, Upstream};
+use jester_testkit::{MockUpstream, TestProxy};
+use serde_json::Value;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn hardened_proxies_serve_but_cannot_exec_or_wander() {
+    // SAFETY: a null attribute with LANDLOCK_CREATE_RULESET_VERSION only
+    // queries the ABI version.
+    let landlock = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<u8>(),
+            0usize,
+            1u32,
+        )
+    } >= 1;
+    let upstream = MockUpstream::start().await.unwrap();
+    let config = Config::builder()
+        .hardening(Hardening {
+            landlock,
+            ..Hardening::default()
+        })
+        .build_unchecked();
+    let proxy = TestProxy::builder()
+        .config(config)
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .admin()
+        .start()
+        .await
+        .unwrap();
+
+    let response = proxy.client().get("example.com", "/").await.unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "ok");
+
+    let startup = proxy
+        .admin_request(
+            Request::get("/startup")
+                .body(Full::new(Bytes::new()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let report: Value = serde_json::from_slice(&startup.body).unwrap();
+    let hardening = &report["hardening"];
+    assert_eq!(hardening["capabilities_dropped"], true);
+    assert_eq!(hardening["native_plugins_refused"], true);
+    let denied = hardening["seccomp_denied"].as_array().unwrap();
+    assert!(denied.iter().any(|name| name == "execve"), "{denied:?}");
+
+    let exec = Command::new("/bin/true").status().unwrap_err();
+    assert_eq!(exec.kind(), ErrorKind::PermissionDenied, "{exec}");
+    if landlock {
+        assert!(hardening["landlock"]["abi"].as_i64().unwrap() >= 1);
+        let read = std::fs::read("/etc/passwd").unwrap_err();
+        assert_eq!(read.kind(), ErrorKind::PermissionDenied, "{read}");
+        // Worker threads are confined too, not just the one that started it.
+        let read = tokio::spawn(tokio::fs::read("/etc/passwd"))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(read.kind(), ErrorKind::PermissionDenied, "{read}");
+    }
+
+    proxy.shutdown().await.unwrap();
+}
@@ -0,0 +1,942 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::{header, Request, StatusCode, Version};
+use http_body_util::Full;
+use jester_core::{
+    config::{
+        ClientAuth, HttpTweaks, Listener, Matchers, ProxyProtocolVersion, Route, StreamTarget,
+        TlsVersion, UdpTarget, Upstream,
+    },
+    proxy::{BindOptions, BindPolicy, Proxy},
+};
+use jester_testkit::{Http3Client, MockUpstream, TestCert, TestClient, TestProxy};
+
+fn proxy_with_busy_listener(cert: &TestCert, busy: &str, policy: BindPolicy) -> Proxy {
+    let listener = |name: &str, bind: &str| {
+        Listener::builder(name, bind).tls(
+            cert.cert_path().to_string_lossy(),
+            cert.key_path().to_string_lossy(),
+        )
+    };
+    Proxy::builder()
+        .listener(listener("busy", busy))
+        .listener(listener("free", "127.0.0.1:0"))
+        .route(Route::builder("app", Upstream::single("http://127.0.0.1:9")).host("example.com"))
+        .bind_options(BindOptions {
+            policy,
+            retries: 1,
+            initial_backoff: Duration::from_millis(10),
+        })
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn fail_fast_reports_every_failed_listener() {
+    let cert = TestCert::generate(&["localhost"]).unwrap();
+    let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let busy = occupied.local_addr().unwrap().to_string();
+
+    let err = proxy_with_busy_listener(&cert, &busy, BindPolicy::FailFast)
+        .start()
+        .await
+        .err()
+        .expect("bind must fail");
+    let message = err.to_string();
+    assert!(message.contains("1 of 2 listeners"), "{message}");
+    assert!(message.contains("`busy`"), "{message}");
+}
+
+#[tokio::test]
+async fn best_effort_starts_remaining_listeners() {
+    let cert = TestCert::generate(&["localhost"]).unwrap();
+    let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let busy = occupied.local_addr().unwrap().to_string();
+
+    let handle = proxy_with_busy_listener(&cert, &busy, BindPolicy::BestEffort)
+        .start()
+        .await
+        .unwrap();
+    assert!(handle.local_addr("busy").is_none());
+    assert!(handle.local_addr("free").is_some());
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn connections_close_after_max_requests_or_idle_timeout() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "ok")
+        .await
+        .unwrap();
+    let proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .listener_http(HttpTweaks {
+            keep_alive_timeout_secs: Some(1),
+            max_requests_per_connection: Some(2),
+            ..Default::default()
+        })
+        .start()
+        .await
+        .unwrap();
+
+    let mut connection = proxy.client().connection().await.unwrap();
+    let first = connection.get("example.com", "/").await.unwrap();
+    assert!(first.headers.get(header::CONNECTION).is_none());
+    let second = connection.get("example.com", "/").await.unwrap();
+    assert_eq!(second.headers[header::CONNECTION], "close");
+    assert!(connection.closed_within(Duration::from_secs(1)).await);
+
+    let mut idle = proxy.client().connection().await.unwrap();
+    idle.get("example.com", "/").await.unwrap();
+    assert!(!idle.closed_within(Duration::from_millis(300)).await);
+    assert!(idle.closed_within(Duration::from_secs(3)).await);
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn http2_clients_are_served_over_http2() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "ok")
+        .await
+        .unwrap();
+    let proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .start()
+        .await
+        .unwrap();
+
+    let response = proxy
+        .client()
+        .http2()
+        .get("example.com", "/greet?name=jester")
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.version, Version::HTTP_2);
+    assert_eq!(response.text(), "ok");
+    assert!(response.headers.get(header::CONNECTION).is_none());
+
+    // `:authority` routes like `Host`, and the HTTP/1 upstream gets HTTP/1.1.
+    let received = upstream.requests();
+    assert_eq!(received[0].version, Version::HTTP_11);
+    assert_eq!(received[0].uri, "/greet?name=jester");
+    assert_eq!(received[0].headers["x-forwarded-proto"], "https");
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn h3_listeners_serve_http3_and_tcp_listeners_advertise_them() {
+    let upstream =
+        MockUpstream::with_handler(|request| http::Response::new(Full::new(request.body.clone())))
+            .await
+            .unwrap();
+    let cert = TestCert::generate(&["localhost"]).unwrap();
+    let listener = |name: &str| {
+        Listener::builder(name, "127.0.0.1:0").tls(
+            cert.cert_path().to_string_lossy(),
+            cert.key_path().to_string_lossy(),
+        )
+    };
+    let handle = Proxy::builder()
+        .listener(listener("edge"))
+        .listener(listener("edge-h3").h3())
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .build()
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    let h3_addr = handle.local_addr("edge-h3").unwrap();
+    let h3 = Http3Client::new(h3_addr, &cert).unwrap();
+
+    let response = h3.get("example.com", "/greet?name=jester").await.unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "");
+    let post = Request::post("https://example.com/echo")
+        .body(Bytes::from_static(b"over quic"))
+        .unwrap();
+    let response = h3.send(post).await.unwrap();
+    assert_eq!(response.text(), "over quic");
+
+    // The HTTP/1 upstream gets HTTP/1.1, and a GET without a body stays one.
+    let received = upstream.requests();
+    assert_eq!(received[0].version, Version::HTTP_11);
+    assert_eq!(received[0].uri, "/greet?name=jester");
+    assert!(received[0].headers.get(header::TRANSFER_ENCODING).is_none());
+    assert_eq!(received[1].body, "over quic");
+
+    let tcp = TestClient::new(handle.local_addr("edge").unwrap(), &cert).unwrap();
+    let response = tcp.get("example.com", "/").await.unwrap();
+    assert_eq!(
+        response.headers[header::ALT_SVC],
+        format!("h3=\":{}\"; ma=86400", h3_addr.port()).as_str()
+    );
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn clients_without_an_allowed_protocol_are_turned_away() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "ok")
+        .await
+        .unwrap();
+    let cert = TestCert::generate(&["localhost"]).unwrap();
+    let handle = Proxy::builder()
+        .listener(
+            Listener::builder("edge", "127.0.0.1:0")
+                .tls(
+                    cert.cert_path().to_string_lossy(),
+                    cert.key_path().to_string_lossy(),
+                )
+                .alpn(["h2"]),
+        )
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .build()
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    let client = TestClient::new(handle.local_addr("edge").unwrap(), &cert).unwrap();
+
+    // Without ALPN the client would speak HTTP/1.1, which the listener does not serve.
+    assert!(client.get("example.com", "/").await.is_err());
+    let response = client.http2().get("example.com", "/").await.unwrap();
+    assert_eq!(response.version, Version::HTTP_2);
+    assert_eq!(upstream.requests().len(), 1);
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn http2_connections_go_away_after_max_requests() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "ok")
+        .await
+        .unwrap();
+    let proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .listener_http(HttpTweaks {
+            max_requests_per_connection: Some(2),
+            h2_max_concurrent_streams: Some(10),
+            ..Default::default()
+        })
+        .start()
+        .await
+        .unwrap();
+
+    let mut connection = proxy.client().http2_connection().await.unwrap();
+    let request = || {
+        Request::get("https://example.com/")
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    };
+    for _ in 0..2 {
+        connection.ready().await.unwrap();
+        let response = connection.send_request(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::CONNECTION).is_none());
+    }
+    // The second response came with a GOAWAY, so the connection is done.
+    let mut closed = false;
+    for _ in 0..50 {
+        if connection.is_closed() || connection.ready().await.is_err() {
+            closed = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(closed, "connection still open after its last request");
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn listeners_sharing_an_address_are_chosen_by_sni() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "ok")
+        .await
+        .unwrap();
+    let shop = TestCert::generate(&["shop.example.com"]).unwrap();
+    let tenants = TestCert::generate(&["*.tenants.example.com"]).unwrap();
+    let fallback = TestCert::generate(&["localhost", "unknown.example.com"]).unwrap();
+    let bind = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let listener = |name: &str, cert: &TestCert| {
+        Listener::builder(name, bind.as_str()).tls(
+            cert.cert_path().to_string_lossy(),
+            cert.key_path().to_string_lossy(),
+        )
+    };
+    let handle = Proxy::builder()
+        .listener(listener("shop", &shop).server_names(["shop.example.com"]))
+        .listener(listener("tenants", &tenants).server_names(["*.tenants.example.com"]))
+        .listener(listener("fallback", &fallback))
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("*"))
+        .bind_options(BindOptions {
+            retries: 3,
+            ..Default::default()
+        })
+        .build()
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    let addr = handle.local_addr("shop").unwrap();
+    assert_eq!(handle.local_addr("tenants"), Some(addr));
+    assert_eq!(handle.local_addr("fallback"), Some(addr));
+    let mut events = handle.tap().subscribe();
+
+    let cases = [
+        (&shop, "shop.example.com", "shop"),
+        (&tenants, "acme.tenants.example.com", "tenants"),
+        (&fallback, "localhost", "fallback"),
+        (&fallback, "unknown.example.com", "fallback"),
+    ];
+    for (cert, server_name, expected) in cases {
+        let client = TestClient::new(addr, cert)
+            .unwrap()
+            .server_name(server_name);
+        let response = client.get(server_name, "/").await.unwrap();
+        assert_eq!(response.status, StatusCode::OK, "{server_name}");
+        assert_eq!(events.recv().await.unwrap().listener, expected);
+    }
+    let wrong_cert = TestClient::new(addr, &shop)
+        .unwrap()
+        .server_name("deep.sub.tenants.example.com");
+    assert!(
+        wrong_cert.get("example.com", "/").await.is_err(),
+        "wildcards cover a single label"
+    );
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn one_listener_serves_certificates_by_sni() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "ok")
+        .await
+        .unwrap();
+    let shop = TestCert::generate(&["shop.example.com"]).unwrap();
+    let tenants = TestCert::generate(&["*.tenants.example.com"]).unwrap();
+    let fallback = TestCert::generate(&["localhost"]).unwrap();
+    let path = |cert: &TestCert, key: bool| {
+        let path = if key {
+            cert.key_path()
+        } else {
+            cert.cert_path()
+        };
+        path.to_string_lossy().into_owned()
+    };
+    let handle = Proxy::builder()
+        .listener(
+            Listener::builder("edge", "127.0.0.1:0")
+                .tls(path(&fallback, false), path(&fallback, true))
+                .tls_certificate(&["shop.example.com"], path(&shop, false), path(&shop, true))
+                .tls_certificate(
+                    &["*.tenants.example.com"],
+                    path(&tenants, false),
+                    path(&tenants, true),
+                ),
+        )
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("*"))
+        .build()
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    let addr = handle.local_addr("edge").unwrap();
+
+    // Each client trusts only the certificate it expects.
+    let cases = [
+        (&shop, "shop.example.com"),
+        (&tenants, "acme.tenants.example.com"),
+        (&fallback, "localhost"),
+    ];
+    for (cert, server_name) in cases {
+        let client = TestClient::new(addr, cert)
+            .unwrap()
+            .server_name(server_name);
+        let response = client.get(server_name, "/").await.unwrap();
+        assert_eq!(response.status, StatusCode::OK, "{server_name}");
+    }
+    let unnamed = TestClient::new(addr, &fallback)
+        .unwrap()
+        .server_name("deep.sub.tenants.example.com");
+    assert!(
+        unnamed.get("example.com", "/").await.is_err(),
+        "names no certificate covers get the default one"
+    );
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn listeners_pick_up_renewed_certificate_files() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "ok")
+        .await
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    let install = |from: &TestCert| {
+        std::fs::copy(from.cert_path(), &cert).unwrap();
+        std::fs::copy(from.key_path(), &key).unwrap();
+    };
+    let original = TestCert::generate(&["localhost"]).unwrap();
+    let renewed = TestCert::generate(&["localhost"]).unwrap();
+    install(&original);
+    let handle = Proxy::builder()
+        .listener(
+            Listener::builder("edge", "127.0.0.1:0")
+                .tls(cert.to_string_lossy(), key.to_string_lossy()),
+        )
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("*"))
+        .build()
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    let addr = handle.local_addr("edge").unwrap();
+    async fn serves(addr: std::net::SocketAddr, cert: &TestCert) -> bool {
+        let client = TestClient::new(addr, cert).unwrap();
+        client.get("localhost", "/").await.is_ok()
+    }
+    assert!(serves(addr, &original).await);
+
+    // A broken certificate is not swapped in, even on request.
+    std::fs::write(&cert, "not a certificate").unwrap();
+    handle.reload_certificates();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(serves(addr, &original).await);
+
+    // Neither is a new key next to the old certificate, as when a renewal has
+    // written only the key so far; the pair is served once the cert follows.
+    std::fs::copy(original.cert_path(), &cert).unwrap();
+    std::fs::copy(renewed.key_path(), &key).unwrap();
+    handle.reload_certificates();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(serves(addr, &original).await);
+
+    std::fs::copy(renewed.cert_path(), &cert).unwrap();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while !serves(addr, &renewed).await {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "the renewed certificate was never served"
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(!serves(addr, &original).await);
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn mutual_tls_listeners_verify_client_certificates() {
+    let billing_upstream = MockUpstream::with_response(StatusCode::OK, "billing")
+        .await
+        .unwrap();
+    let fallback = MockUpstream::with_response(StatusCode::OK, "fallback")
+        .await
+        .unwrap();
+    let cert = TestCert::generate(&["localhost"]).unwrap();
+    let clients = TestCert::generate_ca("Jester Test Clients").unwrap();
+    let billing = clients.issue("billing", &["billing.internal"]).unwrap();
+    let other = clients.issue("other", &[]).unwrap();
+    let stranger = TestCert::generate_ca("Someone Else")
+        .unwrap()
+        .issue("billing", &[])
+        .unwrap();
+    let listener = |name: &str, auth: ClientAuth| {
+        Listener::builder(name, "127.0.0.1:0")
+            .tls(
+                cert.cert_path().to_string_lossy(),
+                cert.key_path().to_string_lossy(),
+            )
+            .tls_client_ca(clients.cert_path().to_string_lossy(), auth)
+    };
+    let handle = Proxy::builder()
+        .listener(listener("required", ClientAuth::Required))
+        .listener(listener("optional", ClientAuth::Optional))
+        .route(
+            Route::builder("billing", Upstream::single(billing_upstream.url())).matchers(
+                Matchers::builder()
+                    .host("*")
+                    .header("x-client-cert-subject", "CN=billing"),
+            ),
+        )
+        .route(Route::builder("fallback", Upstream::single(fallback.url())).host("*"))
+        .build()
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    let mut events = handle.tap().subscribe();
+    let spoofed = || {
+        Request::get("/")
+            .header(header::HOST, "localhost")
+            .header("x-client-cert-subject", "CN=billing")
+            .header("x-client-cert-san", "DNS:admin.internal")
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    };
+
+    let required = handle.local_addr("required").unwrap();
+    let client = TestClient::new(required, &cert).unwrap();
+    assert!(client.get("localhost", "/").await.is_err());
+    let foreign = client.clone().client_cert(&stranger).unwrap();
+    assert!(foreign.get("localhost", "/").await.is_err());
+
+    let response = client
+        .clone()
+        .client_cert(&billing)
+        .unwrap()
+        .get("localhost", "/")
+        .await
+        .unwrap();
+    assert_eq!(response.text(), "billing");
+    let seen = &billing_upstream.requests()[0].headers;
+    assert_eq!(seen["x-client-cert-subject"], "CN=billing");
+    assert_eq!(seen["x-client-cert-san"], "DNS:billing.internal");
+    let event = events.recv().await.unwrap();
+    assert_eq!(event.client_cert.as_deref(), Some("CN=billing"));
+
+    // What a client claims in the headers is replaced, not trusted.
+    let response = client
+        .clone()
+        .client_cert(&other)
+        .unwrap()
+        .send(spoofed())
+        .await
+        .unwrap();
+    assert_eq!(response.text(), "fallback");
+    let seen = &fallback.requests()[0].headers;
+    assert_eq!(seen["x-client-cert-subject"], "CN=other");
+    assert!(!seen.contains_key("x-client-cert-san"));
+
+    let optional = handle.local_addr("optional").unwrap();
+    let anonymous = TestClient::new(optional, &cert).unwrap();
+    let response = anonymous.send(spoofed()).await.unwrap();
+    assert_eq!(response.text(), "fallback");
+    let seen = &fallback.requests()[1].headers;
+    assert!(!seen.contains_key("x-client-cert-subject"));
+    assert!(!seen.contains_key("x-client-cert-san"));
+    let foreign = anonymous.client_cert(&stranger).unwrap();
+    assert!(foreign.get("localhost", "/").await.is_err());
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn tls_settings_limit_versions_and_cipher_suites() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "ok")
+        .await
+        .unwrap();
+    let cert = TestCert::generate(&["localhost"]).unwrap();
+    let listener = |name: &str| {
+        Listener::builder(name, "127.0.0.1:0").tls(
+            cert.cert_path().to_string_lossy(),
+            cert.key_path().to_string_lossy(),
+        )
+    };
+    let handle = Proxy::builder()
+        .listener(listener("modern").tls_versions(TlsVersion::Tls13, TlsVersion::Tls13))
+        .listener(listener("suites").tls_cipher_suites(&[
+            "TLS13_CHACHA20_POLY1305_SHA256",
+            "TLS13_AES_128_GCM_SHA256",
+            "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+        ]))
+        .listener(
+            listener("edge-h3")
+                .h3()
+                .tls_versions(TlsVersion::Tls13, TlsVersion::Tls13)
+                .tls_cipher_suites(&["TLS13_CHACHA20_POLY1305_SHA256", "TLS13_AES_128_GCM_SHA256"]),
+        )
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("*"))
+        .build()
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    let negotiated = |version: &str, suite: &str| Some((version.to_string(), suite.to_string()));
+
+    let modern = TestClient::new(handle.local_addr("modern").unwrap(), &cert).unwrap();
+    assert_eq!(
+        modern.negotiated().await.ok(),
+        negotiated("TLSv1_3", "TLS13_AES_256_GCM_SHA384")
+    );
+    assert!(modern
+        .clone()
+        .tls12_only()
+        .unwrap()
+        .negotiated()
+        .await
+        .is_err());
+
+    // The listener's order wins over the client's, which puts AES-256 first.
+    let suites = TestClient::new(handle.local_addr("suites").unwrap(), &cert).unwrap();
+    assert_eq!(
+        suites.negotiated().await.ok(),
+        negotiated("TLSv1_3", "TLS13_CHACHA20_POLY1305_SHA256")
+    );
+    let tls12 = suites.clone().tls12_only().unwrap();
+    assert_eq!(
+        tls12.negotiated().await.ok(),
+        negotiated("TLSv1_2", "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256")
+    );
+    assert_eq!(tls12.get("localhost", "/").await.unwrap().text(), "ok");
+
+    let h3 = Http3Client::new(handle.local_addr("edge-h3").unwrap(), &cert).unwrap();
+    assert_eq!(
+        h3.get("localhost", "/").await.unwrap().status,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn https_redirect_listeners_answer_with_https_urls() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let cert = TestCert::generate(&["localhost"]).unwrap();
+    let handle = Proxy::builder()
+        .listener(Listener::builder("tls", "127.0.0.1:0").tls(
+            cert.cert_path().to_string_lossy(),
+            cert.key_path().to_string_lossy(),
+        ))
+        .listener(
+            Listener::builder("redirect", "127.0.0.1:0")
+                .https_redirect()
+                .redirect_port(8443),
+        )
+        .route(Route::builder("app", Upstream::single("http://127.0.0.1:9")).host("example.com"))
+        .build()
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    let addr = handle.local_addr("redirect").unwrap();
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /docs?page=2 HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 301 Moved Permanently"),
+        "{response}"
+    );
+    assert!(
+        response
+            .to_ascii_lowercase()
+            .contains("location: https://example.com:8443/docs?page=2\r\n"),
+        "{response}"
+    );
+    let report = handle.startup_report();
+    assert!(report.degraded.is_empty(), "{:?}", report.degraded);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn plaintext_listeners_proxy_plain_http() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let upstream = MockUpstream::with_response(StatusCode::OK, "plain")
+        .await
+        .unwrap();
+    let handle = Proxy::builder()
+        .listener(Listener::builder("plain", "127.0.0.1:0"))
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .build()
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+
+    let mut stream = tokio::net::TcpStream::connect(handle.local_addr("plain").unwrap())
+        .await
+        .unwrap();
+    stream
+        .write_all(b"GET /app HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    assert!(response.ends_with("plain"), "{response}");
+    assert!(
+        !response.to_ascii_lowercase().contains("alt-svc"),
+        "{response}"
+    );
+    let request = upstream.requests().pop().unwrap();
+    assert_eq!(request.headers["x-forwarded-proto"], "http");
+    let report = handle.startup_report();
+    assert!(report.degraded.is_empty(), "{:?}", report.degraded);
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn health_check_paths_are_answered_at_the_listener_until_draining() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let cert = TestCert::generate(&["localhost"]).unwrap();
+    let handle = Proxy::builder()
+        .listener(
+            Listener::builder("tls", "127.0.0.1:0")
+                .tls(
+                    cert.cert_path().to_string_lossy(),
+                    cert.key_path().to_string_lossy(),
+                )
+                .health_check_paths(["/elb-status"]),
+        )
+        .listener(
+            Listener::builder("redirect", "127.0.0.1:0")
+                .https_redirect()
+                .health_check_paths(["/elb-status"]),
+        )
+        .route(Route::builder("app", Upstream::single("http://127.0.0.1:9")).host("example.com"))
+        .build()
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    let mut events = handle.tap().subscribe();
+
+    // No route matches the balancer's host, and nothing reaches the tap.
+    let client = TestClient::new(handle.local_addr("tls").unwrap(), &cert).unwrap();
+    let response = client.get("10.0.0.7", "/elb-status").await.unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "ok");
+    let response = client.get("10.0.0.7", "/other").await.unwrap();
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+    assert_eq!(events.recv().await.unwrap().path, "/other");
+
+    // Plain-HTTP listeners answer them too, instead of redirecting.
+    let mut stream = tokio::net::TcpStream::connect(handle.local_addr("redirect").unwrap())
+        .await
+        .unwrap();
+    let check = b"GET /elb-status HTTP/1.1\r\nHost: 10.0.0.7\r\n\r\n";
+    async fn read(stream: &mut tokio::net::TcpStream) -> String {
+        let mut buf = [0; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).into_owned()
+    }
+    stream.write_all(check).await.unwrap();
+    let response = read(&mut stream).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+
+    // Once draining, checks on connections still open fail.
+    handle.shutdown().await.unwrap();
+    stream.write_all(check).await.unwrap();
+    let response = read(&mut stream).await;
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable"),
+        "{response}"
+    );
+}
+
+#[tokio::test]
+async fn stream_listeners_relay_raw_bytes() {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    // Echoes everything after the PROXY protocol line, which it reports.
+    let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_addr = backend.local_addr().unwrap();
+    let echo = tokio::spawn(async move {
+        let (stream, _) = backend.accept().await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut read = BufReader::new(read);
+        let mut opening = String::new();
+        read.read_line(&mut opening).await.unwrap();
+        tokio::io::copy(&mut read, &mut write).await.unwrap();
+        write.shutdown().await.unwrap();
+        opening
+    });
+    let mut target = StreamTarget::new(backend_addr.to_string());
+    target.proxy_protocol = Some(ProxyProtocolVersion::V1);
+    // No routes: a config of stream listeners alone needs none.
+    let handle = Proxy::builder()
+        .listener(Listener::builder("db", "127.0.0.1:0").stream(target))
+        .build()
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    let addr = handle.local_addr("db").unwrap();
+
+    let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let client_addr = client.local_addr().unwrap();
+    client.write_all(b"\x00\x00\x00\x08ping").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut echoed = Vec::new();
+    client.read_to_end(&mut echoed).await.unwrap();
+    assert_eq!(echoed, b"\x00\x00\x00\x08ping");
+    assert_eq!(
+        echo.await.unwrap(),
+        format!(
+            "PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\n",
+            client_addr.port(),
+            addr.port()
+        )
+    );
+    // The connection counts against the listener until it closes.
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.stats().client_connections("db") > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn udp_listeners_keep_a_session_per_client() {
+    use tokio::net::UdpSocket;
+
+    // Answers each datagram with the port it came from, so sessions show.
+    let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let backend_addr = backend.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 512];
+        loop {
+            let (len, from) = backend.recv_from(&mut buf).await.unwrap();
+            let reply = format!("{}:{}", from.port(), String::from_utf8_lossy(&buf[..len]));
+            backend.send_to(reply.as_bytes(), from).await.unwrap();
+        }
+    });
+    let mut target = UdpTarget::new(backend_addr.to_string());
+    target.idle_timeout_secs = 1;
+    target.max_sessions = Some(2);
+    let handle = Proxy::builder()
+        .listener(Listener::builder("dns", "127.0.0.1:0").udp(target))
+        .build()
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    let addr = handle.local_addr("dns").unwrap();
+
+    let client = || async {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(addr).await.unwrap();
+        socket
+    };
+    // The port the backend saw the query from, or `None` without a reply.
+    async fn ask(socket: &UdpSocket, query: &str) -> Option<String> {
+        socket.send(query.as_bytes()).await.unwrap();
+        let mut buf = [0; 512];
+        let len = tokio::time::timeout(Duration::from_millis(500), socket.recv(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        let reply = String::from_utf8(buf[..len].to_vec()).unwrap();
+        let (port, answer) = reply.split_once(':').unwrap();
+        assert_eq!(answer, query);
+        Some(port.to_string())
+    }
+
+    let (first, second) = (client().await, client().await);
+    let first_session = ask(&first, "a").await.unwrap();
+    assert_eq!(ask(&first, "b").await.unwrap(), first_session);
+    let second_session = ask(&second, "c").await.unwrap();
+    assert_ne!(second_session, first_session);
+    assert_eq!(handle.stats().client_connections("dns"), 2);
+    // Past max_sessions, new clients' datagrams are dropped.
+    assert_eq!(ask(&client().await, "d").await, None);
+
+    // Quiet sessions expire, and the client's next datagram opens another.
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.stats().client_connections("dns") > 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_ne!(ask(&first, "e").await.unwrap(), first_session);
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn stream_listeners_pass_tls_through_by_server_name() {
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    // Answers with its name and the bytes it received.
+    let backend = |name: &'static str| async move {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(name.as_bytes()).await.unwrap();
+            received
+        });
+        (addr, served)
+    };
+    let (db_addr, db) = backend("db").await;
+    let (pg_addr, pg) = backend("pg").await;
+    let target = StreamTarget {
+        upstream: None,
+        ..StreamTarget::new("unused:1")
+    }
+    .sni(&["db.example.com"], db_addr.to_string())
+    .sni(&["*.pg.example.com"], pg_addr.to_string());
+    let handle = Proxy::builder()
+        .listener(Listener::builder("tls", "127.0.0.1:0").stream(target))
+        .build()
+        .unwrap()
+        .start()
+        .await
+        .unwrap();
+    let addr = handle.local_addr("tls").unwrap();
+
+    let config = Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth(),
+    );
+    let client_hello = |server_name: &str| {
+        let mut tls =
+            ClientConnection::new(config.clone(), server_name.try_into().unwrap()).unwrap();
+        let mut hello = Vec::new();
+        tls.write_tls(&mut hello).unwrap();
+        hello
+    };
+    let exchange = |hello: Vec<u8>| async move {
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client.write_all(&hello).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut answer = Vec::new();
+        // A connection jester closes without a match may end in a reset.
+        let _ = client.read_to_end(&mut answer).await;
+        answer
+    };
+
+    let hello = client_hello("db.example.com");
+    assert_eq!(exchange(hello.clone()).await, b"db");
+    assert_eq!(
+        db.await.unwrap(),
+        hello,
+        "the ClientHello arrives untouched"
+    );
+    let hello = client_hello("replica.pg.example.com");
+    assert_eq!(exchange(hello.clone()).await, b"pg");
+    assert_eq!(pg.await.unwrap(), hello);
+
+    // Without a fallback upstream, unclaimed names and plaintext are closed.
+    assert!(exchange(client_hello("other.example.com")).await.is_empty());
+    assert!(exchange(b"GET / HTTP/1.1\r\n\r\n".to_vec())
+        .await
+        .is_empty());
+
+    handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,1274 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use bytes::Bytes;
+use http::{header, request::Parts, Request, Response, StatusCode};
+use http_body_util::Full;
+use jester_core::config::{
+    Config, DebugRequests, Filter, HostHeader, Matchers, ProxyProtocolVersion, RetryOn,
+    RetryPolicy, Route, Shutdown, Streaming, TapOptions, TimeMatch, Upstream, UpstreamOverride,
+    UpstreamProtocol, UpstreamStrategy, UpstreamTarget, Via, WellKnown,
+};
+use jester_core::plugin::{AttemptOutcome, DynLayer, JesterPlugin, RetryAdvisor, RetryDecision};
+use jester_testkit::{MockUpstream, TestProxy};
+
+#[tokio::test]
+async fn forwards_to_upstream_and_publishes_access_event() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "hello")
+        .await
+        .unwrap();
+    let mut proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .start()
+        .await
+        .unwrap();
+
+    let response = proxy
+        .client()
+        .get("example.com", "/greet?name=jester")
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "hello");
+
+    let received = upstream.requests();
+    assert_eq!(received.len(), 1);
+    assert_eq!(
+        received[0].uri.path_and_query().unwrap(),
+        "/greet?name=jester"
+    );
+    assert_eq!(received[0].headers["x-forwarded-proto"], "https");
+    assert_eq!(
+        received[0].headers["x-forwarded-port"],
+        proxy.addr().port().to_string()
+    );
+
+    let event = proxy.next_event().await.unwrap();
+    assert_eq!(event.route.as_deref(), Some("app"));
+    assert_eq!(event.status, 200);
+    let timings = event.timings;
+    assert!(timings.tls_handshake.is_some() && timings.routing.is_some());
+    assert!(timings.filters().is_some() && timings.upstream_connect.is_some());
+    assert!(timings.upstream_ttfb.unwrap() <= event.duration);
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn route_labels_reach_access_events() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "paid")
+        .await
+        .unwrap();
+    let mut proxy = TestProxy::builder()
+        .route(
+            Route::builder("payments", Upstream::single(upstream.url()))
+                .host("pay.example.com")
+                .label("team", "payments")
+                .label("tier", "1"),
+        )
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .start()
+        .await
+        .unwrap();
+
+    proxy
+        .client()
+        .get("pay.example.com", "/charge")
+        .await
+        .unwrap();
+    let event = proxy.next_event().await.unwrap();
+    assert_eq!(event.labels["team"], "payments");
+    assert_eq!(event.labels["tier"], "1");
+    let json = serde_json::to_value(&event).unwrap();
+    assert_eq!(json["labels"]["team"], "payments");
+
+    proxy.client().get("example.com", "/").await.unwrap();
+    let event = proxy.next_event().await.unwrap();
+    assert!(event.labels.is_empty());
+    assert!(serde_json::to_value(&event)
+        .unwrap()
+        .get("labels")
+        .is_none());
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn timed_routes_match_only_while_their_schedule_is_active() {
+    let maintenance = MockUpstream::with_response(StatusCode::SERVICE_UNAVAILABLE, "maintenance")
+        .await
+        .unwrap();
+    let app = MockUpstream::with_response(StatusCode::OK, "app")
+        .await
+        .unwrap();
+    let timed = |name: &str, cron: &str| {
+        Route::builder(name, Upstream::single(maintenance.url())).matchers(
+            Matchers::builder().host("example.com").time(TimeMatch {
+                cron: vec![cron.into()],
+                ..Default::default()
+            }),
+        )
+    };
+    let proxy = TestProxy::builder()
+        // February 30th never comes.
+        .route(timed("never", "0 0 30 2 *"))
+        .route(Route::builder("app", Upstream::single(app.url())).host("example.com"))
+        .start()
+        .await
+        .unwrap();
+    let response = proxy.client().get("example.com", "/").await.unwrap();
+    assert_eq!(response.text(), "app");
+    proxy.shutdown().await.unwrap();
+
+    let proxy = TestProxy::builder()
+        .route(timed("always", "* * * * *"))
+        .route(Route::builder("app", Upstream::single(app.url())).host("example.com"))
+        .start()
+        .await
+        .unwrap();
+    let response = proxy.client().get("example.com", "/").await.unwrap();
+    assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.text(), "maintenance");
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn unmatched_host_returns_not_found() {
+    let upstream = MockUpstream::start().await.unwrap();
+    let mut proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .start()
+        .await
+        .unwrap();
+
+    let response = proxy.client().get("other.test", "/").await.unwrap();
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+    assert!(upstream.requests().is_empty());
+    assert_eq!(proxy.next_event().await.unwrap().route, None);
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn unreachable_upstream_returns_bad_gateway() {
+    // Reserve a port, then free it so nothing is listening there.
+    let closed = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let proxy = TestProxy::builder()
+        .route(
+            Route::builder("app", Upstream::single(format!("http://{closed}"))).host("example.com"),
+        )
+        .start()
+        .await
+        .unwrap();
+
+    let response = proxy.client().get("example.com", "/").await.unwrap();
+    assert_eq!(response.status, StatusCode::BAD_GATEWAY);
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn route_timeout_returns_gateway_timeout() {
+    // Accepts connections but never answers.
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let silent_addr = silent.local_addr().unwrap();
+    let _silent = tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((stream, _)) = silent.accept().await {
+            held.push(stream);
+        }
+    });
+    let mut proxy = TestProxy::builder()
+        .route(
+            Route::builder("slow", Upstream::single(format!("http://{silent_addr}")))
+                .host("example.com")
+                .filter(Filter::builtin("timeout").config(serde_json::json!({
+                    "request_secs": 1,
+                    "body": "backend too slow",
+                }))),
+        )
+        .start()
+        .await
+        .unwrap();
+
+    let response = proxy.client().get("example.com", "/").await.unwrap();
+    assert_eq!(response.status, StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(response.text(), "backend too slow");
+    assert_eq!(proxy.next_event().await.unwrap().status, 504);
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn upstream_connections_are_pooled_unless_disabled() {
+    let pooled = MockUpstream::start().await.unwrap();
+    let unpooled = MockUpstream::start().await.unwrap();
+    let proxy = TestProxy::builder()
+        .route(Route::builder("pooled", Upstream::single(pooled.url())).host("pooled.test"))
+        .route(
+            Route::builder(
+                "unpooled",
+                Upstream::single(unpooled.url()).keep_alive(false),
+            )
+            .host("unpooled.test"),
+        )
+        .start()
+        .await
+        .unwrap();
+
+    for host in ["pooled.test", "pooled.test", "unpooled.test"] {
+        let response = proxy.client().get(host, "/").await.unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+    }
+    assert_eq!(proxy.stats().upstream_connections(&pooled.url()), 1);
+    assert!(!pooled.requests()[1].headers.contains_key("connection"));
+    assert_eq!(unpooled.requests()[0].headers["connection"], "close");
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn proxy_protocol_upstreams_learn_the_client_address() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Answers every request, reporting what each connection opened with.
+    let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_addr = backend.local_addr().unwrap();
+    let (tx, mut openings) = tokio::sync::mpsc::unbounded_channel();
+    let _backend = tokio::spawn(async move {
+        while let Ok((mut stream, _)) = backend.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut read = Vec::new();
+                let mut buf = [0u8; 1024];
+                let mut reported = false;
+                while let Ok(n @ 1..) = stream.read(&mut buf).await {
+                    read.extend_from_slice(&buf[..n]);
+                    if !reported {
+                        if let Some(end) = read.windows(2).position(|w| w == b"\r\n") {
+                            let line = String::from_utf8_lossy(&read[..end]).into_owned();
+                            tx.send(line).unwrap();
+                            reported = true;
+                        }
+                    }
+                    if read.ends_with(b"\r\n\r\n") {
+                        read.clear();
+                        let response = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+                        stream.write_all(response).await.unwrap();
+                    }
+                }
+            });
+        }
+    });
+    let proxy = TestProxy::builder()
+        .route(
+            Route::builder(
+                "pg",
+                Upstream::single(format!("http://{backend_addr}"))
+                    .proxy_protocol(ProxyProtocolVersion::V1),
+            )
+            .host("example.com"),
+        )
+        .start()
+        .await
+        .unwrap();
+
+    for _ in 0..2 {
+        let response = proxy.client().get("example.com", "/").await.unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.text(), "ok");
+    }
+    // Each request got a connection of its own, announcing the client.
+    let port = proxy.addr().port();
+    for _ in 0..2 {
+        let opening = openings.recv().await.unwrap();
+        let fields: Vec<&str> = opening.split(' ').collect();
+        assert_eq!(
+            fields[..4],
+            ["PROXY", "TCP4", "127.0.0.1", "127.0.0.1"],
+            "{opening}"
+        );
+        assert_eq!(fields[5], port.to_string(), "{opening}");
+    }
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn host_header_policy_controls_upstream_host() {
+    let upstream = MockUpstream::start().await.unwrap();
+    let proxy = TestProxy::builder()
+        .route(Route::builder("default", Upstream::single(upstream.url())).host("default.test"))
+        .route(
+            Route::builder(
+                "preserve",
+                Upstream::single(upstream.url()).host_header(HostHeader::Preserve),
+            )
+            .host("preserve.test"),
+        )
+        .route(
+            Route::builder(
+                "custom",
+                Upstream::single(upstream.url())
+                    .host_header(HostHeader::Custom("backend.internal".into())),
+            )
+            .host("custom.test"),
+        )
+        .start()
+        .await
+        .unwrap();
+
+    for host in ["default.test", "preserve.test", "custom.test"] {
+        proxy.client().get(host, "/").await.unwrap();
+    }
+    let hosts = upstream
+        .requests()
+        .into_iter()
+        .map(|req| req.headers["host"].to_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        hosts,
+        vec![
+            upstream.addr().to_string(),
+            "preserve.test".to_string(),
+            "backend.internal".to_string(),
+        ]
+    );
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn debug_requests_get_server_timing() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "hello")
+        .await
+        .unwrap();
+    let config = Config {
+        debug: Some(DebugRequests {
+            header: "x-jester-debug".into(),
+            secret: "let-me-see".into(),
+        }),
+        ..Default::default()
+    };
+    let proxy = TestProxy::builder()
+        .config(config)
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .start()
+        .await
+        .unwrap();
+    let request = |secret: &str| {
+        Request::get("/")
+            .header(header::HOST, "example.com")
+            .header("x-jester-debug", secret)
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    };
+
+    let response = proxy.client().send(request("let-me-see")).await.unwrap();
+    assert_eq!(response.text(), "hello");
+    let timing = response.headers["server-timing"].to_str().unwrap();
+    for phase in [
+        "tls;dur=",
+        "queue;dur=",
+        "connect;dur=",
+        "ttfb;dur=",
+        "body;dur=",
+        "total;dur=",
+    ] {
+        assert!(timing.contains(phase), "{phase} missing from {timing}");
+    }
+
+    let response = proxy.client().send(request("guess")).await.unwrap();
+    assert!(!response.headers.contains_key("server-timing"));
+    assert!(upstream
+        .requests()
+        .iter()
+        .all(|request| !request.headers.contains_key("x-jester-debug")));
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn trusted_clients_may_override_the_upstream() {
+    let routed = MockUpstream::with_response(StatusCode::OK, "routed")
+        .await
+        .unwrap();
+    let canary = MockUpstream::with_response(StatusCode::OK, "canary")
+        .await
+        .unwrap();
+    let config = |trusted: &str| Config {
+        upstream_override: Some(UpstreamOverride {
+            header: "x-jester-upstream".into(),
+            trusted: vec![trusted.into()],
+        }),
+        ..Default::default()
+    };
+    let request = |target: &str| {
+        Request::get("/")
+            .header(header::HOST, "example.com")
+            .header("x-jester-upstream", target)
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    };
+
+    let proxy = TestProxy::builder()
+        .config(config("127.0.0.0/8"))
+        .route(Route::builder("app", Upstream::single(routed.url())).host("example.com"))
+        .start()
+        .await
+        .unwrap();
+    let response = proxy.client().send(request(&canary.url())).await.unwrap();
+    assert_eq!(response.text(), "canary");
+    let response = proxy.client().send(request("not a uri")).await.unwrap();
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    assert!(canary
+        .requests()
+        .iter()
+        .all(|request| !request.headers.contains_key("x-jester-upstream")));
+    proxy.shutdown().await.unwrap();
+
+    let proxy = TestProxy::builder()
+        .config(config("192.0.2.0/24"))
+        .route(Route::builder("app", Upstream::single(routed.url())).host("example.com"))
+        .start()
+        .await
+        .unwrap();
+    let response = proxy.client().send(request(&canary.url())).await.unwrap();
+    assert_eq!(response.text(), "routed");
+    assert!(!routed.requests()[0]
+        .headers
+        .contains_key("x-jester-upstream"));
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn via_headers_name_the_node_unless_suppressed() {
+    let upstream = MockUpstream::start().await.unwrap();
+    let proxy = TestProxy::builder()
+        .config(Config {
+            via: Via {
+                enabled: true,
+                node: "edge-1".into(),
+            },
+            ..Default::default()
+        })
+        .route(Route::builder("public", Upstream::single(upstream.url())).host("public.test"))
+        .route(
+            Route::builder("private", Upstream::single(upstream.url()))
+                .host("private.test")
+                .suppress_via(true),
+        )
+        .start()
+        .await
+        .unwrap();
+
+    let expected = format!("1.1 edge-1 (jester/{})", jester_core::version());
+    let response = proxy.client().get("public.test", "/").await.unwrap();
+    assert_eq!(response.headers[header::VIA], expected.as_str());
+    let response = proxy.client().get("private.test", "/").await.unwrap();
+    assert!(!response.headers.contains_key(header::VIA));
+
+    let received = upstream.requests();
+    assert_eq!(received[0].headers[header::VIA], expected.as_str());
+    assert!(!received[1].headers.contains_key(header::VIA));
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn tap_captures_bodies_up_to_their_limits() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "a response longer than the limit")
+        .await
+        .unwrap();
+    let proxy = TestProxy::builder()
+        .config(Config {
+            tap: TapOptions {
+                capture_bodies: true,
+                max_request_body_bytes: 1024,
+                max_response_body_bytes: 10,
+            },
+            ..Default::default()
+        })
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .start()
+        .await
+        .unwrap();
+    let mut captures = proxy.tap().subscribe_captures();
+
+    let request = Request::post("/submit")
+        .header(header::HOST, "example.com")
+        .body(Full::new(Bytes::from_static(b"name=jester")))
+        .unwrap();
+    let response = proxy.client().send(request).await.unwrap();
+    assert_eq!(response.text(), "a response longer than the limit");
+
+    let capture = tokio::time::timeout(std::time::Duration::from_secs(5), captures.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(capture.event.path, "/submit");
+    assert_eq!(capture.request_body.data, "name=jester");
+    assert!(!capture.request_body.truncated);
+    assert_eq!(capture.response_body.size, 32);
+    assert_eq!(capture.response_body.data, "a response");
+    assert!(capture.response_body.truncated);
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn hash_upstreams_keep_keys_on_one_target() {
+    let a = MockUpstream::with_response(StatusCode::OK, "a")
+        .await
+        .unwrap();
+    let b = MockUpstream::with_response(StatusCode::OK, "b")
+        .await
+        .unwrap();
+    let proxy = TestProxy::builder()
+        .route(
+            Route::builder("app", Upstream::hash([a.url(), b.url()], "header:x-user"))
+                .host("example.com"),
+        )
+        .start()
+        .await
+        .unwrap();
+    let request = |user: &str| {
+        Request::get("/")
+            .header(header::HOST, "example.com")
+            .header("x-user", user)
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    };
+
+    let mut served = std::collections::HashSet::new();
+    for user in 0..20 {
+        let user = format!("user-{user}");
+        let first = proxy.client().send(request(&user)).await.unwrap().text();
+        let second = proxy.client().send(request(&user)).await.unwrap().text();
+        assert_eq!(first, second, "{user} moved between targets");
+        served.insert(first);
+    }
+    assert_eq!(served.len(), 2);
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn round_robin_upstreams_follow_target_weights() {
+    let a = MockUpstream::with_response(StatusCode::OK, "a")
+        .await
+        .unwrap();
+    let b = MockUpstream::with_response(StatusCode::OK, "b")
+        .await
+        .unwrap();
+    let proxy = TestProxy::builder()
+        .route(
+            Route::builder(
+                "app",
+                Upstream::round_robin([
+                    UpstreamTarget::from(a.url()),
+                    UpstreamTarget::weighted(b.url(), 3),
+                ]),
+            )
+            .host("example.com"),
+        )
+        .start()
+        .await
+        .unwrap();
+
+    let mut served = String::new();
+    for _ in 0..8 {
+        let request = Request::get("/")
+            .header(header::HOST, "example.com")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        served.push_str(&proxy.client().send(request).await.unwrap().text());
+    }
+    assert_eq!(served, "babbbabb");
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn well_known_files_are_answered_before_routing() {
+    let upstream = MockUpstream::start().await.unwrap();
+    let proxy = TestProxy::builder()
+        .config(Config {
+            well_known: vec![
+                WellKnown {
+                    hosts: vec!["staging.test".into()],
+                    robots_txt: Some("User-agent: *\nDisallow: /\n".into()),
+                    ..Default::default()
+                },
+                WellKnown {
+                    robots_txt: Some("User-agent: *\nAllow: /\n".into()),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        })
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("*"))
+        .start()
+        .await
+        .unwrap();
+
+    let response = proxy
+        .client()
+        .get("staging.test", "/robots.txt")
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.text().contains("Disallow: /"));
+    let response = proxy.client().get("www.test", "/robots.txt").await.unwrap();
+    assert!(response.text().contains("Allow: /"));
+    // Nothing is configured for security.txt, so the route answers it.
+    let response = proxy
+        .client()
+        .get("www.test", "/.well-known/security.txt")
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+
+    let received = upstream.requests();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].uri.path(), "/.well-known/security.txt");
+    proxy.shutdown().await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket_upstreams_receive_proxied_requests() {
+    use hyper::{server::conn::http1, service::service_fn};
+    use hyper_util::rt::TokioIo;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("app.sock");
+    let listener = tokio::net::UnixListener::bind(&path).unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let service = service_fn(|req: Request<hyper::body::Incoming>| async move {
+                let host = req.headers()[header::HOST].to_str().unwrap().to_string();
+                let body = format!("{} via {host}", req.uri());
+                Ok::<_, hyper::Error>(http::Response::new(Full::new(Bytes::from(body))))
+            });
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+        }
+    });
+
+    let target = format!("unix://{}", path.display());
+    let mut proxy = TestProxy::builder()
+        .route(Route::builder("sidecar", Upstream::single(target)).host("example.com"))
+        .start()
+        .await
+        .unwrap();
+    let response = proxy
+        .client()
+        .get("example.com", "/health?deep=1")
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "/health?deep=1 via localhost");
+    let event = proxy.next_event().await.unwrap();
+    assert_eq!(event.route.as_deref(), Some("sidecar"));
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn srv_upstreams_send_traffic_to_discovered_targets() {
+    let upstream = MockUpstream::with_response(StatusCode::OK, "discovered")
+        .await
+        .unwrap();
+    let port = upstream.addr().port();
+    // Answers every query with one SRV record pointing at the mock upstream.
+    let dns = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let resolver = dns.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 512];
+        while let Ok((len, peer)) = dns.recv_from(&mut buf).await {
+            let query = &buf[..len];
+            let mut response = query[..2].to_vec();
+            response.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+            response.extend_from_slice(&query[12..]);
+            let target = b"\x03127\x010\x010\x011\x00";
+            response.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60]);
+            response.extend_from_slice(&(6 + target.len() as u16).to_be_bytes());
+            response.extend_from_slice(&[0, 1, 0, 1]);
+            response.extend_from_slice(&port.to_be_bytes());
+            response.extend_from_slice(target);
+            dns.send_to(&response, peer).await.unwrap();
+        }
+    });
+
+    let srv = UpstreamStrategy::Srv {
+        name: "_http._tcp.app.example.com".into(),
+        scheme: "http".into(),
+        resolvers: vec![resolver.to_string()],
+        refresh_secs: 60,
+    };
+    let proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::from(srv)).host("example.com"))
+        .start()
+        .await
+        .unwrap();
+    // The first lookup runs in the background; until it lands the route has
+    // no targets.
+    let mut response = proxy.client().get("example.com", "/").await.unwrap();
+    for _ in 0..50 {
+        if response.status != StatusCode::SERVICE_UNAVAILABLE {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        response = proxy.client().get("example.com", "/").await.unwrap();
+    }
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "discovered");
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn consul_upstreams_follow_catalog_changes() {
+    let first = MockUpstream::with_response(StatusCode::OK, "first")
+        .await
+        .unwrap();
+    let second = MockUpstream::with_response(StatusCode::OK, "second")
+        .await
+        .unwrap();
+    // The fake agent answers with whichever instance is registered now, and
+    // bumps its index on every change.
+    let registered = Arc::new(std::sync::Mutex::new((1u64, first.addr().port())));
+    let catalog = registered.clone();
+    let consul = MockUpstream::with_handler(move |_| {
+        let (index, port) = *catalog.lock().unwrap();
+        let body = format!(
+            r#"[{{"Node": {{"Address": "127.0.0.1"}}, "Service": {{"Address": "", "Port": {port}}}}}]"#
+        );
+        let mut response = Response::new(Full::new(Bytes::from(body)));
+        response
+            .headers_mut()
+            .insert("x-consul-index", index.to_string().parse().unwrap());
+        response
+    })
+    .await
+    .unwrap();
+
+    let strategy = UpstreamStrategy::Consul {
+        service: "app".into(),
+        address: consul.url(),
+        scheme: "http".into(),
+        tag: Some("v2".into()),
+        datacenter: None,
+        token: Some("agent-token".into()),
+        wait_secs: 5,
+    };
+    let proxy = TestProxy::builder()
+        .route(Route::builder("app", Upstream::from(strategy)).host("example.com"))
+        .start()
+        .await
+        .unwrap();
+    let body_within = |expected: &'static str| {
+        let client = proxy.client();
+        async move {
+            for _ in 0..150 {
+                let response = client.get("example.com", "/").await.unwrap();
+                if response.status == StatusCode::OK && response.text() == expected {
+                    return true;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            false
+        }
+    };
+    assert!(body_within("first").await);
+
+    *registered.lock().unwrap() = (2, second.addr().port());
+    assert!(body_within("second").await);
+
+    let query = &consul.requests()[0];
+    assert_eq!(query.uri.path(), "/v1/health/service/app");
+    assert!(query.uri.query().unwrap().contains("passing=true"));
+    assert!(query.uri.query().unwrap().contains("tag=v2"));
+    assert_eq!(query.headers["x-consul-token"], "agent-token");
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn retries_replay_idempotent_requests_until_the_upstream_recovers() {
+    // Every third request succeeds.
+    let served = Arc::new(AtomicUsize::new(0));
+    let upstream = MockUpstream::with_handler(move |_| {
+        let status = match served.fetch_add(1, Ordering::SeqCst) % 3 {
+            2 => StatusCode::OK,
+            _ => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        let mut response = Response::new(Full::new(Bytes::from_static(b"")));
+        *response.status_mut() = status;
+        response
+    })
+    .await
+    .unwrap();
+    let retry = RetryPolicy {
+        retry_on: vec![RetryOn::ServerError],
+        backoff_base_ms: 1,
+        backoff_max_ms: 5,
+        ..Default::default()
+    };
+    let proxy = TestProxy::builder()
+        .route(
+            Route::builder("app", Upstream::single(upstream.url()))
+                .host("example.com")
+                .retry(retry),
+        )
+        .start()
+        .await
+        .unwrap();
+    let request = |method: &str| {
+        Request::builder()
+            .method(method)
+            .uri("/items")
+            .header(header::HOST, "example.com")
+            .body(Full::new(Bytes::from_static(b"payload")))
+            .unwrap()
+    };
+
+    let response = proxy.client().send(request("PUT")).await.unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 3);
+    assert!(requests.iter().all(|request| request.body == "payload"));
+
+    // POST may have been acted on, so its 503 goes back to the client.
+    let response = proxy.client().send(request("POST")).await.unwrap();
+    assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(upstream.requests().len(), 4);
+}
+
+/// Retries failed attempts against `fallback`, for requests carrying an
+/// idempotency key only.
+struct IdempotentFailover;
+
+impl JesterPlugin for IdempotentFailover {
+    fn name(&self) -> &'static str {
+        "idempotent-failover"
+    }
+
+    fn version(&self) -> semver::Version {
+        semver::Version::new(0, 1, 0)
+    }
+
+    fn layer(&self, _cfg: serde_json::Value) -> anyhow::Result<DynLayer> {
+        anyhow::bail!("idempotent-failover only advises on retries")
+    }
+
+    fn capabilities(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn retry_advisor(
+        &self,
+        cfg: serde_json::Value,
+    ) -> anyhow::Result<Option<Arc<dyn RetryAdvisor>>> {
+        let fallback = cfg["fallback"].as_str().unwrap_or_default().parse()?;
+        Ok(Some(Arc::new(Failover { fallback })))
+    }
+}
+
+struct Failover {
+    fallback: http::Uri,
+}
+
+impl RetryAdvisor for Failover {
+    fn decide(
+        &self,
+        request: &mut Parts,
+        outcome: AttemptOutcome<'_>,
+        _attempt: u32,
+    ) -> RetryDecision {
+        let failed = match outcome {
+            AttemptOutcome::Response { status, .. } => status.is_server_error(),
+            AttemptOutcome::Failed(_) => true,
+        };
+        if !failed || !request.headers.contains_key("idempotency-key") {
+            return RetryDecision::Stop;
+        }
+        let mut target = self.fallback.clone().into_parts();
+        target.path_and_query = request.uri.path_and_query().cloned();
+        request.uri = http::Uri::from_parts(target).unwrap();
+        RetryDecision::Retry
+    }
+}
+
+#[tokio::test]
+async fn retry_advisors_fail_over_requests_they_deem_safe() {
+    let primary = MockUpstream::with_response(StatusCode::SERVICE_UNAVAILABLE, "down")
+        .await
+        .unwrap();
+    let fallback = MockUpstream::with_response(StatusCode::OK, "fallback")
+        .await
+        .unwrap();
+    let retry = RetryPolicy {
+        backoff_base_ms: 1,
+        backoff_max_ms: 5,
+        advisor: Some("idempotent-failover".into()),
+        advisor_config: serde_json::json!({ "fallback": fallback.url() }),
+        ..Default::default()
+    };
+    let proxy = TestProxy::builder()
+        .plugin(Arc::new(IdempotentFailover))
+        .route(
+            Route::builder("app", Upstream::single(primary.url()))
+                .host("example.com")
+                .retry(retry),
+        )
+        .start()
+        .await
+        .unwrap();
+    let post = |key: Option<&str>| {
+        let mut request = Request::post("/orders").header(header::HOST, "example.com");
+        if let Some(key) = key {
+            request = request.header("idempotency-key", key);
+        }
+        request
+            .body(Full::new(Bytes::from_static(b"order")))
+            .unwrap()
+    };
+
+    let response = proxy.client().send(post(Some("order-1"))).await.unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "fallback");
+    let retried = fallback.requests();
+    assert_eq!(retried.len(), 1);
+    assert_eq!(retried[0].uri.path(), "/orders");
+    assert_eq!(retried[0].body, "order");
+
+    // Without a key the POST goes back to the client after one try.
+    let response = proxy.client().send(post(None)).await.unwrap();
+    assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(primary.requests().len(), 2);
+    assert_eq!(fallback.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn h2c_upstreams_receive_http2_with_te_trailers() {
+    let upstream =
+        MockUpstream::h2c_with_handler(|_| Response::new(Full::new(Bytes::from_static(b"ok"))))
+            .await
+            .unwrap();
+    let proxy = TestProxy::builder()
+        .route(
+            Route::builder(
+                "grpc",
+                Upstream::single(upstream.url()).protocol(UpstreamProtocol::H2c),
+            )
+            .host("example.com"),
+        )
+        .start()
+        .await
+        .unwrap();
+
+    let request = Request::get("/helloworld.Greeter/SayHello")
+        .header(header::HOST, "example.com")
+        .header(header::TE, "trailers")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = proxy.client().send(request).await.unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "ok");
+    let requests = upstream.requests();
+    assert_eq!(requests[0].version, http::Version::HTTP_2);
+    assert_eq!(requests[0].headers[header::TE], "trailers");
+}
+
+#[tokio::test]
+async fn grpc_calls_keep_their_trailers_and_report_grpc_status() {
+    let upstream = MockUpstream::h2c_with_trailers(|_| {
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, "application/grpc")
+            .body(Full::new(Bytes::from_static(b"\0\0\0\0\0")))
+            .unwrap();
+        let mut trailers = http::HeaderMap::new();
+        trailers.insert("grpc-status", "5".parse().unwrap());
+        trailers.insert("grpc-message", "no such greeter".parse().unwrap());
+        (response, trailers)
+    })
+    .await
+    .unwrap();
+    let closed = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut proxy = TestProxy::builder()
+        .route(
+            Route::builder(
+                "grpc",
+                Upstream::single(upstream.url()).protocol(UpstreamProtocol::H2c),
+            )
+            .host("example.com"),
+        )
+        .route(
+            Route::builder(
+                "gone",
+                Upstream::single(format!("http://{closed}")).protocol(UpstreamProtocol::H2c),
+            )
+            .host("gone.example.com"),
+        )
+        .start()
+        .await
+        .unwrap();
+
+    let call = |host: &str| {
+        Request::post("/helloworld.Greeter/SayHello")
+            .header(header::HOST, host)
+            .header(header::CONTENT_TYPE, "application/grpc")
+            .header(header::TE, "trailers")
+            .body(Full::new(Bytes::from_static(b"\0\0\0\0\0")))
+            .unwrap()
+    };
+    let response = proxy
+        .client()
+        .http2()
+        .send(call("example.com"))
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    let trailers = response.trailers.expect("trailers");
+    assert_eq!(trailers["grpc-status"], "5");
+    assert_eq!(trailers["grpc-message"], "no such greeter");
+    let event = proxy.next_event().await.unwrap();
+    assert_eq!(event.route.as_deref(), Some("grpc"));
+    assert_eq!(event.grpc_status, Some(5));
+
+    let response = proxy
+        .client()
+        .http2()
+        .send(call("gone.example.com"))
+        .await
+        .unwrap();
+    assert_eq!(response.status, StatusCode::BAD_GATEWAY);
+    assert_eq!(proxy.next_event().await.unwrap().grpc_status, Some(14));
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn draining_proxies_end_event_streams_with_a_retry_event() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Sends one event, then keeps the stream open.
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = upstream.accept().await.unwrap();
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        stream
+            .write_all(
+                b"HTTP/1.1 200 OK\r\n\
+                  content-type: text/event-stream\r\n\
+                  transfer-encoding: chunked\r\n\r\n\
+                  e\r\ndata: update\n\n\r\n",
+            )
+            .await
+            .unwrap();
+        std::future::pending::<()>().await;
+    });
+    let proxy = TestProxy::builder()
+        .config(
+            Config::builder()
+                .shutdown(Shutdown {
+                    stream_notice_secs: 30,
+                    sse_retry_ms: 3000,
+                })
+                .build_unchecked(),
+        )
+        .route(
+            Route::builder(
+                "events",
+                Upstream::single(format!("http://{upstream_addr}")),
+            )
+            .host("example.com"),
+        )
+        .start()
+        .await
+        .unwrap();
+
+    let client = proxy.client();
+    let events = tokio::spawn(async move { client.get("example.com", "/events").await });
+    // Give the first event time to arrive before draining.
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    tokio::time::timeout(std::time::Duration::from_secs(5), proxy.shutdown())
+        .await
+        .expect("the stream ends well before the notice period")
+        .unwrap();
+    let response = events.await.unwrap().unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.text(), "data: update\n\nretry: 3000\n\n");
+}
+
+/// A chunked SSE upstream that sends one event, then the next once
+/// `release` fires, then ends.
+async fn held_event_stream(release: tokio::sync::oneshot::Receiver<()>) -> std::net::SocketAddr {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = upstream.accept().await.unwrap();
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        stream
+            .write_all(
+                b"HTTP/1.1 200 OK\r\n\
+                  content-type: text/event-stream\r\n\
+                  transfer-encoding: chunked\r\n\r\n\
+                  9\r\ndata: a\n\n\r\n",
+            )
+            .await
+            .unwrap();
+        if release.await.is_ok() {
+            stream
+                .write_all(b"9\r\ndata: b\n\n\r\n0\r\n\r\n")
+                .await
+                .unwrap();
+        }
+        std::future::pending::<()>().await;
+    });
+    addr
+}
+
+#[tokio::test]
+async fn event_stream_chunks_reach_the_client_as_they_arrive() {
+    use http_body_util::BodyExt;
+
+    let (release, held) = tokio::sync::oneshot::channel();
+    let upstream_addr = held_event_stream(held).await;
+    let proxy = TestProxy::builder()
+        .route(
+            Route::builder(
+                "events",
+                Upstream::single(format!("http://{upstream_addr}")),
+            )
+            .host("example.com")
+            .filter(Filter::builtin("compression")),
+        )
+        .start()
+        .await
+        .unwrap();
+
+    let request = Request::get("/events")
+        .header(header::HOST, "example.com")
+        .header(header::ACCEPT_ENCODING, "gzip")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = proxy.client().stream(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    let mut body = response.into_body();
+    let mut next_event = async || {
+        tokio::time::timeout(std::time::Duration::from_secs(2), body.frame())
+            .await
+            .expect("the event arrives without waiting for the rest of the body")
+            .unwrap()
+            .unwrap()
+            .into_data()
+            .unwrap()
+    };
+    // The upstream holds the second event until the first has been seen.
+    assert_eq!(next_event().await, "data: a\n\n");
+    release.send(()).unwrap();
+    assert_eq!(next_event().await, "data: b\n\n");
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn streaming_routes_cut_bodies_that_go_idle() {
+    use http_body_util::BodyExt;
+
+    let (_release, held) = tokio::sync::oneshot::channel();
+    let upstream_addr = held_event_stream(held).await;
+    let proxy = TestProxy::builder()
+        .route(
+            Route::builder(
+                "events",
+                Upstream::single(format!("http://{upstream_addr}")),
+            )
+            .host("example.com")
+            .streaming(Streaming {
+                flush_interval_ms: None,
+                idle_timeout_secs: Some(1),
+            }),
+        )
+        .start()
+        .await
+        .unwrap();
+
+    let request = Request::get("/events")
+        .header(header::HOST, "example.com")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let response = proxy.client().stream(request).await.unwrap();
+    let mut body = response.into_body();
+    let first = body.frame().await.unwrap().unwrap();
+    assert_eq!(first.into_data().unwrap(), "data: a\n\n");
+    let rest = tokio::time::timeout(std::time::Duration::from_secs(5), body.collect())
+        .await
+        .expect("the idle body is cut");
+    assert!(rest.is_err());
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn filters_share_values_through_the_request_context() {
+    use jester_core::{context::RequestContext, plugin::HttpRequest};
+    use tower::{layer::layer_fn, ServiceExt};
+
+    fn context(req: &HttpRequest) -> &RequestContext {
+        req.extensions().get::<RequestContext>().unwrap()
+    }
+
+    let upstream = MockUpstream::with_response(StatusCode::OK, "ok")
+        .await
+        .unwrap();
+    let mut proxy = TestProxy::builder()
+        // Stands in for an auth filter resolving the user...
+        .layer(layer_fn(|inner: jester_core::plugin::JesterService| {
+            jester_core::plugin::JesterService::new(inner.map_request(|req: HttpRequest| {
+                context(&req).set_value("auth.user", serde_json::json!({"id": "alice"}));
+                req
+            }))
+        }))
+        // ...and a later one keying on it.
+        .layer(layer_fn(|inner: jester_core::plugin::JesterService| {
+            jester_core::plugin::JesterService::new(inner.map_request(|mut req: HttpRequest| {
+                let user = context(&req).value("auth.user").unwrap();
+                let id = user["id"].as_str().unwrap().parse().unwrap();
+                req.headers_mut().insert("x-user", id);
+                req
+            }))
+        }))
+        .route(Route::builder("app", Upstream::single(upstream.url())).host("example.com"))
+        .start()
+        .await
+        .unwrap();
+
+    let response = proxy.client().get("example.com", "/").await.unwrap();
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(upstream.requests()[0].headers["x-user"], "alice");
+    let event = proxy.next_event().await.unwrap();
+    assert_eq!(
+        event.context["auth.user"],
+        serde_json::json!({"id": "alice"})
+    );
+
+    proxy.shutdown().await.unwrap();
+}
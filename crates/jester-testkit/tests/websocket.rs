@@ -0,0 +1,263 @@
+use std::{net::SocketAddr, time::Duration};
+
+use bytes::Bytes;
+use http::{header, Request, StatusCode};
+use http_body_util::Full;
+use jester_core::config::{Config, Route, Shutdown, Upstream, WebsocketLimits};
+use jester_testkit::TestProxy;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Accepts WebSocket handshakes and echoes every byte back afterwards.
+async fn echo_upstream() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut byte = [0u8; 1];
+                while !head.ends_with(b"\r\n\r\n") {
+                    if stream.read(&mut byte).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    head.push(byte[0]);
+                }
+                stream
+                    .write_all(
+                        b"HTTP/1.1 101 Switching Protocols\r\n\
+                          connection: upgrade\r\n\
+                          upgrade: websocket\r\n\r\n",
+                    )
+                    .await
+                    .unwrap();
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await.ok();
+            });
+        }
+    });
+    addr
+}
+
+/// Accepts WebSocket handshakes, then answers pings with pongs when `answer`
+/// is set and swallows every other frame.
+async fn ping_upstream(answer: bool) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut byte = [0u8; 1];
+                while !head.ends_with(b"\r\n\r\n") {
+                    if stream.read(&mut byte).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    head.push(byte[0]);
+                }
+                stream
+                    .write_all(
+                        b"HTTP/1.1 101 Switching Protocols\r\n\
+                          connection: upgrade\r\n\
+                          upgrade: websocket\r\n\r\n",
+                    )
+                    .await
+                    .unwrap();
+                while let Ok((opcode, _)) = read_frame(&mut stream).await {
+                    if opcode == 0x9 && answer {
+                        stream.write_all(&[0x8A, 0x00]).await.unwrap();
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Reads one short frame, unmasking it if needed.
+async fn read_frame(stream: &mut (impl AsyncReadExt + Unpin)) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await?;
+    let mut mask = [0u8; 4];
+    if head[1] & 0x80 != 0 {
+        stream.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0; usize::from(head[1] & 0x7F)];
+    stream.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((head[0] & 0x0F, payload))
+}
+
+/// Reads frames until the next one that is not a pong.
+async fn next_non_pong(stream: &mut (impl AsyncReadExt + Unpin)) -> std::io::Result<(u8, Vec<u8>)> {
+    loop {
+        let frame = read_frame(stream).await?;
+        if frame.0 != 0xA {
+            return Ok(frame);
+        }
+    }
+}
+
+fn upgrade_request() -> Request<Full<Bytes>> {
+    Request::get("/socket")
+        .header(header::HOST, "ws.test")
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header("sec-websocket-version", "13")
+        .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .body(Full::new(Bytes::new()))
+        .unwrap()
+}
+
+/// Masked client text frame.
+fn text_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x81, 0x80 | payload.len() as u8, 0, 0, 0, 0];
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[tokio::test]
+async fn websocket_traffic_is_relayed_and_limited() {
+    let upstream = echo_upstream().await;
+    let proxy = TestProxy::builder()
+        .route(
+            Route::builder("ws", Upstream::single(format!("http://{upstream}")))
+                .host("ws.test")
+                .websocket(WebsocketLimits {
+                    max_frame_bytes: Some(8),
+                    max_connections_per_client: Some(1),
+                    ..Default::default()
+                }),
+        )
+        .start()
+        .await
+        .unwrap();
+
+    let (status, socket) = proxy.client().upgrade(upgrade_request()).await.unwrap();
+    assert_eq!(status, StatusCode::SWITCHING_PROTOCOLS);
+    let mut socket = socket.unwrap();
+
+    let (second, _) = proxy.client().upgrade(upgrade_request()).await.unwrap();
+    assert_eq!(second, StatusCode::TOO_MANY_REQUESTS);
+
+    let frame = text_frame(b"hello");
+    socket.write_all(&frame).await.unwrap();
+    let mut echoed = vec![0; frame.len()];
+    socket.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(echoed, frame);
+    assert_eq!(proxy.stats().websockets("ws"), 1);
+
+    socket.write_all(&text_frame(b"too long!")).await.unwrap();
+    let mut close = [0u8; 4];
+    socket.read_exact(&mut close).await.unwrap();
+    assert_eq!(close, [0x88, 0x02, 0x03, 0xF1]);
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn draining_proxies_send_websocket_clients_going_away() {
+    let upstream = echo_upstream().await;
+    let proxy = TestProxy::builder()
+        .config(
+            Config::builder()
+                .shutdown(Shutdown {
+                    stream_notice_secs: 30,
+                    ..Default::default()
+                })
+                .build_unchecked(),
+        )
+        .route(Route::builder("ws", Upstream::single(format!("http://{upstream}"))).host("ws.test"))
+        .start()
+        .await
+        .unwrap();
+
+    let (_, socket) = proxy.client().upgrade(upgrade_request()).await.unwrap();
+    let mut socket = socket.unwrap();
+    let frame = text_frame(b"hello");
+    socket.write_all(&frame).await.unwrap();
+    let mut echoed = vec![0; frame.len()];
+    socket.read_exact(&mut echoed).await.unwrap();
+
+    let shutdown = tokio::spawn(proxy.shutdown());
+    let mut close = [0u8; 4];
+    socket.read_exact(&mut close).await.unwrap();
+    assert_eq!(close, [0x88, 0x02, 0x03, 0xE9]);
+    // Shutdown waits for the client to go, not for the whole notice period.
+    assert!(!shutdown.is_finished());
+    drop(socket);
+    tokio::time::timeout(Duration::from_secs(5), shutdown)
+        .await
+        .expect("shutdown waits only for open streams")
+        .unwrap()
+        .unwrap();
+}
+
+fn keepalive_route(upstream: SocketAddr) -> jester_core::config::RouteBuilder {
+    Route::builder("ws", Upstream::single(format!("http://{upstream}")))
+        .host("ws.test")
+        .websocket(WebsocketLimits {
+            ping_interval_secs: Some(1),
+            pong_timeout_secs: Some(1),
+            ..Default::default()
+        })
+}
+
+#[tokio::test]
+async fn websocket_clients_that_stop_answering_pings_are_closed() {
+    let upstream = ping_upstream(true).await;
+    let proxy = TestProxy::builder()
+        .route(keepalive_route(upstream))
+        .start()
+        .await
+        .unwrap();
+    let (_, socket) = proxy.client().upgrade(upgrade_request()).await.unwrap();
+    let mut socket = socket.unwrap();
+
+    // Answered pings keep the socket open past the pong timeout.
+    for _ in 0..2 {
+        assert_eq!(next_non_pong(&mut socket).await.unwrap(), (0x9, vec![]));
+        socket.write_all(&[0x8A, 0x80, 0, 0, 0, 0]).await.unwrap();
+    }
+    assert_eq!(next_non_pong(&mut socket).await.unwrap(), (0x9, vec![]));
+    let closed = tokio::time::timeout(Duration::from_secs(5), next_non_pong(&mut socket))
+        .await
+        .expect("unanswered ping closes the socket");
+    assert!(closed.is_err(), "{closed:?}");
+
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn websocket_upstreams_that_stop_answering_pings_are_closed() {
+    let upstream = ping_upstream(false).await;
+    let proxy = TestProxy::builder()
+        .route(keepalive_route(upstream))
+        .start()
+        .await
+        .unwrap();
+    let (_, socket) = proxy.client().upgrade(upgrade_request()).await.unwrap();
+    let mut socket = socket.unwrap();
+
+    assert_eq!(next_non_pong(&mut socket).await.unwrap(), (0x9, vec![]));
+    socket.write_all(&[0x8A, 0x80, 0, 0, 0, 0]).await.unwrap();
+    // The next ping to the client may go out just before the upstream's
+    // deadline passes.
+    let close = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let frame = next_non_pong(&mut socket).await.unwrap();
+            if frame.0 != 0x9 {
+                return frame;
+            }
+        }
+    })
+    .await
+    .expect("silent upstream closes the socket");
+    assert_eq!(close, (0x8, vec![0x03, 0xE9]));
+
+    proxy.shutdown().await.unwrap();
+}